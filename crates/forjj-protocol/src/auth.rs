@@ -0,0 +1,362 @@
+//! Authentication in the Hello exchange.
+//!
+//! The client presents credentials in [`HelloRequest::auth`] along with the
+//! access it needs. The server checks them with an [`AuthHandler`] before
+//! answering, and reports the identity and granted access in its
+//! [`HelloResponse`](crate::messages::HelloResponse). Failures are reported to
+//! the client as [`ErrorCode::PermissionDenied`](crate::messages::ErrorCode).
+//!
+//! Secrets are never echoed back: errors say what failed without quoting the
+//! credentials, and [`Auth`]'s `Debug` output redacts them.
+
+use tokio::io::AsyncWrite;
+
+use crate::envelope::{WireFormat, close_with_error};
+use crate::error::ProtocolError;
+use crate::messages::{AccessLevel, Auth, HelloRequest, RepoRef};
+
+/// Result of successful authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthGrant {
+    /// Who the client is, or `None` for anonymous access
+    pub identity: Option<String>,
+    /// Highest access the credentials allow
+    pub access: AccessLevel,
+}
+
+/// Authentication failures.
+///
+/// None of these include the credentials, so they are safe to send to the
+/// client and to log.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("authentication required")]
+    MissingCredentials,
+
+    #[error("invalid credentials")]
+    InvalidCredentials,
+
+    #[error("authentication method not supported")]
+    UnsupportedMethod,
+
+    #[error("{requested} access denied")]
+    AccessDenied { requested: AccessLevel },
+}
+
+/// Checks credentials presented in the Hello exchange.
+///
+/// Implemented by the server. Implementations should compare secrets in
+/// constant time and must not log them.
+pub trait AuthHandler {
+    /// Check `auth` for a client that needs `requested` access.
+    ///
+    /// Returning a grant with less access than requested denies the session.
+    fn authenticate(&self, auth: &Auth, requested: AccessLevel) -> Result<AuthGrant, AuthError>;
+
+    /// Check `auth` for a client that needs `requested` access to `repo`,
+    /// the repository its Hello selects.
+    ///
+    /// Override this if what credentials grant depends on the repository;
+    /// by default it is [`authenticate`](Self::authenticate).
+    fn authenticate_on(
+        &self,
+        auth: &Auth,
+        requested: AccessLevel,
+        _repo: &RepoRef,
+    ) -> Result<AuthGrant, AuthError> {
+        self.authenticate(auth, requested)
+    }
+}
+
+/// Grants read access to anyone and denies writes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AnonymousRead;
+
+impl AuthHandler for AnonymousRead {
+    fn authenticate(&self, _auth: &Auth, requested: AccessLevel) -> Result<AuthGrant, AuthError> {
+        match requested {
+            AccessLevel::Read => Ok(AuthGrant {
+                identity: None,
+                access: AccessLevel::Read,
+            }),
+            AccessLevel::Write => Err(AuthError::AccessDenied { requested }),
+        }
+    }
+}
+
+/// Grants what the transport already authenticated, e.g. the identity behind
+/// an SSH key, whatever credentials the Hello carries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportAuth(pub AuthGrant);
+
+impl AuthHandler for TransportAuth {
+    fn authenticate(&self, _auth: &Auth, _requested: AccessLevel) -> Result<AuthGrant, AuthError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Server side: authenticate the client's Hello with `handler`.
+///
+/// On failure, reports the error to the client, closes the writer, and fails
+/// with [`ProtocolError::Auth`]. On success, the caller should copy the grant
+/// into its Hello response.
+pub async fn server_authenticate<W, A>(
+    writer: &mut W,
+    request: &HelloRequest,
+    handler: &A,
+) -> Result<AuthGrant, ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+    A: AuthHandler + ?Sized,
+{
+    let granted = match &request.repo {
+        Some(repo) => handler.authenticate_on(&request.auth, request.access, repo),
+        None => handler.authenticate(&request.auth, request.access),
+    };
+    let result = granted.and_then(|grant| {
+        if grant.access >= request.access {
+            Ok(grant)
+        } else {
+            Err(AuthError::AccessDenied {
+                requested: request.access,
+            })
+        }
+    });
+
+    match result {
+        Ok(grant) => Ok(grant),
+        Err(error) => {
+            let error = ProtocolError::Auth(error);
+            close_with_error(writer, error.to_error_message(), WireFormat::Json).await;
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+    use crate::envelope::{read_message_as, write_message};
+    use crate::handshake::{VersionRange, server_read_hello, server_send_hello};
+    use crate::messages::{ErrorCode, HelloResponse, ServerLimits};
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    const TOKEN: &str = "fj_4f8a1c2e9b7d";
+    const FINGERPRINT: &str = "SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8";
+
+    /// Accepts one hardcoded token with full access, and SSH keys the
+    /// transport verified with read access.
+    struct TokenAuth;
+
+    impl AuthHandler for TokenAuth {
+        fn authenticate(
+            &self,
+            auth: &Auth,
+            requested: AccessLevel,
+        ) -> Result<AuthGrant, AuthError> {
+            match auth {
+                Auth::BearerToken(token) if token == TOKEN => Ok(AuthGrant {
+                    identity: Some("alice".to_string()),
+                    access: AccessLevel::Write,
+                }),
+                Auth::BearerToken(_) => Err(AuthError::InvalidCredentials),
+                Auth::SshKey { fingerprint, .. } => Ok(AuthGrant {
+                    identity: Some(fingerprint.clone()),
+                    access: AccessLevel::Read,
+                }),
+                Auth::None if requested == AccessLevel::Read => Ok(AuthGrant {
+                    identity: None,
+                    access: AccessLevel::Read,
+                }),
+                Auth::None => Err(AuthError::MissingCredentials),
+            }
+        }
+    }
+
+    fn hello(auth: Auth, access: AccessLevel) -> HelloRequest {
+        HelloRequest {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+            client_op_heads: vec![],
+            compression: vec![],
+            max_frame_size: None,
+            min_protocol_version: None,
+            auth,
+            access,
+            repo: None,
+            create_if_missing: false,
+        }
+    }
+
+    /// Run the server side of the Hello exchange against `request`, returning
+    /// its result and everything it sent.
+    async fn serve(request: HelloRequest) -> (Result<AuthGrant, ProtocolError>, Vec<u8>) {
+        let mut input = Vec::new();
+        write_message(&mut input, &request.into(), WireFormat::Json)
+            .await
+            .unwrap();
+        let mut reader = Cursor::new(input);
+        let mut output = Vec::new();
+
+        let result = async {
+            let (request, version) =
+                server_read_hello(&mut reader, &mut output, VersionRange::SUPPORTED).await?;
+            let grant = server_authenticate(&mut output, &request, &TokenAuth).await?;
+            let response = HelloResponse {
+                protocol_version: version,
+                capabilities: vec![],
+                server_op_heads: vec![],
+                common_ancestor: None,
+                compression: None,
+                max_frame_size: None,
+                identity: grant.identity.clone(),
+                access: Some(grant.access),
+                limits: ServerLimits::default(),
+                extensions: BTreeMap::new(),
+            };
+            server_send_hello(&mut output, &request, response).await?;
+            Ok::<_, ProtocolError>(grant)
+        }
+        .await;
+        (result, output)
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn test_valid_token_grants_access() {
+        let (result, output) = serve(hello(
+            Auth::BearerToken(TOKEN.to_string()),
+            AccessLevel::Write,
+        ))
+        .await;
+        assert_eq!(result.unwrap().identity.as_deref(), Some("alice"));
+        assert!(!contains(&output, TOKEN));
+
+        let response: HelloResponse = read_message_as(&mut Cursor::new(output), WireFormat::Json)
+            .await
+            .unwrap();
+        assert_eq!(response.identity.as_deref(), Some("alice"));
+        assert_eq!(response.access, Some(AccessLevel::Write));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_is_denied() {
+        let wrong = "fj_guessed_token";
+        let (result, output) = serve(hello(
+            Auth::BearerToken(wrong.to_string()),
+            AccessLevel::Read,
+        ))
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Auth(AuthError::InvalidCredentials))
+        ));
+        assert!(!contains(&output, wrong));
+
+        let error = read_message_as::<HelloResponse, _>(&mut Cursor::new(output), WireFormat::Json)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+        assert!(!error.to_string().contains(wrong));
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_access() {
+        let (result, _) = serve(hello(Auth::None, AccessLevel::Read)).await;
+        assert_eq!(result.unwrap().identity, None);
+
+        let (result, _) = serve(hello(Auth::None, AccessLevel::Write)).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Auth(AuthError::MissingCredentials))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_grant_is_denied() {
+        let ssh = Auth::SshKey {
+            fingerprint: FINGERPRINT.to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+        };
+        let (result, _) = serve(hello(ssh.clone(), AccessLevel::Read)).await;
+        assert_eq!(result.unwrap().identity.as_deref(), Some(FINGERPRINT));
+
+        let (result, output) = serve(hello(ssh, AccessLevel::Write)).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Auth(AuthError::AccessDenied {
+                requested: AccessLevel::Write
+            }))
+        ));
+        let error = read_message_as::<HelloResponse, _>(&mut Cursor::new(output), WireFormat::Json)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+    }
+
+    #[test]
+    fn test_anonymous_read() {
+        let grant = AnonymousRead
+            .authenticate(&Auth::BearerToken(TOKEN.to_string()), AccessLevel::Read)
+            .unwrap();
+        assert_eq!(grant.identity, None);
+        assert!(matches!(
+            AnonymousRead.authenticate(&Auth::None, AccessLevel::Write),
+            Err(AuthError::AccessDenied {
+                requested: AccessLevel::Write
+            })
+        ));
+    }
+
+    #[test]
+    fn test_transport_auth() {
+        let grant = AuthGrant {
+            identity: Some("alice@laptop".to_string()),
+            access: AccessLevel::Read,
+        };
+        let auth = TransportAuth(grant.clone());
+        assert_eq!(
+            auth.authenticate(&Auth::None, AccessLevel::Read).unwrap(),
+            grant
+        );
+        // The session checks the requested access against the grant.
+        assert_eq!(
+            auth.authenticate(&Auth::None, AccessLevel::Write).unwrap(),
+            grant
+        );
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let request = hello(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+        let debug = format!("{request:?}");
+        assert!(!debug.contains(TOKEN), "{debug}");
+        assert!(debug.contains("BearerToken(<redacted>)"));
+
+        let ssh = Auth::SshKey {
+            fingerprint: FINGERPRINT.to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+        };
+        let debug = format!("{ssh:?}");
+        assert!(debug.contains(FINGERPRINT));
+        assert!(!debug.contains("c2lnbmF0dXJl"), "{debug}");
+    }
+
+    #[test]
+    fn test_auth_serializes_in_hello() {
+        let request = hello(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["auth"]["bearer_token"], TOKEN);
+        assert_eq!(json["access"], "write");
+
+        let parsed: HelloRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.auth, request.auth);
+    }
+}
@@ -0,0 +1,199 @@
+//! Bloom filters over commit IDs.
+//!
+//! A peer that already has most of the commits a transfer would carry can
+//! describe them with a [`CommitFilter`] instead of listing them. The sender
+//! of the pack promises the commits the filter probably contains (see
+//! [`crate::pack`]) rather than sending them. A false positive costs one
+//! more round trip: the receiver asks for the promised commits it turns out
+//! to lack, so correctness never depends on the filter.
+//!
+//! Commit IDs are already uniformly distributed hashes, so the bit positions
+//! come straight from the ID's first 16 bytes by double hashing.
+
+use forjj_storage::CommitId;
+use serde::{Deserialize, Serialize};
+
+/// Largest filter accepted, in bits (1 MiB of filter data).
+pub const MAX_FILTER_BITS: u32 = 8 * 1024 * 1024;
+
+/// Largest number of bit positions per commit accepted.
+pub const MAX_HASHES: u8 = 16;
+
+/// Bits per commit in a filter sized by [`CommitFilter::for_commits`],
+/// about a 1% false positive rate with [`DEFAULT_HASHES`].
+pub const BITS_PER_COMMIT: u32 = 10;
+
+/// Bit positions per commit in a filter sized by
+/// [`CommitFilter::for_commits`].
+pub const DEFAULT_HASHES: u8 = 7;
+
+/// Reasons a received commit filter is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CommitFilterError {
+    #[error("commit filter has no bits")]
+    Empty,
+
+    #[error("commit filter has {0} bits, more than {MAX_FILTER_BITS}")]
+    TooLarge(u32),
+
+    #[error("commit filter uses {0} hashes, expected 1 to {MAX_HASHES}")]
+    InvalidHashCount(u8),
+
+    #[error("commit filter of {bits} bits has {actual} bytes of data")]
+    LengthMismatch { bits: u32, actual: usize },
+}
+
+/// A bloom filter over commit IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitFilter {
+    /// Number of bits in the filter
+    pub bits: u32,
+    /// Number of bit positions set per commit
+    pub hashes: u8,
+    /// The bits, least significant first within each byte
+    pub data: Vec<u8>,
+}
+
+impl CommitFilter {
+    /// An empty filter of `bits` bits setting `hashes` positions per commit.
+    pub fn new(bits: u32, hashes: u8) -> Result<Self, CommitFilterError> {
+        let filter = Self {
+            bits,
+            hashes,
+            data: vec![0; bits.div_ceil(8) as usize],
+        };
+        filter.validate()?;
+        Ok(filter)
+    }
+
+    /// A filter containing `commits`, sized for about a 1% false positive
+    /// rate.
+    pub fn for_commits(commits: &[CommitId]) -> Self {
+        let wanted = u32::try_from(commits.len())
+            .unwrap_or(u32::MAX)
+            .saturating_mul(BITS_PER_COMMIT);
+        let bits = wanted.clamp(64, MAX_FILTER_BITS);
+        let mut filter = Self {
+            bits,
+            hashes: DEFAULT_HASHES,
+            data: vec![0; bits.div_ceil(8) as usize],
+        };
+        for id in commits {
+            filter.insert(id);
+        }
+        filter
+    }
+
+    /// Check a filter received from a peer before using it.
+    pub fn validate(&self) -> Result<(), CommitFilterError> {
+        if self.bits == 0 {
+            return Err(CommitFilterError::Empty);
+        }
+        if self.bits > MAX_FILTER_BITS {
+            return Err(CommitFilterError::TooLarge(self.bits));
+        }
+        if self.hashes == 0 || self.hashes > MAX_HASHES {
+            return Err(CommitFilterError::InvalidHashCount(self.hashes));
+        }
+        if self.data.len() != self.bits.div_ceil(8) as usize {
+            return Err(CommitFilterError::LengthMismatch {
+                bits: self.bits,
+                actual: self.data.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Add `id` to the filter.
+    pub fn insert(&mut self, id: &CommitId) {
+        for position in self.positions(id) {
+            self.data[position / 8] |= 1 << (position % 8);
+        }
+    }
+
+    /// Whether `id` is probably in the filter. Never false for an ID that
+    /// was inserted.
+    pub fn contains(&self, id: &CommitId) -> bool {
+        self.positions(id)
+            .all(|position| self.data[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    fn positions(&self, id: &CommitId) -> impl Iterator<Item = usize> + use<> {
+        let mut bytes = [0; 16];
+        let id = id.as_bytes();
+        let len = id.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&id[..len]);
+        let first = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        // Odd, so the positions don't repeat early.
+        let step = u64::from_le_bytes(bytes[8..].try_into().unwrap()) | 1;
+        let bits = u64::from(self.bits);
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use forjj_storage::ObjectId;
+
+    use super::*;
+
+    fn ids(prefix: &str, count: usize) -> Vec<CommitId> {
+        (0..count)
+            .map(|i| ObjectId::hash(format!("{prefix}{i}").as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_contains_inserted() {
+        let inserted = ids("in", 1000);
+        let filter = CommitFilter::for_commits(&inserted);
+        assert_eq!(filter.bits, 1000 * BITS_PER_COMMIT);
+        filter.validate().unwrap();
+        assert!(inserted.iter().all(|id| filter.contains(id)));
+
+        // Around 1% of other IDs are false positives.
+        let others = ids("out", 1000);
+        let false_positives = others.iter().filter(|id| filter.contains(id)).count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+
+        let empty = CommitFilter::new(64, 3).unwrap();
+        assert!(!inserted.iter().any(|id| empty.contains(id)));
+    }
+
+    #[test]
+    fn test_saturated_filter_contains_everything() {
+        let mut filter = CommitFilter::new(1, 1).unwrap();
+        filter.insert(&ObjectId::hash(b"one"));
+        assert!(ids("any", 100).iter().all(|id| filter.contains(id)));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert_eq!(CommitFilter::new(0, 1), Err(CommitFilterError::Empty));
+        assert_eq!(
+            CommitFilter::new(MAX_FILTER_BITS + 1, 1),
+            Err(CommitFilterError::TooLarge(MAX_FILTER_BITS + 1))
+        );
+        assert_eq!(
+            CommitFilter::new(64, 0),
+            Err(CommitFilterError::InvalidHashCount(0))
+        );
+        assert_eq!(
+            CommitFilter::new(64, MAX_HASHES + 1),
+            Err(CommitFilterError::InvalidHashCount(MAX_HASHES + 1))
+        );
+        let short = CommitFilter {
+            bits: 64,
+            hashes: 1,
+            data: vec![0; 7],
+        };
+        assert_eq!(
+            short.validate(),
+            Err(CommitFilterError::LengthMismatch {
+                bits: 64,
+                actual: 7
+            })
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Cancelling operations in flight.
+//!
+//! After the Hello exchange, a client may send [`Cancel`] at any time. The
+//! server stops the current operation before its next pack chunk, discards
+//! partial state, and answers with [`CancelAck`]. Any messages the server sent
+//! before seeing the Cancel are discarded by the client, after which the
+//! connection can carry a new request.
+//!
+//! A Cancel that arrives after the operation already finished is answered with
+//! a CancelAck as well, so the client can always wait for one.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::Notify;
+
+use crate::envelope::{Message, WireFormat, read_message, read_message_after, write_message};
+use crate::error::ProtocolError;
+use crate::keepalive::{answer_ping, eof_as_frame_error};
+use crate::messages::{Cancel, CancelAck, PackChunk};
+use crate::pack::{PackEntry, PackReader};
+use crate::transfer::{ChunkSequence, trailer_hash};
+
+/// How long a client waits for the server to acknowledge a Cancel.
+pub const DEFAULT_CANCEL_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handle for cancelling an operation from another task, e.g. on Ctrl-C.
+///
+/// Clones share the same state: cancelling one cancels all of them.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    /// Create a token that hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Whether cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until cancellation is requested.
+    pub async fn cancelled(&self) {
+        loop {
+            // Register before checking so a concurrent cancel isn't missed.
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Result of a send that the client may cancel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// Every chunk was sent.
+    Completed { chunks: u64 },
+    /// The client cancelled after `chunks` chunks were sent.
+    Cancelled { chunks: u64 },
+}
+
+/// Send an in-memory pack as [`PackChunk`] messages, stopping if the client
+/// sends [`Cancel`].
+///
+/// The server checks for a Cancel before each chunk, so at most one more chunk
+/// is sent once it arrives. Keepalive pings are answered along the way.
+pub async fn send_pack_cancellable<R, W>(
+    pack: &[u8],
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    chunk_size: usize,
+) -> Result<SendOutcome, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let pack_hash = trailer_hash(pack)?;
+    let mut chunks = pack.chunks(chunk_size).peekable();
+    let mut sequence = 0;
+
+    loop {
+        while let Some(message) = try_read_message(reader, format).await? {
+            match message {
+                Message::Cancel(_) => {
+                    write_message(writer, &CancelAck {}.into(), format).await?;
+                    return Ok(SendOutcome::Cancelled { chunks: sequence });
+                }
+                Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+                Message::Pong(_) => {}
+                other => {
+                    return Err(ProtocolError::UnexpectedMessage {
+                        expected: "Cancel",
+                        actual: other.name(),
+                    });
+                }
+            }
+        }
+
+        let data = chunks.next().unwrap_or_default();
+        let last = chunks.peek().is_none();
+        let chunk = PackChunk {
+            sequence,
+            data: data.to_vec(),
+            last,
+            pack_hash: last.then_some(pack_hash),
+        };
+        write_message(writer, &chunk.into(), format).await?;
+        sequence += 1;
+
+        if last {
+            return Ok(SendOutcome::Completed { chunks: sequence });
+        }
+    }
+}
+
+/// Send [`Cancel`] and wait up to `timeout` for the server's [`CancelAck`].
+///
+/// Messages from the cancelled operation are discarded. Returns how many were
+/// discarded.
+pub async fn cancel_operation<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    timeout: Duration,
+) -> Result<usize, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_message(writer, &Cancel { reason: None }.into(), format).await?;
+    tokio::time::timeout(timeout, drain_until_cancel_ack(reader, writer, format))
+        .await
+        .map_err(|_| ProtocolError::IdleTimeout(timeout))?
+}
+
+async fn drain_until_cancel_ack<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+) -> Result<usize, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut discarded = 0;
+    loop {
+        match read_message(reader, format).await? {
+            Message::CancelAck(_) => return Ok(discarded),
+            Message::Error(error) => return Err(ProtocolError::Remote(error)),
+            Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+            Message::Pong(_) | Message::Progress(_) => {}
+            _ => discarded += 1,
+        }
+    }
+}
+
+/// Receive a pack, cancelling the transfer if `token` is cancelled first.
+///
+/// On cancellation, sends [`Cancel`], waits for the acknowledgement, and
+/// returns [`ProtocolError::Cancelled`]. The connection remains usable.
+pub async fn receive_pack_cancellable<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    token: &CancelToken,
+) -> Result<Vec<PackEntry>, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut sequence = ChunkSequence::default();
+    let mut pack = Vec::new();
+
+    while !sequence.is_finished() {
+        let first = tokio::select! {
+            biased;
+            () = token.cancelled() => None,
+            first = reader.read_u8() => Some(first.map_err(eof_as_frame_error)?),
+        };
+        let Some(first) = first else {
+            cancel_operation(reader, writer, format, DEFAULT_CANCEL_ACK_TIMEOUT).await?;
+            return Err(ProtocolError::Cancelled);
+        };
+
+        match read_message_after(first, reader, format).await? {
+            Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+            Message::Pong(_) | Message::Progress(_) => {}
+            message => {
+                let chunk = PackChunk::try_from(message)?;
+                sequence.accept(&chunk)?;
+                pack.extend_from_slice(&chunk.data);
+            }
+        }
+    }
+
+    Ok(PackReader::new(pack.as_slice()).await?.read_all().await?)
+}
+
+/// Read a message if one has started arriving, without waiting otherwise.
+async fn try_read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    format: WireFormat,
+) -> Result<Option<Message>, ProtocolError> {
+    let first = tokio::select! {
+        biased;
+        first = reader.read_u8() => first.map_err(eof_as_frame_error)?,
+        () = std::future::ready(()) => return Ok(None),
+    };
+    read_message_after(first, reader, format).await.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::read_message_as;
+    use crate::messages::FetchRequest;
+    use crate::pack::{ObjectKind, PackWriter};
+    use forjj_storage::ObjectId;
+
+    const FORMAT: WireFormat = WireFormat::Binary;
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    async fn build_pack() -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), 20).await.unwrap();
+        for i in 0..20u32 {
+            let data = vec![i as u8; 10_000];
+            pack.add_object(ObjectKind::File, &ObjectId::hash(&data), &data)
+                .await
+                .unwrap();
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    fn fetch() -> FetchRequest {
+        FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+            include_operations: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_mid_pack() {
+        let pack = build_pack().await;
+        // Smaller than one chunk frame, so the server can't run ahead.
+        let (client, server) = tokio::io::duplex(8 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+
+        let serve = async {
+            let outcome = send_pack_cancellable(
+                &pack,
+                &mut server_read,
+                &mut server_write,
+                FORMAT,
+                CHUNK_SIZE,
+            )
+            .await
+            .unwrap();
+            // The connection carries the next request after the cancel.
+            let next: FetchRequest = read_message_as(&mut server_read, FORMAT).await.unwrap();
+            (outcome, next)
+        };
+        let client = async {
+            let first: PackChunk = read_message_as(&mut client_read, FORMAT).await.unwrap();
+            assert_eq!(first.sequence, 0);
+            let discarded = cancel_operation(
+                &mut client_read,
+                &mut client_write,
+                FORMAT,
+                DEFAULT_CANCEL_ACK_TIMEOUT,
+            )
+            .await
+            .unwrap();
+            write_message(&mut client_write, &fetch().into(), FORMAT)
+                .await
+                .unwrap();
+            discarded
+        };
+
+        let ((outcome, next), discarded) = tokio::join!(serve, client);
+        assert!(discarded <= 1, "{discarded} chunks after cancel");
+        assert!(matches!(outcome, SendOutcome::Cancelled { chunks } if chunks <= 2));
+        assert_eq!(next.want_refs, vec!["main".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_token_stops_receive() {
+        let pack = build_pack().await;
+        let (client, server) = tokio::io::duplex(8 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+
+        let token = CancelToken::new();
+        token.cancel();
+
+        let serve = send_pack_cancellable(
+            &pack,
+            &mut server_read,
+            &mut server_write,
+            FORMAT,
+            CHUNK_SIZE,
+        );
+        let receive = receive_pack_cancellable(&mut client_read, &mut client_write, FORMAT, &token);
+
+        let (outcome, received) = tokio::join!(serve, receive);
+        assert!(matches!(received, Err(ProtocolError::Cancelled)));
+        assert!(matches!(
+            outcome.unwrap(),
+            SendOutcome::Cancelled { chunks } if chunks <= 2
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_uncancelled_receive_completes() {
+        let pack = build_pack().await;
+        let (client, server) = tokio::io::duplex(8 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+
+        let token = CancelToken::new();
+        let serve = send_pack_cancellable(
+            &pack,
+            &mut server_read,
+            &mut server_write,
+            FORMAT,
+            CHUNK_SIZE,
+        );
+        let receive = receive_pack_cancellable(&mut client_read, &mut client_write, FORMAT, &token);
+
+        let (outcome, received) = tokio::join!(serve, receive);
+        assert_eq!(
+            outcome.unwrap(),
+            SendOutcome::Completed {
+                chunks: pack.len().div_ceil(CHUNK_SIZE) as u64
+            }
+        );
+        assert_eq!(received.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_token_wakes_waiters() {
+        let token = CancelToken::new();
+        let waiter = {
+            let token = token.clone();
+            tokio::spawn(async move { token.cancelled().await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!token.is_cancelled());
+
+        token.cancel();
+        waiter.await.unwrap();
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,226 @@
+//! Capability sets and negotiation.
+//!
+//! Each peer advertises the capabilities it supports in the Hello exchange.
+//! A session gets those both peers advertised, and either side may refuse to
+//! continue without some of them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::Capability;
+
+/// Capability negotiation errors.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NegotiationError {
+    #[error("required capability {0} not supported by both peers")]
+    MissingRequired(Capability),
+}
+
+/// A set of capabilities.
+///
+/// Serialized as a list of capability names, like the capabilities in the
+/// Hello messages. Order and duplicates don't matter.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<Capability>", into = "Vec<Capability>")]
+pub struct CapabilitySet {
+    capabilities: Vec<Capability>,
+}
+
+impl CapabilitySet {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `capability`, returning whether it was newly added.
+    pub fn insert(&mut self, capability: Capability) -> bool {
+        if self.contains(&capability) {
+            return false;
+        }
+        self.capabilities.push(capability);
+        true
+    }
+
+    /// Whether the set contains `capability`.
+    pub fn contains(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Capabilities in both `self` and `other`.
+    pub fn intersect(&self, other: &CapabilitySet) -> CapabilitySet {
+        self.iter()
+            .filter(|capability| other.contains(capability))
+            .cloned()
+            .collect()
+    }
+
+    /// The capabilities this peer knows, without unknown ones.
+    pub fn known(&self) -> CapabilitySet {
+        self.iter()
+            .filter(|capability| capability.is_known())
+            .cloned()
+            .collect()
+    }
+
+    /// Iterate over the capabilities in insertion order.
+    pub fn iter(&self) -> std::slice::Iter<'_, Capability> {
+        self.capabilities.iter()
+    }
+
+    /// Number of capabilities in the set.
+    pub fn len(&self) -> usize {
+        self.capabilities.len()
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.capabilities.is_empty()
+    }
+
+    /// The capabilities as a slice, in insertion order.
+    pub fn as_slice(&self) -> &[Capability] {
+        &self.capabilities
+    }
+}
+
+impl PartialEq for CapabilitySet {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|capability| other.contains(capability))
+    }
+}
+
+impl Eq for CapabilitySet {}
+
+impl FromIterator<Capability> for CapabilitySet {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        let mut set = CapabilitySet::new();
+        for capability in iter {
+            set.insert(capability);
+        }
+        set
+    }
+}
+
+impl From<Vec<Capability>> for CapabilitySet {
+    fn from(capabilities: Vec<Capability>) -> Self {
+        capabilities.into_iter().collect()
+    }
+}
+
+impl From<&[Capability]> for CapabilitySet {
+    fn from(capabilities: &[Capability]) -> Self {
+        capabilities.iter().cloned().collect()
+    }
+}
+
+impl From<CapabilitySet> for Vec<Capability> {
+    fn from(set: CapabilitySet) -> Self {
+        set.capabilities
+    }
+}
+
+impl<'a> IntoIterator for &'a CapabilitySet {
+    type Item = &'a Capability;
+    type IntoIter = std::slice::Iter<'a, Capability>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Capabilities both `client` and `server` advertised, failing if any of
+/// `required` is missing.
+///
+/// Unknown capabilities are left out of the result, even if both peers
+/// advertised them: neither side knows what they mean.
+pub fn negotiate(
+    client: &CapabilitySet,
+    server: &CapabilitySet,
+    required: &[Capability],
+) -> Result<CapabilitySet, NegotiationError> {
+    let common = client.intersect(server).known();
+    match required
+        .iter()
+        .find(|capability| !common.contains(capability))
+    {
+        Some(missing) => Err(NegotiationError::MissingRequired(missing.clone())),
+        None => Ok(common),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(capabilities: &[Capability]) -> CapabilitySet {
+        capabilities.into()
+    }
+
+    fn unknown(name: &str) -> Capability {
+        Capability::Unknown(name.to_string())
+    }
+
+    #[test]
+    fn test_intersect_and_contains() {
+        let client = set(&[
+            Capability::Operations,
+            Capability::ThinPack,
+            Capability::Compression,
+        ]);
+        let server = set(&[Capability::Compression, Capability::Operations]);
+
+        let common = client.intersect(&server);
+        assert_eq!(
+            common,
+            set(&[Capability::Operations, Capability::Compression])
+        );
+        assert!(common.contains(&Capability::Compression));
+        assert!(!common.contains(&Capability::ThinPack));
+        assert_eq!(common, server.intersect(&client));
+    }
+
+    #[test]
+    fn test_duplicates_are_ignored() {
+        let mut capabilities = set(&[Capability::ThinPack, Capability::ThinPack]);
+        assert_eq!(capabilities.len(), 1);
+        assert!(!capabilities.insert(Capability::ThinPack));
+        assert!(capabilities.insert(Capability::Resumable));
+        assert_eq!(capabilities.len(), 2);
+    }
+
+    #[test]
+    fn test_negotiate_drops_unknown() {
+        let client = set(&[Capability::Operations, unknown("quantum_sync")]);
+        let server = set(&[Capability::Operations, unknown("quantum_sync")]);
+
+        let common = negotiate(&client, &server, &[Capability::Operations]).unwrap();
+        assert_eq!(common, set(&[Capability::Operations]));
+    }
+
+    #[test]
+    fn test_negotiate_missing_required() {
+        let client = set(&[Capability::Operations, Capability::ThinPack]);
+        let server = set(&[Capability::Operations]);
+
+        let error = negotiate(
+            &client,
+            &server,
+            &[Capability::Operations, Capability::ThinPack],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            NegotiationError::MissingRequired(Capability::ThinPack)
+        );
+        assert!(error.to_string().contains("thin_pack"), "{error}");
+    }
+
+    #[test]
+    fn test_unknown_capabilities_roundtrip() {
+        let json = r#"["binary_frames","quantum_sync"]"#;
+        let parsed: CapabilitySet = serde_json::from_str(json).unwrap();
+        assert!(parsed.contains(&Capability::BinaryFrames));
+        assert!(parsed.contains(&unknown("quantum_sync")));
+        assert_eq!(parsed.known(), set(&[Capability::BinaryFrames]));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+}
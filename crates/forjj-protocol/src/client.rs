@@ -0,0 +1,945 @@
+//! High-level client for the sync protocol.
+//!
+//! [`ForjjClient`] drives the message sequence for an integrator: it performs
+//! the Hello exchange on [`connect`](ForjjClient::connect), then runs fetches,
+//! pushes, and ref listings over the same connection. Keepalive pings are
+//! answered and progress updates are passed to the client's
+//! [`ProgressSink`] along the way, and Error frames from the server surface
+//! as [`ProtocolError::Remote`].
+//!
+//! The client only needs an [`AsyncRead`] + [`AsyncWrite`] stream, so it runs
+//! equally over an SSH channel, a TCP socket, or an in-memory duplex.
+
+use std::future::Future;
+use std::time::Duration;
+
+use forjj_storage::{OperationId, RawObjectKind, Repository};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use crate::capability::CapabilitySet;
+use crate::envelope::{Message, WireFormat, close_with_error, read_message, write_message};
+use crate::error::ProtocolError;
+use crate::framing::FrameError;
+use crate::handshake::{Negotiated, VersionRange, client_hello};
+use crate::keepalive::{
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL, answer_ping, keepalive_while,
+};
+use crate::messages::{
+    AccessLevel, Auth, Capability, FetchRequest, FetchResponse, HelloRequest, HelloResponse,
+    ListRefsRequest, ListRefsResponse, OperationRecord, PackChunk, PushNegotiate, PushRequest,
+    PushResult, RefInfo, RepoRef, ServerLimits,
+};
+use crate::negotiation::{HaveWalker, OpGraph};
+use crate::pack::PackError;
+use crate::progress::{NoProgress, ProgressSink};
+use crate::sync::{CONTENT_KINDS, export_objects, export_pack, export_pack_except};
+use crate::throttle::{RateLimit, ThrottledWriter};
+use crate::transfer::{ChunkSequence, DEFAULT_CHUNK_SIZE, send_pack};
+
+/// Settings for [`ForjjClient::connect`].
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Repository to sync
+    pub repo: RepoRef,
+    /// Ask the server to create `repo` if it doesn't exist yet
+    pub create_if_missing: bool,
+    /// Credentials presented to the server
+    pub auth: Auth,
+    /// Access the session needs
+    pub access: AccessLevel,
+    /// Protocol versions the client speaks
+    pub versions: VersionRange,
+    /// Capabilities the client advertises
+    pub capabilities: CapabilitySet,
+    /// Capabilities the session can't do without
+    pub required_capabilities: Vec<Capability>,
+    /// Operation heads the client already has
+    pub op_heads: Vec<OperationId>,
+    /// The client's operation log, for offering older operations when the
+    /// server doesn't know the ones in a fetch request
+    pub op_log: Option<OpGraph>,
+    /// Pack bytes per chunk when pushing
+    pub chunk_size: usize,
+    /// How long to wait for a frame from the server, if limited
+    pub idle_timeout: Option<Duration>,
+    /// How often to ping the server while building a pack to push, if at
+    /// all
+    pub keepalive_interval: Option<Duration>,
+    /// Cap on the client's outbound throughput, if any
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl ClientOptions {
+    /// Options for syncing `repo` anonymously with read access.
+    pub fn new(repo: RepoRef) -> Self {
+        Self {
+            repo,
+            create_if_missing: false,
+            auth: Auth::None,
+            access: AccessLevel::Read,
+            versions: VersionRange::SUPPORTED,
+            capabilities: [
+                Capability::Operations,
+                Capability::BinaryFrames,
+                Capability::Pipelining,
+            ]
+            .into_iter()
+            .collect(),
+            required_capabilities: Vec::new(),
+            op_heads: Vec::new(),
+            op_log: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            rate_limit: None,
+        }
+    }
+
+    /// Present `auth` and request `access`.
+    pub fn with_auth(mut self, auth: Auth, access: AccessLevel) -> Self {
+        self.auth = auth;
+        self.access = access;
+        self
+    }
+
+    /// Ask the server to create the repository if it doesn't exist yet,
+    /// for pushing to a new name. Needs write access, and only works on
+    /// servers that allow it.
+    pub fn with_create_if_missing(mut self) -> Self {
+        self.create_if_missing = true;
+        self
+    }
+
+    /// Advertise `capabilities` instead of the defaults.
+    pub fn with_capabilities(mut self, capabilities: impl Into<CapabilitySet>) -> Self {
+        self.capabilities = capabilities.into();
+        self
+    }
+
+    /// Fail to connect unless the server supports all of `required`.
+    pub fn with_required_capabilities(mut self, required: Vec<Capability>) -> Self {
+        self.required_capabilities = required;
+        self
+    }
+
+    /// Report `op_heads` as the operations the client already has.
+    pub fn with_op_heads(mut self, op_heads: Vec<OperationId>) -> Self {
+        self.op_heads = op_heads;
+        self
+    }
+
+    /// Offer operations from `op_log` when the server asks for more during
+    /// a fetch.
+    pub fn with_op_log(mut self, op_log: OpGraph) -> Self {
+        self.op_log = Some(op_log);
+        self
+    }
+
+    /// Send no faster than `limit`, e.g. to mirror politely.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    fn hello(&self) -> HelloRequest {
+        HelloRequest {
+            protocol_version: self.versions.max,
+            capabilities: self.capabilities.clone().into(),
+            client_op_heads: self.op_heads.clone(),
+            compression: Vec::new(),
+            max_frame_size: None,
+            min_protocol_version: Some(self.versions.min),
+            auth: self.auth.clone(),
+            access: self.access,
+            repo: Some(self.repo.clone()),
+            create_if_missing: self.create_if_missing,
+        }
+    }
+}
+
+/// Result of [`ForjjClient::fetch`].
+#[derive(Debug, Clone)]
+pub struct FetchOutcome {
+    /// The server's answer to the fetch
+    pub response: FetchResponse,
+    /// Number of pack bytes written to the sink
+    pub pack_bytes: u64,
+    /// Operations that followed the pack, parents before children
+    pub operations: Vec<OperationRecord>,
+}
+
+/// A connection to a Forjj server after a successful Hello exchange.
+pub struct ForjjClient<S> {
+    reader: ReadHalf<S>,
+    writer: ThrottledWriter<WriteHalf<S>>,
+    hello: HelloResponse,
+    negotiated: Negotiated,
+    chunk_size: usize,
+    idle_timeout: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    op_log: Option<OpGraph>,
+    progress: Box<dyn ProgressSink + Send>,
+    /// `request_id` for the next pipelined request
+    next_request_id: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite> ForjjClient<S> {
+    /// Perform the Hello exchange over `stream`.
+    ///
+    /// Fails with [`ProtocolError::Remote`] if the server rejects the Hello,
+    /// e.g. for bad credentials or an unknown repository, and with
+    /// [`ProtocolError::Negotiation`] if it lacks a required capability.
+    pub async fn connect(stream: S, options: ClientOptions) -> Result<Self, ProtocolError> {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let mut writer = ThrottledWriter::new(writer, options.rate_limit);
+        let (hello, negotiated) = client_hello(&mut reader, &mut writer, &options.hello()).await?;
+        negotiated.require(&options.required_capabilities)?;
+
+        Ok(Self {
+            reader,
+            writer,
+            hello,
+            negotiated,
+            chunk_size: options.chunk_size,
+            idle_timeout: options.idle_timeout,
+            keepalive_interval: options.keepalive_interval,
+            op_log: options.op_log,
+            progress: Box::new(NoProgress),
+            next_request_id: 1,
+        })
+    }
+
+    /// Pass progress updates from the server to `sink`.
+    pub fn set_progress<P: ProgressSink + Send + 'static>(&mut self, sink: P) {
+        self.progress = Box::new(sink);
+    }
+
+    /// Offer operations from `op_log` in later fetches, e.g. after applying
+    /// a fetch changed it.
+    pub fn set_op_log(&mut self, op_log: OpGraph) {
+        self.op_log = Some(op_log);
+    }
+
+    /// The server's answer to the Hello.
+    pub fn hello(&self) -> &HelloResponse {
+        &self.hello
+    }
+
+    /// Limits the server advertised; all unset for servers that predate
+    /// them.
+    pub fn limits(&self) -> &ServerLimits {
+        &self.hello.limits
+    }
+
+    /// Check a pack of `size` bytes against the server's limit, so an
+    /// oversized push can be turned down before anything is sent.
+    pub fn check_pack_size(&self, size: u64) -> Result<(), ProtocolError> {
+        match self.limits().max_pack_size {
+            Some(max) if size > max => Err(ProtocolError::PackOverServerLimit { size, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Session parameters agreed on in the Hello exchange.
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    /// List the server's refs that match `patterns`, or all of them if
+    /// there are none, with where each one points.
+    ///
+    /// Patterns are matched as in a fetch's `want_refs`, except that a
+    /// literal name for a ref that doesn't exist is left out rather than
+    /// failing.
+    pub async fn list_refs(&mut self, patterns: &[&str]) -> Result<Vec<RefInfo>, ProtocolError> {
+        let request = ListRefsRequest {
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            request_id: None,
+        };
+        self.send(request.into()).await?;
+        let response: ListRefsResponse = self.receive().await?.try_into()?;
+        Ok(response.refs)
+    }
+
+    /// Fetch `request` from the server, writing the pack, if any, to `sink`.
+    ///
+    /// If the server doesn't know some of the request's `have_ops`, it may
+    /// ask for older ones; those come from the options' operation log, if
+    /// any.
+    ///
+    /// The sink receives the raw pack bytes as they arrive and is flushed at
+    /// the end. Sequence numbers and the final pack hash are checked along the
+    /// way.
+    pub async fn fetch<W>(
+        &mut self,
+        request: FetchRequest,
+        sink: &mut W,
+    ) -> Result<FetchOutcome, ProtocolError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let walker = self.have_walker(&request.have_ops);
+        self.send(request.into()).await?;
+        self.read_fetch(walker, None, sink).await
+    }
+
+    /// Fetch each request into its sink, sending them all before reading
+    /// the first response, so they cost one round trip instead of one each.
+    ///
+    /// The server answers in order, one response and its pack at a time,
+    /// and each response's `request_id` is checked against its request's.
+    /// Needs [`Capability::Pipelining`].
+    pub async fn fetch_pipelined<'a, W>(
+        &mut self,
+        requests: impl IntoIterator<Item = (FetchRequest, &'a mut W)>,
+    ) -> Result<Vec<FetchOutcome>, ProtocolError>
+    where
+        W: AsyncWrite + Unpin + 'a,
+    {
+        self.negotiated.require(&[Capability::Pipelining])?;
+        let mut pending = Vec::new();
+        for (mut request, sink) in requests {
+            let id = self.next_request_id;
+            self.next_request_id += 1;
+            request.request_id = Some(id);
+            pending.push((id, self.have_walker(&request.have_ops), sink));
+            self.send(request.into()).await?;
+        }
+
+        let mut outcomes = Vec::with_capacity(pending.len());
+        for (id, walker, sink) in pending {
+            outcomes.push(self.read_fetch(walker, Some(id), sink).await?);
+        }
+        Ok(outcomes)
+    }
+
+    /// The walker to negotiate a fetch from `have_ops` with: batched if the
+    /// server takes [`Capability::NegotiateV2`].
+    fn have_walker(&self, have_ops: &[OperationId]) -> HaveWalker {
+        if self.negotiated.has(Capability::NegotiateV2) {
+            HaveWalker::batched(have_ops)
+        } else {
+            HaveWalker::new(have_ops)
+        }
+    }
+
+    /// Read the server's answer to a fetch request, negotiating with
+    /// `walker` and checking the response is for request `id`, if given.
+    async fn read_fetch<W>(
+        &mut self,
+        mut walker: HaveWalker,
+        id: Option<u64>,
+        sink: &mut W,
+    ) -> Result<FetchOutcome, ProtocolError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let response: FetchResponse = loop {
+            match self.receive().await? {
+                Message::AckReady(ack) if !ack.ready => {
+                    let more = walker.answer(self.op_log.as_ref(), &ack);
+                    self.send(more.into()).await?;
+                }
+                Message::AckReady(_) => {}
+                message => break message.try_into()?,
+            }
+        };
+        match id {
+            Some(expected) if response.request_id != id => {
+                return Err(ProtocolError::ResponseMismatch {
+                    expected,
+                    actual: response.request_id,
+                });
+            }
+            _ => {}
+        }
+
+        let mut pack_bytes = 0;
+        if response.pack_follows {
+            let mut sequence = ChunkSequence::default();
+            while !sequence.is_finished() {
+                let chunk: PackChunk = self.receive().await?.try_into()?;
+                sequence.accept(&chunk)?;
+                sink.write_all(&chunk.data).await.map_err(PackError::from)?;
+                pack_bytes += chunk.data.len() as u64;
+            }
+            sink.flush().await.map_err(PackError::from)?;
+        }
+
+        let mut operations = Vec::with_capacity(response.operation_count as usize);
+        for _ in 0..response.operation_count {
+            operations.push(self.receive().await?.try_into()?);
+        }
+
+        Ok(FetchOutcome {
+            response,
+            pack_bytes,
+            operations,
+        })
+    }
+
+    /// Push `request`, sending the pack from `pack_source` if the server
+    /// asks for objects.
+    ///
+    /// `pack_source` is only read if the server needs it.
+    pub async fn push<R>(
+        &mut self,
+        request: PushRequest,
+        pack_source: &mut R,
+    ) -> Result<PushResult, ProtocolError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.push_with_operations(request, pack_source, &[]).await
+    }
+
+    /// Like [`push`](Self::push), but also send `operations` for the server
+    /// to adopt before it moves bookmarks. They must be in parent-first
+    /// order, as [`export_operations`](crate::sync::export_operations)
+    /// returns them.
+    pub async fn push_with_operations<R>(
+        &mut self,
+        mut request: PushRequest,
+        pack_source: &mut R,
+        operations: &[OperationRecord],
+    ) -> Result<PushResult, ProtocolError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let negotiate = self.begin_push(request, operations).await?;
+        if negotiate.need_objects {
+            self.write_pack(pack_source).await?;
+        }
+        for operation in operations {
+            self.send(operation.clone().into()).await?;
+        }
+        self.receive().await?.try_into()
+    }
+
+    /// Push `request`, packing objects from `repo`, then `operations`.
+    ///
+    /// The pack is built once the server says it needs objects, with every
+    /// content object of `repo` except the commits in the server's
+    /// [commit filter](crate::bloom), which are only promised. If the server
+    /// turns out to lack some of those, it asks for them and they follow in
+    /// one more pack. The server is pinged every
+    /// [`ClientOptions::keepalive_interval`] while a pack is built, so it
+    /// doesn't give up on the connection.
+    ///
+    /// A pack larger than the server accepts fails with
+    /// [`ProtocolError::PackOverServerLimit`] without being sent, ending
+    /// the session.
+    pub async fn push_from_repo(
+        &mut self,
+        request: PushRequest,
+        repo: &Repository,
+        operations: &[OperationRecord],
+    ) -> Result<PushResult, ProtocolError> {
+        let negotiate = self.begin_push(request, operations).await?;
+        if negotiate.need_objects {
+            let pack = match &negotiate.have_commits_filter {
+                Some(have) => {
+                    have.validate()?;
+                    self.keepalive(export_pack_except(repo, CONTENT_KINDS, have))
+                        .await??
+                }
+                None => self.keepalive(export_pack(repo, CONTENT_KINDS)).await??,
+            };
+            self.check_outgoing_pack(&pack.data).await?;
+            self.write_pack(&mut pack.data.as_slice()).await?;
+        }
+        for operation in operations {
+            self.send(operation.clone().into()).await?;
+        }
+        loop {
+            match self.receive().await? {
+                Message::PushNegotiate(more) => {
+                    let commits: Vec<_> = more
+                        .want_commits
+                        .iter()
+                        .map(|id| (RawObjectKind::Commit, *id))
+                        .collect();
+                    let pack = self.keepalive(export_objects(repo, &commits)).await??;
+                    self.check_outgoing_pack(&pack.data).await?;
+                    self.write_pack(&mut pack.data.as_slice()).await?;
+                }
+                message => return message.try_into(),
+            }
+        }
+    }
+
+    /// Send `request`, announcing `operations`, and read the server's
+    /// answer.
+    async fn begin_push(
+        &mut self,
+        mut request: PushRequest,
+        operations: &[OperationRecord],
+    ) -> Result<PushNegotiate, ProtocolError> {
+        request.operation_count = u32::try_from(operations.len())
+            .map_err(|_| anyhow::anyhow!("too many operations: {}", operations.len()))?;
+        self.send(request.into()).await?;
+        self.receive().await?.try_into()
+    }
+
+    /// Check `pack` against the server's limit before pushing it. If it's
+    /// too large, the server is told the push is abandoned.
+    async fn check_outgoing_pack(&mut self, pack: &[u8]) -> Result<(), ProtocolError> {
+        let result = self.check_pack_size(pack.len() as u64);
+        if let Err(error) = &result {
+            let format = self.negotiated.format;
+            close_with_error(&mut self.writer, error.to_error_message(), format).await;
+        }
+        result
+    }
+
+    /// Run `work`, pinging the server every
+    /// [`ClientOptions::keepalive_interval`] until it is done.
+    async fn keepalive<F: Future>(&mut self, work: F) -> Result<F::Output, ProtocolError> {
+        match self.keepalive_interval {
+            Some(interval) => {
+                keepalive_while(&mut self.writer, self.negotiated.format, interval, work).await
+            }
+            None => Ok(work.await),
+        }
+    }
+
+    async fn write_pack<R: AsyncRead + Unpin>(
+        &mut self,
+        pack_source: &mut R,
+    ) -> Result<u64, ProtocolError> {
+        send_pack(
+            pack_source,
+            &mut self.writer,
+            self.negotiated.format,
+            self.chunk_size,
+        )
+        .await
+    }
+
+    /// Close the connection.
+    pub async fn shutdown(mut self) -> Result<(), ProtocolError> {
+        self.writer
+            .shutdown()
+            .await
+            .map_err(|error| FrameError::from(error).into())
+    }
+
+    /// Get the underlying stream back.
+    pub fn into_inner(self) -> S
+    where
+        S: Unpin,
+    {
+        self.reader.unsplit(self.writer.into_inner())
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), ProtocolError> {
+        self.negotiated.check_message(&message)?;
+        write_message(&mut self.writer, &message, self.negotiated.format).await
+    }
+
+    /// Read the next message that isn't a keepalive or progress update.
+    async fn receive(&mut self) -> Result<Message, ProtocolError> {
+        let format = self.negotiated.format;
+        loop {
+            let message = match self.idle_timeout {
+                Some(timeout) => {
+                    tokio::time::timeout(timeout, read_message(&mut self.reader, format))
+                        .await
+                        .map_err(|_| ProtocolError::IdleTimeout(timeout))??
+                }
+                None => read_message(&mut self.reader, format).await?,
+            };
+            match message {
+                Message::Ping(ping) => answer_ping(&mut self.writer, ping, format).await?,
+                Message::Pong(_) => {}
+                Message::Progress(update) => self.progress.progress(&update),
+                message => return Ok(message),
+            }
+        }
+    }
+}
+
+impl<S> std::fmt::Debug for ForjjClient<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ForjjClient")
+            .field("hello", &self.hello)
+            .field("negotiated", &self.negotiated)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+    use crate::capability::NegotiationError;
+    use crate::envelope::read_message_as;
+    use crate::handshake::{server_read_hello, server_select_repo, server_send_hello};
+    use crate::messages::{
+        ErrorCode, ErrorMessage, FetchMode, Ping, Pong, ProgressMessage, ProgressPhase, PushStatus,
+        RefTargetWire, RefUpdate,
+    };
+    use crate::pack::{ObjectKind, PackReader, PackWriter};
+    use crate::testing::{ScriptedServer, TestPair, within};
+    use crate::transfer::receive_pack;
+    use forjj_storage::ObjectId;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::DuplexStream;
+
+    async fn build_pack(count: u32) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), count).await.unwrap();
+        for i in 0..count {
+            let data = vec![i as u8; 4000];
+            pack.add_object(ObjectKind::File, &ObjectId::hash(&data), &data)
+                .await
+                .unwrap();
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    fn options() -> ClientOptions {
+        ClientOptions::new(RepoRef::new("alice", "project"))
+    }
+
+    /// The server's side of a Hello exchange accepting `options()`.
+    fn hello_ok() -> HelloResponse {
+        HelloResponse {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Operations, Capability::BinaryFrames],
+            server_op_heads: vec![],
+            common_ancestor: None,
+            compression: None,
+            max_frame_size: None,
+            identity: None,
+            access: Some(AccessLevel::Read),
+            limits: ServerLimits::default(),
+            extensions: BTreeMap::new(),
+        }
+    }
+
+    /// Serve the Hello exchange for `alice/project`.
+    async fn accept(
+        stream: DuplexStream,
+    ) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>, Negotiated) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (request, version) =
+            server_read_hello(&mut reader, &mut writer, VersionRange::SUPPORTED)
+                .await
+                .unwrap();
+        server_select_repo(&mut writer, &request, |repo| repo.name == "project")
+            .await
+            .unwrap();
+        let response = HelloResponse {
+            protocol_version: version,
+            ..hello_ok()
+        };
+        let negotiated = server_send_hello(&mut writer, &request, response)
+            .await
+            .unwrap();
+        (reader, writer, negotiated)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_push() {
+        let fetched = build_pack(6).await;
+        let pushed = build_pack(3).await;
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let serve = async {
+            let (mut reader, mut writer, negotiated) = accept(server).await;
+            let format = negotiated.format;
+
+            let fetch: FetchRequest = read_message_as(&mut reader, format).await.unwrap();
+            assert_eq!(fetch.want_refs, vec!["main".to_string()]);
+            let progress = ProgressMessage {
+                phase: ProgressPhase::Counting,
+                current: 6,
+                total: Some(6),
+                bytes: None,
+            };
+            write_message(&mut writer, &progress.into(), format)
+                .await
+                .unwrap();
+            let response = FetchResponse {
+                pack_follows: true,
+                ops_to_send: vec![],
+                commit_count: 6,
+                resume_session: None,
+                refs: vec![],
+                shallow_boundary: vec![],
+                filtered: false,
+                operation_count: 0,
+                request_id: None,
+                want_errors: vec![],
+                mode: FetchMode::Operations,
+                ref_targets: vec![],
+            };
+            write_message(&mut writer, &response.into(), format)
+                .await
+                .unwrap();
+            send_pack(&mut fetched.as_slice(), &mut writer, format, 8 * 1024)
+                .await
+                .unwrap();
+
+            let push: PushRequest = read_message_as(&mut reader, format).await.unwrap();
+            assert_eq!(push.updates.len(), 1);
+            let negotiate = PushNegotiate {
+                common_op: None,
+                need_objects: true,
+                have_commits_filter: None,
+                want_commits: vec![],
+            };
+            write_message(&mut writer, &negotiate.into(), format)
+                .await
+                .unwrap();
+            let entries = receive_pack(&mut reader, format).await.unwrap();
+
+            // A keepalive while the push is applied.
+            let ping = Ping { payload: [7; 8] };
+            write_message(&mut writer, &ping.into(), format)
+                .await
+                .unwrap();
+            let pong: Pong = read_message_as(&mut reader, format).await.unwrap();
+            assert_eq!(pong.payload, [7; 8]);
+
+            let result = PushResult {
+                status: PushStatus::Ok,
+                new_op_head: None,
+                ref_results: vec![],
+                request_id: None,
+                new_objects: 0,
+                skipped_objects: 0,
+                created_repo: false,
+            };
+            write_message(&mut writer, &result.into(), format)
+                .await
+                .unwrap();
+            entries.len()
+        };
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let run = async {
+            let mut client = ForjjClient::connect(client, options()).await.unwrap();
+            assert_eq!(client.negotiated().version, PROTOCOL_VERSION);
+            assert_eq!(client.negotiated().format, WireFormat::Binary);
+            let seen = updates.clone();
+            client.set_progress(move |update: &ProgressMessage| {
+                seen.lock().unwrap().push(update.phase)
+            });
+
+            let request = FetchRequest {
+                have_ops: vec![],
+                want_refs: vec!["main".to_string()],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            };
+            let mut pack = Vec::new();
+            let outcome = client.fetch(request, &mut pack).await.unwrap();
+
+            let push = PushRequest {
+                have_ops: vec![],
+                updates: vec![RefUpdate {
+                    ref_name: "main".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some("abc".to_string()),
+                    force: false,
+                }],
+                atomic: false,
+                operation_count: 0,
+                request_id: None,
+                push_id: None,
+            };
+            let result = client.push(push, &mut pushed.as_slice()).await.unwrap();
+            (outcome, pack, result)
+        };
+
+        let (pushed_objects, (outcome, pack, result)) = tokio::join!(serve, run);
+        assert_eq!(outcome.response.commit_count, 6);
+        assert_eq!(outcome.pack_bytes, fetched.len() as u64);
+        assert_eq!(pack, fetched);
+        assert_eq!(
+            PackReader::new(pack.as_slice())
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap()
+                .len(),
+            6
+        );
+        assert_eq!(*updates.lock().unwrap(), vec![ProgressPhase::Counting]);
+        assert_eq!(pushed_objects, 3);
+        assert_eq!(result.status, PushStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reports_server_error() {
+        let TestPair { client, server } = TestPair::new();
+        let error = ErrorMessage::retryable(ErrorCode::NotFound, "no bookmark named main");
+        let script = ScriptedServer::new()
+            .expect("Hello")
+            .send(hello_ok())
+            .switch_format(WireFormat::Binary)
+            .expect("Fetch")
+            .send(error.clone());
+
+        let run = async {
+            let mut client = ForjjClient::connect(client, options()).await.unwrap();
+            let request = FetchRequest {
+                have_ops: vec![],
+                want_refs: vec!["main".to_string()],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            };
+            client.fetch(request, &mut Vec::new()).await
+        };
+
+        let (served, result) = within(async { tokio::join!(script.run(server), run) }).await;
+        let received = served.unwrap();
+        assert!(matches!(
+            &received[1],
+            Message::Fetch(request) if request.want_refs == ["main"]
+        ));
+        match result {
+            Err(ProtocolError::Remote(remote)) => assert_eq!(remote, error),
+            other => panic!("expected the server's error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_response_mismatch() {
+        let TestPair { client, server } = TestPair::new();
+        let hello = HelloResponse {
+            capabilities: vec![
+                Capability::Operations,
+                Capability::BinaryFrames,
+                Capability::Pipelining,
+            ],
+            ..hello_ok()
+        };
+        // The server answers the second request first.
+        let response = FetchResponse {
+            pack_follows: false,
+            ops_to_send: vec![],
+            commit_count: 0,
+            resume_session: None,
+            refs: vec![],
+            shallow_boundary: vec![],
+            filtered: false,
+            operation_count: 0,
+            request_id: Some(2),
+            want_errors: vec![],
+            mode: FetchMode::Operations,
+            ref_targets: vec![],
+        };
+        let script = ScriptedServer::new()
+            .expect("Hello")
+            .send(hello)
+            .switch_format(WireFormat::Binary)
+            .expect("Fetch")
+            .expect("Fetch")
+            .send(response);
+
+        let run = async {
+            let mut client = ForjjClient::connect(client, options()).await.unwrap();
+            let request = FetchRequest {
+                have_ops: vec![],
+                want_refs: vec![],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            };
+            let mut first = Vec::new();
+            let mut second = Vec::new();
+            client
+                .fetch_pipelined([(request.clone(), &mut first), (request, &mut second)])
+                .await
+        };
+
+        let (served, result) = within(async { tokio::join!(script.run(server), run) }).await;
+        let received = served.unwrap();
+        assert!(matches!(
+            &received[1..],
+            [Message::Fetch(a), Message::Fetch(b)]
+                if a.request_id == Some(1) && b.request_id == Some(2)
+        ));
+        assert!(matches!(
+            result,
+            Err(ProtocolError::ResponseMismatch {
+                expected: 1,
+                actual: Some(2)
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pipelining_needs_the_capability() {
+        let TestPair { client, server } = TestPair::new();
+        let script = ScriptedServer::new().expect("Hello").send(hello_ok());
+
+        let run = async {
+            let mut client = ForjjClient::connect(client, options()).await.unwrap();
+            client
+                .fetch_pipelined(std::iter::empty::<(FetchRequest, &mut Vec<u8>)>())
+                .await
+        };
+
+        let (served, result) = within(async { tokio::join!(script.run(server), run) }).await;
+        served.unwrap();
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Negotiation(
+                NegotiationError::MissingRequired(Capability::Pipelining)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_unknown_repo() {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let serve = async {
+            let (mut reader, mut writer) = tokio::io::split(server);
+            let (request, _) = server_read_hello(&mut reader, &mut writer, VersionRange::SUPPORTED)
+                .await
+                .unwrap();
+            server_select_repo(&mut writer, &request, |_| false)
+                .await
+                .unwrap_err();
+        };
+        let options = ClientOptions::new(RepoRef::new("alice", "missing"));
+        let (_, result) = tokio::join!(serve, ForjjClient::connect(client, options));
+        assert_eq!(result.unwrap_err().remote_code(), Some(ErrorCode::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_connect_missing_required_capability() {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let options = options()
+            .with_capabilities(vec![Capability::Operations, Capability::Resumable])
+            .with_required_capabilities(vec![Capability::Resumable]);
+
+        let (_, result) = tokio::join!(accept(server), ForjjClient::connect(client, options));
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Negotiation(
+                NegotiationError::MissingRequired(Capability::Resumable)
+            ))
+        ));
+    }
+}
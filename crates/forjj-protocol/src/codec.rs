@@ -0,0 +1,334 @@
+//! A [`tokio_util::codec`] codec for protocol messages.
+//!
+//! [`ForjjCodec`] reads and writes the same frames as
+//! [`read_message`](crate::envelope::read_message) and
+//! [`write_message`](crate::envelope::write_message), under the same
+//! [`FrameOptions`] and [`FrameLimits`], so a transport can wrap its stream
+//! in a `Framed<S, ForjjCodec>` and deal in a `Stream` and `Sink` of
+//! [`Message`]s. Raw-bytes paths such as pack chunks keep using the framing
+//! functions.
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::envelope::{Message, WireFormat, decode_message, encode_message};
+use crate::error::ProtocolError;
+use crate::framing::{FrameError, FrameLimits, FrameOptions, put_frame, unpack_flagged};
+
+/// Encodes and decodes [`Message`]s as frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForjjCodec {
+    format: WireFormat,
+    options: FrameOptions,
+    limits: FrameLimits,
+}
+
+impl ForjjCodec {
+    /// A codec for message bodies in `format`, with default framing options
+    /// and limits.
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            ..Self::default()
+        }
+    }
+
+    /// Use negotiated framing options for subsequent frames.
+    pub fn with_options(mut self, options: FrameOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Limit frames in both directions, e.g. to the negotiated limits.
+    pub fn with_limits(mut self, limits: FrameLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Switch to `format` for subsequent messages, e.g. once the Hello
+    /// exchange has picked one.
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    /// Use negotiated framing options for subsequent frames.
+    pub fn set_options(&mut self, options: FrameOptions) {
+        self.options = options;
+    }
+
+    /// Reject frames larger than `limits` allow, in both directions.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// The format message bodies are encoded in.
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// The framing options in use.
+    pub fn options(&self) -> FrameOptions {
+        self.options
+    }
+
+    /// The limits enforced on frames.
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+}
+
+impl Decoder for ForjjCodec {
+    type Item = Message;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        if src.len() < 4 {
+            src.reserve(4 - src.len());
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        // Checked before buffering, so an oversized frame is never read.
+        let max = self.limits.max_frame_size;
+        if len > max {
+            return Err(FrameError::MessageTooLarge { size: len, max }.into());
+        }
+
+        let trailer = if self.options.checksums { 4 } else { 0 };
+        let frame_len = 4 + len as usize + trailer;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let body = src.split_to(len as usize).freeze();
+        if self.options.checksums {
+            let expected = src.get_u32();
+            let actual = crc32c::crc32c(&body);
+            if expected != actual {
+                return Err(FrameError::ChecksumMismatch { expected, actual }.into());
+            }
+        }
+        let payload = if self.options.compression.is_some() {
+            unpack_flagged(body, self.limits)?
+        } else {
+            body
+        };
+        decode_message(&payload, self.format).map(Some)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        match self.decode(src)? {
+            Some(message) => Ok(Some(message)),
+            None if src.is_empty() => Ok(None),
+            None => Err(FrameError::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl Encoder<Message> for ForjjCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        self.encode(&message, dst)
+    }
+}
+
+impl Encoder<&Message> for ForjjCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        let payload = encode_message(message, self.format)?;
+        put_frame(dst, &payload, self.options, self.limits)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{read_message, write_message};
+    use crate::framing::{read_frame_with, write_frame_with};
+    use crate::messages::{
+        CompressionAlgorithm, FetchRequest, Ping, ProgressMessage, ProgressPhase,
+    };
+
+    fn messages() -> Vec<Message> {
+        let fetch = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string(); 200],
+            depth: Some(3),
+            have_commits: vec![],
+            path_filters: vec!["src/".to_string()],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: Some(7),
+            include_operations: None,
+        };
+        let progress = ProgressMessage {
+            phase: ProgressPhase::Sending,
+            current: 10,
+            total: Some(20),
+            bytes: Some(4096),
+        };
+        vec![
+            fetch.into(),
+            Ping { payload: [1; 8] }.into(),
+            progress.into(),
+        ]
+    }
+
+    fn all_options() -> Vec<FrameOptions> {
+        let mut all = Vec::new();
+        for compression in [None, Some(CompressionAlgorithm::Zstd)] {
+            for checksums in [false, true] {
+                all.push(FrameOptions {
+                    compression,
+                    checksums,
+                    ..FrameOptions::default()
+                });
+            }
+        }
+        all
+    }
+
+    /// Messages don't compare, but their encodings do.
+    fn payloads(messages: &[Message], format: WireFormat) -> Vec<Vec<u8>> {
+        messages
+            .iter()
+            .map(|message| encode_message(message, format).unwrap())
+            .collect()
+    }
+
+    fn encode_all(codec: &mut ForjjCodec, messages: &[Message]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        for message in messages {
+            codec.encode(message, &mut buffer).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_decode_one_byte_at_a_time() {
+        for options in all_options() {
+            let mut codec = ForjjCodec::new(WireFormat::Binary).with_options(options);
+            let encoded = encode_all(&mut codec, &messages());
+
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            for &byte in encoded.iter() {
+                src.extend_from_slice(&[byte]);
+                if let Some(message) = codec.decode(&mut src).unwrap() {
+                    decoded.push(message);
+                }
+            }
+            assert_eq!(
+                payloads(&decoded, WireFormat::Binary),
+                payloads(&messages(), WireFormat::Binary),
+                "{options:?}"
+            );
+            assert!(src.is_empty());
+            assert!(codec.decode_eof(&mut src).unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_codec_matches_free_functions() {
+        for options in all_options() {
+            let mut codec = ForjjCodec::new(WireFormat::Binary).with_options(options);
+            for message in messages() {
+                let encoded = encode_all(&mut codec, std::slice::from_ref(&message));
+
+                // The codec's frames are the functions' frames.
+                let payload = encode_message(&message, WireFormat::Binary).unwrap();
+                let mut written = Vec::new();
+                write_frame_with(&mut written, &payload, options)
+                    .await
+                    .unwrap();
+                assert_eq!(&encoded[..], &written[..], "{options:?}");
+
+                let frame = read_frame_with(&mut &written[..], options).await.unwrap();
+                assert_eq!(frame, payload);
+            }
+        }
+
+        // Messages read and written whole, in the Hello exchange's format.
+        let mut codec = ForjjCodec::new(WireFormat::Json);
+        let mut written = Vec::new();
+        for message in messages() {
+            write_message(&mut written, &message, WireFormat::Json)
+                .await
+                .unwrap();
+        }
+        let mut src = BytesMut::from(&written[..]);
+        let mut decoded = Vec::new();
+        while let Some(message) = codec.decode(&mut src).unwrap() {
+            decoded.push(message);
+        }
+        assert_eq!(
+            payloads(&decoded, WireFormat::Json),
+            payloads(&messages(), WireFormat::Json)
+        );
+
+        let encoded = encode_all(&mut codec, &messages());
+        let mut reader = &encoded[..];
+        for expected in payloads(&messages(), WireFormat::Json) {
+            let message = read_message(&mut reader, WireFormat::Json).await.unwrap();
+            assert_eq!(
+                encode_message(&message, WireFormat::Json).unwrap(),
+                expected
+            );
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_codec_limits() {
+        let limits = FrameLimits::new(64);
+        let mut codec = ForjjCodec::new(WireFormat::Binary).with_limits(limits);
+        let large = messages().remove(0);
+        let error = codec.encode(&large, &mut BytesMut::new()).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::Frame(FrameError::MessageTooLarge { max: 64, .. })
+        ));
+
+        // An oversized length is refused before its frame arrives.
+        let mut src = BytesMut::from(&1000u32.to_be_bytes()[..]);
+        let error = codec.decode(&mut src).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::Frame(FrameError::MessageTooLarge {
+                size: 1000,
+                max: 64
+            })
+        ));
+
+        // A frame cut short at the end of the stream.
+        let mut src = BytesMut::from(&[0, 0, 0, 8, 1][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        let error = codec.decode_eof(&mut src).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::Frame(FrameError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn test_codec_checksum_mismatch() {
+        let options = FrameOptions {
+            compression: None,
+            checksums: true,
+            ..FrameOptions::default()
+        };
+        let mut codec = ForjjCodec::new(WireFormat::Binary).with_options(options);
+        let mut encoded = encode_all(&mut codec, &messages()[1..2]);
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0xff;
+        let error = codec.decode(&mut encoded).unwrap_err();
+        assert!(matches!(
+            error,
+            ProtocolError::Frame(FrameError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+//! Copy/insert delta encoding for thin packs.
+//!
+//! Format:
+//!
+//! ```text
+//! Header: [4-byte base length][4-byte result length]
+//! Copy:   [0x01][4-byte base offset][4-byte length]
+//! Insert: [0x02][4-byte length][bytes]
+//! ```
+//!
+//! All integers are big-endian.
+
+use std::collections::HashMap;
+
+use crate::messages::Capability;
+
+const OP_COPY: u8 = 0x01;
+const OP_INSERT: u8 = 0x02;
+
+/// Size of the delta header in bytes.
+const HEADER_LEN: usize = 8;
+
+/// Length of the base blocks indexed when searching for matches.
+const BLOCK_LEN: usize = 16;
+
+/// Delta encoding errors.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DeltaError {
+    #[error("delta is truncated")]
+    Truncated,
+
+    #[error("unknown delta opcode: {0:#04x}")]
+    UnknownOpcode(u8),
+
+    #[error("delta base length mismatch: expected {expected}, got {actual}")]
+    BaseLengthMismatch { expected: u64, actual: u64 },
+
+    #[error("delta copy out of bounds: {len} bytes at offset {offset}")]
+    CopyOutOfBounds { offset: u32, len: u32 },
+
+    #[error("delta result length mismatch: expected {expected}, got {actual}")]
+    ResultLengthMismatch { expected: u64, actual: u64 },
+
+    #[error("delta result too large: {size} bytes (max {max})")]
+    ResultTooLarge { size: u64, max: u32 },
+}
+
+/// Whether thin packs may be used, given both peers' capabilities.
+///
+/// Deltas against objects the receiver already has are only sent if both
+/// sides advertise [`Capability::ThinPack`].
+pub fn thin_pack_enabled(local: &[Capability], remote: &[Capability]) -> bool {
+    local.contains(&Capability::ThinPack) && remote.contains(&Capability::ThinPack)
+}
+
+/// Encode `target` as a delta against `base`.
+///
+/// Both inputs must be smaller than 4 GiB, which pack object limits already
+/// guarantee.
+pub fn compute_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::with_capacity(HEADER_LEN + target.len() / 4);
+    delta.extend_from_slice(&(base.len() as u32).to_be_bytes());
+    delta.extend_from_slice(&(target.len() as u32).to_be_bytes());
+
+    let mut index: HashMap<&[u8], usize> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK_LEN - 1)).step_by(BLOCK_LEN) {
+        index
+            .entry(&base[offset..offset + BLOCK_LEN])
+            .or_insert(offset);
+    }
+
+    let mut insert_start = 0;
+    let mut position = 0;
+    while position + BLOCK_LEN <= target.len() {
+        let Some(&base_offset) = index.get(&target[position..position + BLOCK_LEN]) else {
+            position += 1;
+            continue;
+        };
+        let len = common_prefix_len(&base[base_offset..], &target[position..]);
+        push_insert(&mut delta, &target[insert_start..position]);
+        push_copy(&mut delta, base_offset, len);
+        position += len;
+        insert_start = position;
+    }
+    push_insert(&mut delta, &target[insert_start..]);
+
+    delta
+}
+
+/// Reconstruct an object by applying `delta` to `base`.
+///
+/// Fails if the result would exceed `max_size` bytes.
+pub fn apply_delta(base: &[u8], delta: &[u8], max_size: u32) -> Result<Vec<u8>, DeltaError> {
+    let mut input = delta;
+
+    let base_len = take_u32(&mut input)?;
+    if u64::from(base_len) != base.len() as u64 {
+        return Err(DeltaError::BaseLengthMismatch {
+            expected: u64::from(base_len),
+            actual: base.len() as u64,
+        });
+    }
+    let result_len = take_u32(&mut input)?;
+    if result_len > max_size {
+        return Err(DeltaError::ResultTooLarge {
+            size: u64::from(result_len),
+            max: max_size,
+        });
+    }
+
+    let mut result = Vec::with_capacity(result_len as usize);
+    while let Some((&opcode, rest)) = input.split_first() {
+        input = rest;
+        let bytes = match opcode {
+            OP_COPY => {
+                let offset = take_u32(&mut input)?;
+                let len = take_u32(&mut input)?;
+                let start = offset as usize;
+                start
+                    .checked_add(len as usize)
+                    .and_then(|end| base.get(start..end))
+                    .ok_or(DeltaError::CopyOutOfBounds { offset, len })?
+            }
+            OP_INSERT => {
+                let len = take_u32(&mut input)?;
+                take(&mut input, len as usize)?
+            }
+            other => return Err(DeltaError::UnknownOpcode(other)),
+        };
+
+        let actual = result.len() + bytes.len();
+        if actual > result_len as usize {
+            return Err(DeltaError::ResultLengthMismatch {
+                expected: u64::from(result_len),
+                actual: actual as u64,
+            });
+        }
+        result.extend_from_slice(bytes);
+    }
+
+    if result.len() != result_len as usize {
+        return Err(DeltaError::ResultLengthMismatch {
+            expected: u64::from(result_len),
+            actual: result.len() as u64,
+        });
+    }
+    Ok(result)
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn push_copy(delta: &mut Vec<u8>, offset: usize, len: usize) {
+    delta.push(OP_COPY);
+    delta.extend_from_slice(&(offset as u32).to_be_bytes());
+    delta.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+fn push_insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    delta.push(OP_INSERT);
+    delta.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    delta.extend_from_slice(bytes);
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], DeltaError> {
+    if input.len() < len {
+        return Err(DeltaError::Truncated);
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn take_u32(input: &mut &[u8]) -> Result<u32, DeltaError> {
+    let bytes = take(input, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u32 = 1024 * 1024;
+
+    fn sample_text(lines: usize) -> Vec<u8> {
+        (0..lines)
+            .flat_map(|i| {
+                format!("line {i}: the quick brown fox jumps over the lazy dog\n").into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base = sample_text(200);
+        let mut edited = base.clone();
+        edited[1000..1006].copy_from_slice(b"EDITED");
+        let mut appended = base.clone();
+        appended.extend_from_slice(b"one more line\n");
+        let mut prepended = b"header\n".to_vec();
+        prepended.extend_from_slice(&base);
+
+        for target in [
+            base.clone(),
+            edited,
+            appended,
+            prepended,
+            b"entirely different".to_vec(),
+            Vec::new(),
+        ] {
+            let delta = compute_delta(&base, &target);
+            assert_eq!(apply_delta(&base, &delta, MAX).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_delta_against_short_base() {
+        for base in [&b""[..], &b"tiny"[..]] {
+            let target = sample_text(3);
+            let delta = compute_delta(base, &target);
+            assert_eq!(apply_delta(base, &delta, MAX).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_similar_objects_produce_small_delta() {
+        let base = sample_text(200);
+        let mut target = base.clone();
+        target[5000] = b'!';
+
+        let delta = compute_delta(&base, &target);
+        assert!(delta.len() < 64, "delta is {} bytes", delta.len());
+    }
+
+    #[test]
+    fn test_base_length_mismatch() {
+        let delta = compute_delta(b"base data here", b"target");
+        assert_eq!(
+            apply_delta(b"other", &delta, MAX),
+            Err(DeltaError::BaseLengthMismatch {
+                expected: 14,
+                actual: 5
+            })
+        );
+    }
+
+    #[test]
+    fn test_malformed_deltas() {
+        let base = [0u8; 4];
+        let header = |result_len: u32| {
+            let mut delta = 4u32.to_be_bytes().to_vec();
+            delta.extend_from_slice(&result_len.to_be_bytes());
+            delta
+        };
+
+        assert_eq!(apply_delta(&base, &[0, 0], MAX), Err(DeltaError::Truncated));
+
+        let mut unknown = header(0);
+        unknown.push(0x7f);
+        assert_eq!(
+            apply_delta(&base, &unknown, MAX),
+            Err(DeltaError::UnknownOpcode(0x7f))
+        );
+
+        let mut out_of_bounds = header(8);
+        push_copy(&mut out_of_bounds, 2, 8);
+        assert_eq!(
+            apply_delta(&base, &out_of_bounds, MAX),
+            Err(DeltaError::CopyOutOfBounds { offset: 2, len: 8 })
+        );
+
+        let mut overlong = header(2);
+        push_insert(&mut overlong, b"abc");
+        assert!(matches!(
+            apply_delta(&base, &overlong, MAX),
+            Err(DeltaError::ResultLengthMismatch { .. })
+        ));
+
+        let mut short = header(4);
+        push_insert(&mut short, b"abc");
+        assert!(matches!(
+            apply_delta(&base, &short, MAX),
+            Err(DeltaError::ResultLengthMismatch { .. })
+        ));
+
+        assert_eq!(
+            apply_delta(&base, &header(MAX + 1), MAX),
+            Err(DeltaError::ResultTooLarge {
+                size: u64::from(MAX) + 1,
+                max: MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_thin_pack_negotiation() {
+        let thin = [Capability::Operations, Capability::ThinPack];
+        let plain = [Capability::Operations];
+        assert!(thin_pack_enabled(&thin, &thin));
+        assert!(!thin_pack_enabled(&thin, &plain));
+        assert!(!thin_pack_enabled(&plain, &thin));
+    }
+}
@@ -0,0 +1,952 @@
+//! Typed message envelope.
+//!
+//! Every frame payload starts with a one-byte message tag followed by the
+//! serialized message body:
+//!
+//! ```text
+//! [1-byte tag][body]
+//! ```
+//!
+//! Tags this peer doesn't know decode into [`Message::Unknown`] so that newer
+//! peers can add messages without breaking older ones.
+//!
+//! Bodies are encoded according to the negotiated [`WireFormat`]. The Hello
+//! exchange is always JSON so that peers can bootstrap before negotiating.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::ProtocolError;
+use crate::framing::{read_frame, write_frame};
+use crate::messages::{
+    AckReady, Cancel, CancelAck, Capability, ErrorMessage, FetchRequest, FetchResponse, HaveMore,
+    HelloRequest, HelloResponse, ListRefsRequest, ListRefsResponse, OperationRecord, PackAck,
+    PackChunk, Ping, Pong, ProgressMessage, PushNegotiate, PushRequest, PushResult, ResumeRequest,
+    ResumeResponse,
+};
+
+/// Encoding used for message bodies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// JSON, used for the Hello exchange and when binary frames aren't mutual
+    #[default]
+    Json,
+    /// Compact binary encoding (postcard)
+    Binary,
+}
+
+impl WireFormat {
+    /// Pick the wire format given both peers' capabilities.
+    ///
+    /// Binary is only used if both sides advertise [`Capability::BinaryFrames`].
+    pub fn negotiate(local: &[Capability], remote: &[Capability]) -> Self {
+        if local.contains(&Capability::BinaryFrames) && remote.contains(&Capability::BinaryFrames) {
+            WireFormat::Binary
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    /// Get the format used for a message with the given tag.
+    fn for_tag(self, tag: u8) -> Self {
+        match tag {
+            tag::HELLO | tag::HELLO_OK => WireFormat::Json,
+            _ => self,
+        }
+    }
+}
+
+/// Wire tags identifying each message type.
+pub mod tag {
+    pub const HELLO: u8 = 1;
+    pub const HELLO_OK: u8 = 2;
+    pub const FETCH: u8 = 3;
+    pub const FETCH_OK: u8 = 4;
+    pub const PUSH: u8 = 5;
+    pub const PUSH_NEGOTIATE: u8 = 6;
+    pub const PUSH_RESULT: u8 = 7;
+    pub const ERROR: u8 = 8;
+    pub const PROGRESS: u8 = 9;
+    pub const PACK_CHUNK: u8 = 10;
+    pub const PACK_ACK: u8 = 11;
+    pub const RESUME: u8 = 12;
+    pub const RESUME_OK: u8 = 13;
+    pub const PING: u8 = 14;
+    pub const PONG: u8 = 15;
+    pub const CANCEL: u8 = 16;
+    pub const CANCEL_ACK: u8 = 17;
+    pub const HAVE_MORE: u8 = 18;
+    pub const ACK_READY: u8 = 19;
+    pub const OPERATION: u8 = 20;
+    pub const LIST_REFS: u8 = 21;
+    pub const LIST_REFS_OK: u8 = 22;
+}
+
+/// A message of a type this peer doesn't understand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMessage {
+    /// The wire tag.
+    pub tag: u8,
+    /// The undecoded message body.
+    pub payload: Vec<u8>,
+}
+
+/// Top-level protocol message.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Hello(HelloRequest),
+    HelloOk(HelloResponse),
+    Fetch(FetchRequest),
+    FetchOk(FetchResponse),
+    Push(PushRequest),
+    PushNegotiate(PushNegotiate),
+    PushResult(PushResult),
+    Error(ErrorMessage),
+    Progress(ProgressMessage),
+    PackChunk(PackChunk),
+    PackAck(PackAck),
+    Resume(ResumeRequest),
+    ResumeOk(ResumeResponse),
+    Ping(Ping),
+    Pong(Pong),
+    Cancel(Cancel),
+    CancelAck(CancelAck),
+    HaveMore(HaveMore),
+    AckReady(AckReady),
+    Operation(OperationRecord),
+    ListRefs(ListRefsRequest),
+    ListRefsOk(ListRefsResponse),
+    Unknown(UnknownMessage),
+}
+
+impl Message {
+    /// Get the wire tag of this message.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Message::Hello(_) => tag::HELLO,
+            Message::HelloOk(_) => tag::HELLO_OK,
+            Message::Fetch(_) => tag::FETCH,
+            Message::FetchOk(_) => tag::FETCH_OK,
+            Message::Push(_) => tag::PUSH,
+            Message::PushNegotiate(_) => tag::PUSH_NEGOTIATE,
+            Message::PushResult(_) => tag::PUSH_RESULT,
+            Message::Error(_) => tag::ERROR,
+            Message::Progress(_) => tag::PROGRESS,
+            Message::PackChunk(_) => tag::PACK_CHUNK,
+            Message::PackAck(_) => tag::PACK_ACK,
+            Message::Resume(_) => tag::RESUME,
+            Message::ResumeOk(_) => tag::RESUME_OK,
+            Message::Ping(_) => tag::PING,
+            Message::Pong(_) => tag::PONG,
+            Message::Cancel(_) => tag::CANCEL,
+            Message::CancelAck(_) => tag::CANCEL_ACK,
+            Message::HaveMore(_) => tag::HAVE_MORE,
+            Message::AckReady(_) => tag::ACK_READY,
+            Message::Operation(_) => tag::OPERATION,
+            Message::ListRefs(_) => tag::LIST_REFS,
+            Message::ListRefsOk(_) => tag::LIST_REFS_OK,
+            Message::Unknown(unknown) => unknown.tag,
+        }
+    }
+
+    /// Get a human-readable name for this message type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "Hello",
+            Message::HelloOk(_) => "HelloOk",
+            Message::Fetch(_) => "Fetch",
+            Message::FetchOk(_) => "FetchOk",
+            Message::Push(_) => "Push",
+            Message::PushNegotiate(_) => "PushNegotiate",
+            Message::PushResult(_) => "PushResult",
+            Message::Error(_) => "Error",
+            Message::Progress(_) => "Progress",
+            Message::PackChunk(_) => "PackChunk",
+            Message::PackAck(_) => "PackAck",
+            Message::Resume(_) => "Resume",
+            Message::ResumeOk(_) => "ResumeOk",
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::Cancel(_) => "Cancel",
+            Message::CancelAck(_) => "CancelAck",
+            Message::HaveMore(_) => "HaveMore",
+            Message::AckReady(_) => "AckReady",
+            Message::Operation(_) => "Operation",
+            Message::ListRefs(_) => "ListRefs",
+            Message::ListRefsOk(_) => "ListRefsOk",
+            Message::Unknown(_) => "Unknown",
+        }
+    }
+
+    /// The protocol version that introduced this message, or `None` for
+    /// messages this peer doesn't know.
+    pub fn since_version(&self) -> Option<u32> {
+        match self {
+            Message::Hello(_)
+            | Message::HelloOk(_)
+            | Message::Fetch(_)
+            | Message::FetchOk(_)
+            | Message::Push(_)
+            | Message::PushNegotiate(_)
+            | Message::PushResult(_)
+            | Message::Error(_)
+            | Message::Progress(_)
+            | Message::PackChunk(_)
+            | Message::PackAck(_)
+            | Message::Resume(_)
+            | Message::ResumeOk(_)
+            | Message::Ping(_)
+            | Message::Pong(_)
+            | Message::Cancel(_)
+            | Message::CancelAck(_)
+            | Message::HaveMore(_)
+            | Message::AckReady(_)
+            | Message::Operation(_)
+            | Message::ListRefs(_)
+            | Message::ListRefsOk(_) => Some(1),
+            Message::Unknown(_) => None,
+        }
+    }
+}
+
+/// Implements `From<T> for Message` and `TryFrom<Message> for T` for each
+/// message body type.
+///
+/// Converting an [`Message::Error`] into any other type yields
+/// [`ProtocolError::Remote`] so callers see the peer's error directly.
+macro_rules! impl_message_conversions {
+    ($($variant:ident($body:ty)),* $(,)?) => {
+        $(
+            impl From<$body> for Message {
+                fn from(body: $body) -> Self {
+                    Message::$variant(body)
+                }
+            }
+
+            impl TryFrom<Message> for $body {
+                type Error = ProtocolError;
+
+                fn try_from(message: Message) -> Result<Self, Self::Error> {
+                    match message {
+                        Message::$variant(body) => Ok(body),
+                        Message::Error(error) => Err(ProtocolError::Remote(error)),
+                        other => Err(ProtocolError::UnexpectedMessage {
+                            expected: stringify!($variant),
+                            actual: other.name(),
+                        }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_message_conversions!(
+    Hello(HelloRequest),
+    HelloOk(HelloResponse),
+    Fetch(FetchRequest),
+    FetchOk(FetchResponse),
+    Push(PushRequest),
+    PushNegotiate(PushNegotiate),
+    PushResult(PushResult),
+    Progress(ProgressMessage),
+    PackChunk(PackChunk),
+    PackAck(PackAck),
+    Resume(ResumeRequest),
+    ResumeOk(ResumeResponse),
+    Ping(Ping),
+    Pong(Pong),
+    Cancel(Cancel),
+    CancelAck(CancelAck),
+    HaveMore(HaveMore),
+    AckReady(AckReady),
+    Operation(OperationRecord),
+    ListRefs(ListRefsRequest),
+    ListRefsOk(ListRefsResponse),
+);
+
+impl From<ErrorMessage> for Message {
+    fn from(body: ErrorMessage) -> Self {
+        Message::Error(body)
+    }
+}
+
+impl TryFrom<Message> for ErrorMessage {
+    type Error = ProtocolError;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::Error(body) => Ok(body),
+            other => Err(ProtocolError::UnexpectedMessage {
+                expected: "Error",
+                actual: other.name(),
+            }),
+        }
+    }
+}
+
+/// Encode a message into a frame payload.
+pub fn encode_message(message: &Message, format: WireFormat) -> Result<Vec<u8>, ProtocolError> {
+    let tag = message.tag();
+    let format = format.for_tag(tag);
+    let mut payload = vec![tag];
+    match message {
+        Message::Hello(body) => encode_body(&mut payload, message, body, format)?,
+        Message::HelloOk(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Fetch(body) => encode_body(&mut payload, message, body, format)?,
+        Message::FetchOk(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Push(body) => encode_body(&mut payload, message, body, format)?,
+        Message::PushNegotiate(body) => encode_body(&mut payload, message, body, format)?,
+        Message::PushResult(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Error(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Progress(body) => encode_body(&mut payload, message, body, format)?,
+        Message::PackChunk(body) => encode_body(&mut payload, message, body, format)?,
+        Message::PackAck(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Resume(body) => encode_body(&mut payload, message, body, format)?,
+        Message::ResumeOk(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Ping(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Pong(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Cancel(body) => encode_body(&mut payload, message, body, format)?,
+        Message::CancelAck(body) => encode_body(&mut payload, message, body, format)?,
+        Message::HaveMore(body) => encode_body(&mut payload, message, body, format)?,
+        Message::AckReady(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Operation(body) => encode_body(&mut payload, message, body, format)?,
+        Message::ListRefs(body) => encode_body(&mut payload, message, body, format)?,
+        Message::ListRefsOk(body) => encode_body(&mut payload, message, body, format)?,
+        Message::Unknown(unknown) => payload.extend_from_slice(&unknown.payload),
+    }
+    Ok(payload)
+}
+
+/// Decode a frame payload into a message.
+pub fn decode_message(payload: &[u8], format: WireFormat) -> Result<Message, ProtocolError> {
+    let (&tag, body) = payload.split_first().ok_or(ProtocolError::EmptyFrame)?;
+    let format = format.for_tag(tag);
+    let message = match tag {
+        tag::HELLO => Message::Hello(decode_body("Hello", body, format)?),
+        tag::HELLO_OK => Message::HelloOk(decode_body("HelloOk", body, format)?),
+        tag::FETCH => Message::Fetch(decode_body("Fetch", body, format)?),
+        tag::FETCH_OK => Message::FetchOk(decode_body("FetchOk", body, format)?),
+        tag::PUSH => Message::Push(decode_body("Push", body, format)?),
+        tag::PUSH_NEGOTIATE => Message::PushNegotiate(decode_body("PushNegotiate", body, format)?),
+        tag::PUSH_RESULT => Message::PushResult(decode_body("PushResult", body, format)?),
+        tag::ERROR => Message::Error(decode_body("Error", body, format)?),
+        tag::PROGRESS => Message::Progress(decode_body("Progress", body, format)?),
+        tag::PACK_CHUNK => Message::PackChunk(decode_body("PackChunk", body, format)?),
+        tag::PACK_ACK => Message::PackAck(decode_body("PackAck", body, format)?),
+        tag::RESUME => Message::Resume(decode_body("Resume", body, format)?),
+        tag::RESUME_OK => Message::ResumeOk(decode_body("ResumeOk", body, format)?),
+        tag::PING => Message::Ping(decode_body("Ping", body, format)?),
+        tag::PONG => Message::Pong(decode_body("Pong", body, format)?),
+        tag::CANCEL => Message::Cancel(decode_body("Cancel", body, format)?),
+        tag::CANCEL_ACK => Message::CancelAck(decode_body("CancelAck", body, format)?),
+        tag::HAVE_MORE => Message::HaveMore(decode_body("HaveMore", body, format)?),
+        tag::ACK_READY => Message::AckReady(decode_body("AckReady", body, format)?),
+        tag::OPERATION => Message::Operation(decode_body("Operation", body, format)?),
+        tag::LIST_REFS => Message::ListRefs(decode_body("ListRefs", body, format)?),
+        tag::LIST_REFS_OK => Message::ListRefsOk(decode_body("ListRefsOk", body, format)?),
+        tag => Message::Unknown(UnknownMessage {
+            tag,
+            payload: body.to_vec(),
+        }),
+    };
+    Ok(message)
+}
+
+fn encode_body<T: Serialize>(
+    payload: &mut Vec<u8>,
+    message: &Message,
+    body: &T,
+    format: WireFormat,
+) -> Result<(), ProtocolError> {
+    let encode_error = |reason: String| ProtocolError::Encode {
+        message: message.name(),
+        reason,
+    };
+    match format {
+        WireFormat::Json => {
+            serde_json::to_writer(payload, body).map_err(|e| encode_error(e.to_string()))
+        }
+        WireFormat::Binary => {
+            let bytes = postcard::to_stdvec(body).map_err(|e| encode_error(e.to_string()))?;
+            payload.extend_from_slice(&bytes);
+            Ok(())
+        }
+    }
+}
+
+fn decode_body<T: DeserializeOwned>(
+    name: &'static str,
+    body: &[u8],
+    format: WireFormat,
+) -> Result<T, ProtocolError> {
+    let decode_error = |reason: String| ProtocolError::Decode {
+        message: name,
+        reason,
+    };
+    match format {
+        WireFormat::Json => serde_json::from_slice(body).map_err(|e| decode_error(e.to_string())),
+        WireFormat::Binary => postcard::from_bytes(body).map_err(|e| decode_error(e.to_string())),
+    }
+}
+
+/// Write a message as a single frame.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+    format: WireFormat,
+) -> Result<(), ProtocolError> {
+    let payload = encode_message(message, format)?;
+    write_frame(writer, &payload).await?;
+    Ok(())
+}
+
+/// Report `error` to the peer and shut down the writer.
+///
+/// This is best-effort: the connection may already be broken, so failures to
+/// send are ignored.
+pub async fn close_with_error<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    error: ErrorMessage,
+    format: WireFormat,
+) {
+    if write_message(writer, &error.into(), format).await.is_ok() {
+        let _ = writer.shutdown().await;
+    }
+}
+
+/// Read a message whose first byte has already been consumed from `reader`.
+///
+/// Racing a one-byte read against other work is cancel-safe, unlike racing a
+/// whole [`read_message`].
+pub(crate) async fn read_message_after<R: AsyncRead + Unpin>(
+    first: u8,
+    reader: &mut R,
+    format: WireFormat,
+) -> Result<Message, ProtocolError> {
+    let prefix = [first];
+    read_message(&mut prefix.as_slice().chain(&mut *reader), format).await
+}
+
+/// Read a single message frame.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    format: WireFormat,
+) -> Result<Message, ProtocolError> {
+    let payload = read_frame(reader).await?;
+    decode_message(&payload, format)
+}
+
+/// Read a message frame and require it to be of type `T`.
+///
+/// [`Message::Progress`] frames are sideband updates and are skipped; use
+/// [`read_message_with_progress`](crate::progress::read_message_with_progress)
+/// to observe them.
+pub async fn read_message_as<T, R>(reader: &mut R, format: WireFormat) -> Result<T, ProtocolError>
+where
+    T: TryFrom<Message, Error = ProtocolError>,
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match read_message(reader, format).await? {
+            Message::Progress(_) => continue,
+            message => return message.try_into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloom::CommitFilter;
+    use crate::messages::{
+        AccessLevel, Auth, CompressionAlgorithm, ErrorCode, FetchMode, ProgressPhase, PushStatus,
+        RefInfo, RefReason, RefResult, RefStatus, RefTargetWire, RefUpdate, RepoRef, ServerLimits,
+        ViewData, WantError, WantReason,
+    };
+    use crate::testing::{TestPair, within};
+    use forjj_storage::{ObjectId, OperationId};
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    const FORMATS: [WireFormat; 2] = [WireFormat::Json, WireFormat::Binary];
+
+    fn hello() -> HelloRequest {
+        HelloRequest {
+            protocol_version: 1,
+            capabilities: vec![Capability::Operations],
+            client_op_heads: vec![OperationId::hash(b"op")],
+            compression: vec![CompressionAlgorithm::Zstd],
+            max_frame_size: Some(1024 * 1024),
+            min_protocol_version: Some(1),
+            auth: Auth::BearerToken("token".to_string()),
+            access: AccessLevel::Write,
+            repo: Some(RepoRef::new("alice", "project")),
+            create_if_missing: false,
+        }
+    }
+
+    /// One sample of every known message type.
+    fn sample_messages() -> Vec<Message> {
+        let op = OperationId::hash(b"op");
+        vec![
+            hello().into(),
+            HelloResponse {
+                protocol_version: 1,
+                capabilities: vec![Capability::BinaryFrames],
+                server_op_heads: vec![op],
+                common_ancestor: Some(op),
+                compression: Some(CompressionAlgorithm::Zstd),
+                max_frame_size: None,
+                identity: Some("alice".to_string()),
+                access: Some(AccessLevel::Write),
+                limits: ServerLimits {
+                    max_pack_size: Some(1 << 30),
+                    max_object_size: None,
+                    compression: vec![CompressionAlgorithm::Zstd],
+                    anonymous_read: Some(false),
+                },
+                extensions: BTreeMap::from([("region".to_string(), "eu".to_string())]),
+            }
+            .into(),
+            FetchRequest {
+                have_ops: vec![op, OperationId::hash(b"other")],
+                want_refs: vec!["main".to_string()],
+                depth: Some(3),
+                have_commits: vec![],
+                path_filters: vec!["src".to_string()],
+                have_commits_filter: Some(CommitFilter::for_commits(&[ObjectId::hash(b"c")])),
+                want_commits: vec![ObjectId::hash(b"wanted").to_hex(), "0a1f".to_string()],
+                request_id: None,
+                include_operations: Some(false),
+            }
+            .into(),
+            FetchResponse {
+                pack_follows: true,
+                ops_to_send: vec![op],
+                commit_count: 42,
+                resume_session: Some("0123abcd".to_string()),
+                refs: vec!["main".to_string()],
+                shallow_boundary: vec![],
+                filtered: true,
+                operation_count: 0,
+                request_id: None,
+                want_errors: vec![WantError {
+                    want: "0a1f".to_string(),
+                    reason: WantReason::Ambiguous,
+                }],
+                mode: FetchMode::CommitsOnly,
+                ref_targets: vec![RefInfo {
+                    name: "main".to_string(),
+                    target: RefTargetWire::Normal(ObjectId::hash(b"c")),
+                    is_tag: false,
+                }],
+            }
+            .into(),
+            PushRequest {
+                have_ops: vec![],
+                updates: vec![RefUpdate {
+                    ref_name: "main".to_string(),
+                    old_id: RefTargetWire::Conflicted {
+                        adds: vec![ObjectId::hash(b"left"), ObjectId::hash(b"right")],
+                        removes: vec![],
+                    },
+                    new_id: Some("abc".to_string()),
+                    force: false,
+                }],
+                atomic: true,
+                operation_count: 0,
+                request_id: None,
+                push_id: Some("9f1c2e".to_string()),
+            }
+            .into(),
+            PushNegotiate {
+                common_op: None,
+                need_objects: true,
+                have_commits_filter: Some(CommitFilter::new(8, 1).unwrap()),
+                want_commits: vec![],
+            }
+            .into(),
+            PushResult {
+                status: PushStatus::Ok,
+                new_op_head: Some(op),
+                ref_results: vec![RefResult {
+                    ref_name: "main".to_string(),
+                    status: RefStatus::Stale,
+                    message: Some("not a fast-forward".to_string()),
+                    reason: Some(RefReason::NotFastForward { behind: 2 }),
+                }],
+                request_id: None,
+                new_objects: 12,
+                skipped_objects: 3,
+                created_repo: false,
+            }
+            .into(),
+            ErrorMessage::retryable(ErrorCode::Internal, "boom").into(),
+            ProgressMessage {
+                phase: ProgressPhase::Counting,
+                current: 10,
+                total: None,
+                bytes: Some(2048),
+            }
+            .into(),
+            PackChunk {
+                sequence: 7,
+                data: vec![1, 2, 3],
+                last: true,
+                pack_hash: Some(ObjectId::hash(b"pack")),
+            }
+            .into(),
+            PackAck {
+                session_id: "0123abcd".to_string(),
+                received_bytes: 4096,
+            }
+            .into(),
+            ResumeRequest {
+                session_id: "0123abcd".to_string(),
+                received_bytes: 4096,
+                received_hash: ObjectId::hash(b"prefix"),
+            }
+            .into(),
+            ResumeResponse { offset: 4096 }.into(),
+            Ping {
+                payload: *b"keepaliv",
+            }
+            .into(),
+            Pong {
+                payload: *b"keepaliv",
+            }
+            .into(),
+            Cancel {
+                reason: Some("interrupted".to_string()),
+            }
+            .into(),
+            CancelAck {}.into(),
+            HaveMore {
+                have_ops: vec![OperationId::hash(b"op")],
+                done: false,
+            }
+            .into(),
+            AckReady {
+                common_ops: vec![OperationId::hash(b"op")],
+                ready: true,
+            }
+            .into(),
+            OperationRecord {
+                id: OperationId::hash(b"op"),
+                parents: vec![OperationId::hash(b"parent")],
+                description: "push from alice".to_string(),
+                data: b"operation".to_vec(),
+                view_id: ObjectId::hash(b"view"),
+                view: ViewData::Full(b"view".to_vec()),
+            }
+            .into(),
+            OperationRecord {
+                id: OperationId::hash(b"child"),
+                parents: vec![OperationId::hash(b"op")],
+                description: String::new(),
+                data: b"child operation".to_vec(),
+                view_id: ObjectId::hash(b"view 2"),
+                view: ViewData::Delta {
+                    base: ObjectId::hash(b"view"),
+                    delta: vec![1, 2, 3],
+                },
+            }
+            .into(),
+            ListRefsRequest {
+                patterns: vec!["release/*".to_string()],
+                request_id: Some(3),
+            }
+            .into(),
+            ListRefsResponse {
+                refs: vec![RefInfo {
+                    name: "main".to_string(),
+                    target: RefTargetWire::Conflicted {
+                        adds: vec![ObjectId::hash(b"left"), ObjectId::hash(b"right")],
+                        removes: vec![ObjectId::hash(b"base")],
+                    },
+                    is_tag: false,
+                }],
+                request_id: Some(3),
+            }
+            .into(),
+        ]
+    }
+
+    #[test]
+    fn test_every_message_roundtrips_in_both_formats() {
+        for format in FORMATS {
+            for message in sample_messages() {
+                let encoded = encode_message(&message, format).unwrap();
+                let decoded = decode_message(&encoded, format).unwrap();
+                assert_eq!(decoded.tag(), message.tag());
+                assert_eq!(
+                    encode_message(&decoded, format).unwrap(),
+                    encoded,
+                    "{} did not round-trip in {format:?}",
+                    message.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_is_smaller_than_json() {
+        let message: Message = FetchRequest {
+            have_ops: (0..100u32)
+                .map(|i| OperationId::hash(&i.to_be_bytes()))
+                .collect(),
+            want_refs: vec![],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        }
+        .into();
+        let json = encode_message(&message, WireFormat::Json).unwrap();
+        let binary = encode_message(&message, WireFormat::Binary).unwrap();
+        assert!(binary.len() * 2 < json.len());
+    }
+
+    #[test]
+    fn test_ids_are_raw_bytes_in_binary() {
+        let fetch = |count: u32| -> Message {
+            let ids = (0..count).map(|i| ObjectId::hash(&i.to_be_bytes()));
+            FetchRequest {
+                have_ops: ids.clone().take(count as usize / 2).collect(),
+                want_refs: vec![],
+                depth: None,
+                have_commits: ids.skip(count as usize / 2).collect(),
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            }
+            .into()
+        };
+        let size = |count, format| encode_message(&fetch(count), format).unwrap().len();
+
+        // Each id is a length byte and its 32 bytes in binary, and a quoted
+        // hex string and a comma in JSON.
+        assert_eq!(
+            size(1002, WireFormat::Binary) - size(1000, WireFormat::Binary),
+            2 * 33
+        );
+        assert_eq!(
+            size(1002, WireFormat::Json) - size(1000, WireFormat::Json),
+            2 * 67
+        );
+
+        // A thousand ids fit in a small fraction of a frame.
+        let binary = size(1000, WireFormat::Binary);
+        assert!(binary < 34 * 1000, "{binary} bytes");
+        assert!(binary < crate::framing::MAX_MESSAGE_SIZE as usize / 400);
+        assert!(binary * 2 < size(1000, WireFormat::Json));
+
+        // Either format reads back the same ids.
+        for format in FORMATS {
+            let encoded = encode_message(&fetch(1000), format).unwrap();
+            let decoded =
+                FetchRequest::try_from(decode_message(&encoded, format).unwrap()).unwrap();
+            let FetchRequest {
+                have_ops,
+                have_commits,
+                ..
+            } = FetchRequest::try_from(fetch(1000)).unwrap();
+            assert_eq!(decoded.have_ops, have_ops);
+            assert_eq!(decoded.have_commits, have_commits);
+        }
+    }
+
+    #[test]
+    fn test_hello_exchange_is_always_json() {
+        let binary = encode_message(&hello().into(), WireFormat::Binary).unwrap();
+        let json = encode_message(&hello().into(), WireFormat::Json).unwrap();
+        assert_eq!(binary, json);
+    }
+
+    #[test]
+    fn test_negotiate_wire_format() {
+        let binary = [Capability::BinaryFrames];
+        let none: [Capability; 0] = [];
+        assert_eq!(WireFormat::negotiate(&binary, &binary), WireFormat::Binary);
+        assert_eq!(WireFormat::negotiate(&binary, &none), WireFormat::Json);
+        assert_eq!(WireFormat::negotiate(&none, &binary), WireFormat::Json);
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let encoded = encode_message(&Message::Hello(hello()), WireFormat::Json).unwrap();
+        assert_eq!(encoded[0], tag::HELLO);
+
+        let decoded = decode_message(&encoded, WireFormat::Json).unwrap();
+        let parsed = HelloRequest::try_from(decoded).unwrap();
+        assert_eq!(parsed.protocol_version, 1);
+        assert_eq!(parsed.capabilities, vec![Capability::Operations]);
+        assert_eq!(parsed.client_op_heads, hello().client_op_heads);
+    }
+
+    #[test]
+    fn test_unknown_tag_is_preserved() {
+        let payload = [200, 1, 2, 3];
+        let decoded = decode_message(&payload, WireFormat::Binary).unwrap();
+        match &decoded {
+            Message::Unknown(unknown) => {
+                assert_eq!(unknown.tag, 200);
+                assert_eq!(unknown.payload, vec![1, 2, 3]);
+            }
+            other => panic!("expected unknown message, got {other:?}"),
+        }
+        assert_eq!(
+            encode_message(&decoded, WireFormat::Binary).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn test_empty_frame() {
+        assert!(matches!(
+            decode_message(&[], WireFormat::Json),
+            Err(ProtocolError::EmptyFrame)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_body() {
+        let result = decode_message(&[tag::HELLO, b'{'], WireFormat::Json);
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Decode {
+                message: "Hello",
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_desync_is_typed_error() {
+        let fetch = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &fetch.into(), WireFormat::Json)
+            .await
+            .unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_message_as::<HelloRequest, _>(&mut cursor, WireFormat::Json).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnexpectedMessage {
+                expected: "Hello",
+                actual: "Fetch",
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_remote_error_surfaces() {
+        let error = ErrorMessage::new(ErrorCode::NotFound, "repository not found");
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &error.into(), WireFormat::Binary)
+            .await
+            .unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_message_as::<HelloResponse, _>(&mut cursor, WireFormat::Binary).await;
+        match result {
+            Err(ProtocolError::Remote(error)) => {
+                assert_eq!(error.code, ErrorCode::NotFound);
+                assert_eq!(error.message, "repository not found");
+            }
+            other => panic!("expected remote error, got {other:?}"),
+        }
+    }
+
+    /// Minimal server that only knows about `repos`, failing otherwise.
+    async fn serve_hello<R, W>(reader: &mut R, writer: &mut W, repos: &[&str], repo: &str)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let hello: HelloRequest = read_message_as(reader, WireFormat::Json).await.unwrap();
+        if !repos.contains(&repo) {
+            let error =
+                ErrorMessage::new(ErrorCode::NotFound, format!("repository {repo} not found"));
+            close_with_error(writer, error, WireFormat::Json).await;
+            return;
+        }
+        let response = HelloResponse {
+            protocol_version: hello.protocol_version,
+            capabilities: vec![],
+            server_op_heads: vec![],
+            common_ancestor: None,
+            compression: None,
+            max_frame_size: None,
+            identity: None,
+            access: None,
+            limits: ServerLimits::default(),
+            extensions: BTreeMap::new(),
+        };
+        write_message(writer, &response.into(), WireFormat::Json)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_rejects_unknown_repo() {
+        let (mut client, mut server) = TestPair::new().split();
+
+        let serve = serve_hello(
+            &mut server.reader,
+            &mut server.writer,
+            &["alice/project"],
+            "alice/missing",
+        );
+        let connect = async {
+            write_message(&mut client.writer, &hello().into(), WireFormat::Json)
+                .await
+                .unwrap();
+            read_message_as::<HelloResponse, _>(&mut client.reader, WireFormat::Json).await
+        };
+        let ((), result) = within(async { tokio::join!(serve, connect) }).await;
+
+        let error = result.unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::NotFound));
+        assert!(error.to_string().contains("alice/missing"));
+    }
+
+    #[test]
+    fn test_local_errors_map_to_codes() {
+        let violation = ProtocolError::ChunkOutOfOrder {
+            expected: 1,
+            actual: 3,
+        };
+        assert_eq!(
+            violation.to_error_message().code,
+            ErrorCode::ProtocolViolation
+        );
+        assert_eq!(violation.remote_code(), None);
+
+        let too_large = ProtocolError::Pack(crate::pack::PackError::PackTooLarge { max: 10 });
+        assert_eq!(too_large.to_error_message().code, ErrorCode::QuotaExceeded);
+
+        let timeout = ProtocolError::IdleTimeout(std::time::Duration::from_secs(1));
+        assert!(timeout.to_error_message().retryable);
+    }
+}
@@ -0,0 +1,222 @@
+//! Protocol-level errors.
+
+use forjj_storage::{CommitId, ObjectId, OperationId};
+
+use crate::auth::AuthError;
+use crate::bloom::CommitFilterError;
+use crate::capability::NegotiationError;
+use crate::framing::FrameError;
+use crate::handshake::VersionRange;
+use crate::messages::{ErrorCode, ErrorMessage, RepoRef};
+use crate::pack::{ObjectKind, PackError};
+use crate::push_log::MAX_PUSH_ID_LEN;
+use crate::refs::RefPatternError;
+use crate::sparse::PathFilterError;
+
+/// Errors raised while exchanging protocol messages.
+///
+/// A broken connection is a [`Frame`](Self::Frame) error, and an error the
+/// peer reported is [`Remote`](Self::Remote). A peer that breaks the protocol
+/// shows up as [`Decode`](Self::Decode) for a malformed message,
+/// [`UnexpectedMessage`](Self::UnexpectedMessage) for one out of turn, or
+/// [`EmptyFrame`](Self::EmptyFrame); failed negotiation as
+/// [`UnsupportedVersion`](Self::UnsupportedVersion) or
+/// [`Negotiation`](Self::Negotiation). Each maps to the code sent to the
+/// peer by [`to_error_message`](Self::to_error_message).
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+
+    #[error("empty message frame")]
+    EmptyFrame,
+
+    #[error("failed to encode {message} message: {reason}")]
+    Encode {
+        message: &'static str,
+        reason: String,
+    },
+
+    #[error("failed to decode {message} message: {reason}")]
+    Decode {
+        message: &'static str,
+        reason: String,
+    },
+
+    #[error("unexpected {actual} message, expected {expected}")]
+    UnexpectedMessage {
+        expected: &'static str,
+        actual: &'static str,
+    },
+
+    #[error("peer reported {} error: {}", .0.code, .0.message)]
+    Remote(ErrorMessage),
+
+    #[error(transparent)]
+    Pack(#[from] PackError),
+
+    #[error("pack chunk out of order: expected sequence {expected}, got {actual}")]
+    ChunkOutOfOrder { expected: u64, actual: u64 },
+
+    #[error("pack hash in final chunk does not match the pack trailer")]
+    PackHashMismatch,
+
+    #[error("unexpected data after the end of the pack")]
+    TrailingPackData,
+
+    #[error("operation cancelled")]
+    Cancelled,
+
+    #[error("no frame received for {0:?}")]
+    IdleTimeout(std::time::Duration),
+
+    #[error("server is shutting down; try again later")]
+    ShuttingDown,
+
+    #[error("unknown or expired resume session")]
+    UnknownSession,
+
+    #[error("invalid resume offset {offset}: {available} bytes available")]
+    InvalidResumeOffset { offset: u64, available: u64 },
+
+    #[error("unsupported protocol version {requested}, supported versions are {supported}")]
+    UnsupportedVersion {
+        requested: VersionRange,
+        supported: VersionRange,
+    },
+
+    #[error("{message} message is not allowed in protocol version {version}")]
+    NotAllowedInVersion { message: &'static str, version: u32 },
+
+    #[error(transparent)]
+    Negotiation(#[from] NegotiationError),
+
+    #[error("authentication failed: {0}")]
+    Auth(#[from] AuthError),
+
+    #[error("Hello does not name a repository")]
+    NoRepoSelected,
+
+    #[error("invalid repository {repo}: {reason}")]
+    InvalidRepoName {
+        repo: RepoRef,
+        reason: forjj_storage::NameError,
+    },
+
+    #[error("repository {0} not found")]
+    RepoNotFound(RepoRef),
+
+    #[error("not allowed to create repository {0}")]
+    CreateDenied(RepoRef),
+
+    #[error("bookmark {0} not found")]
+    UnknownBookmark(String),
+
+    #[error("invalid ref pattern: {0}")]
+    InvalidRefPattern(#[from] RefPatternError),
+
+    #[error("invalid path filter: {0}")]
+    InvalidPathFilter(#[from] PathFilterError),
+
+    #[error("invalid commit filter: {0}")]
+    InvalidCommitFilter(#[from] CommitFilterError),
+
+    #[error("commit {0} not found")]
+    UnknownCommit(CommitId),
+
+    #[error("unexpected {0:?} object in pack")]
+    UnexpectedObject(ObjectKind),
+
+    #[error("pack has two different {kind:?} objects with id {id}")]
+    ConflictingObject { kind: ObjectKind, id: ObjectId },
+
+    #[error("operation {operation} has unknown parent {parent}")]
+    MissingParentOperation {
+        operation: OperationId,
+        parent: OperationId,
+    },
+
+    #[error("operation {0} does not match its record")]
+    OperationMismatch(OperationId),
+
+    #[error("response is for request {actual:?}, expected request {expected}")]
+    ResponseMismatch { expected: u64, actual: Option<u64> },
+
+    #[error("more than {0} pipelined requests waiting")]
+    TooManyRequests(usize),
+
+    #[error("push id must be 1 to {MAX_PUSH_ID_LEN} bytes, got {len}")]
+    InvalidPushId { len: usize },
+
+    #[error("pack of {size} bytes exceeds the server's limit of {max} bytes")]
+    PackOverServerLimit { size: u64, max: u64 },
+
+    #[error("storage error: {0:#}")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Frame(FrameError::Io(error))
+    }
+}
+
+impl ProtocolError {
+    /// The error code reported by the peer, if this is a remote error.
+    pub fn remote_code(&self) -> Option<ErrorCode> {
+        match self {
+            ProtocolError::Remote(error) => Some(error.code),
+            _ => None,
+        }
+    }
+
+    /// Whether to tell the peer about this error before closing the
+    /// connection: not if the peer reported it, nor if the connection is
+    /// already broken.
+    pub fn should_report(&self) -> bool {
+        !matches!(
+            self,
+            ProtocolError::Remote(_) | ProtocolError::Frame(FrameError::Io(_))
+        )
+    }
+
+    /// Build the Error frame to send to the peer before closing the
+    /// connection because of this error.
+    pub fn to_error_message(&self) -> ErrorMessage {
+        match self {
+            ProtocolError::Remote(error) => error.clone(),
+            ProtocolError::Encode { .. } => {
+                ErrorMessage::new(ErrorCode::Internal, self.to_string())
+            }
+            ProtocolError::Frame(FrameError::Io(_)) | ProtocolError::ShuttingDown => {
+                ErrorMessage::retryable(ErrorCode::Internal, self.to_string())
+            }
+            ProtocolError::IdleTimeout(_) | ProtocolError::Frame(FrameError::Timeout(_)) => {
+                ErrorMessage::retryable(ErrorCode::ProtocolViolation, self.to_string())
+            }
+            ProtocolError::Pack(
+                PackError::PackTooLarge { .. } | PackError::ObjectTooLarge { .. },
+            )
+            | ProtocolError::PackOverServerLimit { .. } => {
+                ErrorMessage::new(ErrorCode::QuotaExceeded, self.to_string())
+            }
+            ProtocolError::UnknownSession
+            | ProtocolError::RepoNotFound(_)
+            | ProtocolError::UnknownBookmark(_) => {
+                ErrorMessage::new(ErrorCode::NotFound, self.to_string())
+            }
+            // Storage errors name server paths, so the client only learns
+            // that something went wrong.
+            ProtocolError::Storage(_) => {
+                ErrorMessage::new(ErrorCode::Internal, "internal server error")
+            }
+            ProtocolError::Auth(_) | ProtocolError::CreateDenied(_) => {
+                ErrorMessage::new(ErrorCode::PermissionDenied, self.to_string())
+            }
+            ProtocolError::UnsupportedVersion { .. } => {
+                ErrorMessage::new(ErrorCode::UnsupportedVersion, self.to_string())
+            }
+            _ => ErrorMessage::new(ErrorCode::ProtocolViolation, self.to_string()),
+        }
+    }
+}
@@ -2,60 +2,632 @@
 //!
 //! Format: [4-byte big-endian length][payload]
 //! Maximum message size: 16 MB
+//!
+//! Once compression is negotiated, frames carry a flags byte ahead of the
+//! payload, and the length covers both:
+//!
+//! Format: [4-byte big-endian length][1-byte flags][payload]
+//!
+//! Once checksums are negotiated, a 4-byte big-endian CRC32C of everything
+//! after the length (flags byte included) trails each frame. The length does
+//! not cover the checksum.
+//!
+//! A peer that stops sending or reading can stall a connection forever, so
+//! network-facing code should use [`FrameStream`] or the `_timeout` variants,
+//! which fail with [`FrameError::Timeout`] instead.
 
+use std::borrow::Cow;
+use std::future::Future;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::messages::CompressionAlgorithm;
 
-/// Maximum message size (16 MB)
+/// Default maximum message size (16 MB)
 pub const MAX_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
 
+/// Payloads smaller than this are sent uncompressed by default.
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// Default share of a payload, in percent, that compressing it must save for
+/// the compressed payload to be sent.
+pub const DEFAULT_MIN_SAVINGS_PERCENT: u8 = 10;
+
+/// zstd compression level for frame payloads.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Bytes a [`FrameReader`] allocates for a frame before more of it arrives.
+pub const INITIAL_READ_CAPACITY: usize = 8 * 1024;
+
+/// Default time allowed for reading or writing a single frame.
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Default time allowed for a whole session.
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Frame flag bits.
+pub mod flags {
+    /// Payload is zstd-compressed.
+    pub const ZSTD: u8 = 0x01;
+
+    /// All flag bits understood by this implementation.
+    pub const KNOWN: u8 = ZSTD;
+}
+
+/// Per-session framing options, negotiated during the Hello exchange.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameOptions {
+    /// Compress large payloads with this algorithm. Enables the flags byte.
+    pub compression: Option<CompressionAlgorithm>,
+    /// Append a CRC32C checksum to each frame.
+    pub checksums: bool,
+    /// Which payloads are worth compressing.
+    pub policy: CompressionPolicy,
+}
+
+/// Which payloads the compressed write path compresses.
+///
+/// Payloads under the threshold, and payloads that don't compress well
+/// enough (e.g. already compressed file content), are sent as they are with
+/// a flags byte of 0; readers take either kind of frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    /// Payloads smaller than this many bytes aren't compressed
+    pub threshold: usize,
+    /// The compressed payload is sent only if it is at least this many
+    /// percent smaller
+    pub min_savings_percent: u8,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl CompressionPolicy {
+    /// [`COMPRESSION_THRESHOLD`] and [`DEFAULT_MIN_SAVINGS_PERCENT`].
+    pub const DEFAULT: Self = Self {
+        threshold: COMPRESSION_THRESHOLD,
+        min_savings_percent: DEFAULT_MIN_SAVINGS_PERCENT,
+    };
+
+    /// Whether compressing `original` bytes down to `compressed` saves
+    /// enough to send them compressed.
+    pub fn worth_sending(&self, original: usize, compressed: usize) -> bool {
+        let kept = 100 - u64::from(self.min_savings_percent.min(100));
+        compressed as u64 * 100 <= original as u64 * kept
+    }
+}
+
+static FRAMES_COMPRESSED: AtomicU64 = AtomicU64::new(0);
+static FRAMES_INCOMPRESSIBLE: AtomicU64 = AtomicU64::new(0);
+static BYTES_SAVED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the compressed write path, across every writer in the
+/// process, as returned by [`compression_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Frames sent compressed
+    pub frames_compressed: u64,
+    /// Frames over the threshold sent as they were, because compressing
+    /// them didn't save enough
+    pub frames_incompressible: u64,
+    /// Payload bytes compression saved
+    pub bytes_saved: u64,
+}
+
+/// The compression counters so far, e.g. for a metrics endpoint.
+pub fn compression_stats() -> CompressionStats {
+    CompressionStats {
+        frames_compressed: FRAMES_COMPRESSED.load(Ordering::Relaxed),
+        frames_incompressible: FRAMES_INCOMPRESSIBLE.load(Ordering::Relaxed),
+        bytes_saved: BYTES_SAVED.load(Ordering::Relaxed),
+    }
+}
+
+/// Per-session size limits.
+///
+/// A reader rejects frames over its own limit before allocating for them. A
+/// writer should use the smaller of its own limit and the peer's advertised
+/// one (see [`FrameLimits::negotiate`]) so it never sends a frame the peer
+/// would reject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    /// Largest frame body, and largest decompressed payload, in bytes.
+    pub max_frame_size: u32,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Limits with the given maximum frame size.
+    pub fn new(max_frame_size: u32) -> Self {
+        Self { max_frame_size }
+    }
+
+    /// The limits to send under, given the maximum the peer advertised.
+    ///
+    /// Peers that don't advertise a maximum accept [`MAX_MESSAGE_SIZE`].
+    pub fn negotiate(self, peer_max: Option<u32>) -> Self {
+        let peer_max = peer_max.unwrap_or(MAX_MESSAGE_SIZE);
+        Self::new(self.max_frame_size.min(peer_max))
+    }
+}
+
 /// Framing errors.
 #[derive(Debug, thiserror::Error)]
 pub enum FrameError {
-    #[error("message too large: {size} bytes (max {MAX_MESSAGE_SIZE})")]
-    MessageTooLarge { size: u32 },
+    #[error("message too large: {size} bytes (max {max})")]
+    MessageTooLarge { size: u32, max: u32 },
 
     #[error("unexpected end of stream")]
     UnexpectedEof,
 
+    #[error("decompressed message exceeds {max} bytes")]
+    DecompressedTooLarge { max: u32 },
+
+    #[error("failed to decompress frame: {0}")]
+    Decompress(String),
+
+    #[error("unknown frame flags: {0:#04x}")]
+    UnknownFlags(u8),
+
+    #[error("frame checksum mismatch: expected {expected:#010x}, got {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
 
-/// Write a length-prefixed frame.
-pub async fn write_frame<W: AsyncWrite + Unpin>(
-    writer: &mut W,
-    data: &[u8],
-) -> Result<(), FrameError> {
-    let len = data.len() as u32;
-    if len > MAX_MESSAGE_SIZE {
-        return Err(FrameError::MessageTooLarge { size: len });
+/// Deadlines applied by a [`FrameStream`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTimeouts {
+    /// Time allowed for reading or writing one frame.
+    pub frame: Option<Duration>,
+    /// Time allowed for the whole session, starting when the stream is
+    /// created.
+    pub session: Option<Duration>,
+}
+
+impl Default for FrameTimeouts {
+    fn default() -> Self {
+        Self {
+            frame: Some(DEFAULT_FRAME_TIMEOUT),
+            session: Some(DEFAULT_SESSION_TIMEOUT),
+        }
     }
+}
 
-    writer.write_u32(len).await?;
-    writer.write_all(data).await?;
-    writer.flush().await?;
+impl FrameTimeouts {
+    /// No deadlines at all.
+    pub const NONE: Self = Self {
+        frame: None,
+        session: None,
+    };
+}
 
-    Ok(())
+/// A stream that reads and writes frames under per-frame and per-session
+/// deadlines.
+///
+/// Whichever deadline comes first applies; the error reports the limit that
+/// expired. A frame that times out midway leaves the stream in an unknown
+/// state, so the connection should be closed.
+#[derive(Debug)]
+pub struct FrameStream<S> {
+    inner: S,
+    options: FrameOptions,
+    limits: FrameLimits,
+    timeouts: FrameTimeouts,
+    session_deadline: Option<Instant>,
 }
 
-/// Read a length-prefixed frame, allocating memory for it.
-pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, FrameError> {
-    let len = match reader.read_u32().await {
-        Ok(len) => len,
-        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-            return Err(FrameError::UnexpectedEof);
+impl<S> FrameStream<S> {
+    /// Wrap a stream. The session deadline starts now.
+    pub fn new(inner: S, timeouts: FrameTimeouts) -> Self {
+        Self {
+            inner,
+            options: FrameOptions::default(),
+            limits: FrameLimits::default(),
+            session_deadline: timeouts.session.map(|session| Instant::now() + session),
+            timeouts,
+        }
+    }
+
+    /// Use negotiated framing options for subsequent frames.
+    pub fn set_options(&mut self, options: FrameOptions) {
+        self.options = options;
+    }
+
+    /// The framing options currently in use.
+    pub fn options(&self) -> FrameOptions {
+        self.options
+    }
+
+    /// Use negotiated limits for subsequent frames, in both directions.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// The limits enforced on frames read and written.
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+
+    /// The deadlines this stream enforces.
+    pub fn timeouts(&self) -> FrameTimeouts {
+        self.timeouts
+    }
+
+    /// The wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The wrapped stream, mutably. Writing to it directly bypasses framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Run `io` under whichever deadline comes first.
+    async fn with_deadline<T>(
+        timeouts: FrameTimeouts,
+        session_deadline: Option<Instant>,
+        io: impl Future<Output = Result<T, FrameError>>,
+    ) -> Result<T, FrameError> {
+        let frame_deadline = timeouts.frame.map(|frame| (Instant::now() + frame, frame));
+        let session_deadline = session_deadline.zip(timeouts.session);
+        let deadline = match (frame_deadline, session_deadline) {
+            (Some(frame), Some(session)) => Some(frame.min(session)),
+            (frame, session) => frame.or(session),
+        };
+
+        match deadline {
+            Some((at, limit)) => tokio::time::timeout_at(at, io)
+                .await
+                .map_err(|_| FrameError::Timeout(limit))?,
+            None => io.await,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> FrameStream<S> {
+    /// Read a frame, failing with [`FrameError::Timeout`] if a deadline
+    /// passes first.
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut reader = FrameReader::with_options(&mut self.inner, self.options);
+        reader.set_limits(self.limits);
+        let io = async move { reader.read_frame().await.map(Vec::from) };
+        Self::with_deadline(self.timeouts, self.session_deadline, io).await
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FrameStream<S> {
+    /// Write a frame, failing with [`FrameError::Timeout`] if a deadline
+    /// passes first, e.g. because the peer stopped reading.
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        let mut writer = FrameWriter::with_options(&mut self.inner, self.options);
+        writer.set_limits(self.limits);
+        let io = async move { writer.write_frame(data).await };
+        Self::with_deadline(self.timeouts, self.session_deadline, io).await
+    }
+}
+
+/// Reads frames into an internal buffer that is reused across frames.
+///
+/// Each frame is returned as [`Bytes`] split off the buffer, so it stays valid
+/// after later reads. Once a returned frame has been dropped, its storage is
+/// reused for the next one instead of allocating. The buffer is never zeroed;
+/// it starts at [`INITIAL_READ_CAPACITY`] and doubles as a frame arrives, up
+/// to the frame's length. Only the frame itself is consumed from the
+/// reader.
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    buffer: BytesMut,
+    options: FrameOptions,
+    limits: FrameLimits,
+}
+
+impl<R> FrameReader<R> {
+    /// Wrap a reader, using default framing options.
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, FrameOptions::default())
+    }
+
+    /// Wrap a reader, using the given framing options.
+    pub fn with_options(inner: R, options: FrameOptions) -> Self {
+        Self {
+            inner,
+            buffer: BytesMut::new(),
+            options,
+            limits: FrameLimits::default(),
+        }
+    }
+
+    /// Use negotiated framing options for subsequent frames.
+    pub fn set_options(&mut self, options: FrameOptions) {
+        self.options = options;
+    }
+
+    /// Reject frames larger than `limits` allow.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// The limits enforced on incoming frames.
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+
+    /// The wrapped reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The wrapped reader, mutably.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Unwrap the reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Read the next frame.
+    pub async fn read_frame(&mut self) -> Result<Bytes, FrameError> {
+        let len = read_len(&mut self.inner, self.limits).await? as usize;
+
+        self.buffer.clear();
+        let mut body = (&mut self.inner).take(len as u64);
+        while self.buffer.len() < len {
+            // Grow as the frame arrives rather than trusting its length, so
+            // a peer must send a large frame to make us allocate for one.
+            if self.buffer.len() == self.buffer.capacity() {
+                let grow = self.buffer.len().max(INITIAL_READ_CAPACITY);
+                self.buffer.reserve(grow.min(len - self.buffer.len()));
+            }
+            if body.read_buf(&mut self.buffer).await? == 0 {
+                return Err(FrameError::UnexpectedEof);
+            }
+        }
+        let frame = self.buffer.split().freeze();
+
+        if self.options.checksums {
+            verify_checksum(&mut self.inner, &frame).await?;
+        }
+
+        if self.options.compression.is_some() {
+            unpack_flagged(frame, self.limits)
+        } else {
+            Ok(frame)
+        }
+    }
+}
+
+/// Writes frames, each with a single vectored write where the writer supports
+/// it.
+///
+/// The length prefix and flags byte are built in an internal buffer that is
+/// reused across frames; the payload itself is never copied.
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    inner: W,
+    header: BytesMut,
+    options: FrameOptions,
+    limits: FrameLimits,
+}
+
+impl<W> FrameWriter<W> {
+    /// Wrap a writer, using default framing options.
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, FrameOptions::default())
+    }
+
+    /// Wrap a writer, using the given framing options.
+    pub fn with_options(inner: W, options: FrameOptions) -> Self {
+        Self {
+            inner,
+            header: BytesMut::with_capacity(5),
+            options,
+            limits: FrameLimits::default(),
+        }
+    }
+
+    /// Use negotiated framing options for subsequent frames.
+    pub fn set_options(&mut self, options: FrameOptions) {
+        self.options = options;
+    }
+
+    /// Refuse to send frames larger than `limits` allow. Pass the negotiated
+    /// limits so the peer never receives a frame it would reject.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// The limits enforced on outgoing frames.
+    pub fn limits(&self) -> FrameLimits {
+        self.limits
+    }
+
+    /// The wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The wrapped writer, mutably.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwrap the writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Write a frame and flush it.
+    pub async fn write_frame(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        self.send_frame(data).await?;
+        self.flush().await
+    }
+
+    /// Write a frame without flushing, so a run of frames such as pack
+    /// chunks can go out together; [`flush`](Self::flush) after the last.
+    pub async fn send_frame(&mut self, data: &[u8]) -> Result<(), FrameError> {
+        let (frame_flags, payload) = prepare_payload(data, self.options, self.limits)?;
+        let len = (payload.len() + usize::from(frame_flags.is_some())) as u32;
+
+        self.header.clear();
+        self.header.put_u32(len);
+        if let Some(frame_flags) = frame_flags {
+            self.header.put_u8(frame_flags);
+        }
+
+        let checksum = if self.options.checksums {
+            let crc = crc32c::crc32c_append(crc32c::crc32c(&self.header[4..]), &payload);
+            Some(crc.to_be_bytes())
+        } else {
+            None
+        };
+        let header: &[u8] = &self.header;
+        let trailer: &[u8] = match &checksum {
+            Some(crc) => crc,
+            None => &[],
+        };
+
+        let mut frame = Buf::chain(header, &*payload).chain(trailer);
+        self.inner.write_all_buf(&mut frame).await?;
+        Ok(())
+    }
+
+    /// Flush frames written with [`send_frame`](Self::send_frame).
+    pub async fn flush(&mut self) -> Result<(), FrameError> {
+        self.inner.flush().await?;
+        Ok(())
+    }
+}
+
+/// Check `data` against `limits` and compress it as `options` ask,
+/// returning the flags byte to send, if any, and the payload.
+fn prepare_payload(
+    data: &[u8],
+    options: FrameOptions,
+    limits: FrameLimits,
+) -> Result<(Option<u8>, Cow<'_, [u8]>), FrameError> {
+    let max = limits.max_frame_size;
+    if data.len() > max as usize {
+        return Err(FrameError::MessageTooLarge {
+            size: u32::try_from(data.len()).unwrap_or(u32::MAX),
+            max,
+        });
+    }
+
+    let (frame_flags, payload) = match options.compression {
+        None => (None, Cow::Borrowed(data)),
+        Some(_) if data.len() < options.policy.threshold => (Some(0), Cow::Borrowed(data)),
+        Some(CompressionAlgorithm::Zstd) => {
+            let compressed = zstd::bulk::compress(data, ZSTD_LEVEL)?;
+            if options.policy.worth_sending(data.len(), compressed.len()) {
+                FRAMES_COMPRESSED.fetch_add(1, Ordering::Relaxed);
+                let saved = data.len() - compressed.len();
+                BYTES_SAVED.fetch_add(saved as u64, Ordering::Relaxed);
+                (Some(flags::ZSTD), Cow::Owned(compressed))
+            } else {
+                FRAMES_INCOMPRESSIBLE.fetch_add(1, Ordering::Relaxed);
+                (Some(0), Cow::Borrowed(data))
+            }
         }
-        Err(e) => return Err(FrameError::Io(e)),
     };
 
-    if len > MAX_MESSAGE_SIZE {
-        return Err(FrameError::MessageTooLarge { size: len });
+    let len = (payload.len() + usize::from(frame_flags.is_some())) as u32;
+    if len > max {
+        return Err(FrameError::MessageTooLarge { size: len, max });
     }
+    Ok((frame_flags, payload))
+}
 
-    let mut buffer = vec![0u8; len as usize];
-    reader.read_exact(&mut buffer).await?;
+/// Append `data` to `dst` as a whole frame, as a [`FrameWriter`] with the
+/// same options and limits would write it.
+pub(crate) fn put_frame(
+    dst: &mut BytesMut,
+    data: &[u8],
+    options: FrameOptions,
+    limits: FrameLimits,
+) -> Result<(), FrameError> {
+    let (frame_flags, payload) = prepare_payload(data, options, limits)?;
+    let len = payload.len() + usize::from(frame_flags.is_some());
+    dst.reserve(4 + len + if options.checksums { 4 } else { 0 });
+    dst.put_u32(len as u32);
+    let body_start = dst.len();
+    if let Some(frame_flags) = frame_flags {
+        dst.put_u8(frame_flags);
+    }
+    dst.put_slice(&payload);
+    if options.checksums {
+        let crc = crc32c::crc32c(&dst[body_start..]);
+        dst.put_u32(crc);
+    }
+    Ok(())
+}
 
-    Ok(buffer)
+/// Write a length-prefixed frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+) -> Result<(), FrameError> {
+    write_frame_with(writer, data, FrameOptions::default()).await
+}
+
+/// Read a length-prefixed frame, allocating memory for it.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, FrameError> {
+    read_frame_with(reader, FrameOptions::default()).await
+}
+
+/// Read a frame, failing with [`FrameError::Timeout`] if it doesn't arrive
+/// in full within `timeout`.
+pub async fn read_frame_timeout<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    timeout: Duration,
+) -> Result<Vec<u8>, FrameError> {
+    tokio::time::timeout(timeout, read_frame(reader))
+        .await
+        .map_err(|_| FrameError::Timeout(timeout))?
+}
+
+/// Write a frame, failing with [`FrameError::Timeout`] if it can't be written
+/// within `timeout`.
+pub async fn write_frame_timeout<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    timeout: Duration,
+) -> Result<(), FrameError> {
+    tokio::time::timeout(timeout, write_frame(writer, data))
+        .await
+        .map_err(|_| FrameError::Timeout(timeout))?
 }
 
 /// Read a frame into a provided buffer.
@@ -63,6 +635,108 @@ pub async fn read_frame_into<R: AsyncRead + Unpin>(
     reader: &mut R,
     buffer: &mut [u8],
 ) -> Result<usize, FrameError> {
+    read_frame_into_with(
+        reader,
+        buffer,
+        FrameOptions::default(),
+        FrameLimits::default(),
+    )
+    .await
+}
+
+/// Write a frame with a flags byte, compressing the payload as the default
+/// [`CompressionPolicy`] allows: if it is at least
+/// [`COMPRESSION_THRESHOLD`] bytes and compresses well enough.
+pub async fn write_frame_compressed<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    algorithm: CompressionAlgorithm,
+) -> Result<(), FrameError> {
+    let options = FrameOptions {
+        compression: Some(algorithm),
+        ..FrameOptions::default()
+    };
+    write_frame_with(writer, data, options).await
+}
+
+/// Read a frame written by [`write_frame_compressed`], decompressing it if
+/// needed.
+///
+/// The decompressed size is limited to [`MAX_MESSAGE_SIZE`] as well, so a
+/// small frame can't expand into an unbounded allocation.
+pub async fn read_frame_compressed<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Vec<u8>, FrameError> {
+    let options = FrameOptions {
+        compression: Some(CompressionAlgorithm::Zstd),
+        ..FrameOptions::default()
+    };
+    read_frame_with(reader, options).await
+}
+
+/// Write a frame using the given session options.
+///
+/// Use a [`FrameWriter`] instead when writing many frames.
+pub async fn write_frame_with<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    options: FrameOptions,
+) -> Result<(), FrameError> {
+    FrameWriter::with_options(writer, options)
+        .write_frame(data)
+        .await
+}
+
+/// Read a frame using the given session options, allocating memory for it.
+///
+/// Use a [`FrameReader`] instead when reading many frames.
+pub async fn read_frame_with<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    options: FrameOptions,
+) -> Result<Vec<u8>, FrameError> {
+    let frame = FrameReader::with_options(reader, options)
+        .read_frame()
+        .await?;
+    Ok(frame.into())
+}
+
+/// Read a frame into a provided buffer using the given session options and
+/// limits.
+pub async fn read_frame_into_with<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    options: FrameOptions,
+    limits: FrameLimits,
+) -> Result<usize, FrameError> {
+    if options.compression.is_some() {
+        // The decompressed size isn't known until the frame has been read.
+        let mut frames = FrameReader::with_options(reader, options);
+        frames.set_limits(limits);
+        let data = frames.read_frame().await?;
+        let target = buffer.get_mut(..data.len()).ok_or_else(buffer_too_small)?;
+        target.copy_from_slice(&data);
+        return Ok(data.len());
+    }
+
+    let len = read_len(reader, limits).await? as usize;
+    if len > buffer.len() {
+        return Err(buffer_too_small());
+    }
+
+    reader.read_exact(&mut buffer[..len]).await?;
+
+    if options.checksums {
+        verify_checksum(reader, &buffer[..len]).await?;
+    }
+
+    Ok(len)
+}
+
+/// Read and validate a frame length prefix.
+async fn read_len<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: FrameLimits,
+) -> Result<u32, FrameError> {
     let len = match reader.read_u32().await {
         Ok(len) => len,
         Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
@@ -71,26 +745,70 @@ pub async fn read_frame_into<R: AsyncRead + Unpin>(
         Err(e) => return Err(FrameError::Io(e)),
     };
 
-    if len > MAX_MESSAGE_SIZE {
-        return Err(FrameError::MessageTooLarge { size: len });
+    let max = limits.max_frame_size;
+    if len > max {
+        return Err(FrameError::MessageTooLarge { size: len, max });
     }
 
-    let len = len as usize;
-    if len > buffer.len() {
-        return Err(FrameError::Io(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "buffer too small",
-        )));
+    Ok(len)
+}
+
+/// Read a trailing CRC32C and check it against the frame body.
+async fn verify_checksum<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    body: &[u8],
+) -> Result<(), FrameError> {
+    let expected = reader.read_u32().await?;
+    let actual = crc32c::crc32c(body);
+    if expected != actual {
+        return Err(FrameError::ChecksumMismatch { expected, actual });
     }
+    Ok(())
+}
 
-    reader.read_exact(&mut buffer[..len]).await?;
+/// Strip the flags byte from a frame body and decompress it if needed.
+pub(crate) fn unpack_flagged(body: Bytes, limits: FrameLimits) -> Result<Bytes, FrameError> {
+    let frame_flags = *body.first().ok_or(FrameError::UnexpectedEof)?;
 
-    Ok(len)
+    if frame_flags & !flags::KNOWN != 0 {
+        return Err(FrameError::UnknownFlags(frame_flags));
+    }
+
+    if frame_flags & flags::ZSTD != 0 {
+        decompress_zstd(&body[1..], limits).map(Bytes::from)
+    } else {
+        Ok(body.slice(1..))
+    }
+}
+
+/// Decompress a zstd payload, enforcing the frame size limit on the output.
+fn decompress_zstd(payload: &[u8], limits: FrameLimits) -> Result<Vec<u8>, FrameError> {
+    let max = limits.max_frame_size;
+    // Corrupt input is the peer's fault, not a broken connection.
+    let corrupt = |error: std::io::Error| FrameError::Decompress(error.to_string());
+    let decoder = zstd::stream::read::Decoder::new(payload).map_err(corrupt)?;
+    let mut output = Vec::new();
+    decoder
+        .take(u64::from(max) + 1)
+        .read_to_end(&mut output)
+        .map_err(corrupt)?;
+    if output.len() > max as usize {
+        return Err(FrameError::DecompressedTooLarge { max });
+    }
+    Ok(output)
+}
+
+fn buffer_too_small() -> FrameError {
+    FrameError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "buffer too small",
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TestPair;
     use std::io::Cursor;
 
     #[tokio::test]
@@ -130,4 +848,779 @@ mod tests {
 
         assert_eq!(&read_buffer[..len], message);
     }
+
+    async fn compressed_roundtrip(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut buffer = Vec::new();
+        write_frame_compressed(&mut buffer, data, CompressionAlgorithm::Zstd)
+            .await
+            .unwrap();
+        let wire_len = buffer.len();
+
+        let mut cursor = Cursor::new(buffer);
+        (read_frame_compressed(&mut cursor).await.unwrap(), wire_len)
+    }
+
+    #[tokio::test]
+    async fn test_compressed_frame_roundtrip() {
+        let data = b"forjj ".repeat(1000);
+        let (result, wire_len) = compressed_roundtrip(&data).await;
+        assert_eq!(result, data);
+        assert!(wire_len < data.len() / 10);
+    }
+
+    #[tokio::test]
+    async fn test_incompressible_frame_roundtrip() {
+        // Pseudo-random bytes from a simple LCG don't compress.
+        let mut state = 0x2545_f491_u32;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 24) as u8
+            })
+            .collect();
+        let (result, _) = compressed_roundtrip(&data).await;
+        assert_eq!(result, data);
+    }
+
+    #[tokio::test]
+    async fn test_small_frame_is_not_compressed() {
+        let data = b"tiny";
+        let mut buffer = Vec::new();
+        write_frame_compressed(&mut buffer, data, CompressionAlgorithm::Zstd)
+            .await
+            .unwrap();
+        assert_eq!(buffer[4], 0);
+        assert_eq!(&buffer[5..], data);
+
+        let mut cursor = Cursor::new(buffer);
+        assert_eq!(read_frame_compressed(&mut cursor).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_decompression_bomb_rejected() {
+        // A few KB of zstd expanding to more than MAX_MESSAGE_SIZE.
+        let bomb = vec![0u8; MAX_MESSAGE_SIZE as usize + 1];
+        let compressed = zstd::bulk::compress(&bomb, ZSTD_LEVEL).unwrap();
+        assert!(compressed.len() < 64 * 1024);
+
+        let mut frame = vec![flags::ZSTD];
+        frame.extend_from_slice(&compressed);
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &frame).await.unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_frame_compressed(&mut cursor).await;
+        assert!(matches!(
+            result,
+            Err(FrameError::DecompressedTooLarge {
+                max: MAX_MESSAGE_SIZE
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_frame_flags_rejected() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &[0x80, 1, 2, 3]).await.unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_frame_compressed(&mut cursor).await;
+        assert!(matches!(result, Err(FrameError::UnknownFlags(0x80))));
+    }
+
+    const CHECKED: FrameOptions = FrameOptions {
+        compression: None,
+        checksums: true,
+        policy: CompressionPolicy::DEFAULT,
+    };
+
+    #[tokio::test]
+    async fn test_checksummed_frame_roundtrip() {
+        let message = b"checked payload";
+
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, message, CHECKED)
+            .await
+            .unwrap();
+        assert_eq!(buffer.len(), 4 + message.len() + 4);
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_frame_with(&mut cursor, CHECKED).await.unwrap();
+        assert_eq!(result, message);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_spans_write_chunks() {
+        let message = vec![0x3c; 128 * 1024 + 5];
+
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, &message, CHECKED)
+            .await
+            .unwrap();
+        let trailer = u32::from_be_bytes(buffer[buffer.len() - 4..].try_into().unwrap());
+        assert_eq!(trailer, crc32c::crc32c(&message));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_frame_detected() {
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, b"checked payload", CHECKED)
+            .await
+            .unwrap();
+        buffer[6] ^= 0x01;
+
+        let mut cursor = Cursor::new(buffer);
+        let result = read_frame_with(&mut cursor, CHECKED).await;
+        assert!(matches!(result, Err(FrameError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn test_corrupt_compressed_frame() {
+        let body = Bytes::from_static(&[flags::ZSTD, 0x28, 0xb5, 0x2f, 0xfd, 0xff, 0xff]);
+        let result = unpack_flagged(body, FrameLimits::default());
+        assert!(matches!(result, Err(FrameError::Decompress(_))));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_frame_detected_by_read_into() {
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, b"checked payload", CHECKED)
+            .await
+            .unwrap();
+        let last = buffer.len() - 1;
+        buffer[last] ^= 0xff;
+
+        let mut cursor = Cursor::new(buffer);
+        let mut read_buffer = [0u8; 64];
+        let result = read_frame_into_with(
+            &mut cursor,
+            &mut read_buffer,
+            CHECKED,
+            FrameLimits::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(FrameError::ChecksumMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_checksummed_frame() {
+        let options = FrameOptions {
+            compression: Some(CompressionAlgorithm::Zstd),
+            checksums: true,
+            ..FrameOptions::default()
+        };
+        let data = b"compress and check ".repeat(100);
+
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, &data, options).await.unwrap();
+
+        let mut cursor = Cursor::new(buffer.clone());
+        assert_eq!(read_frame_with(&mut cursor, options).await.unwrap(), data);
+
+        // Corrupt the flags byte.
+        buffer[4] ^= 0x02;
+        let mut cursor = Cursor::new(buffer);
+        let result = read_frame_with(&mut cursor, options).await;
+        assert!(matches!(result, Err(FrameError::ChecksumMismatch { .. })));
+    }
+
+    fn noise_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_incompressible_frame_sent_as_is() {
+        let data = noise_bytes(4096);
+        let before = compression_stats();
+
+        let mut buffer = Vec::new();
+        write_frame_compressed(&mut buffer, &data, CompressionAlgorithm::Zstd)
+            .await
+            .unwrap();
+        assert_eq!(buffer[4], 0, "random data is not worth compressing");
+        assert_eq!(&buffer[5..], &data[..]);
+        assert!(compression_stats().frames_incompressible > before.frames_incompressible);
+
+        let mut cursor = Cursor::new(buffer);
+        assert_eq!(read_frame_compressed(&mut cursor).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_compressible_frame_counts_savings() {
+        let data = b"the same line again\n".repeat(200);
+        let before = compression_stats();
+
+        let mut buffer = Vec::new();
+        write_frame_compressed(&mut buffer, &data, CompressionAlgorithm::Zstd)
+            .await
+            .unwrap();
+        assert_eq!(buffer[4], flags::ZSTD);
+
+        let after = compression_stats();
+        assert!(after.frames_compressed > before.frames_compressed);
+        let saved = (data.len() - (buffer.len() - 5)) as u64;
+        assert!(after.bytes_saved >= before.bytes_saved + saved);
+    }
+
+    #[tokio::test]
+    async fn test_compression_policy_threshold() {
+        let options = FrameOptions {
+            compression: Some(CompressionAlgorithm::Zstd),
+            policy: CompressionPolicy {
+                threshold: 16 * 1024,
+                ..CompressionPolicy::default()
+            },
+            ..FrameOptions::default()
+        };
+        let small = b"compressible ".repeat(200);
+        let large = b"compressible ".repeat(2000);
+
+        let mut writer = FrameWriter::with_options(Vec::new(), options);
+        writer.write_frame(&small).await.unwrap();
+        writer.write_frame(&large).await.unwrap();
+        let buffer = writer.into_inner();
+        assert_eq!(buffer[4], 0, "under the custom threshold");
+        assert_eq!(buffer[5 + small.len() + 4], flags::ZSTD);
+
+        let mut reader = FrameReader::with_options(Cursor::new(buffer), options);
+        assert_eq!(reader.read_frame().await.unwrap(), small);
+        assert_eq!(reader.read_frame().await.unwrap(), large);
+    }
+
+    #[test]
+    fn test_compression_policy_worth_sending() {
+        let policy = CompressionPolicy::default();
+        assert!(policy.worth_sending(1000, 900));
+        assert!(!policy.worth_sending(1000, 901));
+        assert!(!policy.worth_sending(1000, 1010));
+
+        let any_savings = CompressionPolicy {
+            min_savings_percent: 0,
+            ..policy
+        };
+        assert!(any_savings.worth_sending(1000, 1000));
+        assert!(!any_savings.worth_sending(1000, 1001));
+
+        let never = CompressionPolicy {
+            min_savings_percent: 100,
+            ..policy
+        };
+        assert!(!never.worth_sending(1000, 1));
+    }
+
+    fn numbered_frames(count: u32) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for i in 0..count {
+            let mut frame = vec![(i % 251) as u8; 32];
+            frame[..4].copy_from_slice(&i.to_be_bytes());
+            stream.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            stream.extend_from_slice(&frame);
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_frames_outlive_later_reads() {
+        let mut reader = FrameReader::new(Cursor::new(numbered_frames(3)));
+
+        let first = reader.read_frame().await.unwrap();
+        let second = reader.read_frame().await.unwrap();
+        let third = reader.read_frame().await.unwrap();
+        for (i, frame) in [first, second, third].iter().enumerate() {
+            assert_eq!(frame.len(), 32);
+            assert_eq!(frame[..4], (i as u32).to_be_bytes());
+            assert!(frame[4..].iter().all(|&b| b == i as u8));
+        }
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::UnexpectedEof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_reuses_buffer() {
+        // Enough small frames to show up in a profile; the buffer should be
+        // allocated once and reused for every one of them.
+        const FRAMES: u32 = 10_000;
+        let mut reader = FrameReader::new(Cursor::new(numbered_frames(FRAMES)));
+
+        let first = reader.read_frame().await.unwrap();
+        let storage = first.as_ptr();
+        drop(first);
+        for i in 1..FRAMES {
+            let frame = reader.read_frame().await.unwrap();
+            assert_eq!(frame[..4], i.to_be_bytes());
+            assert_eq!(frame.as_ptr(), storage, "frame {i} reallocated");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_large_frames() {
+        let sizes = [INITIAL_READ_CAPACITY * 5 + 3, 1, INITIAL_READ_CAPACITY * 40];
+        let (mut near, far) = tokio::io::duplex(1024);
+        let write = async {
+            for (i, &size) in sizes.iter().enumerate() {
+                write_frame(&mut near, &vec![i as u8; size]).await.unwrap();
+            }
+            drop(near);
+        };
+        let read = async {
+            let mut reader = FrameReader::new(far);
+            let mut frames = Vec::new();
+            while let Ok(frame) = reader.read_frame().await {
+                frames.push(frame);
+            }
+            frames
+        };
+        let ((), frames) = tokio::join!(write, read);
+
+        assert_eq!(frames.len(), sizes.len());
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), sizes[i]);
+            assert!(frame.iter().all(|&b| b == i as u8), "frame {i} changed");
+        }
+        // Frames held at once don't share storage.
+        for (i, a) in frames.iter().enumerate() {
+            for b in &frames[i + 1..] {
+                let a = a.as_ptr_range();
+                let b = b.as_ptr_range();
+                assert!(a.end <= b.start || b.end <= a.start);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_allocates_as_frames_arrive() {
+        // A length the peer never follows up on.
+        let mut stream = (MAX_MESSAGE_SIZE / 2).to_be_bytes().to_vec();
+        stream.extend_from_slice(&[0; 100]);
+        let mut reader = FrameReader::new(Cursor::new(stream));
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::UnexpectedEof)
+        ));
+        assert!(reader.buffer.capacity() <= INITIAL_READ_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_reads_only_its_frame() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"framed").await.unwrap();
+        stream.extend_from_slice(b"raw");
+
+        let mut cursor = Cursor::new(stream);
+        let frame = FrameReader::new(&mut cursor).read_frame().await.unwrap();
+        assert_eq!(frame.as_ref(), b"framed");
+        let mut rest = Vec::new();
+        AsyncReadExt::read_to_end(&mut cursor, &mut rest)
+            .await
+            .unwrap();
+        assert_eq!(rest, b"raw");
+    }
+
+    #[tokio::test]
+    async fn test_frame_reader_and_writer_with_options() {
+        let options = FrameOptions {
+            compression: Some(CompressionAlgorithm::Zstd),
+            checksums: true,
+            ..FrameOptions::default()
+        };
+        let large = b"compressible ".repeat(200);
+
+        let mut writer = FrameWriter::with_options(Vec::new(), options);
+        writer.write_frame(b"small").await.unwrap();
+        writer.write_frame(&large).await.unwrap();
+
+        let mut reader = FrameReader::with_options(Cursor::new(writer.into_inner()), options);
+        assert_eq!(reader.read_frame().await.unwrap().as_ref(), b"small");
+        assert_eq!(
+            reader.read_frame().await.unwrap().as_ref(),
+            large.as_slice()
+        );
+    }
+
+    /// Counts write and flush calls, accepting everything offered in each
+    /// write.
+    #[derive(Default)]
+    struct CountingWriter {
+        written: Vec<u8>,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            bufs: &[std::io::IoSlice<'_>],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            let mut len = 0;
+            for buf in bufs {
+                self.written.extend_from_slice(buf);
+                len += buf.len();
+            }
+            std::task::Poll::Ready(Ok(len))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.flushes += 1;
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_writer_single_write_per_frame() {
+        const FRAMES: usize = 10_000;
+        let mut writer = FrameWriter::with_options(CountingWriter::default(), CHECKED);
+        for i in 0..FRAMES {
+            writer.write_frame(&(i as u32).to_be_bytes()).await.unwrap();
+        }
+
+        let counting = writer.into_inner();
+        assert_eq!(counting.writes, FRAMES);
+        assert_eq!(counting.flushes, FRAMES);
+
+        let mut reader = FrameReader::with_options(Cursor::new(counting.written), CHECKED);
+        for i in 0..FRAMES {
+            let frame = reader.read_frame().await.unwrap();
+            assert_eq!(frame.as_ref(), (i as u32).to_be_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_writer_batches_flushes() {
+        let chunk = vec![0x5a; 64 * 1024];
+        let mut writer = FrameWriter::with_options(CountingWriter::default(), CHECKED);
+        for _ in 0..100 {
+            writer.send_frame(&chunk).await.unwrap();
+        }
+        assert_eq!(writer.get_ref().flushes, 0);
+        writer.flush().await.unwrap();
+
+        let counting = writer.into_inner();
+        assert_eq!(counting.writes, 100);
+        assert_eq!(counting.flushes, 1);
+
+        // Readers see the same frames as if each had been flushed.
+        let mut flushed = FrameWriter::with_options(Vec::new(), CHECKED);
+        for _ in 0..100 {
+            flushed.write_frame(&chunk).await.unwrap();
+        }
+        assert_eq!(counting.written, flushed.into_inner());
+    }
+
+    /// Limits as a client and server would set them after the Hello exchange,
+    /// returning each side's (reader, writer) limits.
+    fn negotiated(
+        client: FrameLimits,
+        server: FrameLimits,
+    ) -> ((FrameLimits, FrameLimits), (FrameLimits, FrameLimits)) {
+        let client_sends = client.negotiate(Some(server.max_frame_size));
+        let server_sends = server.negotiate(Some(client.max_frame_size));
+        ((client, client_sends), (server, server_sends))
+    }
+
+    #[tokio::test]
+    async fn test_client_respects_smaller_server_limit() {
+        let ((_, client_sends), (server_reads, _)) =
+            negotiated(FrameLimits::default(), FrameLimits::new(1024));
+        assert_eq!(client_sends.max_frame_size, 1024);
+
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_limits(client_sends);
+        let result = writer.write_frame(&[0; 2048]).await;
+        assert!(matches!(
+            result,
+            Err(FrameError::MessageTooLarge {
+                size: 2048,
+                max: 1024
+            })
+        ));
+        writer.write_frame(&[1; 1024]).await.unwrap();
+
+        let mut reader = FrameReader::new(Cursor::new(writer.into_inner()));
+        reader.set_limits(server_reads);
+        assert_eq!(reader.read_frame().await.unwrap().len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_server_respects_smaller_client_limit() {
+        let ((client_reads, _), (_, server_sends)) =
+            negotiated(FrameLimits::new(1024), FrameLimits::default());
+        assert_eq!(server_sends.max_frame_size, 1024);
+
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_limits(server_sends);
+        assert!(writer.write_frame(&[0; 2048]).await.is_err());
+
+        // A server that ignores the limit has its frame rejected by the
+        // client before the body is read.
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &[0; 2048]).await.unwrap();
+        let mut reader = FrameReader::new(Cursor::new(stream));
+        reader.set_limits(client_reads);
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::MessageTooLarge {
+                size: 2048,
+                max: 1024
+            })
+        ));
+        assert_eq!(reader.get_ref().position(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_raised_limit_between_trusted_peers() {
+        let raised = FrameLimits::new(MAX_MESSAGE_SIZE * 2);
+        let ((_, client_sends), (server_reads, _)) = negotiated(raised, raised);
+        let data = vec![0x5a; MAX_MESSAGE_SIZE as usize + 1];
+
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_limits(client_sends);
+        writer.write_frame(&data).await.unwrap();
+
+        let mut reader = FrameReader::new(Cursor::new(writer.into_inner()));
+        reader.set_limits(server_reads);
+        assert_eq!(reader.read_frame().await.unwrap().len(), data.len());
+    }
+
+    #[test]
+    fn test_unadvertised_limit_is_default() {
+        let raised = FrameLimits::new(MAX_MESSAGE_SIZE * 2);
+        assert_eq!(raised.negotiate(None), FrameLimits::default());
+        let lowered = FrameLimits::new(1024);
+        assert_eq!(lowered.negotiate(None), lowered);
+    }
+
+    #[tokio::test]
+    async fn test_decompressed_size_uses_session_limit() {
+        let options = FrameOptions {
+            compression: Some(CompressionAlgorithm::Zstd),
+            checksums: false,
+            ..FrameOptions::default()
+        };
+        let mut writer = FrameWriter::with_options(Vec::new(), options);
+        writer.write_frame(&[0; 4096]).await.unwrap();
+
+        let mut reader = FrameReader::with_options(Cursor::new(writer.into_inner()), options);
+        reader.set_limits(FrameLimits::new(1024));
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::DecompressedTooLarge { max: 1024 })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout_on_stalled_peer() {
+        let TestPair {
+            client: _peer,
+            server: mut stream,
+        } = TestPair::with_capacity(1024);
+        let timeout = Duration::from_secs(30);
+
+        let start = Instant::now();
+        let result = read_frame_timeout(&mut stream, timeout).await;
+        assert!(matches!(result, Err(FrameError::Timeout(t)) if t == timeout));
+        assert_eq!(start.elapsed(), timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout_on_partial_frame() {
+        let TestPair {
+            client: mut peer,
+            server: mut stream,
+        } = TestPair::with_capacity(1024);
+        // Announce 100 bytes but only send 10.
+        peer.write_u32(100).await.unwrap();
+        peer.write_all(&[0; 10]).await.unwrap();
+
+        let result = read_frame_timeout(&mut stream, Duration::from_secs(30)).await;
+        assert!(matches!(result, Err(FrameError::Timeout(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_timeout_when_peer_stops_reading() {
+        let TestPair {
+            client: _peer,
+            server: mut stream,
+        } = TestPair::with_capacity(1024);
+        let timeout = Duration::from_secs(30);
+
+        let start = Instant::now();
+        let result = write_frame_timeout(&mut stream, &[0; 4096], timeout).await;
+        assert!(matches!(result, Err(FrameError::Timeout(t)) if t == timeout));
+        assert_eq!(start.elapsed(), timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_stream_roundtrip_within_deadlines() {
+        let TestPair { client, server } = TestPair::with_capacity(1024);
+        let mut client = FrameStream::new(client, FrameTimeouts::default());
+        let mut server = FrameStream::new(server, FrameTimeouts::default());
+
+        client.write_frame(b"ping").await.unwrap();
+        assert_eq!(server.read_frame().await.unwrap(), b"ping");
+        server.write_frame(b"pong").await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap(), b"pong");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_stream_enforces_session_limits() {
+        let TestPair { client, server } = TestPair::with_capacity(8192);
+        let mut client = FrameStream::new(client, FrameTimeouts::default());
+        let mut server = FrameStream::new(server, FrameTimeouts::default());
+        server.set_limits(FrameLimits::new(1024));
+
+        // Over the session's limit but well under the default one.
+        assert!(matches!(
+            server.write_frame(&[0; 2048]).await,
+            Err(FrameError::MessageTooLarge {
+                size: 2048,
+                max: 1024
+            })
+        ));
+        client.write_frame(&[0; 2048]).await.unwrap();
+        assert!(matches!(
+            server.read_frame().await,
+            Err(FrameError::MessageTooLarge {
+                size: 2048,
+                max: 1024
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_into_enforces_limits() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &[0; 2048]).await.unwrap();
+
+        let mut read_buffer = [0u8; 4096];
+        let result = read_frame_into_with(
+            &mut Cursor::new(&buffer),
+            &mut read_buffer,
+            FrameOptions::default(),
+            FrameLimits::new(1024),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(FrameError::MessageTooLarge {
+                size: 2048,
+                max: 1024
+            })
+        ));
+        let len = read_frame_into_with(
+            &mut Cursor::new(&buffer),
+            &mut read_buffer,
+            FrameOptions::default(),
+            FrameLimits::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(len, 2048);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_stream_write_stall() {
+        let TestPair {
+            client: _peer,
+            server: stream,
+        } = TestPair::with_capacity(1024);
+        let timeouts = FrameTimeouts {
+            frame: Some(Duration::from_secs(10)),
+            session: None,
+        };
+        let mut stream = FrameStream::new(stream, timeouts);
+
+        let result = stream.write_frame(&[0; 4096]).await;
+        assert!(matches!(result, Err(FrameError::Timeout(t)) if t == Duration::from_secs(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_stream_session_deadline() {
+        let TestPair {
+            client: mut peer,
+            server: stream,
+        } = TestPair::with_capacity(1024);
+        let timeouts = FrameTimeouts {
+            frame: Some(Duration::from_secs(60)),
+            session: Some(Duration::from_secs(100)),
+        };
+        let mut stream = FrameStream::new(stream, timeouts);
+        let start = Instant::now();
+
+        let trickle = async {
+            // Each frame is well within the per-frame limit, but together
+            // they outlast the session.
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_secs(40)).await;
+                write_frame(&mut peer, b"slow").await.unwrap();
+            }
+            peer
+        };
+        let read = async {
+            let mut frames = 0;
+            let error = loop {
+                match stream.read_frame().await {
+                    Ok(_) => frames += 1,
+                    Err(e) => break e,
+                }
+            };
+            (frames, error, start.elapsed())
+        };
+
+        let (_peer, (frames, error, failed_after)) = tokio::join!(trickle, read);
+        assert_eq!(frames, 2);
+        assert!(matches!(error, FrameError::Timeout(t) if t == Duration::from_secs(100)));
+        assert_eq!(failed_after, Duration::from_secs(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_stream_without_timeouts_waits() {
+        let TestPair {
+            client: mut peer,
+            server: stream,
+        } = TestPair::with_capacity(1024);
+        let mut stream = FrameStream::new(stream, FrameTimeouts::NONE);
+
+        let late = async {
+            tokio::time::sleep(DEFAULT_SESSION_TIMEOUT * 2).await;
+            write_frame(&mut peer, b"late").await.unwrap();
+        };
+        let (_, frame) = tokio::join!(late, stream.read_frame());
+        assert_eq!(frame.unwrap(), b"late");
+    }
 }
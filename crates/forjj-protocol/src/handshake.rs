@@ -0,0 +1,687 @@
+//! The Hello exchange.
+//!
+//! The client sends [`HelloRequest`] with the newest protocol version it
+//! supports (and optionally the oldest). The server picks the older of the two
+//! maxima, provided both sides support it, and answers with [`HelloResponse`],
+//! or with an [`ErrorCode::UnsupportedVersion`] error listing its own range if
+//! there is none. Both sides end up with the same [`Negotiated`] session
+//! parameters.
+//!
+//! The Hello also names the repository to sync, which the server checks with
+//! [`server_select_repo`]. A session serves that one repository; to sync
+//! another, open a new connection.
+//!
+//! [`ErrorCode::UnsupportedVersion`]: crate::messages::ErrorCode::UnsupportedVersion
+
+use std::fmt;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::capability::{CapabilitySet, NegotiationError};
+use crate::envelope::{Message, WireFormat, close_with_error, read_message_as, write_message};
+use crate::error::ProtocolError;
+use crate::framing::{FrameLimits, FrameOptions, MAX_MESSAGE_SIZE};
+use crate::messages::{Capability, HelloRequest, HelloResponse, RepoRef};
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// An inclusive range of protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// The versions this implementation supports.
+    pub const SUPPORTED: Self = Self {
+        min: MIN_PROTOCOL_VERSION,
+        max: PROTOCOL_VERSION,
+    };
+
+    /// Create a range. `min` must not exceed `max`.
+    pub fn new(min: u32, max: u32) -> Self {
+        assert!(min <= max, "empty version range {min}-{max}");
+        Self { min, max }
+    }
+
+    /// Whether `version` is in this range.
+    pub fn contains(self, version: u32) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+
+    /// The range a client advertised in its Hello.
+    pub fn of_client(hello: &HelloRequest) -> Self {
+        let max = hello.protocol_version;
+        let min = hello.min_protocol_version.unwrap_or(MIN_PROTOCOL_VERSION);
+        Self {
+            min: min.min(max),
+            max,
+        }
+    }
+
+    /// Pick the version to speak with a peer supporting `other`: the smaller
+    /// of the two maxima, if both ranges contain it.
+    pub fn negotiate(self, other: VersionRange) -> Option<u32> {
+        let version = self.max.min(other.max);
+        (self.contains(version) && other.contains(version)).then_some(version)
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Session parameters agreed on in the Hello exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Protocol version both peers speak.
+    pub version: u32,
+    /// Capabilities both peers advertised that `version` allows.
+    pub capabilities: CapabilitySet,
+    /// Encoding for message bodies after the Hello exchange.
+    pub format: WireFormat,
+    /// Framing options for both directions.
+    pub frame_options: FrameOptions,
+    /// Limits on frames this side sends.
+    pub send_limits: FrameLimits,
+    /// Limits on frames this side accepts.
+    pub receive_limits: FrameLimits,
+}
+
+impl Negotiated {
+    /// The client's view of the session.
+    pub fn for_client(request: &HelloRequest, response: &HelloResponse) -> Self {
+        Self::new(
+            request,
+            response,
+            request.max_frame_size,
+            response.max_frame_size,
+        )
+    }
+
+    /// The server's view of the session.
+    pub fn for_server(request: &HelloRequest, response: &HelloResponse) -> Self {
+        Self::new(
+            request,
+            response,
+            response.max_frame_size,
+            request.max_frame_size,
+        )
+    }
+
+    fn new(
+        request: &HelloRequest,
+        response: &HelloResponse,
+        local_max: Option<u32>,
+        peer_max: Option<u32>,
+    ) -> Self {
+        let version = response.protocol_version;
+        let capabilities: CapabilitySet = request
+            .capabilities
+            .iter()
+            .filter(|capability| response.capabilities.contains(capability))
+            .filter(|capability| {
+                capability
+                    .since_version()
+                    .is_some_and(|since| since <= version)
+            })
+            .cloned()
+            .collect();
+
+        let format = WireFormat::negotiate(capabilities.as_slice(), capabilities.as_slice());
+        let frame_options = FrameOptions {
+            compression: response
+                .compression
+                .filter(|_| capabilities.contains(&Capability::Compression)),
+            checksums: capabilities.contains(&Capability::FrameChecksums),
+            ..FrameOptions::default()
+        };
+        let receive_limits = FrameLimits::new(local_max.unwrap_or(MAX_MESSAGE_SIZE));
+
+        Self {
+            version,
+            capabilities,
+            format,
+            frame_options,
+            send_limits: receive_limits.negotiate(peer_max),
+            receive_limits,
+        }
+    }
+
+    /// Whether both peers have `capability` in this session.
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Fail with [`NegotiationError::MissingRequired`] unless every one of
+    /// `required` is available in this session.
+    pub fn require(&self, required: &[Capability]) -> Result<(), NegotiationError> {
+        match required
+            .iter()
+            .find(|capability| !self.capabilities.contains(capability))
+        {
+            Some(missing) => Err(NegotiationError::MissingRequired(missing.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `message` may be sent in this session's protocol version.
+    pub fn allows(&self, message: &Message) -> bool {
+        message
+            .since_version()
+            .is_some_and(|since| since <= self.version)
+    }
+
+    /// Fail with [`ProtocolError::NotAllowedInVersion`] unless `message` may
+    /// be sent in this session.
+    pub fn check_message(&self, message: &Message) -> Result<(), ProtocolError> {
+        if self.allows(message) {
+            Ok(())
+        } else {
+            Err(ProtocolError::NotAllowedInVersion {
+                message: message.name(),
+                version: self.version,
+            })
+        }
+    }
+}
+
+/// Client side: send `request` and wait for the server's response.
+///
+/// Fails with [`ProtocolError::Remote`] if the server rejects the Hello, and
+/// with [`ProtocolError::UnsupportedVersion`] if it picks a version outside
+/// the client's range.
+pub async fn client_hello<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    request: &HelloRequest,
+) -> Result<(HelloResponse, Negotiated), ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_message(writer, &request.clone().into(), WireFormat::Json).await?;
+    let response: HelloResponse = read_message_as(reader, WireFormat::Json).await?;
+
+    let supported = VersionRange::of_client(request);
+    if !supported.contains(response.protocol_version) {
+        let version = response.protocol_version;
+        return Err(ProtocolError::UnsupportedVersion {
+            requested: VersionRange::new(version, version),
+            supported,
+        });
+    }
+
+    let negotiated = Negotiated::for_client(request, &response);
+    Ok((response, negotiated))
+}
+
+/// Server side: read the client's Hello and pick a protocol version.
+///
+/// If the client has no version in common with `supported`, reports
+/// [`ErrorCode::UnsupportedVersion`] to it, closes the writer, and fails with
+/// [`ProtocolError::UnsupportedVersion`]. A malformed Hello, or another
+/// message in its place, is reported the same way as a protocol violation.
+///
+/// [`ErrorCode::UnsupportedVersion`]: crate::messages::ErrorCode::UnsupportedVersion
+pub async fn server_read_hello<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    supported: VersionRange,
+) -> Result<(HelloRequest, u32), ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request: HelloRequest = match read_message_as(reader, WireFormat::Json).await {
+        Ok(request) => request,
+        Err(error) => {
+            // Whatever the client sent instead of a Hello, it is told why.
+            if error.should_report() {
+                close_with_error(writer, error.to_error_message(), WireFormat::Json).await;
+            }
+            return Err(error);
+        }
+    };
+    let requested = VersionRange::of_client(&request);
+
+    match supported.negotiate(requested) {
+        Some(version) => Ok((request, version)),
+        None => {
+            let error = ProtocolError::UnsupportedVersion {
+                requested,
+                supported,
+            };
+            close_with_error(writer, error.to_error_message(), WireFormat::Json).await;
+            Err(error)
+        }
+    }
+}
+
+/// Server side: check the repository named in the client's Hello.
+///
+/// Names must follow the storage naming rules, and `exists` must report the
+/// repository as present. On failure, reports the error to the client, closes
+/// the writer, and fails with [`ProtocolError::NoRepoSelected`],
+/// [`ProtocolError::InvalidRepoName`], or [`ProtocolError::RepoNotFound`].
+pub async fn server_select_repo<W, F>(
+    writer: &mut W,
+    request: &HelloRequest,
+    exists: F,
+) -> Result<RepoRef, ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+    F: FnOnce(&RepoRef) -> bool,
+{
+    let result = match &request.repo {
+        None => Err(ProtocolError::NoRepoSelected),
+        Some(repo) => forjj_storage::validate_name(&repo.owner)
+            .and_then(|()| forjj_storage::validate_name(&repo.name))
+            .map_err(|reason| ProtocolError::InvalidRepoName {
+                repo: repo.clone(),
+                reason,
+            })
+            .and_then(|()| {
+                if exists(repo) {
+                    Ok(repo.clone())
+                } else {
+                    Err(ProtocolError::RepoNotFound(repo.clone()))
+                }
+            }),
+    };
+
+    if let Err(error) = &result {
+        close_with_error(writer, error.to_error_message(), WireFormat::Json).await;
+    }
+    result
+}
+
+/// Server side: send `response` to the Hello `request`.
+///
+/// `response.protocol_version` should be the version picked by
+/// [`server_read_hello`].
+pub async fn server_send_hello<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    request: &HelloRequest,
+    response: HelloResponse,
+) -> Result<Negotiated, ProtocolError> {
+    let negotiated = Negotiated::for_server(request, &response);
+    write_message(writer, &response.into(), WireFormat::Json).await?;
+    Ok(negotiated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{UnknownMessage, decode_message, encode_message, read_message};
+    use crate::framing::write_frame;
+    use crate::messages::{
+        AccessLevel, Auth, CompressionAlgorithm, ErrorCode, FetchRequest, Ping, Pong, RepoRef,
+        ServerLimits,
+    };
+    use crate::testing::{RecordingStream, ScriptedServer, TestPair, within};
+    use std::collections::BTreeMap;
+
+    fn hello(versions: VersionRange) -> HelloRequest {
+        HelloRequest {
+            protocol_version: versions.max,
+            capabilities: vec![
+                Capability::Operations,
+                Capability::BinaryFrames,
+                Capability::Compression,
+            ],
+            client_op_heads: vec![],
+            compression: vec![CompressionAlgorithm::Zstd],
+            max_frame_size: Some(1024 * 1024),
+            min_protocol_version: Some(versions.min),
+            auth: Auth::None,
+            access: AccessLevel::Read,
+            repo: Some(RepoRef::new("alice", "project")),
+            create_if_missing: false,
+        }
+    }
+
+    fn response(version: u32) -> HelloResponse {
+        HelloResponse {
+            protocol_version: version,
+            capabilities: vec![
+                Capability::BinaryFrames,
+                Capability::Compression,
+                Capability::FrameChecksums,
+            ],
+            server_op_heads: vec![],
+            common_ancestor: None,
+            compression: Some(CompressionAlgorithm::Zstd),
+            max_frame_size: None,
+            identity: None,
+            access: Some(AccessLevel::Read),
+            limits: ServerLimits::default(),
+            extensions: BTreeMap::new(),
+        }
+    }
+
+    /// Run a full Hello exchange, returning both sides' results.
+    async fn exchange(
+        client: VersionRange,
+        server: VersionRange,
+    ) -> (
+        Result<(HelloResponse, Negotiated), ProtocolError>,
+        Result<Negotiated, ProtocolError>,
+    ) {
+        let (mut client_end, mut server_end) = TestPair::new().split();
+
+        let request = hello(client);
+        let client = client_hello(&mut client_end.reader, &mut client_end.writer, &request);
+        let serve = async {
+            let (request, version) =
+                server_read_hello(&mut server_end.reader, &mut server_end.writer, server).await?;
+            server_send_hello(&mut server_end.writer, &request, response(version)).await
+        };
+        within(async { tokio::join!(client, serve) }).await
+    }
+
+    #[test]
+    fn test_negotiate_versions() {
+        let v1 = VersionRange::new(1, 1);
+        assert_eq!(v1.negotiate(v1), Some(1));
+        assert_eq!(
+            VersionRange::new(1, 2).negotiate(VersionRange::new(1, 3)),
+            Some(2)
+        );
+        assert_eq!(
+            VersionRange::new(1, 3).negotiate(VersionRange::new(2, 2)),
+            Some(2)
+        );
+        assert_eq!(
+            VersionRange::new(1, 2).negotiate(VersionRange::new(3, 4)),
+            None
+        );
+        assert_eq!(
+            VersionRange::new(3, 4).negotiate(VersionRange::new(1, 2)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_client_range_defaults() {
+        let mut request = hello(VersionRange::new(1, 3));
+        request.min_protocol_version = None;
+        assert_eq!(VersionRange::of_client(&request), VersionRange::new(1, 3));
+    }
+
+    #[tokio::test]
+    async fn test_equal_versions() {
+        let (client, server) = exchange(VersionRange::SUPPORTED, VersionRange::SUPPORTED).await;
+        let (_, client) = client.unwrap();
+        let server = server.unwrap();
+        assert_eq!(client.version, PROTOCOL_VERSION);
+        assert_eq!(server.version, PROTOCOL_VERSION);
+        assert_eq!(client.capabilities, server.capabilities);
+        assert_eq!(client.format, server.format);
+        assert_eq!(client.frame_options, server.frame_options);
+    }
+
+    #[tokio::test]
+    async fn test_client_newer() {
+        let (client, server) = exchange(VersionRange::new(1, 3), VersionRange::new(1, 2)).await;
+        assert_eq!(client.unwrap().1.version, 2);
+        assert_eq!(server.unwrap().version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_newer() {
+        let (client, server) = exchange(VersionRange::new(1, 1), VersionRange::new(1, 3)).await;
+        assert_eq!(client.unwrap().1.version, 1);
+        assert_eq!(server.unwrap().version, 1);
+    }
+
+    #[tokio::test]
+    async fn test_disjoint_ranges() {
+        let (client, server) = exchange(VersionRange::new(3, 4), VersionRange::new(1, 2)).await;
+
+        assert!(matches!(
+            server,
+            Err(ProtocolError::UnsupportedVersion { requested, supported })
+                if requested == VersionRange::new(3, 4) && supported == VersionRange::new(1, 2)
+        ));
+        let error = client.unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::UnsupportedVersion));
+        assert!(error.to_string().contains("1-2"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_hello_is_reported() {
+        let (mut client_end, mut server_end) = TestPair::new().split();
+        // A Hello whose version is a string.
+        let mut payload =
+            encode_message(&hello(VersionRange::new(1, 1)).into(), WireFormat::Json).unwrap();
+        payload.truncate(1);
+        payload.extend_from_slice(br#"{"protocol_version":"one"}"#);
+
+        let client = async {
+            write_frame(&mut client_end.writer, &payload).await.unwrap();
+            read_message(&mut client_end.reader, WireFormat::Json).await
+        };
+        let serve = server_read_hello(
+            &mut server_end.reader,
+            &mut server_end.writer,
+            VersionRange::new(1, 1),
+        );
+        let (reply, served) = within(async { tokio::join!(client, serve) }).await;
+        assert!(matches!(
+            served,
+            Err(ProtocolError::Decode {
+                message: "Hello",
+                ..
+            })
+        ));
+        match reply.unwrap() {
+            Message::Error(error) => assert_eq!(error.code, ErrorCode::ProtocolViolation),
+            other => panic!("expected an Error, got {}", other.name()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_rejects_version_outside_its_range() {
+        let TestPair { client, server } = TestPair::new();
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        let request = hello(VersionRange::new(2, 3));
+        let client = client_hello(&mut reader, &mut writer, &request);
+        // A broken server that ignores the client's minimum.
+        let script = ScriptedServer::new().expect("Hello").send(response(1));
+
+        let (result, served) = within(async { tokio::join!(client, script.run(server)) }).await;
+        served.unwrap();
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnsupportedVersion { requested, .. })
+                if requested == VersionRange::new(1, 1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hello_conformance() {
+        // The client opens with a single JSON Hello, and everything after
+        // the server's answer uses the negotiated format.
+        let TestPair { client, server } = TestPair::new();
+        let client = RecordingStream::new(client);
+        let sent = client.sent();
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let answer = HelloResponse {
+            capabilities: vec![Capability::BinaryFrames],
+            compression: None,
+            ..response(PROTOCOL_VERSION)
+        };
+        let script = ScriptedServer::new()
+            .expect("Hello")
+            .send(answer)
+            .switch_format(WireFormat::Binary)
+            .expect("Ping")
+            .send(Pong { payload: [7; 8] });
+
+        let request = hello(VersionRange::SUPPORTED);
+        let run = async {
+            let (_, negotiated) = client_hello(&mut reader, &mut writer, &request).await?;
+            let ping = Message::Ping(Ping { payload: [7; 8] });
+            write_message(&mut writer, &ping, negotiated.format).await?;
+            let pong: Pong = read_message_as(&mut reader, negotiated.format).await?;
+            Ok::<_, ProtocolError>((negotiated, pong))
+        };
+        let (served, result) = within(async { tokio::join!(script.run(server), run) }).await;
+        let (negotiated, pong) = result.unwrap();
+        served.unwrap();
+
+        assert_eq!(negotiated.format, WireFormat::Binary);
+        assert_eq!(negotiated.frame_options, FrameOptions::default());
+        assert_eq!(pong.payload, [7; 8]);
+        let frames = sent.frames();
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(
+            decode_message(&frames[0], WireFormat::Json).unwrap(),
+            Message::Hello(first) if first.protocol_version == request.protocol_version
+        ));
+        assert!(matches!(
+            decode_message(&frames[1], WireFormat::Binary).unwrap(),
+            Message::Ping(Ping { payload: [7, ..] })
+        ));
+    }
+
+    #[test]
+    fn test_negotiated_session_parameters() {
+        let request = hello(VersionRange::SUPPORTED);
+        let response = HelloResponse {
+            max_frame_size: Some(64 * 1024),
+            ..response(PROTOCOL_VERSION)
+        };
+
+        let client = Negotiated::for_client(&request, &response);
+        assert_eq!(
+            client.capabilities,
+            CapabilitySet::from(vec![Capability::BinaryFrames, Capability::Compression])
+        );
+        assert!(!client.has(Capability::FrameChecksums));
+        assert!(client.require(&[Capability::Compression]).is_ok());
+        assert_eq!(
+            client.require(&[Capability::BinaryFrames, Capability::FrameChecksums]),
+            Err(NegotiationError::MissingRequired(
+                Capability::FrameChecksums
+            ))
+        );
+        assert_eq!(client.format, WireFormat::Binary);
+        assert_eq!(
+            client.frame_options.compression,
+            Some(CompressionAlgorithm::Zstd)
+        );
+        assert_eq!(client.send_limits, FrameLimits::new(64 * 1024));
+        assert_eq!(client.receive_limits, FrameLimits::new(1024 * 1024));
+
+        let server = Negotiated::for_server(&request, &response);
+        assert_eq!(server.send_limits, FrameLimits::new(64 * 1024));
+        assert_eq!(server.receive_limits, FrameLimits::new(64 * 1024));
+    }
+
+    #[test]
+    fn test_version_gates_messages() {
+        let negotiated =
+            Negotiated::for_client(&hello(VersionRange::SUPPORTED), &response(PROTOCOL_VERSION));
+        let fetch: Message = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec![],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        }
+        .into();
+        assert!(negotiated.allows(&fetch));
+        negotiated.check_message(&fetch).unwrap();
+
+        let unknown = Message::Unknown(UnknownMessage {
+            tag: 200,
+            payload: vec![],
+        });
+        assert!(matches!(
+            negotiated.check_message(&unknown),
+            Err(ProtocolError::NotAllowedInVersion {
+                message: "Unknown",
+                ..
+            })
+        ));
+    }
+
+    /// Run repo selection on a server hosting only alice/project, returning
+    /// the result and whatever was sent to the client.
+    async fn select(repo: Option<RepoRef>) -> (Result<RepoRef, ProtocolError>, Vec<u8>) {
+        let request = HelloRequest {
+            repo,
+            ..hello(VersionRange::SUPPORTED)
+        };
+        let mut output = Vec::new();
+        let result = server_select_repo(&mut output, &request, |repo| {
+            *repo == RepoRef::new("alice", "project")
+        })
+        .await;
+        (result, output)
+    }
+
+    async fn reported_code(output: Vec<u8>) -> Option<ErrorCode> {
+        let mut output = std::io::Cursor::new(output);
+        read_message_as::<HelloResponse, _>(&mut output, WireFormat::Json)
+            .await
+            .unwrap_err()
+            .remote_code()
+    }
+
+    #[tokio::test]
+    async fn test_select_known_repo() {
+        let repo = RepoRef::new("alice", "project");
+        let (result, output) = select(Some(repo.clone())).await;
+        assert_eq!(result.unwrap(), repo);
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_select_unknown_repo() {
+        let (result, output) = select(Some(RepoRef::new("alice", "missing"))).await;
+        assert!(matches!(result, Err(ProtocolError::RepoNotFound(_))));
+        assert_eq!(reported_code(output).await, Some(ErrorCode::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_select_invalid_repo_names() {
+        for (owner, name) in [
+            ("alice", "../etc"),
+            ("..", "project"),
+            ("alice", ""),
+            ("alice", "my project"),
+            ("alice/project", "x"),
+        ] {
+            let (result, output) = select(Some(RepoRef::new(owner, name))).await;
+            assert!(
+                matches!(result, Err(ProtocolError::InvalidRepoName { .. })),
+                "{owner}/{name}: {result:?}"
+            );
+            assert_eq!(
+                reported_code(output).await,
+                Some(ErrorCode::ProtocolViolation)
+            );
+        }
+
+        let (result, output) = select(None).await;
+        assert!(matches!(result, Err(ProtocolError::NoRepoSelected)));
+        assert_eq!(
+            reported_code(output).await,
+            Some(ErrorCode::ProtocolViolation)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Client side of the HTTP transport.
+//!
+//! For networks that only let HTTP(S) through, the server accepts
+//! forjj-sync as an HTTP/1.1 upgrade: the client sends
+//! `POST /api/v1/repos/<owner>/<name>/sync` with `Upgrade: forjj-sync`, the
+//! server answers `101 Switching Protocols`, and the connection then carries
+//! the protocol exactly as a plain stream would.
+//!
+//! The bearer token goes in the `Authorization` header and is checked by
+//! the server's HTTP middleware, so the Hello can carry
+//! [`Auth::None`](crate::messages::Auth::None); the server ignores its
+//! credentials.
+
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::StatusCode;
+use hyper::header::{AUTHORIZATION, CONNECTION, HOST, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::messages::RepoRef;
+
+/// Protocol name in the `Upgrade` header.
+pub const SYNC_UPGRADE: &str = "forjj-sync";
+
+/// The upgraded connection of a forjj-sync session.
+pub type HttpStream = TokioIo<Upgraded>;
+
+/// Errors from setting up an HTTP session.
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("invalid request: {0}")]
+    Request(#[from] hyper::http::Error),
+
+    #[error("server refused the upgrade: {0}")]
+    Status(StatusCode),
+}
+
+/// Path of the sync endpoint for `repo`.
+pub fn sync_path(repo: &RepoRef) -> String {
+    format!("/api/v1/repos/{}/{}/sync", repo.owner, repo.name)
+}
+
+/// Opens forjj-sync sessions on an HTTP server.
+#[derive(Clone)]
+pub struct HttpTransport {
+    authority: String,
+    token: Option<String>,
+}
+
+impl HttpTransport {
+    /// A transport for the server at `authority`, e.g. `forjj.example.com:3000`.
+    pub fn new(authority: impl Into<String>) -> Self {
+        Self {
+            authority: authority.into(),
+            token: None,
+        }
+    }
+
+    /// Authenticate with bearer `token`.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Connect over TCP and start a session on `repo`.
+    pub async fn connect(&self, repo: &RepoRef) -> Result<HttpStream, HttpError> {
+        let stream = TcpStream::connect(&self.authority).await?;
+        self.connect_over(stream, repo).await
+    }
+
+    /// Start a session on `repo` over an open connection to the server, e.g.
+    /// one wrapped in TLS.
+    pub async fn connect_over<S>(&self, stream: S, repo: &RepoRef) -> Result<HttpStream, HttpError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        // Drives the connection until it is handed over by the upgrade.
+        tokio::spawn(connection.with_upgrades());
+
+        let mut request = hyper::Request::post(sync_path(repo))
+            .header(HOST, &self.authority)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, SYNC_UPGRADE);
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = sender
+            .send_request(request.body(Empty::<Bytes>::new())?)
+            .await?;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(HttpError::Status(response.status()));
+        }
+        Ok(TokioIo::new(hyper::upgrade::on(response).await?))
+    }
+}
+
+impl std::fmt::Debug for HttpTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpTransport")
+            .field("authority", &self.authority)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_path() {
+        let repo = RepoRef::new("alice", "project");
+        assert_eq!(sync_path(&repo), "/api/v1/repos/alice/project/sync");
+    }
+
+    #[test]
+    fn test_debug_redacts_token() {
+        let transport = HttpTransport::new("localhost:3000").with_token("fj_secret");
+        let debug = format!("{transport:?}");
+        assert!(!debug.contains("fj_secret"), "{debug}");
+        assert!(debug.contains("<redacted>"));
+    }
+}
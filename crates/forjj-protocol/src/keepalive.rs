@@ -0,0 +1,282 @@
+//! Keepalive pings and idle timeouts.
+//!
+//! Long server-side work (verifying packs, running hooks) can leave a
+//! connection silent for longer than NAT or SSH idle timeouts allow. The side
+//! doing the work sends [`Ping`]s with [`keepalive_while`], and the waiting side
+//! answers them with [`Pong`]s.
+//!
+//! Keepalive frames never carry protocol state: a Pong echoes its Ping's
+//! payload and is otherwise ignored.
+//!
+//! A server in the middle of a request relies on them: a client that sends
+//! nothing for [`DEFAULT_STALL_TIMEOUT`], two keepalive intervals, is taken
+//! to be gone and its session aborted. Clients ping while they build a pack
+//! for the same reason.
+
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use crate::envelope::{Message, WireFormat, read_message, read_message_after, write_message};
+use crate::error::ProtocolError;
+use crate::framing::FrameError;
+use crate::messages::{Ping, Pong};
+
+/// Default interval between keepalive pings.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Default time a server waits for a frame before closing the connection.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Default time a server waits for more of a request before taking the
+/// connection for dead: two missed keepalives.
+pub const DEFAULT_STALL_TIMEOUT: Duration =
+    Duration::from_secs(2 * DEFAULT_KEEPALIVE_INTERVAL.as_secs());
+
+/// Run `work`, sending a [`Ping`] every `interval` until it completes.
+///
+/// `work` must not use `writer`; the pings are the only frames written while
+/// it runs.
+pub async fn keepalive_while<W, F>(
+    writer: &mut W,
+    format: WireFormat,
+    interval: Duration,
+    work: F,
+) -> Result<F::Output, ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+    F: Future,
+{
+    let mut work = pin!(work);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut sent: u64 = 0;
+
+    loop {
+        tokio::select! {
+            biased;
+            output = &mut work => return Ok(output),
+            _ = ticker.tick() => {
+                sent += 1;
+                let ping = Ping {
+                    payload: sent.to_be_bytes(),
+                };
+                write_message(writer, &ping.into(), format).await?;
+            }
+        }
+    }
+}
+
+/// Run `work`, answering any [`Ping`]s from the peer until it completes.
+///
+/// The peer is waiting for the result of `work`, so the only frames expected
+/// meanwhile are keepalives; anything else is a protocol violation.
+pub async fn answer_pings_while<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    work: F,
+) -> Result<F::Output, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Future,
+{
+    let mut work = pin!(work);
+
+    loop {
+        // Only the first byte of a frame is raced against `work`, so a frame is
+        // never abandoned halfway through.
+        let first = tokio::select! {
+            biased;
+            output = &mut work => return Ok(output),
+            first = reader.read_u8() => first.map_err(eof_as_frame_error)?,
+        };
+        match read_message_after(first, reader, format).await? {
+            Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+            Message::Pong(_) => {}
+            other => {
+                return Err(ProtocolError::UnexpectedMessage {
+                    expected: "Ping",
+                    actual: other.name(),
+                });
+            }
+        }
+    }
+}
+
+/// Read the next message that isn't a keepalive.
+///
+/// Pings are answered immediately and Pongs are dropped. If `idle_timeout` is
+/// set and no frame at all arrives within it, fails with
+/// [`ProtocolError::IdleTimeout`].
+pub async fn read_message_answering_pings<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    idle_timeout: Option<Duration>,
+) -> Result<Message, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let message = match idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read_message(reader, format))
+                .await
+                .map_err(|_| ProtocolError::IdleTimeout(timeout))??,
+            None => read_message(reader, format).await?,
+        };
+        match message {
+            Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+            Message::Pong(_) => {}
+            message => return Ok(message),
+        }
+    }
+}
+
+pub(crate) async fn answer_ping<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    ping: Ping,
+    format: WireFormat,
+) -> Result<(), ProtocolError> {
+    let pong = Pong {
+        payload: ping.payload,
+    };
+    write_message(writer, &pong.into(), format).await
+}
+
+pub(crate) fn eof_as_frame_error(e: std::io::Error) -> FrameError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        FrameError::UnexpectedEof
+    } else {
+        FrameError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::read_message_as;
+    use crate::messages::FetchRequest;
+    use std::io::Cursor;
+    use tokio::time::Instant;
+
+    const FORMAT: WireFormat = WireFormat::Binary;
+
+    fn fetch() -> FetchRequest {
+        FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+            include_operations: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_pings_during_long_work() {
+        let mut buffer: Vec<u8> = Vec::new();
+        let work = async {
+            tokio::time::sleep(Duration::from_secs(35)).await;
+            42
+        };
+        let output = keepalive_while(&mut buffer, FORMAT, Duration::from_secs(10), work)
+            .await
+            .unwrap();
+        assert_eq!(output, 42);
+
+        let mut cursor = Cursor::new(buffer);
+        for expected in 1..=3u64 {
+            let ping: Ping = read_message_as(&mut cursor, FORMAT).await.unwrap();
+            assert_eq!(ping.payload, expected.to_be_bytes());
+        }
+        assert_eq!(cursor.position() as usize, cursor.get_ref().len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pings_answered_mid_operation() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+        let start = Instant::now();
+
+        let serve = answer_pings_while(
+            &mut server_read,
+            &mut server_write,
+            FORMAT,
+            tokio::time::sleep(Duration::from_secs(60)),
+        );
+        let client = async {
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            let ping = Ping {
+                payload: *b"keepaliv",
+            };
+            write_message(&mut client_write, &ping.into(), FORMAT)
+                .await
+                .unwrap();
+            let pong: Pong = read_message_as(&mut client_read, FORMAT).await.unwrap();
+            (pong, start.elapsed())
+        };
+
+        let (served, (pong, answered_after)) = tokio::join!(serve, client);
+        served.unwrap();
+        assert_eq!(pong.payload, *b"keepaliv");
+        assert_eq!(answered_after, Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let (mut reader, mut writer) = tokio::io::split(server);
+
+        let timeout = Duration::from_secs(60);
+        let start = Instant::now();
+        let result =
+            read_message_answering_pings(&mut reader, &mut writer, FORMAT, Some(timeout)).await;
+        assert!(matches!(result, Err(ProtocolError::IdleTimeout(t)) if t == timeout));
+        assert_eq!(start.elapsed(), timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pings_reset_idle_timeout() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+
+        let serve = read_message_answering_pings(
+            &mut server_read,
+            &mut server_write,
+            FORMAT,
+            Some(Duration::from_secs(60)),
+        );
+        let client = async {
+            // Each gap is under the timeout even though the total is not.
+            for i in 0..2u64 {
+                tokio::time::sleep(Duration::from_secs(40)).await;
+                let ping = Ping {
+                    payload: i.to_be_bytes(),
+                };
+                write_message(&mut client_write, &ping.into(), FORMAT)
+                    .await
+                    .unwrap();
+                let pong: Pong = read_message_as(&mut client_read, FORMAT).await.unwrap();
+                assert_eq!(pong.payload, i.to_be_bytes());
+            }
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            write_message(&mut client_write, &fetch().into(), FORMAT)
+                .await
+                .unwrap();
+        };
+
+        let (message, ()) = tokio::join!(serve, client);
+        assert!(matches!(message, Ok(Message::Fetch(_))));
+    }
+}
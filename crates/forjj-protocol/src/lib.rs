@@ -3,14 +3,133 @@
 //! This crate implements the forjj-sync protocol for pushing and fetching
 //! repositories between jj clients and the Forjj server.
 
+pub mod auth;
+pub mod bloom;
+pub mod cancel;
+pub mod capability;
+pub mod client;
+pub mod codec;
+pub mod delta;
+pub mod envelope;
+pub mod error;
 pub mod framing;
+pub mod handshake;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod keepalive;
 pub mod messages;
+pub mod negotiation;
+#[cfg(feature = "noise")]
+pub mod noise;
+pub mod pack;
+pub mod progress;
+pub mod push_log;
+pub mod refs;
+pub mod resume;
+pub mod server;
+pub mod shallow;
+pub mod sideband;
+pub mod sparse;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod sync;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod throttle;
+pub mod transfer;
 
-pub use framing::{FrameError, read_frame, write_frame};
+pub use auth::{
+    AnonymousRead, AuthError, AuthGrant, AuthHandler, TransportAuth, server_authenticate,
+};
+pub use bloom::{
+    BITS_PER_COMMIT, CommitFilter, CommitFilterError, DEFAULT_HASHES, MAX_FILTER_BITS, MAX_HASHES,
+};
+pub use cancel::{
+    CancelToken, SendOutcome, cancel_operation, receive_pack_cancellable, send_pack_cancellable,
+};
+pub use capability::{CapabilitySet, NegotiationError, negotiate};
+pub use client::{ClientOptions, FetchOutcome, ForjjClient};
+pub use codec::ForjjCodec;
+pub use delta::{DeltaError, apply_delta, compute_delta, thin_pack_enabled};
+pub use envelope::{
+    Message, UnknownMessage, WireFormat, close_with_error, decode_message, encode_message,
+    read_message, read_message_as, write_message,
+};
+pub use error::ProtocolError;
+pub use framing::{
+    CompressionPolicy, CompressionStats, FrameError, FrameLimits, FrameOptions, FrameReader,
+    FrameStream, FrameTimeouts, FrameWriter, compression_stats, read_frame, read_frame_compressed,
+    read_frame_into_with, read_frame_timeout, read_frame_with, write_frame, write_frame_compressed,
+    write_frame_timeout, write_frame_with,
+};
+pub use handshake::{
+    Negotiated, VersionRange, client_hello, server_read_hello, server_select_repo,
+    server_send_hello,
+};
+#[cfg(feature = "http")]
+pub use http::{HttpError, HttpStream, HttpTransport, SYNC_UPGRADE, sync_path};
+pub use keepalive::{answer_pings_while, keepalive_while, read_message_answering_pings};
 pub use messages::{
-    Capability, FetchRequest, FetchResponse, HelloRequest, HelloResponse, PushRequest, PushResult,
-    PushStatus, RefUpdate,
+    AccessLevel, AckReady, Auth, Cancel, CancelAck, Capability, CompressionAlgorithm, ErrorCode,
+    ErrorMessage, FetchMode, FetchRequest, FetchResponse, HaveMore, HelloRequest, HelloResponse,
+    ListRefsRequest, ListRefsResponse, OperationRecord, PackAck, PackChunk, Ping, Pong,
+    ProgressMessage, ProgressPhase, PushNegotiate, PushRequest, PushResult, PushStatus, RefInfo,
+    RefReason, RefResult, RefStatus, RefTargetWire, RefUpdate, RepoRef, ResumeRequest,
+    ResumeResponse, ServerLimits, ViewData, WantError, WantReason,
+};
+pub use negotiation::{
+    DEFAULT_MAX_HAVES, DEFAULT_MAX_ROUNDS, FIRST_HAVE_BATCH, FetchPlan, HaveWalker, MAX_HAVE_BATCH,
+    Negotiation, OpGraph,
+};
+#[cfg(feature = "noise")]
+pub use noise::{
+    MAX_NOISE_PLAINTEXT, NOISE_KEY_LEN, NOISE_PARAMS, NoiseError, NoiseKeypair, NoiseStream,
+    noise_accept, noise_connect, parse_noise_key,
+};
+pub use pack::{
+    DeltaResolver, ObjectKind, PackEntry, PackError, PackLimits, PackReader, PackWriter,
+};
+pub use progress::{NoProgress, ProgressSink, read_message_with_progress};
+pub use push_log::{
+    DEFAULT_MAX_PUSH_IDS, DEFAULT_PUSH_ID_TTL, MAX_PUSH_ID_LEN, PUSH_LOG_FILE, PushLog,
+    check_push_id, new_push_id,
+};
+pub use refs::{
+    Expansion, MAX_PATTERN_LEN, RefPattern, RefPatternError, TAG_PREFIX, expand_want_refs,
+    match_refs,
+};
+pub use resume::{
+    ResumableReceiver, ResumeSessions, receive_acks, resumable_enabled, send_pack_from,
+};
+pub use server::{
+    AllowForcePush, BookmarksMoved, PushPolicy, RepoProvider, RequestServed, ServerOptions,
+    SessionAbort, SessionMonitor, SessionShutdown, serve_session,
+};
+pub use shallow::{ShallowSelection, select_shallow};
+pub use sideband::{
+    Channel, Demultiplexer, PACK_QUEUE, PROGRESS_QUEUE, PackStream, Sideband, demultiplex,
+    forward_progress,
+};
+pub use sparse::{MAX_FILTER_LEN, PathFilter, PathFilterError};
+#[cfg(feature = "ssh")]
+pub use ssh::{SYNC_COMMAND, SshError, SshStream, SshTarget, connect_ssh, sync_command};
+pub use sync::{
+    CONTENT_KINDS, ExportedPack, ImportStats, apply_fetch, apply_fetch_commits, export_objects,
+    export_operations, export_pack, export_pack_except, export_partial, import_objects,
+    import_operations, missing_commits,
+};
+#[cfg(feature = "tcp")]
+pub use tcp::connect_tcp;
+pub use throttle::{RateLimit, ThrottledWriter};
+pub use transfer::{
+    PackChunkReader, receive_pack, receive_pack_with_limits, receive_pack_with_progress, send_pack,
+    send_pack_with_progress,
 };
 
-/// Protocol version
+/// Newest protocol version this implementation speaks
 pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this implementation still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
@@ -1,11 +1,17 @@
 //! Protocol message definitions for forjj-sync/1.0
 
-use forjj_storage::OperationId;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use forjj_storage::{BookmarkTarget, CommitId, ObjectId, OperationId, ViewId};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::bloom::CommitFilter;
 
 /// Capabilities that can be negotiated between client and server.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
+///
+/// Serialized as snake_case names. Names this peer doesn't know, e.g. from a
+/// newer peer, become [`Capability::Unknown`] instead of failing to decode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Capability {
     /// Include operation log in sync
     Operations,
@@ -13,23 +19,372 @@ pub enum Capability {
     ThinPack,
     /// Resumable transfers
     Resumable,
+    /// Compact binary message bodies after the Hello exchange
+    BinaryFrames,
+    /// Compressed frame payloads
+    Compression,
+    /// CRC32C checksum trailing each frame
+    FrameChecksums,
+    /// Several requests in flight at once, matched to responses by
+    /// `request_id`
+    Pipelining,
+    /// Batched have/ack negotiation for fetches; see
+    /// [`negotiation`](crate::negotiation)
+    NegotiateV2,
+    /// A capability this peer doesn't know, by name
+    Unknown(String),
+}
+
+impl Capability {
+    /// Look up a capability by its wire name.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "operations" => Capability::Operations,
+            "thin_pack" => Capability::ThinPack,
+            "resumable" => Capability::Resumable,
+            "binary_frames" => Capability::BinaryFrames,
+            "compression" => Capability::Compression,
+            "frame_checksums" => Capability::FrameChecksums,
+            "pipelining" => Capability::Pipelining,
+            "negotiate_v2" => Capability::NegotiateV2,
+            other => Capability::Unknown(other.to_string()),
+        }
+    }
+
+    /// Get the wire name of this capability.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Capability::Operations => "operations",
+            Capability::ThinPack => "thin_pack",
+            Capability::Resumable => "resumable",
+            Capability::BinaryFrames => "binary_frames",
+            Capability::Compression => "compression",
+            Capability::FrameChecksums => "frame_checksums",
+            Capability::Pipelining => "pipelining",
+            Capability::NegotiateV2 => "negotiate_v2",
+            Capability::Unknown(name) => name,
+        }
+    }
+
+    /// Whether this peer knows what the capability means.
+    pub fn is_known(&self) -> bool {
+        !matches!(self, Capability::Unknown(_))
+    }
+
+    /// The protocol version that introduced this capability, or `None` for
+    /// capabilities this peer doesn't know.
+    pub fn since_version(&self) -> Option<u32> {
+        match self {
+            Capability::Operations
+            | Capability::ThinPack
+            | Capability::Resumable
+            | Capability::BinaryFrames
+            | Capability::Compression
+            | Capability::FrameChecksums
+            | Capability::Pipelining
+            | Capability::NegotiateV2 => Some(1),
+            Capability::Unknown(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Capability {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Capability {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(Capability::from_name(&name))
+    }
+}
+
+/// Compression algorithm for frame payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionAlgorithm {
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// All algorithms supported by this implementation, in preference order.
+    pub const SUPPORTED: &'static [CompressionAlgorithm] = &[CompressionAlgorithm::Zstd];
+
+    /// Pick the first algorithm offered by the client that we support.
+    pub fn negotiate(offered: &[CompressionAlgorithm]) -> Option<CompressionAlgorithm> {
+        offered
+            .iter()
+            .copied()
+            .find(|algorithm| Self::SUPPORTED.contains(algorithm))
+    }
+}
+
+/// Credentials presented in the Hello exchange.
+///
+/// The `Debug` output never includes secrets, so requests can be logged.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    /// Anonymous access
+    #[default]
+    None,
+    /// API token issued by the server
+    BearerToken(String),
+    /// SSH key already verified by the transport; carried for logging
+    SshKey {
+        /// Fingerprint of the client's key, e.g. `SHA256:...`
+        fingerprint: String,
+        /// Signature over the transport's challenge
+        signature: String,
+    },
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::None => f.write_str("None"),
+            Auth::BearerToken(_) => f.write_str("BearerToken(<redacted>)"),
+            Auth::SshKey { fingerprint, .. } => f
+                .debug_struct("SshKey")
+                .field("fingerprint", fingerprint)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Level of access to a repository.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLevel {
+    /// Fetch only
+    #[default]
+    Read,
+    /// Fetch and push
+    Write,
+}
+
+impl AccessLevel {
+    /// Get the wire name of this access level.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccessLevel::Read => "read",
+            AccessLevel::Write => "write",
+        }
+    }
+}
+
+impl std::fmt::Display for AccessLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A repository, identified by owner and name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RepoRef {
+    pub owner: String,
+    pub name: String,
+}
+
+impl RepoRef {
+    /// Create a reference to `owner/name`.
+    pub fn new(owner: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            owner: owner.into(),
+            name: name.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for RepoRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
 }
 
 /// Initial handshake from client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HelloRequest {
+    /// Newest protocol version the client supports
     pub protocol_version: u32,
     pub capabilities: Vec<Capability>,
     pub client_op_heads: Vec<OperationId>,
+    /// Compression algorithms the client accepts, in preference order
+    #[serde(default)]
+    pub compression: Vec<CompressionAlgorithm>,
+    /// Largest frame the client accepts, if not the default
+    #[serde(default)]
+    pub max_frame_size: Option<u32>,
+    /// Oldest protocol version the client supports, if not 1
+    #[serde(default)]
+    pub min_protocol_version: Option<u32>,
+    /// Credentials, if any
+    #[serde(default)]
+    pub auth: Auth,
+    /// Access the client needs for this session
+    #[serde(default)]
+    pub access: AccessLevel,
+    /// Repository to sync. A session serves a single repository.
+    #[serde(default)]
+    pub repo: Option<RepoRef>,
+    /// Create the repository if it doesn't exist yet, to push to it. The
+    /// server decides who may.
+    #[serde(default)]
+    pub create_if_missing: bool,
 }
 
 /// Server response to handshake.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HelloResponse {
+    /// Protocol version chosen for the session
     pub protocol_version: u32,
     pub capabilities: Vec<Capability>,
+    /// Operation heads of the repository selected in the Hello
     pub server_op_heads: Vec<OperationId>,
+    /// Common ancestor of the client's and the selected repository's
+    /// operations, if found
     pub common_ancestor: Option<OperationId>,
+    /// Compression algorithm selected by the server, if any
+    #[serde(default)]
+    pub compression: Option<CompressionAlgorithm>,
+    /// Largest frame the server accepts, if not the default
+    #[serde(default)]
+    pub max_frame_size: Option<u32>,
+    /// Who the client authenticated as, if anyone
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// Access granted for this session
+    #[serde(default)]
+    pub access: Option<AccessLevel>,
+    /// Limits the server enforces, so requests can be checked before
+    /// anything is sent
+    #[serde(default)]
+    pub limits: ServerLimits,
+    /// Further server settings by name, for ones this version doesn't know
+    /// of yet. Clients skip names they don't recognize.
+    #[serde(default)]
+    pub extensions: BTreeMap<String, String>,
+}
+
+/// Limits a server advertises in its [`HelloResponse`].
+///
+/// Everything is optional: a server that predates a limit, or doesn't
+/// impose it, leaves it out. The largest frame is
+/// [`HelloResponse::max_frame_size`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerLimits {
+    /// Largest pack the server accepts, in bytes, including its header and
+    /// trailer
+    #[serde(default)]
+    pub max_pack_size: Option<u64>,
+    /// Largest single object the server accepts in a pack, in bytes
+    #[serde(default)]
+    pub max_object_size: Option<u32>,
+    /// Frame compression algorithms the server can read
+    #[serde(default)]
+    pub compression: Vec<CompressionAlgorithm>,
+    /// Whether the server lets clients read without credentials, if it
+    /// says
+    #[serde(default)]
+    pub anonymous_read: Option<bool>,
+}
+
+/// Request for the refs on the server and where they point, without
+/// fetching anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListRefsRequest {
+    /// Patterns for the refs to list, as in [`FetchRequest::want_refs`];
+    /// empty for all
+    pub patterns: Vec<String>,
+    /// Identifies the request when several are in flight, echoed on the
+    /// response; see [`Capability::Pipelining`]
+    #[serde(default)]
+    pub request_id: Option<u64>,
+}
+
+/// The refs a [`ListRefsRequest`] matched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListRefsResponse {
+    /// The matching refs, in the order of the request's patterns
+    pub refs: Vec<RefInfo>,
+    /// The `request_id` of the request this answers
+    #[serde(default)]
+    pub request_id: Option<u64>,
+}
+
+/// A ref and where it points.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefInfo {
+    /// Name of the ref, tags prefixed with `tags/`
+    pub name: String,
+    /// Where the ref points
+    pub target: RefTargetWire,
+    /// Whether the ref is a tag rather than a bookmark
+    pub is_tag: bool,
+}
+
+/// Where a ref points, as advertised in a [`RefInfo`] and as expected by a
+/// [`RefUpdate`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefTargetWire {
+    /// The ref doesn't exist
+    Absent,
+    /// The ref points at one commit
+    Normal(CommitId),
+    /// Concurrent operations moved the ref to each of `adds`, from
+    /// `removes`, leaving it conflicted
+    Conflicted {
+        adds: Vec<CommitId>,
+        removes: Vec<CommitId>,
+    },
+}
+
+impl RefTargetWire {
+    /// The commit the ref points at, unless it is absent or conflicted.
+    pub fn as_normal(&self) -> Option<&CommitId> {
+        match self {
+            RefTargetWire::Normal(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Whether the ref is conflicted.
+    pub fn is_conflicted(&self) -> bool {
+        matches!(self, RefTargetWire::Conflicted { .. })
+    }
+}
+
+impl From<BookmarkTarget> for RefTargetWire {
+    fn from(target: BookmarkTarget) -> Self {
+        match target {
+            BookmarkTarget::Absent => RefTargetWire::Absent,
+            BookmarkTarget::Normal(id) => RefTargetWire::Normal(id),
+            BookmarkTarget::Conflicted { adds, removes } => {
+                RefTargetWire::Conflicted { adds, removes }
+            }
+        }
+    }
+}
+
+impl From<RefTargetWire> for BookmarkTarget {
+    fn from(target: RefTargetWire) -> Self {
+        match target {
+            RefTargetWire::Absent => BookmarkTarget::Absent,
+            RefTargetWire::Normal(id) => BookmarkTarget::Normal(id),
+            RefTargetWire::Conflicted { adds, removes } => {
+                BookmarkTarget::conflicted(adds, removes)
+            }
+        }
+    }
 }
 
 /// Fetch request from client.
@@ -37,10 +392,38 @@ pub struct HelloResponse {
 pub struct FetchRequest {
     /// Operations the client already has
     pub have_ops: Vec<OperationId>,
-    /// Bookmark names to fetch
+    /// Patterns for the refs to fetch, see [`crate::refs`]; empty for all
     pub want_refs: Vec<String>,
     /// Shallow fetch limit (optional)
     pub depth: Option<u32>,
+    /// Commits the client has without their full history, such as the
+    /// boundary of an earlier shallow fetch
+    #[serde(default)]
+    pub have_commits: Vec<CommitId>,
+    /// Path prefixes to fetch file contents for, see [`crate::sparse`];
+    /// empty for all
+    #[serde(default)]
+    pub path_filters: Vec<String>,
+    /// Commits the client probably has, see [`crate::bloom`]; the server
+    /// promises these instead of sending them
+    #[serde(default)]
+    pub have_commits_filter: Option<CommitFilter>,
+    /// Commits to send with their trees and files, as hex IDs or unique
+    /// prefixes of them, regardless of the refs and operations: say, a
+    /// commit CI was asked to build, or promised ones the client turned out
+    /// to lack. With no `want_refs`, only these are sent.
+    #[serde(default)]
+    pub want_commits: Vec<String>,
+    /// Whether to send the operations that brought the refs about, as a jj
+    /// client syncing its op log wants, or only the commits, as for a CI
+    /// clone. Unset means yes if [`Capability::Operations`] is mutual; the
+    /// response's [`FetchResponse::mode`] says which was done.
+    #[serde(default)]
+    pub include_operations: Option<bool>,
+    /// Identifies the request when several are in flight, echoed on the
+    /// response; see [`Capability::Pipelining`]
+    #[serde(default)]
+    pub request_id: Option<u64>,
 }
 
 /// Fetch response header.
@@ -52,6 +435,91 @@ pub struct FetchResponse {
     pub ops_to_send: Vec<OperationId>,
     /// Number of commits in the pack
     pub commit_count: u64,
+    /// Session for resuming the pack transfer if the connection drops
+    pub resume_session: Option<String>,
+    /// Refs the request's patterns matched, tags prefixed with `tags/`
+    #[serde(default)]
+    pub refs: Vec<String>,
+    /// For a shallow fetch, the sent commits whose parents were cut off
+    #[serde(default)]
+    pub shallow_boundary: Vec<CommitId>,
+    /// Whether path filters left files out of the pack as promised entries
+    #[serde(default)]
+    pub filtered: bool,
+    /// Number of [`OperationRecord`]s sent after the pack
+    #[serde(default)]
+    pub operation_count: u32,
+    /// The `request_id` of the request this answers
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    /// Entries of the request's `want_commits` that weren't sent
+    #[serde(default)]
+    pub want_errors: Vec<WantError>,
+    /// Whether operations or only commits were sent
+    #[serde(default)]
+    pub mode: FetchMode,
+    /// In [`FetchMode::CommitsOnly`], where the fetched refs point, for the
+    /// client to record in an operation of its own
+    #[serde(default)]
+    pub ref_targets: Vec<RefInfo>,
+}
+
+/// How a fetch brought the client up to date.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchMode {
+    /// The server's operations were sent, for the client to adopt as they
+    /// are
+    #[default]
+    Operations,
+    /// Only commits were sent; the client records the refs in
+    /// [`FetchResponse::ref_targets`] with an import operation of its own
+    CommitsOnly,
+}
+
+/// An entry of [`FetchRequest::want_commits`] the server couldn't send.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WantError {
+    /// The ID or prefix, as requested
+    pub want: String,
+    /// Why it wasn't sent
+    pub reason: WantReason,
+}
+
+/// Why a wanted commit wasn't sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WantReason {
+    /// Not a hex commit ID or prefix
+    Invalid,
+    /// No commit matches
+    NotFound,
+    /// More than one commit matches the prefix
+    Ambiguous,
+    /// The commit exists, but isn't reachable from any visible head and the
+    /// server doesn't serve such commits
+    Hidden,
+}
+
+/// More operations the client has, sent in answer to an [`AckReady`] that
+/// isn't ready.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HaveMore {
+    /// Further operations the client has, typically parents of the ones the
+    /// server didn't know
+    pub have_ops: Vec<OperationId>,
+    /// Whether the client has nothing more to offer
+    pub done: bool,
+}
+
+/// Server's acknowledgement of the operations a fetching client has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckReady {
+    /// Operations the client has that the server knows, so far
+    pub common_ops: Vec<OperationId>,
+    /// Whether the server has enough to send the pack. If not, the client
+    /// answers with [`HaveMore`].
+    pub ready: bool,
 }
 
 /// Push request from client.
@@ -61,6 +529,60 @@ pub struct PushRequest {
     pub have_ops: Vec<OperationId>,
     /// Reference updates to apply
     pub updates: Vec<RefUpdate>,
+    /// Whether the updates apply all or none; otherwise each one that
+    /// passes its checks applies on its own
+    #[serde(default)]
+    pub atomic: bool,
+    /// Number of [`OperationRecord`]s the client sends after the pack, or
+    /// after the server's [`PushNegotiate`] if it needs no objects
+    #[serde(default)]
+    pub operation_count: u32,
+    /// Identifies the request when several are in flight, echoed on the
+    /// result; see [`Capability::Pipelining`]
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    /// Names the push across retries: a push that repeats the id of one
+    /// the server has completed gets that push's result, and changes
+    /// nothing; see [`push_log`](crate::push_log)
+    #[serde(default)]
+    pub push_id: Option<String>,
+}
+
+/// One operation of the sender's operation log, with its view.
+///
+/// Records follow the pack of a fetch or push, parents before children, so
+/// the receiver can check that every parent exists before storing one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationRecord {
+    /// The operation's ID
+    pub id: OperationId,
+    /// The operations it was based on
+    pub parents: Vec<OperationId>,
+    /// What the operation did, as shown by `jj op log`
+    pub description: String,
+    /// The operation as stored
+    pub data: Vec<u8>,
+    /// ID of the operation's view
+    pub view_id: ViewId,
+    /// Contents of the view
+    pub view: ViewData,
+}
+
+/// Contents of an operation's view in an [`OperationRecord`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewData {
+    /// The view as stored
+    Full(Vec<u8>),
+    /// A [delta](crate::delta) against another view, one the receiver has
+    /// or was sent earlier. Only sent when both peers advertise
+    /// [`Capability::Operations`].
+    Delta {
+        /// The view the delta applies to
+        base: ViewId,
+        /// The delta
+        delta: Vec<u8>,
+    },
 }
 
 /// Reference update in a push.
@@ -68,10 +590,14 @@ pub struct PushRequest {
 pub struct RefUpdate {
     /// Name of the reference (bookmark)
     pub ref_name: String,
-    /// Expected current value (None for create)
-    pub old_id: Option<String>,
+    /// Where the bookmark must currently point: absent to create it, or
+    /// exactly the conflicted target a resolving push replaces
+    pub old_id: RefTargetWire,
     /// New value (None for delete)
     pub new_id: Option<String>,
+    /// Move the bookmark even if the new value doesn't descend from the old
+    #[serde(default)]
+    pub force: bool,
 }
 
 /// Server response to push negotiation.
@@ -81,10 +607,19 @@ pub struct PushNegotiate {
     pub common_op: Option<OperationId>,
     /// Whether the server needs objects
     pub need_objects: bool,
+    /// Commits the server probably has, which the pack may promise instead
+    /// of sending; see [`crate::bloom`]
+    #[serde(default)]
+    pub have_commits_filter: Option<CommitFilter>,
+    /// Promised commits the server lacks after all. When non-empty, this
+    /// follows the client's pack and operations, and asks for one more pack
+    /// with just these commits.
+    #[serde(default)]
+    pub want_commits: Vec<CommitId>,
 }
 
 /// Final push result.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PushResult {
     /// Overall status
     pub status: PushStatus,
@@ -92,6 +627,19 @@ pub struct PushResult {
     pub new_op_head: Option<OperationId>,
     /// Per-reference results
     pub ref_results: Vec<RefResult>,
+    /// The `request_id` of the request this answers
+    #[serde(default)]
+    pub request_id: Option<u64>,
+    /// Objects in the push's packs that the server stored
+    #[serde(default)]
+    pub new_objects: u64,
+    /// Objects in the push's packs that the server already had, or that
+    /// came more than once
+    #[serde(default)]
+    pub skipped_objects: u64,
+    /// Whether the repository was created for this push
+    #[serde(default)]
+    pub created_repo: bool,
 }
 
 /// Push status.
@@ -104,11 +652,15 @@ pub enum PushStatus {
 }
 
 /// Result for a single reference update.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RefResult {
     pub ref_name: String,
     pub status: RefStatus,
+    /// Human-readable explanation of a refusal
     pub message: Option<String>,
+    /// Why the update was refused, for clients to act on
+    #[serde(default)]
+    pub reason: Option<RefReason>,
 }
 
 /// Status for a single reference update.
@@ -123,9 +675,190 @@ pub enum RefStatus {
     Conflict,
 }
 
+/// Structured reason a reference update was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefReason {
+    /// The bookmark isn't where the update expected
+    Stale {
+        /// Where the bookmark points; a push can present a conflicted
+        /// target as it is to resolve it
+        actual: RefTargetWire,
+    },
+    /// The update would drop commits from the bookmark and isn't forced
+    NotFastForward {
+        /// Commits of the old target the new one lacks
+        behind: u64,
+    },
+    /// The server's policy doesn't allow this update to the bookmark
+    Protected,
+    /// The bookmark name isn't allowed
+    InvalidName { why: String },
+    /// The push would take the repository over a limit
+    QuotaExceeded { limit: u64, attempted: u64 },
+    /// The repository is archived and takes no pushes
+    Archived,
+}
+
+/// One piece of a pack streamed across multiple frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackChunk {
+    /// Position of this chunk in the transfer, starting at 0
+    pub sequence: u64,
+    /// Raw pack bytes
+    pub data: Vec<u8>,
+    /// Whether this is the last chunk of the pack
+    pub last: bool,
+    /// Pack trailer hash, set on the last chunk
+    pub pack_hash: Option<ObjectId>,
+}
+
+/// Acknowledgement of pack bytes received in a resumable transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackAck {
+    pub session_id: String,
+    /// Total pack bytes received so far
+    pub received_bytes: u64,
+}
+
+/// Request to continue an interrupted pack transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeRequest {
+    pub session_id: String,
+    /// Number of pack bytes the client already has
+    pub received_bytes: u64,
+    /// Hash of the pack bytes the client already has
+    pub received_hash: ObjectId,
+}
+
+/// Server response to a resume request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeResponse {
+    /// Offset the pack stream continues from, or 0 if the transfer restarts
+    pub offset: u64,
+}
+
+/// Keepalive request. The peer answers with a [`Pong`] echoing the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ping {
+    /// Opaque data echoed back in the Pong
+    pub payload: [u8; 8],
+}
+
+/// Answer to a [`Ping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pong {
+    pub payload: [u8; 8],
+}
+
+/// Request to abort the operation in progress.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cancel {
+    /// Why the client is cancelling, for server logs
+    pub reason: Option<String>,
+}
+
+/// Acknowledgement that the operation in progress was cancelled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelAck {}
+
+/// Category of an error reported by the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The repository or another requested resource doesn't exist
+    NotFound,
+    /// The client isn't allowed to perform the operation
+    PermissionDenied,
+    /// A size or rate limit was exceeded
+    QuotaExceeded,
+    /// The peer sent something the protocol doesn't allow
+    ProtocolViolation,
+    /// Unexpected failure on the reporting side
+    Internal,
+    /// The peers have no protocol version in common
+    UnsupportedVersion,
+}
+
+impl ErrorCode {
+    /// Get the wire name of this code.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::ProtocolViolation => "protocol_violation",
+            ErrorCode::Internal => "internal",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error reported by the peer, terminating the current exchange.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    /// Human-readable description
+    pub message: String,
+    /// Whether retrying the same request later may succeed
+    pub retryable: bool,
+}
+
+impl ErrorMessage {
+    /// Create a non-retryable error.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    /// Create an error the client may retry later.
+    pub fn retryable(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            retryable: true,
+            ..Self::new(code, message)
+        }
+    }
+}
+
+/// Phase of a long-running operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressPhase {
+    /// Enumerating objects to send, counted in objects
+    Counting,
+    /// Building the pack, counted in objects
+    Compressing,
+    /// Streaming the pack, counted in bytes
+    Sending,
+}
+
+/// Progress update during a long-running operation.
+///
+/// Progress is sideband information: receivers may ignore it entirely.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProgressMessage {
+    pub phase: ProgressPhase,
+    /// Units of work completed in this phase
+    pub current: u64,
+    /// Total units of work in this phase, if known
+    pub total: Option<u64>,
+    /// Bytes produced or sent so far, if meaningful for this phase
+    pub bytes: Option<u64>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::envelope::{Message, WireFormat, decode_message, encode_message};
 
     #[test]
     fn test_hello_request_serialization() {
@@ -133,12 +866,143 @@ mod tests {
             protocol_version: 1,
             capabilities: vec![Capability::Operations],
             client_op_heads: vec![],
+            compression: vec![],
+            max_frame_size: None,
+            min_protocol_version: None,
+            auth: Auth::None,
+            access: AccessLevel::Read,
+            repo: None,
+            create_if_missing: false,
         };
 
-        let json = serde_json::to_string(&request).unwrap();
-        let parsed: HelloRequest = serde_json::from_str(&json).unwrap();
+        let encoded = encode_message(&Message::Hello(request), WireFormat::Json).unwrap();
+        let parsed =
+            HelloRequest::try_from(decode_message(&encoded, WireFormat::Json).unwrap()).unwrap();
 
         assert_eq!(parsed.protocol_version, 1);
         assert_eq!(parsed.capabilities, vec![Capability::Operations]);
     }
+
+    #[test]
+    fn test_operation_ids_serialize_as_hex() {
+        let op_id = OperationId::hash(b"op");
+        let request = HelloRequest {
+            protocol_version: 1,
+            capabilities: vec![],
+            client_op_heads: vec![op_id],
+            compression: vec![],
+            max_frame_size: None,
+            min_protocol_version: None,
+            auth: Auth::None,
+            access: AccessLevel::Read,
+            repo: None,
+            create_if_missing: false,
+        };
+
+        let value = serde_json::to_value(&request).unwrap();
+        let head = value["client_op_heads"][0].as_str().unwrap();
+        assert_eq!(head.len(), 64);
+        assert_eq!(head, op_id.to_hex());
+
+        let parsed: HelloRequest = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.client_op_heads, vec![op_id]);
+    }
+
+    #[test]
+    fn test_hello_without_compression_field() {
+        let json = r#"{"protocol_version":1,"capabilities":[],"client_op_heads":[]}"#;
+        let parsed: HelloRequest = serde_json::from_str(json).unwrap();
+        assert!(parsed.compression.is_empty());
+        assert_eq!(parsed.max_frame_size, None);
+        assert_eq!(parsed.auth, Auth::None);
+        assert_eq!(parsed.access, AccessLevel::Read);
+        assert_eq!(parsed.repo, None);
+    }
+
+    #[test]
+    fn test_hello_response_limits() {
+        let response = HelloResponse {
+            protocol_version: 1,
+            capabilities: vec![],
+            server_op_heads: vec![],
+            common_ancestor: None,
+            compression: None,
+            max_frame_size: None,
+            identity: None,
+            access: Some(AccessLevel::Read),
+            limits: ServerLimits {
+                max_pack_size: Some(2 << 30),
+                max_object_size: Some(1 << 20),
+                compression: vec![CompressionAlgorithm::Zstd],
+                anonymous_read: Some(true),
+            },
+            extensions: BTreeMap::from([("max_refs".to_string(), "1000".to_string())]),
+        };
+        let encoded =
+            encode_message(&Message::HelloOk(response.clone()), WireFormat::Json).unwrap();
+        let parsed =
+            HelloResponse::try_from(decode_message(&encoded, WireFormat::Json).unwrap()).unwrap();
+        assert_eq!(parsed.limits, response.limits);
+        assert_eq!(parsed.extensions, response.extensions);
+
+        // A server that predates limits.
+        let json = r#"{"protocol_version":1,"capabilities":[],"server_op_heads":[],"common_ancestor":null}"#;
+        let parsed: HelloResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.limits, ServerLimits::default());
+        assert!(parsed.extensions.is_empty());
+
+        // Limits from a newer server that this version doesn't know.
+        let json = r#"{"protocol_version":1,"capabilities":[],"server_op_heads":[],"common_ancestor":null,"limits":{"max_pack_size":64,"max_refs":10},"extensions":{"quantum":"yes"}}"#;
+        let parsed: HelloResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.limits.max_pack_size, Some(64));
+        assert_eq!(parsed.extensions["quantum"], "yes");
+    }
+
+    #[test]
+    fn test_capability_names_roundtrip() {
+        let capabilities = vec![
+            Capability::Operations,
+            Capability::ThinPack,
+            Capability::Resumable,
+            Capability::BinaryFrames,
+            Capability::Compression,
+            Capability::FrameChecksums,
+            Capability::Pipelining,
+            Capability::NegotiateV2,
+        ];
+        let json = serde_json::to_string(&capabilities).unwrap();
+        assert_eq!(
+            json,
+            r#"["operations","thin_pack","resumable","binary_frames","compression","frame_checksums","pipelining","negotiate_v2"]"#
+        );
+        let parsed: Vec<Capability> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, capabilities);
+    }
+
+    #[test]
+    fn test_unknown_capability_is_preserved() {
+        let json = r#"{"protocol_version":2,"capabilities":["thin_pack","quantum_sync"],"client_op_heads":[]}"#;
+        let parsed: HelloRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            parsed.capabilities,
+            vec![
+                Capability::ThinPack,
+                Capability::Unknown("quantum_sync".to_string())
+            ]
+        );
+        assert!(!parsed.capabilities[1].is_known());
+        assert_eq!(parsed.capabilities[1].since_version(), None);
+
+        let value = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(value["capabilities"][1], "quantum_sync");
+    }
+
+    #[test]
+    fn test_negotiate_compression() {
+        assert_eq!(
+            CompressionAlgorithm::negotiate(&[CompressionAlgorithm::Zstd]),
+            Some(CompressionAlgorithm::Zstd)
+        );
+        assert_eq!(CompressionAlgorithm::negotiate(&[]), None);
+    }
 }
@@ -0,0 +1,987 @@
+//! Operation-log negotiation for fetches.
+//!
+//! A fetching client lists the operations it has in its fetch request. The
+//! server keeps the ones it knows and works out what the client is missing:
+//! every operation that isn't an ancestor of a common one, and the commits
+//! those operations' views make reachable that the client's views don't.
+//!
+//! Operations the server doesn't know, typically ones the client made
+//! locally, are ignored. When the client offered any, the server answers
+//! with an [`AckReady`] that isn't ready, and the client offers their parents
+//! in a [`HaveMore`], for up to a configurable number of rounds.
+//!
+//! That goes back one generation per round, which isn't far when the client
+//! has a long run of local operations. With [`Capability::NegotiateV2`],
+//! the negotiation is batched instead, much like git's: the client walks its
+//! log from the heads and offers growing batches of operations, whatever
+//! the server said about the last one. Each [`AckReady`] lists the
+//! operations of the last batch the server has (none is a "nak"), and the
+//! client stops walking past them, since the server has all their
+//! ancestors too. The client says when it has nothing more to offer, and
+//! the server caps the rounds and the total number of operations offered.
+//!
+//! [`Capability::NegotiateV2`]: crate::messages::Capability::NegotiateV2
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use forjj_storage::{CommitId, OperationEntry, OperationId, Repository};
+
+use crate::error::ProtocolError;
+use crate::messages::{AckReady, HaveMore};
+
+/// Default limit on HaveMore rounds in one fetch.
+pub const DEFAULT_MAX_ROUNDS: u32 = 4;
+
+/// Default limit on the operations a client may offer in a batched
+/// negotiation.
+pub const DEFAULT_MAX_HAVES: u32 = 4096;
+
+/// Operations in a client's first batch; each later batch doubles, up to
+/// [`MAX_HAVE_BATCH`].
+pub const FIRST_HAVE_BATCH: usize = 32;
+
+/// Most operations in one batch.
+pub const MAX_HAVE_BATCH: usize = 1024;
+
+/// A repository's operation log, loaded for negotiation.
+#[derive(Debug, Clone, Default)]
+pub struct OpGraph {
+    heads: Vec<OperationId>,
+    ops: HashMap<OperationId, OperationEntry>,
+}
+
+impl OpGraph {
+    /// Create a graph with the given heads and no operations yet.
+    pub fn new(heads: Vec<OperationId>) -> Self {
+        Self {
+            heads,
+            ops: HashMap::new(),
+        }
+    }
+
+    /// Load the operation log of `repo`.
+    pub async fn load(repo: &Repository) -> Result<Self, ProtocolError> {
+        let mut graph = Self::new(repo.op_head_ids().await?);
+        for entry in repo.operation_log().await? {
+            graph.insert(entry);
+        }
+        Ok(graph)
+    }
+
+    /// Add an operation.
+    pub fn insert(&mut self, entry: OperationEntry) {
+        self.ops.insert(entry.id, entry);
+    }
+
+    /// The head operations.
+    pub fn heads(&self) -> &[OperationId] {
+        &self.heads
+    }
+
+    /// Whether the graph has `op`.
+    pub fn contains(&self, op: &OperationId) -> bool {
+        self.ops.contains_key(op)
+    }
+
+    /// Get an operation.
+    pub fn get(&self, op: &OperationId) -> Option<&OperationEntry> {
+        self.ops.get(op)
+    }
+
+    /// Number of operations in the graph.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the graph has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// `ops` and all their ancestors. Operations not in the graph are
+    /// skipped.
+    pub fn ancestors<'b>(
+        &self,
+        ops: impl IntoIterator<Item = &'b OperationId>,
+    ) -> HashSet<OperationId> {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<_> = ops.into_iter().copied().collect();
+        while let Some(op) = pending.pop() {
+            let Some(entry) = self.ops.get(&op) else {
+                continue;
+            };
+            if seen.insert(op) {
+                pending.extend(&entry.parents);
+            }
+        }
+        seen
+    }
+
+    /// Operations reachable from the heads that aren't in `known` or
+    /// ancestors of them, parents before children.
+    pub fn missing(&self, known: &[OperationId]) -> Vec<OperationId> {
+        // Post-order walk from the heads, so parents come first.
+        let mut missing = Vec::new();
+        let mut visited = self.ancestors(known);
+        for head in &self.heads {
+            let mut stack = vec![(*head, false)];
+            while let Some((op, expanded)) = stack.pop() {
+                if expanded {
+                    missing.push(op);
+                    continue;
+                }
+                let Some(entry) = self.ops.get(&op) else {
+                    continue;
+                };
+                if !visited.insert(op) {
+                    continue;
+                }
+                stack.push((op, true));
+                for parent in &entry.parents {
+                    if !visited.contains(parent) {
+                        stack.push((*parent, false));
+                    }
+                }
+            }
+        }
+        missing
+    }
+
+    /// Head commits of the views of `ops`.
+    fn view_heads<'b>(&self, ops: impl IntoIterator<Item = &'b OperationId>) -> Vec<CommitId> {
+        ops.into_iter()
+            .filter_map(|op| self.ops.get(op))
+            .flat_map(|entry| entry.view_heads.iter().copied())
+            .collect()
+    }
+
+    /// The latest of `ops` this graph has: one that isn't an ancestor of
+    /// another one it has. Returns `None` if it has none of them.
+    pub fn common_ancestor(&self, ops: &[OperationId]) -> Option<OperationId> {
+        let known: Vec<_> = ops.iter().filter(|op| self.contains(op)).collect();
+        known
+            .iter()
+            .find(|&&candidate| {
+                known.iter().all(|&other| {
+                    other == candidate || !self.ancestors([other]).contains(candidate)
+                })
+            })
+            .map(|&&op| op)
+    }
+}
+
+/// What a fetching client is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchPlan {
+    /// Operations the client doesn't have, parents before children
+    pub missing_ops: Vec<OperationId>,
+    /// The server's operation heads among them, for the client to adopt
+    pub new_heads: Vec<OperationId>,
+    /// Commits reachable from the missing operations' views but not from
+    /// the views of operations the client has
+    pub commits: Vec<CommitId>,
+}
+
+/// Server side of a fetch negotiation.
+#[derive(Debug)]
+pub struct Negotiation<'a> {
+    graph: &'a OpGraph,
+    common: Vec<OperationId>,
+    /// Operations in the last offer that the server has
+    acked: Vec<OperationId>,
+    /// Whether the operations last offered included some the server
+    /// doesn't know
+    missed: bool,
+    done: bool,
+    rounds: u32,
+    max_rounds: u32,
+    /// Operations offered so far, counted in a batched negotiation
+    haves: u32,
+    /// Limit on `haves`, if batched
+    max_haves: Option<u32>,
+}
+
+impl<'a> Negotiation<'a> {
+    /// Start with the operations from the client's fetch request, asking for
+    /// more at most `max_rounds` times.
+    pub fn new(graph: &'a OpGraph, have_ops: &[OperationId], max_rounds: u32) -> Self {
+        Self::start(graph, have_ops, max_rounds, None)
+    }
+
+    /// Start a batched negotiation, for [`Capability::NegotiateV2`]: ask
+    /// for more until the client is done, at most `max_rounds` times, and
+    /// take at most `max_haves` operations in all.
+    ///
+    /// [`Capability::NegotiateV2`]: crate::messages::Capability::NegotiateV2
+    pub fn batched(
+        graph: &'a OpGraph,
+        have_ops: &[OperationId],
+        max_rounds: u32,
+        max_haves: u32,
+    ) -> Self {
+        Self::start(graph, have_ops, max_rounds, Some(max_haves))
+    }
+
+    fn start(
+        graph: &'a OpGraph,
+        have_ops: &[OperationId],
+        max_rounds: u32,
+        max_haves: Option<u32>,
+    ) -> Self {
+        let mut negotiation = Self {
+            graph,
+            common: Vec::new(),
+            acked: Vec::new(),
+            missed: false,
+            done: false,
+            rounds: 0,
+            max_rounds,
+            haves: 0,
+            max_haves,
+        };
+        negotiation.offer(have_ops);
+        negotiation
+    }
+
+    /// Take the client's answer to an [`AckReady`] that wasn't ready.
+    pub fn receive(&mut self, more: &HaveMore) {
+        self.rounds += 1;
+        self.offer(&more.have_ops);
+        self.done |= more.done;
+    }
+
+    fn offer(&mut self, have_ops: &[OperationId]) {
+        self.missed = false;
+        self.acked.clear();
+        for op in have_ops {
+            if let Some(max_haves) = self.max_haves {
+                // Past the limit, offers are ignored.
+                if self.haves >= max_haves {
+                    break;
+                }
+                self.haves += 1;
+            }
+            if !self.graph.contains(op) {
+                self.missed = true;
+            } else {
+                self.acked.push(*op);
+                if !self.common.contains(op) {
+                    self.common.push(*op);
+                }
+            }
+        }
+    }
+
+    /// Whether to stop asking for more: the round limit is reached, or the
+    /// client has nothing more to offer. Unbatched, also when the client
+    /// offered nothing unknown last time; batched, when the client's first
+    /// offer had nothing unknown, or the limit on operations is reached.
+    pub fn is_ready(&self) -> bool {
+        if self.done || self.rounds >= self.max_rounds {
+            return true;
+        }
+        match self.max_haves {
+            Some(max_haves) => (self.rounds == 0 && !self.missed) || self.haves >= max_haves,
+            None => !self.missed,
+        }
+    }
+
+    /// Number of HaveMore messages received.
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// Operations the client offered that the server has.
+    pub fn common(&self) -> &[OperationId] {
+        &self.common
+    }
+
+    /// The acknowledgement to send the client: every operation in common so
+    /// far or, batched, the ones from the last offer.
+    pub fn ack(&self) -> AckReady {
+        let common_ops = match self.max_haves {
+            Some(_) => self.acked.clone(),
+            None => self.common.clone(),
+        };
+        AckReady {
+            common_ops,
+            ready: self.is_ready(),
+        }
+    }
+
+    /// Work out what the client is missing, walking the commit graph with
+    /// `commit_parents`.
+    ///
+    /// Every commit reachable from the views of the client's operations is
+    /// visited, so the cost grows with the shared history, not just the new
+    /// part of it.
+    pub fn plan<E>(
+        &self,
+        mut commit_parents: impl FnMut(&CommitId) -> Result<Vec<CommitId>, E>,
+    ) -> Result<FetchPlan, E> {
+        let have = self.graph.ancestors(&self.common);
+        let missing_ops = self.graph.missing(&self.common);
+
+        let mut new_heads = Vec::new();
+        for head in &self.graph.heads {
+            if !have.contains(head) && !new_heads.contains(head) {
+                new_heads.push(*head);
+            }
+        }
+
+        // Everything the client's views reach...
+        let mut seen = HashSet::new();
+        let mut pending = self.graph.view_heads(&have);
+        while let Some(commit) = pending.pop() {
+            if seen.insert(commit) {
+                pending.extend(commit_parents(&commit)?);
+            }
+        }
+        // ...stops the walk from the missing operations' views.
+        let mut commits = Vec::new();
+        let mut pending = self.graph.view_heads(&missing_ops);
+        while let Some(commit) = pending.pop() {
+            if seen.insert(commit) {
+                commits.push(commit);
+                pending.extend(commit_parents(&commit)?);
+            }
+        }
+
+        Ok(FetchPlan {
+            missing_ops,
+            new_heads,
+            commits,
+        })
+    }
+}
+
+/// Client side of a fetch negotiation.
+///
+/// Each time the server isn't ready, offers the parents of the operations
+/// last offered that the server didn't know. Batched, offers the next batch
+/// of the client's log instead, leaving out the ancestors of operations the
+/// server acknowledged.
+#[derive(Debug, Clone, Default)]
+pub struct HaveWalker {
+    offered: HashSet<OperationId>,
+    last: Vec<OperationId>,
+    batches: Option<Batches>,
+}
+
+/// Where a batched walk is.
+#[derive(Debug, Clone, Default)]
+struct Batches {
+    /// Operations to offer, nearest the heads first
+    queue: VecDeque<OperationId>,
+    /// Operations the server has, with their ancestors
+    common: HashSet<OperationId>,
+    /// Size of the next batch
+    size: usize,
+    /// Whether the request's operations have been walked from
+    started: bool,
+}
+
+impl HaveWalker {
+    /// Start from the operations in the fetch request.
+    pub fn new(have_ops: &[OperationId]) -> Self {
+        Self {
+            offered: have_ops.iter().copied().collect(),
+            last: have_ops.to_vec(),
+            batches: None,
+        }
+    }
+
+    /// Start a batched walk from the operations in the fetch request, for
+    /// [`Capability::NegotiateV2`].
+    ///
+    /// [`Capability::NegotiateV2`]: crate::messages::Capability::NegotiateV2
+    pub fn batched(have_ops: &[OperationId]) -> Self {
+        Self {
+            batches: Some(Batches {
+                size: FIRST_HAVE_BATCH,
+                ..Batches::default()
+            }),
+            ..Self::new(have_ops)
+        }
+    }
+
+    /// Answer `ack` with operations from the client's `graph`.
+    ///
+    /// Without a graph, or with nothing older to offer, the answer is done.
+    pub fn answer(&mut self, graph: Option<&OpGraph>, ack: &AckReady) -> HaveMore {
+        let Some(graph) = graph else {
+            return HaveMore {
+                have_ops: vec![],
+                done: true,
+            };
+        };
+        if let Some(batches) = &mut self.batches {
+            return batches.next(graph, ack, &self.last, &mut self.offered);
+        }
+
+        let mut next = Vec::new();
+        for op in &self.last {
+            if ack.common_ops.contains(op) {
+                continue;
+            }
+            for parent in graph.get(op).into_iter().flat_map(|entry| &entry.parents) {
+                if self.offered.insert(*parent) {
+                    next.push(*parent);
+                }
+            }
+        }
+        self.last = next.clone();
+        HaveMore {
+            done: next.is_empty(),
+            have_ops: next,
+        }
+    }
+}
+
+impl Batches {
+    /// The next batch, after the server acknowledged `ack`. The first one
+    /// walks from `request_ops`, the operations in the fetch request.
+    fn next(
+        &mut self,
+        graph: &OpGraph,
+        ack: &AckReady,
+        request_ops: &[OperationId],
+        offered: &mut HashSet<OperationId>,
+    ) -> HaveMore {
+        self.common.extend(graph.ancestors(&ack.common_ops));
+        if !self.started {
+            self.started = true;
+            for op in request_ops {
+                enqueue_parents(graph, op, offered, &mut self.queue);
+            }
+        }
+
+        let mut batch = Vec::new();
+        while batch.len() < self.size {
+            let Some(op) = self.queue.pop_front() else {
+                break;
+            };
+            if self.common.contains(&op) {
+                continue;
+            }
+            enqueue_parents(graph, &op, offered, &mut self.queue);
+            batch.push(op);
+        }
+        self.size = (self.size * 2).min(MAX_HAVE_BATCH);
+        // Whatever is left may all be common by now, but only the server
+        // can tell.
+        HaveMore {
+            done: self.queue.is_empty(),
+            have_ops: batch,
+        }
+    }
+}
+
+/// Queue the parents of `op` in `graph` that haven't been offered.
+fn enqueue_parents(
+    graph: &OpGraph,
+    op: &OperationId,
+    offered: &mut HashSet<OperationId>,
+    queue: &mut VecDeque<OperationId>,
+) {
+    for parent in graph.get(op).into_iter().flat_map(|entry| &entry.parents) {
+        if offered.insert(*parent) {
+            queue.push_back(*parent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forjj_storage::ObjectId;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::convert::Infallible;
+
+    fn op_id(name: &str) -> OperationId {
+        OperationId::hash(format!("op {name}").as_bytes())
+    }
+
+    fn commit_id(name: &str) -> CommitId {
+        CommitId::hash(format!("commit {name}").as_bytes())
+    }
+
+    fn entry(name: &str, parents: &[&str], view_heads: &[&str]) -> OperationEntry {
+        OperationEntry {
+            id: op_id(name),
+            parents: parents.iter().map(|parent| op_id(parent)).collect(),
+            view_id: ObjectId::hash(format!("view {name}").as_bytes()),
+            view_heads: view_heads.iter().map(|commit| commit_id(commit)).collect(),
+            description: format!("operation {name}"),
+        }
+    }
+
+    fn graph(heads: &[&str], entries: Vec<OperationEntry>) -> OpGraph {
+        let mut graph = OpGraph::new(heads.iter().map(|head| op_id(head)).collect());
+        for entry in entries {
+            graph.insert(entry);
+        }
+        graph
+    }
+
+    /// A random operation log and the commit graph its views refer to.
+    struct History {
+        graph: OpGraph,
+        ops: Vec<OperationId>,
+        commit_parents: HashMap<CommitId, Vec<CommitId>>,
+    }
+
+    impl History {
+        fn parents(&self, commit: &CommitId) -> Result<Vec<CommitId>, Infallible> {
+            Ok(self.commit_parents[commit].clone())
+        }
+    }
+
+    /// Up to `max` distinct items from `from`, at least one if possible.
+    fn pick(rng: &mut StdRng, from: &[ObjectId], max: usize) -> Vec<ObjectId> {
+        if from.is_empty() {
+            return Vec::new();
+        }
+        let mut picked = Vec::new();
+        for _ in 0..rng.random_range(1..=max) {
+            let id = from[rng.random_range(0..from.len())];
+            if !picked.contains(&id) {
+                picked.push(id);
+            }
+        }
+        picked
+    }
+
+    /// Random op and commit DAGs, built in order so parents come first.
+    /// Operations are named with `prefix` and only pick parents from `base`
+    /// and each other.
+    fn random_history(rng: &mut StdRng, prefix: &str, base: &[OperationId]) -> History {
+        let mut commits = Vec::new();
+        let mut commit_parents = HashMap::new();
+        for i in 0..rng.random_range(1..16) {
+            let id = commit_id(&format!("{prefix}{i}"));
+            commit_parents.insert(id, pick(rng, &commits, 2));
+            commits.push(id);
+        }
+
+        let mut graph = OpGraph::default();
+        let mut ops = base.to_vec();
+        for i in 0..rng.random_range(1..16) {
+            let name = format!("{prefix}{i}");
+            let parents = pick(rng, &ops, 2);
+            let view_heads = pick(rng, &commits, 3);
+            graph.insert(OperationEntry {
+                id: op_id(&name),
+                parents,
+                view_id: ObjectId::hash(name.as_bytes()),
+                view_heads,
+                description: name.clone(),
+            });
+            ops.push(op_id(&name));
+        }
+        let ops: Vec<_> = ops.split_off(base.len());
+        graph.heads = ops
+            .iter()
+            .filter(|op| !graph.ops.values().any(|entry| entry.parents.contains(op)))
+            .copied()
+            .collect();
+        History {
+            graph,
+            ops,
+            commit_parents,
+        }
+    }
+
+    /// Brute force: grow `start` by parents until nothing changes.
+    fn reachable(
+        start: &[ObjectId],
+        parents: impl Fn(&ObjectId) -> Vec<ObjectId>,
+    ) -> HashSet<ObjectId> {
+        let mut set: HashSet<_> = start.iter().copied().collect();
+        loop {
+            let next: HashSet<_> = set
+                .iter()
+                .flat_map(&parents)
+                .chain(set.iter().copied())
+                .collect();
+            if next.len() == set.len() {
+                return set;
+            }
+            set = next;
+        }
+    }
+
+    /// Check `plan` against a brute-force computation for a client that has
+    /// the server's operations `client_has`.
+    fn check_plan(history: &History, client_has: &[OperationId], plan: &FetchPlan) {
+        let graph = &history.graph;
+        let op_parents = |op: &OperationId| graph.get(op).map_or(vec![], |e| e.parents.clone());
+        let commit_parents = |commit: &CommitId| history.commit_parents[commit].clone();
+        let view_heads = |ops: &HashSet<OperationId>| -> Vec<CommitId> {
+            ops.iter()
+                .flat_map(|op| graph.get(op).unwrap().view_heads.clone())
+                .collect()
+        };
+
+        let all = reachable(graph.heads(), op_parents);
+        let have = reachable(client_has, op_parents);
+        let missing: HashSet<_> = all.difference(&have).copied().collect();
+        let commits: HashSet<_> = reachable(&view_heads(&missing), commit_parents)
+            .difference(&reachable(&view_heads(&have), commit_parents))
+            .copied()
+            .collect();
+
+        assert_eq!(plan.missing_ops.len(), missing.len());
+        assert_eq!(
+            plan.missing_ops.iter().copied().collect::<HashSet<_>>(),
+            missing
+        );
+        assert_eq!(plan.commits.len(), commits.len());
+        assert_eq!(
+            plan.commits.iter().copied().collect::<HashSet<_>>(),
+            commits
+        );
+        let new_heads: HashSet<_> = graph
+            .heads()
+            .iter()
+            .filter(|head| missing.contains(head))
+            .copied()
+            .collect();
+        assert_eq!(
+            plan.new_heads.iter().copied().collect::<HashSet<_>>(),
+            new_heads
+        );
+
+        // Parents before children.
+        for (i, op) in plan.missing_ops.iter().enumerate() {
+            for parent in &graph.get(op).unwrap().parents {
+                if missing.contains(parent) {
+                    assert!(plan.missing_ops[..i].contains(parent));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_matches_brute_force() {
+        for seed in 0..300 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let history = random_history(&mut rng, "", &[]);
+
+            let mut have_ops = pick(&mut rng, &history.ops, 3);
+            have_ops.truncate(rng.random_range(0..=have_ops.len()));
+            if rng.random_bool(0.3) {
+                have_ops.push(op_id("unknown"));
+            }
+
+            let negotiation = Negotiation::new(&history.graph, &have_ops, 0);
+            assert!(negotiation.is_ready());
+            let plan = negotiation.plan(|id| history.parents(id)).unwrap();
+            let known: Vec<_> = have_ops
+                .iter()
+                .filter(|op| history.graph.contains(op))
+                .copied()
+                .collect();
+            check_plan(&history, &known, &plan);
+        }
+    }
+
+    #[test]
+    fn test_rounds_find_common_operations_behind_local_ones() {
+        for seed in 0..300 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let server = random_history(&mut rng, "", &[]);
+
+            // The client has some of the server's history plus operations
+            // of its own on top.
+            let base = pick(&mut rng, &server.ops, 2);
+            let local = random_history(&mut rng, "local", &base);
+            let mut client = local.graph.clone();
+            for op in server.graph.ancestors(&base) {
+                client.insert(server.graph.get(&op).unwrap().clone());
+            }
+            let have_ops = client.heads().to_vec();
+            let shared: Vec<_> = client
+                .ancestors(&have_ops)
+                .into_iter()
+                .filter(|op| server.graph.contains(op))
+                .collect();
+
+            let mut negotiation = Negotiation::new(&server.graph, &have_ops, 100);
+            let mut walker = HaveWalker::new(&have_ops);
+            while !negotiation.is_ready() {
+                let more = walker.answer(Some(&client), &negotiation.ack());
+                negotiation.receive(&more);
+            }
+            assert!(negotiation.rounds() as usize <= local.ops.len());
+
+            let plan = negotiation.plan(|id| server.parents(id)).unwrap();
+            check_plan(&server, &shared, &plan);
+        }
+    }
+
+    #[test]
+    fn test_batches_find_common_operations_behind_local_ones() {
+        for seed in 0..300 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let server = random_history(&mut rng, "", &[]);
+            let base = pick(&mut rng, &server.ops, 2);
+            let local = random_history(&mut rng, "local", &base);
+            let mut client = local.graph.clone();
+            for op in server.graph.ancestors(&base) {
+                client.insert(server.graph.get(&op).unwrap().clone());
+            }
+            let have_ops = client.heads().to_vec();
+            let shared: Vec<_> = client
+                .ancestors(&have_ops)
+                .into_iter()
+                .filter(|op| server.graph.contains(op))
+                .collect();
+
+            let mut negotiation =
+                Negotiation::batched(&server.graph, &have_ops, 100, DEFAULT_MAX_HAVES);
+            let mut walker = HaveWalker::batched(&have_ops);
+            while !negotiation.is_ready() {
+                let more = walker.answer(Some(&client), &negotiation.ack());
+                negotiation.receive(&more);
+            }
+            // These logs fit in the first batch.
+            assert!(negotiation.rounds() <= 1);
+
+            let plan = negotiation.plan(|id| server.parents(id)).unwrap();
+            check_plan(&server, &shared, &plan);
+        }
+    }
+
+    /// A chain of `count` operations named `prefix0`, `prefix1`, ... on top
+    /// of `base`, each with a view of a commit of the same name on top of
+    /// the last one.
+    fn chain(prefix: &str, count: usize, base: &str) -> Vec<OperationEntry> {
+        let mut parent = base.to_string();
+        let mut entries = Vec::new();
+        for i in 0..count {
+            let name = format!("{prefix}{i}");
+            entries.push(entry(&name, &[&parent], &[&name]));
+            parent = name;
+        }
+        entries
+    }
+
+    /// Run a negotiation to the end, answering from `client`.
+    fn negotiate(negotiation: &mut Negotiation, walker: &mut HaveWalker, client: &OpGraph) {
+        while !negotiation.is_ready() {
+            negotiation.receive(&walker.answer(Some(client), &negotiation.ack()));
+        }
+    }
+
+    #[test]
+    fn test_batches_reach_past_long_local_history() {
+        // 200 shared operations; the server has 5 more, and the client 100
+        // of its own.
+        let shared = || {
+            let mut entries = vec![entry("root", &[], &["root"])];
+            entries.extend(chain("s", 200, "root"));
+            entries
+        };
+        let mut server = graph(&["n4"], shared());
+        for entry in chain("n", 5, "s199") {
+            server.insert(entry);
+        }
+        let mut client = graph(&["l99"], shared());
+        for entry in chain("l", 100, "s199") {
+            client.insert(entry);
+        }
+        let commit_parents = |commit: &CommitId| {
+            let entry = server.ops.values().find(|e| e.view_heads == [*commit]);
+            let parents = entry.into_iter().flat_map(|entry| &entry.parents);
+            Ok::<_, Infallible>(
+                parents
+                    .filter_map(|parent| server.get(parent))
+                    .flat_map(|parent| parent.view_heads.clone())
+                    .collect(),
+            )
+        };
+        let have_ops = vec![op_id("l99")];
+
+        // One generation per round never gets past the local operations,
+        // so the whole history is sent.
+        let mut single = Negotiation::new(&server, &have_ops, DEFAULT_MAX_ROUNDS);
+        negotiate(&mut single, &mut HaveWalker::new(&have_ops), &client);
+        assert!(single.common().is_empty());
+        let single = single.plan(commit_parents).unwrap();
+        assert_eq!(single.commits.len(), 206);
+
+        let mut batched =
+            Negotiation::batched(&server, &have_ops, DEFAULT_MAX_ROUNDS, DEFAULT_MAX_HAVES);
+        let mut walker = HaveWalker::batched(&have_ops);
+        negotiate(&mut batched, &mut walker, &client);
+        assert!(batched.common().contains(&op_id("s199")));
+        let batched = batched.plan(commit_parents).unwrap();
+        assert_eq!(batched.commits.len(), 5);
+        assert_eq!(batched.missing_ops.len(), 5);
+        assert!(single.commits.len() >= 10 * batched.commits.len());
+    }
+
+    #[test]
+    fn test_batches_and_acks() {
+        let (server, client) = server_and_client();
+        let have_ops = vec![op_id("d")];
+
+        let mut negotiation = Negotiation::batched(&server, &have_ops, DEFAULT_MAX_ROUNDS, 100);
+        let mut walker = HaveWalker::batched(&have_ops);
+        assert!(!negotiation.is_ready());
+        // A nak: the server has none of d.
+        assert!(negotiation.ack().common_ops.is_empty());
+
+        // The whole rest of the log fits in the first batch.
+        let more = walker.answer(Some(&client), &negotiation.ack());
+        assert_eq!(
+            more.have_ops,
+            vec![op_id("c"), op_id("b"), op_id("a"), op_id("root")]
+        );
+        assert!(more.done);
+        negotiation.receive(&more);
+        assert!(negotiation.is_ready());
+        assert_eq!(
+            negotiation.ack().common_ops,
+            vec![op_id("b"), op_id("a"), op_id("root")]
+        );
+        let plan = negotiation.plan(commit_parents).unwrap();
+        assert_eq!(plan, FetchPlan::default());
+
+        // Operations the server acknowledged aren't walked past.
+        let mut walker = HaveWalker::batched(&[op_id("d"), op_id("b")]);
+        let ack = AckReady {
+            common_ops: vec![op_id("b")],
+            ready: false,
+        };
+        let more = walker.answer(Some(&client), &ack);
+        assert_eq!(more.have_ops, vec![op_id("c")]);
+        assert!(more.done);
+    }
+
+    #[test]
+    fn test_have_limit() {
+        let (server, client) = server_and_client();
+        let have_ops = vec![op_id("d")];
+
+        // Only the first two operations offered count: d and c.
+        let mut negotiation = Negotiation::batched(&server, &have_ops, DEFAULT_MAX_ROUNDS, 2);
+        let mut walker = HaveWalker::batched(&have_ops);
+        negotiation.receive(&walker.answer(Some(&client), &negotiation.ack()));
+        assert!(negotiation.is_ready());
+        assert!(negotiation.common().is_empty());
+        assert!(negotiation.ack().common_ops.is_empty());
+    }
+
+    /// root <- a <- b on the server; the client added c and d on top of b.
+    fn server_and_client() -> (OpGraph, OpGraph) {
+        let shared = || {
+            vec![
+                entry("root", &[], &["root"]),
+                entry("a", &["root"], &["1"]),
+                entry("b", &["a"], &["2"]),
+            ]
+        };
+        let server = graph(&["b"], shared());
+        let mut client = graph(&["d"], shared());
+        client.insert(entry("c", &["b"], &["3"]));
+        client.insert(entry("d", &["c"], &["3"]));
+        (server, client)
+    }
+
+    fn commit_parents(commit: &CommitId) -> Result<Vec<CommitId>, Infallible> {
+        let parent = [("1", "root"), ("2", "1"), ("3", "2")]
+            .into_iter()
+            .find(|(child, _)| commit_id(child) == *commit)
+            .map(|(_, parent)| commit_id(parent));
+        Ok(parent.into_iter().collect())
+    }
+
+    #[test]
+    fn test_have_more_rounds() {
+        let (server, client) = server_and_client();
+        let have_ops = vec![op_id("d")];
+
+        let mut negotiation = Negotiation::new(&server, &have_ops, DEFAULT_MAX_ROUNDS);
+        let mut walker = HaveWalker::new(&have_ops);
+        assert!(!negotiation.is_ready());
+
+        let more = walker.answer(Some(&client), &negotiation.ack());
+        assert_eq!(more.have_ops, vec![op_id("c")]);
+        negotiation.receive(&more);
+        assert!(!negotiation.is_ready());
+
+        let more = walker.answer(Some(&client), &negotiation.ack());
+        assert_eq!(more.have_ops, vec![op_id("b")]);
+        assert!(!more.done);
+        negotiation.receive(&more);
+        assert!(negotiation.is_ready());
+        assert_eq!(negotiation.rounds(), 2);
+        assert_eq!(negotiation.ack().common_ops, vec![op_id("b")]);
+
+        let plan = negotiation.plan(commit_parents).unwrap();
+        assert_eq!(plan, FetchPlan::default());
+    }
+
+    #[test]
+    fn test_round_limit() {
+        let (server, client) = server_and_client();
+        let have_ops = vec![op_id("d")];
+
+        let mut negotiation = Negotiation::new(&server, &have_ops, 1);
+        let mut walker = HaveWalker::new(&have_ops);
+        negotiation.receive(&walker.answer(Some(&client), &negotiation.ack()));
+        assert!(negotiation.is_ready());
+        assert!(negotiation.common().is_empty());
+
+        // Nothing in common, so the client gets everything.
+        let plan = negotiation.plan(commit_parents).unwrap();
+        assert_eq!(
+            plan.missing_ops,
+            vec![op_id("root"), op_id("a"), op_id("b")]
+        );
+        assert_eq!(plan.new_heads, vec![op_id("b")]);
+        assert_eq!(plan.commits.len(), 3);
+    }
+
+    #[test]
+    fn test_client_without_op_log_is_done() {
+        let (server, _) = server_and_client();
+        let have_ops = vec![op_id("d")];
+
+        let mut negotiation = Negotiation::new(&server, &have_ops, DEFAULT_MAX_ROUNDS);
+        let more = HaveWalker::new(&have_ops).answer(None, &negotiation.ack());
+        assert!(more.done);
+        negotiation.receive(&more);
+        assert!(negotiation.is_ready());
+    }
+
+    #[test]
+    fn test_unknown_have_ops_are_ignored() {
+        let (server, _) = server_and_client();
+
+        let negotiation = Negotiation::new(&server, &[op_id("a"), op_id("unknown")], 0);
+        assert_eq!(negotiation.common(), &[op_id("a")]);
+        let plan = negotiation.plan(commit_parents).unwrap();
+        assert_eq!(plan.missing_ops, vec![op_id("b")]);
+        assert_eq!(plan.commits, vec![commit_id("2")]);
+    }
+
+    #[test]
+    fn test_common_ancestor() {
+        let (server, _) = server_and_client();
+
+        assert_eq!(
+            server.common_ancestor(&[op_id("a"), op_id("b"), op_id("d")]),
+            Some(op_id("b"))
+        );
+        assert_eq!(
+            server.common_ancestor(&[op_id("root")]),
+            Some(op_id("root"))
+        );
+        assert_eq!(server.common_ancestor(&[op_id("d")]), None);
+        assert_eq!(server.common_ancestor(&[]), None);
+    }
+}
@@ -0,0 +1,536 @@
+//! Noise encryption for the raw TCP transport.
+//!
+//! The TCP transport carries the protocol in cleartext, which only suits a
+//! trusted network. [`noise_connect`] and [`noise_accept`] run a Noise XX
+//! handshake ([`NOISE_PARAMS`]) on a stream and return a [`NoiseStream`]
+//! that encrypts what is written to it and decrypts what is read from it,
+//! so the protocol runs on it exactly as it would on the bare stream.
+//!
+//! Both peers have a static [`NoiseKeypair`]. The client pins the server's
+//! public key, and gives up before revealing its own if the server presents
+//! another. The server learns the client's key, but sessions still
+//! authenticate with the bearer token in their Hello.
+//!
+//! On the wire, each Noise message is a 2-byte big-endian length followed by
+//! the message, as the Noise specification suggests. Each write of up to
+//! [`MAX_NOISE_PLAINTEXT`] bytes is sealed into one message.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use snow::params::{DHChoice, NoiseParams};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::Dh;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The Noise protocol both peers run.
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Length of Curve25519 private and public keys, in bytes.
+pub const NOISE_KEY_LEN: usize = 32;
+
+/// Largest Noise message, in bytes.
+const MAX_MESSAGE: usize = u16::MAX as usize;
+
+/// Length of the authentication tag on each transport message.
+const TAG_LEN: usize = 16;
+
+/// Most plaintext sealed into one transport message, in bytes.
+pub const MAX_NOISE_PLAINTEXT: usize = MAX_MESSAGE - TAG_LEN;
+
+/// Errors from setting up an encrypted stream.
+#[derive(Debug, thiserror::Error)]
+pub enum NoiseError {
+    #[error("Noise handshake failed: {0}")]
+    Handshake(#[from] snow::Error),
+
+    #[error("I/O error during the Noise handshake: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("server key does not match the expected key")]
+    ServerKeyMismatch,
+
+    #[error("invalid Noise key: {0}")]
+    InvalidKey(String),
+}
+
+/// A static Curve25519 key pair.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NoiseKeypair {
+    private: [u8; NOISE_KEY_LEN],
+    public: [u8; NOISE_KEY_LEN],
+}
+
+impl NoiseKeypair {
+    /// A new random key pair.
+    pub fn generate() -> Self {
+        Self::from_private(rand::random())
+    }
+
+    /// The key pair for `private`, deriving its public key.
+    pub fn from_private(private: [u8; NOISE_KEY_LEN]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("the default resolver supports Curve25519");
+        dh.set(&private);
+        let mut public = [0; NOISE_KEY_LEN];
+        public.copy_from_slice(dh.pubkey());
+        Self { private, public }
+    }
+
+    /// Parse a private key written by [`to_hex`](Self::to_hex).
+    pub fn from_hex(text: &str) -> Result<Self, NoiseError> {
+        parse_noise_key(text).map(Self::from_private)
+    }
+
+    /// The private key, in hex.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.private)
+    }
+
+    /// The public key, for clients to pin.
+    pub fn public_key(&self) -> &[u8; NOISE_KEY_LEN] {
+        &self.public
+    }
+
+    fn builder(&self) -> Builder<'_> {
+        let params: NoiseParams = NOISE_PARAMS.parse().expect("valid Noise parameters");
+        Builder::new(params).local_private_key(&self.private)
+    }
+}
+
+/// Shows only the public key.
+impl fmt::Debug for NoiseKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseKeypair")
+            .field("public", &hex::encode(self.public))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse a hex-encoded key, such as a pinned public key.
+pub fn parse_noise_key(text: &str) -> Result<[u8; NOISE_KEY_LEN], NoiseError> {
+    let bytes =
+        hex::decode(text.trim()).map_err(|error| NoiseError::InvalidKey(error.to_string()))?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        NoiseError::InvalidKey(format!(
+            "expected {NOISE_KEY_LEN} bytes, got {}",
+            bytes.len()
+        ))
+    })
+}
+
+/// Encrypt `stream` as the client, accepting only a server whose public key
+/// is `server_key`.
+pub async fn noise_connect<S>(
+    mut stream: S,
+    keypair: &NoiseKeypair,
+    server_key: &[u8; NOISE_KEY_LEN],
+) -> Result<NoiseStream<S>, NoiseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = keypair.builder().build_initiator()?;
+    let mut buffers = HandshakeBuffers::new();
+    // -> e
+    buffers.send(&mut stream, &mut state).await?;
+    // <- e, ee, s, es
+    buffers.receive(&mut stream, &mut state).await?;
+    if state.get_remote_static() != Some(&server_key[..]) {
+        return Err(NoiseError::ServerKeyMismatch);
+    }
+    // -> s, se
+    buffers.send(&mut stream, &mut state).await?;
+    NoiseStream::new(stream, state)
+}
+
+/// Encrypt `stream` as the server, accepting any client key.
+pub async fn noise_accept<S>(
+    mut stream: S,
+    keypair: &NoiseKeypair,
+) -> Result<NoiseStream<S>, NoiseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut state = keypair.builder().build_responder()?;
+    let mut buffers = HandshakeBuffers::new();
+    // -> e
+    buffers.receive(&mut stream, &mut state).await?;
+    // <- e, ee, s, es
+    buffers.send(&mut stream, &mut state).await?;
+    // -> s, se
+    buffers.receive(&mut stream, &mut state).await?;
+    NoiseStream::new(stream, state)
+}
+
+/// Room for one handshake message and its payload.
+struct HandshakeBuffers {
+    message: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl HandshakeBuffers {
+    fn new() -> Self {
+        Self {
+            message: vec![0; MAX_MESSAGE],
+            payload: vec![0; MAX_MESSAGE],
+        }
+    }
+
+    /// Write the next handshake message, with an empty payload.
+    async fn send<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        state: &mut HandshakeState,
+    ) -> Result<(), NoiseError> {
+        let len = state.write_message(&[], &mut self.message)?;
+        stream.write_u16(len as u16).await?;
+        stream.write_all(&self.message[..len]).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Read the next handshake message.
+    async fn receive<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        state: &mut HandshakeState,
+    ) -> Result<(), NoiseError> {
+        let len = stream.read_u16().await? as usize;
+        let message = &mut self.message[..len];
+        stream.read_exact(message).await?;
+        state.read_message(message, &mut self.payload)?;
+        Ok(())
+    }
+}
+
+/// A stream encrypted with Noise, from [`noise_connect`] or
+/// [`noise_accept`].
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+    remote_key: [u8; NOISE_KEY_LEN],
+    /// Ciphertext read but not yet decrypted
+    incoming: Vec<u8>,
+    /// The last decrypted message, and how much of it has been read
+    plaintext: Vec<u8>,
+    read: usize,
+    /// The last sealed message, and how much of it has been written
+    outgoing: Vec<u8>,
+    written: usize,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(inner: S, state: HandshakeState) -> Result<Self, NoiseError> {
+        let mut remote_key = [0; NOISE_KEY_LEN];
+        match state.get_remote_static() {
+            Some(key) if key.len() == NOISE_KEY_LEN => remote_key.copy_from_slice(key),
+            _ => {
+                return Err(NoiseError::InvalidKey(
+                    "peer sent no static key".to_string(),
+                ));
+            }
+        }
+        Ok(Self {
+            inner,
+            transport: state.into_transport_mode()?,
+            remote_key,
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            read: 0,
+            outgoing: Vec::new(),
+            written: 0,
+        })
+    }
+
+    /// The peer's static public key.
+    pub fn remote_key(&self) -> &[u8; NOISE_KEY_LEN] {
+        &self.remote_key
+    }
+
+    /// The underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Decrypt the next message if it has been read whole.
+    fn open_buffered(&mut self) -> io::Result<bool> {
+        let Some(header) = self.incoming.get(..2) else {
+            return Ok(false);
+        };
+        let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+        let Some(message) = self.incoming.get(2..2 + len) else {
+            return Ok(false);
+        };
+        self.plaintext.resize(MAX_NOISE_PLAINTEXT, 0);
+        let opened = self
+            .transport
+            .read_message(message, &mut self.plaintext)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        self.plaintext.truncate(opened);
+        self.read = 0;
+        self.incoming.drain(..2 + len);
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    /// Write out the rest of the last sealed message.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.outgoing.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.outgoing.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> fmt::Debug for NoiseStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseStream")
+            .field("remote_key", &hex::encode(self.remote_key))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read < this.plaintext.len() {
+                let len = buf.remaining().min(this.plaintext.len() - this.read);
+                buf.put_slice(&this.plaintext[this.read..this.read + len]);
+                this.read += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.open_buffered()? {
+                continue;
+            }
+
+            let mut chunk = [0; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if this.incoming.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ended inside a Noise message",
+                )));
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_NOISE_PLAINTEXT);
+        this.outgoing.resize(2 + MAX_MESSAGE, 0);
+        let sealed = this
+            .transport
+            .write_message(&buf[..len], &mut this.outgoing[2..])
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        this.outgoing.truncate(2 + sealed);
+        this.outgoing[..2].copy_from_slice(&(sealed as u16).to_be_bytes());
+
+        // The message is ours now; what doesn't go out here goes out on the
+        // next write or flush.
+        if let Poll::Ready(Err(error)) = this.poll_send(cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+    use crate::client::{ClientOptions, ForjjClient};
+    use crate::handshake::{
+        VersionRange, server_read_hello, server_select_repo, server_send_hello,
+    };
+    use crate::messages::{AccessLevel, Capability, HelloResponse, RepoRef, ServerLimits};
+    use crate::testing::RecordingStream;
+    use std::collections::BTreeMap;
+
+    /// Serve the Hello exchange for `alice/project` on `stream`.
+    async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (request, version) =
+            server_read_hello(&mut reader, &mut writer, VersionRange::SUPPORTED)
+                .await
+                .unwrap();
+        server_select_repo(&mut writer, &request, |repo| repo.name == "project")
+            .await
+            .unwrap();
+        let response = HelloResponse {
+            protocol_version: version,
+            capabilities: vec![Capability::Operations],
+            server_op_heads: vec![],
+            common_ancestor: None,
+            compression: None,
+            max_frame_size: None,
+            identity: Some("mirror".to_string()),
+            access: Some(AccessLevel::Read),
+            limits: ServerLimits::default(),
+            extensions: BTreeMap::new(),
+        };
+        server_send_hello(&mut writer, &request, response)
+            .await
+            .unwrap();
+    }
+
+    fn options() -> ClientOptions {
+        ClientOptions::new(RepoRef::new("alice", "project"))
+    }
+
+    #[tokio::test]
+    async fn test_hello_over_noise() {
+        let server_key = NoiseKeypair::generate();
+        let client_key = NoiseKeypair::generate();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let client = RecordingStream::new(client);
+        let (sent, received) = (client.sent(), client.received());
+
+        let serve = async {
+            let stream = noise_accept(server, &server_key).await.unwrap();
+            assert_eq!(stream.remote_key(), client_key.public_key());
+            accept(stream).await;
+        };
+        let run = async {
+            let stream = noise_connect(client, &client_key, server_key.public_key())
+                .await
+                .unwrap();
+            let client = ForjjClient::connect(stream, options()).await.unwrap();
+            assert_eq!(client.negotiated().version, PROTOCOL_VERSION);
+            assert_eq!(client.hello().identity.as_deref(), Some("mirror"));
+        };
+        tokio::join!(serve, run);
+
+        // Neither the Hello nor its response can be read off the wire.
+        for bytes in [sent.bytes(), received.bytes()] {
+            assert!(!bytes.is_empty());
+            for plaintext in [&b"project"[..], b"protocol_version", b"mirror", b"{\""] {
+                assert!(
+                    !bytes
+                        .windows(plaintext.len())
+                        .any(|window| window == plaintext),
+                    "{:?} in the raw bytes",
+                    String::from_utf8_lossy(plaintext)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_in_cleartext() {
+        // The check above would catch the Hello without Noise.
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let client = RecordingStream::new(client);
+        let sent = client.sent();
+        tokio::join!(accept(server), async {
+            ForjjClient::connect(client, options()).await.unwrap();
+        });
+        let bytes = sent.bytes();
+        assert!(bytes.windows(7).any(|window| window == b"project"));
+    }
+
+    #[tokio::test]
+    async fn test_server_key_pinning() {
+        let server_key = NoiseKeypair::generate();
+        let pinned = NoiseKeypair::generate();
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let (served, connected) = tokio::join!(
+            noise_accept(server, &server_key),
+            noise_connect(client, &NoiseKeypair::generate(), pinned.public_key()),
+        );
+        assert!(matches!(connected, Err(NoiseError::ServerKeyMismatch)));
+        // The client hangs up before sending its key.
+        assert!(matches!(served, Err(NoiseError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_large_writes() {
+        let server_key = NoiseKeypair::generate();
+        let (client, server) = tokio::io::duplex(4 * 1024);
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        let (client, server) = tokio::join!(
+            noise_connect(client, &NoiseKeypair::generate(), server_key.public_key()),
+            noise_accept(server, &server_key),
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let write = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut read = Vec::new();
+            server.read_to_end(&mut read).await.unwrap();
+            read
+        };
+        let ((), read) = tokio::join!(write, read);
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_keypairs() {
+        // Public keys derive as snow's own key pairs do.
+        let params: NoiseParams = NOISE_PARAMS.parse().unwrap();
+        let generated = Builder::new(params).generate_keypair().unwrap();
+        let keypair = NoiseKeypair::from_private(generated.private.try_into().unwrap());
+        assert_eq!(&keypair.public_key()[..], &generated.public[..]);
+
+        let parsed = NoiseKeypair::from_hex(&format!("{}\n", keypair.to_hex())).unwrap();
+        assert_eq!(parsed, keypair);
+        assert!(!format!("{keypair:?}").contains(&keypair.to_hex()));
+
+        assert!(matches!(
+            parse_noise_key("abcd"),
+            Err(NoiseError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            parse_noise_key("not hex"),
+            Err(NoiseError::InvalidKey(_))
+        ));
+    }
+}
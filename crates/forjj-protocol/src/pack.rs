@@ -0,0 +1,1083 @@
+//! Object pack format for transferring commits, trees, and files.
+//!
+//! Format:
+//!
+//! ```text
+//! Header:  [4-byte magic "FJPK"][4-byte version][4-byte object count]
+//! Entry:   [1-byte kind][1-byte id length][id][4-byte payload length][payload]
+//! Trailer: [32-byte BLAKE2b-256 hash of everything before the trailer]
+//! ```
+//!
+//! A [`ObjectKind::Delta`] payload is `[1-byte base id length][base id][delta]`,
+//! with the delta in the format described in [`crate::delta`]. Deltas
+//! reconstruct file objects; use a [`DeltaResolver`] to turn them back into
+//! full objects.
+//!
+//! A [`ObjectKind::Promised`] entry stands in for an object the sender left
+//! out, such as a file outside a sparse fetch's path filters. Its payload is
+//! the 1-byte kind of the object it stands for; the receiver can fetch the
+//! object itself later.
+//!
+//! The reader checks every entry as it goes. An id of [`HASH_LEN`] bytes is
+//! a forjj content hash, so it must be the [`ObjectId::hash`] of the
+//! payload. An id of [`NATIVE_HASH_LEN`] bytes must be the one jj's native
+//! backend gives the payload, see [`RawObjectKind::native_id`]. Ids of any
+//! other length can't be checked, so those entries are refused. The trailer
+//! covers every byte of the pack besides.
+//!
+//! All integers are big-endian.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use forjj_storage::object_id::{HASH_LEN, NATIVE_HASH_LEN, ObjectIdError};
+use forjj_storage::{ObjectHasher, ObjectId, RawObjectKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::delta::{DeltaError, apply_delta, compute_delta};
+
+/// Magic bytes at the start of every pack.
+pub const PACK_MAGIC: [u8; 4] = *b"FJPK";
+
+/// Current pack format version.
+pub const PACK_VERSION: u32 = 1;
+
+/// Kind of object stored in a pack entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    File,
+    Symlink,
+    Conflict,
+    /// File object encoded as a delta against a base object
+    Delta,
+    /// Operation log entry
+    Operation,
+    /// Repository view recorded by an operation
+    View,
+    /// Object left out of the pack, with only its id and kind sent
+    Promised,
+}
+
+impl ObjectKind {
+    /// Get the wire tag for this kind.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            ObjectKind::Commit => 1,
+            ObjectKind::Tree => 2,
+            ObjectKind::File => 3,
+            ObjectKind::Symlink => 4,
+            ObjectKind::Conflict => 5,
+            ObjectKind::Delta => 6,
+            ObjectKind::Operation => 7,
+            ObjectKind::View => 8,
+            ObjectKind::Promised => 9,
+        }
+    }
+
+    /// Parse a wire tag.
+    pub fn from_u8(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(ObjectKind::Commit),
+            2 => Some(ObjectKind::Tree),
+            3 => Some(ObjectKind::File),
+            4 => Some(ObjectKind::Symlink),
+            5 => Some(ObjectKind::Conflict),
+            6 => Some(ObjectKind::Delta),
+            7 => Some(ObjectKind::Operation),
+            8 => Some(ObjectKind::View),
+            9 => Some(ObjectKind::Promised),
+            _ => None,
+        }
+    }
+}
+
+/// Get the storage kind of a pack entry, if it is stored as-is.
+pub(crate) fn raw_kind(kind: ObjectKind) -> Option<RawObjectKind> {
+    match kind {
+        ObjectKind::Commit => Some(RawObjectKind::Commit),
+        ObjectKind::Tree => Some(RawObjectKind::Tree),
+        ObjectKind::File => Some(RawObjectKind::File),
+        ObjectKind::Symlink => Some(RawObjectKind::Symlink),
+        ObjectKind::Conflict => Some(RawObjectKind::Conflict),
+        ObjectKind::Operation => Some(RawObjectKind::Operation),
+        ObjectKind::View => Some(RawObjectKind::View),
+        ObjectKind::Delta | ObjectKind::Promised => None,
+    }
+}
+
+/// Size limits enforced while reading or writing a pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackLimits {
+    /// Maximum payload size of a single object
+    pub max_object_size: u32,
+    /// Maximum size of the whole pack, including header and trailer
+    pub max_pack_size: u64,
+}
+
+impl Default for PackLimits {
+    fn default() -> Self {
+        Self {
+            max_object_size: 128 * 1024 * 1024,
+            max_pack_size: 8 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// A single object read from a pack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackEntry {
+    pub kind: ObjectKind,
+    pub id: ObjectId,
+    pub data: Vec<u8>,
+}
+
+impl PackEntry {
+    /// For a [`ObjectKind::Promised`] entry, the kind of the object it
+    /// stands for.
+    pub fn promised_kind(&self) -> Option<ObjectKind> {
+        match (self.kind, self.data.as_slice()) {
+            (ObjectKind::Promised, &[tag]) => ObjectKind::from_u8(tag),
+            _ => None,
+        }
+    }
+
+    /// Check that the id matches the payload.
+    ///
+    /// Fails with [`PackError::UnverifiableObject`] if the id is neither a
+    /// forjj content hash nor a native jj id, or the payload doesn't decode
+    /// as an object of its kind. Deltas are checked once resolved, and
+    /// promised entries have no contents to check.
+    pub fn verify(&self) -> Result<(), PackError> {
+        if matches!(self.kind, ObjectKind::Delta | ObjectKind::Promised) {
+            return Ok(());
+        }
+        verify_object(self.kind, &self.id, &self.data)
+    }
+}
+
+/// Check that `id` names `data` as an object of `kind`.
+pub(crate) fn verify_object(kind: ObjectKind, id: &ObjectId, data: &[u8]) -> Result<(), PackError> {
+    let actual = content_id(kind, id, data)?;
+    if !actual.ct_eq(id) {
+        return Err(PackError::ObjectHashMismatch {
+            kind,
+            expected: *id,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Compute the id of `data` as an object of `kind`, the same way `id` was.
+fn content_id(kind: ObjectKind, id: &ObjectId, data: &[u8]) -> Result<ObjectId, PackError> {
+    let unverifiable = || PackError::UnverifiableObject { kind, id: *id };
+    match id.byte_len() {
+        HASH_LEN => Ok(ObjectId::hash(data)),
+        NATIVE_HASH_LEN => raw_kind(kind)
+            .and_then(|kind| kind.native_id(data).ok())
+            .ok_or_else(unverifiable),
+        _ => Err(unverifiable()),
+    }
+}
+
+/// Default maximum length of a delta chain.
+pub const DEFAULT_MAX_DELTA_DEPTH: u32 = 50;
+
+/// Pack errors.
+#[derive(Debug, thiserror::Error)]
+pub enum PackError {
+    #[error("not a pack: bad magic bytes")]
+    InvalidMagic,
+
+    #[error("unsupported pack version: {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("unknown object kind: {0}")]
+    UnknownObjectKind(u8),
+
+    #[error("invalid object id: {0}")]
+    InvalidObjectId(#[from] ObjectIdError),
+
+    #[error("object too large: {size} bytes (max {max})")]
+    ObjectTooLarge { size: u64, max: u32 },
+
+    #[error("pack exceeds maximum size of {max} bytes")]
+    PackTooLarge { max: u64 },
+
+    #[error("object count mismatch: header says {expected}, got {actual}")]
+    ObjectCountMismatch { expected: u32, actual: u32 },
+
+    #[error("pack trailer hash mismatch: expected {expected}, computed {actual}")]
+    TrailerMismatch {
+        expected: ObjectId,
+        actual: ObjectId,
+    },
+
+    #[error("pack is truncated")]
+    Truncated,
+
+    #[error("invalid delta: {0}")]
+    InvalidDelta(#[from] DeltaError),
+
+    #[error("delta base not found: {0}")]
+    MissingDeltaBase(ObjectId),
+
+    #[error("delta chain exceeds maximum depth of {max}")]
+    DeltaDepthExceeded { max: u32 },
+
+    #[error("reconstructed object hash mismatch: expected {expected}, computed {actual}")]
+    DeltaHashMismatch {
+        expected: ObjectId,
+        actual: ObjectId,
+    },
+
+    #[error("{kind:?} object hash mismatch: expected {expected}, computed {actual}")]
+    ObjectHashMismatch {
+        kind: ObjectKind,
+        expected: ObjectId,
+        actual: ObjectId,
+    },
+
+    #[error("{kind:?} object {id} can't be checked against its contents")]
+    UnverifiableObject { kind: ObjectKind, id: ObjectId },
+
+    #[error("invalid promised entry for {0}")]
+    InvalidPromise(ObjectId),
+
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for PackError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            PackError::Truncated
+        } else {
+            PackError::Io(e)
+        }
+    }
+}
+
+/// Size of the pack header in bytes.
+const HEADER_LEN: u64 = 12;
+
+/// Writes objects into a pack.
+pub struct PackWriter<W> {
+    writer: W,
+    hasher: ObjectHasher,
+    limits: PackLimits,
+    expected_count: u32,
+    written_count: u32,
+    written_bytes: u64,
+}
+
+impl<W: AsyncWrite + Unpin> PackWriter<W> {
+    /// Start a pack that will contain `object_count` objects.
+    pub async fn new(writer: W, object_count: u32) -> Result<Self, PackError> {
+        Self::with_limits(writer, object_count, PackLimits::default()).await
+    }
+
+    /// Start a pack with custom size limits.
+    pub async fn with_limits(
+        writer: W,
+        object_count: u32,
+        limits: PackLimits,
+    ) -> Result<Self, PackError> {
+        let mut pack = Self {
+            writer,
+            hasher: ObjectHasher::new(),
+            limits,
+            expected_count: object_count,
+            written_count: 0,
+            written_bytes: 0,
+        };
+
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(&PACK_MAGIC);
+        header.extend_from_slice(&PACK_VERSION.to_be_bytes());
+        header.extend_from_slice(&object_count.to_be_bytes());
+        pack.write_hashed(&header).await?;
+
+        Ok(pack)
+    }
+
+    /// Append an object to the pack.
+    pub async fn add_object(
+        &mut self,
+        kind: ObjectKind,
+        id: &ObjectId,
+        data: &[u8],
+    ) -> Result<(), PackError> {
+        if self.written_count == self.expected_count {
+            return Err(PackError::ObjectCountMismatch {
+                expected: self.expected_count,
+                actual: self.written_count + 1,
+            });
+        }
+        let size = u32::try_from(data.len())
+            .ok()
+            .filter(|size| *size <= self.limits.max_object_size)
+            .ok_or(PackError::ObjectTooLarge {
+                size: data.len() as u64,
+                max: self.limits.max_object_size,
+            })?;
+
+        let mut entry_header = Vec::with_capacity(2 + id.byte_len() + 4);
+        entry_header.push(kind.as_u8());
+        entry_header.push(id.byte_len() as u8);
+        entry_header.extend_from_slice(id.as_bytes());
+        entry_header.extend_from_slice(&size.to_be_bytes());
+        self.write_hashed(&entry_header).await?;
+        self.write_hashed(data).await?;
+
+        self.written_count += 1;
+        Ok(())
+    }
+
+    /// Append a file object encoded as a delta against `base_id`.
+    ///
+    /// The receiver must have the base, either earlier in this pack or, for
+    /// thin packs, in its own repository.
+    pub async fn add_delta(
+        &mut self,
+        id: &ObjectId,
+        base_id: &ObjectId,
+        delta: &[u8],
+    ) -> Result<(), PackError> {
+        let mut payload = Vec::with_capacity(1 + base_id.byte_len() + delta.len());
+        payload.push(base_id.byte_len() as u8);
+        payload.extend_from_slice(base_id.as_bytes());
+        payload.extend_from_slice(delta);
+        self.add_object(ObjectKind::Delta, id, &payload).await
+    }
+
+    /// Append a promise for an object of `kind` that the pack leaves out.
+    pub async fn add_promised(&mut self, kind: ObjectKind, id: &ObjectId) -> Result<(), PackError> {
+        self.add_object(ObjectKind::Promised, id, &[kind.as_u8()])
+            .await
+    }
+
+    /// Append a file object, as a delta against `base` if that is smaller.
+    ///
+    /// Returns whether a delta was written.
+    pub async fn add_file(
+        &mut self,
+        id: &ObjectId,
+        data: &[u8],
+        base: Option<(&ObjectId, &[u8])>,
+    ) -> Result<bool, PackError> {
+        if let Some((base_id, base_data)) = base {
+            let delta = compute_delta(base_data, data);
+            if 1 + base_id.byte_len() + delta.len() < data.len() {
+                self.add_delta(id, base_id, &delta).await?;
+                return Ok(true);
+            }
+        }
+        self.add_object(ObjectKind::File, id, data).await?;
+        Ok(false)
+    }
+
+    /// Write the trailer and return the underlying writer and the pack hash.
+    pub async fn finish(mut self) -> Result<(W, ObjectId), PackError> {
+        if self.written_count != self.expected_count {
+            return Err(PackError::ObjectCountMismatch {
+                expected: self.expected_count,
+                actual: self.written_count,
+            });
+        }
+        self.check_size(HASH_LEN)?;
+
+        let trailer = self.hasher.finalize();
+        self.writer.write_all(trailer.as_bytes()).await?;
+        self.writer.flush().await?;
+
+        Ok((self.writer, trailer))
+    }
+
+    async fn write_hashed(&mut self, data: &[u8]) -> Result<(), PackError> {
+        self.check_size(data.len())?;
+        self.hasher.update(data);
+        self.writer.write_all(data).await?;
+        self.written_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    fn check_size(&self, additional: usize) -> Result<(), PackError> {
+        if self.written_bytes + additional as u64 > self.limits.max_pack_size {
+            return Err(PackError::PackTooLarge {
+                max: self.limits.max_pack_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Reads objects from a pack, verifying the trailer hash at the end.
+pub struct PackReader<R> {
+    reader: R,
+    hasher: Option<ObjectHasher>,
+    limits: PackLimits,
+    object_count: u32,
+    read_count: u32,
+    read_bytes: u64,
+}
+
+impl<R: AsyncRead + Unpin> PackReader<R> {
+    /// Start reading a pack, validating its header.
+    pub async fn new(reader: R) -> Result<Self, PackError> {
+        Self::with_limits(reader, PackLimits::default()).await
+    }
+
+    /// Start reading a pack with custom size limits.
+    pub async fn with_limits(reader: R, limits: PackLimits) -> Result<Self, PackError> {
+        let mut pack = Self {
+            reader,
+            hasher: Some(ObjectHasher::new()),
+            limits,
+            object_count: 0,
+            read_count: 0,
+            read_bytes: 0,
+        };
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        pack.read_hashed(&mut header).await?;
+        if header[..4] != PACK_MAGIC {
+            return Err(PackError::InvalidMagic);
+        }
+        let version = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if version != PACK_VERSION {
+            return Err(PackError::UnsupportedVersion(version));
+        }
+        pack.object_count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+        Ok(pack)
+    }
+
+    /// Number of objects announced in the pack header.
+    pub fn object_count(&self) -> u32 {
+        self.object_count
+    }
+
+    /// Read the next object.
+    ///
+    /// Returns `Ok(None)` once every object has been read and the trailer
+    /// hash has been verified.
+    pub async fn next_entry(&mut self) -> Result<Option<PackEntry>, PackError> {
+        if self.read_count == self.object_count {
+            self.verify_trailer().await?;
+            return Ok(None);
+        }
+
+        let mut prefix = [0u8; 2];
+        self.read_hashed(&mut prefix).await?;
+        let kind = ObjectKind::from_u8(prefix[0]).ok_or(PackError::UnknownObjectKind(prefix[0]))?;
+
+        let mut id_bytes = vec![0u8; usize::from(prefix[1])];
+        self.read_hashed(&mut id_bytes).await?;
+        let id = ObjectId::from_slice(&id_bytes)?;
+
+        let mut size = [0u8; 4];
+        self.read_hashed(&mut size).await?;
+        let size = u32::from_be_bytes(size);
+        if size > self.limits.max_object_size {
+            return Err(PackError::ObjectTooLarge {
+                size: u64::from(size),
+                max: self.limits.max_object_size,
+            });
+        }
+
+        let mut data = vec![0u8; size as usize];
+        self.read_hashed(&mut data).await?;
+
+        let entry = PackEntry { kind, id, data };
+        if kind == ObjectKind::Promised
+            && !matches!(
+                entry.promised_kind(),
+                Some(promised) if promised != ObjectKind::Promised && promised != ObjectKind::Delta
+            )
+        {
+            return Err(PackError::InvalidPromise(id));
+        }
+        entry.verify()?;
+
+        self.read_count += 1;
+        Ok(Some(entry))
+    }
+
+    /// Read every remaining object.
+    pub async fn read_all(mut self) -> Result<Vec<PackEntry>, PackError> {
+        let mut entries = Vec::with_capacity(self.object_count as usize);
+        while let Some(entry) = self.next_entry().await? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    async fn read_hashed(&mut self, buffer: &mut [u8]) -> Result<(), PackError> {
+        self.read_bytes += buffer.len() as u64;
+        if self.read_bytes > self.limits.max_pack_size {
+            return Err(PackError::PackTooLarge {
+                max: self.limits.max_pack_size,
+            });
+        }
+        self.reader.read_exact(buffer).await?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(buffer);
+        }
+        Ok(())
+    }
+
+    async fn verify_trailer(&mut self) -> Result<(), PackError> {
+        // The trailer is only verified once; later calls keep returning None.
+        let Some(hasher) = self.hasher.take() else {
+            return Ok(());
+        };
+
+        let mut trailer = [0u8; HASH_LEN];
+        self.reader.read_exact(&mut trailer).await?;
+        let expected = ObjectId::from_bytes(trailer);
+        let actual = hasher.finalize();
+        if !expected.ct_eq(&actual) {
+            return Err(PackError::TrailerMismatch { expected, actual });
+        }
+        Ok(())
+    }
+}
+
+/// Resolves [`ObjectKind::Delta`] entries back into full file objects.
+///
+/// Bases are file objects seen earlier in the same pack, or objects the
+/// receiver already has, supplied by `lookup`. Every reconstructed object is
+/// checked against its declared id.
+pub struct DeltaResolver<F> {
+    lookup: F,
+    max_depth: u32,
+    max_object_size: u32,
+    bases: HashMap<ObjectId, (Vec<u8>, u32)>,
+}
+
+impl<F> DeltaResolver<F>
+where
+    F: FnMut(&ObjectId) -> Option<Vec<u8>>,
+{
+    /// Create a resolver that fetches external bases with `lookup`.
+    pub fn new(lookup: F) -> Self {
+        Self {
+            lookup,
+            max_depth: DEFAULT_MAX_DELTA_DEPTH,
+            max_object_size: PackLimits::default().max_object_size,
+            bases: HashMap::new(),
+        }
+    }
+
+    /// Set the maximum length of a delta chain.
+    pub fn with_max_depth(mut self, max_depth: u32) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Apply the object size limit from `limits` to reconstructed objects.
+    pub fn with_limits(mut self, limits: PackLimits) -> Self {
+        self.max_object_size = limits.max_object_size;
+        self
+    }
+
+    /// Resolve a pack entry, returning it unchanged unless it is a delta.
+    pub fn resolve(&mut self, entry: PackEntry) -> Result<PackEntry, PackError> {
+        match entry.kind {
+            ObjectKind::Delta => self.resolve_delta(entry.id, &entry.data),
+            ObjectKind::File => {
+                self.bases.insert(entry.id, (entry.data.clone(), 0));
+                Ok(entry)
+            }
+            _ => Ok(entry),
+        }
+    }
+
+    fn resolve_delta(&mut self, id: ObjectId, payload: &[u8]) -> Result<PackEntry, PackError> {
+        let (&id_len, rest) = payload.split_first().ok_or(DeltaError::Truncated)?;
+        if rest.len() < usize::from(id_len) {
+            return Err(DeltaError::Truncated.into());
+        }
+        let (base_id, delta) = rest.split_at(usize::from(id_len));
+        let base_id = ObjectId::from_slice(base_id)?;
+
+        let (base, base_depth) = match self.bases.get(&base_id) {
+            Some((base, depth)) => (Cow::Borrowed(base.as_slice()), *depth),
+            None => {
+                let base = (self.lookup)(&base_id).ok_or(PackError::MissingDeltaBase(base_id))?;
+                (Cow::Owned(base), 0)
+            }
+        };
+        let depth = base_depth + 1;
+        if depth > self.max_depth {
+            return Err(PackError::DeltaDepthExceeded {
+                max: self.max_depth,
+            });
+        }
+
+        let data = apply_delta(&base, delta, self.max_object_size)?;
+        let actual = content_id(ObjectKind::File, &id, &data)?;
+        if actual != id {
+            return Err(PackError::DeltaHashMismatch {
+                expected: id,
+                actual,
+            });
+        }
+
+        self.bases.insert(id, (data.clone(), depth));
+        Ok(PackEntry {
+            kind: ObjectKind::File,
+            id,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::export_pack;
+    use forjj_storage::{RepositoryManager, StorageConfig};
+    use tempfile::TempDir;
+
+    /// Every kind of entry: the objects of a real repository, with native
+    /// jj ids, then objects named by forjj content hashes.
+    async fn sample_objects() -> Vec<PackEntry> {
+        let dir = TempDir::new().unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.create_repo("alice", "project").unwrap();
+        repo.write_commit(&[], &[("file", b"native file")], "native commit")
+            .await
+            .unwrap();
+        let pack = export_pack(&repo, &RawObjectKind::ALL).await.unwrap().data;
+        let mut entries = PackReader::new(pack.as_slice())
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap();
+
+        entries.extend([
+            PackEntry {
+                kind: ObjectKind::File,
+                id: ObjectId::hash(b"file data"),
+                data: b"file data".to_vec(),
+            },
+            PackEntry {
+                kind: ObjectKind::Symlink,
+                id: ObjectId::hash(b"target"),
+                data: b"target".to_vec(),
+            },
+            PackEntry {
+                kind: ObjectKind::Conflict,
+                id: ObjectId::hash(b""),
+                data: Vec::new(),
+            },
+        ]);
+        entries
+    }
+
+    async fn write_pack(entries: &[PackEntry]) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+            .await
+            .unwrap();
+        for entry in entries {
+            pack.add_object(entry.kind, &entry.id, &entry.data)
+                .await
+                .unwrap();
+        }
+        let (bytes, _) = pack.finish().await.unwrap();
+        bytes
+    }
+
+    async fn read_pack(bytes: &[u8], limits: PackLimits) -> Result<Vec<PackEntry>, PackError> {
+        PackReader::with_limits(bytes, limits)
+            .await?
+            .read_all()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_pack_roundtrip() {
+        let entries = sample_objects().await;
+        let bytes = write_pack(&entries).await;
+
+        let reader = PackReader::new(bytes.as_slice()).await.unwrap();
+        assert_eq!(reader.object_count(), entries.len() as u32);
+        assert_eq!(reader.read_all().await.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_empty_pack() {
+        let bytes = write_pack(&[]).await;
+        assert_eq!(bytes.len() as u64, HEADER_LEN + HASH_LEN as u64);
+
+        let reader = PackReader::new(bytes.as_slice()).await.unwrap();
+        assert!(reader.read_all().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finish_returns_trailer_hash() {
+        let mut pack = PackWriter::new(Vec::new(), 0).await.unwrap();
+        pack.add_object(ObjectKind::File, &ObjectId::ZERO, b"extra")
+            .await
+            .unwrap_err();
+        let (bytes, hash) = pack.finish().await.unwrap();
+        assert_eq!(&bytes[bytes.len() - HASH_LEN..], hash.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_pack() {
+        let bytes = write_pack(&sample_objects().await).await;
+
+        for len in [
+            0,
+            5,
+            HEADER_LEN as usize + 3,
+            bytes.len() / 2,
+            bytes.len() - 1,
+        ] {
+            let result = read_pack(&bytes[..len], PackLimits::default()).await;
+            assert!(
+                matches!(result, Err(PackError::Truncated)),
+                "len {len}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_pack_fails_trailer() {
+        let mut pack = PackWriter::new(Vec::new(), 1).await.unwrap();
+        pack.add_promised(ObjectKind::File, &ObjectId::hash(b"left out"))
+            .await
+            .unwrap();
+        let (mut bytes, _) = pack.finish().await.unwrap();
+        // Flip a byte of the promised id, which only the trailer covers.
+        bytes[HEADER_LEN as usize + 2] ^= 0xff;
+
+        let reader = PackReader::new(bytes.as_slice()).await.unwrap();
+        let result = reader.read_all().await;
+        assert!(matches!(result, Err(PackError::TrailerMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_native_object_names_it() {
+        for (kind, needle) in [
+            (ObjectKind::File, &b"native file"[..]),
+            (ObjectKind::Commit, b"native commit"),
+        ] {
+            let mut entries = sample_objects().await;
+            let entry = entries
+                .iter_mut()
+                .find(|entry| {
+                    entry.kind == kind
+                        && entry
+                            .data
+                            .windows(needle.len())
+                            .any(|window| window == needle)
+                })
+                .unwrap();
+            assert_eq!(entry.id.byte_len(), NATIVE_HASH_LEN);
+            let offset = entry
+                .data
+                .windows(needle.len())
+                .position(|window| window == needle)
+                .unwrap();
+            entry.data[offset] ^= 0x01;
+            let id = entry.id;
+
+            // The trailer matches, so only the object's id can catch it.
+            let bytes = write_pack(&entries).await;
+            let result = read_pack(&bytes, PackLimits::default()).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(PackError::ObjectHashMismatch { kind: actual, expected, .. })
+                        if actual == kind && expected == id
+                ),
+                "{kind:?}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unverifiable_objects_refused() {
+        let entries: [(ObjectKind, &[u8], &[u8]); 3] = [
+            // A Git id, which a pack never carries.
+            (ObjectKind::Tree, &[0x7e; 20], b"tree data"),
+            // Conflicts jj can no longer read.
+            (ObjectKind::Conflict, &[0xcf; 64], b"conflict"),
+            // A payload that doesn't decode as a commit.
+            (ObjectKind::Commit, &[0xc0; 64], b"\xff"),
+        ];
+        for (kind, id, data) in entries {
+            let id = ObjectId::from_slice(id).unwrap();
+            let bytes = write_pack(&[PackEntry {
+                kind,
+                id,
+                data: data.to_vec(),
+            }])
+            .await;
+            let result = read_pack(&bytes, PackLimits::default()).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(PackError::UnverifiableObject { kind: actual, id: actual_id })
+                        if actual == kind && actual_id == id
+                ),
+                "{kind:?}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_object_names_it() {
+        let mut bytes = write_pack(&sample_objects().await).await;
+        let offset = bytes
+            .windows(b"file data".len())
+            .position(|window| window == b"file data")
+            .unwrap();
+        bytes[offset] ^= 0x01;
+
+        // Caught at the object, before the trailer is reached.
+        let result = read_pack(&bytes, PackLimits::default()).await;
+        match result {
+            Err(PackError::ObjectHashMismatch { kind, expected, .. }) => {
+                assert_eq!(kind, ObjectKind::File);
+                assert_eq!(expected, ObjectId::hash(b"file data"));
+            }
+            other => panic!("expected an object hash mismatch, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_trailer() {
+        let mut bytes = write_pack(&sample_objects().await).await;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        let result = read_pack(&bytes, PackLimits::default()).await;
+        assert!(matches!(result, Err(PackError::TrailerMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_bad_magic() {
+        let mut bytes = write_pack(&[]).await;
+        bytes[0] = b'X';
+        let result = PackReader::new(bytes.as_slice()).await;
+        assert!(matches!(result, Err(PackError::InvalidMagic)));
+    }
+
+    #[tokio::test]
+    async fn test_promised_entries() {
+        let file_id = ObjectId::hash(b"left out");
+        let symlink_id = ObjectId::hash(b"target");
+        let mut pack = PackWriter::new(Vec::new(), 3).await.unwrap();
+        pack.add_object(ObjectKind::File, &ObjectId::hash(b"sent"), b"sent")
+            .await
+            .unwrap();
+        pack.add_promised(ObjectKind::File, &file_id).await.unwrap();
+        pack.add_promised(ObjectKind::Symlink, &symlink_id)
+            .await
+            .unwrap();
+        let (bytes, _) = pack.finish().await.unwrap();
+
+        let entries = read_pack(&bytes, PackLimits::default()).await.unwrap();
+        assert_eq!(entries[0].promised_kind(), None);
+        assert_eq!(entries[1].kind, ObjectKind::Promised);
+        assert_eq!(entries[1].id, file_id);
+        assert_eq!(entries[1].promised_kind(), Some(ObjectKind::File));
+        assert_eq!(entries[2].id, symlink_id);
+        assert_eq!(entries[2].promised_kind(), Some(ObjectKind::Symlink));
+
+        for payload in [&[][..], &[ObjectKind::Promised.as_u8()], &[3, 3], &[0xff]] {
+            let bytes = write_pack(&[PackEntry {
+                kind: ObjectKind::Promised,
+                id: file_id,
+                data: payload.to_vec(),
+            }])
+            .await;
+            let result = read_pack(&bytes, PackLimits::default()).await;
+            assert!(
+                matches!(result, Err(PackError::InvalidPromise(id)) if id == file_id),
+                "{payload:?}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_size_limit() {
+        let limits = PackLimits {
+            max_object_size: 4,
+            ..PackLimits::default()
+        };
+
+        let mut pack = PackWriter::with_limits(Vec::new(), 1, limits)
+            .await
+            .unwrap();
+        let result = pack
+            .add_object(ObjectKind::File, &ObjectId::ZERO, b"too big")
+            .await;
+        assert!(matches!(result, Err(PackError::ObjectTooLarge { .. })));
+
+        let bytes = write_pack(&sample_objects().await).await;
+        let mut reader = PackReader::with_limits(bytes.as_slice(), limits)
+            .await
+            .unwrap();
+        let result = reader.next_entry().await;
+        assert!(matches!(result, Err(PackError::ObjectTooLarge { .. })));
+    }
+
+    fn file_versions(count: usize) -> Vec<PackEntry> {
+        let mut data: Vec<u8> = (0..100)
+            .flat_map(|i| format!("line {i} of a tracked file\n").into_bytes())
+            .collect();
+        (0..count)
+            .map(|version| {
+                data.extend_from_slice(format!("version {version}\n").as_bytes());
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data: data.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Write each version as a delta against the previous one, starting from
+    /// `first_base`.
+    async fn write_delta_chain(first_base: &PackEntry, versions: &[PackEntry]) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), versions.len() as u32)
+            .await
+            .unwrap();
+        let mut base = first_base;
+        for version in versions {
+            let delta = compute_delta(&base.data, &version.data);
+            pack.add_delta(&version.id, &base.id, &delta).await.unwrap();
+            base = version;
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    async fn resolve_pack<F>(
+        bytes: &[u8],
+        resolver: &mut DeltaResolver<F>,
+    ) -> Result<Vec<PackEntry>, PackError>
+    where
+        F: FnMut(&ObjectId) -> Option<Vec<u8>>,
+    {
+        let mut reader = PackReader::new(bytes).await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry().await? {
+            entries.push(resolver.resolve(entry)?);
+        }
+        Ok(entries)
+    }
+
+    #[tokio::test]
+    async fn test_delta_roundtrip() {
+        let versions = file_versions(3);
+
+        let mut pack = PackWriter::new(Vec::new(), 3).await.unwrap();
+        assert!(
+            !pack
+                .add_file(&versions[0].id, &versions[0].data, None)
+                .await
+                .unwrap()
+        );
+        for pair in versions.windows(2) {
+            let base = Some((&pair[0].id, pair[0].data.as_slice()));
+            assert!(
+                pack.add_file(&pair[1].id, &pair[1].data, base)
+                    .await
+                    .unwrap()
+            );
+        }
+        let (bytes, _) = pack.finish().await.unwrap();
+        let full_size: usize = versions.iter().map(|version| version.data.len()).sum();
+        assert!(bytes.len() < full_size / 2);
+
+        let mut resolver = DeltaResolver::new(|_: &ObjectId| None);
+        let entries = resolve_pack(&bytes, &mut resolver).await.unwrap();
+        assert_eq!(entries, versions);
+    }
+
+    #[tokio::test]
+    async fn test_thin_delta_against_receiver_object() {
+        let versions = file_versions(2);
+        let bytes = write_delta_chain(&versions[0], &versions[1..]).await;
+
+        let have = versions[0].clone();
+        let mut resolver =
+            DeltaResolver::new(|id: &ObjectId| (*id == have.id).then(|| have.data.clone()));
+        let entries = resolve_pack(&bytes, &mut resolver).await.unwrap();
+        assert_eq!(entries, &versions[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_delta_missing_base() {
+        let versions = file_versions(2);
+        let bytes = write_delta_chain(&versions[0], &versions[1..]).await;
+
+        let mut resolver = DeltaResolver::new(|_: &ObjectId| None);
+        let result = resolve_pack(&bytes, &mut resolver).await;
+        assert!(
+            matches!(result, Err(PackError::MissingDeltaBase(id)) if id == versions[0].id),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delta_depth_limit() {
+        let versions = file_versions(4);
+        let bytes = write_delta_chain(&versions[0], &versions[1..]).await;
+        let have = versions[0].clone();
+        let lookup = |id: &ObjectId| (*id == have.id).then(|| have.data.clone());
+
+        let mut resolver = DeltaResolver::new(lookup).with_max_depth(3);
+        assert_eq!(resolve_pack(&bytes, &mut resolver).await.unwrap().len(), 3);
+
+        let mut resolver = DeltaResolver::new(lookup).with_max_depth(2);
+        let result = resolve_pack(&bytes, &mut resolver).await;
+        assert!(matches!(
+            result,
+            Err(PackError::DeltaDepthExceeded { max: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_delta_hash_mismatch() {
+        let versions = file_versions(2);
+        let delta = compute_delta(&versions[0].data, &versions[1].data);
+        let wrong_id = ObjectId::hash(b"something else");
+
+        let mut pack = PackWriter::new(Vec::new(), 2).await.unwrap();
+        pack.add_file(&versions[0].id, &versions[0].data, None)
+            .await
+            .unwrap();
+        pack.add_delta(&wrong_id, &versions[0].id, &delta)
+            .await
+            .unwrap();
+        let (bytes, _) = pack.finish().await.unwrap();
+
+        let mut resolver = DeltaResolver::new(|_: &ObjectId| None);
+        let result = resolve_pack(&bytes, &mut resolver).await;
+        assert!(matches!(result, Err(PackError::DeltaHashMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_pack_size_limit() {
+        let bytes = write_pack(&sample_objects().await).await;
+        let limits = PackLimits {
+            max_pack_size: 64,
+            ..PackLimits::default()
+        };
+        let result = read_pack(&bytes, limits).await;
+        assert!(matches!(result, Err(PackError::PackTooLarge { max: 64 })));
+    }
+}
@@ -0,0 +1,189 @@
+//! Progress reporting during long-running operations.
+//!
+//! A server may send [`ProgressMessage`]s before and between the messages of
+//! an exchange, including interleaved with pack chunks. They form a sideband:
+//! receivers that don't care about progress skip them, and no exchange depends
+//! on them for correctness.
+
+use tokio::io::AsyncRead;
+
+use crate::envelope::{Message, WireFormat, read_message};
+use crate::error::ProtocolError;
+use crate::messages::ProgressMessage;
+
+/// Receives progress updates, e.g. to render a progress bar.
+pub trait ProgressSink {
+    /// Called for every progress update received.
+    fn progress(&mut self, update: &ProgressMessage);
+}
+
+impl<F: FnMut(&ProgressMessage)> ProgressSink for F {
+    fn progress(&mut self, update: &ProgressMessage) {
+        self(update)
+    }
+}
+
+/// A sink that discards all progress updates.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn progress(&mut self, _update: &ProgressMessage) {}
+}
+
+/// Read a message of type `T`, passing any progress updates before it to
+/// `sink`.
+pub async fn read_message_with_progress<T, R>(
+    reader: &mut R,
+    format: WireFormat,
+    sink: &mut dyn ProgressSink,
+) -> Result<T, ProtocolError>
+where
+    T: TryFrom<Message, Error = ProtocolError>,
+    R: AsyncRead + Unpin,
+{
+    loop {
+        match read_message(reader, format).await? {
+            Message::Progress(update) => sink.progress(&update),
+            message => return message.try_into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{read_message_as, write_message};
+    use crate::messages::{FetchMode, FetchResponse, ProgressPhase};
+    use crate::pack::{ObjectKind, PackEntry, PackWriter};
+    use crate::transfer::{receive_pack, receive_pack_with_progress, send_pack_with_progress};
+    use forjj_storage::ObjectId;
+    use std::io::Cursor;
+
+    const FORMAT: WireFormat = WireFormat::Binary;
+
+    fn sample_entries() -> Vec<PackEntry> {
+        (0..8u32)
+            .map(|i| {
+                let data = vec![i as u8; 5000];
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    /// Write a fetch response stream with progress for every phase.
+    async fn fetch_stream(entries: &[PackEntry]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        let count = entries.len() as u64;
+        for current in 0..=count {
+            let update = ProgressMessage {
+                phase: ProgressPhase::Counting,
+                current,
+                total: None,
+                bytes: None,
+            };
+            write_message(&mut stream, &update.into(), FORMAT)
+                .await
+                .unwrap();
+        }
+
+        let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+            .await
+            .unwrap();
+        for (i, entry) in entries.iter().enumerate() {
+            pack.add_object(entry.kind, &entry.id, &entry.data)
+                .await
+                .unwrap();
+            let update = ProgressMessage {
+                phase: ProgressPhase::Compressing,
+                current: i as u64 + 1,
+                total: Some(count),
+                bytes: None,
+            };
+            write_message(&mut stream, &update.into(), FORMAT)
+                .await
+                .unwrap();
+        }
+        let (pack, _) = pack.finish().await.unwrap();
+
+        let response = FetchResponse {
+            pack_follows: true,
+            ops_to_send: vec![],
+            commit_count: 0,
+            resume_session: None,
+            refs: vec![],
+            shallow_boundary: vec![],
+            filtered: false,
+            operation_count: 0,
+            request_id: None,
+            want_errors: vec![],
+            mode: FetchMode::Operations,
+            ref_targets: vec![],
+        };
+        write_message(&mut stream, &response.into(), FORMAT)
+            .await
+            .unwrap();
+        send_pack_with_progress(
+            &mut pack.as_slice(),
+            &mut stream,
+            FORMAT,
+            4096,
+            Some(pack.len() as u64),
+        )
+        .await
+        .unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_fetch_progress_is_monotonic() {
+        let entries = sample_entries();
+        let mut stream = Cursor::new(fetch_stream(&entries).await);
+
+        let mut updates = Vec::new();
+        let mut sink = |update: &ProgressMessage| updates.push(update.clone());
+        let response: FetchResponse = read_message_with_progress(&mut stream, FORMAT, &mut sink)
+            .await
+            .unwrap();
+        assert!(response.pack_follows);
+        let received = receive_pack_with_progress(&mut stream, FORMAT, sink)
+            .await
+            .unwrap();
+        assert_eq!(received, entries);
+
+        for phase in [
+            ProgressPhase::Counting,
+            ProgressPhase::Compressing,
+            ProgressPhase::Sending,
+        ] {
+            let counts: Vec<u64> = updates
+                .iter()
+                .filter(|update| update.phase == phase)
+                .map(|update| update.current)
+                .collect();
+            assert!(!counts.is_empty(), "no {phase:?} updates");
+            assert!(
+                counts.windows(2).all(|pair| pair[0] <= pair[1]),
+                "{phase:?} went backwards: {counts:?}"
+            );
+        }
+        let sent = updates.last().unwrap();
+        assert_eq!(sent.phase, ProgressPhase::Sending);
+        assert_eq!(Some(sent.current), sent.total);
+    }
+
+    #[tokio::test]
+    async fn test_progress_unaware_receiver_skips_updates() {
+        let entries = sample_entries();
+        let mut stream = Cursor::new(fetch_stream(&entries).await);
+
+        let response: FetchResponse = read_message_as(&mut stream, FORMAT).await.unwrap();
+        assert!(response.pack_follows);
+        let received = receive_pack(&mut stream, FORMAT).await.unwrap();
+        assert_eq!(received, entries);
+    }
+}
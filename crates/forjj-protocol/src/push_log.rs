@@ -0,0 +1,221 @@
+//! Idempotent pushes.
+//!
+//! A client may give a push a `push_id`, so that retrying it after a
+//! dropped connection can't apply it twice. Once a push with an id is done,
+//! the server records its [`PushResult`] in a sidecar file of the
+//! repository, where it survives restarts. A push that repeats a recorded
+//! id gets the recorded result back and changes nothing.
+//!
+//! Records expire after a TTL, and only the most recent ones are kept.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use forjj_storage::Repository;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ProtocolError;
+use crate::messages::PushResult;
+
+/// Sidecar file the records are kept in.
+pub const PUSH_LOG_FILE: &str = "push-ids.json";
+
+/// How long a completed push is remembered.
+pub const DEFAULT_PUSH_ID_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most completed pushes remembered per repository.
+pub const DEFAULT_MAX_PUSH_IDS: usize = 1024;
+
+/// Longest push id accepted, in bytes.
+pub const MAX_PUSH_ID_LEN: usize = 128;
+
+/// Check that `push_id` is usable as a push id.
+pub fn check_push_id(push_id: &str) -> Result<(), ProtocolError> {
+    if push_id.is_empty() || push_id.len() > MAX_PUSH_ID_LEN {
+        return Err(ProtocolError::InvalidPushId { len: push_id.len() });
+    }
+    Ok(())
+}
+
+/// A new random push id.
+pub fn new_push_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    push_id: String,
+    /// Seconds since the Unix epoch
+    completed_at: u64,
+    result: PushResult,
+}
+
+/// Where completed pushes are looked up and recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushLog {
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl Default for PushLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_PUSH_ID_TTL, DEFAULT_MAX_PUSH_IDS)
+    }
+}
+
+impl PushLog {
+    /// Remember up to `max_entries` pushes per repository, each for `ttl`.
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self { ttl, max_entries }
+    }
+
+    /// The result of the push `push_id` to `repo`, if it completed within
+    /// the TTL.
+    pub fn find(
+        &self,
+        repo: &Repository,
+        push_id: &str,
+    ) -> Result<Option<PushResult>, ProtocolError> {
+        self.find_at(repo, push_id, unix_now())
+    }
+
+    /// Record that the push `push_id` to `repo` ended with `result`.
+    pub fn record(
+        &self,
+        repo: &Repository,
+        push_id: &str,
+        result: &PushResult,
+    ) -> Result<(), ProtocolError> {
+        self.record_at(repo, push_id, result, unix_now())
+    }
+
+    fn find_at(
+        &self,
+        repo: &Repository,
+        push_id: &str,
+        now: u64,
+    ) -> Result<Option<PushResult>, ProtocolError> {
+        let records = parse_records(repo.read_sidecar(PUSH_LOG_FILE)?);
+        Ok(records
+            .into_iter()
+            .rev()
+            .find(|record| record.push_id == push_id && !self.is_expired(record, now))
+            .map(|record| record.result))
+    }
+
+    fn record_at(
+        &self,
+        repo: &Repository,
+        push_id: &str,
+        result: &PushResult,
+        now: u64,
+    ) -> Result<(), ProtocolError> {
+        repo.update_sidecar(PUSH_LOG_FILE, |data| {
+            let mut records = parse_records(data);
+            records.retain(|record| record.push_id != push_id && !self.is_expired(record, now));
+            records.push(Record {
+                push_id: push_id.to_string(),
+                completed_at: now,
+                result: result.clone(),
+            });
+            // Oldest first, so the excess is at the front.
+            let excess = records.len().saturating_sub(self.max_entries);
+            records.drain(..excess);
+            Ok(serde_json::to_vec(&records)?)
+        })?;
+        Ok(())
+    }
+
+    fn is_expired(&self, record: &Record, now: u64) -> bool {
+        now.saturating_sub(record.completed_at) >= self.ttl.as_secs()
+    }
+}
+
+/// The records in a push log file. A file that can't be read is treated as
+/// empty: at worst, a retried push is checked like a new one.
+fn parse_records(data: Option<Vec<u8>>) -> Vec<Record> {
+    data.and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{PushStatus, RefResult, RefStatus};
+    use forjj_storage::{RepositoryManager, StorageConfig};
+    use tempfile::TempDir;
+
+    fn result(ref_name: &str) -> PushResult {
+        PushResult {
+            status: PushStatus::Ok,
+            new_op_head: None,
+            ref_results: vec![RefResult {
+                ref_name: ref_name.to_string(),
+                status: RefStatus::Ok,
+                message: None,
+                reason: None,
+            }],
+            request_id: None,
+            new_objects: 3,
+            skipped_objects: 1,
+            created_repo: false,
+        }
+    }
+
+    #[test]
+    fn test_push_log() {
+        let dir = TempDir::new().unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let repo = repos.create_repo("alice", "project").unwrap();
+        let log = PushLog::new(Duration::from_secs(60), 2);
+
+        assert_eq!(log.find_at(&repo, "a", 1000).unwrap(), None);
+        log.record_at(&repo, "a", &result("main"), 1000).unwrap();
+        assert_eq!(log.find_at(&repo, "a", 1059).unwrap(), Some(result("main")));
+        assert_eq!(log.find_at(&repo, "a", 1060).unwrap(), None);
+        assert_eq!(log.find_at(&repo, "b", 1000).unwrap(), None);
+
+        // Records are in the repository, not the log.
+        let reopened = repos.open_repo("alice", "project").unwrap();
+        assert_eq!(
+            PushLog::default().find(&reopened, "a").unwrap(),
+            None,
+            "expired by the real clock"
+        );
+        assert_eq!(
+            log.find_at(&reopened, "a", 1000).unwrap(),
+            Some(result("main"))
+        );
+
+        // Only the most recent records are kept.
+        log.record_at(&repo, "b", &result("b"), 1001).unwrap();
+        log.record_at(&repo, "c", &result("c"), 1002).unwrap();
+        assert_eq!(log.find_at(&repo, "a", 1002).unwrap(), None);
+        assert_eq!(log.find_at(&repo, "b", 1002).unwrap(), Some(result("b")));
+        assert_eq!(log.find_at(&repo, "c", 1002).unwrap(), Some(result("c")));
+
+        // A damaged file is started over.
+        repo.update_sidecar(PUSH_LOG_FILE, |_| Ok(b"{".to_vec()))
+            .unwrap();
+        assert_eq!(log.find_at(&repo, "c", 1002).unwrap(), None);
+        log.record_at(&repo, "d", &result("d"), 1003).unwrap();
+        assert_eq!(log.find_at(&repo, "d", 1003).unwrap(), Some(result("d")));
+    }
+
+    #[test]
+    fn test_check_push_id() {
+        assert!(check_push_id(&new_push_id()).is_ok());
+        assert_ne!(new_push_id(), new_push_id());
+        assert!(check_push_id("").is_err());
+        assert!(check_push_id(&"x".repeat(MAX_PUSH_ID_LEN)).is_ok());
+        assert!(check_push_id(&"x".repeat(MAX_PUSH_ID_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Ref patterns for fetches and ref listings.
+//!
+//! A fetch names the refs it wants as glob patterns: `*` matches any run of
+//! characters, `?` matches exactly one, and everything else matches itself.
+//! Bookmarks are matched by name and tags as `tags/<name>`. Patterns are
+//! only ever compared against ref names, but anything that looks like a path
+//! (`..`, a leading `/`, backslashes) is rejected outright so no later use
+//! of a pattern can be steered outside the ref namespace.
+
+/// Prefix under which tags are matched.
+pub const TAG_PREFIX: &str = "tags/";
+
+/// Maximum length of a ref pattern, in bytes.
+pub const MAX_PATTERN_LEN: usize = 256;
+
+/// Reasons a ref pattern is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RefPatternError {
+    #[error("pattern is empty")]
+    Empty,
+
+    #[error("pattern is longer than {MAX_PATTERN_LEN} bytes")]
+    TooLong,
+
+    #[error("pattern contains invalid character {0:?}")]
+    InvalidChar(char),
+
+    #[error("pattern {0:?} looks like a path")]
+    PathLike(String),
+}
+
+/// A parsed ref pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefPattern {
+    pattern: String,
+}
+
+impl RefPattern {
+    /// Parse and validate `pattern`.
+    pub fn parse(pattern: &str) -> Result<Self, RefPatternError> {
+        if pattern.is_empty() {
+            return Err(RefPatternError::Empty);
+        }
+        if pattern.len() > MAX_PATTERN_LEN {
+            return Err(RefPatternError::TooLong);
+        }
+        if let Some(c) = pattern
+            .chars()
+            .find(|&c| c.is_control() || c == '\\' || c == ':')
+        {
+            return Err(RefPatternError::InvalidChar(c));
+        }
+        if pattern.starts_with('/')
+            || pattern.ends_with('/')
+            || pattern
+                .split('/')
+                .any(|component| component.is_empty() || component == "." || component == "..")
+        {
+            return Err(RefPatternError::PathLike(pattern.to_string()));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+        })
+    }
+
+    /// Whether the pattern has no wildcards and matches only itself.
+    pub fn is_literal(&self) -> bool {
+        !self.pattern.contains(['*', '?'])
+    }
+
+    /// The pattern as given.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether `name` matches the pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        // Greedy matching that backtracks to the last `*`.
+        let (mut p, mut n) = (0, 0);
+        let mut star = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some(&c) if c == '?' || c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match star {
+                    Some((star_p, star_n)) => {
+                        p = star_p + 1;
+                        n = star_n + 1;
+                        star = Some((star_p, star_n + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl std::fmt::Display for RefPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Outcome of expanding a fetch's wanted refs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expansion {
+    /// These refs matched, in the order of the patterns
+    Refs(Vec<String>),
+    /// A literal pattern named a ref that doesn't exist
+    Missing(String),
+}
+
+/// Expand `want_refs` against the repository's `bookmarks` and `tags`.
+///
+/// Tags are matched with [`TAG_PREFIX`]. No patterns means every ref. A
+/// wildcard pattern that matches nothing contributes nothing, while a
+/// literal one for a ref that doesn't exist is reported as missing. Each
+/// ref is listed once.
+pub fn expand_want_refs(
+    want_refs: &[String],
+    bookmarks: &[String],
+    tags: &[String],
+) -> Result<Expansion, RefPatternError> {
+    let names = ref_names(bookmarks, tags);
+    let patterns = parse_patterns(want_refs)?;
+    if let Some(missing) = patterns
+        .iter()
+        .find(|pattern| pattern.is_literal() && !names.iter().any(|name| name == pattern.as_str()))
+    {
+        return Ok(Expansion::Missing(missing.to_string()));
+    }
+    Ok(Expansion::Refs(select_refs(&patterns, names)))
+}
+
+/// The refs among `bookmarks` and `tags` that `patterns` match, matched as
+/// by [`expand_want_refs`] except that a literal pattern for a ref that
+/// doesn't exist just matches nothing.
+pub fn match_refs(
+    patterns: &[String],
+    bookmarks: &[String],
+    tags: &[String],
+) -> Result<Vec<String>, RefPatternError> {
+    let patterns = parse_patterns(patterns)?;
+    Ok(select_refs(&patterns, ref_names(bookmarks, tags)))
+}
+
+/// Every ref name, sorted, with tags under [`TAG_PREFIX`].
+fn ref_names(bookmarks: &[String], tags: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = bookmarks
+        .iter()
+        .cloned()
+        .chain(tags.iter().map(|tag| format!("{TAG_PREFIX}{tag}")))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn parse_patterns(patterns: &[String]) -> Result<Vec<RefPattern>, RefPatternError> {
+    patterns
+        .iter()
+        .map(|pattern| RefPattern::parse(pattern))
+        .collect()
+}
+
+/// The `names` that `patterns` match, in the order of the patterns, each
+/// once; all of them if there are no patterns.
+fn select_refs(patterns: &[RefPattern], names: Vec<String>) -> Vec<String> {
+    if patterns.is_empty() {
+        return names;
+    }
+    let mut refs = Vec::new();
+    for pattern in patterns {
+        for name in names.iter().filter(|name| pattern.matches(name)) {
+            if !refs.contains(name) {
+                refs.push(name.clone());
+            }
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        RefPattern::parse(pattern).unwrap().matches(name)
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        assert!(matches("main", "main"));
+        assert!(!matches("main", "main2"));
+        assert!(!matches("main", "mai"));
+
+        assert!(matches("*", "anything/at/all"));
+        assert!(matches("release/*", "release/1.0"));
+        assert!(matches("release/*", "release/"));
+        assert!(!matches("release/*", "releases/1.0"));
+        assert!(matches("*-fix", "bug-123-fix"));
+        assert!(!matches("*-fix", "bug-123-fixed"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+
+        assert!(matches("v?", "v1"));
+        assert!(!matches("v?", "v"));
+        assert!(!matches("v?", "v10"));
+        assert!(matches("v?.*", "v2.0.1"));
+        assert!(matches("??", "é!"));
+    }
+
+    #[test]
+    fn test_literal() {
+        assert!(RefPattern::parse("feature/login").unwrap().is_literal());
+        assert!(!RefPattern::parse("feature/*").unwrap().is_literal());
+        assert!(!RefPattern::parse("v?").unwrap().is_literal());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert_eq!(RefPattern::parse(""), Err(RefPatternError::Empty));
+        assert_eq!(
+            RefPattern::parse(&"a".repeat(MAX_PATTERN_LEN + 1)),
+            Err(RefPatternError::TooLong)
+        );
+        assert_eq!(
+            RefPattern::parse("main\n"),
+            Err(RefPatternError::InvalidChar('\n'))
+        );
+        assert_eq!(
+            RefPattern::parse("main\0"),
+            Err(RefPatternError::InvalidChar('\0'))
+        );
+    }
+
+    #[test]
+    fn test_path_injection_is_rejected() {
+        for pattern in [
+            "../main",
+            "tags/../../etc/passwd",
+            "..",
+            "/etc/passwd",
+            "release/",
+            "release//1.0",
+            "./main",
+            "*/../*",
+        ] {
+            assert!(
+                matches!(
+                    RefPattern::parse(pattern),
+                    Err(RefPatternError::PathLike(_))
+                ),
+                "{pattern} was accepted"
+            );
+        }
+        for pattern in ["..\\main", "C:\\repo", "c:main"] {
+            assert!(
+                matches!(
+                    RefPattern::parse(pattern),
+                    Err(RefPatternError::InvalidChar(_))
+                ),
+                "{pattern} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_expand_want_refs() {
+        let bookmarks = strings(&["main", "release/1.0", "release/2.0", "feature"]);
+        let tags = strings(&["v1.0", "v2.0"]);
+        let expand = |want: &[&str]| expand_want_refs(&strings(want), &bookmarks, &tags).unwrap();
+
+        assert_eq!(
+            expand(&[]),
+            Expansion::Refs(strings(&[
+                "feature",
+                "main",
+                "release/1.0",
+                "release/2.0",
+                "tags/v1.0",
+                "tags/v2.0",
+            ]))
+        );
+        assert_eq!(
+            expand(&["release/*", "main", "release/1.0"]),
+            Expansion::Refs(strings(&["release/1.0", "release/2.0", "main"]))
+        );
+        assert_eq!(
+            expand(&["tags/v1.*"]),
+            Expansion::Refs(strings(&["tags/v1.0"]))
+        );
+        // Tags only match under their prefix.
+        assert_eq!(expand(&["v*"]), Expansion::Refs(vec![]));
+        assert_eq!(expand(&["hotfix/*"]), Expansion::Refs(vec![]));
+        assert_eq!(
+            expand(&["main", "hotfix"]),
+            Expansion::Missing("hotfix".to_string())
+        );
+        assert!(expand_want_refs(&strings(&["../*"]), &bookmarks, &tags).is_err());
+    }
+
+    #[test]
+    fn test_match_refs() {
+        let bookmarks = strings(&["main", "release/1.0"]);
+        let tags = strings(&["v1.0"]);
+        let list = |patterns: &[&str]| match_refs(&strings(patterns), &bookmarks, &tags).unwrap();
+
+        assert_eq!(list(&[]), strings(&["main", "release/1.0", "tags/v1.0"]));
+        assert_eq!(list(&["tags/*", "main"]), strings(&["tags/v1.0", "main"]));
+        // A missing literal is simply not listed.
+        assert_eq!(list(&["hotfix", "main"]), strings(&["main"]));
+        assert!(match_refs(&strings(&["/main"]), &bookmarks, &tags).is_err());
+    }
+}
@@ -0,0 +1,663 @@
+//! Resumable pack transfers.
+//!
+//! When both peers advertise [`Capability::Resumable`], the server registers
+//! the pack for a fetch in [`ResumeSessions`] and returns the session id in
+//! `FetchResponse::resume_session`. While receiving, the client periodically
+//! acknowledges its progress with [`PackAck`] messages.
+//!
+//! After a dropped connection, the client sends a [`ResumeRequest`] with the
+//! number of bytes it already has and their hash. The server checks that hash
+//! against its copy of the pack and continues from that offset, or restarts
+//! from the beginning if the prefix doesn't match.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use forjj_storage::object_id::HASH_LEN;
+use forjj_storage::{ObjectHasher, ObjectId};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::envelope::{WireFormat, read_message_as, write_message};
+use crate::error::ProtocolError;
+use crate::messages::{Capability, PackAck, PackChunk, ResumeRequest, ResumeResponse};
+use crate::transfer::trailer_hash;
+
+/// How long an idle session is kept before it expires.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of sessions a server keeps at once.
+pub const DEFAULT_MAX_SESSIONS: usize = 64;
+
+/// Maximum total size of the packs a server keeps for resuming.
+pub const DEFAULT_MAX_SESSION_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Number of bytes a client receives between acknowledgements.
+pub const DEFAULT_ACK_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Whether transfers may be resumed, given both peers' capabilities.
+///
+/// Sessions are only created if both sides advertise [`Capability::Resumable`].
+pub fn resumable_enabled(local: &[Capability], remote: &[Capability]) -> bool {
+    local.contains(&Capability::Resumable) && remote.contains(&Capability::Resumable)
+}
+
+struct Session {
+    /// Hash of the session id, so lookups compare it in constant time
+    id_hash: ObjectId,
+    pack: Arc<[u8]>,
+    acknowledged: u64,
+    last_active: Instant,
+}
+
+/// Server-side table of resumable pack transfers.
+///
+/// Sessions expire after a period of inactivity. Each session holds its whole
+/// pack in memory, so the table is bounded both by session count and by the
+/// total size of the packs. When either is reached, the least recently
+/// active sessions are evicted to make room for a new one.
+///
+/// Session ids are bearer secrets, so they are never used as map keys:
+/// lookups compare the id's hash against every session in constant time.
+pub struct ResumeSessions {
+    ttl: Duration,
+    max_sessions: usize,
+    max_bytes: u64,
+    sessions: Mutex<Vec<Session>>,
+}
+
+impl Default for ResumeSessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TTL, DEFAULT_MAX_SESSIONS)
+    }
+}
+
+impl ResumeSessions {
+    /// Create an empty session table.
+    pub fn new(ttl: Duration, max_sessions: usize) -> Self {
+        Self {
+            ttl,
+            max_sessions,
+            max_bytes: DEFAULT_MAX_SESSION_BYTES,
+            sessions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set the maximum total size of the buffered packs.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Register a pack transfer and return its session id.
+    ///
+    /// Returns `None` if the pack alone is larger than the byte limit; the
+    /// transfer then goes ahead without being resumable.
+    pub fn create(&self, pack: Arc<[u8]>) -> Option<String> {
+        let size = pack.len() as u64;
+        if size > self.max_bytes {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|session| !self.is_expired(session, now));
+        let mut buffered: u64 = sessions
+            .iter()
+            .map(|session| session.pack.len() as u64)
+            .sum();
+        while !sessions.is_empty()
+            && (sessions.len() >= self.max_sessions || buffered + size > self.max_bytes)
+        {
+            let oldest = sessions
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, session)| session.last_active)
+                .map(|(index, _)| index)
+                .expect("sessions is not empty");
+            buffered -= sessions.swap_remove(oldest).pack.len() as u64;
+        }
+
+        let session_id = hex::encode(rand::random::<[u8; 16]>());
+        sessions.push(Session {
+            id_hash: ObjectId::hash(session_id.as_bytes()),
+            pack,
+            acknowledged: 0,
+            last_active: now,
+        });
+        Some(session_id)
+    }
+
+    /// Record a client acknowledgement.
+    ///
+    /// Returns `true` once the whole pack has been acknowledged, at which
+    /// point the session is removed.
+    pub fn acknowledge(&self, ack: &PackAck) -> Result<bool, ProtocolError> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let index = self.live_session(&mut sessions, &ack.session_id, now)?;
+        let session = &mut sessions[index];
+
+        let available = session.pack.len() as u64;
+        if ack.received_bytes > available {
+            return Err(ProtocolError::InvalidResumeOffset {
+                offset: ack.received_bytes,
+                available,
+            });
+        }
+        session.acknowledged = session.acknowledged.max(ack.received_bytes);
+        session.last_active = now;
+
+        if session.acknowledged == available {
+            sessions.swap_remove(index);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Number of bytes acknowledged in a session.
+    pub fn acknowledged(&self, session_id: &str) -> Option<u64> {
+        let sessions = self.sessions.lock().unwrap();
+        find_session(&sessions, session_id)
+            .map(|index| &sessions[index])
+            .filter(|session| !self.is_expired(session, Instant::now()))
+            .map(|session| session.acknowledged)
+    }
+
+    /// Look up the pack for a resume request and the offset to continue from.
+    ///
+    /// The offset is 0 if the client's prefix doesn't match the pack.
+    pub fn resume(&self, request: &ResumeRequest) -> Result<(Arc<[u8]>, u64), ProtocolError> {
+        let pack = {
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().unwrap();
+            let index = self.live_session(&mut sessions, &request.session_id, now)?;
+            let session = &mut sessions[index];
+            session.last_active = now;
+            session.pack.clone()
+        };
+
+        // Hash outside the lock: the prefix can be gigabytes.
+        let prefix_matches = usize::try_from(request.received_bytes)
+            .ok()
+            .and_then(|len| pack.get(..len))
+            .is_some_and(|prefix| ObjectId::hash(prefix).ct_eq(&request.received_hash));
+        let offset = if prefix_matches {
+            request.received_bytes
+        } else {
+            0
+        };
+        Ok((pack, offset))
+    }
+
+    /// Number of live sessions.
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .filter(|session| !self.is_expired(session, now))
+            .count()
+    }
+
+    /// Whether there are no live sessions.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_expired(&self, session: &Session, now: Instant) -> bool {
+        now.duration_since(session.last_active) >= self.ttl
+    }
+
+    /// Index of a live session, removing it instead if it has expired.
+    fn live_session(
+        &self,
+        sessions: &mut Vec<Session>,
+        session_id: &str,
+        now: Instant,
+    ) -> Result<usize, ProtocolError> {
+        let index = find_session(sessions, session_id).ok_or(ProtocolError::UnknownSession)?;
+        if self.is_expired(&sessions[index], now) {
+            sessions.swap_remove(index);
+            return Err(ProtocolError::UnknownSession);
+        }
+        Ok(index)
+    }
+}
+
+/// Find a session by id, comparing against every session in constant time so
+/// the time taken says nothing about which one matched.
+fn find_session(sessions: &[Session], session_id: &str) -> Option<usize> {
+    let hash = ObjectId::hash(session_id.as_bytes());
+    let mut found = None;
+    for (index, session) in sessions.iter().enumerate() {
+        if session.id_hash.ct_eq(&hash) {
+            found = Some(index);
+        }
+    }
+    found
+}
+
+/// Send an in-memory pack as [`PackChunk`] messages, starting at `offset`.
+///
+/// Sequence numbers start at 0 for every call. Returns the number of chunks
+/// sent.
+pub async fn send_pack_from<W: AsyncWrite + Unpin>(
+    pack: &[u8],
+    offset: u64,
+    writer: &mut W,
+    format: WireFormat,
+    chunk_size: usize,
+) -> Result<u64, ProtocolError> {
+    let pack_hash = trailer_hash(pack)?;
+    let remaining = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| pack.get(offset..))
+        .ok_or(ProtocolError::InvalidResumeOffset {
+            offset,
+            available: pack.len() as u64,
+        })?;
+
+    let mut chunks = remaining.chunks(chunk_size).peekable();
+    let mut sequence = 0;
+    loop {
+        let data = chunks.next().unwrap_or_default();
+        let last = chunks.peek().is_none();
+        let chunk = PackChunk {
+            sequence,
+            data: data.to_vec(),
+            last,
+            pack_hash: last.then_some(pack_hash),
+        };
+        write_message(writer, &chunk.into(), format).await?;
+        sequence += 1;
+
+        if last {
+            return Ok(sequence);
+        }
+    }
+}
+
+/// Apply [`PackAck`] messages from a client until the whole pack is
+/// acknowledged.
+///
+/// Returns an error if the connection drops first; the session is kept so the
+/// client can resume.
+pub async fn receive_acks<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    format: WireFormat,
+    sessions: &ResumeSessions,
+) -> Result<(), ProtocolError> {
+    loop {
+        let ack: PackAck = read_message_as(reader, format).await?;
+        if sessions.acknowledge(&ack)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Client side of a resumable pack transfer.
+///
+/// Received bytes are buffered in memory and survive a dropped connection, so
+/// the same receiver can continue after a [`ResumeResponse`].
+pub struct ResumableReceiver {
+    session_id: String,
+    received: Vec<u8>,
+    hasher: ObjectHasher,
+    ack_interval: u64,
+    acknowledged: u64,
+}
+
+impl ResumableReceiver {
+    /// Create a receiver for the session announced in `FetchResponse`.
+    pub fn new(session_id: String) -> Self {
+        Self {
+            session_id,
+            received: Vec::new(),
+            hasher: ObjectHasher::new(),
+            ack_interval: DEFAULT_ACK_INTERVAL,
+            acknowledged: 0,
+        }
+    }
+
+    /// Set how many bytes to receive between acknowledgements.
+    pub fn with_ack_interval(mut self, ack_interval: u64) -> Self {
+        self.ack_interval = ack_interval;
+        self
+    }
+
+    /// Number of pack bytes received so far.
+    pub fn received_bytes(&self) -> u64 {
+        self.received.len() as u64
+    }
+
+    /// Build the request to send after reconnecting.
+    pub fn resume_request(&self) -> ResumeRequest {
+        ResumeRequest {
+            session_id: self.session_id.clone(),
+            received_bytes: self.received_bytes(),
+            received_hash: self.hasher.clone().finalize(),
+        }
+    }
+
+    /// Apply the server's answer to a [`ResumeRequest`].
+    pub fn apply_resume(&mut self, response: &ResumeResponse) -> Result<(), ProtocolError> {
+        if response.offset == 0 {
+            self.received.clear();
+            self.hasher = ObjectHasher::new();
+            self.acknowledged = 0;
+        } else if response.offset != self.received_bytes() {
+            return Err(ProtocolError::InvalidResumeOffset {
+                offset: response.offset,
+                available: self.received_bytes(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Receive pack chunks from `reader`, acknowledging progress on `writer`.
+    ///
+    /// Returns once the final chunk has been received and verified. If the
+    /// connection drops, the bytes received so far are kept for resuming.
+    pub async fn receive<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        format: WireFormat,
+    ) -> Result<(), ProtocolError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut sequence = 0;
+        loop {
+            let chunk: PackChunk = read_message_as(reader, format).await?;
+            if chunk.sequence != sequence {
+                return Err(ProtocolError::ChunkOutOfOrder {
+                    expected: sequence,
+                    actual: chunk.sequence,
+                });
+            }
+            sequence += 1;
+
+            self.hasher.update(&chunk.data);
+            self.received.extend_from_slice(&chunk.data);
+
+            if chunk.last {
+                let tail = &self.received[self.received.len().saturating_sub(HASH_LEN)..];
+                if !chunk.pack_hash.is_some_and(|hash| hash.as_bytes() == tail) {
+                    return Err(ProtocolError::PackHashMismatch);
+                }
+                self.acknowledge(writer, format).await?;
+                return Ok(());
+            }
+            if self.received_bytes() - self.acknowledged >= self.ack_interval {
+                self.acknowledge(writer, format).await?;
+            }
+        }
+    }
+
+    /// Consume the receiver, returning the received pack bytes.
+    pub fn into_pack(self) -> Vec<u8> {
+        self.received
+    }
+
+    async fn acknowledge<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        format: WireFormat,
+    ) -> Result<(), ProtocolError> {
+        self.acknowledged = self.received_bytes();
+        let ack = PackAck {
+            session_id: self.session_id.clone(),
+            received_bytes: self.acknowledged,
+        };
+        write_message(writer, &ack.into(), format).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{ObjectKind, PackEntry, PackReader, PackWriter};
+    use std::io::Cursor;
+
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    fn sample_entries() -> Vec<PackEntry> {
+        (0..20u32)
+            .map(|i| {
+                let data = vec![i as u8; 10_000];
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    async fn build_pack(entries: &[PackEntry]) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+            .await
+            .unwrap();
+        for entry in entries {
+            pack.add_object(entry.kind, &entry.id, &entry.data)
+                .await
+                .unwrap();
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    /// Encode the chunk stream for `pack` from `offset`, cut off after
+    /// `keep` bytes to simulate a dropped connection.
+    async fn chunk_stream(pack: &[u8], offset: u64, keep: Option<usize>) -> Cursor<Vec<u8>> {
+        let mut stream = Vec::new();
+        send_pack_from(pack, offset, &mut stream, WireFormat::Binary, CHUNK_SIZE)
+            .await
+            .unwrap();
+        if let Some(keep) = keep {
+            stream.truncate(keep);
+        }
+        Cursor::new(stream)
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_dropped_connection() {
+        let entries = sample_entries();
+        let pack: Arc<[u8]> = build_pack(&entries).await.into();
+        let sessions = ResumeSessions::default();
+        let session_id = sessions.create(pack.clone()).unwrap();
+
+        // First connection drops halfway through the pack.
+        let mut client = ResumableReceiver::new(session_id.clone()).with_ack_interval(32 * 1024);
+        let mut stream = chunk_stream(&pack, 0, Some(pack.len() / 2)).await;
+        let mut acks: Vec<u8> = Vec::new();
+        let result = client
+            .receive(&mut stream, &mut acks, WireFormat::Binary)
+            .await;
+        assert!(matches!(result, Err(ProtocolError::Frame(_))));
+        let received = client.received_bytes();
+        assert!(received > 0 && received < pack.len() as u64);
+
+        // The server saw some acknowledgements before the drop.
+        let result = receive_acks(&mut acks.as_slice(), WireFormat::Binary, &sessions).await;
+        assert!(result.is_err());
+        assert!(sessions.acknowledged(&session_id).unwrap() > 0);
+
+        // Reconnect and continue from where the client left off.
+        let (server_pack, offset) = sessions.resume(&client.resume_request()).unwrap();
+        assert_eq!(offset, received);
+        client.apply_resume(&ResumeResponse { offset }).unwrap();
+
+        let mut stream = chunk_stream(&server_pack, offset, None).await;
+        let mut acks: Vec<u8> = Vec::new();
+        client
+            .receive(&mut stream, &mut acks, WireFormat::Binary)
+            .await
+            .unwrap();
+        receive_acks(&mut acks.as_slice(), WireFormat::Binary, &sessions)
+            .await
+            .unwrap();
+        assert!(sessions.is_empty());
+
+        let bytes = client.into_pack();
+        assert_eq!(bytes, &pack[..]);
+        let reader = PackReader::new(bytes.as_slice()).await.unwrap();
+        assert_eq!(reader.read_all().await.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_prefix_mismatch_restarts_transfer() {
+        let pack: Arc<[u8]> = build_pack(&sample_entries()).await.into();
+        let sessions = ResumeSessions::default();
+        let session_id = sessions.create(pack.clone()).unwrap();
+
+        // The client receives a corrupted prefix before the connection drops.
+        let mut corrupted = pack.to_vec();
+        corrupted[100] ^= 0xff;
+        let mut client = ResumableReceiver::new(session_id);
+        let mut stream = chunk_stream(&corrupted, 0, Some(pack.len() / 2)).await;
+        let result = client
+            .receive(&mut stream, &mut Vec::<u8>::new(), WireFormat::Binary)
+            .await;
+        assert!(result.is_err());
+        assert!(client.received_bytes() > 0);
+
+        let (server_pack, offset) = sessions.resume(&client.resume_request()).unwrap();
+        assert_eq!(offset, 0);
+        client.apply_resume(&ResumeResponse { offset }).unwrap();
+        assert_eq!(client.received_bytes(), 0);
+
+        let mut stream = chunk_stream(&server_pack, offset, None).await;
+        client
+            .receive(&mut stream, &mut Vec::<u8>::new(), WireFormat::Binary)
+            .await
+            .unwrap();
+        assert_eq!(client.into_pack(), &pack[..]);
+    }
+
+    #[tokio::test]
+    async fn test_resume_at_end_of_pack() {
+        let pack = build_pack(&sample_entries()).await;
+        let mut stream = chunk_stream(&pack, pack.len() as u64, None).await;
+
+        let chunk: PackChunk = read_message_as(&mut stream, WireFormat::Binary)
+            .await
+            .unwrap();
+        assert!(chunk.last);
+        assert!(chunk.data.is_empty());
+        assert_eq!(
+            chunk.pack_hash.unwrap().as_bytes(),
+            &pack[pack.len() - HASH_LEN..]
+        );
+
+        let result = send_pack_from(
+            &pack,
+            pack.len() as u64 + 1,
+            &mut Vec::<u8>::new(),
+            WireFormat::Binary,
+            CHUNK_SIZE,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::InvalidResumeOffset { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_client_resume_offset() {
+        let mut client = ResumableReceiver::new("session".to_string());
+        let result = client.apply_resume(&ResumeResponse { offset: 10 });
+        assert!(matches!(
+            result,
+            Err(ProtocolError::InvalidResumeOffset {
+                offset: 10,
+                available: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let sessions = ResumeSessions::new(Duration::ZERO, DEFAULT_MAX_SESSIONS);
+        let session_id = sessions.create(Arc::from(vec![0u8; 64])).unwrap();
+        let request = ResumeRequest {
+            session_id,
+            received_bytes: 0,
+            received_hash: ObjectId::hash(b""),
+        };
+        assert!(matches!(
+            sessions.resume(&request),
+            Err(ProtocolError::UnknownSession)
+        ));
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_session_id() {
+        let sessions = ResumeSessions::default();
+        let session_id = sessions.create(Arc::from(vec![0u8; 64])).unwrap();
+        let mut forged = session_id.clone().into_bytes();
+        let last = forged.last_mut().unwrap();
+        *last = if *last == b'0' { b'1' } else { b'0' };
+        let ack = PackAck {
+            session_id: String::from_utf8(forged).unwrap(),
+            received_bytes: 10,
+        };
+        assert!(matches!(
+            sessions.acknowledge(&ack),
+            Err(ProtocolError::UnknownSession)
+        ));
+        assert_eq!(sessions.acknowledged(&session_id), Some(0));
+    }
+
+    #[test]
+    fn test_sessions_are_bounded() {
+        let sessions = ResumeSessions::new(DEFAULT_SESSION_TTL, 2);
+        let first = sessions.create(Arc::from(vec![1u8; 64])).unwrap();
+        let second = sessions.create(Arc::from(vec![2u8; 64])).unwrap();
+
+        // Touch the first session so the second is the least recently active.
+        std::thread::sleep(Duration::from_millis(1));
+        let ack = PackAck {
+            session_id: first.clone(),
+            received_bytes: 10,
+        };
+        assert!(!sessions.acknowledge(&ack).unwrap());
+        let third = sessions.create(Arc::from(vec![3u8; 64])).unwrap();
+
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.acknowledged(&first), Some(10));
+        assert_eq!(sessions.acknowledged(&second), None);
+        assert_eq!(sessions.acknowledged(&third), Some(0));
+    }
+
+    #[test]
+    fn test_sessions_bounded_by_bytes() {
+        let sessions =
+            ResumeSessions::new(DEFAULT_SESSION_TTL, DEFAULT_MAX_SESSIONS).with_max_bytes(100);
+        let first = sessions.create(Arc::from(vec![1u8; 40])).unwrap();
+        let second = sessions.create(Arc::from(vec![2u8; 40])).unwrap();
+
+        // A third pack doesn't fit alongside both, so the oldest is evicted.
+        let third = sessions.create(Arc::from(vec![3u8; 40])).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.acknowledged(&first), None);
+        assert_eq!(sessions.acknowledged(&second), Some(0));
+        assert_eq!(sessions.acknowledged(&third), Some(0));
+
+        // A pack larger than the whole budget is refused outright.
+        assert_eq!(sessions.create(Arc::from(vec![4u8; 101])), None);
+        assert_eq!(sessions.len(), 2);
+    }
+
+    #[test]
+    fn test_resumable_negotiation() {
+        let resumable = [Capability::Resumable];
+        let plain = [Capability::Operations];
+        assert!(resumable_enabled(&resumable, &resumable));
+        assert!(!resumable_enabled(&resumable, &plain));
+        assert!(!resumable_enabled(&plain, &resumable));
+    }
+}
@@ -0,0 +1,2067 @@
+//! Serving a sync session against a repository.
+//!
+//! [`serve_session`] runs the server side of a connection: the Hello exchange
+//! (version, credentials, repository), then any number of fetches, pushes,
+//! and ref listings until the client disconnects. Failures are reported to
+//! the client as an Error frame before the connection is closed.
+//!
+//! A fetch sends the operations and commits the client is missing, as worked
+//! out by [`Negotiation`](crate::negotiation::Negotiation). Trees and file
+//! contents aren't narrowed down yet: every one the server has is sent, and
+//! the client skips what it already has. A fetch with a depth is served as a
+//! [shallow fetch](crate::shallow) instead. Either kind can be narrowed by
+//! [path filters](crate::sparse), which turn the files outside them into
+//! promised entries.
+//!
+//! Building a pack can take a while. Meanwhile, the client gets a Counting
+//! progress update every [`ServerOptions::keepalive_interval`], so the
+//! connection doesn't look dead, and a client that goes away stops the work.
+//!
+//! Operations travel as [`OperationRecord`]s after the pack, in both
+//! directions. A push's operations are stored and staged: the bookmarks they
+//! move are checked like updates, and they are adopted with the push's
+//! updates, which build on them, or not at all. A push that
+//! repeats the `push_id` of one already done is answered from the
+//! [push log](crate::push_log) instead of being applied again.
+//!
+//! With [`ServerOptions::create_on_push`], a client with write access may
+//! ask in its Hello for a repository under its own name to be created. A
+//! repository created that way is deleted again when the session ends, if
+//! nothing was pushed to it.
+//!
+//! Each side can leave out the commits the other probably has, going by a
+//! [commit filter](crate::bloom): the client's in a fetch request, the
+//! server's in its answer to a push. Those commits are only promised, and
+//! the ones that were false positives are asked for by ID afterwards.
+//!
+//! Commits can be wanted by ID, or by an unambiguous prefix of one, as well
+//! as by ref. They are sent on top of whatever the refs call for; the ones
+//! that can't be sent are listed in the response's `want_errors` rather
+//! than failing the fetch. Commits that are no longer visible are only
+//! served with [`ServerOptions::fetch_hidden_commits`].
+//!
+//! With [`Capability::Pipelining`], the client may send requests before the
+//! earlier ones are answered. They are still served one at a time, in the
+//! order they arrive: a request that comes in while another one waits for
+//! the client (say, for a [`HaveMore`] during negotiation) is queued. Each
+//! response, with the pack and operations that follow it, is complete
+//! before the next one starts, and echoes its request's `request_id`.
+//!
+//! A client that goes quiet in the middle of a request, for longer than
+//! [`ServerOptions::stall_timeout`], is taken to be gone, as is one whose
+//! connection fails. Its session is aborted and reported to the
+//! [`ServerOptions::monitor`], which is also told when sessions start and
+//! end, of each request they serve, and of the bookmarks each push moves.
+//! Nothing a request does is stored until its client has sent all of it:
+//! a push's pack is held in memory until the last chunk, and its
+//! operations and bookmarks are only written after that, so an aborted
+//! push leaves the repository as it was.
+//!
+//! When the server shuts down, its [`ServerOptions::shutdown`] first drains
+//! sessions: each finishes the request it is serving, then ends with a
+//! retryable Error frame rather than starting another. Requests still
+//! running when the server stops waiting are aborted the same way.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::{Pin, pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use forjj_storage::object_id::{MAX_ID_LEN, ObjectIdError};
+use forjj_storage::{
+    BookmarkNameError, BookmarkTarget, BookmarkUpdate, CommitId, FileId, ObjectId, OperationId,
+    PrefixResolution, RawObjectKind, Repository, RepositoryManager, StorageError,
+    validate_bookmark_name,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::{AnonymousRead, AuthError, AuthGrant, AuthHandler, server_authenticate};
+use crate::bloom::{CommitFilter, DEFAULT_HASHES};
+use crate::capability::CapabilitySet;
+use crate::envelope::{Message, WireFormat, close_with_error, read_message_after, write_message};
+use crate::error::ProtocolError;
+use crate::framing::FrameError;
+use crate::handshake::{
+    Negotiated, VersionRange, server_read_hello, server_select_repo, server_send_hello,
+};
+use crate::keepalive::{
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_STALL_TIMEOUT, answer_ping,
+    eof_as_frame_error,
+};
+use crate::messages::{
+    AccessLevel, Auth, CancelAck, Capability, CompressionAlgorithm, FetchMode, FetchRequest,
+    FetchResponse, HaveMore, HelloResponse, ListRefsRequest, ListRefsResponse, OperationRecord,
+    ProgressMessage, ProgressPhase, PushNegotiate, PushRequest, PushResult, PushStatus, RefInfo,
+    RefReason, RefResult, RefStatus, RefUpdate, RepoRef, ServerLimits, WantError, WantReason,
+};
+use crate::negotiation::{DEFAULT_MAX_HAVES, DEFAULT_MAX_ROUNDS, Negotiation, OpGraph};
+use crate::pack::{PackEntry, PackLimits};
+use crate::push_log::{PushLog, check_push_id};
+use crate::refs::{Expansion, TAG_PREFIX, expand_want_refs, match_refs};
+use crate::shallow::select_shallow;
+use crate::sparse::PathFilter;
+use crate::sync::{
+    CONTENT_KINDS, ExportedPack, ImportStats, export_operations, export_partial, import_objects,
+    import_operations, missing_commits,
+};
+use crate::throttle::{RateLimit, ThrottledWriter};
+use crate::transfer::{DEFAULT_CHUNK_SIZE, receive_pack_with_limits, send_pack};
+
+/// Most pipelined requests a session holds while serving an earlier one.
+pub const MAX_QUEUED_REQUESTS: usize = 32;
+
+/// Looks up the repositories a server hosts.
+pub trait RepoProvider {
+    /// Whether `repo` exists.
+    fn exists(&self, repo: &RepoRef) -> bool;
+
+    /// Open `repo` for a session.
+    fn open(&self, repo: &RepoRef) -> anyhow::Result<Repository>;
+
+    /// Create `repo`, empty, for a session that pushes to it.
+    fn create(&self, repo: &RepoRef) -> anyhow::Result<Repository> {
+        anyhow::bail!("creating repositories is not supported: {repo}")
+    }
+
+    /// Delete `repo`, which a session created but didn't push to.
+    fn delete(&self, repo: &RepoRef) -> anyhow::Result<()> {
+        anyhow::bail!("deleting repositories is not supported: {repo}")
+    }
+
+    /// Whether a client with `grant` may see `repo`, which exists. Clients
+    /// that may not are told there is no such repository.
+    fn may_see(&self, _repo: &RepoRef, _grant: &AuthGrant) -> bool {
+        true
+    }
+}
+
+impl RepoProvider for RepositoryManager {
+    fn exists(&self, repo: &RepoRef) -> bool {
+        self.repo_exists(&repo.owner, &repo.name)
+    }
+
+    fn open(&self, repo: &RepoRef) -> anyhow::Result<Repository> {
+        self.open_repo(&repo.owner, &repo.name)
+    }
+
+    fn create(&self, repo: &RepoRef) -> anyhow::Result<Repository> {
+        self.create_repo(&repo.owner, &repo.name)
+    }
+
+    fn delete(&self, repo: &RepoRef) -> anyhow::Result<()> {
+        self.delete_repo(&repo.owner, &repo.name)
+    }
+}
+
+/// Per-repository rules for pushes.
+pub trait PushPolicy {
+    /// Whether `repo` refuses non-fast-forward updates, even ones with
+    /// [`RefUpdate::force`] set.
+    fn deny_force_push(&self, repo: &RepoRef) -> bool;
+
+    /// Whether `repo` refuses every update to `bookmark`.
+    fn protects_bookmark(&self, _repo: &RepoRef, _bookmark: &str) -> bool {
+        false
+    }
+
+    /// Most bookmarks `repo` may have, if limited.
+    fn max_bookmarks(&self, _repo: &RepoRef) -> Option<u64> {
+        None
+    }
+}
+
+/// Allows force pushes to every repository.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowForcePush;
+
+impl PushPolicy for AllowForcePush {
+    fn deny_force_push(&self, _repo: &RepoRef) -> bool {
+        false
+    }
+}
+
+/// Told about sessions that end because the client went away, about the
+/// requests sessions serve, and about the bookmarks pushes move.
+pub trait SessionMonitor {
+    /// A session was aborted: its client stopped sending, or the connection
+    /// failed.
+    fn aborted(&self, abort: &SessionAbort);
+
+    /// A push moved bookmarks. Called once the move is stored, before the
+    /// client is answered.
+    fn bookmarks_moved(&self, _moved: &BookmarksMoved) {}
+
+    /// A session agreed on its Hello and started serving requests on
+    /// `repo`.
+    fn session_started(&self, _repo: &RepoRef) {}
+
+    /// A session that started has ended, however it ended, even if its
+    /// future was dropped.
+    fn session_ended(&self, _repo: &RepoRef) {}
+
+    /// A session served a request.
+    fn request_served(&self, _served: &RequestServed) {}
+}
+
+/// A request a session served, for [`SessionMonitor::request_served`].
+#[derive(Debug, Clone)]
+pub struct RequestServed {
+    /// The repository the session is on
+    pub repo: RepoRef,
+    /// `"Fetch"`, `"Push"` or `"ListRefs"`
+    pub request: &'static str,
+    /// Bytes read from the client while serving it, such as a push's pack
+    pub bytes_received: u64,
+    /// Bytes written to the client while serving it, such as a fetch's
+    /// pack
+    pub bytes_sent: u64,
+}
+
+/// Tells a monitor that a session ended when dropped.
+struct ActiveSession<'a> {
+    monitor: &'a (dyn SessionMonitor + Send + Sync),
+    repo: RepoRef,
+}
+
+impl<'a> ActiveSession<'a> {
+    fn start(monitor: &'a (dyn SessionMonitor + Send + Sync), repo: &RepoRef) -> Self {
+        monitor.session_started(repo);
+        Self {
+            monitor,
+            repo: repo.clone(),
+        }
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.monitor.session_ended(&self.repo);
+    }
+}
+
+/// The bookmarks a push moved.
+#[derive(Debug, Clone)]
+pub struct BookmarksMoved {
+    /// The repository the session was on
+    pub repo: RepoRef,
+    /// Who the client authenticated as, if anyone
+    pub identity: Option<String>,
+    /// The operation that moved them
+    pub operation: OperationId,
+    /// Each bookmark moved: `expected` is where it was, and `target` where
+    /// it is now
+    pub updates: Vec<BookmarkUpdate>,
+}
+
+/// A session that ended without its client closing the connection.
+#[derive(Debug, Clone)]
+pub struct SessionAbort {
+    /// The repository the session was on
+    pub repo: RepoRef,
+    /// Who the client authenticated as, if anyone
+    pub identity: Option<String>,
+    /// The request being served, e.g. `"Push"`, if any
+    pub request: Option<&'static str>,
+    /// Why the session was aborted
+    pub reason: String,
+    /// Bytes read from the client, Hello included
+    pub bytes_received: u64,
+    /// Bytes written to the client, Hello included
+    pub bytes_sent: u64,
+}
+
+/// Settings for [`serve_session`].
+#[derive(Clone)]
+pub struct ServerOptions {
+    /// Protocol versions the server speaks
+    pub versions: VersionRange,
+    /// Capabilities the server advertises
+    pub capabilities: CapabilitySet,
+    /// Checks the client's credentials
+    pub auth: Arc<dyn AuthHandler + Send + Sync>,
+    /// How long to wait for the client's next frame, if limited
+    pub idle_timeout: Option<Duration>,
+    /// How long a request may wait for more of it from the client before
+    /// the connection is taken for dead, if limited
+    pub stall_timeout: Option<Duration>,
+    /// How often to tell a client waiting for a pack that it is still being
+    /// built, if at all
+    pub keepalive_interval: Option<Duration>,
+    /// Pack bytes per chunk when sending
+    pub chunk_size: usize,
+    /// How many times a fetch may ask the client for more operations
+    pub max_negotiation_rounds: u32,
+    /// How many operations a client may offer in a batched negotiation
+    pub max_negotiation_haves: u32,
+    /// Rules for pushes
+    pub push_policy: Arc<dyn PushPolicy + Send + Sync>,
+    /// Size in bits of the commit filter sent for a push, if fixed;
+    /// otherwise it is sized for the repository's commits
+    pub commit_filter_bits: Option<u32>,
+    /// Cap on each connection's outbound throughput, if any
+    pub rate_limit: Option<RateLimit>,
+    /// Whether a fetch may ask by ID for commits that are in the store but
+    /// not reachable from any visible head
+    pub fetch_hidden_commits: bool,
+    /// Whether a client with write access may create a repository under
+    /// its own name by pushing to it
+    pub create_on_push: bool,
+    /// Size limits on the packs clients push, advertised in the Hello
+    pub pack_limits: PackLimits,
+    /// Extra settings advertised in the Hello, for clients that know them
+    pub extensions: BTreeMap<String, String>,
+    /// Remembers pushes with a `push_id`, so retries aren't applied twice
+    pub push_log: PushLog,
+    /// Told about aborted sessions and moved bookmarks, if anything
+    pub monitor: Option<Arc<dyn SessionMonitor + Send + Sync>>,
+    /// Tells sessions the server is shutting down
+    pub shutdown: SessionShutdown,
+}
+
+/// Tells sessions that the server is shutting down.
+///
+/// Clones share their state, so the server keeps one and its sessions get
+/// the others through [`ServerOptions`].
+#[derive(Debug, Clone, Default)]
+pub struct SessionShutdown {
+    drain: CancellationToken,
+    abort: CancellationToken,
+}
+
+impl SessionShutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let each session finish the request it is serving, then end it with
+    /// [`ProtocolError::ShuttingDown`] instead of serving another.
+    pub fn drain(&self) {
+        self.drain.cancel();
+    }
+
+    /// End every session now, in the middle of a request if need be, with
+    /// [`ProtocolError::ShuttingDown`].
+    pub fn abort(&self) {
+        self.drain.cancel();
+        self.abort.cancel();
+    }
+
+    /// Whether [`drain`](Self::drain) or [`abort`](Self::abort) has been
+    /// called.
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_cancelled()
+    }
+}
+
+impl ServerOptions {
+    /// Default options, checking credentials with `auth`.
+    pub fn new(auth: Arc<dyn AuthHandler + Send + Sync>) -> Self {
+        Self {
+            auth,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ServerOptions {
+    /// Anonymous read-only access.
+    fn default() -> Self {
+        Self {
+            versions: VersionRange::SUPPORTED,
+            capabilities: [
+                Capability::Operations,
+                Capability::BinaryFrames,
+                Capability::Pipelining,
+            ]
+            .into_iter()
+            .collect(),
+            auth: Arc::new(AnonymousRead),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            stall_timeout: Some(DEFAULT_STALL_TIMEOUT),
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_negotiation_rounds: DEFAULT_MAX_ROUNDS,
+            max_negotiation_haves: DEFAULT_MAX_HAVES,
+            push_policy: Arc::new(AllowForcePush),
+            commit_filter_bits: None,
+            rate_limit: None,
+            fetch_hidden_commits: false,
+            create_on_push: false,
+            pack_limits: PackLimits::default(),
+            extensions: BTreeMap::new(),
+            push_log: PushLog::default(),
+            monitor: None,
+            shutdown: SessionShutdown::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ServerOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerOptions")
+            .field("versions", &self.versions)
+            .field("capabilities", &self.capabilities)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("stall_timeout", &self.stall_timeout)
+            .field("keepalive_interval", &self.keepalive_interval)
+            .field("chunk_size", &self.chunk_size)
+            .field("max_negotiation_rounds", &self.max_negotiation_rounds)
+            .field("max_negotiation_haves", &self.max_negotiation_haves)
+            .field("commit_filter_bits", &self.commit_filter_bits)
+            .field("rate_limit", &self.rate_limit)
+            .field("fetch_hidden_commits", &self.fetch_hidden_commits)
+            .field("create_on_push", &self.create_on_push)
+            .field("pack_limits", &self.pack_limits)
+            .field("extensions", &self.extensions)
+            .field("push_log", &self.push_log)
+            .field("shutdown", &self.shutdown)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serve one client connection until it disconnects.
+///
+/// Returns `Ok(())` when the client closes the connection between requests.
+/// Any failure is reported to the client as an Error frame, after which the
+/// connection is closed and the error returned.
+pub async fn serve_session<S, P>(
+    stream: S,
+    provider: &P,
+    options: &ServerOptions,
+) -> Result<(), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite,
+    P: RepoProvider + ?Sized,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = ClientReader::new(reader);
+    let mut writer = ThrottledWriter::new(writer, options.rate_limit);
+
+    // These report their own failures to the client.
+    let (request, version) = server_read_hello(&mut reader, &mut writer, options.versions).await?;
+    let grant = server_authenticate(&mut writer, &request, options.auth.as_ref()).await?;
+    // A repository the client may not see is treated as missing throughout,
+    // down to the error for trying to create it.
+    let visible = |repo: &RepoRef| provider.exists(repo) && provider.may_see(repo, &grant);
+    let repo_ref = server_select_repo(&mut writer, &request, |repo| {
+        visible(repo) || request.create_if_missing
+    })
+    .await?;
+    let create = !visible(&repo_ref);
+
+    let opened = async {
+        let repo = if create {
+            if !may_create(options, &grant, &repo_ref) || provider.exists(&repo_ref) {
+                return Err(ProtocolError::CreateDenied(repo_ref.clone()));
+            }
+            provider.create(&repo_ref)?
+        } else {
+            provider.open(&repo_ref)?
+        };
+        let op_heads = repo.op_head_ids().await?;
+        let common_ancestor = if request.client_op_heads.is_empty() {
+            None
+        } else {
+            OpGraph::load(&repo)
+                .await?
+                .common_ancestor(&request.client_op_heads)
+        };
+        Ok::<_, ProtocolError>((repo, op_heads, common_ancestor))
+    }
+    .await;
+    let (repo, op_heads, common_ancestor) = match opened {
+        Ok(opened) => opened,
+        Err(error) => {
+            close_with_error(&mut writer, error.to_error_message(), WireFormat::Json).await;
+            return Err(error);
+        }
+    };
+
+    // A repository created for the session is only kept if something is
+    // pushed to it.
+    let created = create.then(|| op_heads.clone());
+    let response = HelloResponse {
+        protocol_version: version,
+        capabilities: options.capabilities.clone().into(),
+        server_op_heads: op_heads,
+        common_ancestor,
+        compression: None,
+        max_frame_size: None,
+        identity: grant.identity.clone(),
+        access: Some(grant.access),
+        limits: server_limits(options),
+        extensions: options.extensions.clone(),
+    };
+    let negotiated = match server_send_hello(&mut writer, &request, response).await {
+        Ok(negotiated) => negotiated,
+        Err(error) => {
+            if let Some(op_heads) = &created {
+                // The failed Hello is the error worth reporting.
+                let _ = remove_unpushed(provider, &repo_ref, op_heads).await;
+            }
+            return Err(error);
+        }
+    };
+
+    let mut session = Session {
+        reader,
+        writer,
+        repo,
+        repo_ref,
+        grant,
+        negotiated,
+        options,
+        queued: VecDeque::new(),
+        request_id: None,
+        request: None,
+        created,
+    };
+    let _active = options
+        .monitor
+        .as_deref()
+        .map(|monitor| ActiveSession::start(monitor, &session.repo_ref));
+    let result = session.run().await;
+    match (&result, &options.monitor) {
+        (Err(error), Some(monitor)) if connection_lost(error) => monitor.aborted(&SessionAbort {
+            repo: session.repo_ref.clone(),
+            identity: session.grant.identity.clone(),
+            request: session.request,
+            reason: error.to_string(),
+            bytes_received: session.reader.bytes_read,
+            bytes_sent: session.writer.bytes_written(),
+        }),
+        _ => {}
+    }
+    match &result {
+        Err(error) if error.should_report() => {
+            let format = session.negotiated.format;
+            close_with_error(&mut session.writer, error.to_error_message(), format).await;
+        }
+        _ => {}
+    }
+    match &session.created {
+        Some(op_heads) => result.and(remove_unpushed(provider, &session.repo_ref, op_heads).await),
+        None => result,
+    }
+}
+
+/// Whether the client with `grant` may create `repo` by pushing to it.
+fn may_create(options: &ServerOptions, grant: &AuthGrant, repo: &RepoRef) -> bool {
+    options.create_on_push
+        && grant.access >= AccessLevel::Write
+        && grant.identity.as_deref() == Some(repo.owner.as_str())
+}
+
+/// Delete `repo`, created by a session, unless its operations have moved
+/// on from `op_heads` since, e.g. because another session pushed to it.
+async fn remove_unpushed<P>(
+    provider: &P,
+    repo: &RepoRef,
+    op_heads: &[OperationId],
+) -> Result<(), ProtocolError>
+where
+    P: RepoProvider + ?Sized,
+{
+    if provider.open(repo)?.op_head_ids().await? == op_heads {
+        provider.delete(repo)?;
+    }
+    Ok(())
+}
+
+/// Whether `error` means the client is gone, rather than that it broke the
+/// protocol.
+fn connection_lost(error: &ProtocolError) -> bool {
+    matches!(
+        error,
+        ProtocolError::IdleTimeout(_)
+            | ProtocolError::Frame(
+                FrameError::Io(_) | FrameError::UnexpectedEof | FrameError::Timeout(_)
+            )
+    )
+}
+
+/// The client's half of a session's connection.
+///
+/// Counts the bytes read, and while a stall timeout is set, fails any read
+/// that waits longer than it with [`io::ErrorKind::TimedOut`].
+struct ClientReader<R> {
+    inner: R,
+    bytes_read: u64,
+    stall_timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ClientReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            stall_timeout: None,
+            sleep: None,
+        }
+    }
+
+    fn set_stall_timeout(&mut self, timeout: Option<Duration>) {
+        self.stall_timeout = timeout;
+        self.sleep = None;
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ClientReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            this.bytes_read += (buf.filled().len() - before) as u64;
+            this.sleep = None;
+            return poll;
+        }
+        let Some(timeout) = this.stall_timeout else {
+            return Poll::Pending;
+        };
+        let sleep = this
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("client sent nothing for {timeout:?}"),
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The limits to advertise for `options`.
+fn server_limits(options: &ServerOptions) -> ServerLimits {
+    // A transport that authenticated the client grants it an identity
+    // whatever the Hello says; that isn't anonymous access.
+    let anonymous = options.auth.authenticate(&Auth::None, AccessLevel::Read);
+    ServerLimits {
+        max_pack_size: Some(options.pack_limits.max_pack_size),
+        max_object_size: Some(options.pack_limits.max_object_size),
+        compression: CompressionAlgorithm::SUPPORTED.to_vec(),
+        anonymous_read: Some(matches!(anonymous, Ok(grant) if grant.identity.is_none())),
+    }
+}
+
+/// State of a session after the Hello exchange.
+struct Session<'a, S> {
+    reader: ClientReader<ReadHalf<S>>,
+    writer: ThrottledWriter<WriteHalf<S>>,
+    repo: Repository,
+    repo_ref: RepoRef,
+    grant: AuthGrant,
+    negotiated: Negotiated,
+    options: &'a ServerOptions,
+    /// Pipelined requests that arrived during an earlier one
+    queued: VecDeque<Message>,
+    /// `request_id` of the request being served
+    request_id: Option<u64>,
+    /// Name of the request being served
+    request: Option<&'static str>,
+    /// Operation heads of the repository, if the session created it and
+    /// nothing has been pushed to it yet
+    created: Option<Vec<OperationId>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Session<'_, S> {
+    async fn run(&mut self) -> Result<(), ProtocolError> {
+        let shutdown = self.options.shutdown.clone();
+        loop {
+            // Between requests, only the idle timeout applies.
+            self.request = None;
+            self.reader.set_stall_timeout(None);
+            if shutdown.is_draining() {
+                return Err(ProtocolError::ShuttingDown);
+            }
+            let message = match self.queued.pop_front() {
+                Some(message) => message,
+                None => tokio::select! {
+                    next = self.next_message() => match next? {
+                        Some(message) => message,
+                        None => return Ok(()),
+                    },
+                    () = shutdown.drain.cancelled() => return Err(ProtocolError::ShuttingDown),
+                },
+            };
+            self.request = Some(message.name());
+            self.reader.set_stall_timeout(self.options.stall_timeout);
+            self.negotiated.check_message(&message)?;
+            let served = matches!(
+                message,
+                Message::Fetch(_) | Message::Push(_) | Message::ListRefs(_)
+            )
+            .then(|| {
+                (
+                    message.name(),
+                    self.reader.bytes_read,
+                    self.writer.bytes_written(),
+                )
+            });
+            tokio::select! {
+                served = self.serve(message) => served?,
+                () = shutdown.abort.cancelled() => return Err(ProtocolError::ShuttingDown),
+            }
+            if let (Some((request, received, sent)), Some(monitor)) =
+                (served, &self.options.monitor)
+            {
+                // Pipelined requests read during this one count toward it.
+                monitor.request_served(&RequestServed {
+                    repo: self.repo_ref.clone(),
+                    request,
+                    bytes_received: self.reader.bytes_read - received,
+                    bytes_sent: self.writer.bytes_written() - sent,
+                });
+            }
+        }
+    }
+
+    /// Serve one request from the client.
+    async fn serve(&mut self, message: Message) -> Result<(), ProtocolError> {
+        match message {
+            Message::Fetch(request) => {
+                self.request_id = request.request_id;
+                self.fetch(request).await
+            }
+            Message::Push(request) => {
+                self.request_id = request.request_id;
+                self.push(request).await
+            }
+            Message::ListRefs(request) => {
+                self.request_id = request.request_id;
+                self.list_refs(request).await
+            }
+            // Nothing runs between requests, so there's nothing to stop.
+            Message::Cancel(_) => self.send(CancelAck {}.into()).await,
+            Message::Error(error) => Err(ProtocolError::Remote(error)),
+            other => Err(ProtocolError::UnexpectedMessage {
+                expected: "Fetch, Push, or ListRefs",
+                actual: other.name(),
+            }),
+        }
+    }
+
+    /// Read the client's next request, answering keepalives.
+    ///
+    /// Returns `None` if the client closed the connection.
+    async fn next_message(&mut self) -> Result<Option<Message>, ProtocolError> {
+        let format = self.negotiated.format;
+        loop {
+            let first = match self.options.idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.reader.read_u8())
+                    .await
+                    .map_err(|_| ProtocolError::IdleTimeout(timeout))?,
+                None => self.reader.read_u8().await,
+            };
+            let first = match first {
+                Ok(first) => first,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(FrameError::Io(e).into()),
+            };
+
+            match read_message_after(first, &mut self.reader, format).await? {
+                Message::Ping(ping) => answer_ping(&mut self.writer, ping, format).await?,
+                Message::Pong(_) => {}
+                message => return Ok(Some(message)),
+            }
+        }
+    }
+
+    /// Read a message the current request can't do without.
+    ///
+    /// With pipelining, requests that arrive first are queued.
+    async fn next_in_request(&mut self) -> Result<Message, ProtocolError> {
+        let pipelining = self.negotiated.has(Capability::Pipelining);
+        loop {
+            let Some(message) = self.next_message().await? else {
+                return Err(FrameError::UnexpectedEof.into());
+            };
+            self.negotiated.check_message(&message)?;
+            match message {
+                Message::Fetch(_) | Message::Push(_) | Message::ListRefs(_) if pipelining => {
+                    queue_request(&mut self.queued, message)?
+                }
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), ProtocolError> {
+        write_message(&mut self.writer, &message, self.negotiated.format).await
+    }
+
+    /// Receive a pushed pack, within the advertised limits.
+    async fn receive_pack(&mut self) -> Result<Vec<PackEntry>, ProtocolError> {
+        let limits = self.options.pack_limits;
+        receive_pack_with_limits(&mut self.reader, self.negotiated.format, limits).await
+    }
+
+    /// Run `work`, which builds a pack from the repository, sending a
+    /// Counting [`ProgressMessage`] every
+    /// [`ServerOptions::keepalive_interval`] until it is done, so the
+    /// connection doesn't look idle.
+    ///
+    /// The client's frames are read meanwhile: pipelined requests are
+    /// queued, and if the connection fails, `work` is dropped unfinished.
+    async fn packing<'a, F>(
+        &'a mut self,
+        work: impl FnOnce(&'a Repository) -> F,
+    ) -> Result<F::Output, ProtocolError>
+    where
+        F: Future,
+    {
+        let Session {
+            reader,
+            writer,
+            repo,
+            negotiated,
+            options,
+            queued,
+            ..
+        } = self;
+        let format = negotiated.format;
+        let pipelining = negotiated.has(Capability::Pipelining);
+        let mut work = pin!(work(repo));
+        let mut ticker = options.keepalive_interval.map(|interval| {
+            let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+
+        // The client has nothing to send while it waits for the pack.
+        let stall_timeout = reader.stall_timeout;
+        reader.set_stall_timeout(None);
+        let result: Result<F::Output, ProtocolError> = async {
+            loop {
+                // Only the first byte of a frame is raced against `work`, so a
+                // frame is never abandoned halfway through.
+                let first = tokio::select! {
+                    biased;
+                    output = &mut work => return Ok(output),
+                    () = tick(&mut ticker) => {
+                        let progress = ProgressMessage {
+                            phase: ProgressPhase::Counting,
+                            current: 0,
+                            total: None,
+                            bytes: None,
+                        };
+                        write_message(writer, &progress.into(), format).await?;
+                        continue;
+                    }
+                    first = reader.read_u8() => first.map_err(eof_as_frame_error)?,
+                };
+                let message = read_message_after(first, reader, format).await?;
+                negotiated.check_message(&message)?;
+                match message {
+                    Message::Ping(ping) => answer_ping(writer, ping, format).await?,
+                    Message::Pong(_) => {}
+                    Message::Fetch(_) | Message::Push(_) | Message::ListRefs(_) if pipelining => {
+                        queue_request(queued, message)?
+                    }
+                    Message::Error(error) => return Err(ProtocolError::Remote(error)),
+                    other => {
+                        return Err(ProtocolError::UnexpectedMessage {
+                            expected: "Ping",
+                            actual: other.name(),
+                        });
+                    }
+                }
+            }
+        }
+        .await;
+        reader.set_stall_timeout(stall_timeout);
+        result
+    }
+
+    async fn list_refs(&mut self, request: ListRefsRequest) -> Result<(), ProtocolError> {
+        // Pick up pushes from other sessions.
+        self.repo.reload()?;
+        let names = match_refs(
+            &request.patterns,
+            &self.repo.bookmark_names(),
+            &self.repo.tag_names(),
+        )?;
+        let mut refs = Vec::with_capacity(names.len());
+        for name in names {
+            let (target, is_tag) = self.ref_target(&name)?;
+            refs.push(RefInfo {
+                name,
+                target: target.into(),
+                is_tag,
+            });
+        }
+        let response = ListRefsResponse {
+            refs,
+            request_id: self.request_id,
+        };
+        self.send(response.into()).await
+    }
+
+    async fn fetch(&mut self, request: FetchRequest) -> Result<(), ProtocolError> {
+        let filter = if request.path_filters.is_empty() {
+            None
+        } else {
+            Some(PathFilter::parse(&request.path_filters)?)
+        };
+        if let Some(have) = &request.have_commits_filter {
+            have.validate()?;
+        }
+        // Pick up pushes from other sessions.
+        self.repo.reload()?;
+        let (wanted, want_errors) = self.resolve_wants(&request.want_commits)?;
+        if !request.want_commits.is_empty() && request.want_refs.is_empty() {
+            return self
+                .fetch_commits(&wanted, want_errors, filter.as_ref())
+                .await;
+        }
+        let refs = match expand_want_refs(
+            &request.want_refs,
+            &self.repo.bookmark_names(),
+            &self.repo.tag_names(),
+        )? {
+            Expansion::Refs(refs) => refs,
+            Expansion::Missing(name) => return Err(ProtocolError::UnknownBookmark(name)),
+        };
+        if refs.is_empty() && !request.want_refs.is_empty() {
+            // The patterns matched nothing, so only wanted commits are sent.
+            return self
+                .fetch_commits(&wanted, want_errors, filter.as_ref())
+                .await;
+        }
+        let include_operations = request
+            .include_operations
+            .unwrap_or_else(|| self.negotiated.has(Capability::Operations));
+        if request.depth.is_some() || !include_operations {
+            // Without operations there is nothing to negotiate: the commits
+            // are walked from the refs as for a shallow fetch of any depth.
+            let depth = request.depth.unwrap_or(u32::MAX);
+            return self
+                .fetch_shallow(&request, refs, &wanted, want_errors, depth, filter.as_ref())
+                .await;
+        }
+
+        let graph = OpGraph::load(&self.repo).await?;
+        let max_rounds = self.options.max_negotiation_rounds;
+        let mut negotiation = if self.negotiated.has(Capability::NegotiateV2) {
+            let max_haves = self.options.max_negotiation_haves;
+            Negotiation::batched(&graph, &request.have_ops, max_rounds, max_haves)
+        } else {
+            Negotiation::new(&graph, &request.have_ops, max_rounds)
+        };
+        while !negotiation.is_ready() {
+            self.send(negotiation.ack().into()).await?;
+            let more: HaveMore = self.next_in_request().await?.try_into()?;
+            negotiation.receive(&more);
+        }
+        if negotiation.rounds() > 0 {
+            self.send(negotiation.ack().into()).await?;
+        }
+
+        let plan = negotiation.plan(|id| self.repo.commit_parent_ids(id))?;
+        if plan.missing_ops.is_empty() {
+            let pack = self
+                .packing(|repo| pack_commits(repo, &wanted, filter.as_ref()))
+                .await??;
+            let response = FetchResponse {
+                pack_follows: !wanted.is_empty(),
+                ops_to_send: plan.new_heads,
+                commit_count: pack.commit_count,
+                resume_session: None,
+                refs,
+                shallow_boundary: vec![],
+                filtered: filter.is_some() && !wanted.is_empty(),
+                operation_count: 0,
+                request_id: self.request_id,
+                want_errors,
+                mode: FetchMode::Operations,
+                ref_targets: vec![],
+            };
+            return self.send_fetch(response, &pack.data, Vec::new()).await;
+        }
+        // Wanted commits go along with the ones the operations bring.
+        let extra: Vec<_> = wanted
+            .iter()
+            .filter(|id| !plan.commits.contains(id))
+            .copied()
+            .collect();
+        let have = request.have_commits_filter.as_ref();
+        let pack = self
+            .packing(|repo| pack_plan(repo, &plan.commits, &extra, filter.as_ref(), have))
+            .await??;
+        let deltas = self
+            .negotiated
+            .capabilities
+            .contains(&Capability::Operations);
+        let operations = export_operations(&self.repo, &graph, &plan.missing_ops, deltas)?;
+
+        let response = FetchResponse {
+            pack_follows: true,
+            ops_to_send: plan.new_heads,
+            commit_count: pack.commit_count,
+            resume_session: None,
+            refs,
+            shallow_boundary: vec![],
+            filtered: filter.is_some(),
+            operation_count: 0,
+            request_id: self.request_id,
+            want_errors,
+            mode: FetchMode::Operations,
+            ref_targets: vec![],
+        };
+        self.send_fetch(response, &pack.data, operations).await
+    }
+
+    /// Serve a fetch of at most `depth` generations from the tips of `refs`,
+    /// or of the visible heads if the repository has no refs, and from the
+    /// `wanted` commits.
+    ///
+    /// No operations are sent; the response lists where `refs` point for the
+    /// client to record itself. Conflicted refs have no single tip and are
+    /// left out of the walk.
+    async fn fetch_shallow(
+        &mut self,
+        request: &FetchRequest,
+        refs: Vec<String>,
+        wanted: &[CommitId],
+        want_errors: Vec<WantError>,
+        depth: u32,
+        filter: Option<&PathFilter>,
+    ) -> Result<(), ProtocolError> {
+        let mut tips = Vec::new();
+        let mut ref_targets = Vec::new();
+        for name in &refs {
+            let (target, is_tag) = self.ref_target(name)?;
+            match target {
+                BookmarkTarget::Normal(id) if !tips.contains(&id) => tips.push(id),
+                _ => {}
+            }
+            ref_targets.push(RefInfo {
+                name: name.clone(),
+                target: target.into(),
+                is_tag,
+            });
+        }
+        if refs.is_empty() {
+            tips = self.repo.head_ids()?;
+        }
+        for id in wanted {
+            if !tips.contains(id) {
+                tips.push(*id);
+            }
+        }
+
+        let mut have: HashSet<_> = request.have_commits.iter().copied().collect();
+        have.insert(self.repo.root_commit_id()?);
+        let selection = select_shallow(&tips, depth, &have, |id| self.repo.commit_parent_ids(id))?;
+        let commits = &selection.commits;
+        let pack = self
+            .packing(|repo| pack_commits(repo, commits, filter))
+            .await??;
+
+        let response = FetchResponse {
+            pack_follows: !selection.commits.is_empty(),
+            ops_to_send: vec![],
+            commit_count: pack.commit_count,
+            resume_session: None,
+            refs,
+            shallow_boundary: selection.boundary,
+            filtered: filter.is_some(),
+            operation_count: 0,
+            request_id: self.request_id,
+            want_errors,
+            mode: FetchMode::CommitsOnly,
+            ref_targets,
+        };
+        self.send_fetch(response, &pack.data, Vec::new()).await
+    }
+
+    /// Where the ref `name` points, tags prefixed with [`TAG_PREFIX`], and
+    /// whether it is a tag.
+    fn ref_target(&self, name: &str) -> Result<(BookmarkTarget, bool), ProtocolError> {
+        Ok(match name.strip_prefix(TAG_PREFIX) {
+            Some(tag) => (self.repo.tag_target(tag)?, true),
+            None => (self.repo.bookmark_target(name)?, false),
+        })
+    }
+
+    /// Serve a fetch of exactly `commits`, e.g. promised ones the client
+    /// turned out to lack, reporting `want_errors` for the rest.
+    async fn fetch_commits(
+        &mut self,
+        commits: &[CommitId],
+        want_errors: Vec<WantError>,
+        filter: Option<&PathFilter>,
+    ) -> Result<(), ProtocolError> {
+        let pack = self
+            .packing(|repo| pack_commits(repo, commits, filter))
+            .await??;
+
+        let response = FetchResponse {
+            pack_follows: !commits.is_empty(),
+            ops_to_send: vec![],
+            commit_count: pack.commit_count,
+            resume_session: None,
+            refs: vec![],
+            shallow_boundary: vec![],
+            filtered: filter.is_some() && !commits.is_empty(),
+            operation_count: 0,
+            request_id: self.request_id,
+            want_errors,
+            mode: FetchMode::CommitsOnly,
+            ref_targets: vec![],
+        };
+        self.send_fetch(response, &pack.data, Vec::new()).await
+    }
+
+    /// Resolve a fetch's `want_commits`, returning the commits to send and
+    /// the entries that can't be.
+    fn resolve_wants(
+        &self,
+        wants: &[String],
+    ) -> Result<(Vec<CommitId>, Vec<WantError>), ProtocolError> {
+        let mut commits = Vec::new();
+        let mut errors = Vec::new();
+        for want in wants {
+            let reason = if !is_commit_prefix(want) {
+                WantReason::Invalid
+            } else {
+                match self.repo.resolve_commit_prefix(want)? {
+                    PrefixResolution::Single(id)
+                        if !self.options.fetch_hidden_commits && !self.repo.is_visible(&id)? =>
+                    {
+                        WantReason::Hidden
+                    }
+                    PrefixResolution::Single(id) => {
+                        if !commits.contains(&id) {
+                            commits.push(id);
+                        }
+                        continue;
+                    }
+                    PrefixResolution::Ambiguous(_) => WantReason::Ambiguous,
+                    PrefixResolution::NoMatch => WantReason::NotFound,
+                }
+            };
+            errors.push(WantError {
+                want: want.clone(),
+                reason,
+            });
+        }
+        Ok((commits, errors))
+    }
+
+    /// Send `response`, followed by `pack` if the response says one follows,
+    /// then `operations`.
+    async fn send_fetch(
+        &mut self,
+        mut response: FetchResponse,
+        mut pack: &[u8],
+        operations: Vec<OperationRecord>,
+    ) -> Result<(), ProtocolError> {
+        response.operation_count = u32::try_from(operations.len())
+            .map_err(|_| anyhow::anyhow!("too many operations: {}", operations.len()))?;
+        let pack_follows = response.pack_follows;
+        self.send(response.into()).await?;
+        if pack_follows {
+            send_pack(
+                &mut pack,
+                &mut self.writer,
+                self.negotiated.format,
+                self.options.chunk_size,
+            )
+            .await?;
+        }
+        for record in operations {
+            self.send(record.into()).await?;
+        }
+        Ok(())
+    }
+
+    async fn push(&mut self, request: PushRequest) -> Result<(), ProtocolError> {
+        if self.grant.access < AccessLevel::Write {
+            return Err(AuthError::AccessDenied {
+                requested: AccessLevel::Write,
+            }
+            .into());
+        }
+        self.repo.reload()?;
+        if let Some(push_id) = &request.push_id {
+            check_push_id(push_id)?;
+            if let Some(result) = self.options.push_log.find(&self.repo, push_id)? {
+                return self.repeat_push(&request, result).await;
+            }
+        }
+
+        // Operations' views can refer to any of the client's commits.
+        let need_objects = request.operation_count > 0
+            || request.updates.iter().any(|update| {
+                matches!(
+                    parse_id(update.new_id.as_deref()),
+                    Ok(Some(id)) if !self.repo.has_commit(&id)
+                )
+            });
+        let have_commits_filter = if need_objects {
+            Some(self.commit_filter()?)
+        } else {
+            None
+        };
+        let negotiate = PushNegotiate {
+            common_op: None,
+            need_objects,
+            have_commits_filter,
+            want_commits: vec![],
+        };
+        self.send(negotiate.into()).await?;
+
+        let mut missing = Vec::new();
+        let mut imported = ImportStats::default();
+        if need_objects {
+            let entries = self.receive_pack().await?;
+            imported = import_objects(&self.repo, &entries, CONTENT_KINDS)?;
+            missing = missing_commits(&self.repo, &entries)?;
+        }
+        let mut operations: Vec<OperationRecord> = Vec::new();
+        for _ in 0..request.operation_count {
+            operations.push(self.next_in_request().await?.try_into()?);
+        }
+        if !missing.is_empty() {
+            // False positives of the filter: ask for those commits by ID.
+            let negotiate = PushNegotiate {
+                common_op: None,
+                need_objects: true,
+                have_commits_filter: None,
+                want_commits: missing.clone(),
+            };
+            self.send(negotiate.into()).await?;
+            let entries = self.receive_pack().await?;
+            let more = import_objects(&self.repo, &entries, CONTENT_KINDS)?;
+            imported.written += more.written;
+            imported.skipped += more.skipped;
+            if let Some(id) = missing.iter().find(|id| !self.repo.has_commit(id)) {
+                return Err(ProtocolError::UnknownCommit(*id));
+            }
+        }
+        // The client's operations are stored but only staged: the bookmarks
+        // they move are checked like updates, and they join the log with the
+        // updates or not at all.
+        let mut moves = None;
+        if !operations.is_empty() {
+            let heads = import_operations(&self.repo, &operations).await?;
+            let before = self.bookmark_targets()?;
+            self.repo.stage_op_heads(&heads)?;
+            moves = Some(bookmark_moves(before, self.bookmark_targets()?));
+        }
+
+        let applied = self.apply_updates(&request.updates, request.atomic, moves.as_deref());
+        if moves.is_some() && !matches!(&applied, Ok(result) if result.new_op_head.is_some()) {
+            self.repo.reload()?;
+        }
+        let mut result = applied?;
+        if result.status == PushStatus::Ok {
+            result.created_repo = self.created.take().is_some();
+        }
+        result.request_id = request.request_id;
+        result.new_objects = imported.written;
+        result.skipped_objects = imported.skipped;
+        if let Some(push_id) = &request.push_id {
+            self.options.push_log.record(&self.repo, push_id, &result)?;
+        }
+        self.send(result.into()).await
+    }
+
+    /// Answer a push that was already done with its recorded `result`,
+    /// taking no objects and dropping its operations.
+    async fn repeat_push(
+        &mut self,
+        request: &PushRequest,
+        mut result: PushResult,
+    ) -> Result<(), ProtocolError> {
+        let negotiate = PushNegotiate {
+            common_op: None,
+            need_objects: false,
+            have_commits_filter: None,
+            want_commits: vec![],
+        };
+        self.send(negotiate.into()).await?;
+        for _ in 0..request.operation_count {
+            OperationRecord::try_from(self.next_in_request().await?)?;
+        }
+        result.request_id = request.request_id;
+        self.send(result.into()).await
+    }
+
+    /// A filter of the repository's commits, for the client to leave out of
+    /// its pack.
+    fn commit_filter(&self) -> Result<CommitFilter, ProtocolError> {
+        let commits = self.repo.list_raw_objects(RawObjectKind::Commit)?;
+        match self.options.commit_filter_bits {
+            Some(bits) => {
+                let mut filter = CommitFilter::new(bits, DEFAULT_HASHES)?;
+                for id in &commits {
+                    filter.insert(id);
+                }
+                Ok(filter)
+            }
+            None => Ok(CommitFilter::for_commits(&commits)),
+        }
+    }
+
+    /// Apply the updates of a push: all or none if `atomic`, otherwise each
+    /// one that passes its checks.
+    ///
+    /// With `moves`, the push's operations are staged and the repository
+    /// shows them. Each bookmark they move is checked like an update from
+    /// where it was before them, the updates are checked against the staged
+    /// view, and the operations are adopted only if everything passes.
+    fn apply_updates(
+        &mut self,
+        updates: &[RefUpdate],
+        atomic: bool,
+        moves: Option<&[BookmarkMove]>,
+    ) -> Result<PushResult, ProtocolError> {
+        let max_bookmarks = self.options.push_policy.max_bookmarks(&self.repo_ref);
+        let mut bookmarks = self.repo.bookmark_names().len() as u64;
+        let staged = moves.is_some();
+        let moves = moves.unwrap_or_default();
+        // Count from before the operations, so that the bookmarks they create
+        // are held to the quota too.
+        for moved in moves {
+            match (&moved.before, &moved.after) {
+                (BookmarkTarget::Absent, _) => bookmarks -= 1,
+                (_, BookmarkTarget::Absent) => bookmarks += 1,
+                _ => {}
+            }
+        }
+        let mut moved = Vec::new();
+        let mut pending = Vec::new();
+        let mut ref_results = Vec::new();
+        for bookmark in moves {
+            let force = updates
+                .iter()
+                .any(|update| update.ref_name == bookmark.name && update.force);
+            let mut check = self.check_move(bookmark, force)?;
+            count_bookmark(&mut check, &mut bookmarks, max_bookmarks);
+            match check {
+                RefCheck::Apply { expected, target } => moved.push(BookmarkUpdate {
+                    name: bookmark.name.clone(),
+                    expected,
+                    target,
+                }),
+                RefCheck::Refuse(refusal) => ref_results.push(refusal.into_result(&bookmark.name)),
+            }
+        }
+        for update in updates {
+            let mut check = self.check_update(update)?;
+            count_bookmark(&mut check, &mut bookmarks, max_bookmarks);
+            let result = match check {
+                RefCheck::Apply { expected, target } => {
+                    pending.push(BookmarkUpdate {
+                        name: update.ref_name.clone(),
+                        expected,
+                        target,
+                    });
+                    RefResult {
+                        ref_name: update.ref_name.clone(),
+                        status: RefStatus::Ok,
+                        message: None,
+                        reason: None,
+                    }
+                }
+                RefCheck::Refuse(refusal) => refusal.into_result(&update.ref_name),
+            };
+            ref_results.push(result);
+        }
+
+        // The operations can't be taken in part.
+        let atomic = atomic || staged;
+        let failed = ref_results
+            .iter()
+            .find(|result| result.status != RefStatus::Ok)
+            .map(|result| result.ref_name.clone());
+        match failed {
+            Some(failed) if atomic => return Ok(rolled_back(ref_results, &failed)),
+            _ => {}
+        }
+
+        let description = match &self.grant.identity {
+            Some(identity) => format!("push from {identity}"),
+            None => "push".to_string(),
+        };
+        let mut new_op_head = None;
+        while !pending.is_empty() {
+            // The write checks every expectation again; a bookmark it finds
+            // stale is reported like one the checks above caught.
+            let error = match self.repo.update_bookmarks(&pending, &description) {
+                Ok(op) => {
+                    new_op_head = Some(op);
+                    break;
+                }
+                Err(error) => error,
+            };
+            let (name, refusal) = match write_failure(error) {
+                WriteFailure::Push(refusal) => return Ok(refused(ref_results, refusal)),
+                WriteFailure::Ref(name, refusal) => (name, refusal),
+                WriteFailure::Internal(error) => return Err(error),
+            };
+            for result in &mut ref_results {
+                if result.ref_name == name {
+                    *result = refusal.clone().into_result(&name);
+                }
+            }
+            if atomic {
+                return Ok(rolled_back(ref_results, &name));
+            }
+            pending.retain(|update| update.name != name);
+        }
+        if staged && new_op_head.is_none() {
+            new_op_head = Some(self.repo.publish_staged_op()?);
+        }
+        if let (Some(operation), Some(monitor)) = (&new_op_head, &self.options.monitor) {
+            moved.extend(pending);
+            monitor.bookmarks_moved(&BookmarksMoved {
+                repo: self.repo_ref.clone(),
+                identity: self.grant.identity.clone(),
+                operation: *operation,
+                updates: moved,
+            });
+        }
+
+        let status = if ref_results
+            .iter()
+            .any(|result| result.status == RefStatus::Conflict)
+        {
+            PushStatus::Conflict
+        } else if ref_results
+            .iter()
+            .any(|result| result.status != RefStatus::Ok)
+        {
+            PushStatus::Rejected
+        } else {
+            PushStatus::Ok
+        };
+        Ok(PushResult {
+            status,
+            new_op_head,
+            ref_results,
+            request_id: None,
+            new_objects: 0,
+            skipped_objects: 0,
+            created_repo: false,
+        })
+    }
+
+    /// Every bookmark target in the repository as loaded.
+    fn bookmark_targets(&self) -> Result<BTreeMap<String, BookmarkTarget>, ProtocolError> {
+        let mut targets = BTreeMap::new();
+        for name in self.repo.bookmark_names() {
+            let target = self.repo.bookmark_target(&name)?;
+            targets.insert(name, target);
+        }
+        Ok(targets)
+    }
+
+    /// Check one update against the repository.
+    fn check_update(&self, update: &RefUpdate) -> Result<RefCheck, ProtocolError> {
+        let Ok(new) = parse_id(update.new_id.as_deref()) else {
+            return Ok(RefCheck::Refuse(Refusal::new(
+                RefStatus::Rejected,
+                "invalid commit id",
+                None,
+            )));
+        };
+        if let Some(refusal) = self.check_name(&update.ref_name) {
+            return Ok(RefCheck::Refuse(refusal));
+        }
+
+        // The bookmark must be where the update expects; a conflicted one is
+        // only replaced by an update that presents the conflict exactly.
+        let old = BookmarkTarget::from(update.old_id.clone());
+        let current = self.repo.bookmark_target(&update.ref_name)?;
+        if current != old {
+            return Ok(RefCheck::Refuse(Refusal::stale(&current)));
+        }
+        self.check_target(old, new, update.force)
+    }
+
+    /// Check a bookmark moved by a push's operations, as an update from
+    /// where it was before them. Operations can't leave a bookmark
+    /// conflicted.
+    fn check_move(&self, moved: &BookmarkMove, force: bool) -> Result<RefCheck, ProtocolError> {
+        if let Some(refusal) = self.check_name(&moved.name) {
+            return Ok(RefCheck::Refuse(refusal));
+        }
+        let new = match &moved.after {
+            BookmarkTarget::Normal(id) => Some(*id),
+            BookmarkTarget::Absent => None,
+            BookmarkTarget::Conflicted { adds, .. } => {
+                return Ok(RefCheck::Refuse(Refusal::new(
+                    RefStatus::Conflict,
+                    format!(
+                        "the pushed operations leave the bookmark with {} conflicting targets",
+                        adds.len()
+                    ),
+                    None,
+                )));
+            }
+        };
+        self.check_target(moved.before.clone(), new, force)
+    }
+
+    /// Check that a bookmark may be updated at all.
+    fn check_name(&self, name: &str) -> Option<Refusal> {
+        if let Err(error) = validate_bookmark_name(name) {
+            return Some(Refusal::invalid_name(&error));
+        }
+        if self
+            .options
+            .push_policy
+            .protects_bookmark(&self.repo_ref, name)
+        {
+            return Some(Refusal::new(
+                RefStatus::Rejected,
+                "bookmark is protected",
+                Some(RefReason::Protected),
+            ));
+        }
+        None
+    }
+
+    /// Check moving a bookmark from `old` to `new`, `None` to delete it.
+    fn check_target(
+        &self,
+        old: BookmarkTarget,
+        new: Option<CommitId>,
+        force: bool,
+    ) -> Result<RefCheck, ProtocolError> {
+        match new {
+            Some(id) if !self.repo.has_commit(&id) => {
+                return Ok(RefCheck::Refuse(Refusal::new(
+                    RefStatus::Rejected,
+                    format!("commit {id} not found"),
+                    None,
+                )));
+            }
+            _ => {}
+        }
+
+        // Moving a bookmark must keep what it pointed at, unless forced.
+        // Resolving a conflict picks a side, and may drop the others.
+        if let (BookmarkTarget::Normal(old), Some(new)) = (&old, new) {
+            let (_, behind) = self.repo.ahead_behind(&new, old)?;
+            if behind > 0 {
+                if !force {
+                    return Ok(RefCheck::Refuse(Refusal::new(
+                        RefStatus::Stale,
+                        format!(
+                            "not a fast-forward: {new} is missing {behind} commits of {old}; \
+                             force the update to move the bookmark anyway"
+                        ),
+                        Some(RefReason::NotFastForward { behind }),
+                    )));
+                }
+                if self.options.push_policy.deny_force_push(&self.repo_ref) {
+                    return Ok(RefCheck::Refuse(Refusal::new(
+                        RefStatus::Rejected,
+                        "force pushes are denied for this repository",
+                        Some(RefReason::Protected),
+                    )));
+                }
+            }
+        }
+
+        Ok(RefCheck::Apply {
+            expected: old,
+            target: new,
+        })
+    }
+}
+
+/// A bookmark that a push's operations move.
+struct BookmarkMove {
+    name: String,
+    /// Where the bookmark is without the operations
+    before: BookmarkTarget,
+    /// Where the bookmark is with them
+    after: BookmarkTarget,
+}
+
+/// The bookmarks whose targets differ between `before` and `after`.
+fn bookmark_moves(
+    mut before: BTreeMap<String, BookmarkTarget>,
+    after: BTreeMap<String, BookmarkTarget>,
+) -> Vec<BookmarkMove> {
+    let mut moves = Vec::new();
+    for (name, after) in after {
+        let before = before.remove(&name).unwrap_or(BookmarkTarget::Absent);
+        if before != after {
+            moves.push(BookmarkMove {
+                name,
+                before,
+                after,
+            });
+        }
+    }
+    moves.extend(before.into_iter().map(|(name, before)| BookmarkMove {
+        name,
+        before,
+        after: BookmarkTarget::Absent,
+    }));
+    moves
+}
+
+/// Hold a check that would create a bookmark to `limit`, counting it in
+/// `bookmarks` if it passes, and count the deletions.
+fn count_bookmark(check: &mut RefCheck, bookmarks: &mut u64, limit: Option<u64>) {
+    let RefCheck::Apply { expected, target } = &*check else {
+        return;
+    };
+    let exists = *expected != BookmarkTarget::Absent;
+    match (exists, target.is_some()) {
+        (false, true) => match limit {
+            Some(limit) if *bookmarks >= limit => {
+                *check = RefCheck::Refuse(Refusal::new(
+                    RefStatus::Rejected,
+                    format!("repository allows at most {limit} bookmarks"),
+                    Some(RefReason::QuotaExceeded {
+                        limit,
+                        attempted: *bookmarks + 1,
+                    }),
+                ));
+            }
+            _ => *bookmarks += 1,
+        },
+        (true, false) => *bookmarks -= 1,
+        _ => {}
+    }
+}
+
+/// Outcome of checking a [`RefUpdate`].
+enum RefCheck {
+    /// Move the bookmark from `expected` to `target`, `None` to delete it
+    Apply {
+        expected: BookmarkTarget,
+        target: Option<CommitId>,
+    },
+    /// Leave the bookmark alone
+    Refuse(Refusal),
+}
+
+/// Why an update was refused, in the shape of a [`RefResult`].
+#[derive(Debug, Clone)]
+struct Refusal {
+    status: RefStatus,
+    message: String,
+    reason: Option<RefReason>,
+}
+
+impl Refusal {
+    fn new(status: RefStatus, message: impl Into<String>, reason: Option<RefReason>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            reason,
+        }
+    }
+
+    /// The bookmark is at `actual`, not where the update expected.
+    fn stale(actual: &BookmarkTarget) -> Self {
+        let (status, message) = match actual {
+            BookmarkTarget::Normal(id) => (RefStatus::Stale, format!("bookmark is at {id}")),
+            BookmarkTarget::Absent => (RefStatus::Stale, "bookmark does not exist".to_string()),
+            BookmarkTarget::Conflicted { adds, .. } => (
+                RefStatus::Conflict,
+                format!("bookmark has {} conflicting targets", adds.len()),
+            ),
+        };
+        let reason = RefReason::Stale {
+            actual: actual.clone().into(),
+        };
+        Self::new(status, message, Some(reason))
+    }
+
+    fn invalid_name(error: &BookmarkNameError) -> Self {
+        Self::new(
+            RefStatus::Rejected,
+            format!("invalid bookmark name: {error}"),
+            Some(RefReason::InvalidName {
+                why: error.to_string(),
+            }),
+        )
+    }
+
+    fn into_result(self, ref_name: &str) -> RefResult {
+        RefResult {
+            ref_name: ref_name.to_string(),
+            status: self.status,
+            message: Some(self.message),
+            reason: self.reason,
+        }
+    }
+}
+
+/// What a failed write means for the push.
+#[derive(Debug)]
+enum WriteFailure {
+    /// Refuse the whole push
+    Push(Refusal),
+    /// Refuse the update to one bookmark
+    Ref(String, Refusal),
+    /// End the session with an Error frame
+    Internal(ProtocolError),
+}
+
+/// Translate a storage error from writing a push's updates into its
+/// response.
+///
+/// Every [`StorageError`] is matched, so a new one needs a decision here.
+/// Anything else is internal, and its Error frame doesn't say more than
+/// that; see [`ProtocolError::to_error_message`].
+fn write_failure(error: anyhow::Error) -> WriteFailure {
+    let Some(refused) = StorageError::of(&error) else {
+        return WriteFailure::Internal(error.into());
+    };
+    match refused {
+        StorageError::QuotaExceeded { limit, attempted } => WriteFailure::Push(Refusal::new(
+            RefStatus::Rejected,
+            format!("repository limit of {limit} exceeded"),
+            Some(RefReason::QuotaExceeded { limit, attempted }),
+        )),
+        StorageError::Archived => WriteFailure::Push(Refusal::new(
+            RefStatus::Rejected,
+            "repository is archived",
+            Some(RefReason::Archived),
+        )),
+        StorageError::Protected { name } => WriteFailure::Ref(
+            name,
+            Refusal::new(
+                RefStatus::Rejected,
+                "bookmark is protected",
+                Some(RefReason::Protected),
+            ),
+        ),
+        StorageError::Stale(stale) => WriteFailure::Ref(stale.name, Refusal::stale(&stale.actual)),
+        StorageError::InvalidBookmarkName(invalid) => {
+            WriteFailure::Ref(invalid.name, Refusal::invalid_name(&invalid.error))
+        }
+        // The session already has the repository open, so these can't
+        // come from its updates.
+        StorageError::NotFound { .. }
+        | StorageError::AlreadyExists { .. }
+        | StorageError::InvalidName { .. } => WriteFailure::Internal(error.into()),
+    }
+}
+
+/// Build the result of a push the repository refused as a whole: each update
+/// not already refused for its own reason is refused for `refusal`.
+fn refused(ref_results: Vec<RefResult>, refusal: Refusal) -> PushResult {
+    let ref_results = ref_results
+        .into_iter()
+        .map(|result| match result.status {
+            RefStatus::Ok => refusal.clone().into_result(&result.ref_name),
+            _ => result,
+        })
+        .collect();
+    PushResult {
+        status: PushStatus::Rejected,
+        new_op_head: None,
+        ref_results,
+        request_id: None,
+        new_objects: 0,
+        skipped_objects: 0,
+        created_repo: false,
+    }
+}
+
+/// Build the result of an atomic push that `failed` stopped: its own result
+/// stands, and every other update is rejected.
+fn rolled_back(mut ref_results: Vec<RefResult>, failed: &str) -> PushResult {
+    let status = if ref_results
+        .iter()
+        .any(|result| result.status == RefStatus::Conflict)
+    {
+        PushStatus::Conflict
+    } else {
+        PushStatus::Rejected
+    };
+    for result in &mut ref_results {
+        if result.ref_name != failed && result.status == RefStatus::Ok {
+            result.status = RefStatus::Rejected;
+            result.message = Some(format!("not applied because the update to {failed} failed"));
+        }
+    }
+    PushResult {
+        status,
+        new_op_head: None,
+        ref_results,
+        request_id: None,
+        new_objects: 0,
+        skipped_objects: 0,
+        created_repo: false,
+    }
+}
+
+/// Hold on to a request pipelined during an earlier one.
+fn queue_request(queued: &mut VecDeque<Message>, message: Message) -> Result<(), ProtocolError> {
+    if queued.len() >= MAX_QUEUED_REQUESTS {
+        return Err(ProtocolError::TooManyRequests(MAX_QUEUED_REQUESTS));
+    }
+    queued.push_back(message);
+    Ok(())
+}
+
+/// Wait for the next tick of `ticker`, or forever without one.
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Pack `commits` with their trees, and their files as `filter` allows.
+async fn pack_commits(
+    repo: &Repository,
+    commits: &[CommitId],
+    filter: Option<&PathFilter>,
+) -> Result<ExportedPack, ProtocolError> {
+    let mut files = Vec::new();
+    let mut others = Vec::new();
+    let mut seen = HashSet::new();
+    for commit in commits {
+        let tree = repo.commit_objects(commit).await?;
+        tokio::task::yield_now().await;
+        files.extend(tree.files);
+        let tree_objects = tree
+            .symlinks
+            .into_iter()
+            .map(|(_, id)| (RawObjectKind::Symlink, id))
+            .chain(tree.trees.into_iter().map(|id| (RawObjectKind::Tree, id)))
+            .chain([(RawObjectKind::Commit, *commit)]);
+        for object in tree_objects {
+            if seen.insert(object) {
+                others.push(object);
+            }
+        }
+    }
+    let (mut objects, promised) = partition_files(&files, filter);
+    objects.extend(others);
+    export_partial(repo, &objects, &promised).await
+}
+
+/// Pack what a fetch's operations bring: `commits` and the `extra` wanted
+/// ones, every tree, and files as `filter` allows. Commits in the client's
+/// `have` filter are only promised.
+async fn pack_plan(
+    repo: &Repository,
+    commits: &[CommitId],
+    extra: &[CommitId],
+    filter: Option<&PathFilter>,
+    have: Option<&CommitFilter>,
+) -> Result<ExportedPack, ProtocolError> {
+    // Walking every new commit's tree costs more than sending what the
+    // client may already have, so all trees and files go along. Only a
+    // path filter needs the walk, to learn where each file lives.
+    let (mut objects, mut promised) = match filter {
+        Some(filter) => {
+            let mut files = Vec::new();
+            for commit in commits.iter().chain(extra) {
+                files.extend(repo.commit_objects(commit).await?.files);
+                tokio::task::yield_now().await;
+            }
+            partition_files(&files, Some(filter))
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+    for &kind in CONTENT_KINDS {
+        let skip =
+            kind == RawObjectKind::Commit || (kind == RawObjectKind::File && filter.is_some());
+        if !skip {
+            for id in repo.list_raw_objects(kind)? {
+                objects.push((kind, id));
+            }
+        }
+    }
+    // The client finds out which promised commits it lacks after all
+    // and asks for them with want_commits.
+    for id in commits {
+        let commit = (RawObjectKind::Commit, *id);
+        if have.is_some_and(|have| have.contains(id)) {
+            promised.push(commit);
+        } else {
+            objects.push(commit);
+        }
+    }
+    objects.extend(extra.iter().map(|id| (RawObjectKind::Commit, *id)));
+    export_partial(repo, &objects, &promised).await
+}
+
+/// Split `files` into the file objects to send and those to promise. Without
+/// a `filter`, every file is sent.
+fn partition_files(
+    files: &[(String, FileId)],
+    filter: Option<&PathFilter>,
+) -> (
+    Vec<(RawObjectKind, ObjectId)>,
+    Vec<(RawObjectKind, ObjectId)>,
+) {
+    let (sent, promised) = match filter {
+        Some(filter) => filter.partition(files),
+        None => {
+            let mut seen = HashSet::new();
+            let sent = files
+                .iter()
+                .map(|(_, id)| *id)
+                .filter(|id| seen.insert(*id))
+                .collect();
+            (sent, Vec::new())
+        }
+    };
+    let as_files = |ids: Vec<FileId>| {
+        ids.into_iter()
+            .map(|id| (RawObjectKind::File, id))
+            .collect::<Vec<_>>()
+    };
+    (as_files(sent), as_files(promised))
+}
+
+/// Whether `want` could name a commit: hex, and no longer than an ID.
+fn is_commit_prefix(want: &str) -> bool {
+    !want.is_empty() && want.len() <= 2 * MAX_ID_LEN && want.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Parse an optional hex commit ID from a [`RefUpdate`].
+fn parse_id(id: Option<&str>) -> Result<Option<CommitId>, ObjectIdError> {
+    id.map(ObjectId::from_hex).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{ErrorCode, RefTargetWire};
+    use std::path::Path;
+
+    fn result(ref_name: &str, status: RefStatus) -> RefResult {
+        RefResult {
+            ref_name: ref_name.to_string(),
+            status,
+            message: None,
+            reason: None,
+        }
+    }
+
+    /// The refusal of the whole push `error` makes.
+    fn push_refusal(error: impl Into<anyhow::Error>) -> Refusal {
+        match write_failure(error.into()) {
+            WriteFailure::Push(refusal) => refusal,
+            failure => panic!("expected the push to be refused, got {failure:?}"),
+        }
+    }
+
+    /// The bookmark and refusal of one update `error` makes.
+    fn ref_refusal(error: impl Into<anyhow::Error>) -> (String, Refusal) {
+        match write_failure(error.into()) {
+            WriteFailure::Ref(name, refusal) => (name, refusal),
+            failure => panic!("expected one update to be refused, got {failure:?}"),
+        }
+    }
+
+    #[test]
+    fn test_refusals_of_whole_pushes() {
+        let quota = StorageError::QuotaExceeded {
+            limit: 10,
+            attempted: 11,
+        };
+        let refusal = push_refusal(quota);
+        assert_eq!(refusal.status, RefStatus::Rejected);
+        assert_eq!(
+            refusal.reason,
+            Some(RefReason::QuotaExceeded {
+                limit: 10,
+                attempted: 11
+            })
+        );
+
+        let refusal = push_refusal(StorageError::Archived);
+        assert_eq!(refusal.reason, Some(RefReason::Archived));
+
+        // Every update is refused, keeping its own reason if it had one.
+        let stale = Refusal::stale(&BookmarkTarget::Absent).into_result("feature");
+        let results = vec![result("main", RefStatus::Ok), stale.clone()];
+        let pushed = refused(results, refusal);
+        assert_eq!(pushed.status, PushStatus::Rejected);
+        assert_eq!(pushed.new_op_head, None);
+        assert_eq!(pushed.ref_results[0].status, RefStatus::Rejected);
+        assert_eq!(pushed.ref_results[0].reason, Some(RefReason::Archived));
+        assert_eq!(pushed.ref_results[1], stale);
+    }
+
+    #[test]
+    fn test_refusals_of_one_update() {
+        let (name, refusal) = ref_refusal(StorageError::Protected {
+            name: "main".to_string(),
+        });
+        assert_eq!(name, "main");
+        assert_eq!(refusal.status, RefStatus::Rejected);
+        assert_eq!(refusal.reason, Some(RefReason::Protected));
+
+        let head = CommitId::hash(b"head");
+        let stale = forjj_storage::StaleBookmark {
+            name: "main".to_string(),
+            actual: BookmarkTarget::Normal(head),
+        };
+        // Bare, as `update_bookmarks` returns them.
+        let (name, refusal) = ref_refusal(stale);
+        assert_eq!(name, "main");
+        assert_eq!(refusal.status, RefStatus::Stale);
+        assert_eq!(
+            refusal.reason,
+            Some(RefReason::Stale {
+                actual: RefTargetWire::Normal(head)
+            })
+        );
+
+        let invalid = forjj_storage::InvalidBookmarkName {
+            name: String::new(),
+            error: BookmarkNameError::Empty,
+        };
+        let (name, refusal) = ref_refusal(StorageError::from(invalid));
+        assert_eq!(name, "");
+        assert_eq!(refusal.status, RefStatus::Rejected);
+        assert!(matches!(
+            refusal.reason,
+            Some(RefReason::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_internal_errors_hide_paths() {
+        let repos_root = Path::new("/srv/forjj/repos");
+        let path = repos_root.join("alice/project/store/commits/abc");
+        let error = anyhow::Error::from(std::io::Error::other("disk full"))
+            .context(format!("failed to write {}", path.display()));
+        let WriteFailure::Internal(error) = write_failure(error) else {
+            panic!("expected an internal error");
+        };
+        // The server's log has the path; the client doesn't.
+        assert!(error.to_string().contains("/srv/forjj/repos"));
+        let message = error.to_error_message();
+        assert_eq!(message.code, ErrorCode::Internal);
+        assert!(!message.retryable);
+        assert!(
+            !message.message.contains(&*repos_root.to_string_lossy()),
+            "{}",
+            message.message
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Shallow fetches.
+//!
+//! A fetch with a depth gets at most that many generations of commits from
+//! each wanted ref tip, with their trees and files but nothing older. The
+//! commits whose parents were cut off are reported as the shallow boundary;
+//! a later fetch sends them back as commits it has, with a greater depth, to
+//! deepen the history.
+//!
+//! A shallow client can't adopt the server's operations, whose views refer to
+//! commits it lacks, so a shallow fetch sends commit objects only.
+
+use std::collections::HashSet;
+
+use forjj_storage::CommitId;
+
+/// Commits selected for a shallow fetch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShallowSelection {
+    /// Commits to send, tips first
+    pub commits: Vec<CommitId>,
+    /// Sent commits with parents that are neither sent nor in the client's
+    /// have set
+    pub boundary: Vec<CommitId>,
+}
+
+/// Select up to `depth` generations of commits from `tips`, walking the
+/// commit graph with `parents`.
+///
+/// Commits in `have` aren't selected, but the walk goes through them, so a
+/// client can deepen its history by listing its boundary. A depth of zero
+/// selects nothing.
+pub fn select_shallow<E>(
+    tips: &[CommitId],
+    depth: u32,
+    have: &HashSet<CommitId>,
+    mut parents: impl FnMut(&CommitId) -> Result<Vec<CommitId>, E>,
+) -> Result<ShallowSelection, E> {
+    let mut visited = HashSet::new();
+    let mut selected = Vec::new();
+    let mut generation: Vec<CommitId> = tips.to_vec();
+    for _ in 0..depth {
+        if generation.is_empty() {
+            break;
+        }
+        let mut next = Vec::new();
+        for commit in generation {
+            if !visited.insert(commit) {
+                continue;
+            }
+            let commit_parents = parents(&commit)?;
+            if !have.contains(&commit) {
+                selected.push((commit, commit_parents.clone()));
+            }
+            next.extend(commit_parents);
+        }
+        generation = next;
+    }
+
+    let mut selection = ShallowSelection::default();
+    for (commit, commit_parents) in selected {
+        if commit_parents
+            .iter()
+            .any(|parent| !visited.contains(parent) && !have.contains(parent))
+        {
+            selection.boundary.push(commit);
+        }
+        selection.commits.push(commit);
+    }
+    Ok(selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    fn commit(i: usize) -> CommitId {
+        CommitId::hash(format!("commit {i}").as_bytes())
+    }
+
+    /// 0 <- 1 <- 2 <- 3 <- 4, with 0 standing in for the root commit.
+    fn chain() -> HashMap<CommitId, Vec<CommitId>> {
+        (0..5)
+            .map(|i| {
+                let parents = if i == 0 { vec![] } else { vec![commit(i - 1)] };
+                (commit(i), parents)
+            })
+            .collect()
+    }
+
+    fn select(
+        graph: &HashMap<CommitId, Vec<CommitId>>,
+        tips: &[CommitId],
+        depth: u32,
+        have: &[CommitId],
+    ) -> ShallowSelection {
+        let have = have.iter().copied().collect();
+        select_shallow(tips, depth, &have, |id| {
+            Ok::<_, Infallible>(graph[id].clone())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_depth_limits_generations() {
+        let graph = chain();
+        let root = [commit(0)];
+
+        let selection = select(&graph, &[commit(4)], 1, &root);
+        assert_eq!(selection.commits, vec![commit(4)]);
+        assert_eq!(selection.boundary, vec![commit(4)]);
+
+        let selection = select(&graph, &[commit(4)], 3, &root);
+        assert_eq!(selection.commits, vec![commit(4), commit(3), commit(2)]);
+        assert_eq!(selection.boundary, vec![commit(2)]);
+
+        // Deep enough to reach the root, which every repository has.
+        let selection = select(&graph, &[commit(4)], 10, &root);
+        assert_eq!(selection.commits.len(), 4);
+        assert!(selection.boundary.is_empty());
+
+        assert_eq!(
+            select(&graph, &[commit(4)], 0, &root),
+            ShallowSelection::default()
+        );
+    }
+
+    #[test]
+    fn test_deepen_from_boundary() {
+        let graph = chain();
+
+        // The client has 4 from a depth-1 fetch and asks for two more
+        // generations.
+        let selection = select(&graph, &[commit(4)], 3, &[commit(0), commit(4)]);
+        assert_eq!(selection.commits, vec![commit(3), commit(2)]);
+        assert_eq!(selection.boundary, vec![commit(2)]);
+    }
+
+    #[test]
+    fn test_parents_the_client_has_are_not_a_boundary() {
+        let graph = chain();
+
+        let selection = select(&graph, &[commit(4)], 1, &[commit(0), commit(3)]);
+        assert_eq!(selection.commits, vec![commit(4)]);
+        assert!(selection.boundary.is_empty());
+    }
+
+    #[test]
+    fn test_merge_and_shared_history() {
+        // 0 <- 1 <- 3, 0 <- 2 <- 3, and a second tip 4 on 1.
+        let graph: HashMap<_, _> = [
+            (commit(0), vec![]),
+            (commit(1), vec![commit(0)]),
+            (commit(2), vec![commit(0)]),
+            (commit(3), vec![commit(1), commit(2)]),
+            (commit(4), vec![commit(1)]),
+        ]
+        .into_iter()
+        .collect();
+
+        let selection = select(&graph, &[commit(3), commit(4)], 2, &[commit(0)]);
+        assert_eq!(
+            selection.commits,
+            vec![commit(3), commit(4), commit(1), commit(2)]
+        );
+        assert!(selection.boundary.is_empty());
+
+        let selection = select(&graph, &[commit(3), commit(4)], 1, &[commit(0)]);
+        assert_eq!(selection.commits, vec![commit(3), commit(4)]);
+        assert_eq!(selection.boundary, vec![commit(3), commit(4)]);
+    }
+}
@@ -0,0 +1,388 @@
+//! Demultiplexing the sidebands interleaved with a pack.
+//!
+//! While a pack is in flight, three kinds of messages share the stream, each
+//! on its own channel:
+//!
+//! | Channel | Message             | Consumer                         |
+//! |---------|---------------------|----------------------------------|
+//! | 1       | [`PackChunk`]       | [`PackStream`], an [`AsyncRead`] |
+//! | 2       | [`ProgressMessage`] | a progress receiver              |
+//! | 3       | [`ErrorMessage`]    | an error receiver                |
+//!
+//! The channel is implied by the message kind, so nothing changes on the
+//! wire. A [`Demultiplexer`] reads the stream and routes each message to its
+//! consumer, which run independently of each other. Pack data is
+//! back-pressured: the driver waits for the pack consumer to catch up. Progress
+//! is not: updates that don't fit in the progress queue are dropped, so a
+//! slow progress consumer never stalls the pack.
+//!
+//! An Error frame ends the exchange. The driver hands it to the error
+//! receiver and stops, so the pack ends early; the driver's result says why.
+//!
+//! [`PackChunk`]: crate::messages::PackChunk
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::envelope::{Message, WireFormat, read_message};
+use crate::error::ProtocolError;
+use crate::messages::{ErrorMessage, ProgressMessage};
+use crate::progress::ProgressSink;
+use crate::transfer::ChunkSequence;
+
+/// Pack chunks buffered ahead of the pack consumer.
+pub const PACK_QUEUE: usize = 16;
+
+/// Progress updates buffered ahead of the progress consumer. Updates beyond
+/// this are dropped.
+pub const PROGRESS_QUEUE: usize = 64;
+
+/// The sideband a message travels on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Pack data
+    Pack,
+    /// Progress updates
+    Progress,
+    /// Errors from the peer
+    Error,
+}
+
+impl Channel {
+    /// The channel of `message`, if it is a sideband message.
+    pub fn of(message: &Message) -> Option<Self> {
+        match message {
+            Message::PackChunk(_) => Some(Channel::Pack),
+            Message::Progress(_) => Some(Channel::Progress),
+            Message::Error(_) => Some(Channel::Error),
+            _ => None,
+        }
+    }
+
+    /// The channel's number.
+    pub fn number(self) -> u8 {
+        match self {
+            Channel::Pack => 1,
+            Channel::Progress => 2,
+            Channel::Error => 3,
+        }
+    }
+}
+
+/// The consuming ends of the sidebands of one pack.
+#[derive(Debug)]
+pub struct Sideband {
+    /// The pack's bytes
+    pub pack: PackStream,
+    /// Progress updates, some possibly dropped
+    pub progress: mpsc::Receiver<ProgressMessage>,
+    /// The peer's error, if it sends one
+    pub errors: mpsc::UnboundedReceiver<ErrorMessage>,
+}
+
+/// Set up demultiplexing of the pack read from `reader`.
+///
+/// Run the returned [`Demultiplexer`] alongside the consumers of the
+/// [`Sideband`], e.g. with `tokio::join!`.
+pub fn demultiplex<R>(reader: R, format: WireFormat) -> (Demultiplexer<R>, Sideband) {
+    let (pack_tx, pack_rx) = mpsc::channel(PACK_QUEUE);
+    let (progress_tx, progress_rx) = mpsc::channel(PROGRESS_QUEUE);
+    let (errors_tx, errors_rx) = mpsc::unbounded_channel();
+    let driver = Demultiplexer {
+        reader,
+        format,
+        pack: pack_tx,
+        progress: progress_tx,
+        errors: errors_tx,
+        sequence: ChunkSequence::default(),
+        dropped_progress: 0,
+    };
+    let sideband = Sideband {
+        pack: PackStream {
+            chunks: pack_rx,
+            chunk: Vec::new(),
+            position: 0,
+        },
+        progress: progress_rx,
+        errors: errors_rx,
+    };
+    (driver, sideband)
+}
+
+/// Reads a pack's messages and routes them to the sidebands.
+#[derive(Debug)]
+pub struct Demultiplexer<R> {
+    reader: R,
+    format: WireFormat,
+    pack: mpsc::Sender<Vec<u8>>,
+    progress: mpsc::Sender<ProgressMessage>,
+    errors: mpsc::UnboundedSender<ErrorMessage>,
+    sequence: ChunkSequence,
+    dropped_progress: u64,
+}
+
+impl<R: AsyncRead + Unpin> Demultiplexer<R> {
+    /// Route messages until the pack's last chunk, then return the reader
+    /// and the number of progress updates dropped.
+    ///
+    /// Fails with [`ProtocolError::Remote`] if the peer sends an Error frame,
+    /// and with [`ProtocolError::UnexpectedMessage`] for a message that
+    /// isn't on any sideband.
+    pub async fn run(mut self) -> Result<(R, u64), ProtocolError> {
+        while !self.sequence.is_finished() {
+            let message = read_message(&mut self.reader, self.format).await?;
+            match message {
+                Message::PackChunk(chunk) => {
+                    self.sequence.accept(&chunk)?;
+                    // A dropped pack consumer doesn't need the rest.
+                    let _ = self.pack.send(chunk.data).await;
+                }
+                Message::Progress(update) => {
+                    if self.progress.try_send(update).is_err() {
+                        self.dropped_progress += 1;
+                    }
+                }
+                Message::Error(error) => {
+                    let _ = self.errors.send(error.clone());
+                    return Err(ProtocolError::Remote(error));
+                }
+                other => {
+                    return Err(ProtocolError::UnexpectedMessage {
+                        expected: "PackChunk",
+                        actual: other.name(),
+                    });
+                }
+            }
+        }
+        Ok((self.reader, self.dropped_progress))
+    }
+}
+
+/// Pass updates from `progress` to `sink` until the sender is gone.
+pub async fn forward_progress(
+    mut progress: mpsc::Receiver<ProgressMessage>,
+    sink: &mut (dyn ProgressSink + Send),
+) {
+    while let Some(update) = progress.recv().await {
+        sink.progress(&update);
+    }
+}
+
+/// The pack bytes of a [`Sideband`].
+///
+/// Ends after the last chunk, or early if the [`Demultiplexer`] stops
+/// before it; a [`PackReader`](crate::pack::PackReader) reports the latter
+/// as a truncated pack.
+#[derive(Debug)]
+pub struct PackStream {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl AsyncRead for PackStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.position);
+                buf.put_slice(&this.chunk[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.chunks.poll_recv(cx)) {
+                Some(chunk) => {
+                    this.chunk = chunk;
+                    this.position = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use forjj_storage::ObjectId;
+
+    use super::*;
+    use crate::envelope::write_message;
+    use crate::messages::{ErrorCode, Ping, ProgressPhase};
+    use crate::pack::{ObjectKind, PackEntry, PackError, PackReader, PackWriter};
+    use crate::transfer::send_pack;
+
+    const FORMAT: WireFormat = WireFormat::Binary;
+
+    fn entries() -> Vec<PackEntry> {
+        (0..8u8)
+            .map(|i| {
+                let data = vec![i; 3000];
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    async fn pack_bytes(entries: &[PackEntry]) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+            .await
+            .unwrap();
+        for entry in entries {
+            pack.add_object(entry.kind, &entry.id, &entry.data)
+                .await
+                .unwrap();
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    fn progress(current: u64) -> Message {
+        ProgressMessage {
+            phase: ProgressPhase::Sending,
+            current,
+            total: None,
+            bytes: None,
+        }
+        .into()
+    }
+
+    /// Split `pack` into chunk messages and put `progress_per_chunk` updates
+    /// before each, then `tail` after the chunks.
+    async fn interleaved(pack: &[u8], progress_per_chunk: u64, tail: &[Message]) -> Vec<u8> {
+        let mut chunks = Vec::new();
+        send_pack(&mut &pack[..], &mut chunks, FORMAT, 1024)
+            .await
+            .unwrap();
+        let mut reader = Cursor::new(chunks);
+        let mut stream = Vec::new();
+        let mut current = 0;
+        while let Ok(message) = read_message(&mut reader, FORMAT).await {
+            for _ in 0..progress_per_chunk {
+                write_message(&mut stream, &progress(current), FORMAT)
+                    .await
+                    .unwrap();
+                current += 1;
+            }
+            write_message(&mut stream, &message, FORMAT).await.unwrap();
+        }
+        for message in tail {
+            write_message(&mut stream, message, FORMAT).await.unwrap();
+        }
+        stream
+    }
+
+    #[test]
+    fn test_channels() {
+        assert_eq!(Channel::of(&progress(0)), Some(Channel::Progress));
+        let error = ErrorMessage::new(ErrorCode::Internal, "boom").into();
+        assert_eq!(Channel::of(&error), Some(Channel::Error));
+        assert_eq!(Channel::of(&Message::Ping(Ping { payload: [0; 8] })), None);
+        assert_eq!(
+            [Channel::Pack, Channel::Progress, Channel::Error].map(Channel::number),
+            [1, 2, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pack_reassembles_while_progress_arrives() {
+        let entries = entries();
+        let stream = interleaved(&pack_bytes(&entries).await, 2, &[]).await;
+        let (driver, sideband) = demultiplex(Cursor::new(stream), FORMAT);
+
+        let mut updates = Vec::new();
+        let mut sink = |update: &ProgressMessage| updates.push(update.current);
+        let read = async { PackReader::new(sideband.pack).await?.read_all().await };
+        let (driven, read, ()) = tokio::join!(
+            driver.run(),
+            read,
+            forward_progress(sideband.progress, &mut sink)
+        );
+        let (_, dropped) = driven.unwrap();
+
+        assert_eq!(read.unwrap(), entries);
+        assert_eq!(dropped, 0);
+        assert_eq!(updates, (0..updates.len() as u64).collect::<Vec<_>>());
+        assert!(!updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_progress_consumer_does_not_stall_pack() {
+        let entries = entries();
+        let stream = interleaved(&pack_bytes(&entries).await, 40, &[]).await;
+        let (driver, sideband) = demultiplex(Cursor::new(stream), FORMAT);
+
+        // Nobody reads progress until the pack is done.
+        let read = async { PackReader::new(sideband.pack).await?.read_all().await };
+        let (driven, read) = tokio::join!(driver.run(), read);
+        let (_, dropped) = driven.unwrap();
+        assert_eq!(read.unwrap(), entries);
+        assert!(dropped > 0);
+
+        let mut progress = sideband.progress;
+        let mut received = 0;
+        while progress.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, PROGRESS_QUEUE as u64);
+    }
+
+    #[tokio::test]
+    async fn test_error_ends_pack() {
+        let pack = pack_bytes(&entries()).await;
+        let error = ErrorMessage::new(ErrorCode::QuotaExceeded, "too big");
+        // Cut the stream after a few chunks and end it with an error.
+        let full = interleaved(&pack, 1, &[]).await;
+        let mut reader = Cursor::new(full);
+        let mut stream = Vec::new();
+        for _ in 0..6 {
+            let message = read_message(&mut reader, FORMAT).await.unwrap();
+            write_message(&mut stream, &message, FORMAT).await.unwrap();
+        }
+        write_message(&mut stream, &error.clone().into(), FORMAT)
+            .await
+            .unwrap();
+        let (driver, mut sideband) = demultiplex(Cursor::new(stream), FORMAT);
+
+        let read = async { PackReader::new(sideband.pack).await?.read_all().await };
+        let (driven, read) = tokio::join!(driver.run(), read);
+        assert!(matches!(driven, Err(ProtocolError::Remote(ref remote)) if *remote == error));
+        assert!(matches!(read, Err(PackError::Truncated)));
+        assert_eq!(sideband.errors.recv().await, Some(error));
+        assert_eq!(
+            sideband.progress.recv().await.map(|update| update.current),
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_messages_are_rejected() {
+        let mut stream = Vec::new();
+        write_message(&mut stream, &progress(0), FORMAT)
+            .await
+            .unwrap();
+        write_message(
+            &mut stream,
+            &Message::Ping(Ping { payload: [0; 8] }),
+            FORMAT,
+        )
+        .await
+        .unwrap();
+        let (driver, _sideband) = demultiplex(Cursor::new(stream), FORMAT);
+        assert!(matches!(
+            driver.run().await,
+            Err(ProtocolError::UnexpectedMessage { .. })
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! Path filters for sparse fetches.
+//!
+//! A sparse fetch names directory prefixes; the server still sends every
+//! commit and tree, but only the files under one of the prefixes. The rest
+//! go into the pack as promised entries (see [`crate::pack`]) so a later
+//! fetch can fill them in. A prefix matches whole path components: `src`
+//! covers `src/main.rs` and a file named `src`, but not `srcs/lib.rs`.
+
+use std::collections::HashSet;
+
+use forjj_storage::FileId;
+
+/// Maximum length of a path filter, in bytes.
+pub const MAX_FILTER_LEN: usize = 1024;
+
+/// Reasons a path filter is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PathFilterError {
+    #[error("path filter is empty")]
+    Empty,
+
+    #[error("path filter is longer than {MAX_FILTER_LEN} bytes")]
+    TooLong,
+
+    #[error("path filter contains invalid character {0:?}")]
+    InvalidChar(char),
+
+    #[error("path filter {0:?} is not a relative path")]
+    NotRelative(String),
+}
+
+/// A set of path prefixes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathFilter {
+    prefixes: Vec<String>,
+}
+
+impl PathFilter {
+    /// Parse and validate `filters`. A trailing `/` is allowed and ignored.
+    pub fn parse(filters: &[String]) -> Result<Self, PathFilterError> {
+        let mut prefixes = Vec::with_capacity(filters.len());
+        for filter in filters {
+            if filter.len() > MAX_FILTER_LEN {
+                return Err(PathFilterError::TooLong);
+            }
+            let prefix = filter.strip_suffix('/').unwrap_or(filter);
+            if prefix.is_empty() {
+                return Err(PathFilterError::Empty);
+            }
+            if let Some(c) = prefix.chars().find(|&c| c.is_control() || c == '\\') {
+                return Err(PathFilterError::InvalidChar(c));
+            }
+            if prefix
+                .split('/')
+                .any(|component| component.is_empty() || component == "." || component == "..")
+            {
+                return Err(PathFilterError::NotRelative(filter.clone()));
+            }
+            prefixes.push(prefix.to_string());
+        }
+        Ok(Self { prefixes })
+    }
+
+    /// The prefixes, without trailing slashes.
+    pub fn prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    /// Whether `path` is under one of the prefixes.
+    pub fn matches(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    /// Split `files` into the ids to send and the ids to promise.
+    ///
+    /// A file is sent if any of its paths matches. Each id is listed once,
+    /// in the order first seen.
+    pub fn partition(&self, files: &[(String, FileId)]) -> (Vec<FileId>, Vec<FileId>) {
+        let wanted: HashSet<_> = files
+            .iter()
+            .filter(|(path, _)| self.matches(path))
+            .map(|(_, id)| *id)
+            .collect();
+        let mut seen = HashSet::new();
+        let mut sent = Vec::new();
+        let mut promised = Vec::new();
+        for (_, id) in files {
+            if seen.insert(*id) {
+                if wanted.contains(id) {
+                    sent.push(*id);
+                } else {
+                    promised.push(*id);
+                }
+            }
+        }
+        (sent, promised)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use forjj_storage::ObjectId;
+
+    use super::*;
+
+    fn filter(prefixes: &[&str]) -> PathFilter {
+        let prefixes: Vec<String> = prefixes.iter().map(|prefix| prefix.to_string()).collect();
+        PathFilter::parse(&prefixes).unwrap()
+    }
+
+    #[test]
+    fn test_matches_whole_components() {
+        let filter = filter(&["src", "docs/api/"]);
+        assert_eq!(filter.prefixes(), ["src", "docs/api"]);
+        assert!(filter.matches("src"));
+        assert!(filter.matches("src/main.rs"));
+        assert!(filter.matches("src/deep/nested/file"));
+        assert!(filter.matches("docs/api/index.md"));
+        assert!(!filter.matches("srcs/lib.rs"));
+        assert!(!filter.matches("docs/guide.md"));
+        assert!(!filter.matches("docs/apis/index.md"));
+        assert!(!filter.matches("README.md"));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        let parse = |filter: &str| PathFilter::parse(&[filter.to_string()]);
+        assert_eq!(parse(""), Err(PathFilterError::Empty));
+        assert_eq!(parse("/"), Err(PathFilterError::Empty));
+        assert_eq!(
+            parse(&"a".repeat(MAX_FILTER_LEN + 1)),
+            Err(PathFilterError::TooLong)
+        );
+        assert_eq!(parse("src\n"), Err(PathFilterError::InvalidChar('\n')));
+        assert_eq!(parse("src\\lib"), Err(PathFilterError::InvalidChar('\\')));
+        for filter in ["/src", "../src", "src/../..", "./src", "src//lib"] {
+            assert!(
+                matches!(parse(filter), Err(PathFilterError::NotRelative(_))),
+                "{filter} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_partition() {
+        let a = ObjectId::hash(b"a");
+        let b = ObjectId::hash(b"b");
+        let c = ObjectId::hash(b"c");
+        let files = vec![
+            ("lib/a.rs".to_string(), a),
+            ("src/b.rs".to_string(), b),
+            ("src/a.rs".to_string(), a),
+            ("lib/c.rs".to_string(), c),
+            ("lib/again/c.rs".to_string(), c),
+        ];
+        // The same contents under a wanted path are sent, not promised.
+        assert_eq!(filter(&["src"]).partition(&files), (vec![a, b], vec![c]));
+        assert_eq!(filter(&["none"]).partition(&files), (vec![], vec![a, b, c]));
+    }
+}
@@ -0,0 +1,162 @@
+//! Client side of the SSH transport.
+//!
+//! The server runs forjj-sync as an SSH exec command, `forjj-sync
+//! '<owner>/<name>'`, and the channel's data carries the protocol exactly as
+//! a plain stream would. [`connect_ssh`] authenticates with a private key,
+//! checks the server's host key against the one the caller expects, and
+//! returns an [`SshStream`] to hand to
+//! [`ForjjClient::connect`](crate::client::ForjjClient::connect).
+//!
+//! The SSH user authenticates the connection, so the Hello can carry
+//! [`Auth::None`](crate::messages::Auth::None); the server ignores its
+//! credentials.
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use russh::ChannelStream;
+use russh::client::{self, Handle, Msg};
+use russh::keys::{PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::messages::RepoRef;
+
+/// Exec command that starts a forjj-sync session on the server.
+pub const SYNC_COMMAND: &str = "forjj-sync";
+
+/// Where and as whom to connect.
+#[derive(Debug, Clone)]
+pub struct SshTarget {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    /// Key to authenticate with
+    pub key: Arc<PrivateKey>,
+    /// Host key the server must present
+    pub host_key: PublicKey,
+}
+
+/// Errors from setting up an SSH connection.
+#[derive(Debug, thiserror::Error)]
+pub enum SshError {
+    #[error("SSH error: {0}")]
+    Ssh(#[from] russh::Error),
+
+    #[error("server host key does not match the expected key")]
+    HostKeyMismatch,
+
+    #[error("server rejected the key for user {0:?}")]
+    AuthRejected(String),
+}
+
+/// The exec command for a session on `repo`.
+pub fn sync_command(repo: &RepoRef) -> String {
+    format!("{SYNC_COMMAND} '{repo}'")
+}
+
+/// Accepts only the expected host key.
+struct HostKeyCheck {
+    expected: PublicKey,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, server_key: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(server_key.key_data() == self.expected.key_data())
+    }
+}
+
+/// Connect to `target` and start a forjj-sync session on `repo`.
+pub async fn connect_ssh(target: &SshTarget, repo: &RepoRef) -> Result<SshStream, SshError> {
+    let config = Arc::new(client::Config::default());
+    let check = HostKeyCheck {
+        expected: target.host_key.clone(),
+    };
+    let mut session = client::connect(config, (target.host.as_str(), target.port), check)
+        .await
+        .map_err(|error| match error {
+            russh::Error::UnknownKey => SshError::HostKeyMismatch,
+            error => SshError::Ssh(error),
+        })?;
+
+    let key = PrivateKeyWithHashAlg::new(target.key.clone(), None);
+    let auth = session.authenticate_publickey(&target.user, key).await?;
+    if !auth.success() {
+        return Err(SshError::AuthRejected(target.user.clone()));
+    }
+
+    let channel = session.channel_open_session().await?;
+    channel.exec(true, sync_command(repo)).await?;
+    Ok(SshStream {
+        stream: channel.into_stream(),
+        _session: session,
+    })
+}
+
+/// A forjj-sync session's SSH channel.
+///
+/// Holds the connection open until dropped.
+pub struct SshStream {
+    stream: ChannelStream<Msg>,
+    _session: Handle<HostKeyCheck>,
+}
+
+impl std::fmt::Debug for SshStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshStream").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_command() {
+        let repo = RepoRef::new("alice", "project");
+        assert_eq!(sync_command(&repo), "forjj-sync 'alice/project'");
+    }
+}
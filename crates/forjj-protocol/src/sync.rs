@@ -0,0 +1,398 @@
+//! Moving repository contents through packs.
+//!
+//! Native-backend repositories are synced by copying stored objects verbatim:
+//! a pack carries commits, trees, and file contents, and
+//! [`OperationRecord`]s after it carry the operations of the sender's
+//! operation log along with their views. The receiver stores whatever it
+//! lacks and adopts the sender's operation heads, merging them with its own
+//! on the next load, so `jj op log` shows the sender's operations as they
+//! were made. A commits-only fetch, see [`crate::messages::FetchMode`],
+//! carries no operations, and the receiver records the fetched refs in an
+//! operation of its own.
+
+use std::collections::{HashMap, HashSet};
+
+use forjj_storage::{CommitId, ObjectId, OperationId, RawObjectKind, Repository, ViewId};
+
+use crate::bloom::CommitFilter;
+use crate::delta::{apply_delta, compute_delta};
+use crate::error::ProtocolError;
+use crate::messages::{OperationRecord, RefInfo, ViewData};
+use crate::negotiation::OpGraph;
+use crate::pack::{
+    ObjectKind, PackEntry, PackError, PackLimits, PackWriter, raw_kind, verify_object,
+};
+use crate::refs::TAG_PREFIX;
+
+/// Kinds of objects that make up commits, without the operation log.
+pub const CONTENT_KINDS: &[RawObjectKind] = &[
+    RawObjectKind::File,
+    RawObjectKind::Symlink,
+    RawObjectKind::Conflict,
+    RawObjectKind::Tree,
+    RawObjectKind::Commit,
+];
+
+impl From<RawObjectKind> for ObjectKind {
+    fn from(kind: RawObjectKind) -> Self {
+        match kind {
+            RawObjectKind::Commit => ObjectKind::Commit,
+            RawObjectKind::Tree => ObjectKind::Tree,
+            RawObjectKind::File => ObjectKind::File,
+            RawObjectKind::Symlink => ObjectKind::Symlink,
+            RawObjectKind::Conflict => ObjectKind::Conflict,
+            RawObjectKind::Operation => ObjectKind::Operation,
+            RawObjectKind::View => ObjectKind::View,
+        }
+    }
+}
+
+/// A pack built from a repository.
+#[derive(Debug, Clone)]
+pub struct ExportedPack {
+    /// The pack bytes, trailer included
+    pub data: Vec<u8>,
+    /// Number of commits in the pack
+    pub commit_count: u64,
+}
+
+/// Pack every object of the given kinds in `repo`.
+///
+/// Objects are written in the order of `kinds`.
+pub async fn export_pack(
+    repo: &Repository,
+    kinds: &[RawObjectKind],
+) -> Result<ExportedPack, ProtocolError> {
+    let mut objects = Vec::new();
+    for &kind in kinds {
+        for id in repo.list_raw_objects(kind)? {
+            objects.push((kind, id));
+        }
+    }
+    export_objects(repo, &objects).await
+}
+
+/// Like [`export_pack`], but promise the commits `have` probably contains
+/// instead of sending them.
+pub async fn export_pack_except(
+    repo: &Repository,
+    kinds: &[RawObjectKind],
+    have: &CommitFilter,
+) -> Result<ExportedPack, ProtocolError> {
+    let mut objects = Vec::new();
+    let mut promised = Vec::new();
+    for &kind in kinds {
+        for id in repo.list_raw_objects(kind)? {
+            if kind == RawObjectKind::Commit && have.contains(&id) {
+                promised.push((kind, id));
+            } else {
+                objects.push((kind, id));
+            }
+        }
+    }
+    export_partial(repo, &objects, &promised).await
+}
+
+/// Pack the given objects of `repo`, in order.
+///
+/// Objects that aren't stored are left out: the root commit, operation, and
+/// view are implicit in every repository.
+pub async fn export_objects(
+    repo: &Repository,
+    objects: &[(RawObjectKind, ObjectId)],
+) -> Result<ExportedPack, ProtocolError> {
+    export_partial(repo, objects, &[]).await
+}
+
+/// Pack the given objects of `repo`, followed by promised entries for the
+/// `promised` ones.
+///
+/// As with [`export_objects`], objects that aren't stored are left out of
+/// both. The export yields after each object it reads, so a long one
+/// doesn't hold up the rest of its task, such as keepalives.
+pub async fn export_partial(
+    repo: &Repository,
+    objects: &[(RawObjectKind, ObjectId)],
+    promised: &[(RawObjectKind, ObjectId)],
+) -> Result<ExportedPack, ProtocolError> {
+    let mut stored = Vec::new();
+    for &(kind, id) in objects {
+        if repo.has_raw_object(kind, &id)? {
+            stored.push((kind, id));
+        }
+    }
+    let mut stored_promised = Vec::new();
+    for &(kind, id) in promised {
+        if repo.has_raw_object(kind, &id)? {
+            stored_promised.push((kind, id));
+        }
+    }
+    let commit_count = stored
+        .iter()
+        .filter(|(kind, _)| *kind == RawObjectKind::Commit)
+        .count() as u64;
+
+    let total = stored.len() + stored_promised.len();
+    let count = u32::try_from(total)
+        .map_err(|_| anyhow::anyhow!("too many objects for one pack: {total}"))?;
+    let mut pack = PackWriter::new(Vec::new(), count).await?;
+    for (kind, id) in stored {
+        let data = repo.read_raw_object(kind, &id)?;
+        pack.add_object(kind.into(), &id, &data).await?;
+        tokio::task::yield_now().await;
+    }
+    for (kind, id) in stored_promised {
+        pack.add_promised(kind.into(), &id).await?;
+    }
+    let (data, _) = pack.finish().await?;
+
+    Ok(ExportedPack { data, commit_count })
+}
+
+/// Objects stored by [`import_objects`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    /// Objects the repository didn't have
+    pub written: u64,
+    /// Objects it already had, or that came twice in the same pack
+    pub skipped: u64,
+}
+
+/// Store the objects in `entries` that `repo` doesn't have yet.
+///
+/// Every entry is checked before anything is stored. Fails with
+/// [`ProtocolError::UnexpectedObject`] if an entry's kind isn't in
+/// `allowed` (deltas must be resolved first), with a
+/// [`PackError::ObjectHashMismatch`] or [`PackError::UnverifiableObject`] if
+/// its contents don't hash to its id, and with
+/// [`ProtocolError::ConflictingObject`] if the same id comes twice with
+/// different contents. Promised entries have nothing to store and are left
+/// out of the counts.
+pub fn import_objects(
+    repo: &Repository,
+    entries: &[PackEntry],
+    allowed: &[RawObjectKind],
+) -> Result<ImportStats, ProtocolError> {
+    let mut seen: HashMap<(RawObjectKind, ObjectId), &[u8]> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut stats = ImportStats::default();
+    for entry in entries {
+        if entry.kind == ObjectKind::Promised {
+            continue;
+        }
+        let Some(kind) = raw_kind(entry.kind).filter(|kind| allowed.contains(kind)) else {
+            return Err(ProtocolError::UnexpectedObject(entry.kind));
+        };
+        entry.verify()?;
+        match seen.get(&(kind, entry.id)) {
+            Some(data) if *data != entry.data.as_slice() => {
+                return Err(ProtocolError::ConflictingObject {
+                    kind: entry.kind,
+                    id: entry.id,
+                });
+            }
+            Some(_) => stats.skipped += 1,
+            None => {
+                seen.insert((kind, entry.id), &entry.data);
+                unique.push((kind, entry));
+            }
+        }
+    }
+
+    // Store objects before anything that refers to them.
+    for kind in RawObjectKind::ALL {
+        for (_, entry) in unique.iter().filter(|(entry_kind, _)| *entry_kind == kind) {
+            if repo.write_raw_object(kind, &entry.id, &entry.data)? {
+                stats.written += 1;
+            } else {
+                stats.skipped += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// The commits `entries` promise that `repo` doesn't have, such as false
+/// positives of a [`CommitFilter`], to ask for by ID.
+pub fn missing_commits(
+    repo: &Repository,
+    entries: &[PackEntry],
+) -> Result<Vec<CommitId>, ProtocolError> {
+    let mut missing = Vec::new();
+    for entry in entries {
+        if entry.promised_kind() == Some(ObjectKind::Commit)
+            && !repo.has_raw_object(RawObjectKind::Commit, &entry.id)?
+        {
+            missing.push(entry.id);
+        }
+    }
+    Ok(missing)
+}
+
+/// Build the records for `ops` of `graph`, in order.
+///
+/// Operations that aren't stored are left out, like the root operation. With
+/// `deltas`, a view is sent as a delta against its operation's first
+/// parent's view when that is smaller; the receiver must have that parent,
+/// as it does when `ops` come parents first.
+pub fn export_operations(
+    repo: &Repository,
+    graph: &OpGraph,
+    ops: &[OperationId],
+    deltas: bool,
+) -> Result<Vec<OperationRecord>, ProtocolError> {
+    let mut records = Vec::new();
+    for op in ops {
+        if !repo.has_raw_object(RawObjectKind::Operation, op)? {
+            continue;
+        }
+        let entry = graph
+            .get(op)
+            .ok_or_else(|| anyhow::anyhow!("operation {op} is not in the operation log"))?;
+        let data = repo.read_raw_object(RawObjectKind::Operation, op)?;
+        let view = repo.read_raw_object(RawObjectKind::View, &entry.view_id)?;
+
+        let base = entry
+            .parents
+            .first()
+            .and_then(|parent| graph.get(parent))
+            .map(|parent| parent.view_id);
+        let view = match base {
+            Some(base) if deltas && repo.has_raw_object(RawObjectKind::View, &base)? => {
+                let delta =
+                    compute_delta(&repo.read_raw_object(RawObjectKind::View, &base)?, &view);
+                if delta.len() < view.len() {
+                    ViewData::Delta { base, delta }
+                } else {
+                    ViewData::Full(view)
+                }
+            }
+            _ => ViewData::Full(view),
+        };
+        records.push(OperationRecord {
+            id: *op,
+            parents: entry.parents.clone(),
+            description: entry.description.clone(),
+            data,
+            view_id: entry.view_id,
+            view,
+        });
+    }
+    Ok(records)
+}
+
+/// Store the operations in `records` and their views.
+///
+/// Every parent must be in `repo` or earlier in `records`, and every delta
+/// base likewise; otherwise nothing more is stored. Each operation and view
+/// must hash to its ID, failing with [`PackError::ObjectHashMismatch`]
+/// otherwise, and each stored operation is read back to check that it
+/// matches its record. Returns the IDs of the operations that no other
+/// record builds on, the heads to adopt; nothing is adopted here.
+pub async fn import_operations(
+    repo: &Repository,
+    records: &[OperationRecord],
+) -> Result<Vec<OperationId>, ProtocolError> {
+    let root = repo.root_operation_id()?;
+    let mut received = HashSet::new();
+    let mut views: HashMap<ViewId, Vec<u8>> = HashMap::new();
+    for record in records {
+        for parent in &record.parents {
+            if *parent != root
+                && !received.contains(parent)
+                && !repo.has_raw_object(RawObjectKind::Operation, parent)?
+            {
+                return Err(ProtocolError::MissingParentOperation {
+                    operation: record.id,
+                    parent: *parent,
+                });
+            }
+        }
+
+        let view = match &record.view {
+            ViewData::Full(view) => view.clone(),
+            ViewData::Delta { base, delta } => {
+                let base = match views.get(base) {
+                    Some(view) => view.clone(),
+                    None if repo.has_raw_object(RawObjectKind::View, base)? => {
+                        repo.read_raw_object(RawObjectKind::View, base)?
+                    }
+                    None => return Err(PackError::MissingDeltaBase(*base).into()),
+                };
+                apply_delta(&base, delta, PackLimits::default().max_object_size)
+                    .map_err(PackError::from)?
+            }
+        };
+        verify_object(ObjectKind::View, &record.view_id, &view)?;
+        verify_object(ObjectKind::Operation, &record.id, &record.data)?;
+        repo.write_raw_object(RawObjectKind::View, &record.view_id, &view)?;
+        repo.write_raw_object(RawObjectKind::Operation, &record.id, &record.data)?;
+
+        let stored = repo.operation_entry(&record.id).await?;
+        if stored.parents != record.parents || stored.view_id != record.view_id {
+            return Err(ProtocolError::OperationMismatch(record.id));
+        }
+        received.insert(record.id);
+        views.insert(record.view_id, view);
+    }
+
+    let parents: HashSet<_> = records
+        .iter()
+        .flat_map(|record| record.parents.iter())
+        .collect();
+    Ok(records
+        .iter()
+        .map(|record| record.id)
+        .filter(|id| !parents.contains(id))
+        .collect())
+}
+
+/// Store a fetched pack and operations in `repo`, then adopt `op_heads`,
+/// the server's heads among the operations.
+pub async fn apply_fetch(
+    repo: &mut Repository,
+    entries: &[PackEntry],
+    operations: &[OperationRecord],
+    op_heads: &[OperationId],
+) -> Result<(), ProtocolError> {
+    import_objects(repo, entries, &RawObjectKind::ALL)?;
+    import_operations(repo, operations).await?;
+    for op in op_heads {
+        repo.add_op_head(op)?;
+    }
+    Ok(())
+}
+
+/// Store a pack fetched in [`FetchMode::CommitsOnly`] in `repo`, then point
+/// its bookmarks and tags where `refs`, the response's `ref_targets`, say,
+/// in a single "fetch" operation for each kind.
+///
+/// Conflicted refs are left as they are. Only commit contents are accepted
+/// from the pack.
+///
+/// [`FetchMode::CommitsOnly`]: crate::messages::FetchMode::CommitsOnly
+pub fn apply_fetch_commits(
+    repo: &mut Repository,
+    entries: &[PackEntry],
+    refs: &[RefInfo],
+) -> Result<(), ProtocolError> {
+    import_objects(repo, entries, CONTENT_KINDS)?;
+    let mut bookmarks = Vec::new();
+    let mut tags = Vec::new();
+    for info in refs {
+        let Some(&target) = info.target.as_normal() else {
+            continue;
+        };
+        match info.name.strip_prefix(TAG_PREFIX) {
+            Some(tag) if info.is_tag => tags.push((tag.to_string(), Some(target))),
+            _ => bookmarks.push((info.name.clone(), Some(target))),
+        }
+    }
+    if !bookmarks.is_empty() {
+        repo.set_bookmarks(&bookmarks, "fetch")?;
+    }
+    if !tags.is_empty() {
+        repo.set_tags(&tags, "fetch")?;
+    }
+    Ok(())
+}
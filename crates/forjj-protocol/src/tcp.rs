@@ -0,0 +1,17 @@
+//! Client side of the raw TCP transport.
+//!
+//! Meant for mirroring between servers on a trusted network: the protocol
+//! runs directly on the connection, with no transport authentication, so
+//! the Hello must carry a bearer token.
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Connect to a forjj-sync TCP listener at `addr`.
+///
+/// Nagle's algorithm is disabled, since the protocol's small request frames
+/// each wait for a response.
+pub async fn connect_tcp(addr: impl ToSocketAddrs) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
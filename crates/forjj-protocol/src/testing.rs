@@ -0,0 +1,349 @@
+//! In-memory peers for testing code that speaks the protocol.
+//!
+//! Built for this crate's tests, and for other crates with the `testing`
+//! feature:
+//!
+//! - [`TestPair`] connects a client and a server over a
+//!   [`tokio::io::duplex`] pipe.
+//! - [`ScriptedServer`] plays a fixed sequence of messages against a client,
+//!   checking what the client sends in between.
+//! - [`RecordingStream`] wraps a stream and keeps a [`Transcript`] of the
+//!   bytes in each direction, for assertions about the frames on the wire.
+//! - [`within`] fails a test that hangs instead of letting it stall the
+//!   suite.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+
+use crate::envelope::{Message, WireFormat, decode_message, read_message, write_message};
+use crate::error::ProtocolError;
+
+/// Default buffer size of a [`TestPair`]'s pipe, in bytes.
+pub const TEST_BUFFER: usize = 64 * 1024;
+
+/// Time [`within`] allows a test future.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client and a server stream connected to each other.
+#[derive(Debug)]
+pub struct TestPair {
+    pub client: DuplexStream,
+    pub server: DuplexStream,
+}
+
+impl Default for TestPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestPair {
+    /// A pair with a [`TEST_BUFFER`]-byte pipe.
+    pub fn new() -> Self {
+        Self::with_capacity(TEST_BUFFER)
+    }
+
+    /// A pair whose pipe buffers `capacity` bytes in each direction, e.g. a
+    /// small one to make writers wait for readers.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (client, server) = tokio::io::duplex(capacity);
+        Self { client, server }
+    }
+
+    /// Split both ends into separate read and write halves.
+    pub fn split(self) -> (Halves, Halves) {
+        (Halves::new(self.client), Halves::new(self.server))
+    }
+}
+
+/// The read and write halves of one end of a [`TestPair`].
+#[derive(Debug)]
+pub struct Halves {
+    pub reader: ReadHalf<DuplexStream>,
+    pub writer: WriteHalf<DuplexStream>,
+}
+
+impl Halves {
+    fn new(stream: DuplexStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self { reader, writer }
+    }
+}
+
+/// Run `future`, panicking if it takes longer than [`TEST_TIMEOUT`].
+///
+/// With paused time, a future that waits on a peer that will never answer
+/// fails at once rather than after the timeout.
+pub async fn within<F: Future>(future: F) -> F::Output {
+    match tokio::time::timeout(TEST_TIMEOUT, future).await {
+        Ok(output) => output,
+        Err(_) => panic!("test did not finish within {TEST_TIMEOUT:?}"),
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    Expect(&'static str),
+    Send(Message),
+    Format(WireFormat),
+}
+
+/// A server that follows a script instead of handling requests.
+///
+/// Steps run in order: [`expect`](Self::expect) reads the client's next
+/// message and checks its kind, [`send`](Self::send) writes a message, and
+/// [`switch_format`](Self::switch_format) changes the format of the steps
+/// after it, as the Hello exchange does. The script starts in
+/// [`WireFormat::Json`], and the stream is shut down after the last step.
+#[derive(Debug)]
+pub struct ScriptedServer {
+    steps: Vec<Step>,
+}
+
+impl Default for ScriptedServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptedServer {
+    /// An empty script.
+    pub fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Read a message, failing unless it is of the kind named `name`, as in
+    /// [`Message::name`].
+    pub fn expect(mut self, name: &'static str) -> Self {
+        self.steps.push(Step::Expect(name));
+        self
+    }
+
+    /// Write `message`.
+    pub fn send(mut self, message: impl Into<Message>) -> Self {
+        self.steps.push(Step::Send(message.into()));
+        self
+    }
+
+    /// Read and write the following steps in `format`.
+    pub fn switch_format(mut self, format: WireFormat) -> Self {
+        self.steps.push(Step::Format(format));
+        self
+    }
+
+    /// Play the script over `stream` and return the messages the client sent.
+    ///
+    /// Fails with [`ProtocolError::UnexpectedMessage`] if the client sends
+    /// something other than what the script expects.
+    pub async fn run<S>(self, stream: S) -> Result<Vec<Message>, ProtocolError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut format = WireFormat::Json;
+        let mut received = Vec::new();
+        for step in self.steps {
+            match step {
+                Step::Expect(expected) => {
+                    let message = read_message(&mut reader, format).await?;
+                    if message.name() != expected {
+                        return Err(ProtocolError::UnexpectedMessage {
+                            expected,
+                            actual: message.name(),
+                        });
+                    }
+                    received.push(message);
+                }
+                Step::Send(message) => write_message(&mut writer, &message, format).await?,
+                Step::Format(new_format) => format = new_format,
+            }
+        }
+        // The client may already be gone, which is its business.
+        let _ = writer.shutdown().await;
+        Ok(received)
+    }
+}
+
+/// Bytes that passed through a [`RecordingStream`] in one direction.
+///
+/// Clones share the same bytes, so a test can keep one while the stream is
+/// moved into the code under test.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Transcript {
+    /// Everything recorded so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().clone()
+    }
+
+    /// The payloads of the complete frames recorded so far.
+    ///
+    /// Assumes the default framing options: no flags byte and no checksums.
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        split_frames(&self.bytes.lock().unwrap())
+    }
+
+    /// The messages of the complete frames recorded so far, all decoded as
+    /// `format`.
+    pub fn messages(&self, format: WireFormat) -> Result<Vec<Message>, ProtocolError> {
+        self.frames()
+            .iter()
+            .map(|payload| decode_message(payload, format))
+            .collect()
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        self.bytes.lock().unwrap().extend_from_slice(bytes);
+    }
+}
+
+/// Split length-prefixed frames, dropping a trailing incomplete one.
+pub fn split_frames(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while let Some((len, rest)) = bytes.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            break;
+        }
+        frames.push(rest[..len].to_vec());
+        bytes = &rest[len..];
+    }
+    frames
+}
+
+/// A stream that records the bytes read from and written to `S`.
+#[derive(Debug)]
+pub struct RecordingStream<S> {
+    inner: S,
+    sent: Transcript,
+    received: Transcript,
+}
+
+impl<S> RecordingStream<S> {
+    /// Start recording `inner`.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            sent: Transcript::default(),
+            received: Transcript::default(),
+        }
+    }
+
+    /// The bytes written to the stream.
+    pub fn sent(&self) -> Transcript {
+        self.sent.clone()
+    }
+
+    /// The bytes read from the stream.
+    pub fn received(&self) -> Transcript {
+        self.received.clone()
+    }
+
+    /// Unwrap the stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.received.record(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.sent.record(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{Ping, Pong};
+
+    #[test]
+    fn test_split_frames() {
+        let bytes = [&[0, 0, 0, 2, 1, 2][..], &[0, 0, 0, 0], &[0, 0, 0, 5, 9]].concat();
+        assert_eq!(split_frames(&bytes), [vec![1, 2], vec![]]);
+        assert!(split_frames(&[0, 0]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recording_stream() {
+        let TestPair { client, server } = TestPair::new();
+        let mut client = RecordingStream::new(client);
+        let sent = client.sent();
+        let received = client.received();
+
+        let script = ScriptedServer::new()
+            .expect("Ping")
+            .send(Pong { payload: [1; 8] });
+        let run = async {
+            let ping = Message::Ping(Ping { payload: [1; 8] });
+            write_message(&mut client, &ping, WireFormat::Json).await?;
+            read_message(&mut client, WireFormat::Json).await
+        };
+        let (script, pong) = within(async { tokio::join!(script.run(server), run) }).await;
+
+        assert!(matches!(script.unwrap().as_slice(), [Message::Ping(_)]));
+        assert!(matches!(pong.unwrap(), Message::Pong(_)));
+        assert!(matches!(
+            sent.messages(WireFormat::Json).unwrap().as_slice(),
+            [Message::Ping(_)]
+        ));
+        assert!(matches!(
+            received.messages(WireFormat::Json).unwrap().as_slice(),
+            [Message::Pong(_)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_script_rejects_unexpected_message() {
+        let TestPair { mut client, server } = TestPair::new();
+        let script = ScriptedServer::new().expect("Fetch");
+        let ping = Message::Ping(Ping { payload: [0; 8] });
+        let send = write_message(&mut client, &ping, WireFormat::Json);
+        let (result, sent) = within(async { tokio::join!(script.run(server), send) }).await;
+        sent.unwrap();
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnexpectedMessage {
+                expected: "Fetch",
+                actual: "Ping"
+            })
+        ));
+    }
+}
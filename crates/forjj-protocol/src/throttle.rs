@@ -0,0 +1,286 @@
+//! Bandwidth limits for outbound streams.
+//!
+//! A [`ThrottledWriter`] paces writes with a token bucket: it holds up to
+//! `burst` bytes of credit, earns `bytes_per_sec` more every second, and
+//! spends one per byte written. When the credit runs out, writes wait on a
+//! tokio timer until enough has accrued, so a throttled connection costs no
+//! CPU while it waits.
+//!
+//! Writes are cut to the available credit rather than refused, so callers
+//! such as [`FrameWriter`](crate::framing::FrameWriter) that loop until
+//! everything is written need no changes. Vectored writes are cut the same
+//! way, across all of their buffers.
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+use tokio::time::{Instant, Sleep};
+
+/// A limit on the throughput of one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained rate
+    pub bytes_per_sec: u64,
+    /// Bytes that may be sent at once after a pause
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A limit of `bytes_per_sec`, with a burst of one second's worth.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+        }
+    }
+}
+
+/// Credit in bytes, earned over time.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        // A zero rate or burst would never let anything through.
+        let burst = limit.burst.max(1) as f64;
+        Self {
+            rate: limit.bytes_per_sec.max(1) as f64,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Whole bytes that may be written now.
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let earned = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.burst);
+        self.updated = now;
+        self.tokens as usize
+    }
+
+    fn spend(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    /// How long until `bytes` may be written, capped at the burst.
+    fn wait_for(&self, bytes: usize) -> Duration {
+        let wanted = (bytes as f64).min(self.burst);
+        Duration::from_secs_f64((wanted - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// An [`AsyncWrite`] that limits the rate bytes pass through to `W`.
+///
+/// Without a limit, writes pass straight through.
+#[derive(Debug)]
+pub struct ThrottledWriter<W> {
+    inner: W,
+    bucket: Option<TokenBucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    written: u64,
+}
+
+impl<W> ThrottledWriter<W> {
+    /// Wrap `inner`, limiting it to `limit` if there is one.
+    pub fn new(inner: W, limit: Option<RateLimit>) -> Self {
+        Self {
+            inner,
+            bucket: limit.map(TokenBucket::new),
+            sleep: None,
+            written: 0,
+        }
+    }
+
+    /// Bytes written through to `W` so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// The wrapped writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The wrapped writer, mutably. Writing to it bypasses the limit.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwrap the writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> ThrottledWriter<W> {
+    /// Wait until at least one byte of a `wanted`-byte write may go, then
+    /// return how many may.
+    fn poll_credit(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        let Some(bucket) = &mut self.bucket else {
+            return Poll::Ready(wanted);
+        };
+        loop {
+            let available = bucket.available();
+            if available > 0 || wanted == 0 {
+                self.sleep = None;
+                return Poll::Ready(available.min(wanted));
+            }
+            let deadline = Instant::now() + bucket.wait_for(wanted);
+            match &mut self.sleep {
+                Some(sleep) => sleep.as_mut().reset(deadline),
+                None => self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline))),
+            }
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+            }
+        }
+    }
+
+    fn spend(&mut self, result: &Poll<io::Result<usize>>) {
+        if let Poll::Ready(Ok(written)) = result {
+            self.written += *written as u64;
+            if let Some(bucket) = &mut self.bucket {
+                bucket.spend(*written);
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(this.poll_credit(cx, buf.len()));
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+        this.spend(&result);
+        result
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let total = bufs.iter().map(|buf| buf.len()).sum();
+        let mut allowed = ready!(this.poll_credit(cx, total));
+        let result = if allowed == total {
+            Pin::new(&mut this.inner).poll_write_vectored(cx, bufs)
+        } else {
+            let mut cut = Vec::with_capacity(bufs.len());
+            for buf in bufs {
+                if allowed == 0 {
+                    break;
+                }
+                let len = buf.len().min(allowed);
+                cut.push(IoSlice::new(&buf[..len]));
+                allowed -= len;
+            }
+            Pin::new(&mut this.inner).poll_write_vectored(cx, &cut)
+        };
+        this.spend(&result);
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::framing::FrameWriter;
+
+    /// Write `len` bytes at 1 KB/s with a 256-byte burst and return how
+    /// long it took.
+    async fn timed_write(len: usize, vectored: bool) -> Duration {
+        let limit = RateLimit {
+            bytes_per_sec: 1024,
+            burst: 256,
+        };
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut writer = ThrottledWriter::new(Vec::new(), Some(limit));
+        let start = Instant::now();
+        if vectored {
+            let (head, tail) = data.split_at(len / 3);
+            let mut buf = bytes::Buf::chain(head, tail);
+            writer.write_all_buf(&mut buf).await.unwrap();
+        } else {
+            writer.write_all(&data).await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(writer.into_inner(), data);
+        elapsed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paces_writes() {
+        // The burst goes at once; the other 4096 bytes take 4 seconds.
+        let elapsed = timed_write(4096 + 256, false).await;
+        assert!(
+            elapsed >= Duration::from_millis(3900) && elapsed <= Duration::from_millis(4100),
+            "took {elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paces_vectored_writes() {
+        let elapsed = timed_write(2048 + 256, true).await;
+        assert!(
+            elapsed >= Duration::from_millis(1900) && elapsed <= Duration::from_millis(2100),
+            "took {elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_writer() {
+        let throttled = ThrottledWriter::new(Vec::new(), Some(RateLimit::new(1024)));
+        let mut writer = FrameWriter::new(throttled);
+        let start = Instant::now();
+        for _ in 0..4 {
+            writer.write_frame(&[7; 1020]).await.unwrap();
+        }
+        // 4 KiB of frames with a one-second burst: three seconds of waiting.
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(2900) && elapsed <= Duration::from_millis(3100),
+            "took {elapsed:?}"
+        );
+        assert_eq!(writer.get_ref().bytes_written(), 4 * 1024);
+        assert_eq!(writer.into_inner().into_inner().len(), 4 * 1024);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited() {
+        let mut writer = ThrottledWriter::new(Vec::new(), None);
+        let start = Instant::now();
+        writer.write_all(&[0; 1 << 20]).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(writer.get_ref().len(), 1 << 20);
+        assert_eq!(writer.bytes_written(), 1 << 20);
+    }
+}
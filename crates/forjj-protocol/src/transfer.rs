@@ -0,0 +1,576 @@
+//! Streaming pack transfer over multiple frames.
+//!
+//! A pack can exceed [`MAX_MESSAGE_SIZE`](crate::framing::MAX_MESSAGE_SIZE),
+//! so after a `FetchOk` with `pack_follows` set, the sender emits a sequence of
+//! [`PackChunk`] messages. Sequence numbers start at 0 and increase by one. The
+//! last chunk sets `last` and repeats the pack trailer hash in `pack_hash`.
+//!
+//! [`ProgressMessage`]s may be interleaved with the chunks, as may keepalives
+//! from a sender still building the pack. Receivers pass progress to a
+//! [`ProgressSink`] and otherwise ignore them.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use forjj_storage::ObjectId;
+use forjj_storage::object_id::HASH_LEN;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::envelope::{Message, WireFormat, read_message, write_message};
+use crate::error::ProtocolError;
+use crate::messages::{PackChunk, ProgressMessage, ProgressPhase};
+use crate::pack::{PackEntry, PackError, PackLimits, PackReader};
+use crate::progress::{NoProgress, ProgressSink};
+
+/// Default number of pack bytes per chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Send a pack as a sequence of [`PackChunk`] messages.
+///
+/// Reads `pack` to the end. Its final bytes are the pack trailer, which is
+/// repeated in the last chunk. Returns the number of chunks sent.
+pub async fn send_pack<R, W>(
+    pack: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    chunk_size: usize,
+) -> Result<u64, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    send_chunks(pack, writer, format, chunk_size, false, None).await
+}
+
+/// Like [`send_pack`], but precedes each chunk with a
+/// [`ProgressPhase::Sending`] update counting the bytes sent so far.
+pub async fn send_pack_with_progress<R, W>(
+    pack: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    chunk_size: usize,
+    total_bytes: Option<u64>,
+) -> Result<u64, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    send_chunks(pack, writer, format, chunk_size, true, total_bytes).await
+}
+
+async fn send_chunks<R, W>(
+    pack: &mut R,
+    writer: &mut W,
+    format: WireFormat,
+    chunk_size: usize,
+    progress: bool,
+    total_bytes: Option<u64>,
+) -> Result<u64, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut current = read_chunk(pack, chunk_size).await?;
+    let mut tail = Vec::with_capacity(HASH_LEN * 2);
+    let mut sequence = 0;
+    let mut sent = 0;
+
+    loop {
+        let next = if current.is_empty() {
+            Vec::new()
+        } else {
+            read_chunk(pack, chunk_size).await?
+        };
+        let last = next.is_empty();
+
+        keep_tail(&mut tail, &current);
+        let pack_hash = if last {
+            let trailer =
+                <[u8; HASH_LEN]>::try_from(tail.as_slice()).map_err(|_| PackError::Truncated)?;
+            Some(ObjectId::from_bytes(trailer))
+        } else {
+            None
+        };
+
+        sent += current.len() as u64;
+        if progress {
+            let update = ProgressMessage {
+                phase: ProgressPhase::Sending,
+                current: sent,
+                total: total_bytes,
+                bytes: Some(sent),
+            };
+            write_message(writer, &update.into(), format).await?;
+        }
+
+        let chunk = PackChunk {
+            sequence,
+            data: current,
+            last,
+            pack_hash,
+        };
+        write_message(writer, &chunk.into(), format).await?;
+        sequence += 1;
+
+        if last {
+            return Ok(sequence);
+        }
+        current = next;
+    }
+}
+
+/// Receive a pack streamed with [`send_pack`] and read all of its objects.
+pub async fn receive_pack<S>(
+    stream: &mut S,
+    format: WireFormat,
+) -> Result<Vec<PackEntry>, ProtocolError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    receive_pack_with_progress(stream, format, NoProgress).await
+}
+
+/// Like [`receive_pack`], but passes interleaved progress updates to `sink`.
+pub async fn receive_pack_with_progress<'a, S, P>(
+    stream: &'a mut S,
+    format: WireFormat,
+    sink: P,
+) -> Result<Vec<PackEntry>, ProtocolError>
+where
+    S: AsyncRead + Unpin + Send,
+    P: ProgressSink + Send + 'a,
+{
+    receive_entries(stream, format, sink, PackLimits::default()).await
+}
+
+/// Like [`receive_pack`], but with `limits` in place of the default ones.
+pub async fn receive_pack_with_limits<S>(
+    stream: &mut S,
+    format: WireFormat,
+    limits: PackLimits,
+) -> Result<Vec<PackEntry>, ProtocolError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    receive_entries(stream, format, NoProgress, limits).await
+}
+
+async fn receive_entries<'a, S, P>(
+    stream: &'a mut S,
+    format: WireFormat,
+    sink: P,
+    limits: PackLimits,
+) -> Result<Vec<PackEntry>, ProtocolError>
+where
+    S: AsyncRead + Unpin + Send,
+    P: ProgressSink + Send + 'a,
+{
+    let mut chunks = PackChunkReader::new(stream, format).with_progress(sink);
+
+    let entries = match read_entries(&mut chunks, limits).await {
+        Ok(entries) => entries,
+        Err(error) => return Err(chunks.take_error().unwrap_or_else(|| error.into())),
+    };
+
+    // The pack reader stops at the trailer; the chunk stream must end there too.
+    if !chunks.is_finished() {
+        let mut extra = [0u8; 1];
+        let read = chunks.read(&mut extra).await;
+        if let Some(error) = chunks.take_error() {
+            return Err(error);
+        }
+        if read.map_err(PackError::from)? != 0 {
+            return Err(ProtocolError::TrailingPackData);
+        }
+    }
+
+    Ok(entries)
+}
+
+async fn read_entries<R: AsyncRead + Unpin>(
+    reader: R,
+    limits: PackLimits,
+) -> Result<Vec<PackEntry>, PackError> {
+    PackReader::with_limits(reader, limits)
+        .await?
+        .read_all()
+        .await
+}
+
+/// Get the trailer hash at the end of an in-memory pack.
+pub(crate) fn trailer_hash(pack: &[u8]) -> Result<ObjectId, PackError> {
+    pack.len()
+        .checked_sub(HASH_LEN)
+        .and_then(|start| <[u8; HASH_LEN]>::try_from(&pack[start..]).ok())
+        .map(ObjectId::from_bytes)
+        .ok_or(PackError::Truncated)
+}
+
+/// Read up to `size` bytes, stopping early only at end of stream.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    size: usize,
+) -> Result<Vec<u8>, PackError> {
+    let mut chunk = Vec::with_capacity(size);
+    (&mut *reader)
+        .take(size as u64)
+        .read_to_end(&mut chunk)
+        .await?;
+    Ok(chunk)
+}
+
+/// Keep the last [`HASH_LEN`] bytes seen across chunks.
+fn keep_tail(tail: &mut Vec<u8>, data: &[u8]) {
+    tail.extend_from_slice(&data[data.len().saturating_sub(HASH_LEN)..]);
+    let excess = tail.len().saturating_sub(HASH_LEN);
+    tail.drain(..excess);
+}
+
+/// Validates sequence numbers and the final pack hash across the chunks of
+/// one pack.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkSequence {
+    next_sequence: u64,
+    tail: Vec<u8>,
+    finished: bool,
+}
+
+impl ChunkSequence {
+    /// Check the next chunk of the pack.
+    pub(crate) fn accept(&mut self, chunk: &PackChunk) -> Result<(), ProtocolError> {
+        if chunk.sequence != self.next_sequence {
+            return Err(ProtocolError::ChunkOutOfOrder {
+                expected: self.next_sequence,
+                actual: chunk.sequence,
+            });
+        }
+        self.next_sequence += 1;
+
+        keep_tail(&mut self.tail, &chunk.data);
+        if chunk.last {
+            let matches = chunk
+                .pack_hash
+                .is_some_and(|hash| hash.as_bytes() == self.tail.as_slice());
+            if !matches {
+                return Err(ProtocolError::PackHashMismatch);
+            }
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the last chunk has been accepted.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+type ReadMessageFuture<'a, R> =
+    Pin<Box<dyn Future<Output = (R, Result<Message, ProtocolError>)> + Send + 'a>>;
+
+/// Adapts a stream of [`PackChunk`] messages into an [`AsyncRead`] of pack
+/// bytes, suitable as input to a [`PackReader`].
+///
+/// Pings between the chunks go unanswered, as the reader can't write.
+///
+/// Protocol violations (out-of-order chunks, unexpected messages, a wrong
+/// final hash) surface as `InvalidData` I/O errors. The typed error is kept
+/// and can be retrieved with [`take_error`](Self::take_error).
+pub struct PackChunkReader<'a, R> {
+    reader: Option<R>,
+    pending: Option<ReadMessageFuture<'a, R>>,
+    format: WireFormat,
+    progress: Box<dyn ProgressSink + Send + 'a>,
+    chunk: Bytes,
+    position: usize,
+    sequence: ChunkSequence,
+    error: Option<ProtocolError>,
+}
+
+impl<'a, R> PackChunkReader<'a, R>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    /// Create a reader consuming chunk messages from `reader`.
+    pub fn new(reader: R, format: WireFormat) -> Self {
+        Self {
+            reader: Some(reader),
+            pending: None,
+            format,
+            progress: Box::new(NoProgress),
+            chunk: Bytes::new(),
+            position: 0,
+            sequence: ChunkSequence::default(),
+            error: None,
+        }
+    }
+
+    /// Pass progress updates interleaved with the chunks to `sink`.
+    pub fn with_progress<P: ProgressSink + Send + 'a>(mut self, sink: P) -> Self {
+        self.progress = Box::new(sink);
+        self
+    }
+
+    /// Whether the last chunk has been received.
+    pub fn is_finished(&self) -> bool {
+        self.sequence.is_finished()
+    }
+
+    /// Take the protocol error that stopped the transfer, if any.
+    pub fn take_error(&mut self) -> Option<ProtocolError> {
+        self.error.take()
+    }
+
+    fn accept(&mut self, message: Message) -> Result<(), ProtocolError> {
+        match &message {
+            Message::Progress(update) => {
+                self.progress.progress(update);
+                return Ok(());
+            }
+            Message::Ping(_) | Message::Pong(_) => return Ok(()),
+            _ => {}
+        }
+        let chunk = PackChunk::try_from(message)?;
+        self.sequence.accept(&chunk)?;
+        self.chunk = chunk.data.into();
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<'a, R> AsyncRead for PackChunkReader<'a, R>
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.position);
+                buf.put_slice(&this.chunk[this.position..this.position + n]);
+                this.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.sequence.is_finished() {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(error) = &this.error {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    error.to_string(),
+                )));
+            }
+
+            if this.pending.is_none() {
+                let mut reader = this
+                    .reader
+                    .take()
+                    .expect("reader is present while no read is pending");
+                let format = this.format;
+                this.pending = Some(Box::pin(async move {
+                    let result = read_message(&mut reader, format).await;
+                    (reader, result)
+                }));
+            }
+            let pending = this.pending.as_mut().expect("read is pending");
+            let (reader, result) = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            this.reader = Some(reader);
+
+            if let Err(error) = result.and_then(|message| this.accept(message)) {
+                this.error = Some(error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::MAX_MESSAGE_SIZE;
+    use crate::messages::Ping;
+    use crate::pack::{ObjectKind, PackWriter};
+    use std::io::Cursor;
+
+    async fn build_pack(entries: &[PackEntry]) -> Vec<u8> {
+        let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+            .await
+            .unwrap();
+        for entry in entries {
+            pack.add_object(entry.kind, &entry.id, &entry.data)
+                .await
+                .unwrap();
+        }
+        pack.finish().await.unwrap().0
+    }
+
+    fn small_entries() -> Vec<PackEntry> {
+        (0..4u32)
+            .map(|i| {
+                let data = vec![i as u8; 100];
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    async fn write_chunks(chunks: Vec<PackChunk>) -> Cursor<Vec<u8>> {
+        let mut buffer = Vec::new();
+        for chunk in chunks {
+            write_message(&mut buffer, &chunk.into(), WireFormat::Binary)
+                .await
+                .unwrap();
+        }
+        Cursor::new(buffer)
+    }
+
+    #[tokio::test]
+    async fn test_transfer_large_pack_over_duplex() {
+        let entries: Vec<PackEntry> = (0..17u32)
+            .map(|i| {
+                let data = vec![i as u8; 1024 * 1024];
+                PackEntry {
+                    kind: ObjectKind::File,
+                    id: ObjectId::hash(&data),
+                    data,
+                }
+            })
+            .collect();
+        let pack = build_pack(&entries).await;
+        assert!(pack.len() > MAX_MESSAGE_SIZE as usize);
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut source = pack.as_slice();
+        let send = send_pack(
+            &mut source,
+            &mut client,
+            WireFormat::Binary,
+            DEFAULT_CHUNK_SIZE,
+        );
+        let receive = receive_pack(&mut server, WireFormat::Binary);
+        let (sent, received) = tokio::join!(send, receive);
+
+        assert_eq!(
+            sent.unwrap(),
+            pack.len().div_ceil(DEFAULT_CHUNK_SIZE) as u64
+        );
+        assert_eq!(received.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_small_chunks() {
+        let entries = small_entries();
+        let pack = build_pack(&entries).await;
+
+        let mut buffer = Vec::new();
+        let mut source = pack.as_slice();
+        send_pack(&mut source, &mut buffer, WireFormat::Json, 7)
+            .await
+            .unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let received = receive_pack(&mut cursor, WireFormat::Json).await.unwrap();
+        assert_eq!(received, entries);
+    }
+
+    #[tokio::test]
+    async fn test_keepalives_before_chunks() {
+        let entries = small_entries();
+        let pack = build_pack(&entries).await;
+
+        let mut buffer = Vec::new();
+        let ping = Ping { payload: [1; 8] };
+        write_message(&mut buffer, &ping.into(), WireFormat::Binary)
+            .await
+            .unwrap();
+        let mut source = pack.as_slice();
+        send_pack(&mut source, &mut buffer, WireFormat::Binary, 64)
+            .await
+            .unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let received = receive_pack(&mut cursor, WireFormat::Binary).await;
+        assert_eq!(received.unwrap(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_chunk() {
+        let pack = build_pack(&small_entries()).await;
+        let mid = pack.len() / 2;
+        let mut cursor = write_chunks(vec![
+            PackChunk {
+                sequence: 0,
+                data: pack[..mid].to_vec(),
+                last: false,
+                pack_hash: None,
+            },
+            PackChunk {
+                sequence: 2,
+                data: pack[mid..].to_vec(),
+                last: true,
+                pack_hash: None,
+            },
+        ])
+        .await;
+
+        let result = receive_pack(&mut cursor, WireFormat::Binary).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::ChunkOutOfOrder {
+                expected: 1,
+                actual: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_final_chunk() {
+        let pack = build_pack(&small_entries()).await;
+        let mut cursor = write_chunks(vec![PackChunk {
+            sequence: 0,
+            data: pack[..pack.len() / 2].to_vec(),
+            last: false,
+            pack_hash: None,
+        }])
+        .await;
+
+        let result = receive_pack(&mut cursor, WireFormat::Binary).await;
+        assert!(matches!(result, Err(ProtocolError::Frame(_))));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_final_hash() {
+        let pack = build_pack(&small_entries()).await;
+        let mut cursor = write_chunks(vec![PackChunk {
+            sequence: 0,
+            data: pack,
+            last: true,
+            pack_hash: Some(ObjectId::ZERO),
+        }])
+        .await;
+
+        let result = receive_pack(&mut cursor, WireFormat::Binary).await;
+        assert!(matches!(result, Err(ProtocolError::PackHashMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_send_empty_pack_source_fails() {
+        let mut buffer = Vec::new();
+        let mut source: &[u8] = &[];
+        let result = send_pack(&mut source, &mut buffer, WireFormat::Binary, 16).await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Pack(PackError::Truncated))
+        ));
+    }
+}
@@ -0,0 +1,3087 @@
+//! End-to-end tests: a client syncing with the server session handler over an
+//! in-memory stream, with real repositories on both ends.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use forjj_protocol::{
+    AccessLevel, Auth, AuthError, AuthGrant, AuthHandler, BookmarksMoved, CONTENT_KINDS,
+    Capability, ClientOptions, CommitFilter, ErrorCode, ErrorMessage, FetchMode, FetchOutcome,
+    FetchRequest, FetchResponse, ForjjClient, FrameError, HaveMore, HelloRequest, Message,
+    ObjectKind, OpGraph, OperationRecord, PackEntry, PackError, PackLimits, PackReader, PackWriter,
+    ProgressMessage, ProgressPhase, ProtocolError, PushPolicy, PushRequest, PushResult, PushStatus,
+    RefInfo, RefReason, RefResult, RefStatus, RefTargetWire, RefUpdate, RepoRef, RequestServed,
+    ServerOptions, SessionAbort, SessionMonitor, SessionShutdown, WantError, WantReason,
+    WireFormat, apply_fetch, apply_fetch_commits, client_hello, encode_message, export_operations,
+    export_pack, import_objects, missing_commits, new_push_id, read_message, serve_session,
+    write_frame,
+};
+use forjj_storage::{
+    BookmarkTarget, CommitObjects, ObjectId, RawObjectKind, Repository, RepositoryManager,
+    StorageConfig,
+};
+use tempfile::TempDir;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+const TOKEN: &str = "fj_e2e_token";
+
+/// Grants write access to the test token and read access to anyone else.
+struct TestAuth;
+
+impl AuthHandler for TestAuth {
+    fn authenticate(&self, auth: &Auth, requested: AccessLevel) -> Result<AuthGrant, AuthError> {
+        match auth {
+            Auth::BearerToken(token) if token == TOKEN => Ok(AuthGrant {
+                identity: Some("alice".to_string()),
+                access: AccessLevel::Write,
+            }),
+            Auth::BearerToken(_) => Err(AuthError::InvalidCredentials),
+            _ if requested == AccessLevel::Read => Ok(AuthGrant {
+                identity: None,
+                access: AccessLevel::Read,
+            }),
+            _ => Err(AuthError::MissingCredentials),
+        }
+    }
+}
+
+fn manager(dir: &TempDir) -> RepositoryManager {
+    RepositoryManager::new(StorageConfig {
+        repos_root: dir.path().to_path_buf(),
+    })
+    .unwrap()
+}
+
+fn server_options() -> ServerOptions {
+    ServerOptions::new(Arc::new(TestAuth))
+}
+
+/// Fetch `request`, returning the response and the pack's entries.
+async fn fetch_entries(
+    client: &mut ForjjClient<DuplexStream>,
+    request: FetchRequest,
+) -> (FetchResponse, Vec<PackEntry>) {
+    let mut pack = Vec::new();
+    let outcome = client.fetch(request, &mut pack).await.unwrap();
+    let entries = PackReader::new(pack.as_slice())
+        .await
+        .unwrap()
+        .read_all()
+        .await
+        .unwrap();
+    (outcome.response, entries)
+}
+
+async fn fetch_refs(
+    client: &mut ForjjClient<DuplexStream>,
+    want_refs: &[&str],
+) -> Result<FetchOutcome, ProtocolError> {
+    let request = FetchRequest {
+        have_ops: vec![],
+        want_refs: want_refs.iter().map(|name| name.to_string()).collect(),
+        depth: None,
+        have_commits: vec![],
+        path_filters: vec![],
+        have_commits_filter: None,
+        want_commits: vec![],
+        request_id: None,
+        include_operations: None,
+    };
+    client.fetch(request, &mut Vec::new()).await
+}
+
+#[tokio::test]
+async fn test_fetch_then_push() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream.head_ids().unwrap()[0];
+    let upstream_op = upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    assert!(!local.has_commit(&main));
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write)
+            .with_op_heads(local.op_head_ids().await.unwrap())
+            .with_op_log(OpGraph::load(&local).await.unwrap());
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("alice"));
+        // The client's only operations are its own.
+        assert_eq!(client.hello().common_ancestor, None);
+
+        // Fetch main into the client's repository. The server doesn't know
+        // the client's operations, so they negotiate back to the root.
+        let request = FetchRequest {
+            have_ops: local.op_head_ids().await.unwrap(),
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let mut pack = Vec::new();
+        let outcome = client.fetch(request, &mut pack).await.unwrap();
+        assert!(outcome.response.pack_follows);
+        let entries = PackReader::new(pack.as_slice())
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap();
+        assert_eq!(outcome.operations.len(), outcome.response.ops_to_send.len());
+        apply_fetch(
+            &mut local,
+            &entries,
+            &outcome.operations,
+            &outcome.response.ops_to_send,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            local.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(main)
+        );
+        // The server's operations themselves were copied, not replayed.
+        let log = local.operation_log().await.unwrap();
+        assert!(
+            log.iter()
+                .any(|entry| entry.id == upstream_op && entry.description == "set main")
+        );
+
+        // Fetching again finds the server's operations in common.
+        client.set_op_log(OpGraph::load(&local).await.unwrap());
+        let request = FetchRequest {
+            have_ops: local.op_head_ids().await.unwrap(),
+            want_refs: vec![],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let outcome = client.fetch(request, &mut Vec::new()).await.unwrap();
+        assert!(!outcome.response.pack_follows);
+        assert!(outcome.response.ops_to_send.is_empty());
+
+        // Push a bookmark to a commit only the client has.
+        let feature = local
+            .head_ids()
+            .unwrap()
+            .into_iter()
+            .find(|id| *id != main)
+            .unwrap();
+        // Send along the operations the server is missing.
+        let pack = export_pack(&local, CONTENT_KINDS).await.unwrap();
+        let graph = OpGraph::load(&local).await.unwrap();
+        let missing = graph.missing(&client.hello().server_op_heads);
+        let operations = export_operations(&local, &graph, &missing, true).unwrap();
+        assert!(!operations.is_empty());
+        let request = PushRequest {
+            have_ops: local.op_head_ids().await.unwrap(),
+            updates: vec![RefUpdate {
+                ref_name: "feature".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: Some(feature.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let result = client
+            .push_with_operations(request, &mut pack.data.as_slice(), &operations)
+            .await
+            .unwrap();
+        assert_eq!(result.status, PushStatus::Ok);
+        assert!(result.new_op_head.is_some());
+
+        client.shutdown().await.unwrap();
+        (feature, missing)
+    };
+
+    let (served, (feature, pushed_ops)) = tokio::join!(serve, run);
+    served.unwrap();
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert!(upstream.has_commit(&feature));
+    assert_eq!(
+        upstream.bookmark_target("feature").unwrap(),
+        BookmarkTarget::Normal(feature)
+    );
+    let log: HashSet<_> = upstream
+        .operation_log()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.id)
+        .collect();
+    assert!(pushed_ops.iter().all(|id| log.contains(id)));
+}
+
+#[tokio::test]
+async fn test_fetch_ref_patterns() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let head = upstream.head_ids().unwrap()[0];
+    let bookmarks: Vec<_> = ["main", "release/1.0", "release/2.0"]
+        .into_iter()
+        .map(|name| (name.to_string(), Some(head)))
+        .collect();
+    upstream.set_bookmarks(&bookmarks, "set bookmarks").unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let outcome = fetch_refs(&mut client, &["release/*"]).await.unwrap();
+        assert!(outcome.response.pack_follows);
+        assert_eq!(outcome.response.refs, vec!["release/1.0", "release/2.0"]);
+
+        let outcome = fetch_refs(&mut client, &[]).await.unwrap();
+        assert_eq!(
+            outcome.response.refs,
+            vec!["main", "release/1.0", "release/2.0"]
+        );
+
+        // A pattern that matches nothing fetches nothing.
+        let outcome = fetch_refs(&mut client, &["hotfix/*"]).await.unwrap();
+        assert!(!outcome.response.pack_follows);
+        assert!(outcome.response.refs.is_empty());
+
+        fetch_refs(&mut client, &["../*"]).await
+    };
+
+    let (served, result) = tokio::join!(serve, run);
+    assert!(matches!(served, Err(ProtocolError::InvalidRefPattern(_))));
+    assert_eq!(
+        result.unwrap_err().remote_code(),
+        Some(ErrorCode::ProtocolViolation)
+    );
+}
+
+#[tokio::test]
+async fn test_list_refs() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let left = upstream.write_commit(&[], &[], "left").await.unwrap();
+    let right = upstream.write_commit(&[], &[], "right").await.unwrap();
+    upstream
+        .set_bookmarks(&[("release/1.0".to_string(), Some(left))], "set release")
+        .unwrap();
+    upstream
+        .set_tags(&[("v1.0".to_string(), Some(left))], "tag v1.0")
+        .unwrap();
+    // Concurrent moves of main leave it conflicted.
+    let mut other = server_repos.open_repo("alice", "project").unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(left))], "main to left")
+        .unwrap();
+    other
+        .set_bookmarks(&[("main".to_string(), Some(right))], "main to right")
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+
+        // Listing is valid straight after the Hello.
+        let refs = client.list_refs(&[]).await.unwrap();
+        let names: Vec<_> = refs.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["main", "release/1.0", "tags/v1.0"]);
+
+        let main = &refs[0];
+        assert!(!main.is_tag);
+        let mut adds = vec![left, right];
+        adds.sort();
+        assert_eq!(
+            main.target,
+            RefTargetWire::Conflicted {
+                adds,
+                removes: vec![]
+            }
+        );
+
+        let tag = &refs[2];
+        assert!(tag.is_tag);
+        assert_eq!(tag.target, RefTargetWire::Normal(left));
+
+        // Patterns are matched as in a fetch, but missing names are fine.
+        let refs = client.list_refs(&["tags/*", "hotfix"]).await.unwrap();
+        let names: Vec<_> = refs.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["tags/v1.0"]);
+
+        // The listing can be followed by a fetch on the same connection.
+        let outcome = fetch_refs(&mut client, &["release/*"]).await.unwrap();
+        assert_eq!(outcome.response.refs, vec!["release/1.0"]);
+
+        client.list_refs(&["../*"]).await
+    };
+
+    let (served, result) = tokio::join!(serve, run);
+    assert!(matches!(served, Err(ProtocolError::InvalidRefPattern(_))));
+    assert_eq!(
+        result.unwrap_err().remote_code(),
+        Some(ErrorCode::ProtocolViolation)
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_fetches() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let head = upstream.head_ids().unwrap()[0];
+    let bookmarks: Vec<_> = ["main", "release/1.0"]
+        .into_iter()
+        .map(|name| (name.to_string(), Some(head)))
+        .collect();
+    upstream.set_bookmarks(&bookmarks, "set bookmarks").unwrap();
+    let local = client_repos.create_repo("alice", "project").unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let have_ops = local.op_head_ids().await.unwrap();
+        let options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_op_heads(have_ops.clone())
+            .with_op_log(OpGraph::load(&local).await.unwrap());
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+
+        // Both requests go out before either is answered. The server doesn't
+        // know the client's operations, so the second arrives while the
+        // first is still negotiating, and waits its turn.
+        let request = |want: &str| FetchRequest {
+            have_ops: have_ops.clone(),
+            want_refs: vec![want.to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        let outcomes = client
+            .fetch_pipelined([
+                (request("main"), &mut first),
+                (request("release/*"), &mut second),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].response.request_id, Some(1));
+        assert_eq!(outcomes[0].response.refs, vec!["main"]);
+        assert_eq!(outcomes[1].response.request_id, Some(2));
+        assert_eq!(outcomes[1].response.refs, vec!["release/1.0"]);
+        for pack in [first, second] {
+            let entries = PackReader::new(pack.as_slice())
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap();
+            assert!(!entries.is_empty());
+        }
+
+        // Unpipelined requests still work in the same session.
+        let outcome = fetch_refs(&mut client, &["main"]).await.unwrap();
+        assert_eq!(outcome.response.request_id, None);
+        client.shutdown().await.unwrap();
+    };
+
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+}
+
+#[tokio::test]
+async fn test_batched_negotiation() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let mut tip = None;
+    for i in 0..20 {
+        let parents: Vec<_> = tip.into_iter().collect();
+        let content = format!("version {i}");
+        let commit = upstream
+            .write_commit(
+                &parents,
+                &[("file.txt", content.as_bytes())],
+                &format!("commit {i}"),
+            )
+            .await
+            .unwrap();
+        tip = Some(commit);
+    }
+    upstream
+        .set_bookmarks(&[("main".to_string(), tip)], "set main")
+        .unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+
+    let negotiate_v2 = vec![
+        Capability::Operations,
+        Capability::BinaryFrames,
+        Capability::NegotiateV2,
+    ];
+    let options = ServerOptions {
+        capabilities: negotiate_v2.clone().into(),
+        ..server_options()
+    };
+
+    // Fetch everything, then diverge: the client makes more operations of
+    // its own than one-generation rounds can get past, and the server gets
+    // one more commit.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let client_options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_capabilities(negotiate_v2.clone())
+            .with_op_log(OpGraph::load(&local).await.unwrap());
+        let mut client = ForjjClient::connect(client, client_options).await.unwrap();
+        let request = FetchRequest {
+            have_ops: local.op_head_ids().await.unwrap(),
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let mut pack = Vec::new();
+        let outcome = client.fetch(request, &mut pack).await.unwrap();
+        let entries = PackReader::new(pack.as_slice())
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap();
+        apply_fetch(
+            &mut local,
+            &entries,
+            &outcome.operations,
+            &outcome.response.ops_to_send,
+        )
+        .await
+        .unwrap();
+        client.shutdown().await.unwrap();
+    };
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+    for i in 0..10 {
+        local
+            .set_bookmarks(&[(format!("local-{i}"), tip)], "local bookmark")
+            .unwrap();
+    }
+    upstream.reload().unwrap();
+    upstream
+        .write_commit(
+            &[tip.unwrap()],
+            &[("file.txt", "new".as_bytes())],
+            "new commit",
+        )
+        .await
+        .unwrap();
+
+    let mut commit_counts = Vec::new();
+    for capabilities in [vec![Capability::Operations], negotiate_v2.clone()] {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let serve = serve_session(server, &server_repos, &options);
+        let run = async {
+            let client_options = ClientOptions::new(RepoRef::new("alice", "project"))
+                .with_capabilities(capabilities)
+                .with_op_log(OpGraph::load(&local).await.unwrap());
+            let mut client = ForjjClient::connect(client, client_options).await.unwrap();
+            let request = FetchRequest {
+                have_ops: local.op_head_ids().await.unwrap(),
+                want_refs: vec![],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            };
+            let outcome = client.fetch(request, &mut Vec::new()).await.unwrap();
+            client.shutdown().await.unwrap();
+            outcome.response.commit_count
+        };
+        let (served, count) = tokio::join!(serve, run);
+        served.unwrap();
+        commit_counts.push(count);
+    }
+
+    // Batched, only the new commit is sent, instead of the whole history.
+    let [single, batched] = commit_counts[..] else {
+        unreachable!();
+    };
+    assert_eq!(batched, 1);
+    assert!(single >= 10 * batched, "{single} commits without batches");
+}
+
+#[tokio::test]
+async fn test_ids_in_either_wire_format() {
+    // Ids are hex in JSON and raw bytes in binary; a client that only speaks
+    // JSON syncs with a server that would speak binary all the same.
+    let binary = vec![Capability::Operations, Capability::BinaryFrames];
+    for capabilities in [vec![Capability::Operations], binary] {
+        let server_dir = TempDir::new().unwrap();
+        let client_dir = TempDir::new().unwrap();
+        let server_repos = manager(&server_dir);
+        let client_repos = manager(&client_dir);
+
+        let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+        let main = upstream.head_ids().unwrap()[0];
+        let upstream_op = upstream
+            .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+            .unwrap();
+        let mut local = client_repos.create_repo("alice", "project").unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let options = server_options();
+        let serve = serve_session(server, &server_repos, &options);
+        let run = async {
+            let format = if capabilities.contains(&Capability::BinaryFrames) {
+                WireFormat::Binary
+            } else {
+                WireFormat::Json
+            };
+            let client_options = ClientOptions::new(RepoRef::new("alice", "project"))
+                .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write)
+                .with_capabilities(capabilities.clone())
+                .with_op_heads(local.op_head_ids().await.unwrap())
+                .with_op_log(OpGraph::load(&local).await.unwrap());
+            let mut client = ForjjClient::connect(client, client_options).await.unwrap();
+            assert_eq!(client.negotiated().format, format);
+            assert_eq!(client.hello().server_op_heads, vec![upstream_op]);
+
+            let request = FetchRequest {
+                have_ops: local.op_head_ids().await.unwrap(),
+                want_refs: vec!["main".to_string()],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations: None,
+            };
+            let mut pack = Vec::new();
+            let outcome = client.fetch(request, &mut pack).await.unwrap();
+            let response = outcome.response;
+            assert!(response.ops_to_send.contains(&upstream_op));
+            let entries = PackReader::new(pack.as_slice())
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap();
+            apply_fetch(
+                &mut local,
+                &entries,
+                &outcome.operations,
+                &response.ops_to_send,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                local.bookmark_target("main").unwrap(),
+                BookmarkTarget::Normal(main)
+            );
+
+            // The ids sent back are the ones the server knows.
+            let request = PushRequest {
+                have_ops: local.op_head_ids().await.unwrap(),
+                updates: vec![RefUpdate {
+                    ref_name: "copy".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some(main.to_hex()),
+                    force: false,
+                }],
+                atomic: true,
+                operation_count: 0,
+                request_id: None,
+                push_id: None,
+            };
+            let pack = export_pack(&local, CONTENT_KINDS).await.unwrap();
+            let result = client
+                .push(request, &mut pack.data.as_slice())
+                .await
+                .unwrap();
+            assert_eq!(result.status, PushStatus::Ok, "{format:?}");
+            assert!(result.new_op_head.is_some());
+            client.shutdown().await.unwrap();
+        };
+        let (served, ()) = tokio::join!(serve, run);
+        served.unwrap();
+        upstream.reload().unwrap();
+        assert_eq!(
+            upstream.bookmark_target("copy").unwrap(),
+            BookmarkTarget::Normal(main)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_keepalives_while_packing() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+
+    // Enough objects that packing them outlasts the keepalive interval.
+    let mut tip = None;
+    for i in 0..100 {
+        let parents: Vec<_> = tip.into_iter().collect();
+        let path = format!("file{i}.txt");
+        let content = format!("version {i}");
+        let commit = upstream
+            .write_commit(
+                &parents,
+                &[(path.as_str(), content.as_bytes())],
+                &format!("commit {i}"),
+            )
+            .await
+            .unwrap();
+        tip = Some(commit);
+    }
+    upstream
+        .set_bookmarks(&[("main".to_string(), tip)], "set main")
+        .unwrap();
+
+    for interval in [Some(Duration::from_millis(1)), None] {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let options = ServerOptions {
+            keepalive_interval: interval,
+            ..server_options()
+        };
+        let serve = serve_session(server, &server_repos, &options);
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let run = async {
+            let options = ClientOptions::new(RepoRef::new("alice", "project"));
+            let mut client = ForjjClient::connect(client, options).await.unwrap();
+            let seen = updates.clone();
+            client.set_progress(move |update: &ProgressMessage| {
+                seen.lock().unwrap().push(update.phase)
+            });
+            let outcome = fetch_refs(&mut client, &["main"]).await.unwrap();
+            client.shutdown().await.unwrap();
+            outcome
+        };
+
+        let (served, outcome) = tokio::join!(serve, run);
+        served.unwrap();
+        assert_eq!(outcome.response.commit_count, 100);
+        // Progress only comes before the response, while the pack is built.
+        let updates = updates.lock().unwrap();
+        match interval {
+            Some(_) => {
+                assert!(!updates.is_empty(), "no keepalives while packing");
+                assert!(
+                    updates
+                        .iter()
+                        .all(|phase| *phase == ProgressPhase::Counting)
+                );
+            }
+            None => assert!(updates.is_empty()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_shallow_fetch() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+
+    // A chain of five commits, each rewriting the same file.
+    let mut chain = Vec::new();
+    for i in 1..=5 {
+        let parents: Vec<_> = chain.last().copied().into_iter().collect();
+        let content = format!("version {i}");
+        let commit = upstream
+            .write_commit(
+                &parents,
+                &[("file.txt", content.as_bytes())],
+                &format!("commit {i}"),
+            )
+            .await
+            .unwrap();
+        chain.push(commit);
+    }
+    let tip = chain[4];
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(tip))], "set main")
+        .unwrap();
+    let tip_objects = upstream.commit_objects(&tip).await.unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let shallow = |depth, have_commits| FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string()],
+            depth: Some(depth),
+            have_commits,
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let first = fetch_entries(&mut client, shallow(1, vec![])).await;
+        let deepened = fetch_entries(&mut client, shallow(3, vec![tip])).await;
+        client.shutdown().await.unwrap();
+        (first, deepened)
+    };
+
+    let (served, ((response, entries), (deepened, deepened_entries))) = tokio::join!(serve, run);
+    served.unwrap();
+
+    // Exactly the tip's objects: its commit, trees, and current file.
+    assert_eq!(response.shallow_boundary, vec![tip]);
+    assert_eq!(response.commit_count, 1);
+    assert!(response.ops_to_send.is_empty());
+    let ids = |kind| -> HashSet<ObjectId> {
+        entries
+            .iter()
+            .filter(|entry| entry.kind == kind)
+            .map(|entry| entry.id)
+            .collect()
+    };
+    assert_eq!(ids(ObjectKind::Commit), HashSet::from([tip]));
+    assert_eq!(
+        ids(ObjectKind::Tree),
+        tip_objects.trees.iter().copied().collect()
+    );
+    assert_eq!(
+        ids(ObjectKind::File),
+        tip_objects.files.iter().map(|(_, id)| *id).collect()
+    );
+    assert_eq!(entries.len(), 1 + tip_objects.trees.len() + 1);
+
+    // Deepening from the boundary sends the next two commits only.
+    assert_eq!(deepened.shallow_boundary, vec![chain[2]]);
+    let commits: HashSet<_> = deepened_entries
+        .iter()
+        .filter(|entry| entry.kind == ObjectKind::Commit)
+        .map(|entry| entry.id)
+        .collect();
+    assert_eq!(commits, HashSet::from([chain[3], chain[2]]));
+}
+
+/// A stream that counts the bytes read through it.
+struct CountingStream {
+    inner: DuplexStream,
+    read: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_with_and_without_operations() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+
+    // Two commits and a long operation log moving main between them.
+    let first = upstream
+        .write_commit(&[], &[("file.txt", b"first")], "first")
+        .await
+        .unwrap();
+    let second = upstream
+        .write_commit(&[first], &[("file.txt", b"second")], "second")
+        .await
+        .unwrap();
+    for i in 0..40 {
+        let target = if i % 2 == 0 { first } else { second };
+        upstream
+            .set_bookmarks(&[("main".to_string(), Some(target))], "move main")
+            .unwrap();
+    }
+    let tag_op = upstream
+        .set_tags(&[("v1".to_string(), Some(first))], "tag v1")
+        .unwrap();
+
+    let mut received = Vec::new();
+    for include_operations in [None, Some(false)] {
+        let client_dir = TempDir::new().unwrap();
+        let client_repos = manager(&client_dir);
+        let mut local = client_repos.create_repo("alice", "project").unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let read = Arc::new(AtomicU64::new(0));
+        let client = CountingStream {
+            inner: client,
+            read: read.clone(),
+        };
+        let options = server_options();
+        let serve = serve_session(server, &server_repos, &options);
+
+        let run = async {
+            let options = ClientOptions::new(RepoRef::new("alice", "project"))
+                .with_op_heads(local.op_head_ids().await.unwrap())
+                .with_op_log(OpGraph::load(&local).await.unwrap());
+            let mut client = ForjjClient::connect(client, options).await.unwrap();
+            let request = FetchRequest {
+                have_ops: local.op_head_ids().await.unwrap(),
+                want_refs: vec![],
+                depth: None,
+                have_commits: vec![],
+                path_filters: vec![],
+                have_commits_filter: None,
+                want_commits: vec![],
+                request_id: None,
+                include_operations,
+            };
+            let before = read.load(Ordering::Relaxed);
+            let mut pack = Vec::new();
+            let outcome = client.fetch(request, &mut pack).await.unwrap();
+            let fetched = read.load(Ordering::Relaxed) - before;
+            client.shutdown().await.unwrap();
+            let entries = PackReader::new(pack.as_slice())
+                .await
+                .unwrap()
+                .read_all()
+                .await
+                .unwrap();
+            (outcome, entries, fetched)
+        };
+        let (served, (outcome, entries, fetched)) = tokio::join!(serve, run);
+        served.unwrap();
+
+        let response = &outcome.response;
+        match include_operations {
+            // Operations is mutual, so they are sent by default.
+            None => {
+                assert_eq!(response.mode, FetchMode::Operations);
+                assert!(response.ref_targets.is_empty());
+                assert!(outcome.operations.len() > 40);
+                apply_fetch(
+                    &mut local,
+                    &entries,
+                    &outcome.operations,
+                    &response.ops_to_send,
+                )
+                .await
+                .unwrap();
+            }
+            _ => {
+                assert_eq!(response.mode, FetchMode::CommitsOnly);
+                assert!(outcome.operations.is_empty());
+                assert!(response.ops_to_send.is_empty());
+                assert_eq!(response.commit_count, 2);
+                assert_eq!(
+                    response.ref_targets,
+                    vec![
+                        RefInfo {
+                            name: "main".to_string(),
+                            target: RefTargetWire::Normal(second),
+                            is_tag: false,
+                        },
+                        RefInfo {
+                            name: "tags/v1".to_string(),
+                            target: RefTargetWire::Normal(first),
+                            is_tag: true,
+                        },
+                    ]
+                );
+                apply_fetch_commits(&mut local, &entries, &response.ref_targets).unwrap();
+            }
+        }
+
+        // Either way the client ends up with the refs, but only a full
+        // fetch brings the server's operations along.
+        local.reload().unwrap();
+        assert_eq!(
+            local.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(second)
+        );
+        assert_eq!(
+            local.tag_target("v1").unwrap(),
+            BookmarkTarget::Normal(first)
+        );
+        let log = local.operation_log().await.unwrap();
+        let copied = log.iter().any(|entry| entry.id == tag_op);
+        assert_eq!(copied, include_operations.is_none());
+        received.push(fetched);
+    }
+
+    // Leaving out the operation log makes for a smaller transfer.
+    assert!(received[1] < received[0], "{received:?}");
+}
+
+#[tokio::test]
+async fn test_sparse_fetch() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+
+    let first = upstream
+        .write_commit(
+            &[],
+            &[("src/lib.rs", b"lib v1"), ("docs/guide.md", b"guide v1")],
+            "first",
+        )
+        .await
+        .unwrap();
+    let second = upstream
+        .write_commit(
+            &[first],
+            &[("src/lib.rs", b"lib v2"), ("docs/guide.md", b"guide v2")],
+            "second",
+        )
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(second))], "set main")
+        .unwrap();
+    let file_ids = |prefix: &str, commits: &[CommitObjects]| -> HashSet<ObjectId> {
+        commits
+            .iter()
+            .flat_map(|objects| objects.files.iter())
+            .filter(|(path, _)| path.starts_with(prefix))
+            .map(|(_, id)| *id)
+            .collect()
+    };
+    let first_objects = upstream.commit_objects(&first).await.unwrap();
+    let second_objects = upstream.commit_objects(&second).await.unwrap();
+    let both = [first_objects, second_objects.clone()];
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let sparse = |depth| FetchRequest {
+            have_ops: vec![],
+            want_refs: vec![],
+            depth,
+            have_commits: vec![],
+            path_filters: vec!["src/".to_string()],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let full = fetch_entries(&mut client, sparse(None)).await;
+        let shallow = fetch_entries(&mut client, sparse(Some(1))).await;
+        let invalid = FetchRequest {
+            path_filters: vec!["../src".to_string()],
+            ..sparse(None)
+        };
+        let result = client.fetch(invalid, &mut Vec::new()).await;
+        (full, shallow, result)
+    };
+
+    let (served, ((response, entries), (shallow, shallow_entries), result)) =
+        tokio::join!(serve, run);
+    assert!(matches!(served, Err(ProtocolError::InvalidPathFilter(_))));
+    assert_eq!(
+        result.unwrap_err().remote_code(),
+        Some(ErrorCode::ProtocolViolation)
+    );
+
+    let ids = |entries: &[PackEntry], kind| -> HashSet<ObjectId> {
+        entries
+            .iter()
+            .filter(|entry| entry.kind == kind)
+            .map(|entry| entry.id)
+            .collect()
+    };
+    let promised = |entries: &[PackEntry]| -> HashSet<ObjectId> {
+        entries
+            .iter()
+            .filter(|entry| entry.promised_kind() == Some(ObjectKind::File))
+            .map(|entry| entry.id)
+            .collect()
+    };
+
+    // Every commit and tree, but only the files under src/.
+    assert!(response.filtered);
+    assert!(ids(&entries, ObjectKind::Commit).is_superset(&HashSet::from([first, second])));
+    let trees: HashSet<_> = both
+        .iter()
+        .flat_map(|objects| objects.trees.iter().copied())
+        .collect();
+    assert!(ids(&entries, ObjectKind::Tree).is_superset(&trees));
+    assert_eq!(ids(&entries, ObjectKind::File), file_ids("src/", &both));
+    assert_eq!(promised(&entries), file_ids("docs/", &both));
+
+    // With a depth, only the tip's files are sent or promised.
+    let tip = [second_objects];
+    assert!(shallow.filtered);
+    assert_eq!(shallow.shallow_boundary, vec![second]);
+    assert_eq!(
+        ids(&shallow_entries, ObjectKind::Commit),
+        HashSet::from([second])
+    );
+    assert_eq!(
+        ids(&shallow_entries, ObjectKind::File),
+        file_ids("src/", &tip)
+    );
+    assert_eq!(promised(&shallow_entries), file_ids("docs/", &tip));
+
+    // Promised entries are skipped when the pack is applied.
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    apply_fetch(&mut local, &entries, &[], &[]).await.unwrap();
+    for id in file_ids("src/", &both) {
+        assert!(local.has_raw_object(RawObjectKind::File, &id).unwrap());
+    }
+    for id in file_ids("docs/", &both) {
+        assert!(!local.has_raw_object(RawObjectKind::File, &id).unwrap());
+    }
+}
+
+/// A fetch of just `want_commits`, or of those along with `want_refs`.
+fn want_commits_request(want_refs: &[&str], want_commits: &[&str]) -> FetchRequest {
+    FetchRequest {
+        have_ops: vec![],
+        want_refs: want_refs.iter().map(|name| name.to_string()).collect(),
+        depth: Some(1),
+        have_commits: vec![],
+        path_filters: vec![],
+        have_commits_filter: None,
+        want_commits: want_commits.iter().map(|id| id.to_string()).collect(),
+        request_id: None,
+        include_operations: None,
+        include_operations: None,
+    }
+}
+
+fn sent_commits(entries: &[PackEntry]) -> HashSet<ObjectId> {
+    entries
+        .iter()
+        .filter(|entry| entry.kind == ObjectKind::Commit)
+        .map(|entry| entry.id)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_fetch_by_commit_id() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream
+        .write_commit(&[], &[("file", b"main")], "main")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+    // A visible head no bookmark points at.
+    let loose = upstream
+        .write_commit(&[], &[("file", b"loose")], "loose")
+        .await
+        .unwrap();
+    // A commit that is in the store but not visible.
+    let other_dir = TempDir::new().unwrap();
+    let mut other = manager(&other_dir).create_repo("bob", "other").unwrap();
+    let hidden = other
+        .write_commit(&[], &[("file", b"hidden")], "hidden")
+        .await
+        .unwrap();
+    for kind in [
+        RawObjectKind::Commit,
+        RawObjectKind::Tree,
+        RawObjectKind::File,
+    ] {
+        for id in other.list_raw_objects(kind).unwrap() {
+            let data = other.read_raw_object(kind, &id).unwrap();
+            upstream.write_raw_object(kind, &id, &data).unwrap();
+        }
+    }
+
+    let main_hex = main.to_hex();
+    let loose_hex = loose.to_hex();
+    let hidden_hex = hidden.to_hex();
+    let unknown = "f".repeat(main_hex.len());
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+
+        // A full ID, a prefix, and two IDs that can't be sent. The others
+        // are delivered anyway.
+        let request = want_commits_request(
+            &[],
+            &[&main_hex, &loose_hex[..12], &unknown, &hidden_hex, "main"],
+        );
+        let (response, entries) = fetch_entries(&mut client, request).await;
+        assert_eq!(sent_commits(&entries), HashSet::from([main, loose]));
+        assert_eq!(response.commit_count, 2);
+        assert_eq!(
+            response.want_errors,
+            vec![
+                WantError {
+                    want: unknown.clone(),
+                    reason: WantReason::NotFound,
+                },
+                WantError {
+                    want: hidden_hex.clone(),
+                    reason: WantReason::Hidden,
+                },
+                WantError {
+                    want: "main".to_string(),
+                    reason: WantReason::Invalid,
+                },
+            ]
+        );
+
+        // Wanted commits are sent along with the refs' commits.
+        let request = want_commits_request(&["main"], &[&loose_hex[..12]]);
+        let (response, entries) = fetch_entries(&mut client, request).await;
+        assert_eq!(sent_commits(&entries), HashSet::from([main, loose]));
+        assert!(response.want_errors.is_empty());
+
+        // Nothing to send is not an error either.
+        let request = want_commits_request(&[], &[&unknown]);
+        let outcome = client.fetch(request, &mut Vec::new()).await.unwrap();
+        assert!(!outcome.response.pack_follows);
+        assert_eq!(outcome.response.want_errors.len(), 1);
+        client.shutdown().await.unwrap();
+    };
+
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+
+    // A server that allows it serves hidden commits too.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        fetch_hidden_commits: true,
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let request = want_commits_request(&[], &[&hidden_hex]);
+        let (response, entries) = fetch_entries(&mut client, request).await;
+        assert_eq!(sent_commits(&entries), HashSet::from([hidden]));
+        assert!(response.want_errors.is_empty());
+        client.shutdown().await.unwrap();
+    };
+
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+}
+
+#[tokio::test]
+async fn test_stale_push_is_rejected() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream.head_ids().unwrap()[0];
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+
+        // main exists, so a push that expects to create it is stale.
+        let request = PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "main".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: None,
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let result = client.push(request, &mut tokio::io::empty()).await.unwrap();
+        client.shutdown().await.unwrap();
+        result
+    };
+
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(result.status, PushStatus::Rejected);
+    assert_eq!(result.ref_results[0].status, RefStatus::Stale);
+    assert_eq!(
+        result.ref_results[0].reason,
+        Some(RefReason::Stale {
+            actual: RefTargetWire::Normal(main)
+        })
+    );
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(main)
+    );
+}
+
+#[tokio::test]
+async fn test_resolve_conflicted_bookmark() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let base = upstream.head_ids().unwrap()[0];
+    let left = upstream.write_commit(&[base], &[], "left").await.unwrap();
+    let right = upstream.write_commit(&[base], &[], "right").await.unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(base))], "set main")
+        .unwrap();
+    // Concurrent moves of main from base leave it conflicted.
+    let mut other = server_repos.open_repo("alice", "project").unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(left))], "main to left")
+        .unwrap();
+    other
+        .set_bookmarks(&[("main".to_string(), Some(right))], "main to right")
+        .unwrap();
+    let mut adds = vec![left, right];
+    adds.sort();
+    let conflict = RefTargetWire::Conflicted {
+        adds,
+        removes: vec![base],
+    };
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let refs = client.list_refs(&["main"]).await.unwrap();
+        let advertised = refs[0].target.clone();
+
+        let resolve = |old_id| PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "main".to_string(),
+                old_id,
+                new_id: Some(left.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        // Expecting one side of the conflict isn't enough.
+        let partial = client
+            .push(
+                resolve(RefTargetWire::Normal(left)),
+                &mut tokio::io::empty(),
+            )
+            .await
+            .unwrap();
+        let resolved = client
+            .push(resolve(advertised.clone()), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        (advertised, partial, resolved)
+    };
+
+    let (served, (advertised, partial, resolved)) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(advertised, conflict);
+
+    assert_eq!(partial.status, PushStatus::Conflict);
+    assert_eq!(partial.ref_results[0].status, RefStatus::Conflict);
+    assert_eq!(
+        partial.ref_results[0].reason,
+        Some(RefReason::Stale { actual: conflict })
+    );
+
+    assert_eq!(resolved.status, PushStatus::Ok);
+    upstream.reload().unwrap();
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(left)
+    );
+}
+
+#[tokio::test]
+async fn test_atomic_push() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream.head_ids().unwrap()[0];
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"))
+            .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+
+        // Creating feature is fine, but main already exists.
+        let request = |atomic| PushRequest {
+            have_ops: vec![],
+            updates: vec![
+                RefUpdate {
+                    ref_name: "feature".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some(main.to_hex()),
+                    force: false,
+                },
+                RefUpdate {
+                    ref_name: "main".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: None,
+                    force: false,
+                },
+            ],
+            atomic,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let atomic = client
+            .push(request(true), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        let independent = client
+            .push(request(false), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        (atomic, independent)
+    };
+
+    let (served, (atomic, independent)) = tokio::join!(serve, run);
+    served.unwrap();
+
+    // The atomic push is rolled back, naming the ref that failed.
+    assert_eq!(atomic.status, PushStatus::Rejected);
+    assert_eq!(atomic.new_op_head, None);
+    assert_eq!(atomic.ref_results[0].status, RefStatus::Rejected);
+    assert!(
+        atomic.ref_results[0]
+            .message
+            .as_deref()
+            .unwrap()
+            .contains("main")
+    );
+    assert_eq!(atomic.ref_results[1].status, RefStatus::Stale);
+
+    // Without atomic, feature is created on its own.
+    assert_eq!(independent.status, PushStatus::Rejected);
+    assert!(independent.new_op_head.is_some());
+    assert_eq!(independent.ref_results[0].status, RefStatus::Ok);
+    assert_eq!(independent.ref_results[1].status, RefStatus::Stale);
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(
+        upstream.bookmark_target("feature").unwrap(),
+        BookmarkTarget::Normal(main)
+    );
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(main)
+    );
+}
+
+#[tokio::test]
+async fn test_atomic_push_with_operations() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream.head_ids().unwrap()[0];
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+    let op_heads = upstream.op_head_ids().await.unwrap();
+
+    // The client's operations create feature, which passes its checks.
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    clone_raw(&upstream, &mut local);
+    let feature = local
+        .write_commit(&[main], &[("file", b"feature")], "feature")
+        .await
+        .unwrap();
+    local
+        .set_bookmarks(&[("feature".to_string(), Some(feature))], "set feature")
+        .unwrap();
+    let head = local.current_op_id().unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let operations = missing_operations(&client, &local).await;
+        let request = PushRequest {
+            have_ops: local.op_head_ids().await.unwrap(),
+            updates: vec![RefUpdate {
+                ref_name: "main".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: None,
+                force: false,
+            }],
+            atomic: true,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let result = client
+            .push_from_repo(request, &local, &operations)
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        result
+    };
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+
+    // The stale update rolls back the operations with it.
+    assert_eq!(result.status, PushStatus::Rejected);
+    assert_eq!(result.new_op_head, None);
+    assert_eq!(result.ref_results.len(), 1);
+    assert_eq!(result.ref_results[0].status, RefStatus::Stale);
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(upstream.op_head_ids().await.unwrap(), op_heads);
+    assert_eq!(
+        upstream.bookmark_target("feature").unwrap(),
+        BookmarkTarget::Absent
+    );
+    let log: HashSet<_> = upstream
+        .operation_log()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.id)
+        .collect();
+    assert!(!log.contains(&head));
+}
+
+/// Refuses force pushes to every repository.
+struct DenyForcePush;
+
+impl PushPolicy for DenyForcePush {
+    fn deny_force_push(&self, _repo: &RepoRef) -> bool {
+        true
+    }
+}
+
+/// Protects main and allows two bookmarks.
+struct ProtectMain;
+
+impl PushPolicy for ProtectMain {
+    fn deny_force_push(&self, _repo: &RepoRef) -> bool {
+        false
+    }
+
+    fn protects_bookmark(&self, _repo: &RepoRef, bookmark: &str) -> bool {
+        bookmark == "main"
+    }
+
+    fn max_bookmarks(&self, _repo: &RepoRef) -> Option<u64> {
+        Some(2)
+    }
+}
+
+/// Connect to alice/project with write access.
+async fn connect_writer(stream: DuplexStream) -> ForjjClient<DuplexStream> {
+    let options = ClientOptions::new(RepoRef::new("alice", "project"))
+        .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write);
+    ForjjClient::connect(stream, options).await.unwrap()
+}
+
+/// Push a single update of main, with no objects.
+async fn push_main(
+    client: &mut ForjjClient<DuplexStream>,
+    old: ObjectId,
+    new: ObjectId,
+    force: bool,
+) -> RefResult {
+    let request = PushRequest {
+        have_ops: vec![],
+        updates: vec![RefUpdate {
+            ref_name: "main".to_string(),
+            old_id: RefTargetWire::Normal(old),
+            new_id: Some(new.to_hex()),
+            force,
+        }],
+        atomic: false,
+        operation_count: 0,
+        request_id: None,
+        push_id: None,
+    };
+    let result = client.push(request, &mut tokio::io::empty()).await.unwrap();
+    result.ref_results.into_iter().next().unwrap()
+}
+
+#[tokio::test]
+async fn test_force_push() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let base = upstream
+        .write_commit(&[], &[("file", b"base")], "base")
+        .await
+        .unwrap();
+    let tip = upstream
+        .write_commit(&[base], &[("file", b"tip")], "tip")
+        .await
+        .unwrap();
+    let side = upstream
+        .write_commit(&[base], &[("file", b"side")], "side")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(base))], "set main")
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let fast_forward = push_main(&mut client, base, tip, false).await;
+        let not_forced = push_main(&mut client, tip, side, false).await;
+        let forced = push_main(&mut client, tip, side, true).await;
+        client.shutdown().await.unwrap();
+        (fast_forward, not_forced, forced)
+    };
+    let (served, (fast_forward, not_forced, forced)) = tokio::join!(serve, run);
+    served.unwrap();
+
+    assert_eq!(fast_forward.status, RefStatus::Ok);
+    assert_eq!(not_forced.status, RefStatus::Stale);
+    assert_eq!(
+        not_forced.reason,
+        Some(RefReason::NotFastForward { behind: 1 })
+    );
+    assert!(not_forced.message.unwrap().contains("fast-forward"));
+    assert_eq!(forced.status, RefStatus::Ok);
+    assert_eq!(forced.reason, None);
+
+    // A policy that denies force pushes overrides the flag.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let mut options = server_options();
+    options.push_policy = Arc::new(DenyForcePush);
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let denied = push_main(&mut client, side, tip, true).await;
+        client.shutdown().await.unwrap();
+        denied
+    };
+    let (served, denied) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(denied.status, RefStatus::Rejected);
+    assert_eq!(denied.reason, Some(RefReason::Protected));
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(side)
+    );
+}
+
+#[tokio::test]
+async fn test_ref_reasons() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let head = upstream.head_ids().unwrap()[0];
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+        .unwrap();
+
+    let create = |name: &str| RefUpdate {
+        ref_name: name.to_string(),
+        old_id: RefTargetWire::Absent,
+        new_id: Some(head.to_hex()),
+        force: false,
+    };
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        push_policy: Arc::new(ProtectMain),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let request = PushRequest {
+            have_ops: vec![],
+            updates: vec![
+                RefUpdate {
+                    ref_name: "main".to_string(),
+                    old_id: RefTargetWire::Normal(head),
+                    new_id: None,
+                    force: false,
+                },
+                create("bad name"),
+                create("feature"),
+                create("another"),
+            ],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let result = client.push(request, &mut tokio::io::empty()).await.unwrap();
+        client.shutdown().await.unwrap();
+        result
+    };
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+
+    let reasons: Vec<_> = result
+        .ref_results
+        .iter()
+        .map(|result| (result.status, result.reason.clone()))
+        .collect();
+    assert_eq!(
+        reasons,
+        [
+            (RefStatus::Rejected, Some(RefReason::Protected)),
+            (
+                RefStatus::Rejected,
+                Some(RefReason::InvalidName {
+                    why: "bookmark name contains invalid character ' '".to_string()
+                })
+            ),
+            (RefStatus::Ok, None),
+            (
+                RefStatus::Rejected,
+                Some(RefReason::QuotaExceeded {
+                    limit: 2,
+                    attempted: 3
+                })
+            ),
+        ]
+    );
+    assert!(
+        result
+            .ref_results
+            .iter()
+            .all(|result| { result.message.is_some() == (result.status != RefStatus::Ok) })
+    );
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(upstream.bookmark_names(), ["feature", "main"]);
+}
+
+/// Copy every object and operation of `from` into `to`, and adopt the
+/// operation `from` is at.
+fn clone_raw(from: &Repository, to: &mut Repository) {
+    for kind in RawObjectKind::ALL {
+        for id in from.list_raw_objects(kind).unwrap() {
+            let data = from.read_raw_object(kind, &id).unwrap();
+            to.write_raw_object(kind, &id, &data).unwrap();
+        }
+    }
+    to.add_op_head(&from.current_op_id().unwrap()).unwrap();
+}
+
+/// The operations of `local` that the server is missing.
+async fn missing_operations(
+    client: &ForjjClient<DuplexStream>,
+    local: &Repository,
+) -> Vec<OperationRecord> {
+    let graph = OpGraph::load(local).await.unwrap();
+    let missing = graph.missing(&client.hello().server_op_heads);
+    let operations = export_operations(local, &graph, &missing, true).unwrap();
+    assert!(!operations.is_empty());
+    operations
+}
+
+/// Push `operations` from `local`, with `updates`.
+async fn push_operations(
+    client: &mut ForjjClient<DuplexStream>,
+    local: &Repository,
+    operations: &[OperationRecord],
+    updates: Vec<RefUpdate>,
+) -> Result<PushResult, ProtocolError> {
+    let request = PushRequest {
+        have_ops: local.op_head_ids().await.unwrap(),
+        updates,
+        atomic: false,
+        operation_count: 0,
+        request_id: None,
+        push_id: None,
+    };
+    client.push_from_repo(request, local, operations).await
+}
+
+#[tokio::test]
+async fn test_pushed_operations_are_checked() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let base = upstream
+        .write_commit(&[], &[("file", b"base")], "base")
+        .await
+        .unwrap();
+    let tip = upstream
+        .write_commit(&[base], &[("file", b"tip")], "tip")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(tip))], "set main")
+        .unwrap();
+    let op_heads = upstream.op_head_ids().await.unwrap();
+
+    // The client's own operation moves main back, which no update mentions.
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    clone_raw(&upstream, &mut local);
+    local
+        .set_bookmarks(&[("main".to_string(), Some(base))], "move main back")
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        push_policy: Arc::new(ProtectMain),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let operations = missing_operations(&client, &local).await;
+        let result = push_operations(&mut client, &local, &operations, vec![]).await;
+        client.shutdown().await.unwrap();
+        result.unwrap()
+    };
+    let (served, protected) = tokio::join!(serve, run);
+    served.unwrap();
+
+    // Refused, the operations stay out of the log.
+    let refused = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(refused.op_head_ids().await.unwrap(), op_heads);
+    assert_eq!(
+        refused.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(tip)
+    );
+
+    // An operation that doesn't hash to its id is refused before it's stored.
+    let head = local.current_op_id().unwrap();
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let mut operations = missing_operations(&client, &local).await;
+        let record = operations
+            .iter_mut()
+            .find(|record| record.id == head)
+            .unwrap();
+        let offset = record
+            .data
+            .windows(b"move main back".len())
+            .position(|window| window == b"move main back")
+            .unwrap();
+        record.data[offset] = b'M';
+        push_operations(&mut client, &local, &operations, vec![]).await
+    };
+    let (served, forged) = tokio::join!(serve, run);
+    match served {
+        Err(ProtocolError::Pack(PackError::ObjectHashMismatch { kind, expected, .. })) => {
+            assert_eq!(kind, ObjectKind::Operation);
+            assert_eq!(expected, head);
+        }
+        served => panic!("{served:?}"),
+    }
+    assert!(forged.is_err());
+    let refused = server_repos.open_repo("alice", "project").unwrap();
+    assert!(
+        !refused
+            .has_raw_object(RawObjectKind::Operation, &head)
+            .unwrap()
+    );
+    assert_eq!(refused.op_head_ids().await.unwrap(), op_heads);
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let operations = missing_operations(&client, &local).await;
+        let not_forced = push_operations(&mut client, &local, &operations, vec![])
+            .await
+            .unwrap();
+        // Forcing main in the same push lets the operations through.
+        let force = RefUpdate {
+            ref_name: "main".to_string(),
+            old_id: RefTargetWire::Normal(base),
+            new_id: Some(base.to_hex()),
+            force: true,
+        };
+        let forced = push_operations(&mut client, &local, &operations, vec![force])
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        (not_forced, forced)
+    };
+    let (served, (not_forced, forced)) = tokio::join!(serve, run);
+    served.unwrap();
+
+    assert_eq!(protected.status, PushStatus::Rejected);
+    assert_eq!(protected.new_op_head, None);
+    assert_eq!(protected.ref_results.len(), 1);
+    assert_eq!(protected.ref_results[0].ref_name, "main");
+    assert_eq!(protected.ref_results[0].reason, Some(RefReason::Protected));
+
+    assert_eq!(not_forced.status, PushStatus::Rejected);
+    assert_eq!(not_forced.new_op_head, None);
+    assert_eq!(not_forced.ref_results.len(), 1);
+    assert_eq!(not_forced.ref_results[0].status, RefStatus::Stale);
+    assert_eq!(
+        not_forced.ref_results[0].reason,
+        Some(RefReason::NotFastForward { behind: 1 })
+    );
+
+    assert_eq!(forced.status, PushStatus::Ok);
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(base)
+    );
+    let log: HashSet<_> = upstream
+        .operation_log()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.id)
+        .collect();
+    assert!(log.contains(&head));
+    assert!(op_heads.iter().all(|head| log.contains(head)));
+}
+
+/// A filter that claims to contain every commit.
+fn saturated_filter() -> CommitFilter {
+    CommitFilter {
+        bits: 8,
+        hashes: 1,
+        data: vec![0xff],
+    }
+}
+
+#[tokio::test]
+async fn test_commit_filter_fetch_false_positives() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let main = upstream
+        .write_commit(&[], &[("file", b"main")], "main")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(main))], "set main")
+        .unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let request = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["main".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: Some(saturated_filter()),
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        let mut pack = Vec::new();
+        let outcome = client.fetch(request, &mut pack).await.unwrap();
+        let entries = PackReader::new(pack.as_slice())
+            .await
+            .unwrap()
+            .read_all()
+            .await
+            .unwrap();
+
+        // Every commit was promised, and the client has none of them.
+        assert!(entries.iter().all(|entry| entry.kind != ObjectKind::Commit));
+        import_objects(&local, &entries, CONTENT_KINDS).unwrap();
+        let missing = missing_commits(&local, &entries).unwrap();
+        assert!(missing.contains(&main));
+
+        let request = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec![],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: missing.iter().map(|id| id.to_hex()).collect(),
+            request_id: None,
+            include_operations: None,
+        };
+        let (response, wanted) = fetch_entries(&mut client, request).await;
+        assert_eq!(response.commit_count, missing.len() as u64);
+        import_objects(&local, &wanted, CONTENT_KINDS).unwrap();
+        assert!(missing_commits(&local, &entries).unwrap().is_empty());
+
+        apply_fetch(
+            &mut local,
+            &entries,
+            &outcome.operations,
+            &outcome.response.ops_to_send,
+        )
+        .await
+        .unwrap();
+        client.shutdown().await.unwrap();
+    };
+
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(
+        local.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(main)
+    );
+}
+
+#[tokio::test]
+async fn test_commit_filter_push_false_positives() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    upstream
+        .write_commit(&[], &[("file", b"upstream")], "upstream")
+        .await
+        .unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let feature = local
+        .write_commit(&[], &[("file", b"feature")], "feature")
+        .await
+        .unwrap();
+
+    // A one-bit filter is saturated by the server's first commit, so the
+    // client promises every commit and the server has to ask for them.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        commit_filter_bits: Some(1),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let request = PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "feature".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: Some(feature.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        let result = client.push_from_repo(request, &local, &[]).await.unwrap();
+        client.shutdown().await.unwrap();
+        result
+    };
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(result.status, PushStatus::Ok);
+
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert!(upstream.has_commit(&feature));
+    assert_eq!(
+        upstream.bookmark_target("feature").unwrap(),
+        BookmarkTarget::Normal(feature)
+    );
+}
+
+#[tokio::test]
+async fn test_push_over_server_limit() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    server_repos.create_repo("alice", "project").unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let feature = local
+        .write_commit(&[], &[("file", &[7; 4096])], "feature")
+        .await
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        pack_limits: PackLimits {
+            max_pack_size: 1024,
+            ..PackLimits::default()
+        },
+        extensions: BTreeMap::from([("region".to_string(), "eu".to_string())]),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        assert_eq!(client.limits().max_pack_size, Some(1024));
+        assert_eq!(client.limits().anonymous_read, Some(true));
+        assert!(!client.limits().compression.is_empty());
+        assert_eq!(client.hello().extensions["region"], "eu");
+        assert!(client.check_pack_size(1024).is_ok());
+
+        let request = PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "feature".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: Some(feature.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        client.push_from_repo(request, &local, &[]).await
+    };
+    let (served, result) = tokio::join!(serve, run);
+
+    // The client gives up before sending the pack, and says why.
+    assert!(matches!(
+        result,
+        Err(ProtocolError::PackOverServerLimit { max: 1024, .. })
+    ));
+    assert_eq!(
+        served.unwrap_err().remote_code(),
+        Some(ErrorCode::QuotaExceeded)
+    );
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert!(!upstream.has_commit(&feature));
+}
+
+#[tokio::test]
+async fn test_repeated_push_id() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let upstream = server_repos.create_repo("alice", "project").unwrap();
+    let ops_before = upstream.operation_log().await.unwrap().len();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let feature = local
+        .write_commit(&[], &[("file", b"feature")], "feature")
+        .await
+        .unwrap();
+    let request = PushRequest {
+        have_ops: vec![],
+        updates: vec![RefUpdate {
+            ref_name: "feature".to_string(),
+            old_id: RefTargetWire::Absent,
+            new_id: Some(feature.to_hex()),
+            force: false,
+        }],
+        atomic: false,
+        operation_count: 0,
+        request_id: None,
+        push_id: Some(new_push_id()),
+    };
+
+    // Each push in a session of its own, as a retry after a dropped
+    // connection would be.
+    let mut results = Vec::new();
+    for _ in 0..2 {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let options = server_options();
+        let serve = serve_session(server, &server_repos, &options);
+        let run = async {
+            let mut client = connect_writer(client).await;
+            let result = client
+                .push_from_repo(request.clone(), &local, &[])
+                .await
+                .unwrap();
+            client.shutdown().await.unwrap();
+            result
+        };
+        let (served, result) = tokio::join!(serve, run);
+        served.unwrap();
+        results.push(result);
+    }
+
+    // Without the push log, the retry would be stale: feature already
+    // exists.
+    assert_eq!(results[0].status, PushStatus::Ok);
+    assert_eq!(results[0].ref_results[0].status, RefStatus::Ok);
+    assert_eq!(results[1], results[0]);
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(
+        upstream.operation_log().await.unwrap().len(),
+        ops_before + 1
+    );
+
+    // A different id is a different push.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let request = PushRequest {
+            push_id: Some(new_push_id()),
+            ..request.clone()
+        };
+        let result = client.push_from_repo(request, &local, &[]).await.unwrap();
+        client.shutdown().await.unwrap();
+        result
+    };
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(result.ref_results[0].status, RefStatus::Stale);
+}
+
+#[tokio::test]
+async fn test_corrupted_push_pack_changes_nothing() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let upstream = server_repos.create_repo("alice", "project").unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let feature = local
+        .write_commit(&[], &[("file", b"feature")], "feature")
+        .await
+        .unwrap();
+    let pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let files_before = upstream.list_raw_objects(RawObjectKind::File).unwrap();
+
+    // A file whose contents no longer match its native id, in a pack whose
+    // trailer does match.
+    let mut entries = PackReader::new(pack.as_slice())
+        .await
+        .unwrap()
+        .read_all()
+        .await
+        .unwrap();
+    let file = entries
+        .iter_mut()
+        .find(|entry| entry.kind == ObjectKind::File && entry.data == b"feature")
+        .unwrap();
+    file.data[0] ^= 0x01;
+    let flipped_object = repack(&entries).await;
+    let mut flipped_trailer = pack.clone();
+    *flipped_trailer.last_mut().unwrap() ^= 0x01;
+    let truncated = pack[..pack.len() / 2].to_vec();
+
+    for (name, bad) in [
+        ("object", flipped_object),
+        ("trailer", flipped_trailer),
+        ("truncated", truncated),
+    ] {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let options = server_options();
+        let serve = serve_session(server, &server_repos, &options);
+        let run = async {
+            let mut client = connect_writer(client).await;
+            let request = PushRequest {
+                have_ops: vec![],
+                updates: vec![RefUpdate {
+                    ref_name: "feature".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some(feature.to_hex()),
+                    force: false,
+                }],
+                atomic: false,
+                operation_count: 0,
+                request_id: None,
+                push_id: None,
+            };
+            client.push(request, &mut bad.as_slice()).await
+        };
+        let (served, result) = tokio::join!(serve, run);
+        assert!(
+            matches!(served, Err(ProtocolError::Pack(_))),
+            "{name}: {served:?}"
+        );
+        if name == "object" {
+            assert!(
+                matches!(
+                    served,
+                    Err(ProtocolError::Pack(PackError::ObjectHashMismatch { .. }))
+                ),
+                "{served:?}"
+            );
+        }
+        assert!(result.is_err(), "{name}: push succeeded");
+
+        // Nothing from the bad pack was stored, and no bookmark moved.
+        let upstream = server_repos.open_repo("alice", "project").unwrap();
+        assert!(!upstream.has_commit(&feature), "{name}");
+        assert_eq!(
+            upstream.list_raw_objects(RawObjectKind::File).unwrap(),
+            files_before,
+            "{name}"
+        );
+        assert_eq!(
+            upstream.bookmark_target("feature").unwrap(),
+            BookmarkTarget::Absent,
+            "{name}"
+        );
+    }
+}
+
+/// Records the sessions the server aborts.
+#[derive(Default)]
+struct Aborts(Mutex<Vec<SessionAbort>>);
+
+impl SessionMonitor for Aborts {
+    fn aborted(&self, abort: &SessionAbort) {
+        self.0.lock().unwrap().push(abort.clone());
+    }
+}
+
+/// Records the bookmarks pushes move.
+#[derive(Default)]
+struct Moves(Mutex<Vec<BookmarksMoved>>);
+
+impl SessionMonitor for Moves {
+    fn aborted(&self, _abort: &SessionAbort) {}
+
+    fn bookmarks_moved(&self, moved: &BookmarksMoved) {
+        self.0.lock().unwrap().push(moved.clone());
+    }
+}
+
+#[tokio::test]
+async fn test_monitor_told_of_moves() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let base = upstream
+        .write_commit(&[], &[("file", b"base")], "base")
+        .await
+        .unwrap();
+    let tip = upstream
+        .write_commit(&[base], &[("file", b"tip")], "tip")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(base))], "set main")
+        .unwrap();
+
+    let moves = Arc::new(Moves::default());
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        monitor: Some(moves.clone()),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let moved = push_main(&mut client, base, tip, false).await;
+        // A refused push moves nothing.
+        let refused = push_main(&mut client, base, tip, false).await;
+        client.shutdown().await.unwrap();
+        (moved, refused)
+    };
+    let (served, (moved, refused)) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(moved.status, RefStatus::Ok);
+    assert_eq!(refused.status, RefStatus::Stale);
+
+    let moves = moves.0.lock().unwrap();
+    assert_eq!(moves.len(), 1);
+    assert_eq!(moves[0].repo, RepoRef::new("alice", "project"));
+    assert_eq!(moves[0].identity.as_deref(), Some("alice"));
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(moves[0].operation, upstream.current_op_id().unwrap());
+    assert_eq!(moves[0].updates.len(), 1);
+    assert_eq!(moves[0].updates[0].name, "main");
+    assert_eq!(moves[0].updates[0].expected, BookmarkTarget::Normal(base));
+    assert_eq!(moves[0].updates[0].target, Some(tip));
+}
+
+/// Records when sessions start and end, and the requests they serve.
+#[derive(Default)]
+struct Served(Mutex<Vec<String>>);
+
+impl SessionMonitor for Served {
+    fn aborted(&self, _abort: &SessionAbort) {}
+
+    fn session_started(&self, repo: &RepoRef) {
+        self.0.lock().unwrap().push(format!("started {repo}"));
+    }
+
+    fn session_ended(&self, repo: &RepoRef) {
+        self.0.lock().unwrap().push(format!("ended {repo}"));
+    }
+
+    fn request_served(&self, served: &RequestServed) {
+        assert!(served.bytes_received > 0 && served.bytes_sent > 0);
+        self.0.lock().unwrap().push(served.request.to_string());
+    }
+}
+
+#[tokio::test]
+async fn test_monitor_told_of_requests() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let mut upstream = server_repos.create_repo("alice", "project").unwrap();
+    let base = upstream
+        .write_commit(&[], &[("file", b"base")], "base")
+        .await
+        .unwrap();
+    upstream
+        .set_bookmarks(&[("main".to_string(), Some(base))], "set main")
+        .unwrap();
+
+    let served = Arc::new(Served::default());
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = ServerOptions {
+        monitor: Some(served.clone()),
+        ..server_options()
+    };
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        fetch_refs(&mut client, &["main"]).await.unwrap();
+        push_main(&mut client, base, base, false).await;
+        client.shutdown().await.unwrap();
+    };
+    let (result, ()) = tokio::join!(serve, run);
+    result.unwrap();
+    assert_eq!(
+        *served.0.lock().unwrap(),
+        [
+            "started alice/project",
+            "Fetch",
+            "Push",
+            "ended alice/project"
+        ]
+    );
+}
+
+/// Files under `dir` left behind by unfinished writes.
+fn temp_files(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            found.extend(temp_files(&path));
+        } else if path.extension().is_some_and(|extension| extension == "tmp") {
+            found.push(path);
+        }
+    }
+    found
+}
+
+#[tokio::test]
+async fn test_client_gone_mid_push() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let upstream = server_repos.create_repo("alice", "project").unwrap();
+    let op_heads = upstream.op_head_ids().await.unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    // Enough content that half the pack spans many chunks.
+    let content: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect();
+    let feature = local
+        .write_commit(&[], &[("file", content.as_slice())], "feature")
+        .await
+        .unwrap();
+    let pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let half = &pack[..pack.len() / 2];
+
+    // Either the client's connection closes, or it goes quiet with the
+    // connection still open.
+    for vanish in [true, false] {
+        let aborts = Arc::new(Aborts::default());
+        let (client, server) = tokio::io::duplex(256 * 1024);
+        let options = ServerOptions {
+            stall_timeout: Some(Duration::from_millis(200)),
+            monitor: Some(aborts.clone()),
+            ..server_options()
+        };
+        let serve = serve_session(server, &server_repos, &options);
+
+        // Half the pack, then nothing for as long as `_feed` is open.
+        let (_feed, rest) = tokio::io::duplex(1);
+        let mut source = half.chain(rest);
+        let run = async {
+            let options = ClientOptions {
+                chunk_size: 1024,
+                ..ClientOptions::new(RepoRef::new("alice", "project"))
+                    .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write)
+            };
+            let mut client = ForjjClient::connect(client, options).await.unwrap();
+            let request = PushRequest {
+                have_ops: vec![],
+                updates: vec![RefUpdate {
+                    ref_name: "feature".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some(feature.to_hex()),
+                    force: false,
+                }],
+                atomic: false,
+                operation_count: 0,
+                request_id: None,
+                push_id: None,
+            };
+            let pushing = client.push(request, &mut source);
+            if vanish {
+                let _ = tokio::time::timeout(Duration::from_millis(50), pushing).await;
+                drop(client);
+            } else {
+                let _ = pushing.await;
+            }
+            std::future::pending::<()>().await
+        };
+        let served = tokio::select! {
+            served = serve => served,
+            () = run => unreachable!(),
+        };
+        let error = served.unwrap_err();
+
+        let aborts = aborts.0.lock().unwrap();
+        let [abort] = aborts.as_slice() else {
+            panic!("vanish {vanish}: {error} aborted {} sessions", aborts.len());
+        };
+        assert_eq!(abort.repo, RepoRef::new("alice", "project"));
+        assert_eq!(abort.identity.as_deref(), Some("alice"));
+        assert_eq!(abort.request, Some("Push"));
+        assert_eq!(abort.reason, error.to_string());
+        assert!(abort.bytes_received > half.len() as u64 / 2, "{abort:?}");
+        assert!(abort.bytes_sent > 0);
+
+        // No new operation, no commit, and nothing half-written.
+        let upstream = server_repos.open_repo("alice", "project").unwrap();
+        assert_eq!(upstream.op_head_ids().await.unwrap(), op_heads);
+        assert!(!upstream.has_commit(&feature));
+        assert_eq!(temp_files(server_dir.path()), Vec::<PathBuf>::new());
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_sessions() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let upstream = server_repos.create_repo("alice", "project").unwrap();
+    let op_heads = upstream.op_head_ids().await.unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let content: Vec<u8> = (0..64 * 1024u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect();
+    let feature = local
+        .write_commit(&[], &[("file", content.as_slice())], "feature")
+        .await
+        .unwrap();
+    let pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let (first, second) = pack.split_at(pack.len() / 2);
+
+    // Draining lets the push in progress finish; aborting stops it.
+    for abort in [true, false] {
+        let shutdown = SessionShutdown::new();
+        let (client, server) = tokio::io::duplex(256 * 1024);
+        let options = ServerOptions {
+            shutdown: shutdown.clone(),
+            ..server_options()
+        };
+        let serve = serve_session(server, &server_repos, &options);
+
+        // Half the pack, then the rest once the server is shutting down.
+        let (mut feed, rest) = tokio::io::duplex(256 * 1024);
+        let mut source = first.chain(rest);
+        let run = async {
+            let options = ClientOptions {
+                chunk_size: 1024,
+                ..ClientOptions::new(RepoRef::new("alice", "project"))
+                    .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write)
+            };
+            let mut client = ForjjClient::connect(client, options).await.unwrap();
+            let request = PushRequest {
+                have_ops: vec![],
+                updates: vec![RefUpdate {
+                    ref_name: "feature".to_string(),
+                    old_id: RefTargetWire::Absent,
+                    new_id: Some(feature.to_hex()),
+                    force: false,
+                }],
+                atomic: false,
+                operation_count: 0,
+                request_id: None,
+                push_id: None,
+            };
+            let shut_down = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if abort {
+                    shutdown.abort();
+                } else {
+                    shutdown.drain();
+                    feed.write_all(second).await.unwrap();
+                    drop(feed);
+                }
+            };
+            let (pushed, ()) = tokio::join!(client.push(request, &mut source), shut_down);
+            if !abort {
+                pushed.unwrap();
+            }
+            std::future::pending::<()>().await
+        };
+        let served = tokio::select! {
+            served = serve => served,
+            () = run => unreachable!(),
+        };
+        let error = served.unwrap_err();
+        assert!(matches!(error, ProtocolError::ShuttingDown), "{error}");
+        let message = error.to_error_message();
+        assert_eq!(message.code, ErrorCode::Internal);
+        assert!(message.retryable);
+
+        let upstream = server_repos.open_repo("alice", "project").unwrap();
+        assert_eq!(upstream.has_commit(&feature), !abort);
+        if abort {
+            assert_eq!(upstream.op_head_ids().await.unwrap(), op_heads);
+        }
+    }
+}
+
+/// Connect as alice to `repo`, asking for it to be created if it doesn't
+/// exist.
+async fn connect_creating(
+    stream: DuplexStream,
+    repo: RepoRef,
+) -> Result<ForjjClient<DuplexStream>, ProtocolError> {
+    let options = ClientOptions::new(repo)
+        .with_auth(Auth::BearerToken(TOKEN.to_string()), AccessLevel::Write)
+        .with_create_if_missing();
+    ForjjClient::connect(stream, options).await
+}
+
+#[tokio::test]
+async fn test_create_on_push() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    let mut local = client_repos.create_repo("alice", "new").unwrap();
+    let feature = local
+        .write_commit(&[], &[("file", b"feature")], "feature")
+        .await
+        .unwrap();
+    let pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let allowed = ServerOptions {
+        create_on_push: true,
+        ..server_options()
+    };
+
+    // Only when the server allows it, and only under the client's own name.
+    for (options, repo) in [
+        (server_options(), RepoRef::new("alice", "new")),
+        (allowed.clone(), RepoRef::new("bob", "new")),
+    ] {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let serve = serve_session(server, &server_repos, &options);
+        let (served, connected) = tokio::join!(serve, connect_creating(client, repo.clone()));
+        assert!(matches!(served, Err(ProtocolError::CreateDenied(_))));
+        let error = connected.unwrap_err();
+        assert_eq!(
+            error.remote_code(),
+            Some(ErrorCode::PermissionDenied),
+            "{repo}"
+        );
+        assert!(!server_repos.repo_exists(&repo.owner, &repo.name));
+    }
+
+    // A repository nothing was pushed to goes again.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let serve = serve_session(server, &server_repos, &allowed);
+    let run = async {
+        let client = connect_creating(client, RepoRef::new("alice", "new"))
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+    };
+    let (served, ()) = tokio::join!(serve, run);
+    served.unwrap();
+    assert!(!server_repos.repo_exists("alice", "new"));
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let serve = serve_session(server, &server_repos, &allowed);
+    let run = async {
+        let mut client = connect_creating(client, RepoRef::new("alice", "new"))
+            .await
+            .unwrap();
+        let created = push_pack(&mut client, "main", feature, &pack)
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        created
+    };
+    let (served, created) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(created.status, PushStatus::Ok);
+    assert!(created.created_repo);
+
+    let names: Vec<_> = server_repos
+        .list_repos("alice")
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    assert_eq!(names, ["new"]);
+    let upstream = server_repos.open_repo("alice", "new").unwrap();
+    assert_eq!(
+        upstream.bookmark_target("main").unwrap(),
+        BookmarkTarget::Normal(feature)
+    );
+}
+
+/// A pack of `entries`, as they are.
+async fn repack(entries: &[PackEntry]) -> Vec<u8> {
+    let mut pack = PackWriter::new(Vec::new(), entries.len() as u32)
+        .await
+        .unwrap();
+    for entry in entries {
+        pack.add_object(entry.kind, &entry.id, &entry.data)
+            .await
+            .unwrap();
+    }
+    pack.finish().await.unwrap().0
+}
+
+/// Push `pack` to set `bookmark` to `target`.
+async fn push_pack(
+    client: &mut ForjjClient<DuplexStream>,
+    bookmark: &str,
+    target: ObjectId,
+    pack: &[u8],
+) -> Result<PushResult, ProtocolError> {
+    let request = PushRequest {
+        have_ops: vec![],
+        updates: vec![RefUpdate {
+            ref_name: bookmark.to_string(),
+            old_id: RefTargetWire::Absent,
+            new_id: Some(target.to_hex()),
+            force: false,
+        }],
+        atomic: false,
+        operation_count: 0,
+        request_id: None,
+        push_id: None,
+    };
+    client.push(request, &mut &pack[..]).await
+}
+
+#[tokio::test]
+async fn test_push_skips_objects_the_server_has() {
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    let client_repos = manager(&client_dir);
+    server_repos.create_repo("alice", "project").unwrap();
+    let mut local = client_repos.create_repo("alice", "project").unwrap();
+    let first = local
+        .write_commit(&[], &[("a", b"first")], "first")
+        .await
+        .unwrap();
+    let first_pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let second = local
+        .write_commit(&[first], &[("b", b"second")], "second")
+        .await
+        .unwrap();
+    // Everything in the first pack, and the second commit's objects.
+    let second_pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let first_entries = PackReader::new(first_pack.as_slice())
+        .await
+        .unwrap()
+        .read_all()
+        .await
+        .unwrap();
+    let second_entries = PackReader::new(second_pack.as_slice())
+        .await
+        .unwrap()
+        .read_all()
+        .await
+        .unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let pushed = push_pack(&mut client, "first", first, &first_pack).await;
+        let overlapping = push_pack(&mut client, "second", second, &second_pack).await;
+        client.shutdown().await.unwrap();
+        (pushed.unwrap(), overlapping.unwrap())
+    };
+    let (served, (pushed, overlapping)) = tokio::join!(serve, run);
+    served.unwrap();
+
+    let first_count = first_entries.len() as u64;
+    assert_eq!(pushed.status, PushStatus::Ok);
+    assert_eq!(pushed.new_objects + pushed.skipped_objects, first_count);
+    assert!(pushed.new_objects > 0);
+    assert_eq!(overlapping.status, PushStatus::Ok);
+    assert_eq!(overlapping.skipped_objects, first_count);
+    assert_eq!(
+        overlapping.new_objects,
+        second_entries.len() as u64 - first_count
+    );
+
+    // A commit that comes twice with different contents, and a commit whose
+    // contents don't match its native id.
+    let third = local
+        .write_commit(&[second], &[("c", b"third")], "third")
+        .await
+        .unwrap();
+    let third_pack = export_pack(&local, CONTENT_KINDS).await.unwrap().data;
+    let entries = PackReader::new(third_pack.as_slice())
+        .await
+        .unwrap()
+        .read_all()
+        .await
+        .unwrap();
+    let mut twin = entries
+        .iter()
+        .find(|entry| entry.id == third)
+        .unwrap()
+        .clone();
+    // An unknown field: the same commit once decoded, but other bytes.
+    twin.data.extend_from_slice(&[0xa0, 0x06, 0x00]);
+    let mut duplicated = entries.clone();
+    duplicated.push(twin);
+    let mut forged = entries.clone();
+    let commit = forged.iter_mut().find(|entry| entry.id == third).unwrap();
+    let offset = commit
+        .data
+        .windows(b"third".len())
+        .position(|window| window == b"third")
+        .unwrap();
+    commit.data[offset] = b'T';
+
+    for (name, bad) in [("duplicate", duplicated), ("forged", forged)] {
+        let pack = repack(&bad).await;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let options = server_options();
+        let serve = serve_session(server, &server_repos, &options);
+        let run = async {
+            let mut client = connect_writer(client).await;
+            push_pack(&mut client, "third", third, &pack).await
+        };
+        let (served, result) = tokio::join!(serve, run);
+        match (name, served) {
+            ("duplicate", Err(ProtocolError::ConflictingObject { id, .. })) => {
+                assert_eq!(id, third)
+            }
+            (
+                "forged",
+                Err(ProtocolError::Pack(PackError::ObjectHashMismatch { expected, .. })),
+            ) => assert_eq!(expected, third),
+            (_, served) => panic!("{name}: {served:?}"),
+        }
+        assert_eq!(
+            result.unwrap_err().remote_code(),
+            Some(ErrorCode::ProtocolViolation),
+            "{name}"
+        );
+
+        // Nothing from the bad pack was stored.
+        let upstream = server_repos.open_repo("alice", "project").unwrap();
+        assert!(!upstream.has_commit(&third), "{name}");
+        assert_eq!(
+            upstream.bookmark_target("third").unwrap(),
+            BookmarkTarget::Absent,
+            "{name}"
+        );
+    }
+
+    // So the genuine commit can still be pushed.
+    let pack = repack(&entries).await;
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let mut client = connect_writer(client).await;
+        let result = push_pack(&mut client, "third", third, &pack).await;
+        client.shutdown().await.unwrap();
+        result
+    };
+    let (served, result) = tokio::join!(serve, run);
+    served.unwrap();
+    assert_eq!(result.unwrap().status, PushStatus::Ok);
+    let upstream = server_repos.open_repo("alice", "project").unwrap();
+    assert_eq!(
+        upstream.bookmark_target("third").unwrap(),
+        BookmarkTarget::Normal(third)
+    );
+}
+
+#[tokio::test]
+async fn test_errors_are_sent_as_error_frames() {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    server_repos.create_repo("alice", "project").unwrap();
+
+    // Anonymous clients may fetch but not push.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let request = PushRequest {
+            have_ops: vec![],
+            updates: vec![],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        };
+        client.push(request, &mut tokio::io::empty()).await
+    };
+    let (served, result) = tokio::join!(serve, run);
+    assert!(matches!(served, Err(ProtocolError::Auth(_))));
+    assert_eq!(
+        result.unwrap_err().remote_code(),
+        Some(ErrorCode::PermissionDenied)
+    );
+
+    // Fetching a bookmark that doesn't exist.
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let options = ClientOptions::new(RepoRef::new("alice", "project"));
+        let mut client = ForjjClient::connect(client, options).await.unwrap();
+        let request = FetchRequest {
+            have_ops: vec![],
+            want_refs: vec!["missing".to_string()],
+            depth: None,
+            have_commits: vec![],
+            path_filters: vec![],
+            have_commits_filter: None,
+            want_commits: vec![],
+            request_id: None,
+            include_operations: None,
+        };
+        client.fetch(request, &mut Vec::new()).await
+    };
+    let (served, result) = tokio::join!(serve, run);
+    assert!(matches!(served, Err(ProtocolError::UnknownBookmark(_))));
+    assert_eq!(result.unwrap_err().remote_code(), Some(ErrorCode::NotFound));
+}
+
+/// Complete a JSON Hello with a session, then send it `frames` as raw bytes
+/// and close. Returns how the session ended and the Error it answered with.
+async fn send_raw_frames(frames: &[Vec<u8>]) -> (Result<(), ProtocolError>, Option<ErrorMessage>) {
+    let server_dir = TempDir::new().unwrap();
+    let server_repos = manager(&server_dir);
+    server_repos.create_repo("alice", "project").unwrap();
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let options = server_options();
+    let serve = serve_session(server, &server_repos, &options);
+    let run = async {
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let hello = HelloRequest {
+            protocol_version: 1,
+            capabilities: vec![Capability::Operations],
+            client_op_heads: vec![],
+            compression: vec![],
+            max_frame_size: None,
+            min_protocol_version: None,
+            auth: Auth::None,
+            access: AccessLevel::Read,
+            repo: Some(RepoRef::new("alice", "project")),
+            create_if_missing: false,
+        };
+        let (_, negotiated) = client_hello(&mut reader, &mut writer, &hello)
+            .await
+            .unwrap();
+        assert_eq!(negotiated.format, WireFormat::Json);
+        for frame in frames {
+            writer.write_all(frame).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        match read_message(&mut reader, WireFormat::Json).await {
+            Ok(Message::Error(error)) => Some(error),
+            _ => None,
+        }
+    };
+    tokio::join!(serve, run)
+}
+
+async fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    write_frame(&mut frame, payload).await.unwrap();
+    frame
+}
+
+#[tokio::test]
+async fn test_protocol_violations_are_reported() {
+    let fetch = FetchRequest {
+        have_ops: vec![],
+        want_refs: vec!["main".to_string()],
+        depth: None,
+        have_commits: vec![],
+        path_filters: vec![],
+        have_commits_filter: None,
+        want_commits: vec![],
+        request_id: None,
+        include_operations: None,
+    };
+    let fetch = encode_message(&fetch.into(), WireFormat::Json).unwrap();
+    let fetch_tag = fetch[0];
+
+    // A Fetch cut short, one whose fields have the wrong types, and an
+    // empty frame.
+    let truncated = &fetch[..fetch.len() - 3];
+    let mut confused = vec![fetch_tag];
+    confused.extend_from_slice(br#"{"have_ops":"all","want_refs":7,"depth":-1}"#);
+    for payload in [truncated, &confused[..]] {
+        let (served, error) = send_raw_frames(&[frame(payload).await]).await;
+        assert!(
+            matches!(
+                served,
+                Err(ProtocolError::Decode {
+                    message: "Fetch",
+                    ..
+                })
+            ),
+            "{served:?}"
+        );
+        assert_eq!(error.unwrap().code, ErrorCode::ProtocolViolation);
+    }
+    let (served, error) = send_raw_frames(&[frame(&[]).await]).await;
+    assert!(
+        matches!(served, Err(ProtocolError::EmptyFrame)),
+        "{served:?}"
+    );
+    assert_eq!(error.unwrap().code, ErrorCode::ProtocolViolation);
+
+    // A well-formed message out of turn.
+    let more = HaveMore {
+        have_ops: vec![],
+        done: true,
+    };
+    let more = encode_message(&more.into(), WireFormat::Json).unwrap();
+    let (served, error) = send_raw_frames(&[frame(&more).await]).await;
+    assert!(
+        matches!(
+            served,
+            Err(ProtocolError::UnexpectedMessage {
+                actual: "HaveMore",
+                ..
+            })
+        ),
+        "{served:?}"
+    );
+    assert_eq!(error.unwrap().code, ErrorCode::ProtocolViolation);
+
+    // A frame cut off by the client closing.
+    let whole = frame(&fetch).await;
+    let (served, _) = send_raw_frames(&[whole[..whole.len() / 2].to_vec()]).await;
+    assert!(
+        matches!(served, Err(ProtocolError::Frame(FrameError::UnexpectedEof))),
+        "{served:?}"
+    );
+}
@@ -1,92 +1,7509 @@
 //! REST API handlers for Forjj.
 
-use axum::{Json, Router, extract::Path, http::StatusCode, response::IntoResponse, routing::get};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::{
+        DefaultBodyLimit, MatchedPath, Path, Query, RawPathParams, Request, State,
+        rejection::{JsonRejection, PathRejection, QueryRejection, RawPathParamsRejection},
+    },
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{
+            ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONNECTION, CONTENT_DISPOSITION,
+            CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+            LOCATION, RANGE, RETRY_AFTER, UPGRADE, WWW_AUTHENTICATE, X_CONTENT_TYPE_OPTIONS,
+        },
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{MethodRouter, delete, get, patch, post, put},
+};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use forjj_protocol::{BookmarksMoved, RepoRef, SYNC_UPGRADE, ServerOptions};
+use forjj_storage::object_id::{CHANGE_ID_LEN, MAX_ID_LEN};
+use forjj_storage::{
+    BackendType, BookmarkTarget, BookmarkUpdate, CommitId, CommitSummary, FileChange,
+    FileChangeKind, FileId, LocalRef, OperationId, PathValue, PrefixResolution, RepoInfo,
+    Repository, RepositoryManager, StorageError, change_id_prefix_to_hex, validate_bookmark_name,
+    validate_name,
+};
+use hyper_util::rt::TokioIo;
+use russh::keys::PublicKey;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::{ReaderStream, SyncIoBridge};
 use tower_http::trace::TraceLayer;
+use tracing::{Span, info, warn};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+#[cfg(feature = "swagger-ui")]
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use crate::auth::{
+    AuthenticatedUser, NewToken, Scope, ScopeRequirement, TokenInfo, TokenStore, anonymous,
+    bearer_auth, require_scope,
+};
+use crate::git_http::{
+    MAX_REQUEST_BYTES, RECEIVE_PACK, UPLOAD_PACK, advertisement_type, decode_request, result_type,
+    upload_pack,
+};
+use crate::health::{Readiness, ReadinessReport};
+use crate::hooks::{self, Delivery, Hook, HookDispatcher, HookEvent, HookInfo};
+use crate::metadata::{
+    LargeFile, MAX_DESCRIPTION_LEN, RepoMetadata, ScannedStats, Visibility, unix_now,
+};
+use crate::metrics::{METRICS_PATH, Metrics, serve_metrics, track_requests};
+use crate::patch::{PatchSide, git_file_patch, unified_diff};
+use crate::rate_limit::{RateLimiter, rate_limit};
+use crate::raw::{RangeRequest, content_type, etag_matches, looks_binary, measure, parse_range};
+use crate::redirects::RedirectStore;
+use crate::request_id::{self, RequestId, request_id};
+use crate::session::serve_transport;
+use crate::ssh::{KeyInfo, ManagedKeys};
+use crate::users::{User, UserStore};
+
+/// Most lines of a file blamed, unless configured.
+pub const DEFAULT_MAX_BLAME_LINES: usize = 20_000;
+
+/// Seconds the statistics from a scan of a repository are served for,
+/// unless configured.
+pub const DEFAULT_STATS_MAX_AGE: u64 = 10 * 60;
+
+/// How many of the largest files repository statistics list.
+const LARGEST_FILES_LISTED: usize = 10;
+
+/// Shared state of the API handlers.
+#[derive(Clone)]
+pub struct AppState {
+    /// Repositories managed by the API and served over the sync endpoint
+    pub repos: Arc<RepositoryManager>,
+    /// Valid bearer tokens
+    pub tokens: Arc<dyn TokenStore>,
+    /// Options for sync sessions; `auth` is replaced by the token's grant
+    pub sync_options: Arc<ServerOptions>,
+    /// Whether deleted repositories are moved to the trash unless the
+    /// request asks for them to be removed permanently
+    pub soft_delete: bool,
+    /// Most bytes of patches answered for one commit
+    pub max_patch_bytes: usize,
+    /// Most bytes of a file answered by the raw endpoint without a range
+    pub max_raw_bytes: u64,
+    /// Most lines of a file the blame endpoint attributes
+    pub max_blame_lines: usize,
+    /// Seconds the statistics from a scan of a repository are served for
+    /// before it is scanned again
+    pub stats_max_age: u64,
+    /// Number of scans made for repository statistics
+    pub stats_scans: Arc<AtomicU64>,
+    /// Whether to serve Swagger UI at [`SWAGGER_UI_PATH`], in builds with
+    /// the `swagger-ui` feature
+    pub swagger_ui: bool,
+    /// Whether requests without a token may read repositories, as on a
+    /// public instance
+    pub anonymous_read: bool,
+    /// Accounts, whose names own repositories
+    pub users: Arc<UserStore>,
+    /// Where renamed and transferred repositories went
+    pub redirects: Arc<RedirectStore>,
+    /// SSH keys, including those users add through the API
+    pub ssh_keys: Arc<ManagedKeys>,
+    /// Posts the bookmarks requests move to repositories' hooks
+    pub hooks: Arc<HookDispatcher>,
+    /// How fast each client may make requests
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Counts of requests and sessions, for operators
+    pub metrics: Arc<Metrics>,
+    /// Whether to serve the metrics at [`METRICS_PATH`], rather than only
+    /// on an admin port of their own
+    pub serve_metrics: bool,
+    /// Checks of the storage the server needs, for the readiness probe
+    pub readiness: Arc<Readiness>,
+}
+
+/// Where the OpenAPI description of the API is served.
+pub const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+
+/// Where Swagger UI is served, if enabled.
+pub const SWAGGER_UI_PATH: &str = "/api/v1/docs";
+
+/// The OpenAPI description of the API, generated from the handlers and the
+/// types they take and answer with.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        root,
+        health,
+        live,
+        ready,
+        openapi_document,
+        list_repos,
+        create_repo,
+        get_repo,
+        delete_repo,
+        update_repo,
+        fork_repo,
+        list_forks,
+        transfer_repo,
+        list_bookmarks,
+        put_bookmark,
+        delete_bookmark,
+        create_hook,
+        list_hooks,
+        delete_hook,
+        list_deliveries,
+        list_conflicts,
+        get_stats,
+        list_commits,
+        get_commit,
+        compare,
+        get_archive,
+        get_blame,
+        get_file_history,
+        get_raw,
+        sync,
+        git_info_refs,
+        git_upload_pack,
+        git_receive_pack,
+        create_token,
+        list_tokens,
+        revoke_token,
+        add_key,
+        list_keys,
+        delete_key,
+        create_user,
+    ),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "meta", description = "The server itself"),
+        (name = "repositories", description = "Creating, listing and deleting repositories"),
+        (name = "bookmarks", description = "Reading and moving bookmarks"),
+        (name = "hooks", description = "Requests made to other services when bookmarks move"),
+        (name = "history", description = "Commits and how they relate"),
+        (name = "files", description = "Files at a revision"),
+        (name = "sync", description = "The forjj-sync protocol over HTTP"),
+        (name = "git", description = "Read-only git smart HTTP for git-backed repositories"),
+        (name = "user", description = "The request's user, their API tokens and SSH keys"),
+        (name = "admin", description = "Administering the server"),
+    )
+)]
+struct ApiDoc;
+
+/// Adds the bearer token scheme that endpoints name, with the scopes they
+/// need.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// `route`, refusing requests whose user doesn't hold `scope`.
+///
+/// Requests without a token are let through to routes needing
+/// [`Scope::RepoRead`] if `anonymous_read` is set.
+fn requires(
+    scope: Scope,
+    anonymous_read: bool,
+    route: MethodRouter<AppState>,
+) -> MethodRouter<AppState> {
+    let requirement = ScopeRequirement {
+        scope,
+        anonymous: anonymous_read && scope == Scope::RepoRead,
+    };
+    route.route_layer(middleware::from_fn_with_state(requirement, require_scope))
+}
+
+/// `route`, answering requests about private repositories the request's
+/// user may not see as if they didn't exist; see [`require_visible`].
+fn hide_private(state: &AppState, route: MethodRouter<AppState>) -> MethodRouter<AppState> {
+    route.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        require_visible,
+    ))
+}
 
 /// Create the API router.
-pub fn create_router() -> Router {
-    Router::new()
+pub fn create_router(state: AppState) -> Router {
+    let openapi = Arc::new(ApiDoc::openapi());
+    let anonymous_read = state.anonymous_read;
+    // Scopes are checked first, so requests without the one needed are
+    // told so whether or not the repository is visible to them.
+    let read = |route| requires(Scope::RepoRead, anonymous_read, hide_private(&state, route));
+    let write = |route| {
+        requires(
+            Scope::RepoWrite,
+            anonymous_read,
+            hide_private(&state, route),
+        )
+    };
+    // Any token will do, but there has to be one.
+    let authenticated = |route| requires(Scope::RepoRead, false, route);
+    let admin = |route| requires(Scope::Admin, false, route);
+    let router = Router::new()
         .route("/", get(root))
         .route("/health", get(health))
-        .route("/api/v1/repos", get(list_repos).post(create_repo))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .route(
+            OPENAPI_PATH,
+            get(openapi_document).layer(Extension(openapi)),
+        )
+        .route(
+            "/api/v1/repos",
+            read(get(list_repos)).merge(write(post(create_repo))),
+        )
         .route(
             "/api/v1/repos/{owner}/{name}",
-            get(get_repo).delete(delete_repo),
+            read(get(get_repo)).merge(write(delete(delete_repo).patch(update_repo))),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/transfer",
+            write(post(transfer_repo)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/forks",
+            read(get(list_forks)).merge(write(post(fork_repo))),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/bookmarks",
+            read(get(list_bookmarks)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/conflicts",
+            read(get(list_conflicts)),
+        )
+        .route("/api/v1/repos/{owner}/{name}/stats", read(get(get_stats)))
+        .route(
+            "/api/v1/repos/{owner}/{name}/bookmarks/{bookmark}",
+            write(put(put_bookmark).delete(delete_bookmark)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/hooks",
+            write(get(list_hooks).post(create_hook)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/hooks/{id}",
+            write(delete(delete_hook)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/hooks/{id}/deliveries",
+            write(get(list_deliveries)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/commits",
+            read(get(list_commits)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/compare/{*range}",
+            read(get(compare)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/commits/{rev}",
+            read(get(get_commit)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/archive/{file}",
+            read(get(get_archive)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/blame/{rev}/{*path}",
+            read(get(get_blame)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/history/{rev}/{*path}",
+            read(get(get_file_history)),
+        )
+        .route(
+            "/api/v1/repos/{owner}/{name}/raw/{rev}/{*path}",
+            read(get(get_raw)),
+        )
+        // Sessions are limited to what the user may do by their grant.
+        .route("/api/v1/repos/{owner}/{name}/sync", read(post(sync)))
+        .route(
+            "/api/v1/user/tokens",
+            authenticated(get(list_tokens).post(create_token)),
+        )
+        .route(
+            "/api/v1/user/tokens/{id}",
+            authenticated(delete(revoke_token)),
+        )
+        .route(
+            "/api/v1/user/keys",
+            authenticated(get(list_keys)).merge(write(post(add_key))),
+        )
+        .route("/api/v1/user/keys/{id}", authenticated(delete(delete_key)))
+        .route("/api/v1/admin/users", admin(post(create_user)))
+        // Git clients name repositories as `/{owner}/{name}.git`.
+        .route(
+            "/{owner}/{name}/info/refs",
+            requires(Scope::RepoRead, anonymous_read, get(git_info_refs)),
+        )
+        .route(
+            "/{owner}/{name}/git-upload-pack",
+            requires(
+                Scope::RepoRead,
+                anonymous_read,
+                post(git_upload_pack).layer(DefaultBodyLimit::max(MAX_REQUEST_BYTES)),
+            ),
+        )
+        .route("/{owner}/{name}/git-receive-pack", post(git_receive_pack));
+    // Swagger UI points at the document rather than serving a copy.
+    #[cfg(feature = "swagger-ui")]
+    let router = if state.swagger_ui {
+        router.merge(SwaggerUi::new(SWAGGER_UI_PATH).config(Config::new([OPENAPI_PATH])))
+    } else {
+        router
+    };
+    // Unless an admin port of their own serves them.
+    let router = if state.serve_metrics {
+        router.route(
+            METRICS_PATH,
+            get(serve_metrics).with_state(state.metrics.clone()),
         )
-        .layer(TraceLayer::new_for_http())
+    } else {
+        router
+    };
+    router
+        // Inside the bearer token check, so clients are told apart by user.
+        .route_layer(middleware::from_fn_with_state(
+            state.rate_limiter.clone(),
+            rate_limit,
+        ))
+        .fallback(unknown_endpoint)
+        .method_not_allowed_fallback(method_not_allowed)
+        .layer(middleware::from_fn_with_state(
+            state.tokens.clone(),
+            bearer_auth,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            track_requests,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        // Outside the trace layer, so the request's span can name its ID.
+        .layer(middleware::from_fn(request_id))
+        .with_state(state)
+}
+
+/// The route of a repository, which routes for things in it extend.
+const REPO_ROUTE: &str = "/api/v1/repos/{owner}/{name}";
+
+/// The span a request is answered in, naming its ID, the route it
+/// matched, if any, and the repository, if the route is for one.
+fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map_or("", |id| id.0.as_str());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let (owner, repo) = match route {
+        Some(route) if route.starts_with(REPO_ROUTE) => {
+            let mut segments = request.uri().path()["/api/v1/repos/".len()..].split('/');
+            (segments.next(), segments.next())
+        }
+        _ => (None, None),
+    };
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        route,
+        request_id,
+        owner,
+        repo,
+    )
+}
+
+/// What the server is.
+#[derive(Debug, Serialize, ToSchema)]
+struct RootResponse {
+    name: &'static str,
+    version: &'static str,
+    description: &'static str,
 }
 
 /// Root handler - basic info.
-async fn root() -> impl IntoResponse {
-    Json(serde_json::json!({
-        "name": "forjj",
-        "version": "0.1.0-dev",
-        "description": "A native jj forge"
-    }))
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "meta",
+    responses((status = 200, description = "What the server is", body = RootResponse))
+)]
+async fn root() -> Json<RootResponse> {
+    Json(RootResponse {
+        name: "forjj",
+        version: "0.1.0-dev",
+        description: "A native jj forge",
+    })
+}
+
+/// Health of the server.
+#[derive(Debug, Serialize, ToSchema)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+/// Health check endpoint, kept for deployments probing it before the
+/// liveness and readiness probes were split.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "meta",
+    responses((status = 200, description = "The server is up", body = HealthResponse))
+)]
+async fn health() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "healthy" })
+}
+
+/// Liveness probe: the server answers, whatever the state of its storage.
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "meta",
+    responses((status = 200, description = "The server is up", body = HealthResponse))
+)]
+async fn live() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "alive" })
+}
+
+/// Readiness probe: the storage the server needs works, so it can serve
+/// requests. Results are kept for a couple of seconds.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Every check passed", body = ReadinessReport),
+        (status = 503, description = "A check failed", body = ReadinessReport),
+    )
+)]
+async fn ready(State(state): State<AppState>) -> Response {
+    let report = state.readiness.report().await;
+    let status = if report.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report.as_ref().clone())).into_response()
+}
+
+/// Get the OpenAPI description of the API.
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    tag = "meta",
+    responses((status = 200, description = "An OpenAPI 3 document"))
+)]
+async fn openapi_document(
+    Extension(openapi): Extension<Arc<utoipa::openapi::OpenApi>>,
+) -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi.as_ref().clone())
+}
+
+/// An error answered to an API request, as a JSON body with a
+/// machine-readable code.
+///
+/// Every handler fails with one, so every error has the same shape:
+/// `{"error": {"code": ..., "message": ..., "details": {...}}}`.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    details: ErrorDetails,
+}
+
+/// A rejected request field.
+#[derive(Debug, Serialize, ToSchema)]
+struct FieldError {
+    field: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: ErrorDetails::default(),
+        }
+    }
+
+    pub(crate) fn unauthorized() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "a token is required",
+        )
+    }
+
+    /// 401 for a bearer token the server doesn't know.
+    pub(crate) fn invalid_token() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            "the token is not valid",
+        )
+    }
+
+    /// 401 for a token that was valid, but is no longer.
+    pub(crate) fn expired_token() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "expired_token",
+            "the token has expired",
+        )
+    }
+
+    /// 429 for a client past its rate limit, which may try again after
+    /// `wait`.
+    pub(crate) fn rate_limited(wait: std::time::Duration) -> Self {
+        // Retry-After is in whole seconds; rounding down would come back
+        // too soon.
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        Self {
+            details: ErrorDetails {
+                retry_after: Some(retry_after.max(1)),
+                ..ErrorDetails::default()
+            },
+            ..Self::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "too many requests; slow down",
+            )
+        }
+    }
+
+    /// 403 for a user changing repositories that aren't theirs.
+    fn not_owner(owner: &str) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "not_owner",
+            format!("only {owner} or an admin may change {owner}'s repositories"),
+        )
+    }
+
+    /// 403 for a token that doesn't allow what `scope` does.
+    pub(crate) fn forbidden(scope: Scope) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            format!("the token does not grant {scope}"),
+        )
+    }
+
+    fn not_found(repo: &RepoRef) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("repository {repo} does not exist"),
+        )
+    }
+
+    /// 409 for a change to a repository that has moved to `moved`, found
+    /// at `location`. Clients must make changes at the new name.
+    fn moved(repo: &RepoRef, moved: &RepoRef, location: String) -> Self {
+        Self {
+            details: ErrorDetails {
+                location: Some(location),
+                ..ErrorDetails::default()
+            },
+            ..Self::new(
+                StatusCode::CONFLICT,
+                "moved",
+                format!("repository {repo} has moved to {moved}"),
+            )
+        }
+    }
+
+    /// 423 for a repository that may not be deleted or moved.
+    fn protected(repo: &RepoRef) -> Self {
+        Self::new(
+            StatusCode::LOCKED,
+            "protected",
+            format!("repository {repo} is protected"),
+        )
+    }
+
+    /// 500 for a repository that exists but can't be read.
+    fn corrupt(repo: &RepoRef) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "corrupt_repository",
+            format!("repository {repo} can't be read"),
+        )
+    }
+
+    /// 409 for a revision that several commits match.
+    fn ambiguous(rev: &str, candidates: Vec<CommitId>) -> Self {
+        Self {
+            details: ErrorDetails {
+                candidates,
+                ..ErrorDetails::default()
+            },
+            ..Self::new(
+                StatusCode::CONFLICT,
+                "ambiguous_revision",
+                format!("revision {rev} matches several commits"),
+            )
+        }
+    }
+
+    /// 400 for a page cursor the listing can't continue from.
+    fn invalid_cursor() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_cursor",
+            "the cursor is not one this server handed out",
+        )
+    }
+
+    /// 500 for a failure the client can do nothing about.
+    ///
+    /// `error` is logged in full under a new error ID, and the answer only
+    /// gives the ID: what went wrong may name the server's files.
+    fn internal(error: impl std::fmt::Display) -> Self {
+        let error_id = format!("{:016x}", rand::random::<u64>());
+        warn!(%error_id, "{error}");
+        Self {
+            details: ErrorDetails {
+                error_id: Some(error_id.clone()),
+                ..ErrorDetails::default()
+            },
+            ..Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                format!("internal server error (error ID {error_id})"),
+            )
+        }
+    }
+
+    /// 422 for the request fields in `fields`.
+    fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        Self {
+            details: ErrorDetails {
+                fields,
+                ..ErrorDetails::default()
+            },
+            ..Self::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_fields",
+                "the request has invalid fields",
+            )
+        }
+    }
+}
+
+/// Body of an error answer.
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorDetail {
+    /// Machine-readable, such as `not_found`
+    code: &'static str,
+    message: String,
+    /// The ID of the request, as in the X-Request-Id header, for finding
+    /// what the server logged about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    details: ErrorDetails,
+}
+
+/// What an error says besides its message. Only the members that apply
+/// to the error are given.
+#[derive(Debug, Default, Serialize, ToSchema)]
+struct ErrorDetails {
+    /// Which request fields were rejected, and why
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+    /// Commits an ambiguous revision could mean
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = Vec<String>)]
+    candidates: Vec<CommitId>,
+    /// Where to find an internal error in the server's log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_id: Option<String>,
+    /// Seconds to wait before trying again, as in the Retry-After header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    /// Where the repository moved to, as in the Location header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let retry_after = self.details.retry_after;
+        let location = self.details.location.clone();
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.code,
+                message: self.message,
+                request_id: request_id::current(),
+                details: self.details,
+            },
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if self.status == StatusCode::UNAUTHORIZED {
+            // Say how to authenticate, as 401 answers must.
+            let scheme = HeaderValue::from_static("Bearer");
+            response.headers_mut().insert(WWW_AUTHENTICATE, scheme);
+        }
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        if let Some(location) = location.and_then(|location| location.parse().ok()) {
+            response.headers_mut().insert(LOCATION, location);
+        }
+        response
+    }
+}
+
+/// Every [`StorageError`] is matched, so a new one needs a status here.
+impl From<StorageError> for ApiError {
+    fn from(refused: StorageError) -> Self {
+        match refused {
+            StorageError::NotFound { .. } => {
+                Self::new(StatusCode::NOT_FOUND, "not_found", refused.to_string())
+            }
+            StorageError::AlreadyExists { .. } => {
+                Self::new(StatusCode::CONFLICT, "already_exists", refused.to_string())
+            }
+            StorageError::InvalidName { .. } => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_name", refused.to_string())
+            }
+            StorageError::QuotaExceeded { limit, attempted } => Self::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "quota_exceeded",
+                format!("repository limit of {limit} exceeded: {attempted} attempted"),
+            ),
+            StorageError::Archived => {
+                Self::new(StatusCode::LOCKED, "archived", "the repository is archived")
+            }
+            StorageError::Protected { name } => Self::new(
+                StatusCode::LOCKED,
+                "protected_bookmark",
+                format!("bookmark {name} is protected"),
+            ),
+            StorageError::Stale(stale) => Self::new(
+                StatusCode::CONFLICT,
+                "stale_bookmark",
+                format!(
+                    "bookmark {} has moved; it is {}",
+                    stale.name,
+                    describe_target(&stale.actual)
+                ),
+            ),
+            StorageError::InvalidBookmarkName(invalid) => Self::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_bookmark_name",
+                invalid.to_string(),
+            ),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), "invalid_request", rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::new(rejection.status(), "invalid_request", rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), "invalid_request", rejection.body_text())
+    }
+}
+
+/// Answer requests for paths no endpoint serves.
+async fn unknown_endpoint() -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "unknown_endpoint",
+        "no such endpoint",
+    )
 }
 
-/// Health check endpoint.
-async fn health() -> impl IntoResponse {
-    Json(serde_json::json!({
-        "status": "healthy"
-    }))
+/// Answer requests whose method the endpoint doesn't take.
+async fn method_not_allowed() -> ApiError {
+    ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "the endpoint does not take this method",
+    )
 }
 
 /// Repository info response.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct RepoResponse {
     owner: String,
     name: String,
     full_name: String,
     backend: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    visibility: Visibility,
+    /// The repository this was forked from, as `owner/name`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forked_from: Option<String>,
+}
+
+impl RepoResponse {
+    fn new(info: &RepoInfo, metadata: Option<RepoMetadata>) -> Self {
+        let metadata = metadata.unwrap_or_default();
+        Self {
+            owner: info.owner.clone(),
+            name: info.name.clone(),
+            full_name: format!("{}/{}", info.owner, info.name),
+            backend: info.backend_type.as_str().to_string(),
+            description: metadata.description,
+            created_at: Some(metadata.created_at).filter(|&at| at != 0),
+            visibility: metadata.visibility,
+            forked_from: metadata.forked_from,
+        }
+    }
+}
+
+/// Repository info with the details that take opening it to find.
+#[derive(Debug, Serialize, ToSchema)]
+struct RepoDetail {
+    #[serde(flatten)]
+    repo: RepoResponse,
+    default_bookmark: Option<String>,
+    head_count: usize,
+    bookmark_count: usize,
+    /// When the repository last changed, in seconds since the Unix epoch
+    updated_at: u64,
+}
+
+impl RepoDetail {
+    fn new(repo: &Repository, metadata: Option<RepoMetadata>) -> Self {
+        Self {
+            default_bookmark: default_bookmark(repo, metadata.as_ref()),
+            repo: RepoResponse::new(repo.info(), metadata),
+            head_count: repo.heads().len(),
+            bookmark_count: repo.bookmark_names().len(),
+            updated_at: repo.operation_time(),
+        }
+    }
+}
+
+/// The bookmark clients should start from: the one the metadata names,
+/// else `main` if it exists.
+fn default_bookmark(repo: &Repository, metadata: Option<&RepoMetadata>) -> Option<String> {
+    metadata
+        .and_then(|metadata| metadata.default_bookmark.clone())
+        .or_else(|| {
+            repo.bookmark_names()
+                .into_iter()
+                .find(|name| name == "main")
+        })
 }
 
 /// Create repository request.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct CreateRepoRequest {
     owner: String,
     name: String,
-    #[allow(dead_code)]
     description: Option<String>,
+    #[serde(default)]
+    backend: RequestedBackend,
+    /// Who may see the repository
+    #[serde(default)]
+    visibility: Visibility,
 }
 
-/// List all repositories.
-async fn list_repos() -> impl IntoResponse {
-    // TODO: Implement actual repository listing
-    Json(serde_json::json!({
-        "repositories": []
-    }))
+/// Backend asked for in a [`CreateRepoRequest`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum RequestedBackend {
+    #[default]
+    Simple,
+    Git,
 }
 
-/// Create a new repository.
-async fn create_repo(Json(payload): Json<CreateRepoRequest>) -> impl IntoResponse {
-    // TODO: Implement actual repository creation
-    (
-        StatusCode::CREATED,
-        Json(RepoResponse {
-            owner: payload.owner.clone(),
-            name: payload.name.clone(),
-            full_name: format!("{}/{}", payload.owner, payload.name),
-            backend: "simple".to_string(),
+impl CreateRepoRequest {
+    /// Check the fields against storage's rules and the server's limits.
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut fields = Vec::new();
+        if let Err(error) = validate_name(&self.owner) {
+            fields.push(FieldError {
+                field: "owner",
+                message: error.to_string(),
+            });
+        }
+        if let Err(error) = validate_name(&self.name) {
+            fields.push(FieldError {
+                field: "name",
+                message: error.to_string(),
+            });
+        }
+        let description_len = self.description.as_ref().map_or(0, String::len);
+        if description_len > MAX_DESCRIPTION_LEN {
+            fields.push(FieldError {
+                field: "description",
+                message: format!("longer than {MAX_DESCRIPTION_LEN} bytes"),
+            });
+        }
+        if self.backend == RequestedBackend::Git {
+            fields.push(FieldError {
+                field: "backend",
+                message: "git-backed repositories can't be created yet".to_string(),
+            });
+        }
+
+        if !fields.is_empty() {
+            return Err(ApiError::invalid_fields(fields));
+        }
+        Ok(())
+    }
+}
+
+/// Run a storage call on the blocking thread pool, in a span of its own
+/// within the request's, so that its events name the request and its
+/// timing can be told from the rest of the request's.
+///
+/// Calls storage refused are answered as their [`StorageError`] says;
+/// other failures are logged and answered with 500. Handlers check for the
+/// conditions that deserve another status before calling into storage.
+async fn blocking<T, F>(call: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    let span = tracing::debug_span!("storage");
+    match tokio::task::spawn_blocking(move || span.in_scope(call)).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(error)) => Err(match StorageError::of(&error) {
+            Some(refused) => refused.into(),
+            None => ApiError::internal(format_args!("storage call failed: {error:#}")),
         }),
+        Err(error) => Err(ApiError::internal(format_args!(
+            "storage task failed: {error}"
+        ))),
+    }
+}
+
+/// Refuse changes to `owner`'s repositories by users who may not make
+/// them.
+fn require_owner(user: &AuthenticatedUser, owner: &str) -> Result<(), ApiError> {
+    if !user.may_change(owner) {
+        return Err(ApiError::not_owner(owner));
+    }
+    Ok(())
+}
+
+/// Whether `user`, or an anonymous request if `None`, may see a repository
+/// of `owner`'s with `visibility`.
+fn may_see(user: Option<&AuthenticatedUser>, owner: &str, visibility: Visibility) -> bool {
+    match visibility {
+        Visibility::Public => true,
+        Visibility::Private => user.is_some_and(|user| user.may_change(owner)),
+    }
+}
+
+/// What [`require_visible`] found at the repository a request names.
+enum Found {
+    /// A repository the request's user may see, or none at all
+    Visible,
+    /// A private repository the request's user may not see
+    Hidden,
+    /// No repository, but one that moved away from the name
+    Moved(RepoRef),
+}
+
+/// Answer requests about a private repository with 404, as for one that
+/// doesn't exist, unless the request's user may see it.
+///
+/// Layered on every route, so handlers of routes with an `{owner}` and a
+/// `{name}` can't forget the check. Requests naming no repository, or one
+/// that doesn't exist, are left to the handler, unless a repository moved
+/// away from the name within the grace period: then reads are answered
+/// with 301 to the same path at the new name, and changes with 409, so a
+/// client doesn't change a repository it didn't mean to without noticing.
+async fn require_visible(
+    State(state): State<AppState>,
+    params: Result<RawPathParams, RawPathParamsRejection>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let repo = params.ok().and_then(|params| {
+        let param = |key| {
+            params
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+        };
+        Some(RepoRef::new(param("owner")?, param("name")?))
+    });
+    let Some(repo) = repo else {
+        return Ok(next.run(request).await);
+    };
+
+    let user = request.extensions().get::<AuthenticatedUser>().cloned();
+    let repos = state.repos.clone();
+    let redirects = state.redirects.clone();
+    let target = repo.clone();
+    let found = blocking(move || {
+        let visible = |repo: &RepoRef, info: &RepoInfo| {
+            let visibility = Visibility::of(&RepoMetadata::load_listed(info));
+            may_see(user.as_ref(), &repo.owner, visibility)
+        };
+        if let Ok(info) = repos.repo_info(&target.owner, &target.name) {
+            return Ok(if visible(&target, &info) {
+                Found::Visible
+            } else {
+                Found::Hidden
+            });
+        }
+        let Some(moved) = redirects.lookup(&target) else {
+            return Ok(Found::Visible);
+        };
+        // The old name says no more about the repository than the new
+        // one does.
+        Ok(match repos.repo_info(&moved.owner, &moved.name) {
+            Ok(info) if visible(&moved, &info) => Found::Moved(moved),
+            Ok(_) => Found::Hidden,
+            Err(_) => Found::Visible,
+        })
+    })
+    .await?;
+    match found {
+        Found::Visible => Ok(next.run(request).await),
+        Found::Hidden => Err(ApiError::not_found(&repo)),
+        Found::Moved(moved) => {
+            let uri = request.uri();
+            let Some(rest) = uri.path().strip_prefix(&format!("/api/v1/repos/{repo}")) else {
+                return Ok(next.run(request).await);
+            };
+            let mut location = format!("/api/v1/repos/{moved}{rest}");
+            if let Some(query) = uri.query() {
+                location = format!("{location}?{query}");
+            }
+            if request.method() == Method::GET || request.method() == Method::HEAD {
+                Ok((StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response())
+            } else {
+                Err(ApiError::moved(&repo, &moved, location))
+            }
+        }
+    }
+}
+
+/// The repository a request path names.
+///
+/// Names storage wouldn't accept are answered with 404: no such repository
+/// can exist.
+fn repo_ref(owner: String, name: String) -> Result<RepoRef, ApiError> {
+    let repo = RepoRef::new(owner, name);
+    if validate_name(&repo.owner).is_err() || validate_name(&repo.name).is_err() {
+        return Err(ApiError::not_found(&repo));
+    }
+    Ok(repo)
+}
+
+/// Repositories answered per page when the request doesn't say.
+const DEFAULT_PAGE_LIMIT: usize = 30;
+
+/// Most repositories answered per page.
+const MAX_PAGE_LIMIT: usize = 100;
+
+/// Query parameters of a repository listing.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListReposQuery {
+    /// Only list this owner's repositories
+    owner: Option<String>,
+    limit: Option<usize>,
+    /// Where the previous page ended
+    cursor: Option<String>,
+}
+
+/// Where a page of a listing ended: the last repository on it.
+///
+/// Listings are sorted by owner, then name, and a page starts after its
+/// cursor, so repositories added or removed between pages don't shift it.
+#[derive(Debug)]
+struct Cursor {
+    owner: String,
+    name: String,
+}
+
+impl Cursor {
+    /// The opaque form handed to clients.
+    fn encode(&self) -> String {
+        hex::encode(format!("{}/{}", self.owner, self.name))
+    }
+
+    /// The cursor `encoded` stands for, or `None` if it isn't one.
+    fn decode(encoded: &str) -> Option<Self> {
+        let decoded = String::from_utf8(hex::decode(encoded).ok()?).ok()?;
+        let (owner, name) = decoded.split_once('/')?;
+        if validate_name(owner).is_err() || validate_name(name).is_err() {
+            return None;
+        }
+        Some(Self {
+            owner: owner.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Whether the repository `owner/name` comes after this cursor.
+    fn precedes(&self, owner: &str, name: &str) -> bool {
+        (self.owner.as_str(), self.name.as_str()) < (owner, name)
+    }
+}
+
+/// A page of repositories.
+#[derive(Debug, Serialize, ToSchema)]
+struct RepoList {
+    repositories: Vec<RepoResponse>,
+    /// Where the next page starts, or null on the last page
+    next_cursor: Option<String>,
+}
+
+/// List repositories, a page at a time.
+///
+/// Takes `?owner=` to list one owner's repositories, and `?limit=` and
+/// `?cursor=` to page through them. Repositories aren't opened: items carry
+/// what the listing and the metadata file have, not [`RepoDetail`]'s counts.
+/// Private repositories are only listed to their owner and admins.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos",
+    tag = "repositories",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ListReposQuery,
+    ),
+    responses(
+        (status = 200, description = "A page of repositories", body = RepoList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 400, description = "The cursor is not one this server handed out", body = ErrorBody),
+    )
+)]
+async fn list_repos(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    query: Result<Query<ListReposQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let user = user.map(|Extension(user)| user);
+    let Query(query) = query?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|cursor| Cursor::decode(cursor).ok_or_else(ApiError::invalid_cursor))
+        .transpose()?;
+
+    let repos = state.repos.clone();
+    let (repositories, more) = blocking(move || {
+        let mut owners = match query.owner {
+            Some(owner) if validate_name(&owner).is_ok() => vec![owner],
+            Some(_) => Vec::new(),
+            None => repos.list_owners()?,
+        };
+        owners.sort();
+        // Owners are read in order until the page is full, so a request
+        // reads at most the owners its page spans.
+        let mut page = Vec::new();
+        for owner in owners {
+            if after.as_ref().is_some_and(|after| owner < after.owner) {
+                continue;
+            }
+            let mut infos = repos.list_repos(&owner)?;
+            infos.sort_by(|a, b| a.name.cmp(&b.name));
+            for info in infos {
+                if after
+                    .as_ref()
+                    .is_some_and(|after| !after.precedes(&info.owner, &info.name))
+                {
+                    continue;
+                }
+                let metadata = RepoMetadata::load_listed(&info);
+                if !may_see(user.as_ref(), &info.owner, Visibility::of(&metadata)) {
+                    continue;
+                }
+                if page.len() == limit {
+                    return Ok((page, true));
+                }
+                let metadata = metadata.unwrap_or_else(|error| {
+                    warn!(
+                        "skipping metadata of {}/{}: {error:#}",
+                        info.owner, info.name
+                    );
+                    None
+                });
+                page.push(RepoResponse::new(&info, metadata));
+            }
+        }
+        Ok((page, false))
+    })
+    .await?;
+
+    let next_cursor = repositories.last().filter(|_| more).map(|last| {
+        Cursor {
+            owner: last.owner.clone(),
+            name: last.name.clone(),
+        }
+        .encode()
+    });
+    Ok(Json(RepoList {
+        repositories,
+        next_cursor,
+    })
+    .into_response())
+}
+
+/// Create a new repository.
+///
+/// Answers 201 with the new repository, 409 if it already exists, or 422
+/// naming the fields that are invalid.
+#[utoipa::path(
+    post,
+    path = "/api/v1/repos",
+    tag = "repositories",
+    request_body = CreateRepoRequest,
+    security(("bearer" = ["repo:write"])),
+    responses(
+        (status = 201, description = "The new repository", body = RepoResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 409, description = "The repository already exists", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
     )
+)]
+async fn create_repo(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    payload: Result<Json<CreateRepoRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    payload.validate()?;
+    require_owner(&user, &payload.owner)?;
+
+    let repos = state.repos.clone();
+    let (owner, name) = (payload.owner, payload.name);
+    let mut metadata = RepoMetadata::new(payload.description);
+    metadata.visibility = payload.visibility;
+    let created = blocking(move || {
+        let repo = repos.create_repo(&owner, &name)?;
+        if let Err(error) = metadata.store(&repo) {
+            // Don't leave a repository behind that the client was told
+            // wasn't created.
+            if let Err(cleanup) = repos.delete_repo(&owner, &name) {
+                warn!("failed to remove {owner}/{name} after a failed create: {cleanup:#}");
+            }
+            return Err(error);
+        }
+        Ok(RepoResponse::new(repo.info(), Some(metadata)))
+    })
+    .await?;
+
+    info!(repo = %created.full_name, "created repository");
+    Ok((StatusCode::CREATED, Json(created)).into_response())
 }
 
 /// Get repository info.
-async fn get_repo(Path((owner, name)): Path<(String, String)>) -> impl IntoResponse {
-    // TODO: Implement actual repository lookup
-    Json(RepoResponse {
-        owner: owner.clone(),
-        name: name.clone(),
-        full_name: format!("{}/{}", owner, name),
-        backend: "simple".to_string(),
+///
+/// A repository that exists but can't be opened is answered with 500 and
+/// the code `corrupt_repository`, so clients can tell it from a missing one.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}",
+    tag = "repositories",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The repository", body = RepoDetail),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 500, description = "The repository can't be read", body = ErrorBody),
+    )
+)]
+async fn get_repo(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let detail = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos
+            .open_repo(&repo.owner, &repo.name)
+            .and_then(|opened| Ok((RepoMetadata::load(&opened)?, opened)));
+        match opened {
+            Ok((metadata, opened)) => Ok(Ok(RepoDetail::new(&opened, metadata))),
+            Err(error) => {
+                warn!(%repo, "repository is unreadable: {error:#}");
+                Ok(Err(ApiError::corrupt(&repo)))
+            }
+        }
+    })
+    .await??;
+    Ok(Json(detail).into_response())
+}
+
+/// Body of a request to change a repository's settings.
+#[derive(Debug, Deserialize, ToSchema)]
+struct UpdateRepoRequest {
+    /// New description; kept if missing
+    #[serde(default)]
+    description: Option<String>,
+    /// Who may see the repository; kept if missing
+    #[serde(default)]
+    visibility: Option<Visibility>,
+    /// New name, under the same owner; kept if missing
+    #[serde(default)]
+    new_name: Option<String>,
+}
+
+/// Change a repository's settings.
+///
+/// Giving `new_name` renames the repository, unless it is protected (423)
+/// or the name is taken (409). For a while after, requests using the old
+/// name are pointed at the new one.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/repos/{owner}/{name}",
+    tag = "repositories",
+    request_body = UpdateRepoRequest,
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The repository, as changed", body = RepoDetail),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 409, description = "The new name is taken", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+        (status = 423, description = "The repository is protected, and can't be renamed", body = ErrorBody),
+    )
+)]
+async fn update_repo(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    payload: Result<Json<UpdateRepoRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let Json(payload) = payload?;
+    let mut fields = Vec::new();
+    let description_len = payload.description.as_ref().map_or(0, String::len);
+    if description_len > MAX_DESCRIPTION_LEN {
+        fields.push(FieldError {
+            field: "description",
+            message: format!("longer than {MAX_DESCRIPTION_LEN} bytes"),
+        });
+    }
+    if let Some(Err(error)) = payload.new_name.as_deref().map(validate_name) {
+        fields.push(FieldError {
+            field: "new_name",
+            message: error.to_string(),
+        });
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::invalid_fields(fields));
+    }
+
+    let repos = state.repos.clone();
+    let redirects = state.redirects.clone();
+    let detail = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let new_name = payload.new_name.filter(|new_name| *new_name != repo.name);
+        let opened = match new_name {
+            Some(new_name) => {
+                let to = RepoRef::new(repo.owner.clone(), new_name);
+                match relocate(&repos, &redirects, &repo, &to)? {
+                    Ok(moved) => moved,
+                    Err(refused) => return Ok(Err(refused)),
+                }
+            }
+            None => repos.open_repo(&repo.owner, &repo.name)?,
+        };
+        let mut metadata = RepoMetadata::load(&opened)?.unwrap_or_default();
+        if let Some(description) = payload.description {
+            metadata.description = Some(description);
+        }
+        if let Some(visibility) = payload.visibility {
+            metadata.visibility = visibility;
+        }
+        metadata.store(&opened)?;
+        Ok(Ok(RepoDetail::new(&opened, Some(metadata))))
     })
+    .await??;
+    info!(repo = %detail.repo.full_name, "updated repository");
+    Ok(Json(detail).into_response())
+}
+
+/// Move `repo` to `to`, unless it is protected, leaving a redirect at its
+/// old name. Runs on the blocking thread pool.
+fn relocate(
+    repos: &RepositoryManager,
+    redirects: &RedirectStore,
+    repo: &RepoRef,
+    to: &RepoRef,
+) -> anyhow::Result<Result<Repository, ApiError>> {
+    let opened = repos.open_repo(&repo.owner, &repo.name)?;
+    // A repository whose metadata can't be read might be protected.
+    if RepoMetadata::load(&opened)?.is_some_and(|metadata| metadata.protected) {
+        return Ok(Err(ApiError::protected(repo)));
+    }
+    drop(opened);
+    let moved = repos.rename_repo(&repo.owner, &repo.name, &to.owner, &to.name)?;
+    if let Err(error) = redirects.add(repo.clone(), to.clone()) {
+        // The move stands; only clients using the old name miss out.
+        warn!(%repo, "failed to record that it moved to {to}: {error:#}");
+    }
+    info!(%repo, "moved repository to {to}");
+    Ok(Ok(moved))
+}
+
+/// Body of a request to give a repository to another owner.
+#[derive(Debug, Deserialize, ToSchema)]
+struct TransferRequest {
+    /// Who is to own the repository, which keeps its name
+    new_owner: String,
+}
+
+/// Give a repository to another owner.
+///
+/// Only its owner or an admin may, and not if it is protected (423) or the
+/// new owner has a repository by its name (409). For a while after,
+/// requests using the old owner are pointed at the new one.
+#[utoipa::path(
+    post,
+    path = "/api/v1/repos/{owner}/{name}/transfer",
+    tag = "repositories",
+    request_body = TransferRequest,
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The repository, under its new owner", body = RepoDetail),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 409, description = "The new owner has a repository by the name", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+        (status = 423, description = "The repository is protected, and can't be transferred", body = ErrorBody),
+    )
+)]
+async fn transfer_repo(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    payload: Result<Json<TransferRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let Json(payload) = payload?;
+    let message = match validate_name(&payload.new_owner) {
+        Err(error) => Some(error.to_string()),
+        Ok(()) if payload.new_owner == repo.owner => {
+            Some(format!("{} already owns it", repo.owner))
+        }
+        Ok(()) => None,
+    };
+    if let Some(message) = message {
+        return Err(ApiError::invalid_fields(vec![FieldError {
+            field: "new_owner",
+            message,
+        }]));
+    }
+
+    let repos = state.repos.clone();
+    let redirects = state.redirects.clone();
+    let to = RepoRef::new(payload.new_owner, repo.name.clone());
+    let detail = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let moved = match relocate(&repos, &redirects, &repo, &to)? {
+            Ok(moved) => moved,
+            Err(refused) => return Ok(Err(refused)),
+        };
+        let metadata = RepoMetadata::load(&moved)?;
+        Ok(Ok(RepoDetail::new(&moved, metadata)))
+    })
+    .await??;
+    Ok(Json(detail).into_response())
+}
+
+/// Header a delete request must carry, naming the repository it deletes.
+const CONFIRM_DELETE: &str = "x-confirm-delete";
+
+/// Query parameters of a delete request.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteRepoQuery {
+    /// Remove the repository even if the server would move it to the trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+/// What became of a repository a delete request named.
+enum Deleted {
+    Missing,
+    Corrupt,
+    Protected,
+    Trashed,
+    Removed,
 }
 
 /// Delete a repository.
-async fn delete_repo(Path((owner, name)): Path<(String, String)>) -> impl IntoResponse {
-    // TODO: Implement actual repository deletion
-    tracing::info!("Delete repository: {}/{}", owner, name);
-    StatusCode::NO_CONTENT
+///
+/// The request must carry `X-Confirm-Delete: owner/name`, so a client that
+/// builds the wrong path can't delete a repository by accident; without it
+/// the answer is 428. Protected repositories are answered with 423. When the
+/// server keeps deleted repositories in the trash, `?permanent=true` removes
+/// the repository instead.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/repos/{owner}/{name}",
+    tag = "repositories",
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        DeleteRepoQuery,
+        ("X-Confirm-Delete" = String, Header, description = "The repository's owner/name"),
+    ),
+    security(("bearer" = ["repo:write"])),
+    responses(
+        (status = 204, description = "The repository was deleted"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 423, description = "The repository is protected", body = ErrorBody),
+        (status = 428, description = "The request does not confirm the delete", body = ErrorBody),
+    )
+)]
+async fn delete_repo(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    query: Result<Query<DeleteRepoQuery>, QueryRejection>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let Query(query) = query?;
+    let confirmed = headers
+        .get(CONFIRM_DELETE)
+        .is_some_and(|value| value.as_bytes() == repo.to_string().as_bytes());
+    if !confirmed {
+        return Err(ApiError::new(
+            StatusCode::PRECONDITION_REQUIRED,
+            "confirmation_required",
+            format!("deleting {repo} requires the header X-Confirm-Delete: {repo}"),
+        ));
+    }
+
+    let repos = state.repos.clone();
+    let trash = state.soft_delete && !query.permanent;
+    let target = repo.clone();
+    let deleted = blocking(move || {
+        let (owner, name) = (&target.owner, &target.name);
+        if !repos.repo_exists(owner, name) {
+            return Ok(Deleted::Missing);
+        }
+        // A repository whose metadata can't be read might be protected.
+        let metadata = repos
+            .open_repo(owner, name)
+            .and_then(|opened| RepoMetadata::load(&opened));
+        match metadata {
+            Ok(Some(metadata)) if metadata.protected => Ok(Deleted::Protected),
+            Ok(_) if trash => {
+                repos.trash_repo(owner, name)?;
+                Ok(Deleted::Trashed)
+            }
+            Ok(_) => {
+                repos.delete_repo(owner, name)?;
+                Ok(Deleted::Removed)
+            }
+            Err(error) => {
+                warn!(repo = %target, "repository is unreadable: {error:#}");
+                Ok(Deleted::Corrupt)
+            }
+        }
+    })
+    .await?;
+
+    match deleted {
+        Deleted::Missing => Err(ApiError::not_found(&repo)),
+        Deleted::Corrupt => Err(ApiError::corrupt(&repo)),
+        Deleted::Protected => Err(ApiError::protected(&repo)),
+        Deleted::Trashed => {
+            info!(%repo, "moved repository to the trash");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Deleted::Removed => {
+            info!(%repo, "deleted repository");
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+/// Body of a request to fork a repository.
+#[derive(Debug, Deserialize, ToSchema)]
+struct ForkRequest {
+    /// Owner of the fork; the request's user if missing
+    #[serde(default)]
+    owner: Option<String>,
+    /// Name of the fork; the repository's own if missing
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Fork a repository: copy it to a new name, which may be under another
+/// owner.
+///
+/// The fork has the repository's history, bookmarks, description and
+/// visibility, so a fork of a private repository is private too, and
+/// records where it came from in `forked_from`. Answers 202 with the fork,
+/// 409 if the new name is taken, or 422 naming the fields that are invalid.
+#[utoipa::path(
+    post,
+    path = "/api/v1/repos/{owner}/{name}/forks",
+    tag = "repositories",
+    request_body = ForkRequest,
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 202, description = "The fork", body = RepoResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the fork's owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 409, description = "The fork's name is taken", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+    )
+)]
+async fn fork_repo(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    payload: Result<Json<ForkRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let source = repo_ref(owner, name)?;
+    let Json(payload) = payload?;
+    let fork = RepoRef::new(
+        payload.owner.unwrap_or_else(|| user.username.clone()),
+        payload.name.unwrap_or_else(|| source.name.clone()),
+    );
+    let mut fields = Vec::new();
+    if let Err(error) = validate_name(&fork.owner) {
+        fields.push(FieldError {
+            field: "owner",
+            message: error.to_string(),
+        });
+    }
+    if let Err(error) = validate_name(&fork.name) {
+        fields.push(FieldError {
+            field: "name",
+            message: error.to_string(),
+        });
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::invalid_fields(fields));
+    }
+    require_owner(&user, &fork.owner)?;
+
+    let repos = state.repos.clone();
+    let (from, to) = (source.clone(), fork.clone());
+    let forked = blocking(move || {
+        if !repos.repo_exists(&from.owner, &from.name) {
+            return Ok(Err(ApiError::not_found(&from)));
+        }
+        let info = repos.repo_info(&from.owner, &from.name)?;
+        let inherited = RepoMetadata::load_listed(&info)?.unwrap_or_default();
+        let forked = repos.fork_repo(&from.owner, &from.name, &to.owner, &to.name)?;
+        let mut metadata = RepoMetadata::new(inherited.description);
+        metadata.default_bookmark = inherited.default_bookmark;
+        metadata.visibility = inherited.visibility;
+        metadata.forked_from = Some(from.to_string());
+        if let Err(error) = metadata.store(&forked) {
+            // As for a failed create, don't leave the fork behind.
+            if let Err(cleanup) = repos.delete_repo(&to.owner, &to.name) {
+                warn!("failed to remove {to} after a failed fork: {cleanup:#}");
+            }
+            return Err(error);
+        }
+        Ok(Ok(RepoResponse::new(forked.info(), Some(metadata))))
+    })
+    .await??;
+
+    info!(repo = %forked.full_name, from = %source, "forked repository");
+    Ok((StatusCode::ACCEPTED, Json(forked)).into_response())
+}
+
+/// The forks of a repository.
+#[derive(Debug, Serialize, ToSchema)]
+struct ForkList {
+    forks: Vec<RepoResponse>,
+}
+
+/// List the forks of a repository that the request's user may see.
+///
+/// Forks are found by the `forked_from` their metadata records, so forks
+/// of forks are listed under the fork they came from, not here.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/forks",
+    tag = "repositories",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The repository's forks", body = ForkList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+    )
+)]
+async fn list_forks(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    params: Result<Path<(String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let user = user.map(|Extension(user)| user);
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let forks = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let source = repo.to_string();
+        let mut owners = repos.list_owners()?;
+        owners.sort();
+        let mut forks = Vec::new();
+        for owner in owners {
+            let mut infos = repos.list_repos(&owner)?;
+            infos.sort_by(|a, b| a.name.cmp(&b.name));
+            for info in infos {
+                // Unreadable metadata doesn't say where a repository came
+                // from, and might have made it private.
+                let Ok(Some(metadata)) = RepoMetadata::load_listed(&info) else {
+                    continue;
+                };
+                if metadata.forked_from.as_deref() != Some(source.as_str())
+                    || !may_see(user.as_ref(), &info.owner, metadata.visibility)
+                {
+                    continue;
+                }
+                forks.push(RepoResponse::new(&info, Some(metadata)));
+            }
+        }
+        Ok(Ok(forks))
+    })
+    .await??;
+    Ok(Json(ForkList { forks }).into_response())
+}
+
+/// Query parameters of a commit listing.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListCommitsQuery {
+    /// Bookmark whose history to list
+    bookmark: Option<String>,
+    /// Head whose history to list, by commit ID
+    head: Option<String>,
+    /// Only list commits that change this path
+    path: Option<String>,
+    limit: Option<usize>,
+    /// ID of the last commit of the previous page
+    cursor: Option<String>,
+}
+
+/// A commit in a listing.
+#[derive(Debug, Serialize, ToSchema)]
+struct CommitResponse {
+    #[schema(value_type = String)]
+    id: CommitId,
+    /// The shortest prefix of `id` no other commit in the repository has
+    short_id: String,
+    /// In jj's reverse-hex form
+    change_id: String,
+    /// The shortest prefix of `change_id` no other visible change has
+    short_change_id: String,
+    author: AuthorResponse,
+    /// When the commit was authored, in seconds since the Unix epoch
+    timestamp: i64,
+    /// First line of the description
+    description: String,
+    #[schema(value_type = Vec<String>)]
+    parent_ids: Vec<CommitId>,
+    empty: bool,
+    conflict: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AuthorResponse {
+    name: String,
+    email: String,
+}
+
+impl From<CommitSummary> for CommitResponse {
+    fn from(summary: CommitSummary) -> Self {
+        Self {
+            id: summary.id,
+            short_id: summary.short_id,
+            change_id: summary.change_id.to_reverse_hex(),
+            short_change_id: summary.short_change_id,
+            author: AuthorResponse {
+                name: summary.author_name,
+                email: summary.author_email,
+            },
+            timestamp: summary.author_time.div_euclid(1000),
+            description: summary.description.lines().next().unwrap_or("").to_string(),
+            parent_ids: summary.parents,
+            empty: summary.empty,
+            conflict: summary.conflict,
+        }
+    }
+}
+
+/// Where a commit listing starts: the bookmark or head asked for, else the
+/// default bookmark, else the repository's only head.
+fn log_start(
+    repo: &Repository,
+    bookmark: Option<&str>,
+    head: Option<&str>,
+) -> anyhow::Result<Result<CommitId, ApiError>> {
+    let bookmark = match (bookmark, head) {
+        (Some(bookmark), _) => bookmark.to_string(),
+        (None, Some(head)) => {
+            let head = CommitId::from_hex(head).ok();
+            return Ok(match head {
+                Some(head) if repo.head_ids()?.contains(&head) => Ok(head),
+                _ => Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "head_not_found",
+                    "the repository has no such head",
+                )),
+            });
+        }
+        (None, None) => match default_bookmark(repo, RepoMetadata::load(repo)?.as_ref()) {
+            Some(bookmark) => bookmark,
+            None => {
+                let heads = repo.head_ids()?;
+                return Ok(match heads[..] {
+                    [head] => Ok(head),
+                    _ => Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "start_required",
+                        "the repository has several heads; choose one with ?head=",
+                    )),
+                });
+            }
+        },
+    };
+    Ok(match repo.bookmark_target(&bookmark)? {
+        BookmarkTarget::Normal(id) => Ok(id),
+        BookmarkTarget::Absent => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "bookmark_not_found",
+            format!("bookmark {bookmark} does not exist"),
+        )),
+        BookmarkTarget::Conflicted { .. } => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "conflicted_bookmark",
+            format!("bookmark {bookmark} is conflicted; choose a head with ?head="),
+        )),
+    })
+}
+
+/// A page of commits.
+#[derive(Debug, Serialize, ToSchema)]
+struct CommitList {
+    commits: Vec<CommitResponse>,
+    /// Where the next page starts, or null on the last page
+    next_cursor: Option<String>,
+}
+
+/// List the commits in the history of a bookmark or head, a page at a time.
+///
+/// Takes `?bookmark=` or `?head=` to choose where to start, `?path=` to
+/// only list commits that change a path, and `?limit=` and `?cursor=` to
+/// page through them.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/commits",
+    tag = "history",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ListCommitsQuery,
+    ),
+    responses(
+        (status = 200, description = "A page of commits", body = CommitList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 400, description = "The start or cursor is invalid", body = ErrorBody),
+        (status = 404, description = "The repository, bookmark or head does not exist", body = ErrorBody),
+        (status = 409, description = "The bookmark is conflicted", body = ErrorBody),
+    )
+)]
+async fn list_commits(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    query: Result<Query<ListCommitsQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Query(query) = query?;
+    if query.bookmark.is_some() && query.head.is_some() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "choose either ?bookmark= or ?head=",
+        ));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|cursor| CommitId::from_hex(cursor).map_err(|_| ApiError::invalid_cursor()))
+        .transpose()?;
+
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let log = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let start = match log_start(&opened, query.bookmark.as_deref(), query.head.as_deref())? {
+            Ok(start) => start,
+            Err(error) => return Ok(Err(error)),
+        };
+        if let Some(after) = &after {
+            if !opened.has_commit(after) || !opened.is_ancestor(after, &start)? {
+                return Ok(Err(ApiError::invalid_cursor()));
+            }
+        }
+        let path = query.path.as_deref();
+        let log = runtime.block_on(opened.log(&start, after.as_ref(), path, limit))?;
+        Ok(Ok(log))
+    })
+    .await??;
+
+    let next_cursor = log
+        .commits
+        .last()
+        .filter(|_| log.more)
+        .map(|last| last.id.to_hex());
+    let commits: Vec<CommitResponse> = log.commits.into_iter().map(Into::into).collect();
+    Ok(Json(CommitList {
+        commits,
+        next_cursor,
+    })
+    .into_response())
+}
+
+/// Query parameters of a commit's details.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GetCommitQuery {
+    /// Add a unified diff of each changed text file
+    #[serde(default)]
+    include_patch: bool,
+}
+
+/// A commit with everything about it and the files it changes.
+#[derive(Debug, Serialize, ToSchema)]
+struct CommitDetail {
+    #[serde(flatten)]
+    commit: CommitResponse,
+    /// The description in full
+    full_description: String,
+    changes: Vec<FileChangeResponse>,
+    /// Whether patches were left out to keep within the server's limit;
+    /// only present if patches were asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch_truncated: Option<bool>,
+}
+
+/// A file a commit changes.
+#[derive(Debug, Serialize, ToSchema)]
+struct FileChangeResponse {
+    path: String,
+    kind: &'static str,
+    /// Size in bytes before the commit, if it was a file
+    old_size: Option<u64>,
+    /// Size in bytes after the commit, if it is a file
+    new_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: Option<String>,
+}
+
+impl FileChangeResponse {
+    fn new(change: &FileChange, patch: Option<String>) -> Self {
+        Self {
+            path: change.path.clone(),
+            kind: change.kind.as_str(),
+            old_size: change.old_size,
+            new_size: change.new_size,
+            patch,
+        }
+    }
+}
+
+/// The commit `rev` names: a commit ID or a prefix of one, else a change ID
+/// or a prefix of one.
+fn resolve_rev(repo: &Repository, rev: &str) -> anyhow::Result<Result<CommitId, ApiError>> {
+    let mut resolution = PrefixResolution::NoMatch;
+    if rev.len() <= 2 * MAX_ID_LEN && rev.bytes().all(|b| b.is_ascii_hexdigit()) {
+        resolution = repo.resolve_commit_prefix(rev)?;
+    }
+    if resolution == PrefixResolution::NoMatch
+        && rev.len() <= 2 * CHANGE_ID_LEN
+        && change_id_prefix_to_hex(rev).is_ok()
+    {
+        resolution = repo.resolve_change_prefix(rev)?;
+    }
+    Ok(match resolution {
+        PrefixResolution::Single(id) => Ok(id),
+        PrefixResolution::Ambiguous(candidates) => Err(ApiError::ambiguous(rev, candidates)),
+        PrefixResolution::NoMatch => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "revision_not_found",
+            format!("no commit matches {rev}"),
+        )),
+    })
+}
+
+/// The patch of a changed file, or `None` if either side isn't a text file.
+async fn file_patch(repo: &Repository, change: &FileChange) -> anyhow::Result<Option<String>> {
+    let old = patch_side(repo, change, change.old_file, FileChangeKind::Added).await?;
+    let new = patch_side(repo, change, change.new_file, FileChangeKind::Deleted).await?;
+    Ok(match (old, new) {
+        (Some(old), Some(new)) => unified_diff(&old, &new),
+        _ => None,
+    })
+}
+
+/// The contents of one side of a changed file, or `None` if that side isn't
+/// a file. Added and deleted files are diffed against nothing, so the side
+/// they are missing on is empty.
+async fn patch_side(
+    repo: &Repository,
+    change: &FileChange,
+    file: Option<FileId>,
+    missing_when: FileChangeKind,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    match file {
+        Some(id) => Ok(Some(repo.file_content(&change.path, &id).await?)),
+        None if change.kind == missing_when => Ok(Some(Vec::new())),
+        None => Ok(None),
+    }
+}
+
+/// Describe changed files, with the patches of text files if
+/// `include_patch`, until they reach `max_patch_bytes`. Returns whether
+/// patches were left out.
+async fn describe_changes(
+    repo: &Repository,
+    changes: Vec<FileChange>,
+    include_patch: bool,
+    max_patch_bytes: usize,
+) -> anyhow::Result<(Vec<FileChangeResponse>, bool)> {
+    let mut described = Vec::new();
+    let mut remaining = max_patch_bytes;
+    let mut truncated = false;
+    for change in changes {
+        let mut patch = None;
+        if include_patch && !truncated {
+            patch = file_patch(repo, &change).await?;
+            match &patch {
+                Some(text) if text.len() > remaining => {
+                    // Later files get no patch either, so what is
+                    // answered is a prefix of the whole.
+                    truncated = true;
+                    patch = None;
+                }
+                Some(text) => remaining -= text.len(),
+                None => {}
+            }
+        }
+        described.push(FileChangeResponse::new(&change, patch));
+    }
+    Ok((described, truncated))
+}
+
+/// Get a commit and the files it changes.
+///
+/// `rev` is a commit ID or change ID, or a prefix of either; one that several
+/// commits match is answered with 409 listing them. With
+/// `?include_patch=true`, text files get a unified diff, until the patches
+/// reach the server's limit.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/commits/{rev}",
+    tag = "history",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("rev" = String, Path, description = "A commit ID or change ID, or a prefix of either"),
+        GetCommitQuery,
+    ),
+    responses(
+        (status = 200, description = "The commit and the files it changes", body = CommitDetail),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository or revision does not exist", body = ErrorBody),
+        (status = 409, description = "The revision matches several commits", body = ErrorBody),
+    )
+)]
+async fn get_commit(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+    query: Result<Query<GetCommitQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, rev)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Query(query) = query?;
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let max_patch_bytes = state.max_patch_bytes;
+    let detail = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let id = match resolve_rev(&opened, &rev)? {
+            Ok(id) => id,
+            Err(error) => return Ok(Err(error)),
+        };
+        let summary = opened.commit_summary(&id)?;
+        let full_description = summary.description.clone();
+        let changes = runtime.block_on(opened.commit_changes(&id))?;
+        let (changes, truncated) = runtime.block_on(describe_changes(
+            &opened,
+            changes,
+            query.include_patch,
+            max_patch_bytes,
+        ))?;
+        Ok(Ok(CommitDetail {
+            commit: summary.into(),
+            full_description,
+            changes,
+            patch_truncated: query.include_patch.then_some(truncated),
+        }))
+    })
+    .await??;
+    Ok(Json(detail).into_response())
+}
+
+/// Most commits a comparison lists.
+const MAX_COMPARE_COMMITS: usize = 250;
+
+/// Media type of plain patches, which clients ask comparisons for with
+/// `Accept`.
+const PATCH_MEDIA_TYPE: &str = "text/x-patch";
+
+/// Query parameters of a comparison.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CompareQuery {
+    #[serde(default)]
+    include_patch: bool,
+}
+
+/// How two revisions relate and differ.
+#[derive(Debug, Serialize, ToSchema)]
+struct Comparison {
+    #[schema(value_type = String)]
+    base: CommitId,
+    #[schema(value_type = String)]
+    head: CommitId,
+    /// The newest commit both descend from, or null if they only share the
+    /// root commit
+    #[schema(value_type = Option<String>)]
+    merge_base: Option<CommitId>,
+    /// Commits `head` has that `base` doesn't
+    ahead_by: usize,
+    /// Commits `base` has that `head` doesn't
+    behind_by: usize,
+    /// The commits `head` has that `base` doesn't, newest first
+    commits: Vec<CommitResponse>,
+    /// Whether there were more commits than listed
+    commits_truncated: bool,
+    /// The files that differ from `base` to `head`
+    files: Vec<FileChangeResponse>,
+    /// Whether patches were left out to keep within the server's limit;
+    /// only present if patches were asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch_truncated: Option<bool>,
+}
+
+/// The commit a side of a comparison names: a bookmark, else a revision as
+/// [`resolve_rev`] takes.
+fn resolve_named_rev(repo: &Repository, rev: &str) -> anyhow::Result<Result<CommitId, ApiError>> {
+    if validate_bookmark_name(rev).is_ok() {
+        match repo.bookmark_target(rev)? {
+            BookmarkTarget::Normal(id) => return Ok(Ok(id)),
+            BookmarkTarget::Conflicted { .. } => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "conflicted_bookmark",
+                    format!("bookmark {rev} is conflicted; compare one of its commits"),
+                )));
+            }
+            BookmarkTarget::Absent => {}
+        }
+    }
+    resolve_rev(repo, rev)
+}
+
+/// One side of a changed file as [`git_file_patch`] takes it: `content` if
+/// it is a file there, else missing if the change `kind` leaves nothing
+/// there.
+fn patch_side_of<'a>(
+    content: Option<&'a [u8]>,
+    kind: FileChangeKind,
+    missing_when: FileChangeKind,
+) -> PatchSide<'a> {
+    match content {
+        Some(content) => PatchSide::File(content),
+        None if kind == missing_when => PatchSide::Missing,
+        None => PatchSide::Other,
+    }
+}
+
+/// Write the patch of `changes` to `writer`, a file at a time.
+fn write_patch(
+    runtime: &tokio::runtime::Handle,
+    repo: &Repository,
+    changes: Vec<FileChange>,
+    mut writer: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    for change in changes {
+        let read = |file: Option<FileId>| match file {
+            Some(id) => runtime
+                .block_on(repo.file_content(&change.path, &id))
+                .map(Some),
+            None => Ok(None),
+        };
+        let (old, new) = (read(change.old_file)?, read(change.new_file)?);
+        let patch = git_file_patch(
+            &change.path,
+            patch_side_of(old.as_deref(), change.kind, FileChangeKind::Added),
+            patch_side_of(new.as_deref(), change.kind, FileChangeKind::Deleted),
+        );
+        runtime.block_on(writer.write_all(patch.as_bytes()))?;
+    }
+    runtime.block_on(writer.shutdown())?;
+    Ok(())
+}
+
+/// Compare two revisions, given as `base...head`.
+///
+/// Either side is a bookmark, or a commit ID or change ID as
+/// [`get_commit`] takes. The answer has the merge base, the commits only
+/// `head` has, and the files that differ from `base` to `head`, with
+/// patches as [`get_commit`] gives them. Clients that accept `text/x-patch`
+/// are sent the whole diff as a plain patch instead, streamed a file at a
+/// time, which `patch` applies to `base`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/compare/{range}",
+    tag = "history",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("range" = String, Path, description = "Two revisions as base...head"),
+        CompareQuery,
+    ),
+    responses(
+        (
+            status = 200,
+            description = "How the revisions relate and differ, or a plain patch",
+            content((Comparison = "application/json"), (String = "text/x-patch"))
+        ),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 400, description = "The range is not base...head", body = ErrorBody),
+        (status = 404, description = "The repository or a revision does not exist", body = ErrorBody),
+        (status = 409, description = "A revision is ambiguous or a conflicted bookmark", body = ErrorBody),
+    )
+)]
+async fn compare(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+    query: Result<Query<CompareQuery>, QueryRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, range)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Query(query) = query?;
+    let Some((base, head)) = range.split_once("...") else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_range",
+            "compare two revisions as base...head",
+        ));
+    };
+    let (base, head) = (base.to_string(), head.to_string());
+    let as_patch = headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| {
+            accept
+                .split(',')
+                .any(|media_type| media_type.trim().starts_with(PATCH_MEDIA_TYPE))
+        });
+
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let max_patch_bytes = state.max_patch_bytes;
+    blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let (base, head) = match (
+            resolve_named_rev(&opened, &base)?,
+            resolve_named_rev(&opened, &head)?,
+        ) {
+            (Ok(base), Ok(head)) => (base, head),
+            (Err(error), _) | (_, Err(error)) => return Ok(Err(error)),
+        };
+        let changes = runtime.block_on(opened.diff_commits(&base, &head))?;
+
+        if as_patch {
+            let (reader, writer) = tokio::io::duplex(64 * 1024);
+            let patch_runtime = runtime.clone();
+            let span = tracing::debug_span!("write_patch");
+            runtime.spawn_blocking(move || {
+                let _entered = span.enter();
+                if let Err(error) = write_patch(&patch_runtime, &opened, changes, writer) {
+                    // Most likely the client hung up; the patch it has is
+                    // cut short either way.
+                    warn!(%repo, "writing patch failed: {error:#}");
+                }
+            });
+            let response = (
+                [(CONTENT_TYPE, "text/x-patch; charset=utf-8")],
+                Body::from_stream(ReaderStream::new(reader)),
+            );
+            return Ok(Ok(response.into_response()));
+        }
+
+        let (ahead_by, behind_by) = opened.ahead_behind(&head, &base)?;
+        let mut commits = opened.commits_between(&base, &head)?;
+        let commits_truncated = commits.len() > MAX_COMPARE_COMMITS;
+        commits.truncate(MAX_COMPARE_COMMITS);
+        let commits = commits
+            .iter()
+            .map(|id| opened.commit_summary(id).map(CommitResponse::from))
+            .collect::<anyhow::Result<_>>()?;
+        let (files, truncated) = runtime.block_on(describe_changes(
+            &opened,
+            changes,
+            query.include_patch,
+            max_patch_bytes,
+        ))?;
+        let comparison = Comparison {
+            base,
+            head,
+            merge_base: opened.merge_base(&base, &head)?,
+            ahead_by,
+            behind_by,
+            commits,
+            commits_truncated,
+            files,
+            patch_truncated: query.include_patch.then_some(truncated),
+        };
+        Ok(Ok(Json(comparison).into_response()))
+    })
+    .await?
+}
+
+/// A bookmark in a listing or written by a request.
+#[derive(Debug, Serialize, ToSchema)]
+struct BookmarkResponse {
+    name: String,
+    /// The commit it points at, unless it is conflicted
+    target: Option<CommitResponse>,
+    conflicted: bool,
+    /// Each commit a conflicted bookmark could point at
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = Vec<String>)]
+    conflicted_targets: Vec<CommitId>,
+    /// Commits it has that the default bookmark doesn't; absent if there is
+    /// no default bookmark or either is conflicted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ahead: Option<usize>,
+    /// Commits the default bookmark has that it doesn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    behind: Option<usize>,
+}
+
+impl BookmarkResponse {
+    /// Describe `bookmark`, counting its commits against `base`, the target
+    /// of the default bookmark.
+    fn new(repo: &Repository, bookmark: LocalRef, base: Option<&CommitId>) -> anyhow::Result<Self> {
+        if bookmark.conflicted {
+            return Ok(Self {
+                name: bookmark.name,
+                target: None,
+                conflicted: true,
+                conflicted_targets: bookmark.targets,
+                ahead: None,
+                behind: None,
+            });
+        }
+        let target = bookmark.targets[0];
+        let (ahead, behind) = match base {
+            Some(base) => {
+                let (ahead, behind) = repo.ahead_behind(&target, base)?;
+                (Some(ahead), Some(behind))
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            name: bookmark.name,
+            target: Some(repo.commit_summary(&target)?.into()),
+            conflicted: false,
+            conflicted_targets: Vec::new(),
+            ahead,
+            behind,
+        })
+    }
+}
+
+/// The commit the default bookmark points at, if there is one and it isn't
+/// conflicted.
+fn default_target(repo: &Repository) -> anyhow::Result<Option<CommitId>> {
+    let Some(bookmark) = default_bookmark(repo, RepoMetadata::load(repo)?.as_ref()) else {
+        return Ok(None);
+    };
+    Ok(match repo.bookmark_target(&bookmark)? {
+        BookmarkTarget::Normal(id) => Some(id),
+        _ => None,
+    })
+}
+
+/// A bookmark target as it reads in a message.
+fn describe_target(target: &BookmarkTarget) -> String {
+    match target {
+        BookmarkTarget::Absent => "absent".to_string(),
+        BookmarkTarget::Normal(id) => format!("at {id}"),
+        BookmarkTarget::Conflicted { adds, .. } => {
+            let adds: Vec<_> = adds.iter().map(CommitId::to_hex).collect();
+            format!("conflicted between {}", adds.join(", "))
+        }
+    }
+}
+
+/// The bookmarks of a repository.
+#[derive(Debug, Serialize, ToSchema)]
+struct BookmarkList {
+    bookmarks: Vec<BookmarkResponse>,
+}
+
+/// List bookmarks with the commits they point at, and how far each is
+/// ahead of and behind the default bookmark.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/bookmarks",
+    tag = "bookmarks",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The bookmarks", body = BookmarkList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+    )
+)]
+async fn list_bookmarks(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let bookmarks = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let base = default_target(&opened)?;
+        let bookmarks = opened
+            .local_bookmarks()?
+            .into_iter()
+            .map(|bookmark| BookmarkResponse::new(&opened, bookmark, base.as_ref()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Ok(bookmarks))
+    })
+    .await??;
+    Ok(Json(BookmarkList { bookmarks }).into_response())
+}
+
+/// A bookmark pointing at a commit with conflicts.
+#[derive(Debug, Serialize, ToSchema)]
+struct ConflictedBookmarkResponse {
+    name: String,
+    #[schema(value_type = String)]
+    commit: CommitId,
+    /// Paths with conflicts in the commit's tree, sorted
+    paths: Vec<String>,
+}
+
+/// The bookmarks of a repository at commits with conflicts.
+#[derive(Debug, Serialize, ToSchema)]
+struct ConflictList {
+    bookmarks: Vec<ConflictedBookmarkResponse>,
+    /// Whether any bookmark is conflicted between several commits
+    has_conflicted_bookmarks: bool,
+}
+
+/// List the bookmarks that point at commits with conflicts, and whether
+/// any bookmark is itself conflicted between several commits.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/conflicts",
+    tag = "bookmarks",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The bookmarks at commits with conflicts", body = ConflictList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+    )
+)]
+async fn list_conflicts(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let (bookmarks, has_conflicted_bookmarks) = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let mut paths: HashMap<CommitId, Vec<String>> = HashMap::new();
+        let mut bookmarks = Vec::new();
+        let mut has_conflicted_bookmarks = false;
+        for bookmark in opened.local_bookmarks()? {
+            if bookmark.conflicted {
+                has_conflicted_bookmarks = true;
+                continue;
+            }
+            let Some(&commit) = bookmark.targets.first() else {
+                continue;
+            };
+            let conflicted = match paths.get(&commit) {
+                Some(conflicted) => conflicted.clone(),
+                None => {
+                    let conflicted = opened.conflicted_paths(&commit)?;
+                    paths.insert(commit, conflicted.clone());
+                    conflicted
+                }
+            };
+            if !conflicted.is_empty() {
+                bookmarks.push(ConflictedBookmarkResponse {
+                    name: bookmark.name,
+                    commit,
+                    paths: conflicted,
+                });
+            }
+        }
+        Ok(Ok((bookmarks, has_conflicted_bookmarks)))
+    })
+    .await??;
+    Ok(Json(ConflictList {
+        bookmarks,
+        has_conflicted_bookmarks,
+    })
+    .into_response())
+}
+
+/// Request to point a bookmark at a commit.
+#[derive(Debug, Deserialize, ToSchema)]
+struct PutBookmarkRequest {
+    /// Revision to point it at, as [`resolve_rev`] takes
+    target: String,
+    /// Commit ID it must currently point at; null or missing if it must not
+    /// exist yet
+    #[serde(default)]
+    expected_old: Option<String>,
+    /// Allow moving it to a commit that doesn't descend from its target
+    #[serde(default)]
+    force: bool,
+}
+
+/// Query parameters of a bookmark delete.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteBookmarkQuery {
+    /// Commit ID the bookmark must currently point at
+    expected_old: String,
+}
+
+/// A bookmark write and the operation that made it.
+#[derive(Debug, Serialize, ToSchema)]
+struct BookmarkWriteResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bookmark: Option<BookmarkResponse>,
+    #[schema(value_type = String)]
+    operation_id: OperationId,
+}
+
+/// The bookmark a request path names, checked as storage would.
+fn bookmark_name(bookmark: String) -> Result<String, ApiError> {
+    match validate_bookmark_name(&bookmark) {
+        Ok(()) => Ok(bookmark),
+        Err(error) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_bookmark_name",
+            format!("invalid bookmark name {bookmark:?}: {error}"),
+        )),
+    }
+}
+
+/// The target an `expected_old` field expects, or `None` if it isn't a
+/// commit ID.
+fn expected_target(expected_old: Option<&str>) -> Option<BookmarkTarget> {
+    match expected_old {
+        Some(id) => CommitId::from_hex(id).ok().map(BookmarkTarget::Normal),
+        None => Some(BookmarkTarget::Absent),
+    }
+}
+
+/// Why a repository refuses bookmark writes, if it does.
+fn write_refusal(metadata: Option<&RepoMetadata>) -> Option<StorageError> {
+    metadata
+        .is_some_and(|metadata| metadata.archived)
+        .then_some(StorageError::Archived)
+}
+
+/// Whether `bookmark` is the default bookmark of a protected repository,
+/// which can't be deleted or rewound.
+fn guards_bookmark(repo: &Repository, metadata: Option<&RepoMetadata>, bookmark: &str) -> bool {
+    metadata.is_some_and(|metadata| metadata.protected)
+        && default_bookmark(repo, metadata).as_deref() == Some(bookmark)
+}
+
+/// Move a bookmark, or delete it, if it is still where the request expects;
+/// otherwise the answer is 409. Returns the new operation, having told
+/// `hooks` of the move.
+fn write_bookmark(
+    repo: &mut Repository,
+    hooks: &Arc<HookDispatcher>,
+    user: &AuthenticatedUser,
+    name: &str,
+    expected: BookmarkTarget,
+    target: Option<CommitId>,
+) -> anyhow::Result<Result<OperationId, ApiError>> {
+    let action = match target {
+        Some(_) => "set",
+        None => "delete",
+    };
+    let description = format!(
+        "{action} bookmark {name} from {} via the API",
+        user.username
+    );
+    let updates = [BookmarkUpdate {
+        name: name.to_string(),
+        expected,
+        target,
+    }];
+    match repo.update_bookmarks(&updates, &description) {
+        Ok(operation) => {
+            let info = repo.info();
+            hooks.bookmarks_moved(
+                HookEvent::Bookmark,
+                BookmarksMoved {
+                    repo: RepoRef::new(&info.owner, &info.name),
+                    identity: Some(user.username.clone()),
+                    operation,
+                    updates: updates.into(),
+                },
+            );
+            Ok(Ok(operation))
+        }
+        Err(error) => match StorageError::of(&error) {
+            Some(refused) => Ok(Err(refused.into())),
+            None => Err(error),
+        },
+    }
+}
+
+/// Point a bookmark at a commit, creating it if `expected_old` is null.
+///
+/// The write is a compare-and-set: a bookmark that isn't at `expected_old`
+/// is answered with 409 `stale_bookmark`. Moves that aren't fast-forwards
+/// need `force`, and can't rewind the default bookmark of a protected
+/// repository. Archived repositories are answered with 423.
+#[utoipa::path(
+    put,
+    path = "/api/v1/repos/{owner}/{name}/bookmarks/{bookmark}",
+    tag = "bookmarks",
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("bookmark" = String, Path, description = "Name of the bookmark"),
+    ),
+    request_body = PutBookmarkRequest,
+    security(("bearer" = ["repo:write"])),
+    responses(
+        (status = 200, description = "The bookmark was moved", body = BookmarkWriteResponse),
+        (status = 201, description = "The bookmark was created", body = BookmarkWriteResponse),
+        (status = 400, description = "The bookmark name is invalid", body = ErrorBody),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository or revision does not exist", body = ErrorBody),
+        (status = 409, description = "The bookmark has moved, or the move is not a fast-forward", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+        (status = 423, description = "The repository is archived, or the bookmark is protected", body = ErrorBody),
+    )
+)]
+async fn put_bookmark(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+    payload: Result<Json<PutBookmarkRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, bookmark)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let bookmark = bookmark_name(bookmark)?;
+    let Json(payload) = payload?;
+    let Some(expected) = expected_target(payload.expected_old.as_deref()) else {
+        return Err(ApiError::invalid_fields(vec![FieldError {
+            field: "expected_old",
+            message: "must be a commit ID or null".to_string(),
+        }]));
+    };
+
+    let repos = state.repos.clone();
+    let hooks = state.hooks.clone();
+    let (created, response) = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let mut opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let metadata = RepoMetadata::load(&opened)?;
+        if let Some(refused) = write_refusal(metadata.as_ref()) {
+            return Ok(Err(refused.into()));
+        }
+        let target = match resolve_rev(&opened, &payload.target)? {
+            Ok(target) => target,
+            Err(error) => return Ok(Err(error)),
+        };
+        // Where the bookmark is doesn't matter if it isn't where the
+        // request expects: the write is refused as stale.
+        if let BookmarkTarget::Normal(old) = &expected {
+            if opened.bookmark_target(&bookmark)? == expected
+                && !opened.is_ancestor(old, &target)?
+            {
+                if guards_bookmark(&opened, metadata.as_ref(), &bookmark) {
+                    let refused = StorageError::Protected { name: bookmark };
+                    return Ok(Err(refused.into()));
+                }
+                if !payload.force {
+                    return Ok(Err(ApiError::new(
+                        StatusCode::CONFLICT,
+                        "non_fast_forward",
+                        format!("moving {bookmark} to {target} needs force"),
+                    )));
+                }
+            }
+        }
+        let created = expected == BookmarkTarget::Absent;
+        let operation_id = match write_bookmark(
+            &mut opened,
+            &hooks,
+            &user,
+            &bookmark,
+            expected,
+            Some(target),
+        )? {
+            Ok(operation_id) => operation_id,
+            Err(error) => return Ok(Err(error)),
+        };
+        info!(%repo, %bookmark, %target, operation = %operation_id, "set bookmark");
+        let written = LocalRef {
+            name: bookmark,
+            targets: vec![target],
+            conflicted: false,
+        };
+        let base = default_target(&opened)?;
+        let response = BookmarkWriteResponse {
+            bookmark: Some(BookmarkResponse::new(&opened, written, base.as_ref())?),
+            operation_id,
+        };
+        Ok(Ok((created, response)))
+    })
+    .await??;
+
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+/// Delete a bookmark if it is still at `?expected_old=`, like
+/// [`put_bookmark`] moves one.
+///
+/// The default bookmark of a protected repository can't be deleted.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/repos/{owner}/{name}/bookmarks/{bookmark}",
+    tag = "bookmarks",
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("bookmark" = String, Path, description = "Name of the bookmark"),
+        DeleteBookmarkQuery,
+    ),
+    security(("bearer" = ["repo:write"])),
+    responses(
+        (status = 200, description = "The bookmark was deleted", body = BookmarkWriteResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository or bookmark does not exist", body = ErrorBody),
+        (status = 409, description = "The bookmark has moved", body = ErrorBody),
+        (status = 423, description = "The repository is archived, or the bookmark is protected", body = ErrorBody),
+    )
+)]
+async fn delete_bookmark(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+    query: Result<Query<DeleteBookmarkQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, bookmark)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let bookmark = bookmark_name(bookmark)?;
+    let Query(query) = query?;
+    let Some(expected) = expected_target(Some(&query.expected_old)) else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request",
+            "expected_old must be a commit ID",
+        ));
+    };
+
+    let repos = state.repos.clone();
+    let hooks = state.hooks.clone();
+    let response = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let mut opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let metadata = RepoMetadata::load(&opened)?;
+        if let Some(refused) = write_refusal(metadata.as_ref()) {
+            return Ok(Err(refused.into()));
+        }
+        if opened.bookmark_target(&bookmark)? == BookmarkTarget::Absent {
+            return Ok(Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "bookmark_not_found",
+                format!("bookmark {bookmark} does not exist"),
+            )));
+        }
+        if guards_bookmark(&opened, metadata.as_ref(), &bookmark) {
+            return Ok(Err(StorageError::Protected { name: bookmark }.into()));
+        }
+        let operation_id =
+            match write_bookmark(&mut opened, &hooks, &user, &bookmark, expected, None)? {
+                Ok(operation_id) => operation_id,
+                Err(error) => return Ok(Err(error)),
+            };
+        info!(%repo, %bookmark, operation = %operation_id, "deleted bookmark");
+        Ok(Ok(BookmarkWriteResponse {
+            bookmark: None,
+            operation_id,
+        }))
+    })
+    .await??;
+    Ok(Json(response).into_response())
+}
+
+/// Longest hook secret accepted, in bytes.
+const MAX_HOOK_SECRET_LEN: usize = 256;
+
+/// Body of a request to add a hook.
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateHookRequest {
+    /// An http:// URL to post payloads to
+    url: String,
+    /// Key to sign payloads with
+    secret: String,
+    /// Which events to post; all of them if missing
+    #[serde(default)]
+    events: Option<Vec<HookEvent>>,
+}
+
+/// Hooks of a repository, oldest first.
+#[derive(Debug, Serialize, ToSchema)]
+struct HookList {
+    hooks: Vec<HookInfo>,
+}
+
+/// Attempts at delivering to a hook, newest first.
+#[derive(Debug, Serialize, ToSchema)]
+struct DeliveryList {
+    deliveries: Vec<Delivery>,
+}
+
+/// The 404 for a hook the repository doesn't have.
+fn hook_not_found(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "hook_not_found",
+        format!("hook {id} does not exist"),
+    )
+}
+
+/// Add a hook to a repository.
+///
+/// When a push or a request to the API moves the repository's bookmarks,
+/// a JSON payload describing the move is posted to the hook's URL, signed
+/// with its secret in the `X-Forjj-Signature-256` header. Deliveries that
+/// fail are tried again, waiting longer each time. The secret isn't shown
+/// again.
+#[utoipa::path(
+    post,
+    path = "/api/v1/repos/{owner}/{name}/hooks",
+    tag = "hooks",
+    request_body = CreateHookRequest,
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 201, description = "The hook was added", body = HookInfo),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+    )
+)]
+async fn create_hook(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    payload: Result<Json<CreateHookRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+    let Json(payload) = payload?;
+    let mut fields = Vec::new();
+    if let Err(message) = hooks::validate_url(&payload.url) {
+        fields.push(FieldError {
+            field: "url",
+            message,
+        });
+    }
+    if payload.secret.is_empty() || payload.secret.len() > MAX_HOOK_SECRET_LEN {
+        fields.push(FieldError {
+            field: "secret",
+            message: format!("must be 1 to {MAX_HOOK_SECRET_LEN} bytes"),
+        });
+    }
+    let events = payload.events.unwrap_or_else(|| HookEvent::ALL.to_vec());
+    if events.is_empty() {
+        fields.push(FieldError {
+            field: "events",
+            message: "must name at least one event".to_string(),
+        });
+    }
+    if !fields.is_empty() {
+        return Err(ApiError::invalid_fields(fields));
+    }
+
+    let hook = Hook::new(payload.url, payload.secret, events);
+    let repos = state.repos.clone();
+    let info = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let info = hook.info();
+        hooks::add_hook(&opened, hook)?;
+        info!(%repo, hook = %info.id, url = %info.url, "added hook");
+        Ok(Ok(info))
+    })
+    .await??;
+    Ok((StatusCode::CREATED, Json(info)).into_response())
+}
+
+/// List a repository's hooks.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/hooks",
+    tag = "hooks",
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 200, description = "The repository's hooks", body = HookList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+    )
+)]
+async fn list_hooks(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+
+    let repos = state.repos.clone();
+    let hooks = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let info = repos.repo_info(&repo.owner, &repo.name)?;
+        Ok(Ok(hooks::load_hooks(&info)?))
+    })
+    .await??;
+    let hooks = hooks.iter().map(Hook::info).collect();
+    Ok(Json(HookList { hooks }).into_response())
+}
+
+/// Remove a hook from a repository.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/repos/{owner}/{name}/hooks/{id}",
+    tag = "hooks",
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("id" = String, Path, description = "ID of the hook"),
+    ),
+    responses(
+        (status = 204, description = "The hook is removed"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository or hook does not exist", body = ErrorBody),
+    )
+)]
+async fn delete_hook(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path((owner, name, id)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+
+    let repos = state.repos.clone();
+    let remove_id = id.clone();
+    let removed = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        Ok(Ok(hooks::remove_hook(&opened, &remove_id)?))
+    })
+    .await??;
+    if !removed {
+        return Err(hook_not_found(&id));
+    }
+    state.hooks.forget(&id);
+    info!(hook = %id, "removed hook");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the last attempts at delivering to a hook.
+///
+/// Attempts are kept in memory, so none are listed from before the server
+/// last started.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/hooks/{id}/deliveries",
+    tag = "hooks",
+    security(("bearer" = ["repo:write"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("id" = String, Path, description = "ID of the hook"),
+    ),
+    responses(
+        (status = 200, description = "Attempts at delivering to the hook", body = DeliveryList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write, or the owner is not the user", body = ErrorBody),
+        (status = 404, description = "The repository or hook does not exist", body = ErrorBody),
+    )
+)]
+async fn list_deliveries(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, id)) = params?;
+    let repo = repo_ref(owner, name)?;
+    require_owner(&user, &repo.owner)?;
+
+    let repos = state.repos.clone();
+    let hooks = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let info = repos.repo_info(&repo.owner, &repo.name)?;
+        Ok(Ok(hooks::load_hooks(&info)?))
+    })
+    .await??;
+    // Only the repository's own hooks' deliveries are shown.
+    if !hooks.iter().any(|hook| hook.id == id) {
+        return Err(hook_not_found(&id));
+    }
+    let deliveries = state.hooks.deliveries(&id);
+    Ok(Json(DeliveryList { deliveries }).into_response())
+}
+
+/// Hex digits of the commit ID in an archive's name.
+const ARCHIVE_ID_LEN: usize = 12;
+
+/// Download the tree at a revision as a gzipped tarball.
+///
+/// `rev` is a bookmark, or a commit ID or change ID as [`get_commit`] takes,
+/// followed by `.tar.gz`. Everything in the archive is under
+/// `{name}-{short id}/`. The same commit always gives the same bytes, so
+/// its ID is the ETag. The archive is compressed as it is sent rather than
+/// built up first. Trees with conflicts are answered with 409.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/archive/{file}",
+    tag = "files",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("file" = String, Path, description = "A revision followed by .tar.gz"),
+    ),
+    responses(
+        (status = 200, description = "The tree as a gzipped tarball"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "The repository, revision or format does not exist", body = ErrorBody),
+        (status = 409, description = "The tree has conflicts", body = ErrorBody),
+    )
+)]
+async fn get_archive(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String)>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, file)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Some(rev) = file.strip_suffix(".tar.gz").map(str::to_string) else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_format",
+            "archives are available as .tar.gz",
+        ));
+    };
+    let repos = state.repos.clone();
+    blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let id = match resolve_named_rev(&opened, &rev)? {
+            Ok(id) => id,
+            Err(error) => return Ok(Err(error)),
+        };
+        if opened.commit_summary(&id)?.conflict {
+            return Ok(Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "conflicted_tree",
+                format!("the tree of {rev} has conflicts"),
+            )));
+        }
+        let etag = format!("\"{id}\"");
+        let current = headers
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| etag_matches(value, &etag));
+        if current {
+            return Ok(Ok(
+                (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response()
+            ));
+        }
+
+        let base_name = format!("{}-{}", repo.name, id.short(ARCHIVE_ID_LEN));
+        let prefix = format!("{base_name}/");
+        let (reader, writer) = tokio::io::duplex(64 * 1024);
+        let span = tracing::debug_span!("write_archive");
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let encoder = GzEncoder::new(SyncIoBridge::new(writer), Compression::default());
+            let written = opened
+                .export_tar(&id, &prefix, encoder)
+                .and_then(|encoder| Ok(encoder.finish()?.shutdown()?));
+            if let Err(error) = written {
+                // Most likely the client hung up; the archive it has is
+                // cut short either way.
+                warn!(%repo, "writing archive failed: {error:#}");
+            }
+        });
+        let disposition = format!("attachment; filename=\"{base_name}.tar.gz\"");
+        let response = (
+            [
+                (CONTENT_TYPE, "application/gzip".to_string()),
+                (CONTENT_DISPOSITION, disposition),
+                (ETAG, etag),
+            ],
+            Body::from_stream(ReaderStream::new(reader)),
+        );
+        Ok(Ok(response.into_response()))
+    })
+    .await?
+}
+
+/// Lines of a file last changed in the same commit.
+#[derive(Debug, Serialize, ToSchema)]
+struct BlameRangeResponse {
+    /// Number of the first line, counting from 1
+    start_line: usize,
+    line_count: usize,
+    commit: CommitResponse,
+}
+
+/// Which commit each line of a file comes from.
+#[derive(Debug, Serialize, ToSchema)]
+struct BlameResponse {
+    path: String,
+    /// ID of the file's contents, which changes whenever they do
+    #[schema(value_type = String)]
+    file_id: FileId,
+    ranges: Vec<BlameRangeResponse>,
+}
+
+/// Get the commit each line of a file at a revision last changed in.
+///
+/// History is followed through first parents. Binary files are answered
+/// with 422, and files with more lines than the server's limit with 413.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/blame/{rev}/{path}",
+    tag = "files",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("rev" = String, Path, description = "A commit ID or change ID, or a prefix of either"),
+        ("path" = String, Path, description = "Path of the file, which may have slashes"),
+    ),
+    responses(
+        (status = 200, description = "The commit each line comes from", body = BlameResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 400, description = "The path is not a file", body = ErrorBody),
+        (status = 404, description = "The repository, revision or path does not exist", body = ErrorBody),
+        (status = 409, description = "The file has conflicts", body = ErrorBody),
+        (status = 413, description = "The file has more lines than the server blames", body = ErrorBody),
+        (status = 422, description = "The file is binary", body = ErrorBody),
+    )
+)]
+async fn get_blame(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String, String)>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, rev, path)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let max_blame_lines = state.max_blame_lines;
+    let blame = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let id = match resolve_rev(&opened, &rev)? {
+            Ok(id) => id,
+            Err(error) => return Ok(Err(error)),
+        };
+        let file = match runtime.block_on(opened.path_value(&id, &path))? {
+            PathValue::File { id, .. } => id,
+            PathValue::Conflict { sides } => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "conflicted_file",
+                    format!("{path} has a conflict between {sides} sides at {rev}"),
+                )));
+            }
+            PathValue::Absent => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "path_not_found",
+                    format!("{path} does not exist at {rev}"),
+                )));
+            }
+            PathValue::Symlink(_) | PathValue::Tree | PathValue::Other => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "not_a_file",
+                    format!("{path} is not a file at {rev}"),
+                )));
+            }
+        };
+        let content = runtime.block_on(opened.file_content(&path, &file))?;
+        if looks_binary(&content) {
+            return Ok(Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "binary_file",
+                format!("{path} is not text"),
+            )));
+        }
+        let lines = content.split_inclusive(|&b| b == b'\n').count();
+        if lines > max_blame_lines {
+            return Ok(Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "file_too_large",
+                format!(
+                    "{path} has {lines} lines, over the limit of {max_blame_lines}; \
+                     fetch it from /raw/{rev}/{path} instead"
+                ),
+            )));
+        }
+
+        let mut summaries = HashMap::new();
+        let mut ranges = Vec::new();
+        for range in runtime.block_on(opened.blame(&id, &path))? {
+            let summary = match summaries.get(&range.commit) {
+                Some(summary) => summary.clone(),
+                None => {
+                    let summary = opened.commit_summary(&range.commit)?;
+                    summaries.insert(range.commit, summary.clone());
+                    summary
+                }
+            };
+            ranges.push(BlameRangeResponse {
+                start_line: range.start_line,
+                line_count: range.line_count,
+                commit: summary.into(),
+            });
+        }
+        Ok(Ok(BlameResponse {
+            path,
+            file_id: file,
+            ranges,
+        }))
+    })
+    .await??;
+    Ok(Json(blame).into_response())
+}
+
+/// Query parameters of repository statistics.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StatsQuery {
+    /// Scan the repository even if the last scan is recent
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Statistics of a repository.
+#[derive(Debug, Serialize, ToSchema)]
+struct StatsResponse {
+    /// Commits reachable from the visible heads
+    commit_count: usize,
+    bookmark_count: usize,
+    tag_count: usize,
+    head_count: usize,
+    /// Operations in the operation log
+    operation_count: usize,
+    /// When the oldest commit was committed, in seconds since the Unix epoch
+    first_commit_at: Option<i64>,
+    /// When the newest commit was committed, in seconds since the Unix epoch
+    latest_commit_at: Option<i64>,
+    /// Bytes the repository takes on disk, as of `scanned_at`
+    disk_size: u64,
+    /// Largest files at the tip of the default bookmark, as of `scanned_at`
+    largest_files: Vec<LargeFile>,
+    /// When the repository was last scanned, in seconds since the Unix epoch
+    scanned_at: u64,
+}
+
+/// Get statistics of a repository.
+///
+/// Counts are found on every request. The disk size and largest files take
+/// a scan, so they are kept and only found again once they are older than
+/// the server's limit, or when asked with `?refresh=true`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/stats",
+    tag = "repositories",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        StatsQuery,
+    ),
+    responses(
+        (status = 200, description = "Statistics of the repository", body = StatsResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+    )
+)]
+async fn get_stats(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    query: Result<Query<StatsQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Query(query) = query?;
+    let repos = state.repos.clone();
+    let scans = state.stats_scans.clone();
+    let metrics = state.metrics.clone();
+    let max_age = state.stats_max_age;
+    let runtime = tokio::runtime::Handle::current();
+    let stats = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let commits = opened.commit_stats()?;
+        let operations = runtime.block_on(opened.operation_log())?;
+
+        let now = unix_now();
+        let kept = if query.refresh {
+            None
+        } else {
+            // A cache that can't be read is as good as none.
+            ScannedStats::load(&opened).unwrap_or_else(|error| {
+                warn!("ignoring the cached statistics of {repo}: {error:#}");
+                None
+            })
+        };
+        let kept = kept.filter(|kept| !kept.is_stale(now, max_age));
+        metrics.stats_cache(kept.is_some());
+        let scanned = match kept {
+            Some(kept) => kept,
+            None => {
+                scans.fetch_add(1, Ordering::Relaxed);
+                let largest_files = match default_target(&opened)? {
+                    Some(tip) => runtime
+                        .block_on(opened.largest_files(&tip, LARGEST_FILES_LISTED))?
+                        .into_iter()
+                        .map(|file| LargeFile {
+                            path: file.path,
+                            size: file.size,
+                        })
+                        .collect(),
+                    None => Vec::new(),
+                };
+                let scanned = ScannedStats {
+                    scanned_at: now,
+                    disk_size: opened.disk_size()?,
+                    largest_files,
+                };
+                scanned.store(&opened)?;
+                scanned
+            }
+        };
+
+        Ok(Ok(StatsResponse {
+            commit_count: commits.count,
+            bookmark_count: opened.bookmark_names().len(),
+            tag_count: opened.tag_names().len(),
+            head_count: opened.head_ids()?.len(),
+            operation_count: operations.len(),
+            first_commit_at: commits.first_time.map(|time| time.div_euclid(1000)),
+            latest_commit_at: commits.latest_time.map(|time| time.div_euclid(1000)),
+            disk_size: scanned.disk_size,
+            largest_files: scanned.largest_files,
+            scanned_at: scanned.scanned_at,
+        }))
+    })
+    .await??;
+    Ok(Json(stats).into_response())
+}
+
+/// Query parameters of a file's history.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FileHistoryQuery {
+    limit: Option<usize>,
+    /// ID of the last commit of the previous page
+    cursor: Option<String>,
+}
+
+/// A commit that changes what is at a path.
+#[derive(Debug, Serialize, ToSchema)]
+struct FileHistoryEntryResponse {
+    commit: CommitResponse,
+    kind: &'static str,
+    /// ID of the file after the commit, if there is one
+    #[schema(value_type = Option<String>)]
+    file_id: Option<FileId>,
+}
+
+/// A page of the history of a path.
+#[derive(Debug, Serialize, ToSchema)]
+struct FileHistoryResponse {
+    path: String,
+    /// The commits that change what is at the path, newest first
+    entries: Vec<FileHistoryEntryResponse>,
+    /// Where the next page starts, or null on the last page
+    next_cursor: Option<String>,
+}
+
+/// List the commits in the history of a revision that change what is at a
+/// path, a page at a time.
+///
+/// Takes `?limit=` and `?cursor=` to page through them. A path that never
+/// existed has an empty history rather than a 404.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/history/{rev}/{path}",
+    tag = "files",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("rev" = String, Path, description = "A bookmark, or a commit ID or change ID or a prefix of either"),
+        ("path" = String, Path, description = "Path of the file, which may have slashes"),
+        FileHistoryQuery,
+    ),
+    responses(
+        (status = 200, description = "A page of the commits that change the path", body = FileHistoryResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 400, description = "The cursor is invalid", body = ErrorBody),
+        (status = 404, description = "The repository or revision does not exist", body = ErrorBody),
+    )
+)]
+async fn get_file_history(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String, String)>, PathRejection>,
+    query: Result<Query<FileHistoryQuery>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, rev, path)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let Query(query) = query?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|cursor| CommitId::from_hex(cursor).map_err(|_| ApiError::invalid_cursor()))
+        .transpose()?;
+
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let (path, history) = blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let start = match resolve_named_rev(&opened, &rev)? {
+            Ok(start) => start,
+            Err(error) => return Ok(Err(error)),
+        };
+        if let Some(after) = &after {
+            if !opened.has_commit(after) || !opened.is_ancestor(after, &start)? {
+                return Ok(Err(ApiError::invalid_cursor()));
+            }
+        }
+        let history =
+            runtime.block_on(opened.file_history(&start, &path, after.as_ref(), limit))?;
+        Ok(Ok((path, history)))
+    })
+    .await??;
+
+    let next_cursor = history
+        .entries
+        .last()
+        .filter(|_| history.more)
+        .map(|last| last.commit.id.to_hex());
+    let entries: Vec<FileHistoryEntryResponse> = history
+        .entries
+        .into_iter()
+        .map(|entry| FileHistoryEntryResponse {
+            kind: entry.kind.as_str(),
+            file_id: entry.file,
+            commit: entry.commit.into(),
+        })
+        .collect();
+    Ok(Json(FileHistoryResponse {
+        path,
+        entries,
+        next_cursor,
+    })
+    .into_response())
+}
+
+/// Header marking a raw answer as the target of a symlink rather than the
+/// contents of a file.
+const SYMLINK_HEADER: &str = "x-forjj-symlink";
+
+/// Get the contents of a file at a revision, as they are.
+///
+/// The file is streamed from storage rather than read into memory. A single
+/// `Range` is honored; without one, files over the server's limit are
+/// answered with 413. The ETag is the file's ID, so `If-None-Match` saves
+/// fetching a file again. Symlinks are answered with their target, marked by
+/// the `X-Forjj-Symlink` header.
+#[utoipa::path(
+    get,
+    path = "/api/v1/repos/{owner}/{name}/raw/{rev}/{path}",
+    tag = "files",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+        ("rev" = String, Path, description = "A commit ID or change ID, or a prefix of either"),
+        ("path" = String, Path, description = "Path of the file, which may have slashes"),
+        ("Range" = Option<String>, Header, description = "A single byte range"),
+    ),
+    responses(
+        (status = 200, description = "The contents of the file, or the target of a symlink"),
+        (status = 206, description = "The range of the file asked for"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 304, description = "The client's copy is current"),
+        (status = 400, description = "The path is not a file", body = ErrorBody),
+        (status = 404, description = "The repository, revision or path does not exist", body = ErrorBody),
+        (status = 409, description = "The file has conflicts", body = ErrorBody),
+        (status = 413, description = "The file is over the server's limit without a range", body = ErrorBody),
+        (status = 416, description = "The range is past the end of the file", body = ErrorBody),
+    )
+)]
+async fn get_raw(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String, String, String)>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path((owner, name, rev, path)) = params?;
+    let repo = repo_ref(owner, name)?;
+    let repos = state.repos.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let max_raw_bytes = state.max_raw_bytes;
+    blocking(move || {
+        if !repos.repo_exists(&repo.owner, &repo.name) {
+            return Ok(Err(ApiError::not_found(&repo)));
+        }
+        let opened = repos.open_repo(&repo.owner, &repo.name)?;
+        let id = match resolve_rev(&opened, &rev)? {
+            Ok(id) => id,
+            Err(error) => return Ok(Err(error)),
+        };
+        let file = match runtime.block_on(opened.path_value(&id, &path))? {
+            PathValue::File { id, .. } => id,
+            PathValue::Symlink(id) => {
+                let target = runtime.block_on(opened.symlink_target(&path, &id))?;
+                let response = (
+                    [
+                        (CONTENT_TYPE, "text/plain; charset=utf-8"),
+                        (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+                    ],
+                    [(SYMLINK_HEADER, "true")],
+                    target,
+                );
+                return Ok(Ok(response.into_response()));
+            }
+            PathValue::Conflict { sides } => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "conflicted_file",
+                    format!("{path} has a conflict between {sides} sides at {rev}"),
+                )));
+            }
+            PathValue::Absent => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "path_not_found",
+                    format!("{path} does not exist at {rev}"),
+                )));
+            }
+            PathValue::Tree | PathValue::Other => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "not_a_file",
+                    format!("{path} is not a file at {rev}"),
+                )));
+            }
+        };
+        let etag = format!("\"{file}\"");
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, HeaderValue::from_str(&etag)?);
+        response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        response_headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        let current = headers
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| etag_matches(value, &etag));
+        if current {
+            return Ok(Ok(
+                (StatusCode::NOT_MODIFIED, response_headers).into_response()
+            ));
+        }
+
+        // The size and type come from reading the file through once, since
+        // storage doesn't record them.
+        let reader = runtime.block_on(opened.open_file(&path, &file))?;
+        let (size, head) = runtime.block_on(measure(reader))?;
+        let media_type = content_type(&path, looks_binary(&head));
+        response_headers.insert(CONTENT_TYPE, HeaderValue::from_static(media_type));
+        let range = headers.get(RANGE).and_then(|value| value.to_str().ok());
+        let (status, start, len) = match parse_range(range, size) {
+            RangeRequest::Whole if size > max_raw_bytes => {
+                return Ok(Err(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "file_too_large",
+                    format!(
+                        "{path} is {size} bytes, over the limit of {max_raw_bytes}; \
+                         fetch it in parts with a Range header"
+                    ),
+                )));
+            }
+            RangeRequest::Whole => (StatusCode::OK, 0, size),
+            RangeRequest::Partial { start, len } => {
+                let content_range = format!("bytes {start}-{}/{size}", start + len - 1);
+                response_headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range)?);
+                (StatusCode::PARTIAL_CONTENT, start, len)
+            }
+            RangeRequest::Unsatisfiable => {
+                let content_range = format!("bytes */{size}");
+                response_headers.insert(CONTENT_RANGE, HeaderValue::from_str(&content_range)?);
+                let error = ApiError::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "range_not_satisfiable",
+                    format!("{path} is {size} bytes"),
+                );
+                return Ok(Ok((response_headers, error).into_response()));
+            }
+        };
+        response_headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+
+        let mut reader = runtime.block_on(opened.open_file(&path, &file))?;
+        runtime.block_on(tokio::io::copy(
+            &mut (&mut reader).take(start),
+            &mut tokio::io::sink(),
+        ))?;
+        let body = Body::from_stream(ReaderStream::new(reader.take(len)));
+        Ok(Ok((status, response_headers, body).into_response()))
+    })
+    .await?
+}
+
+/// Body of a request to create an account.
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateUserRequest {
+    /// Also the owner name of the user's repositories
+    username: String,
+}
+
+/// Create an account.
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/users",
+    tag = "admin",
+    request_body = CreateUserRequest,
+    security(("bearer" = ["admin"])),
+    responses(
+        (status = 201, description = "The new account", body = User),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant admin", body = ErrorBody),
+        (status = 409, description = "There already is an account by that name", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+    )
+)]
+async fn create_user(
+    State(state): State<AppState>,
+    payload: Result<Json<CreateUserRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    if let Err(error) = validate_name(&payload.username) {
+        return Err(ApiError::invalid_fields(vec![FieldError {
+            field: "username",
+            message: error.to_string(),
+        }]));
+    }
+
+    let user = User {
+        username: payload.username,
+        created_at: unix_now(),
+    };
+    let users = state.users.clone();
+    let created = user.clone();
+    if !blocking(move || users.create(created)).await? {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "already_exists",
+            format!("user {} already exists", user.username),
+        ));
+    }
+    info!(user = %user.username, "created user");
+    Ok((StatusCode::CREATED, Json(user)).into_response())
+}
+
+/// Longest name a token can be given, in bytes.
+const MAX_TOKEN_NAME_LEN: usize = 100;
+
+/// Body of a request to make a token.
+#[derive(Debug, Deserialize, ToSchema)]
+struct CreateTokenRequest {
+    /// What to call the token, to tell it apart from the user's others
+    name: String,
+    /// What the token allows; no more than the request's own token does
+    scopes: Vec<Scope>,
+    /// When the token stops working, in seconds since the Unix epoch
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+impl CreateTokenRequest {
+    /// Check the fields, for a token made by `user` at `now`.
+    fn validate(&self, user: &AuthenticatedUser, now: u64) -> Result<(), ApiError> {
+        let mut fields = Vec::new();
+        if self.name.trim().is_empty() || self.name.len() > MAX_TOKEN_NAME_LEN {
+            fields.push(FieldError {
+                field: "name",
+                message: format!("must be 1 to {MAX_TOKEN_NAME_LEN} bytes"),
+            });
+        }
+        if self.scopes.is_empty() {
+            fields.push(FieldError {
+                field: "scopes",
+                message: "at least one scope is needed".to_string(),
+            });
+        }
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            fields.push(FieldError {
+                field: "expires_at",
+                message: "must be in the future".to_string(),
+            });
+        }
+        if !fields.is_empty() {
+            return Err(ApiError::invalid_fields(fields));
+        }
+        // A token can't be used to make a more powerful one.
+        match self.scopes.iter().find(|scope| !user.has(**scope)) {
+            Some(scope) => Err(ApiError::forbidden(*scope)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A token just made: the only time the token itself is answered.
+#[derive(Debug, Serialize, ToSchema)]
+struct CreatedTokenResponse {
+    #[serde(flatten)]
+    info: TokenInfo,
+    /// The token, to send as `Authorization: Bearer <token>`
+    token: String,
+}
+
+/// A user's tokens.
+#[derive(Debug, Serialize, ToSchema)]
+struct TokenList {
+    tokens: Vec<TokenInfo>,
+}
+
+/// Make an API token for the request's user.
+///
+/// The token is in the answer and nowhere else: the server keeps only its
+/// hash.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/tokens",
+    tag = "user",
+    request_body = CreateTokenRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 201, description = "The new token", body = CreatedTokenResponse),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token asked for would allow more than the request's", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+    )
+)]
+async fn create_token(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    payload: Result<Json<CreateTokenRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    payload.validate(&user, unix_now())?;
+
+    let new = NewToken {
+        name: payload.name,
+        scopes: payload.scopes,
+        expires_at: payload.expires_at,
+    };
+    let tokens = state.tokens.clone();
+    let username = user.username.clone();
+    let (info, token) = blocking(move || tokens.create_token(&username, new)).await?;
+    info!(user = %user.username, token = %info.id, "created API token");
+    let created = CreatedTokenResponse { info, token };
+    Ok((StatusCode::CREATED, Json(created)).into_response())
+}
+
+/// List the request's user's API tokens, without the tokens themselves.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/tokens",
+    tag = "user",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The user's tokens, oldest first", body = TokenList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+    )
+)]
+async fn list_tokens(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Result<Json<TokenList>, ApiError> {
+    let tokens = state.tokens.clone();
+    let tokens = blocking(move || tokens.list_tokens(&user.username)).await?;
+    Ok(Json(TokenList { tokens }))
+}
+
+/// Revoke one of the request's user's API tokens.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/tokens/{id}",
+    tag = "user",
+    security(("bearer" = [])),
+    params(("id" = String, Path, description = "ID of the token")),
+    responses(
+        (status = 204, description = "The token is revoked"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The user has no such token", body = ErrorBody),
+    )
+)]
+async fn revoke_token(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<String>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = params?;
+    let tokens = state.tokens.clone();
+    let username = user.username.clone();
+    let revoke_id = id.clone();
+    if !blocking(move || tokens.revoke_token(&username, &revoke_id)).await? {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "token_not_found",
+            format!("token {id} does not exist"),
+        ));
+    }
+    info!(user = %user.username, token = %id, "revoked API token");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Longest title a key can be given, in bytes.
+const MAX_KEY_TITLE_LEN: usize = 100;
+
+/// Body of a request to add an SSH key.
+#[derive(Debug, Deserialize, ToSchema)]
+struct AddKeyRequest {
+    /// What to call the key, to tell it apart from the user's others
+    title: String,
+    /// The public key in OpenSSH format, as in `~/.ssh/id_ed25519.pub`
+    key: String,
+}
+
+/// A user's SSH keys.
+#[derive(Debug, Serialize, ToSchema)]
+struct KeyList {
+    keys: Vec<KeyInfo>,
+}
+
+/// Add an SSH key for the request's user.
+///
+/// The key writes to the user's repositories and reads others' over the
+/// SSH transport.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/keys",
+    tag = "user",
+    request_body = AddKeyRequest,
+    security(("bearer" = ["repo:write"])),
+    responses(
+        (status = 201, description = "The key was added", body = KeyInfo),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 403, description = "The token does not grant repo:write", body = ErrorBody),
+        (status = 409, description = "The key is already added", body = ErrorBody),
+        (status = 422, description = "Fields of the request are invalid", body = ErrorBody),
+    )
+)]
+async fn add_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    payload: Result<Json<AddKeyRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(payload) = payload?;
+    let mut fields = Vec::new();
+    if payload.title.trim().is_empty() || payload.title.len() > MAX_KEY_TITLE_LEN {
+        fields.push(FieldError {
+            field: "title",
+            message: format!("must be 1 to {MAX_KEY_TITLE_LEN} bytes"),
+        });
+    }
+    let key = match PublicKey::from_openssh(payload.key.trim()) {
+        Ok(key) => Some(key),
+        Err(error) => {
+            fields.push(FieldError {
+                field: "key",
+                message: format!("invalid public key: {error}"),
+            });
+            None
+        }
+    };
+    let (Some(key), true) = (key, fields.is_empty()) else {
+        return Err(ApiError::invalid_fields(fields));
+    };
+
+    let keys = state.ssh_keys.clone();
+    let username = user.username.clone();
+    let Some(info) = blocking(move || keys.add_key(&username, &payload.title, &key)).await? else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "already_exists",
+            "the key is already added",
+        ));
+    };
+    info!(user = %user.username, key = %info.fingerprint, "added SSH key");
+    Ok((StatusCode::CREATED, Json(info)).into_response())
+}
+
+/// List the request's user's SSH keys.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/keys",
+    tag = "user",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The user's keys, oldest first", body = KeyList),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+    )
+)]
+async fn list_keys(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Json<KeyList> {
+    let keys = state.ssh_keys.list_keys(&user.username);
+    Json(KeyList { keys })
+}
+
+/// Remove one of the request's user's SSH keys.
+///
+/// Sessions started with the key afterwards are refused.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/keys/{id}",
+    tag = "user",
+    security(("bearer" = [])),
+    params(("id" = String, Path, description = "ID of the key")),
+    responses(
+        (status = 204, description = "The key is removed"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The user has no such key", body = ErrorBody),
+    )
+)]
+async fn delete_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    params: Result<Path<String>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = params?;
+    let keys = state.ssh_keys.clone();
+    let username = user.username.clone();
+    let remove_id = id.clone();
+    if !blocking(move || keys.remove_key(&username, &remove_id)).await? {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "key_not_found",
+            format!("key {id} does not exist"),
+        ));
+    }
+    info!(user = %user.username, key = %id, "removed SSH key");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Upgrade the connection to a forjj-sync session on the repository.
+///
+/// The session gets the access of the request's bearer token, but only
+/// reads others' repositories unless the user is an admin. Without a token
+/// it gets anonymous read access, where anonymous reads are allowed.
+#[utoipa::path(
+    post,
+    path = "/api/v1/repos/{owner}/{name}/sync",
+    tag = "sync",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository"),
+    ),
+    responses(
+        (status = 101, description = "The connection is now a forjj-sync session"),
+        (status = 401, description = "A token is required, or the one given is not valid", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 426, description = "The request does not ask to upgrade to forjj-sync", body = ErrorBody),
+    )
+)]
+async fn sync(
+    State(state): State<AppState>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    mut request: Request,
+) -> Result<Response, ApiError> {
+    let upgrading = request
+        .headers()
+        .get(UPGRADE)
+        .is_some_and(|value| value == SYNC_UPGRADE);
+    if !upgrading {
+        let error = ApiError::new(
+            StatusCode::UPGRADE_REQUIRED,
+            "upgrade_required",
+            "expected Upgrade: forjj-sync",
+        );
+        return Ok(([(UPGRADE, SYNC_UPGRADE)], error).into_response());
+    }
+    let Path((owner, name)) = params?;
+    let repo = repo_ref(owner, name)?;
+    if !state.repos.repo_exists(&repo.owner, &repo.name) {
+        return Err(ApiError::not_found(&repo));
+    }
+
+    let grant = match request.extensions().get::<AuthenticatedUser>() {
+        Some(user) => user.grant_on(&repo.owner),
+        None => anonymous(),
+    };
+    info!(identity = ?grant.identity, %repo, "forjj-sync session over HTTP");
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        let upgraded = match upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(error) => {
+                warn!("forjj-sync upgrade failed: {error}");
+                return;
+            }
+        };
+        let stream = TokioIo::new(upgraded);
+        let options = &state.sync_options;
+        if let Err(error) = serve_transport(stream, state.repos, repo, grant, options).await {
+            warn!("forjj-sync session failed: {error}");
+        }
+    });
+
+    Ok((
+        StatusCode::SWITCHING_PROTOCOLS,
+        [(CONNECTION, "upgrade"), (UPGRADE, SYNC_UPGRADE)],
+    )
+        .into_response())
+}
+
+/// The service a git ref advertisement is for.
+#[derive(Debug, Deserialize, IntoParams)]
+struct InfoRefsQuery {
+    /// Only `git-upload-pack` is offered
+    service: Option<String>,
+}
+
+/// 403 for a git client pushing.
+fn push_unsupported() -> ApiError {
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        "push_unsupported",
+        "pushing over git is not supported; push with forjj-sync",
+    )
+}
+
+/// The git directory of the repository a git smart HTTP path names as
+/// `{owner}/{name}.git`, if `user` may see it.
+///
+/// Repositories with the native backend have no git directory, and are
+/// answered with 409 pointing the client at forjj-sync.
+async fn git_dir(
+    state: &AppState,
+    user: Option<AuthenticatedUser>,
+    owner: String,
+    name: String,
+) -> Result<std::path::PathBuf, ApiError> {
+    let Some(name) = name.strip_suffix(".git") else {
+        return Err(ApiError::not_found(&RepoRef::new(owner, name)));
+    };
+    let repo = repo_ref(owner, name.to_string())?;
+    let repos = state.repos.clone();
+    let target = repo.clone();
+    let info = blocking(move || {
+        let Ok(info) = repos.repo_info(&target.owner, &target.name) else {
+            return Ok(None);
+        };
+        let visibility = Visibility::of(&RepoMetadata::load_listed(&info));
+        Ok(may_see(user.as_ref(), &target.owner, visibility).then_some(info))
+    })
+    .await?;
+    let Some(info) = info else {
+        return Err(ApiError::not_found(&repo));
+    };
+    if info.backend_type != BackendType::Git {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "not_git_backed",
+            format!("repository {repo} uses the native jj backend; fetch it with forjj-sync"),
+        ));
+    }
+    info.git_dir()
+        .map_err(|error| ApiError::internal(format_args!("{error:#}")))
+}
+
+/// The client's `Git-Protocol` header, if any.
+fn git_protocol(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("git-protocol")
+        .and_then(|value| value.to_str().ok())
+}
+
+/// List the refs of a git-backed repository, to start a git fetch or clone.
+#[utoipa::path(
+    get,
+    path = "/{owner}/{name}/info/refs",
+    tag = "git",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository, followed by `.git`"),
+        InfoRefsQuery,
+    ),
+    responses(
+        (status = 200, description = "The refs, as git-upload-pack advertises them"),
+        (status = 403, description = "The service is not offered", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 409, description = "The repository has the native backend", body = ErrorBody),
+    )
+)]
+async fn git_info_refs(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    query: Result<Query<InfoRefsQuery>, QueryRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let user = user.map(|Extension(user)| user);
+    let Path((owner, name)) = params?;
+    let Query(query) = query?;
+    match query.service.as_deref() {
+        Some(UPLOAD_PACK) => {}
+        Some(RECEIVE_PACK) => return Err(push_unsupported()),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "unsupported_service",
+                format!("only {UPLOAD_PACK} is offered, over smart HTTP"),
+            ));
+        }
+    }
+    let git_dir = git_dir(&state, user, owner, name).await?;
+    let body = upload_pack(&git_dir, true, git_protocol(&headers), Bytes::new())
+        .await
+        .map_err(|error| ApiError::internal(format_args!("{error:#}")))?;
+    Ok((
+        [
+            (CONTENT_TYPE, advertisement_type(UPLOAD_PACK)),
+            (CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Send a git client the objects it asks for.
+#[utoipa::path(
+    post,
+    path = "/{owner}/{name}/git-upload-pack",
+    tag = "git",
+    security((), ("bearer" = ["repo:read"])),
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository, followed by `.git`"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/x-git-upload-pack-request"),
+    responses(
+        (status = 200, description = "The result of git-upload-pack, with a pack if one was asked for"),
+        (status = 400, description = "The request body can't be read", body = ErrorBody),
+        (status = 404, description = "The repository does not exist, or is private", body = ErrorBody),
+        (status = 409, description = "The repository has the native backend", body = ErrorBody),
+    )
+)]
+async fn git_upload_pack(
+    State(state): State<AppState>,
+    user: Option<Extension<AuthenticatedUser>>,
+    params: Result<Path<(String, String)>, PathRejection>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let user = user.map(|Extension(user)| user);
+    let Path((owner, name)) = params?;
+    let git_dir = git_dir(&state, user, owner, name).await?;
+    let encoding = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok());
+    let input = decode_request(body, encoding).map_err(|error| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_body",
+            format!("{error:#}"),
+        )
+    })?;
+    let body = upload_pack(&git_dir, false, git_protocol(&headers), input)
+        .await
+        .map_err(|error| ApiError::internal(format_args!("{error:#}")))?;
+    Ok((
+        [
+            (CONTENT_TYPE, result_type(UPLOAD_PACK)),
+            (CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Refuse a git push: repositories are changed with forjj-sync.
+#[utoipa::path(
+    post,
+    path = "/{owner}/{name}/git-receive-pack",
+    tag = "git",
+    params(
+        ("owner" = String, Path, description = "Owner of the repository"),
+        ("name" = String, Path, description = "Name of the repository, followed by `.git`"),
+    ),
+    responses((status = 403, description = "Pushing over git is not supported", body = ErrorBody))
+)]
+async fn git_receive_pack() -> ApiError {
+    push_unsupported()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{ManagedTokens, TokenFile};
+    use crate::patch::DEFAULT_MAX_PATCH_BYTES;
+    use crate::rate_limit::{RateLimit, RateLimits};
+    use crate::raw::DEFAULT_MAX_RAW_BYTES;
+    use crate::redirects::DEFAULT_GRACE_PERIOD;
+    use crate::ssh::{AuthorizedKeysFile, SshServer, fingerprint, load_or_generate_host_key};
+    use crate::state::StateStore;
+    use axum::body::{Body, to_bytes};
+    use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use forjj_protocol::{
+        AccessLevel, Auth, ClientOptions, ErrorCode, ForjjClient, HttpError, HttpStream,
+        HttpTransport, ProtocolError, PushRequest, PushStatus, RefTargetWire, RefUpdate, SshTarget,
+        connect_ssh,
+    };
+    use forjj_storage::{CommitId, RepositoryManager, StorageConfig};
+    use russh::keys::ssh_key::rand_core::OsRng;
+    use russh::keys::{Algorithm, PrivateKey};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    const TOKENS: &str =
+        "write alice fj_alice\nread ci fj_ci\nwrite bob fj_bob\nadmin root fj_root\n";
+
+    /// An API server on a localhost port with `alice/project`.
+    struct TestServer {
+        _dir: TempDir,
+        repos: Arc<RepositoryManager>,
+        authority: String,
+        head: CommitId,
+    }
+
+    impl TestServer {
+        async fn start() -> Self {
+            let dir = TempDir::new().unwrap();
+            let repos = Arc::new(
+                RepositoryManager::new(StorageConfig {
+                    repos_root: dir.path().to_path_buf(),
+                })
+                .unwrap(),
+            );
+            let repo = repos.create_repo("alice", "project").unwrap();
+            let head = repo.head_ids().unwrap()[0];
+            let state = AppState {
+                repos: repos.clone(),
+                tokens: Arc::new(test_tokens(dir.path())),
+                sync_options: Arc::new(ServerOptions::default()),
+                soft_delete: false,
+                max_patch_bytes: DEFAULT_MAX_PATCH_BYTES,
+                max_raw_bytes: DEFAULT_MAX_RAW_BYTES,
+                max_blame_lines: DEFAULT_MAX_BLAME_LINES,
+                stats_max_age: DEFAULT_STATS_MAX_AGE,
+                stats_scans: Arc::default(),
+                swagger_ui: false,
+                // Clients may fetch anonymously.
+                anonymous_read: true,
+                users: Arc::new(test_users(dir.path())),
+                redirects: Arc::new(test_redirects(dir.path())),
+                ssh_keys: Arc::new(test_keys(dir.path())),
+                hooks: test_hooks(&repos),
+                rate_limiter: Arc::new(RateLimiter::new(RateLimits::UNLIMITED)),
+                metrics: Arc::new(Metrics::new().unwrap()),
+                serve_metrics: true,
+                readiness: test_readiness(&repos, dir.path()),
+            };
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let authority = listener.local_addr().unwrap().to_string();
+            tokio::spawn(async move { axum::serve(listener, create_router(state)).await });
+            Self {
+                _dir: dir,
+                repos,
+                authority,
+                head,
+            }
+        }
+
+        fn transport(&self, token: Option<&str>) -> HttpTransport {
+            let transport = HttpTransport::new(&self.authority);
+            match token {
+                Some(token) => transport.with_token(token),
+                None => transport,
+            }
+        }
+
+        async fn connect(
+            &self,
+            token: Option<&str>,
+            access: AccessLevel,
+        ) -> Result<ForjjClient<HttpStream>, ProtocolError> {
+            let repo = RepoRef::new("alice", "project");
+            let stream = self.transport(token).connect(&repo).await.unwrap();
+            let options = ClientOptions::new(repo).with_auth(Auth::None, access);
+            ForjjClient::connect(stream, options).await
+        }
+    }
+
+    /// [`TOKENS`], and the tokens users make, kept in `dir`.
+    fn test_tokens(dir: &std::path::Path) -> ManagedTokens {
+        let state = Arc::new(StateStore::open(&dir.join(".state")).unwrap());
+        ManagedTokens::open(TokenFile::parse(TOKENS).unwrap(), state).unwrap()
+    }
+
+    /// The accounts, kept in `dir`.
+    fn test_users(dir: &std::path::Path) -> UserStore {
+        UserStore::open(Arc::new(StateStore::open(&dir.join(".state")).unwrap())).unwrap()
+    }
+
+    /// Where moved repositories went, kept in `dir`.
+    fn test_redirects(dir: &std::path::Path) -> RedirectStore {
+        let state = Arc::new(StateStore::open(&dir.join(".state")).unwrap());
+        RedirectStore::open(state, DEFAULT_GRACE_PERIOD).unwrap()
+    }
+
+    /// The SSH keys users add, kept in `dir`.
+    fn test_keys(dir: &std::path::Path) -> ManagedKeys {
+        let state = Arc::new(StateStore::open(&dir.join(".state")).unwrap());
+        ManagedKeys::open(AuthorizedKeysFile::default(), state).unwrap()
+    }
+
+    /// A router over an empty tempdir-backed manager.
+    fn test_app() -> (TempDir, Router) {
+        test_app_with(|_| {})
+    }
+
+    /// [`test_app`], with the state changed by `configure`.
+    /// A hook dispatcher that tries deliveries again without waiting long.
+    fn test_hooks(repos: &Arc<RepositoryManager>) -> Arc<HookDispatcher> {
+        let retry = hooks::RetryPolicy {
+            attempts: 3,
+            first_delay: std::time::Duration::from_millis(10),
+        };
+        Arc::new(HookDispatcher::new(repos.clone(), retry))
+    }
+
+    /// Readiness checks of `repos`, kept in `dir`, and the state there.
+    fn test_readiness(repos: &Arc<RepositoryManager>, dir: &std::path::Path) -> Arc<Readiness> {
+        let state = Arc::new(StateStore::open(&dir.join(".state")).unwrap());
+        Arc::new(Readiness::new(
+            repos.clone(),
+            dir.to_path_buf(),
+            state,
+            None,
+        ))
+    }
+
+    fn test_app_with(configure: impl FnOnce(&mut AppState)) -> (TempDir, Router) {
+        let dir = TempDir::new().unwrap();
+        let repos = Arc::new(
+            RepositoryManager::new(StorageConfig {
+                repos_root: dir.path().to_path_buf(),
+            })
+            .unwrap(),
+        );
+        let mut state = AppState {
+            hooks: test_hooks(&repos),
+            readiness: test_readiness(&repos, dir.path()),
+            repos,
+            tokens: Arc::new(test_tokens(dir.path())),
+            sync_options: Arc::new(ServerOptions::default()),
+            soft_delete: false,
+            max_patch_bytes: DEFAULT_MAX_PATCH_BYTES,
+            max_raw_bytes: DEFAULT_MAX_RAW_BYTES,
+            max_blame_lines: DEFAULT_MAX_BLAME_LINES,
+            stats_max_age: DEFAULT_STATS_MAX_AGE,
+            stats_scans: Arc::default(),
+            swagger_ui: false,
+            anonymous_read: false,
+            users: Arc::new(test_users(dir.path())),
+            redirects: Arc::new(test_redirects(dir.path())),
+            ssh_keys: Arc::new(test_keys(dir.path())),
+            rate_limiter: Arc::new(RateLimiter::new(RateLimits::UNLIMITED)),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            serve_metrics: true,
+        };
+        configure(&mut state);
+        (dir, create_router(state))
+    }
+
+    /// Send a request with `alice`'s write token and return the status
+    /// and JSON body, if any.
+    async fn call(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, Option<serde_json::Value>) {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer fj_alice");
+        let request = match body {
+            Some(body) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        let response = app.clone().oneshot(request.unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).ok())
+    }
+
+    /// Send a delete request with `alice`'s write token, confirming it with
+    /// `confirm` if given.
+    async fn delete(
+        app: &Router,
+        uri: &str,
+        confirm: Option<&str>,
+    ) -> (StatusCode, Option<serde_json::Value>) {
+        let request = axum::http::Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer fj_alice");
+        let request = match confirm {
+            Some(confirm) => request.header(CONFIRM_DELETE, confirm),
+            None => request,
+        };
+        let request = request.body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).ok())
+    }
+
+    /// Send a GET request with `alice`'s write token and `headers`, and
+    /// return the status, headers and body as they are.
+    async fn get_raw_response(
+        app: &Router,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let mut request = axum::http::Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer fj_alice");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, bytes.to_vec())
+    }
+
+    fn create_main(head: &CommitId) -> PushRequest {
+        PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "main".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: Some(head.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_over_http() {
+        let server = TestServer::start().await;
+        let mut client = server
+            .connect(Some("fj_alice"), AccessLevel::Write)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("alice"));
+        let result = client
+            .push(create_main(&server.head), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        assert_eq!(result.status, PushStatus::Ok);
+        client.shutdown().await.unwrap();
+
+        let repo = server.repos.open_repo("alice", "project").unwrap();
+        let bookmarks = repo.bookmark_ids().unwrap();
+        assert_eq!(bookmarks, [("main".to_string(), server.head)]);
+    }
+
+    #[tokio::test]
+    async fn test_push_over_ssh_to_api_repo() {
+        // The SSH listener shares the API's repositories and keys, as in
+        // the server.
+        let mut shared = None;
+        let (dir, app) = test_app_with(|state| {
+            shared = Some((state.repos.clone(), state.ssh_keys.clone()));
+        });
+        let (repos, keys) = shared.unwrap();
+        let host_key = load_or_generate_host_key(&dir.path().join("host_key")).unwrap();
+        let host_public = host_key.public_key().clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = SshServer::new(repos.clone(), keys, ServerOptions::default());
+        tokio::spawn(server.run(listener, host_key));
+
+        let create = serde_json::json!({ "owner": "alice", "name": "mirror" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let add = serde_json::json!({
+            "title": "laptop",
+            "key": key.public_key().to_openssh().unwrap(),
+        });
+        let (status, _) = call(&app, "POST", "/api/v1/user/keys", Some(add)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let target = SshTarget {
+            host: "127.0.0.1".to_string(),
+            port,
+            user: "forjj".to_string(),
+            key: Arc::new(key),
+            host_key: host_public,
+        };
+        let repo = RepoRef::new("alice", "mirror");
+        let stream = connect_ssh(&target, &repo).await.unwrap();
+        let options = ClientOptions::new(repo).with_auth(Auth::None, AccessLevel::Write);
+        let mut client = ForjjClient::connect(stream, options).await.unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("alice"));
+        let head = repos
+            .open_repo("alice", "mirror")
+            .unwrap()
+            .head_ids()
+            .unwrap()[0];
+        let result = client
+            .push(create_main(&head), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        assert_eq!(result.status, PushStatus::Ok);
+        client.shutdown().await.unwrap();
+
+        // The API sees what was pushed.
+        let uri = "/api/v1/repos/alice/mirror/bookmarks";
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let bookmark = &body.unwrap()["bookmarks"][0];
+        assert_eq!(bookmark["name"], "main");
+        assert_eq!(bookmark["target"]["id"], head.to_hex().as_str());
+    }
+
+    /// Run git with `args`, returning its output, or `None` if git isn't
+    /// installed.
+    async fn git(args: &[&str]) -> Option<std::process::Output> {
+        let output = tokio::process::Command::new("git")
+            .args([
+                "-c",
+                "user.name=Alice",
+                "-c",
+                "user.email=alice@example.com",
+            ])
+            .args(args)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .await
+            .ok()?;
+        Some(output)
+    }
+
+    #[tokio::test]
+    async fn test_git_clone() {
+        if git(&["--version"]).await.is_none() {
+            eprintln!("skipping: git is not installed");
+            return;
+        }
+        let server = TestServer::start().await;
+        // A git-backed repository, laid out as jj lays one out, whose git
+        // store has a commit and one of jj's own refs.
+        let work = TempDir::new().unwrap();
+        let work = work.path().to_str().unwrap();
+        let store = server
+            .repos
+            .repo_path("alice", "mirror")
+            .join(".jj/repo/store");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join("type"), "git").unwrap();
+        std::fs::write(store.join("git_target"), "git").unwrap();
+        let bare = store.join("git");
+        let bare = bare.to_str().unwrap();
+        assert!(git(&["init", "-q", work]).await.unwrap().status.success());
+        let commit = ["-C", work, "commit", "-q", "--allow-empty", "-m", "first"];
+        assert!(git(&commit).await.unwrap().status.success());
+        let cloned = git(&["clone", "-q", "--bare", work, bare]).await.unwrap();
+        assert!(cloned.status.success());
+        let keep = [
+            "--git-dir",
+            bare,
+            "update-ref",
+            "refs/jj/keep/first",
+            "HEAD",
+        ];
+        assert!(git(&keep).await.unwrap().status.success());
+        let head = git(&["-C", work, "rev-parse", "HEAD"])
+            .await
+            .unwrap()
+            .stdout;
+
+        let url = format!("http://{}/alice/mirror.git", server.authority);
+        for version in ["0", "2"] {
+            let protocol = format!("protocol.version={version}");
+            let clone = TempDir::new().unwrap();
+            let clone = clone.path().join("mirror");
+            let clone = clone.to_str().unwrap();
+            let output = git(&["-c", &protocol, "clone", "-q", &url, clone])
+                .await
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(output.status.success(), "v{version}: {stderr}");
+            let cloned = git(&["-C", clone, "rev-parse", "HEAD"]).await.unwrap();
+            assert_eq!(cloned.stdout, head, "v{version}");
+
+            let listed = git(&["-c", &protocol, "ls-remote", &url]).await.unwrap();
+            let refs = String::from_utf8(listed.stdout).unwrap();
+            assert!(refs.contains("refs/heads/"), "v{version}: {refs}");
+            assert!(!refs.contains("refs/jj/"), "v{version}: {refs}");
+        }
+
+        // Native repositories and pushes are refused.
+        let url = format!("http://{}/alice/project.git", server.authority);
+        assert!(!git(&["ls-remote", &url]).await.unwrap().status.success());
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().to_str().unwrap();
+        let url = format!("http://{}/alice/mirror.git", server.authority);
+        let output = git(&["clone", "-q", &url, target]).await.unwrap();
+        assert!(output.status.success());
+        let push = git(&[
+            "-C",
+            target,
+            "push",
+            "-q",
+            "origin",
+            "HEAD:refs/heads/other",
+        ])
+        .await
+        .unwrap();
+        assert!(!push.status.success());
+    }
+
+    #[tokio::test]
+    async fn test_git_http_refusals() {
+        let (_dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let refs = "/alice/project.git/info/refs?service=git-upload-pack";
+        let (status, body) = call(&app, "GET", refs, None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let error = &body.unwrap()["error"];
+        assert_eq!(error["code"], "not_git_backed");
+        assert!(error["message"].as_str().unwrap().contains("forjj-sync"));
+
+        for (method, uri, expected) in [
+            (
+                "GET",
+                "/alice/missing.git/info/refs?service=git-upload-pack",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "GET",
+                "/alice/project/info/refs?service=git-upload-pack",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "GET",
+                "/alice/project.git/info/refs?service=git-receive-pack",
+                StatusCode::FORBIDDEN,
+            ),
+            ("GET", "/alice/project.git/info/refs", StatusCode::FORBIDDEN),
+            (
+                "POST",
+                "/alice/project.git/git-receive-pack",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                "POST",
+                "/alice/project.git/git-upload-pack",
+                StatusCode::CONFLICT,
+            ),
+        ] {
+            let (status, _) = call(&app, method, uri, None).await;
+            assert_eq!(status, expected, "{method} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_follows_token() {
+        let server = TestServer::start().await;
+
+        // Anonymous and read-only clients may connect to read.
+        let client = server.connect(None, AccessLevel::Read).await.unwrap();
+        assert_eq!(client.hello().identity, None);
+        client.shutdown().await.unwrap();
+        let client = server
+            .connect(Some("fj_ci"), AccessLevel::Read)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("ci"));
+        client.shutdown().await.unwrap();
+
+        // The protocol refuses them write access.
+        for token in [None, Some("fj_ci")] {
+            let error = server.connect(token, AccessLevel::Write).await.unwrap_err();
+            assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refused_before_upgrade() {
+        let server = TestServer::start().await;
+
+        // The middleware turns away unknown tokens.
+        let repo = RepoRef::new("alice", "project");
+        let error = server
+            .transport(Some("fj_guess"))
+            .connect(&repo)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, HttpError::Status(StatusCode::UNAUTHORIZED)),
+            "{error}"
+        );
+
+        let error = server
+            .transport(None)
+            .connect(&RepoRef::new("alice", "missing"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, HttpError::Status(StatusCode::NOT_FOUND)),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repo_lifecycle() {
+        let (_dir, app) = test_app();
+        let (status, body) = call(&app, "GET", "/api/v1/repos", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["repositories"], serde_json::json!([]));
+
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(create.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let body = body.unwrap();
+        assert_eq!(body["full_name"], "alice/project");
+        assert_eq!(body["backend"], "simple");
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["name"], "project");
+        let (_, body) = call(&app, "GET", "/api/v1/repos", None).await;
+        let listed = &body.unwrap()["repositories"];
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["full_name"], "alice/project");
+
+        let uri = "/api/v1/repos/alice/project";
+        let (status, _) = delete(&app, uri, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = delete(&app, uri, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_repos() {
+        let (dir, app) = test_app();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        for i in 0..15 {
+            repos.create_repo("alice", &format!("repo-{i:02}")).unwrap();
+        }
+        for i in 0..10 {
+            repos.create_repo("bob", &format!("repo-{i:02}")).unwrap();
+        }
+        let repo = repos.open_repo("bob", "repo-03").unwrap();
+        RepoMetadata::new(Some("described".to_string()))
+            .store(&repo)
+            .unwrap();
+        let names = |body: &serde_json::Value| -> Vec<String> {
+            body["repositories"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|repo| repo["full_name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos?owner=bob", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        let bob: Vec<String> = (0..10).map(|i| format!("bob/repo-{i:02}")).collect();
+        assert_eq!(names(&body), bob);
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+        assert_eq!(body["repositories"][3]["description"], "described");
+        assert!(body["repositories"][3].get("head_count").is_none());
+
+        let (_, body) = call(&app, "GET", "/api/v1/repos?owner=carol", None).await;
+        assert_eq!(body.unwrap()["repositories"], serde_json::json!([]));
+
+        // Pages end at the limit and pick up after the cursor.
+        let (_, body) = call(&app, "GET", "/api/v1/repos?limit=10", None).await;
+        let body = body.unwrap();
+        let alice: Vec<String> = (0..15).map(|i| format!("alice/repo-{i:02}")).collect();
+        assert_eq!(names(&body), alice[..10]);
+        let cursor = body["next_cursor"].as_str().unwrap().to_string();
+
+        // Repositories added before and after the cursor don't shift it.
+        repos.create_repo("alice", "repo-00a").unwrap();
+        repos.create_repo("alice", "repo-14a").unwrap();
+        let uri = format!("/api/v1/repos?limit=10&cursor={cursor}");
+        let (_, body) = call(&app, "GET", &uri, None).await;
+        let body = body.unwrap();
+        let mut expected = alice[10..].to_vec();
+        expected.push("alice/repo-14a".to_string());
+        expected.extend(bob[..4].iter().cloned());
+        assert_eq!(names(&body), expected);
+
+        let cursor = body["next_cursor"].as_str().unwrap();
+        let uri = format!("/api/v1/repos?limit=10&cursor={cursor}");
+        let (_, body) = call(&app, "GET", &uri, None).await;
+        let body = body.unwrap();
+        assert_eq!(names(&body), bob[4..]);
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos?cursor=zz", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["error"]["code"], "invalid_cursor");
+    }
+
+    #[tokio::test]
+    async fn test_list_commits() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let first = repo
+            .write_commit(&[], &[("README.md", b"hello")], "first\n\nmore")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(&[first], &[("src/lib.rs", b"// lib")], "second")
+            .await
+            .unwrap();
+        let third = repo
+            .write_commit(&[second], &[("README.md", b"hello again")], "third")
+            .await
+            .unwrap();
+        let side = repo
+            .write_commit(&[first], &[("docs/guide.md", b"guide")], "side")
+            .await
+            .unwrap();
+        let uri = "/api/v1/repos/alice/project/commits";
+        let ids = |body: &serde_json::Value| -> Vec<String> {
+            body["commits"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|commit| commit["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // With several heads and no main, the request must choose.
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["error"]["code"], "start_required");
+
+        repo.set_bookmarks(&[("main".to_string(), Some(third))], "set main")
+            .unwrap();
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(
+            ids(&body),
+            [third.to_hex(), second.to_hex(), first.to_hex()]
+        );
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+        let oldest = &body["commits"][2];
+        assert_eq!(oldest["description"], "first");
+        assert_eq!(oldest["empty"], false);
+        assert_eq!(oldest["conflict"], false);
+        assert_eq!(oldest["parent_ids"].as_array().unwrap().len(), 1);
+        assert_eq!(body["commits"][0]["parent_ids"][0], second.to_hex());
+        let summary = repo.commit_summary(&first).unwrap();
+        assert_eq!(oldest["short_id"], summary.short_id);
+        assert!(first.to_hex().starts_with(&summary.short_id));
+        assert_eq!(oldest["short_change_id"], summary.short_change_id);
+        let change_id = oldest["change_id"].as_str().unwrap();
+        assert!(change_id.starts_with(&summary.short_change_id));
+
+        let (_, body) = call(&app, "GET", &format!("{uri}?head={side}"), None).await;
+        assert_eq!(ids(&body.unwrap()), [side.to_hex(), first.to_hex()]);
+        let (_, body) = call(&app, "GET", &format!("{uri}?path=README.md"), None).await;
+        assert_eq!(ids(&body.unwrap()), [third.to_hex(), first.to_hex()]);
+
+        // Pages continue from the cursor.
+        let (_, body) = call(&app, "GET", &format!("{uri}?bookmark=main&limit=2"), None).await;
+        let body = body.unwrap();
+        assert_eq!(ids(&body), [third.to_hex(), second.to_hex()]);
+        let cursor = body["next_cursor"].as_str().unwrap();
+        let (_, body) = call(&app, "GET", &format!("{uri}?limit=2&cursor={cursor}"), None).await;
+        let body = body.unwrap();
+        assert_eq!(ids(&body), [first.to_hex()]);
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+
+        // A cursor off the listed history is as bad as a malformed one.
+        for cursor in ["xyz".to_string(), side.to_hex()] {
+            let (status, body) = call(&app, "GET", &format!("{uri}?cursor={cursor}"), None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body.unwrap()["error"]["code"], "invalid_cursor");
+        }
+        let (status, body) = call(&app, "GET", &format!("{uri}?bookmark=missing"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "bookmark_not_found");
+        let missing = "/api/v1/repos/alice/missing/commits";
+        let (status, body) = call(&app, "GET", missing, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_get_commit() {
+        let (dir, app) = test_app_with(|state| state.max_patch_bytes = 48);
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let first = repo
+            .write_commit(&[], &[("README.md", b"hello\n")], "first")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(
+                &[first],
+                &[
+                    ("README.md", b"hello\nworld\n"),
+                    ("src/lib.rs", b"// lib\n"),
+                ],
+                "second\n\nwith a body",
+            )
+            .await
+            .unwrap();
+        let change_id = repo.commit_summary(&second).unwrap().change_id;
+        let uri = |rev: &str| format!("/api/v1/repos/alice/project/commits/{rev}");
+
+        let (status, body) = call(&app, "GET", &uri(&second.to_hex()), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let commit = body.unwrap();
+        assert_eq!(commit["id"], second.to_hex());
+        assert_eq!(commit["description"], "second");
+        assert_eq!(commit["full_description"], "second\n\nwith a body");
+        assert_eq!(commit["parent_ids"], serde_json::json!([first.to_hex()]));
+        assert_eq!(
+            commit["changes"],
+            serde_json::json!([
+                { "path": "README.md", "kind": "modified", "old_size": 6, "new_size": 12 },
+                { "path": "src/lib.rs", "kind": "added", "old_size": null, "new_size": 7 },
+            ])
+        );
+        assert!(commit.get("patch_truncated").is_none());
+
+        // Change IDs and prefixes of either kind of ID name it too.
+        for rev in [
+            change_id.to_reverse_hex(),
+            change_id.to_reverse_hex()[..8].to_string(),
+            second.to_hex()[..12].to_string(),
+        ] {
+            let (status, body) = call(&app, "GET", &uri(&rev), None).await;
+            assert_eq!(status, StatusCode::OK, "{rev}");
+            assert_eq!(body.unwrap()["id"], second.to_hex(), "{rev}");
+        }
+        let (status, body) = call(&app, "GET", &uri(&"f".repeat(40)), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "revision_not_found");
+        let (status, _) = call(&app, "GET", &uri("not-a-rev"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // The patches stop before the second file's would go over the limit.
+        let with_patch = format!("{}?include_patch=true", uri(&second.to_hex()));
+        let (_, body) = call(&app, "GET", &with_patch, None).await;
+        let commit = body.unwrap();
+        let patch = commit["changes"][0]["patch"].as_str().unwrap();
+        assert!(patch.contains("+world\n"), "{patch}");
+        assert!(commit["changes"][1].get("patch").is_none());
+        assert_eq!(commit["patch_truncated"], true);
+
+        // Write commits until two share their first hex digit.
+        let prefix = second.to_hex()[..1].to_string();
+        let mut parent = second;
+        while !matches!(
+            repo.resolve_commit_prefix(&prefix).unwrap(),
+            PrefixResolution::Ambiguous(_)
+        ) {
+            parent = repo.write_commit(&[parent], &[], "more").await.unwrap();
+        }
+        let (status, body) = call(&app, "GET", &uri(&prefix), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let error = &body.unwrap()["error"];
+        assert_eq!(error["code"], "ambiguous_revision");
+        let candidates = error["details"]["candidates"].as_array().unwrap();
+        assert!(candidates.len() >= 2);
+        assert!(candidates.contains(&serde_json::json!(second.to_hex())));
+    }
+
+    #[tokio::test]
+    async fn test_get_raw() {
+        let (dir, app) = test_app_with(|state| state.max_raw_bytes = 16);
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let base = repo
+            .write_commit(
+                &[],
+                &[
+                    ("README.md", b"hello\n"),
+                    ("data.bin", b"\0\x01\x02\xff"),
+                    ("notes.txt", b"0123456789abcdefghij"),
+                ],
+                "base",
+            )
+            .await
+            .unwrap();
+        let uri = |rev: &CommitId, path: &str| {
+            format!("/api/v1/repos/alice/project/raw/{}/{path}", rev.to_hex())
+        };
+
+        let (status, headers, body) = get_raw_response(&app, &uri(&base, "README.md"), &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"hello\n");
+        assert_eq!(headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(headers[CONTENT_LENGTH], "6");
+        assert_eq!(headers[ACCEPT_RANGES], "bytes");
+
+        let (status, headers, body) = get_raw_response(&app, &uri(&base, "data.bin"), &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"\0\x01\x02\xff");
+        assert_eq!(headers[CONTENT_TYPE], "application/octet-stream");
+
+        // A client with the current version gets no body.
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+        let (status, _, body) =
+            get_raw_response(&app, &uri(&base, "data.bin"), &[("if-none-match", &etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        let (status, _, _) =
+            get_raw_response(&app, &uri(&base, "README.md"), &[("if-none-match", &etag)]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // notes.txt is over the limit, so only ranges of it are answered.
+        let notes = uri(&base, "notes.txt");
+        let (status, _, body) = get_raw_response(&app, &notes, &[]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "file_too_large");
+        let (status, headers, body) =
+            get_raw_response(&app, &notes, &[("range", "bytes=10-13")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, b"abcd");
+        assert_eq!(headers[CONTENT_RANGE], "bytes 10-13/20");
+        assert_eq!(headers[CONTENT_LENGTH], "4");
+        let (_, _, body) = get_raw_response(&app, &notes, &[("range", "bytes=-3")]).await;
+        assert_eq!(body, b"hij");
+        let (status, headers, _) = get_raw_response(&app, &notes, &[("range", "bytes=20-")]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[CONTENT_RANGE], "bytes */20");
+
+        let (status, _, _) = get_raw_response(&app, &uri(&base, "missing"), &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Both sides of the merge change README.md, which leaves a conflict.
+        let left = repo
+            .write_commit(&[base], &[("README.md", b"left\n")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("README.md", b"right\n")], "right")
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(&[left, right], &[], "merge")
+            .await
+            .unwrap();
+        let (status, _, body) = get_raw_response(&app, &uri(&merge, "README.md"), &[]).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "conflicted_file");
+        let (status, _, body) = get_raw_response(&app, &uri(&merge, "data.bin"), &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"\0\x01\x02\xff");
+    }
+
+    #[tokio::test]
+    async fn test_blame() {
+        let (dir, app) = test_app_with(|state| state.max_blame_lines = 4);
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let first = repo
+            .write_commit(
+                &[],
+                &[
+                    ("a.txt", b"one\ntwo\nthree\n"),
+                    ("data.bin", b"\0\x01\x02\xff"),
+                    ("long.txt", b"1\n2\n3\n4\n5\n"),
+                ],
+                "first\n\nwith a body",
+            )
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(&[first], &[("a.txt", b"one\n2\n3\nthree\n")], "second")
+            .await
+            .unwrap();
+        let uri = |rev: &CommitId, path: &str| {
+            format!("/api/v1/repos/alice/project/blame/{}/{path}", rev.to_hex())
+        };
+
+        let (status, body) = call(&app, "GET", &uri(&second, "a.txt"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let blame = body.unwrap();
+        assert_eq!(blame["path"], "a.txt");
+        let ranges = blame["ranges"].as_array().unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0]["start_line"], 1);
+        assert_eq!(ranges[0]["line_count"], 1);
+        assert_eq!(ranges[0]["commit"]["id"], first.to_hex());
+        assert_eq!(ranges[0]["commit"]["description"], "first");
+        assert_eq!(ranges[1]["start_line"], 2);
+        assert_eq!(ranges[1]["line_count"], 2);
+        assert_eq!(ranges[1]["commit"]["id"], second.to_hex());
+        assert_eq!(ranges[2]["start_line"], 4);
+        assert_eq!(ranges[2]["commit"]["id"], first.to_hex());
+
+        // The file's ID follows its contents, not the commit blamed.
+        let (_, body) = call(&app, "GET", &uri(&first, "a.txt"), None).await;
+        let before = body.unwrap();
+        assert_eq!(before["ranges"].as_array().unwrap().len(), 1);
+        assert_ne!(before["file_id"], blame["file_id"]);
+
+        let (status, body) = call(&app, "GET", &uri(&second, "data.bin"), None).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["error"]["code"], "binary_file");
+        let (status, body) = call(&app, "GET", &uri(&second, "missing"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "path_not_found");
+        // long.txt has more lines than the server blames.
+        let (status, body) = call(&app, "GET", &uri(&second, "long.txt"), None).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let error = body.unwrap();
+        assert_eq!(error["error"]["code"], "file_too_large");
+        assert!(
+            error["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("/raw/")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_history() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let added = repo
+            .write_commit(&[], &[("docs/a.txt", b"one")], "add")
+            .await
+            .unwrap();
+        let modified = repo
+            .write_commit(&[added], &[("docs/a.txt", b"two")], "modify")
+            .await
+            .unwrap();
+        let removed = repo
+            .remove_files(&[modified], &["docs/a.txt"], "remove")
+            .await
+            .unwrap();
+        let readded = repo
+            .write_commit(&[removed], &[("docs/a.txt", b"three")], "re-add")
+            .await
+            .unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(readded))], "set main")
+            .unwrap();
+        let uri = "/api/v1/repos/alice/project/history/main/docs/a.txt";
+
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["path"], "docs/a.txt");
+        let entries = body["entries"].as_array().unwrap();
+        let steps: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry["commit"]["id"].as_str().unwrap(),
+                    entry["kind"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (readded.to_hex().as_str(), "added"),
+                (removed.to_hex().as_str(), "deleted"),
+                (modified.to_hex().as_str(), "modified"),
+                (added.to_hex().as_str(), "added"),
+            ]
+        );
+        assert_eq!(entries[1]["file_id"], serde_json::Value::Null);
+        assert!(entries[2]["file_id"].is_string());
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+
+        // Pages continue from the cursor.
+        let (_, body) = call(&app, "GET", &format!("{uri}?limit=3"), None).await;
+        let body = body.unwrap();
+        assert_eq!(body["entries"].as_array().unwrap().len(), 3);
+        let cursor = body["next_cursor"].as_str().unwrap();
+        assert_eq!(cursor, modified.to_hex());
+        let (_, body) = call(&app, "GET", &format!("{uri}?limit=3&cursor={cursor}"), None).await;
+        let body = body.unwrap();
+        assert_eq!(body["entries"][0]["commit"]["id"], added.to_hex());
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+
+        // The removed file's history is still there from before it came back.
+        let uri = format!(
+            "/api/v1/repos/alice/project/history/{}/docs/a.txt",
+            removed.to_hex()
+        );
+        let (_, body) = call(&app, "GET", &uri, None).await;
+        assert_eq!(body.unwrap()["entries"].as_array().unwrap().len(), 3);
+
+        let uri = "/api/v1/repos/alice/project/history/main/never.txt";
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["entries"], serde_json::json!([]));
+
+        let uri = "/api/v1/repos/alice/project/history/main/docs/a.txt?cursor=zz";
+        let (status, _) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let scans = Arc::new(AtomicU64::new(0));
+        let (dir, app) = test_app_with(|state| state.stats_scans = scans.clone());
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let first = repo
+            .write_commit(
+                &[],
+                &[("README.md", b"hello\n"), ("big.bin", &[0; 64])],
+                "first",
+            )
+            .await
+            .unwrap();
+        let side = repo.write_commit(&[first], &[], "side").await.unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(first))], "set main")
+            .unwrap();
+        repo.set_tags(&[("v1".to_string(), Some(first))], "tag v1")
+            .unwrap();
+        let uri = "/api/v1/repos/alice/project/stats";
+
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let stats = body.unwrap();
+        assert_eq!(stats["commit_count"], 2);
+        assert_eq!(stats["bookmark_count"], 1);
+        assert_eq!(stats["tag_count"], 1);
+        assert_eq!(stats["head_count"], 1);
+        assert!(stats["operation_count"].as_u64().unwrap() >= 4);
+        assert!(stats["first_commit_at"].as_i64().unwrap() > 0);
+        assert!(stats["latest_commit_at"].as_i64() >= stats["first_commit_at"].as_i64());
+        assert!(stats["disk_size"].as_u64().unwrap() > 0);
+        assert_eq!(
+            stats["largest_files"],
+            serde_json::json!([
+                { "path": "big.bin", "size": 64 },
+                { "path": "README.md", "size": 6 },
+            ])
+        );
+        assert_eq!(scans.load(Ordering::Relaxed), 1);
+
+        // Counts are current, but the scan is kept while it is recent.
+        let second = repo
+            .write_commit(&[side], &[("huge.bin", &[0; 128])], "second")
+            .await
+            .unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(second))], "move main")
+            .unwrap();
+        let (_, body) = call(&app, "GET", uri, None).await;
+        let kept = body.unwrap();
+        assert_eq!(kept["commit_count"], 3);
+        assert_eq!(kept["largest_files"], stats["largest_files"]);
+        assert_eq!(kept["scanned_at"], stats["scanned_at"]);
+        assert_eq!(scans.load(Ordering::Relaxed), 1);
+
+        let (_, body) = call(&app, "GET", &format!("{uri}?refresh=true"), None).await;
+        let refreshed = body.unwrap();
+        assert_eq!(refreshed["largest_files"][0]["path"], "huge.bin");
+        assert_eq!(scans.load(Ordering::Relaxed), 2);
+
+        // A scan older than the limit is made again.
+        let old = ScannedStats {
+            scanned_at: 0,
+            disk_size: 0,
+            largest_files: Vec::new(),
+        };
+        old.store(&repo).unwrap();
+        let (_, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(body.unwrap()["largest_files"][0]["path"], "huge.bin");
+        assert_eq!(scans.load(Ordering::Relaxed), 3);
+
+        let (status, _) = call(&app, "GET", "/api/v1/repos/alice/missing/stats", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_conflicts() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let uri = "/api/v1/repos/alice/project/conflicts";
+
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body.unwrap(),
+            serde_json::json!({ "bookmarks": [], "has_conflicted_bookmarks": false })
+        );
+
+        let base = repo
+            .write_commit(&[], &[("a.txt", b"a"), ("b.txt", b"b")], "base")
+            .await
+            .unwrap();
+        let left = repo
+            .write_commit(&[base], &[("a.txt", b"left")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("a.txt", b"right")], "right")
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(&[left, right], &[], "merge")
+            .await
+            .unwrap();
+        repo.set_bookmarks(
+            &[
+                ("main".to_string(), Some(base)),
+                ("merge".to_string(), Some(merge)),
+            ],
+            "set bookmarks",
+        )
+        .unwrap();
+
+        let (_, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(
+            body.unwrap(),
+            serde_json::json!({
+                "bookmarks": [{ "name": "merge", "commit": merge.to_hex(), "paths": ["a.txt"] }],
+                "has_conflicted_bookmarks": false,
+            })
+        );
+
+        // Two concurrent operations move main apart, which conflicts it.
+        let mut other = repos.open_repo("alice", "project").unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(left))], "main to left")
+            .unwrap();
+        other
+            .set_bookmarks(&[("main".to_string(), Some(right))], "main to right")
+            .unwrap();
+        let (_, body) = call(&app, "GET", uri, None).await;
+        let body = body.unwrap();
+        assert_eq!(body["has_conflicted_bookmarks"], true);
+        assert_eq!(body["bookmarks"].as_array().unwrap().len(), 1);
+
+        let (status, _) = call(&app, "GET", "/api/v1/repos/alice/missing/conflicts", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let (_dir, app) = test_app();
+        let (status, body) = call(&app, "GET", "/health/live", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["status"], "alive");
+        let (status, body) = call(&app, "GET", "/health/ready", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["status"], "ready");
+
+        // Another app, whose result isn't cached yet.
+        let (dir, app) = test_app();
+        std::fs::remove_dir_all(dir.path().join(".state")).unwrap();
+        let (status, body) = call(&app, "GET", "/health/ready", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let body = body.unwrap();
+        assert_eq!(body["status"], "not ready");
+        let checks = body["checks"].as_array().unwrap();
+        assert_eq!(checks[0]["name"], "repos_root");
+        assert_eq!(checks[0]["ok"], true);
+        assert_eq!(checks[1]["name"], "state");
+        assert_eq!(checks[1]["ok"], false);
+        assert!(checks[1]["error"].is_string());
+        // Liveness doesn't depend on storage.
+        let (status, _) = call(&app, "GET", "/health/live", None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        // Every route create_router registers, as axum writes them.
+        const ROUTES: &[(&str, &[&str])] = &[
+            ("/", &["get"]),
+            ("/health", &["get"]),
+            ("/health/live", &["get"]),
+            ("/health/ready", &["get"]),
+            ("/api/v1/openapi.json", &["get"]),
+            ("/api/v1/repos", &["get", "post"]),
+            ("/api/v1/repos/{owner}/{name}", &["get", "delete", "patch"]),
+            ("/api/v1/repos/{owner}/{name}/transfer", &["post"]),
+            ("/api/v1/repos/{owner}/{name}/forks", &["get", "post"]),
+            ("/api/v1/repos/{owner}/{name}/bookmarks", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/conflicts", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/stats", &["get"]),
+            (
+                "/api/v1/repos/{owner}/{name}/bookmarks/{bookmark}",
+                &["put", "delete"],
+            ),
+            ("/api/v1/repos/{owner}/{name}/hooks", &["get", "post"]),
+            ("/api/v1/repos/{owner}/{name}/hooks/{id}", &["delete"]),
+            (
+                "/api/v1/repos/{owner}/{name}/hooks/{id}/deliveries",
+                &["get"],
+            ),
+            ("/api/v1/repos/{owner}/{name}/commits", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/compare/{*range}", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/commits/{rev}", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/archive/{file}", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/blame/{rev}/{*path}", &["get"]),
+            (
+                "/api/v1/repos/{owner}/{name}/history/{rev}/{*path}",
+                &["get"],
+            ),
+            ("/api/v1/repos/{owner}/{name}/raw/{rev}/{*path}", &["get"]),
+            ("/api/v1/repos/{owner}/{name}/sync", &["post"]),
+            ("/api/v1/user/tokens", &["get", "post"]),
+            ("/api/v1/user/tokens/{id}", &["delete"]),
+            ("/api/v1/user/keys", &["get", "post"]),
+            ("/api/v1/user/keys/{id}", &["delete"]),
+            ("/api/v1/admin/users", &["post"]),
+            ("/{owner}/{name}/info/refs", &["get"]),
+            ("/{owner}/{name}/git-upload-pack", &["post"]),
+            ("/{owner}/{name}/git-receive-pack", &["post"]),
+        ];
+
+        let (_dir, app) = test_app();
+        let (status, body) = call(&app, "GET", OPENAPI_PATH, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let document = body.unwrap();
+        assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+        serde_json::from_value::<utoipa::openapi::OpenApi>(document.clone()).unwrap();
+
+        let paths = document["paths"].as_object().unwrap();
+        for (route, methods) in ROUTES {
+            // OpenAPI has no wildcards; the parameter just takes slashes.
+            let path = route.replace("{*", "{");
+            for method in *methods {
+                assert!(
+                    paths.get(&path).and_then(|path| path.get(method)).is_some(),
+                    "{method} {path} is not described"
+                );
+            }
+        }
+        assert_eq!(paths.len(), ROUTES.len());
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("CommitResponse"));
+        assert!(schemas.contains_key("ErrorBody"));
+        assert!(
+            document["components"]["securitySchemes"]
+                .get("bearer")
+                .is_some()
+        );
+    }
+
+    #[cfg(feature = "swagger-ui")]
+    #[tokio::test]
+    async fn test_swagger_ui() {
+        let (_dir, app) = test_app();
+        let uri = format!("{SWAGGER_UI_PATH}/");
+        let (status, _, _) = get_raw_response(&app, &uri, &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_dir, app) = test_app_with(|state| state.swagger_ui = true);
+        let (status, headers, _) = get_raw_response(&app, &uri, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            headers[CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html")
+        );
+        // The document is still served once, by the API.
+        let (status, _) = call(&app, "GET", OPENAPI_PATH, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_compare() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let base = repo
+            .write_commit(&[], &[("README.md", b"hello\n")], "base")
+            .await
+            .unwrap();
+        let main = repo
+            .write_commit(
+                &[base],
+                &[
+                    ("README.md", b"hello\nworld\n"),
+                    ("src/lib.rs", b"// lib\n"),
+                ],
+                "main",
+            )
+            .await
+            .unwrap();
+        let feature = repo
+            .write_commit(&[base], &[("notes.txt", b"notes\n")], "feature")
+            .await
+            .unwrap();
+        let unrelated = repo.write_commit(&[], &[], "unrelated").await.unwrap();
+        repo.set_bookmarks(
+            &[
+                ("main".to_string(), Some(main)),
+                ("feature".to_string(), Some(feature)),
+            ],
+            "set bookmarks",
+        )
+        .unwrap();
+        let uri = |range: &str| format!("/api/v1/repos/alice/project/compare/{range}");
+
+        let (status, body) = call(&app, "GET", &uri("main...feature"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let comparison = body.unwrap();
+        assert_eq!(comparison["base"], main.to_hex());
+        assert_eq!(comparison["head"], feature.to_hex());
+        assert_eq!(comparison["merge_base"], base.to_hex());
+        assert_eq!(comparison["ahead_by"], 1);
+        assert_eq!(comparison["behind_by"], 1);
+        assert_eq!(comparison["commits"][0]["id"], feature.to_hex());
+        let files: Vec<_> = comparison["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["path"].as_str().unwrap(),
+                    file["kind"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("README.md", "modified"),
+                ("notes.txt", "added"),
+                ("src/lib.rs", "deleted"),
+            ]
+        );
+
+        let range = format!("{}...{}?include_patch=true", base.to_hex(), main.to_hex());
+        let (status, body) = call(&app, "GET", &uri(&range), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let comparison = body.unwrap();
+        assert_eq!(comparison["merge_base"], base.to_hex());
+        assert_eq!(comparison["ahead_by"], 1);
+        assert_eq!(comparison["behind_by"], 0);
+        let patch = comparison["files"][0]["patch"].as_str().unwrap();
+        assert!(patch.contains("+world\n"), "{patch}");
+        assert_eq!(comparison["patch_truncated"], false);
+
+        let (status, body) = call(&app, "GET", &uri("main...main"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let comparison = body.unwrap();
+        assert_eq!(comparison["merge_base"], main.to_hex());
+        assert_eq!(comparison["ahead_by"], 0);
+        assert_eq!(comparison["commits"], serde_json::json!([]));
+        assert_eq!(comparison["files"], serde_json::json!([]));
+
+        // Revisions with only the root commit in common have no merge base.
+        let range = format!("main...{}", unrelated.to_hex());
+        let (status, body) = call(&app, "GET", &uri(&range), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["merge_base"], serde_json::Value::Null);
+
+        let (status, body) = call(&app, "GET", &uri("main"), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.unwrap()["error"]["code"], "invalid_range");
+        let (status, body) = call(&app, "GET", &uri("main...missing"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "revision_not_found");
+
+        // A plain patch turns the base into the head.
+        let range = format!("{}...main", base.to_hex());
+        let (status, headers, body) =
+            get_raw_response(&app, &uri(&range), &[("accept", "text/x-patch")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            headers[CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/x-patch")
+        );
+        let patch = String::from_utf8(body).unwrap();
+        assert!(
+            patch.starts_with(
+                "diff --git a/README.md b/README.md\n--- a/README.md\n+++ b/README.md\n"
+            ),
+            "{patch}"
+        );
+        assert!(patch.contains("+world\n"), "{patch}");
+        assert!(
+            patch.contains("--- /dev/null\n+++ b/src/lib.rs\n@@"),
+            "{patch}"
+        );
+        assert!(patch.ends_with("+// lib\n"), "{patch}");
+    }
+
+    #[tokio::test]
+    async fn test_get_archive() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let base = repo
+            .write_commit(
+                &[],
+                &[("README.md", b"hello\n"), ("src/lib.rs", b"// lib\n")],
+                "base",
+            )
+            .await
+            .unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(base))], "set main")
+            .unwrap();
+        let uri = |rev: &str| format!("/api/v1/repos/alice/project/archive/{rev}.tar.gz");
+
+        let (status, headers, archive) = get_raw_response(&app, &uri("main"), &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_TYPE], "application/gzip");
+        let short = base.short(ARCHIVE_ID_LEN);
+        assert_eq!(
+            headers[CONTENT_DISPOSITION],
+            format!("attachment; filename=\"project-{short}.tar.gz\"").as_str()
+        );
+        assert_eq!(headers[ETAG], format!("\"{base}\"").as_str());
+        let mut entries = Vec::new();
+        let mut tarball = tar::Archive::new(flate2::read::GzDecoder::new(&archive[..]));
+        for entry in tarball.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            entries.push((path, content));
+        }
+        assert_eq!(
+            entries,
+            [
+                (format!("project-{short}/README.md"), "hello\n".to_string()),
+                (
+                    format!("project-{short}/src/lib.rs"),
+                    "// lib\n".to_string()
+                ),
+            ]
+        );
+
+        // The same commit gives the same bytes, by any name.
+        let (_, _, again) = get_raw_response(&app, &uri(&base.to_hex()), &[]).await;
+        assert_eq!(again, archive);
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+        let (status, _, _) =
+            get_raw_response(&app, &uri("main"), &[("if-none-match", &etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+
+        let (status, _, _) =
+            get_raw_response(&app, "/api/v1/repos/alice/project/archive/main.zip", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let left = repo
+            .write_commit(&[base], &[("README.md", b"left\n")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("README.md", b"right\n")], "right")
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(&[left, right], &[], "merge")
+            .await
+            .unwrap();
+        let (status, _, body) = get_raw_response(&app, &uri(&merge.to_hex()), &[]).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "conflicted_tree");
+    }
+
+    #[tokio::test]
+    async fn test_bookmarks() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let mut repo = repos.open_repo("alice", "project").unwrap();
+        let first = repo.write_commit(&[], &[], "first").await.unwrap();
+        let second = repo.write_commit(&[first], &[], "second").await.unwrap();
+        let other = repo.write_commit(&[], &[], "other").await.unwrap();
+        let uri = |bookmark: &str| format!("/api/v1/repos/alice/project/bookmarks/{bookmark}");
+        let put = |target: &CommitId, expected_old: Option<&CommitId>, force: bool| {
+            serde_json::json!({
+                "target": target.to_hex(),
+                "expected_old": expected_old.map(CommitId::to_hex),
+                "force": force,
+            })
+        };
+
+        // Create, then fast-forward.
+        let (status, body) = call(&app, "PUT", &uri("main"), Some(put(&first, None, false))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let body = body.unwrap();
+        assert_eq!(body["bookmark"]["target"]["id"], first.to_hex());
+        assert!(
+            body["operation_id"]
+                .as_str()
+                .is_some_and(|id| !id.is_empty())
+        );
+        let (status, body) = call(
+            &app,
+            "PUT",
+            &uri("main"),
+            Some(put(&second, Some(&first), false)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["bookmark"]["target"]["id"], second.to_hex());
+
+        // main is no longer at `first`.
+        let (status, body) = call(
+            &app,
+            "PUT",
+            &uri("main"),
+            Some(put(&other, Some(&first), true)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "stale_bookmark");
+        let (status, body) = call(&app, "PUT", &uri("main"), Some(put(&first, None, false))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "stale_bookmark");
+
+        // Moving to a commit off main's history takes force.
+        let (status, body) = call(
+            &app,
+            "PUT",
+            &uri("main"),
+            Some(put(&other, Some(&second), false)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "non_fast_forward");
+        let (status, _) = call(
+            &app,
+            "PUT",
+            &uri("main"),
+            Some(put(&other, Some(&second), true)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = call(
+            &app,
+            "PUT",
+            &uri("feature"),
+            Some(put(&second, None, false)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/project/bookmarks", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let bookmarks = body.unwrap()["bookmarks"].clone();
+        assert_eq!(bookmarks[0]["name"], "feature");
+        assert_eq!(bookmarks[0]["target"]["id"], second.to_hex());
+        assert_eq!(bookmarks[0]["conflicted"], false);
+        assert_eq!(bookmarks[0]["ahead"], 2);
+        assert_eq!(bookmarks[0]["behind"], 1);
+        assert_eq!(bookmarks[1]["name"], "main");
+        assert_eq!(bookmarks[1]["ahead"], 0);
+        assert_eq!(bookmarks[1]["behind"], 0);
+
+        // Deletes are compare-and-set too.
+        let delete_uri = |bookmark: &str, expected: &CommitId| {
+            format!("{}?expected_old={}", uri(bookmark), expected.to_hex())
+        };
+        let (status, body) = call(&app, "DELETE", &delete_uri("feature", &first), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "stale_bookmark");
+        let (status, _) = call(&app, "DELETE", &uri("feature"), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) = call(&app, "DELETE", &delete_uri("feature", &second), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.unwrap()["operation_id"].is_string());
+        let (status, body) = call(&app, "DELETE", &delete_uri("feature", &second), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "bookmark_not_found");
+
+        // A protected repository keeps its default bookmark, and an archived
+        // one takes no writes.
+        let repo = repos.open_repo("alice", "project").unwrap();
+        let mut metadata = RepoMetadata::load(&repo).unwrap().unwrap();
+        metadata.protected = true;
+        metadata.store(&repo).unwrap();
+        let (status, body) = call(&app, "DELETE", &delete_uri("main", &other), None).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body.unwrap()["error"]["code"], "protected_bookmark");
+        let (status, _) = call(
+            &app,
+            "PUT",
+            &uri("main"),
+            Some(put(&first, Some(&other), true)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::LOCKED);
+        metadata.archived = true;
+        metadata.store(&repo).unwrap();
+        let (status, body) = call(&app, "PUT", &uri("topic"), Some(put(&first, None, false))).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body.unwrap()["error"]["code"], "archived");
+    }
+
+    #[tokio::test]
+    async fn test_delete_repo() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let uri = "/api/v1/repos/alice/project";
+
+        // Without a confirmation naming the repository, nothing is deleted.
+        for confirm in [None, Some("alice/other")] {
+            let (status, body) = delete(&app, uri, confirm).await;
+            assert_eq!(status, StatusCode::PRECONDITION_REQUIRED);
+            assert_eq!(body.unwrap()["error"]["code"], "confirmation_required");
+        }
+        let (status, _) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) =
+            delete(&app, "/api/v1/repos/alice/missing", Some("alice/missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "not_found");
+
+        // Protected repositories stay.
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let repo = repos.open_repo("alice", "project").unwrap();
+        let mut metadata = RepoMetadata::load(&repo).unwrap().unwrap();
+        metadata.protected = true;
+        metadata.store(&repo).unwrap();
+        let (status, body) = delete(&app, uri, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body.unwrap()["error"]["code"], "protected");
+        assert!(repos.repo_exists("alice", "project"));
+
+        metadata.protected = false;
+        metadata.store(&repo).unwrap();
+        let (status, _) = delete(&app, uri, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!dir.path().join("alice/project").exists());
+        assert!(!dir.path().join(".trash").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_repo_to_trash() {
+        let (dir, app) = test_app_with(|state| state.soft_delete = true);
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let uri = "/api/v1/repos/alice/project";
+        let trash = dir.path().join(".trash/alice");
+        let trashed = || std::fs::read_dir(&trash).map_or(0, |entries| entries.count());
+
+        call(&app, "POST", "/api/v1/repos", Some(create.clone())).await;
+        let (status, _) = delete(&app, uri, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(trashed(), 1);
+
+        // The trash isn't listed as an owner.
+        let (_, body) = call(&app, "GET", "/api/v1/repos", None).await;
+        assert_eq!(body.unwrap()["repositories"], serde_json::json!([]));
+
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        let permanent = format!("{uri}?permanent=true");
+        let (status, _) = delete(&app, &permanent, Some("alice/project")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!dir.path().join("alice/project").exists());
+        assert_eq!(trashed(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_repo_changes_need_write_access() {
+        let (_dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let request = |token: Option<&str>| {
+            let request = axum::http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/repos")
+                .header(CONTENT_TYPE, "application/json");
+            let request = match token {
+                Some(token) => request.header(AUTHORIZATION, format!("Bearer {token}")),
+                None => request,
+            };
+            request.body(Body::from(create.to_string())).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(request(Some("fj_ci"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let (_, body) = call(&app, "GET", "/api/v1/repos", None).await;
+        assert_eq!(body.unwrap()["repositories"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth() {
+        let send = |app: Router, method: &str, uri: &str, token: Option<&str>| {
+            let request = axum::http::Request::builder().method(method).uri(uri);
+            let request = match token {
+                Some(token) => request.header(AUTHORIZATION, format!("Bearer {token}")),
+                None => request,
+            };
+            let request = request.body(Body::empty()).unwrap();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let challenge = response.headers().get(WWW_AUTHENTICATE).cloned();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+                let code = body["error"]["code"].as_str().map(str::to_string);
+                (status, challenge, code)
+            }
+        };
+        let (_dir, app) = test_app();
+        call(
+            &app,
+            "POST",
+            "/api/v1/repos",
+            Some(serde_json::json!({ "owner": "alice", "name": "project" })),
+        )
+        .await;
+
+        // A missing token is refused, with the envelope and a challenge.
+        let (status, challenge, code) = send(app.clone(), "GET", "/api/v1/repos", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.unwrap(), "Bearer");
+        assert_eq!(code.as_deref(), Some("unauthorized"));
+        let sync = "/api/v1/repos/alice/project/sync";
+        let (status, _, _) = send(app.clone(), "POST", sync, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // So is a token the server doesn't know, even where none is needed.
+        for uri in ["/api/v1/repos", "/health"] {
+            let (status, challenge, code) = send(app.clone(), "GET", uri, Some("fj_guess")).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(challenge.unwrap(), "Bearer");
+            assert_eq!(code.as_deref(), Some("invalid_token"));
+        }
+
+        // A valid token reads, but needs repo:write to change anything.
+        let (status, _, _) = send(
+            app.clone(),
+            "GET",
+            "/api/v1/repos/alice/project",
+            Some("fj_ci"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let bookmark = "/api/v1/repos/alice/project/bookmarks/main";
+        let (status, _, code) = send(app.clone(), "DELETE", bookmark, Some("fj_ci")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("forbidden"));
+
+        // The server itself is described to anyone.
+        for uri in ["/", "/health", OPENAPI_PATH] {
+            let (status, _, _) = send(app.clone(), "GET", uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+        }
+
+        // A public instance lets anyone read, but not write.
+        let (_dir, app) = test_app_with(|state| state.anonymous_read = true);
+        let (status, _, _) = send(app.clone(), "GET", "/api/v1/repos", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, code) = send(app.clone(), "POST", "/api/v1/repos", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(code.as_deref(), Some("unauthorized"));
+    }
+
+    #[tokio::test]
+    async fn test_create_repo() {
+        let (_dir, app) = test_app();
+        let create = serde_json::json!({
+            "owner": "alice",
+            "name": "project",
+            "description": "a project",
+            "backend": "simple",
+        });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(create.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let created = body.unwrap();
+        assert_eq!(created["description"], "a project");
+        assert!(created["created_at"].as_u64().unwrap() > 0);
+
+        // The metadata is read back with the repository.
+        let (_, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        let fetched = body.unwrap();
+        assert_eq!(fetched["description"], "a project");
+        assert_eq!(fetched["created_at"], created["created_at"]);
+
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "already_exists");
+    }
+
+    #[tokio::test]
+    async fn test_get_repo() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        call(&app, "POST", "/api/v1/repos", Some(create)).await;
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let repo = body.unwrap();
+        assert_eq!(repo["full_name"], "alice/project");
+        assert_eq!(repo["backend"], "simple");
+        assert_eq!(repo["default_bookmark"], serde_json::Value::Null);
+        assert_eq!(repo["bookmark_count"], 0);
+        assert!(repo["head_count"].as_u64().unwrap() >= 1);
+        assert!(repo["updated_at"].as_u64().unwrap() > 0);
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "not_found");
+
+        // A repository whose store can't be loaded isn't reported missing.
+        let store_type = dir.path().join("alice/project/.jj/repo/store/type");
+        std::fs::remove_file(store_type).unwrap();
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.unwrap()["error"]["code"], "corrupt_repository");
+    }
+
+    #[tokio::test]
+    async fn test_create_repo_rejects_invalid_fields() {
+        let (_dir, app) = test_app();
+
+        let traversal = serde_json::json!({ "owner": "../alice", "name": "../etc" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(traversal)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let error = &body.unwrap()["error"];
+        assert_eq!(error["code"], "invalid_fields");
+        let fields: Vec<&str> = error["details"]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, ["owner", "name"]);
+
+        let git = serde_json::json!({ "owner": "alice", "name": "project", "backend": "git" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(git)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body.unwrap()["error"]["details"]["fields"][0]["field"],
+            "backend"
+        );
+
+        let unknown = serde_json::json!({ "owner": "alice", "name": "project", "backend": "svn" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(unknown)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body.unwrap()["error"]["code"], "invalid_request");
+
+        let (_, body) = call(&app, "GET", "/api/v1/repos", None).await;
+        assert_eq!(body.unwrap()["repositories"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_error_envelope() {
+        let (_dir, app) = test_app();
+        let shape = |body: Option<serde_json::Value>| {
+            let body = body.unwrap();
+            let object = body.as_object().unwrap();
+            assert_eq!(object.keys().collect::<Vec<_>>(), ["error"]);
+            let error = &body["error"];
+            assert!(error["message"].is_string());
+            assert!(error["details"].is_object());
+            error["code"].as_str().unwrap().to_string()
+        };
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(shape(body), "not_found");
+
+        let (status, body) = call(&app, "GET", "/api/v1/nothing/here", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(shape(body), "unknown_endpoint");
+
+        let (status, body) = call(&app, "PATCH", "/api/v1/repos", None).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(shape(body), "method_not_allowed");
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/%FF", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(shape(body), "invalid_request");
+
+        let (status, body) = call(&app, "POST", "/api/v1/repos/alice/project/sync", None).await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(shape(body), "upgrade_required");
+    }
+
+    #[tokio::test]
+    async fn test_internal_error_hides_paths() {
+        // Repositories can't be created under a file.
+        let dir = TempDir::new().unwrap();
+        let blocked = dir.path().join("blocked");
+        std::fs::write(&blocked, "").unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: blocked.clone(),
+        })
+        .unwrap();
+        let (_other, app) = test_app_with(|state| state.repos = Arc::new(repos));
+
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body.unwrap();
+        assert_eq!(body["error"]["code"], "internal");
+        let error_id = body["error"]["details"]["error_id"].as_str().unwrap();
+        assert_eq!(error_id.len(), 16);
+        assert!(
+            body["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains(error_id)
+        );
+        let text = body.to_string();
+        assert!(!text.contains(blocked.to_str().unwrap()));
+        assert!(!text.contains("Not a directory"));
+    }
+
+    #[tokio::test]
+    async fn test_user_tokens() {
+        let (dir, app) = test_app();
+        let with_token = |method: &str, uri: &str, token: &str, body: Option<serde_json::Value>| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {token}"));
+            let request = match body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response = app.clone().oneshot(request.unwrap());
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&bytes).ok(),
+                )
+            }
+        };
+
+        let create = serde_json::json!({ "name": "ci", "scopes": ["repo:read"] });
+        let (status, body) = call(&app, "POST", "/api/v1/user/tokens", Some(create)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let created = body.unwrap();
+        let token = created["token"].as_str().unwrap().to_string();
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(created["scopes"], serde_json::json!(["repo:read"]));
+
+        // The token works, for what it allows.
+        let (status, _) = with_token("GET", "/api/v1/repos", &token, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let repo = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, _) = with_token("POST", "/api/v1/repos", &token, Some(repo)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Listings never have the secret.
+        let (status, body) = with_token("GET", "/api/v1/user/tokens", &token, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let listed = body.unwrap();
+        assert_eq!(listed["tokens"][0]["id"], id.as_str());
+        assert_eq!(listed["tokens"][0]["name"], "ci");
+        assert!(!listed.to_string().contains(&token));
+        assert!(!listed.to_string().contains(&token["fj_".len()..]));
+        let kept = std::fs::read_to_string(dir.path().join(".state/tokens.json")).unwrap();
+        assert!(!kept.contains(&token));
+
+        // A token can't make a more powerful one.
+        let admin = serde_json::json!({ "name": "root", "scopes": ["admin"] });
+        let (status, body) = with_token("POST", "/api/v1/user/tokens", &token, Some(admin)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.unwrap()["error"]["code"], "forbidden");
+        let past = serde_json::json!({ "name": "old", "scopes": ["repo:read"], "expires_at": 1 });
+        let (status, body) = call(&app, "POST", "/api/v1/user/tokens", Some(past)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body.unwrap()["error"]["details"]["fields"][0]["field"],
+            "expires_at"
+        );
+
+        // Other users can't revoke it; its user can, once.
+        let uri = format!("/api/v1/user/tokens/{id}");
+        let (status, _) = with_token("DELETE", &uri, "fj_ci", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, "DELETE", &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = with_token("GET", "/api/v1/repos", &token, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body.unwrap()["error"]["code"], "invalid_token");
+        let (status, body) = call(&app, "DELETE", &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "token_not_found");
+
+        // Managing tokens takes one, even on a public instance.
+        let (_dir, app) = test_app_with(|state| state.anonymous_read = true);
+        let request = axum::http::Request::builder()
+            .uri("/api/v1/user/tokens")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_expired_token() {
+        let dir = TempDir::new().unwrap();
+        let tokens = test_tokens(dir.path());
+        let new = NewToken {
+            name: "old".to_string(),
+            scopes: vec![Scope::RepoRead],
+            expires_at: Some(unix_now() - 1),
+        };
+        let (_, token) = tokens.create_token("alice", new).unwrap();
+        let (_other, app) = test_app_with(|state| state.tokens = Arc::new(tokens));
+
+        let request = axum::http::Request::builder()
+            .uri("/api/v1/repos")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "expired_token");
+    }
+
+    #[tokio::test]
+    async fn test_repo_ownership() {
+        let (_dir, app) = test_app();
+        let with_token = |method: &str, uri: &str, token: &str, body: Option<serde_json::Value>| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {token}"))
+                .header(CONFIRM_DELETE, uri.trim_start_matches("/api/v1/repos/"));
+            let request = match body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response = app.clone().oneshot(request.unwrap());
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&bytes).ok(),
+                )
+            }
+        };
+        let repo = |owner: &str, name: &str| serde_json::json!({ "owner": owner, "name": name });
+
+        // alice can create her own repositories, and not bob's.
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(repo("alice", "foo"))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(repo("bob", "foo"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.unwrap()["error"]["code"], "not_owner");
+
+        // An admin can do both.
+        let (status, _) =
+            with_token("POST", "/api/v1/repos", "fj_root", Some(repo("bob", "foo"))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = with_token(
+            "POST",
+            "/api/v1/repos",
+            "fj_root",
+            Some(repo("alice", "bar")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Others' repositories can be read, but not changed.
+        let (status, _) = call(&app, "GET", "/api/v1/repos/bob/foo", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let move_main = serde_json::json!({ "target": "root()" });
+        let uri = "/api/v1/repos/bob/foo/bookmarks/main";
+        let (status, body) = call(&app, "PUT", uri, Some(move_main)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.unwrap()["error"]["code"], "not_owner");
+        let (status, _) = with_token("DELETE", "/api/v1/repos/bob/foo", "fj_alice", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = with_token("DELETE", "/api/v1/repos/bob/foo", "fj_bob", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = with_token("DELETE", "/api/v1/repos/alice/bar", "fj_root", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_create_user() {
+        let (dir, app) = test_app();
+        let create = |token: &'static str, username: &str| {
+            let request = axum::http::Request::builder()
+                .method("POST")
+                .uri("/api/v1/admin/users")
+                .header(AUTHORIZATION, format!("Bearer {token}"))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "username": username }).to_string(),
+                ))
+                .unwrap();
+            let response = app.clone().oneshot(request);
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+                )
+            }
+        };
+
+        let (status, body) = create("fj_root", "carol").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["username"], "carol");
+        assert!(body["created_at"].as_u64().unwrap() > 0);
+        assert!(test_users(dir.path()).get("carol").is_some());
+
+        let (status, body) = create("fj_root", "carol").await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "already_exists");
+        let (status, body) = create("fj_root", "../carol").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["details"]["fields"][0]["field"], "username");
+
+        // Only admins create accounts.
+        let (status, body) = create("fj_alice", "dave").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "forbidden");
+    }
+
+    #[tokio::test]
+    async fn test_ssh_keys() {
+        let (dir, app) = test_app();
+        let with_token = |method: &str, uri: &str, token: &str, body: Option<serde_json::Value>| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {token}"));
+            let request = match body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response = app.clone().oneshot(request.unwrap());
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&bytes).ok(),
+                )
+            }
+        };
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let public = key.public_key().to_openssh().unwrap();
+        let add = serde_json::json!({ "title": "laptop", "key": public });
+
+        let (status, body) = call(&app, "POST", "/api/v1/user/keys", Some(add.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let added = body.unwrap();
+        let id = added["id"].as_str().unwrap().to_string();
+        let fingerprint = fingerprint(key.public_key());
+        assert_eq!(added["fingerprint"], fingerprint.as_str());
+        assert_eq!(added["title"], "laptop");
+
+        let (status, body) = call(&app, "GET", "/api/v1/user/keys", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["keys"][0]["id"], id.as_str());
+        let (_, body) = with_token("GET", "/api/v1/user/keys", "fj_bob", None).await;
+        assert_eq!(body.unwrap()["keys"], serde_json::json!([]));
+
+        // A key belongs to one user.
+        let (status, body) = call(&app, "POST", "/api/v1/user/keys", Some(add.clone())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "already_exists");
+        let (status, _) = with_token("POST", "/api/v1/user/keys", "fj_bob", Some(add)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let garbage = serde_json::json!({ "title": "laptop", "key": "ssh-ed25519 garbage" });
+        let (status, body) = call(&app, "POST", "/api/v1/user/keys", Some(garbage)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let field = &body.unwrap()["error"]["details"]["fields"][0];
+        assert_eq!(field["field"], "key");
+        assert!(
+            field["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("invalid public key")
+        );
+        let other = serde_json::json!({ "title": "ci", "key": public });
+        let (status, _) = with_token("POST", "/api/v1/user/keys", "fj_ci", Some(other)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // The SSH transport sees the key, until it is removed.
+        assert_eq!(
+            test_keys(dir.path()).lookup(&fingerprint).as_deref(),
+            Some("alice")
+        );
+        let uri = format!("/api/v1/user/keys/{id}");
+        let (status, _) = with_token("DELETE", &uri, "fj_bob", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, "DELETE", &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&app, "DELETE", &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "key_not_found");
+        assert_eq!(test_keys(dir.path()).lookup(&fingerprint), None);
+    }
+
+    #[tokio::test]
+    async fn test_private_repo() {
+        let (dir, app) = test_app_with(|state| state.anonymous_read = true);
+        let send =
+            |method: &str, uri: &str, token: Option<&str>, body: Option<serde_json::Value>| {
+                let mut request = axum::http::Request::builder().method(method).uri(uri);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+                }
+                let request = match body {
+                    Some(body) => request
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(body.to_string())),
+                    None => request.body(Body::empty()),
+                };
+                let response = app.clone().oneshot(request.unwrap());
+                async move {
+                    let response = response.await.unwrap();
+                    let status = response.status();
+                    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    let body: serde_json::Value =
+                        serde_json::from_slice(&bytes).unwrap_or_default();
+                    (
+                        status,
+                        body["error"]["code"].as_str().map(str::to_string),
+                        body,
+                    )
+                }
+            };
+
+        let secret =
+            serde_json::json!({ "owner": "alice", "name": "secret", "visibility": "private" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(secret)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body.unwrap()["visibility"], "private");
+        let open = serde_json::json!({ "owner": "alice", "name": "open" });
+        let (status, body) = call(&app, "POST", "/api/v1/repos", Some(open)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body.unwrap()["visibility"], "public");
+
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let root = repos
+            .open_repo("alice", "secret")
+            .unwrap()
+            .root_commit_id()
+            .unwrap()
+            .to_hex();
+        let base = "/api/v1/repos/alice/secret";
+        let reads = [
+            base.to_string(),
+            format!("{base}/bookmarks"),
+            format!("{base}/conflicts"),
+            format!("{base}/stats"),
+            format!("{base}/commits"),
+            format!("{base}/compare/{root}...{root}"),
+            format!("{base}/commits/{root}"),
+            format!("{base}/archive/{root}.tar.gz"),
+            format!("{base}/blame/{root}/README.md"),
+            format!("{base}/history/{root}/README.md"),
+            format!("{base}/raw/{root}/README.md"),
+        ];
+        for uri in &reads {
+            // The owner and admins get whatever the route answers, and
+            // everyone else the same as for a repository that doesn't exist.
+            for token in ["fj_alice", "fj_root"] {
+                let (_, code, _) = send("GET", uri, Some(token), None).await;
+                assert_ne!(code.as_deref(), Some("not_found"), "{uri} as {token}");
+            }
+            for token in [Some("fj_bob"), Some("fj_ci"), None] {
+                let (status, code, _) = send("GET", uri, token, None).await;
+                assert_eq!(status, StatusCode::NOT_FOUND, "{uri} as {token:?}");
+                assert_eq!(code.as_deref(), Some("not_found"), "{uri} as {token:?}");
+            }
+        }
+        let sync = format!("{base}/sync");
+        let (status, _, _) = send("POST", &sync, Some("fj_alice"), None).await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+        for token in [Some("fj_bob"), None] {
+            let (status, _, _) = send("POST", &sync, token, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+        // Writes are hidden the same way.
+        let main = serde_json::json!({ "target": root });
+        let uri = format!("{base}/bookmarks/main");
+        let (status, _, _) = send("PUT", &uri, Some("fj_bob"), Some(main)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Listings leave it out for those who can't see it.
+        let names = |body: &serde_json::Value| -> Vec<String> {
+            body["repositories"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|repo| repo["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let (_, _, body) = send("GET", "/api/v1/repos?owner=alice", None, None).await;
+        assert_eq!(names(&body), ["open"]);
+        let (_, _, body) = send("GET", "/api/v1/repos?limit=1", Some("fj_bob"), None).await;
+        assert_eq!(names(&body), ["open"]);
+        assert_eq!(body["next_cursor"], serde_json::Value::Null);
+        let (_, _, body) = send("GET", "/api/v1/repos?owner=alice", Some("fj_alice"), None).await;
+        assert_eq!(names(&body), ["open", "secret"]);
+
+        // Once public, anyone may read it, and only its owner change it.
+        let public = serde_json::json!({ "visibility": "public" });
+        let (status, _, _) = send("PATCH", base, Some("fj_bob"), Some(public.clone())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, body) = send("PATCH", base, Some("fj_alice"), Some(public.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["visibility"], "public");
+        let (status, _, _) = send("GET", base, None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, code, _) = send("PATCH", base, Some("fj_bob"), Some(public)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(code.as_deref(), Some("not_owner"));
+    }
+
+    #[tokio::test]
+    async fn test_rename_repo() {
+        let (dir, app) = test_app();
+        for name in ["project", "taken"] {
+            let repo = serde_json::json!({ "owner": "alice", "name": name });
+            let (status, _) = call(&app, "POST", "/api/v1/repos", Some(repo)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let rename = serde_json::json!({ "new_name": "renamed", "description": "moved" });
+        let (status, body) = call(&app, "PATCH", "/api/v1/repos/alice/project", Some(rename)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["full_name"], "alice/renamed");
+        assert_eq!(body["description"], "moved");
+        let (status, _) = call(&app, "GET", "/api/v1/repos/alice/renamed", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Reads of the old name are redirected, query and all, and changes
+        // refused with the new location.
+        let (status, headers, _) =
+            get_raw_response(&app, "/api/v1/repos/alice/project/commits?limit=5", &[]).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            headers[LOCATION],
+            "/api/v1/repos/alice/renamed/commits?limit=5"
+        );
+        let main = serde_json::json!({ "target": "root()" });
+        let uri = "/api/v1/repos/alice/project/bookmarks/main";
+        let (status, body) = call(&app, "PUT", uri, Some(main)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let error = &body.unwrap()["error"];
+        assert_eq!(error["code"], "moved");
+        assert_eq!(
+            error["details"]["location"],
+            "/api/v1/repos/alice/renamed/bookmarks/main"
+        );
+
+        // A repository created at the old name takes it back.
+        let project = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(project)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.unwrap()["full_name"], "alice/project");
+
+        // Taken names, bad names and protected repositories are refused.
+        let uri = "/api/v1/repos/alice/renamed";
+        let taken = serde_json::json!({ "new_name": "taken" });
+        let (status, body) = call(&app, "PATCH", uri, Some(taken)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.unwrap()["error"]["code"], "already_exists");
+        let bad = serde_json::json!({ "new_name": "../etc" });
+        let (status, _) = call(&app, "PATCH", uri, Some(bad)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let repo = repos.open_repo("alice", "renamed").unwrap();
+        let mut metadata = RepoMetadata::load(&repo).unwrap().unwrap();
+        metadata.protected = true;
+        metadata.store(&repo).unwrap();
+        let other = serde_json::json!({ "new_name": "other" });
+        let (status, body) = call(&app, "PATCH", uri, Some(other)).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body.unwrap()["error"]["code"], "protected");
+        assert!(repos.repo_exists("alice", "renamed"));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_repo() {
+        let (_dir, app) = test_app_with(|state| state.anonymous_read = true);
+        let with_token =
+            |method: &str, uri: &str, token: Option<&str>, body: Option<serde_json::Value>| {
+                let mut request = axum::http::Request::builder().method(method).uri(uri);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+                }
+                let request = match body {
+                    Some(body) => request
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(body.to_string())),
+                    None => request.body(Body::empty()),
+                };
+                let response = app.clone().oneshot(request.unwrap());
+                async move {
+                    let response = response.await.unwrap();
+                    let status = response.status();
+                    let location = response.headers().get(LOCATION).cloned();
+                    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    let body: serde_json::Value =
+                        serde_json::from_slice(&bytes).unwrap_or_default();
+                    (status, location, body)
+                }
+            };
+        for (owner, token) in [("alice", "fj_alice"), ("bob", "fj_bob")] {
+            let repo = serde_json::json!({ "owner": owner, "name": "project" });
+            let (status, _, _) = with_token("POST", "/api/v1/repos", Some(token), Some(repo)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let secret =
+            serde_json::json!({ "owner": "alice", "name": "secret", "visibility": "private" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(secret)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Only the owner or an admin may transfer it, and not to an owner
+        // with a repository of the same name.
+        let uri = "/api/v1/repos/alice/project/transfer";
+        let to_bob = serde_json::json!({ "new_owner": "bob" });
+        let to_carol = serde_json::json!({ "new_owner": "carol" });
+        let (status, _, body) =
+            with_token("POST", uri, Some("fj_bob"), Some(to_carol.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "not_owner");
+        let (status, _, body) = with_token("POST", uri, Some("fj_alice"), Some(to_bob)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "already_exists");
+        let to_alice = serde_json::json!({ "new_owner": "alice" });
+        let (status, _, _) = with_token("POST", uri, Some("fj_alice"), Some(to_alice)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, _, body) = with_token("POST", uri, Some("fj_alice"), Some(to_carol)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["full_name"], "carol/project");
+        let (status, location, _) =
+            with_token("GET", "/api/v1/repos/alice/project", None, None).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(location.unwrap(), "/api/v1/repos/carol/project");
+        let (status, location, body) = with_token(
+            "DELETE",
+            "/api/v1/repos/alice/project",
+            Some("fj_alice"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "moved");
+        assert_eq!(location.unwrap(), "/api/v1/repos/carol/project");
+
+        // Moving again redirects from every old name.
+        let uri = "/api/v1/repos/carol/project/transfer";
+        let to_dave = serde_json::json!({ "new_owner": "dave" });
+        let (status, _, _) = with_token("POST", uri, Some("fj_root"), Some(to_dave)).await;
+        assert_eq!(status, StatusCode::OK);
+        for old in ["alice", "carol"] {
+            let uri = format!("/api/v1/repos/{old}/project/bookmarks");
+            let (status, location, _) = with_token("GET", &uri, None, None).await;
+            assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+            assert_eq!(location.unwrap(), "/api/v1/repos/dave/project/bookmarks");
+        }
+
+        // The old name of a private repository only leads those who may see
+        // it to the new one.
+        let uri = "/api/v1/repos/alice/secret/transfer";
+        let to_bob = serde_json::json!({ "new_owner": "bob" });
+        let (status, _, _) = with_token("POST", uri, Some("fj_root"), Some(to_bob)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, location, _) =
+            with_token("GET", "/api/v1/repos/alice/secret", Some("fj_bob"), None).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(location.unwrap(), "/api/v1/repos/bob/secret");
+        for token in [Some("fj_alice"), None] {
+            let (status, location, _) =
+                with_token("GET", "/api/v1/repos/alice/secret", token, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{token:?}");
+            assert_eq!(location, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fork_repo() {
+        let (_dir, app) = test_app();
+        let with_token = |method: &str, uri: &str, token: &str, body: Option<serde_json::Value>| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {token}"));
+            let request = match body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response = app.clone().oneshot(request.unwrap());
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+                (status, body)
+            }
+        };
+        let project =
+            serde_json::json!({ "owner": "alice", "name": "project", "description": "a project" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(project)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // The fork goes to the user, under the same name, unless told
+        // otherwise.
+        let uri = "/api/v1/repos/alice/project/forks";
+        let (status, body) = with_token("POST", uri, "fj_bob", Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["full_name"], "bob/project");
+        assert_eq!(body["forked_from"], "alice/project");
+        assert_eq!(body["description"], "a project");
+        let copy = serde_json::json!({ "name": "copy" });
+        let (status, body) = call(&app, "POST", uri, Some(copy)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body.unwrap()["full_name"], "alice/copy");
+
+        let (status, body) = call(&app, "GET", "/api/v1/repos/bob/project", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let fork = body.unwrap();
+        assert_eq!(fork["forked_from"], "alice/project");
+        assert!(fork["head_count"].as_u64().unwrap() >= 1);
+        let (_, body) = call(&app, "GET", "/api/v1/repos/alice/project", None).await;
+        assert_eq!(body.unwrap()["forked_from"], serde_json::Value::Null);
+
+        let (status, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let forks: Vec<&str> = body.as_ref().unwrap()["forks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|fork| fork["full_name"].as_str().unwrap())
+            .collect();
+        assert_eq!(forks, ["alice/copy", "bob/project"]);
+
+        // Taken names, others' owners and bad names are refused.
+        let (status, body) = with_token("POST", uri, "fj_bob", Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"]["code"], "already_exists");
+        let own = serde_json::json!({ "name": "project" });
+        let (status, _) = call(&app, "POST", uri, Some(own)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let elsewhere = serde_json::json!({ "owner": "alice", "name": "mine" });
+        let (status, body) = with_token("POST", uri, "fj_bob", Some(elsewhere)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "not_owner");
+        let bad = serde_json::json!({ "name": "../etc" });
+        let (status, _) = call(&app, "POST", uri, Some(bad)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let missing = "/api/v1/repos/alice/missing/forks";
+        let (status, _) = call(&app, "POST", missing, Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&app, "GET", missing, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_fork_private_repo() {
+        let (_dir, app) = test_app();
+        let with_token = |method: &str, uri: &str, token: &str, body: Option<serde_json::Value>| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(AUTHORIZATION, format!("Bearer {token}"));
+            let request = match body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response = app.clone().oneshot(request.unwrap());
+            async move {
+                let response = response.await.unwrap();
+                let status = response.status();
+                let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+                (status, body)
+            }
+        };
+        let secret =
+            serde_json::json!({ "owner": "alice", "name": "secret", "visibility": "private" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(secret)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Those who can't see it can't fork it, or list its forks.
+        let uri = "/api/v1/repos/alice/secret/forks";
+        let (status, _) = with_token("POST", uri, "fj_bob", Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = with_token("GET", uri, "fj_bob", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // The fork is private too.
+        let copy = serde_json::json!({ "name": "copy" });
+        let (status, body) = call(&app, "POST", uri, Some(copy)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body.unwrap()["visibility"], "private");
+        let (status, _) = with_token("GET", "/api/v1/repos/alice/copy", "fj_bob", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(body.unwrap()["forks"][0]["full_name"], "alice/copy");
+
+        // An admin may fork it to anyone, and the fork stays private.
+        let to_bob = serde_json::json!({ "owner": "bob" });
+        let (status, _) = with_token("POST", uri, "fj_root", Some(to_bob)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let (status, body) = with_token("GET", "/api/v1/repos/bob/secret", "fj_bob", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["visibility"], "private");
+    }
+
+    #[tokio::test]
+    async fn test_update_repo() {
+        let (_dir, app) = test_app();
+        let repo = serde_json::json!({ "owner": "alice", "name": "project", "description": "old" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(repo)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let uri = "/api/v1/repos/alice/project";
+        let update = serde_json::json!({ "description": "new" });
+        let (status, body) = call(&app, "PATCH", uri, Some(update)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["description"], "new");
+        assert_eq!(body["visibility"], "public");
+        let (_, body) = call(&app, "GET", uri, None).await;
+        assert_eq!(body.unwrap()["description"], "new");
+
+        let long = serde_json::json!({ "description": "x".repeat(MAX_DESCRIPTION_LEN + 1) });
+        let (status, _) = call(&app, "PATCH", uri, Some(long)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _) = call(
+            &app,
+            "PATCH",
+            "/api/v1/repos/alice/missing",
+            Some(serde_json::json!({})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_repo_hooks() {
+        let (dir, app) = test_app();
+        let create = serde_json::json!({ "owner": "alice", "name": "project" });
+        let (status, _) = call(&app, "POST", "/api/v1/repos", Some(create)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let root = repos
+            .open_repo("alice", "project")
+            .unwrap()
+            .root_commit_id()
+            .unwrap();
+
+        // A local service that hears of bookmarks moving.
+        let (sender, mut caught) = tokio::sync::mpsc::unbounded_channel();
+        let catcher = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: bytes::Bytes| {
+                sender.send((headers, body)).unwrap();
+                async { StatusCode::NO_CONTENT }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, catcher).await });
+
+        let hooks = "/api/v1/repos/alice/project/hooks";
+        let invalid = serde_json::json!({ "url": "https://ci", "secret": "", "events": [] });
+        let (status, body) = call(&app, "POST", hooks, Some(invalid)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let fields = &body.unwrap()["error"]["details"]["fields"];
+        assert_eq!(fields.as_array().unwrap().len(), 3);
+
+        let hook = serde_json::json!({ "url": url, "secret": "s3cret" });
+        let (status, body) = call(&app, "POST", hooks, Some(hook)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let hook = body.unwrap();
+        assert_eq!(hook["events"], serde_json::json!(["push", "bookmark"]));
+        assert!(hook.get("secret").is_none());
+        let id = hook["id"].as_str().unwrap().to_string();
+        let (_, body) = call(&app, "GET", hooks, None).await;
+        assert_eq!(body.unwrap()["hooks"], serde_json::json!([hook]));
+
+        // Moving a bookmark posts a signed payload.
+        let main = serde_json::json!({ "target": root.to_hex() });
+        let uri = "/api/v1/repos/alice/project/bookmarks/main";
+        let (status, body) = call(&app, "PUT", uri, Some(main)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let operation_id = body.unwrap()["operation_id"].clone();
+        let (headers, body) =
+            tokio::time::timeout(std::time::Duration::from_secs(10), caught.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(headers[hooks::EVENT_HEADER], "bookmark");
+        assert_eq!(
+            headers[hooks::SIGNATURE_HEADER],
+            hooks::sign("s3cret", &body).as_str()
+        );
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["repository"], "alice/project");
+        assert_eq!(payload["sender"], "alice");
+        assert_eq!(payload["operation_id"], operation_id);
+        assert_eq!(
+            payload["bookmarks"],
+            serde_json::json!([{ "name": "main", "old": null, "new": root.to_hex() }])
+        );
+        assert_eq!(payload["commits"][0]["id"], root.to_hex());
+
+        // The attempt is recorded once answered.
+        let deliveries = format!("{hooks}/{id}/deliveries");
+        let mut listed = serde_json::Value::Null;
+        for _ in 0..100 {
+            let (status, body) = call(&app, "GET", &deliveries, None).await;
+            assert_eq!(status, StatusCode::OK);
+            listed = body.unwrap()["deliveries"].clone();
+            if !listed.as_array().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(listed[0]["status"], "delivered");
+        assert_eq!(listed[0]["response_code"], 204);
+        assert_eq!(listed[0]["event"], "bookmark");
+        let (status, body) = call(&app, "GET", &format!("{hooks}/0/deliveries"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body.unwrap()["error"]["code"], "hook_not_found");
+
+        let (status, _) = call(&app, "DELETE", &format!("{hooks}/{id}"), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, "DELETE", &format!("{hooks}/{id}"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = call(&app, "GET", hooks, None).await;
+        assert_eq!(body.unwrap()["hooks"], serde_json::json!([]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let (_dir, app) = test_app_with(|state| {
+            let limits = RateLimits {
+                default: RateLimit {
+                    per_minute: 60,
+                    burst: 2,
+                },
+                ..RateLimits::UNLIMITED
+            };
+            state.rate_limiter = Arc::new(RateLimiter::new(limits));
+        });
+        let send = |token: &str| {
+            let request = axum::http::Request::builder()
+                .uri("/health")
+                .header(AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        for _ in 0..2 {
+            assert_eq!(send("fj_alice").await.unwrap().status(), StatusCode::OK);
+        }
+        let response = send("fj_alice").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "rate_limited");
+        assert_eq!(body["error"]["details"]["retry_after"], 1);
+        // Other users have limits of their own.
+        assert_eq!(send("fj_bob").await.unwrap().status(), StatusCode::OK);
+
+        tokio::time::advance(std::time::Duration::from_secs(1)).await;
+        assert_eq!(send("fj_alice").await.unwrap().status(), StatusCode::OK);
+        let response = send("fj_alice").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let (_dir, app) = test_app();
+        let send = |uri: &str, id: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .uri(uri)
+                .header(AUTHORIZATION, "Bearer fj_alice");
+            if let Some(id) = id {
+                request = request.header(request_id::REQUEST_ID_HEADER, id);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // A valid ID is echoed.
+        let response = send("/health", Some("trace-42")).await.unwrap();
+        assert_eq!(
+            response.headers()[request_id::REQUEST_ID_HEADER],
+            "trace-42"
+        );
+
+        // Otherwise one is made, different for each request.
+        let mut made = Vec::new();
+        for given in [None, Some("not valid")] {
+            let response = send("/health", given).await.unwrap();
+            let id = response.headers()[request_id::REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(id.len(), 36, "{id}");
+            assert!(request_id::is_valid(&id));
+            made.push(id);
+        }
+        assert_ne!(made[0], made[1]);
+
+        // Errors give the same ID in their body.
+        for given in [Some("trace-43"), None] {
+            let response = send("/api/v1/repos/alice/missing", given).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let header = response.headers()[request_id::REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["error"]["request_id"], header);
+            if let Some(given) = given {
+                assert_eq!(header, given);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (_dir, app) = test_app();
+        for uri in [
+            "/health",
+            "/health",
+            "/api/v1/repos/alice/missing",
+            "/nowhere",
+        ] {
+            call(&app, "GET", uri, None).await;
+        }
+
+        let request = axum::http::Request::builder()
+            .uri(METRICS_PATH)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        // Requests are labelled by route, not by path.
+        for series in [
+            "forjj_http_requests_total{method=\"GET\",route=\"/health\",status=\"200\"} 2",
+            "forjj_http_requests_total{method=\"GET\",route=\"/api/v1/repos/{owner}/{name}\",status=\"404\"} 1",
+            "forjj_http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1",
+            "forjj_http_request_duration_seconds_count{method=\"GET\",route=\"/health\"} 2",
+            // The scrape itself is in flight.
+            "forjj_http_requests_in_flight 1",
+            "forjj_sync_sessions_active 0",
+        ] {
+            assert!(
+                text.lines().any(|line| line == series),
+                "{series} in {text}"
+            );
+        }
+        assert!(!text.contains("alice/missing"));
+
+        // Metrics served elsewhere aren't served with the API.
+        let (_dir, app) = test_app_with(|state| state.serve_metrics = false);
+        let (status, _) = call(&app, "GET", METRICS_PATH, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_json_logs() {
+        /// Collects what is logged.
+        #[derive(Clone, Default)]
+        struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(bytes);
+                Ok(bytes.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for Captured {
+            type Writer = Captured;
+
+            fn make_writer(&'a self) -> Captured {
+                self.clone()
+            }
+        }
+
+        use tracing_subscriber::layer::SubscriberExt;
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(crate::logging::fmt_layer(
+            crate::logging::LogFormat::Json,
+            captured.clone(),
+            false,
+        ));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (_dir, app) = test_app();
+        let request = axum::http::Request::builder()
+            .uri("/api/v1/repos/alice/missing")
+            .header(AUTHORIZATION, "Bearer fj_alice")
+            .header(request_id::REQUEST_ID_HEADER, "trace-44")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let logged = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // Every event in the request names it, and the request span's
+        // closing gives its timing.
+        let in_request: Vec<_> = lines
+            .iter()
+            .filter(|line| line["request_id"] == "trace-44")
+            .collect();
+        assert!(!in_request.is_empty(), "{logged}");
+        let closed = in_request
+            .iter()
+            .find(|line| line["message"] == "close" && line["spans"] == "request")
+            .unwrap();
+        assert_eq!(closed["route"], "/api/v1/repos/{owner}/{name}");
+        assert_eq!(closed["owner"], "alice");
+        assert_eq!(closed["repo"], "missing");
+        assert!(closed["time.busy"].is_string());
+    }
 }
@@ -0,0 +1,687 @@
+//! Bearer-token authentication for the HTTP API.
+//!
+//! [`bearer_auth`] checks the `Authorization: Bearer <token>` header of each
+//! request against a [`TokenStore`] and attaches the token's
+//! [`AuthenticatedUser`] to the request. Requests without the header go
+//! through anonymously; requests with a header that doesn't name a known
+//! token are refused with 401 before they reach a handler. Routes then
+//! name the [`Scope`] they need with [`require_scope`].
+//!
+//! Transports with no authentication of their own check the same tokens in
+//! the Hello with [`TokenAuth`].
+//!
+//! Tokens come from a file the operator writes, and from users, who make
+//! their own through the API; [`ManagedTokens`] keeps both.
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context, Result, bail};
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use forjj_protocol::{AccessLevel, Auth, AuthError, AuthGrant, AuthHandler, RepoRef};
+use forjj_storage::ObjectId;
+use rand::TryRngCore;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::api::ApiError;
+use crate::metadata::unix_now;
+use crate::state::StateStore;
+
+/// What a token allows its user to do.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema,
+)]
+pub enum Scope {
+    /// Read repositories, and fetch from them
+    #[serde(rename = "repo:read")]
+    RepoRead,
+    /// Change repositories, and push to them
+    #[serde(rename = "repo:write")]
+    RepoWrite,
+    /// Anything, including administering the server
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    /// The scope's name, as in token files and the API description.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::RepoRead => "repo:read",
+            Scope::RepoWrite => "repo:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// The scope named `name`.
+    pub fn parse(name: &str) -> Option<Self> {
+        [Scope::RepoRead, Scope::RepoWrite, Scope::Admin]
+            .into_iter()
+            .find(|scope| scope.as_str() == name)
+    }
+
+    /// Whether holding this scope allows what `other` does: `admin` allows
+    /// everything, and `repo:write` allows reading too.
+    pub fn covers(self, other: Scope) -> bool {
+        match self {
+            Scope::Admin => true,
+            Scope::RepoWrite => other != Scope::Admin,
+            Scope::RepoRead => other == Scope::RepoRead,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The user a request's token belongs to, attached to the request as an
+/// extension by [`bearer_auth`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    /// What the token allows
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthenticatedUser {
+    /// Whether the token allows what `scope` does.
+    pub fn has(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|held| held.covers(scope))
+    }
+
+    /// Whether the user may change `owner`'s repositories: they are the
+    /// user's own, or the user is an admin.
+    pub fn may_change(&self, owner: &str) -> bool {
+        self.username == owner || self.has(Scope::Admin)
+    }
+
+    /// The grant for the user's sync sessions on `owner`'s repositories,
+    /// which only read unless the user may change them.
+    pub fn grant_on(&self, owner: &str) -> AuthGrant {
+        let mut grant = self.grant();
+        if !self.may_change(owner) {
+            grant.access = AccessLevel::Read;
+        }
+        grant
+    }
+
+    /// The grant for the user's sync sessions.
+    pub fn grant(&self) -> AuthGrant {
+        let access = if self.has(Scope::RepoWrite) {
+            AccessLevel::Write
+        } else {
+            AccessLevel::Read
+        };
+        AuthGrant {
+            identity: Some(self.username.clone()),
+            access,
+        }
+    }
+}
+
+/// Why a token was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TokenError {
+    #[error("the token is not valid")]
+    Unknown,
+
+    #[error("the token has expired")]
+    Expired,
+}
+
+/// A token made through the API, as its user sees it: everything but the
+/// token itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct TokenInfo {
+    pub id: String,
+    /// What the user called it
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// When the token stops working, in seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// What a token to make is to be.
+#[derive(Debug, Clone)]
+pub struct NewToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Seconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// Decides which bearer tokens are valid, and whose they are.
+///
+/// Stores that only check tokens leave the methods for managing them as
+/// they are: such a store has none to list, and makes none.
+pub trait TokenStore: Send + Sync {
+    /// The user `token` belongs to.
+    fn authenticate(&self, token: &str) -> Result<AuthenticatedUser, TokenError>;
+
+    /// The tokens `username` made, oldest first.
+    fn list_tokens(&self, _username: &str) -> Result<Vec<TokenInfo>> {
+        Ok(Vec::new())
+    }
+
+    /// Make a token for `username`, returning it and what it is. The token
+    /// itself isn't kept, and can't be had again.
+    fn create_token(&self, _username: &str, _new: NewToken) -> Result<(TokenInfo, String)> {
+        bail!("this token store does not make tokens")
+    }
+
+    /// Revoke the token `id` of `username`, returning whether there was one.
+    fn revoke_token(&self, _username: &str, _id: &str) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The hash a token is stored as, so that a leaked store doesn't leak the
+/// tokens themselves.
+pub fn hash_token(token: &str) -> ObjectId {
+    ObjectId::hash(token.as_bytes())
+}
+
+/// Prefix of a token file entry that gives the token's hash rather than
+/// the token.
+const HASH_PREFIX: &str = "hash:";
+
+/// Tokens listed in a file, one per line: the scopes the token allows, the
+/// user it belongs to, and the token.
+///
+/// ```text
+/// write alice fj_4f8a1c2e9b7d
+/// read ci fj_0b93d7a61c5e
+/// repo:read,admin root hash:1c0e...
+/// ```
+///
+/// Scopes are listed by name, separated by commas; `read`, `write` and
+/// `admin` are short for `repo:read`, `repo:write` and `admin`. A token
+/// can be given as `hash:` and the hex of its [`hash_token`], to keep it out
+/// of the file; either way only its hash is kept in memory.
+///
+/// Blank lines and lines starting with `#` are skipped.
+#[derive(Default)]
+pub struct TokenFile {
+    entries: Vec<(ObjectId, AuthenticatedUser)>,
+}
+
+impl TokenFile {
+    /// Parse the contents of a token file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_token_line(line).with_context(|| format!("line {}", number + 1))?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Read and parse the file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid tokens in {}", path.display()))
+    }
+
+    /// Number of tokens listed.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl std::fmt::Debug for TokenFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenFile")
+            .field("tokens", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStore for TokenFile {
+    fn authenticate(&self, token: &str) -> Result<AuthenticatedUser, TokenError> {
+        // Compare every entry in constant time, so the time taken says
+        // nothing about which entry matched.
+        let hash = hash_token(token);
+        let mut found = None;
+        for (listed, user) in &self.entries {
+            if listed.ct_eq(&hash) {
+                found = Some(user.clone());
+            }
+        }
+        found.ok_or(TokenError::Unknown)
+    }
+}
+
+/// State file the tokens users made are kept in.
+pub const TOKENS_FILE: &str = "tokens.json";
+
+/// A token a user made, as kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenRecord {
+    id: String,
+    username: String,
+    name: String,
+    scopes: Vec<Scope>,
+    /// The [`hash_token`] of the token
+    hash: ObjectId,
+    created_at: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+impl TokenRecord {
+    fn info(&self) -> TokenInfo {
+        TokenInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// The tokens in a [`TokenFile`], and those users make through the API,
+/// which are kept in a [`StateStore`].
+pub struct ManagedTokens {
+    fixed: TokenFile,
+    state: Arc<StateStore>,
+    records: RwLock<Vec<TokenRecord>>,
+}
+
+impl ManagedTokens {
+    /// Check the tokens in `fixed`, and those kept in `state`.
+    pub fn open(fixed: TokenFile, state: Arc<StateStore>) -> Result<Self> {
+        let records = state.read(TOKENS_FILE)?;
+        Ok(Self {
+            fixed,
+            state,
+            records: RwLock::new(records),
+        })
+    }
+
+    /// Number of tokens users have made.
+    pub fn managed_len(&self) -> usize {
+        self.records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+}
+
+impl std::fmt::Debug for ManagedTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedTokens")
+            .field("fixed", &self.fixed)
+            .field("managed", &self.managed_len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStore for ManagedTokens {
+    fn authenticate(&self, token: &str) -> Result<AuthenticatedUser, TokenError> {
+        if let Ok(user) = self.fixed.authenticate(token) {
+            return Ok(user);
+        }
+        let hash = hash_token(token);
+        let records = self
+            .records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut found = None;
+        for record in records.iter() {
+            if record.hash.ct_eq(&hash) {
+                found = Some(record);
+            }
+        }
+        let record = found.ok_or(TokenError::Unknown)?;
+        if record
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= unix_now())
+        {
+            return Err(TokenError::Expired);
+        }
+        Ok(AuthenticatedUser {
+            username: record.username.clone(),
+            scopes: record.scopes.clone(),
+        })
+    }
+
+    fn list_tokens(&self, username: &str) -> Result<Vec<TokenInfo>> {
+        let records = self
+            .records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok(records
+            .iter()
+            .filter(|record| record.username == username)
+            .map(TokenRecord::info)
+            .collect())
+    }
+
+    fn create_token(&self, username: &str, new: NewToken) -> Result<(TokenInfo, String)> {
+        let token = generate_token();
+        let record = TokenRecord {
+            id: format!("{:016x}", rand::random::<u64>()),
+            username: username.to_string(),
+            name: new.name,
+            scopes: new.scopes,
+            hash: hash_token(&token),
+            created_at: unix_now(),
+            expires_at: new.expires_at,
+        };
+        let mut records = self
+            .records
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut updated = records.clone();
+        updated.push(record.clone());
+        self.state.write(TOKENS_FILE, &updated)?;
+        *records = updated;
+        Ok((record.info(), token))
+    }
+
+    fn revoke_token(&self, username: &str, id: &str) -> Result<bool> {
+        let mut records = self
+            .records
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut updated = records.clone();
+        updated.retain(|record| !(record.username == username && record.id == id));
+        if updated.len() == records.len() {
+            return Ok(false);
+        }
+        self.state.write(TOKENS_FILE, &updated)?;
+        *records = updated;
+        Ok(true)
+    }
+}
+
+/// Prefix of the tokens the server makes, so they can be recognized, for
+/// example by secret scanners.
+const TOKEN_PREFIX: &str = "fj_";
+
+/// Bytes of randomness in the tokens the server makes.
+const TOKEN_BYTES: usize = 32;
+
+/// A new token, from the operating system's random number generator.
+fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::rngs::OsRng
+        .try_fill_bytes(&mut bytes)
+        .expect("the operating system's random number generator failed");
+    format!("{TOKEN_PREFIX}{}", hex::encode(bytes))
+}
+
+fn parse_token_line(line: &str) -> Result<(ObjectId, AuthenticatedUser)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [scopes, username, token] = fields[..] else {
+        bail!("expected scopes, a user name, and a token");
+    };
+    let scopes = scopes
+        .split(',')
+        .map(|name| match name {
+            "read" => Ok(Scope::RepoRead),
+            "write" => Ok(Scope::RepoWrite),
+            name => Scope::parse(name).with_context(|| format!("unknown scope {name:?}")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let hash = match token.strip_prefix(HASH_PREFIX) {
+        Some(hex) => ObjectId::from_hex(hex).context("invalid token hash")?,
+        None => hash_token(token),
+    };
+    let user = AuthenticatedUser {
+        username: username.to_string(),
+        scopes,
+    };
+    Ok((hash, user))
+}
+
+/// Checks the bearer token in the Hello against a [`TokenStore`].
+///
+/// A token is required, even for read access. Sessions on a repository only
+/// write to it if the token's user may change it, as over HTTP and SSH.
+pub struct TokenAuth(pub Arc<dyn TokenStore>);
+
+impl TokenAuth {
+    /// The user whose token `auth` presents.
+    fn user(&self, auth: &Auth) -> Result<AuthenticatedUser, AuthError> {
+        match auth {
+            Auth::BearerToken(token) => self
+                .0
+                .authenticate(token)
+                .map_err(|_| AuthError::InvalidCredentials),
+            Auth::SshKey { .. } => Err(AuthError::UnsupportedMethod),
+            Auth::None => Err(AuthError::MissingCredentials),
+        }
+    }
+}
+
+impl AuthHandler for TokenAuth {
+    fn authenticate(&self, auth: &Auth, _requested: AccessLevel) -> Result<AuthGrant, AuthError> {
+        self.user(auth).map(|user| user.grant())
+    }
+
+    fn authenticate_on(
+        &self,
+        auth: &Auth,
+        _requested: AccessLevel,
+        repo: &RepoRef,
+    ) -> Result<AuthGrant, AuthError> {
+        self.user(auth).map(|user| user.grant_on(&repo.owner))
+    }
+}
+
+/// The grant for requests without credentials.
+pub fn anonymous() -> AuthGrant {
+    AuthGrant {
+        identity: None,
+        access: AccessLevel::Read,
+    }
+}
+
+/// Middleware that authenticates the request's bearer token, if any, and
+/// attaches its [`AuthenticatedUser`] as an extension.
+pub async fn bearer_auth(
+    State(tokens): State<Arc<dyn TokenStore>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(header) = request.headers().get(AUTHORIZATION) else {
+        return next.run(request).await;
+    };
+    let user = match header
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => tokens.authenticate(token.trim()),
+        None => Err(TokenError::Unknown),
+    };
+    match user {
+        Ok(user) => {
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        Err(TokenError::Unknown) => ApiError::invalid_token().into_response(),
+        Err(TokenError::Expired) => ApiError::expired_token().into_response(),
+    }
+}
+
+/// What a route needs of the request's user, checked by [`require_scope`].
+#[derive(Debug, Clone, Copy)]
+pub struct ScopeRequirement {
+    pub scope: Scope,
+    /// Whether requests without a token go through
+    pub anonymous: bool,
+}
+
+/// Middleware for a route that refuses requests whose user doesn't hold
+/// the scope it requires: with 401 if there is no user, or 403 if the
+/// user's token doesn't allow it.
+pub async fn require_scope(
+    State(requirement): State<ScopeRequirement>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    match request.extensions().get::<AuthenticatedUser>() {
+        Some(user) if user.has(requirement.scope) => {}
+        Some(_) => return Err(ApiError::forbidden(requirement.scope)),
+        None if requirement.anonymous => {}
+        None => return Err(ApiError::unauthorized()),
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_file() {
+        let text = format!(
+            "# tokens\n\nwrite alice fj_a\nread ci fj_b\nrepo:read,admin root hash:{}\n",
+            hash_token("fj_c").to_hex()
+        );
+        let tokens = TokenFile::parse(&text).unwrap();
+        assert_eq!(tokens.len(), 3);
+        let alice = tokens.authenticate("fj_a").unwrap();
+        assert_eq!(alice.username, "alice");
+        assert_eq!(alice.scopes, [Scope::RepoWrite]);
+        assert!(alice.has(Scope::RepoRead));
+        assert!(!alice.has(Scope::Admin));
+        assert_eq!(alice.grant().access, AccessLevel::Write);
+        let ci = tokens.authenticate("fj_b").unwrap();
+        assert!(!ci.has(Scope::RepoWrite));
+        assert_eq!(ci.grant().access, AccessLevel::Read);
+        let root = tokens.authenticate("fj_c").unwrap();
+        assert_eq!(root.scopes, [Scope::RepoRead, Scope::Admin]);
+        assert!(root.has(Scope::RepoWrite));
+        assert_eq!(tokens.authenticate("fj_"), Err(TokenError::Unknown));
+        assert!(tokens.authenticate("").is_err());
+        // Hashed entries match the hash, not the text of it.
+        assert!(tokens.authenticate(&hash_token("fj_c").to_hex()).is_err());
+        assert!(!format!("{tokens:?}").contains("fj_a"));
+
+        let error = TokenFile::parse("owner alice fj_a").unwrap_err();
+        assert!(format!("{error:#}").contains("line 1"), "{error:#}");
+        assert!(TokenFile::parse("write fj_a").is_err());
+        assert!(TokenFile::parse("write alice hash:xyz").is_err());
+    }
+
+    #[test]
+    fn test_managed_tokens() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let state = Arc::new(StateStore::open(dir.path()).unwrap());
+        let fixed = TokenFile::parse("write alice fj_alice").unwrap();
+        let tokens = ManagedTokens::open(fixed, state.clone()).unwrap();
+        assert_eq!(tokens.authenticate("fj_alice").unwrap().username, "alice");
+
+        let new = NewToken {
+            name: "laptop".to_string(),
+            scopes: vec![Scope::RepoRead],
+            expires_at: None,
+        };
+        let (info, token) = tokens.create_token("alice", new.clone()).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + 2 * TOKEN_BYTES);
+        let user = tokens.authenticate(&token).unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.scopes, [Scope::RepoRead]);
+        assert_eq!(tokens.list_tokens("alice").unwrap(), [info.clone()]);
+        assert!(tokens.list_tokens("bob").unwrap().is_empty());
+
+        // Only the hash is kept, and it is kept across restarts.
+        let kept = std::fs::read_to_string(dir.path().join(TOKENS_FILE)).unwrap();
+        assert!(!kept.contains(&token));
+        assert!(kept.contains(&hash_token(&token).to_hex()));
+        let fixed = TokenFile::parse("write alice fj_alice").unwrap();
+        let tokens = ManagedTokens::open(fixed, state).unwrap();
+        assert_eq!(tokens.authenticate(&token).unwrap().username, "alice");
+
+        // Expired tokens are told apart from unknown ones.
+        let expired = NewToken {
+            expires_at: Some(unix_now() - 1),
+            ..new
+        };
+        let (_, old) = tokens.create_token("alice", expired).unwrap();
+        assert_eq!(tokens.authenticate(&old), Err(TokenError::Expired));
+
+        // Tokens are revoked by their user only.
+        assert!(!tokens.revoke_token("bob", &info.id).unwrap());
+        assert!(tokens.revoke_token("alice", &info.id).unwrap());
+        assert_eq!(tokens.authenticate(&token), Err(TokenError::Unknown));
+        assert!(!tokens.revoke_token("alice", &info.id).unwrap());
+        assert_eq!(tokens.list_tokens("alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_may_change() {
+        let alice = AuthenticatedUser {
+            username: "alice".to_string(),
+            scopes: vec![Scope::RepoWrite],
+        };
+        assert!(alice.may_change("alice"));
+        assert!(!alice.may_change("bob"));
+        assert_eq!(alice.grant_on("alice").access, AccessLevel::Write);
+        assert_eq!(alice.grant_on("bob").access, AccessLevel::Read);
+        let root = AuthenticatedUser {
+            username: "root".to_string(),
+            scopes: vec![Scope::Admin],
+        };
+        assert!(root.may_change("bob"));
+        assert_eq!(root.grant_on("bob").access, AccessLevel::Write);
+    }
+
+    #[test]
+    fn test_scope_covers() {
+        assert_eq!(Scope::parse("repo:write"), Some(Scope::RepoWrite));
+        assert_eq!(Scope::parse("write"), None);
+        assert!(Scope::Admin.covers(Scope::RepoWrite));
+        assert!(Scope::RepoWrite.covers(Scope::RepoRead));
+        assert!(!Scope::RepoWrite.covers(Scope::Admin));
+        assert!(!Scope::RepoRead.covers(Scope::RepoWrite));
+    }
+
+    #[test]
+    fn test_token_auth() {
+        let tokens = TokenFile::parse("read ci fj_ci\nwrite alice fj_alice").unwrap();
+        let auth = TokenAuth(Arc::new(tokens));
+        let token = Auth::BearerToken("fj_ci".to_string());
+        let grant = auth.authenticate(&token, AccessLevel::Read).unwrap();
+        assert_eq!(grant.identity.as_deref(), Some("ci"));
+
+        // Write tokens only write to their user's own repositories.
+        let alice = Auth::BearerToken("fj_alice".to_string());
+        let own = RepoRef::new("alice", "project");
+        let others = RepoRef::new("bob", "project");
+        let grant = auth.authenticate_on(&alice, AccessLevel::Write, &own);
+        assert_eq!(grant.unwrap().access, AccessLevel::Write);
+        let grant = auth.authenticate_on(&alice, AccessLevel::Write, &others);
+        assert_eq!(grant.unwrap().access, AccessLevel::Read);
+
+        let wrong = Auth::BearerToken("fj_guess".to_string());
+        assert!(matches!(
+            auth.authenticate(&wrong, AccessLevel::Read),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.authenticate(&Auth::None, AccessLevel::Read),
+            Err(AuthError::MissingCredentials)
+        ));
+    }
+}
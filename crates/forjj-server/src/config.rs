@@ -0,0 +1,629 @@
+//! The server's configuration: a TOML file, overridden by command-line
+//! flags, over built-in defaults.
+//!
+//! Every setting has a default, so an empty file, or none, is a valid
+//! config. Unknown keys are errors rather than ignored, so a misspelt
+//! setting doesn't silently keep its default. [`ServerConfig::validate`]
+//! checks what can be checked without starting the server, such as that
+//! the files named exist and can be read.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand};
+use forjj_storage::validate_name;
+use serde::Deserialize;
+
+use crate::api::{DEFAULT_MAX_BLAME_LINES, DEFAULT_STATS_MAX_AGE};
+use crate::health::{Canary, DEFAULT_CANARY_BUDGET};
+use crate::logging::{LogConfig, LogFormat};
+use crate::patch::DEFAULT_MAX_PATCH_BYTES;
+use crate::rate_limit::RateLimits;
+use crate::raw::DEFAULT_MAX_RAW_BYTES;
+use crate::redirects::DEFAULT_GRACE_PERIOD;
+use crate::shutdown::DEFAULT_DRAIN_TIMEOUT;
+
+/// Where the API listens, unless configured.
+pub const DEFAULT_BIND: &str = "0.0.0.0:3000";
+
+/// Where SSH listens, unless configured.
+pub const DEFAULT_SSH_BIND: &str = "0.0.0.0:2222";
+
+/// A native jj forge.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server.
+    Serve(ConfigArgs),
+    /// Work with the server's configuration.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Check the configuration is valid, without starting the server.
+    Check(ConfigArgs),
+}
+
+/// Where the config is, and the settings to override in it.
+#[derive(Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// The config file; the defaults are used if not given
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address for the API to listen on
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Directory repositories are kept in
+    #[arg(long)]
+    pub repos_root: Option<PathBuf>,
+    /// How to write logs: pretty, json or compact
+    #[arg(long)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl ConfigArgs {
+    /// The config file's settings, with the flags' over them, validated.
+    pub fn load(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(bind) = self.bind {
+            config.bind = bind;
+        }
+        if let Some(repos_root) = &self.repos_root {
+            config.storage.repos_root = Some(repos_root.clone());
+        }
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Everything the server can be configured with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the API listens on
+    pub bind: SocketAddr,
+    /// Directory the server keeps its state in, and repositories unless
+    /// [`StorageOptions::repos_root`] is given
+    pub data_dir: PathBuf,
+    pub storage: StorageOptions,
+    /// Serve the API over TLS, if given, rather than plain HTTP
+    pub tls: Option<TlsOptions>,
+    pub auth: AuthOptions,
+    pub ssh: SshOptions,
+    /// Serve mirroring sessions over TCP, if given
+    pub tcp: Option<TcpOptions>,
+    pub metrics: MetricsOptions,
+    pub rate_limits: RateLimits,
+    pub limits: Limits,
+    /// Serve Swagger UI for the API description, in builds with the
+    /// `swagger-ui` feature
+    pub swagger_ui: bool,
+    pub log: LogConfig,
+    pub shutdown: ShutdownOptions,
+    pub health: HealthOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND.parse().expect("default bind address is valid"),
+            data_dir: PathBuf::from("data"),
+            storage: StorageOptions::default(),
+            tls: None,
+            auth: AuthOptions::default(),
+            ssh: SshOptions::default(),
+            tcp: None,
+            metrics: MetricsOptions::default(),
+            rate_limits: RateLimits::default(),
+            limits: Limits::default(),
+            swagger_ui: false,
+            log: LogConfig::default(),
+            shutdown: ShutdownOptions::default(),
+            health: HealthOptions::default(),
+        }
+    }
+}
+
+/// Where and how repositories are stored.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageOptions {
+    /// Directory repositories are kept in; `repos` in the data directory
+    /// if not given
+    pub repos_root: Option<PathBuf>,
+    /// Move deleted repositories aside rather than removing them
+    pub soft_delete: bool,
+    /// How long the old name of a renamed or transferred repository
+    /// points to the new one
+    pub redirect_grace_days: u64,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            repos_root: None,
+            soft_delete: false,
+            redirect_grace_days: DEFAULT_GRACE_PERIOD.as_secs() / (24 * 60 * 60),
+        }
+    }
+}
+
+impl StorageOptions {
+    /// How long redirects from old repository names are followed.
+    pub fn redirect_grace_period(&self) -> Duration {
+        Duration::from_secs(self.redirect_grace_days.saturating_mul(24 * 60 * 60))
+    }
+}
+
+/// The certificate and key to serve TLS with. They are read again on
+/// SIGHUP, so a renewed certificate needn't mean a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    /// PEM certificate chain, the server's certificate first
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+/// How clients are authenticated.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthOptions {
+    /// Let clients without a token read public repositories
+    pub anonymous_read: bool,
+    /// API tokens fixed by the operator; `tokens` in the data directory if
+    /// not given, which need not exist
+    pub tokens_file: Option<PathBuf>,
+    /// SSH keys fixed by the operator; `authorized_keys` in the data
+    /// directory if not given, which need not exist
+    pub authorized_keys_file: Option<PathBuf>,
+}
+
+/// The SSH transport.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshOptions {
+    /// Serve forjj-sync sessions over SSH
+    pub enabled: bool,
+    pub bind: SocketAddr,
+    /// The server's private key; `ssh_host_ed25519_key` in the data
+    /// directory if not given. Generated on first start if missing.
+    pub host_key: Option<PathBuf>,
+}
+
+impl Default for SshOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind: DEFAULT_SSH_BIND
+                .parse()
+                .expect("default SSH bind address is valid"),
+            host_key: None,
+        }
+    }
+}
+
+/// The TCP transport for mirroring.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpOptions {
+    pub bind: SocketAddr,
+    /// Encrypt sessions with Noise
+    #[serde(default)]
+    pub noise: bool,
+}
+
+/// Where metrics are served.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsOptions {
+    /// An admin address to serve metrics on instead of the API's
+    pub bind: Option<SocketAddr>,
+}
+
+/// Caps on what one API request may ask for.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_patch_bytes: usize,
+    pub max_raw_bytes: u64,
+    pub max_blame_lines: usize,
+    /// How long repository statistics are kept before being scanned again
+    pub stats_max_age_secs: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_patch_bytes: DEFAULT_MAX_PATCH_BYTES,
+            max_raw_bytes: DEFAULT_MAX_RAW_BYTES,
+            max_blame_lines: DEFAULT_MAX_BLAME_LINES,
+            stats_max_age_secs: DEFAULT_STATS_MAX_AGE,
+        }
+    }
+}
+
+/// How the server shuts down.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownOptions {
+    /// How long forjj-sync sessions may take to finish their requests
+    /// before they are aborted
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: DEFAULT_DRAIN_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+/// What the readiness probe checks.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthOptions {
+    /// A repository, as `owner/name`, opened by each readiness check
+    pub canary_repo: Option<String>,
+    /// How long opening the canary repository may take
+    pub canary_budget_ms: u64,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        Self {
+            canary_repo: None,
+            canary_budget_ms: DEFAULT_CANARY_BUDGET.as_millis() as u64,
+        }
+    }
+}
+
+impl HealthOptions {
+    /// The canary repository, if one is configured.
+    pub fn canary(&self) -> Result<Option<Canary>> {
+        let Some(repo) = &self.canary_repo else {
+            return Ok(None);
+        };
+        let Some((owner, name)) = repo.split_once('/') else {
+            bail!("canary repository {repo:?} is not owner/name");
+        };
+        validate_name(owner).with_context(|| format!("invalid canary repository {repo:?}"))?;
+        validate_name(name).with_context(|| format!("invalid canary repository {repo:?}"))?;
+        Ok(Some(Canary {
+            owner: owner.to_string(),
+            name: name.to_string(),
+            budget: Duration::from_millis(self.canary_budget_ms),
+        }))
+    }
+}
+
+impl ServerConfig {
+    /// The config in the TOML file at `path`, not yet validated.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// The config in `text`, not yet validated.
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// The directory repositories are kept in.
+    pub fn repos_root(&self) -> PathBuf {
+        match &self.storage.repos_root {
+            Some(repos_root) => repos_root.clone(),
+            None => self.data_dir.join("repos"),
+        }
+    }
+
+    /// The file of API tokens fixed by the operator.
+    pub fn tokens_file(&self) -> PathBuf {
+        match &self.auth.tokens_file {
+            Some(path) => path.clone(),
+            None => self.data_dir.join("tokens"),
+        }
+    }
+
+    /// The file of SSH keys fixed by the operator.
+    pub fn authorized_keys_file(&self) -> PathBuf {
+        match &self.auth.authorized_keys_file {
+            Some(path) => path.clone(),
+            None => self.data_dir.join("authorized_keys"),
+        }
+    }
+
+    /// The SSH host key, which need not exist yet.
+    pub fn ssh_host_key(&self) -> PathBuf {
+        match &self.ssh.host_key {
+            Some(path) => path.clone(),
+            None => self.data_dir.join("ssh_host_ed25519_key"),
+        }
+    }
+
+    /// Check everything that can be without starting the server.
+    pub fn validate(&self) -> Result<()> {
+        check_directory("data directory", &self.data_dir)?;
+        check_directory("repository root", &self.repos_root())?;
+        if let Some(tls) = &self.tls {
+            check_readable("TLS certificate", &tls.cert)?;
+            check_readable("TLS private key", &tls.key)?;
+        }
+        // Files named in the config must exist; the defaults needn't.
+        if let Some(path) = &self.auth.tokens_file {
+            check_readable("tokens file", path)?;
+        }
+        if let Some(path) = &self.auth.authorized_keys_file {
+            check_readable("authorized keys file", path)?;
+        }
+        let mut binds = vec![("API", self.bind)];
+        if self.ssh.enabled {
+            // A host key that exists must be a file; one that doesn't is
+            // generated.
+            let host_key = self.ssh_host_key();
+            if host_key.exists() {
+                check_readable("SSH host key", &host_key)?;
+            }
+            binds.push(("SSH", self.ssh.bind));
+        }
+        if let Some(tcp) = &self.tcp {
+            binds.push(("TCP", tcp.bind));
+        }
+        if let Some(bind) = self.metrics.bind {
+            binds.push(("metrics", bind));
+        }
+        for (i, (name, bind)) in binds.iter().enumerate() {
+            if let Some((other, _)) = binds[..i].iter().find(|(_, other)| other == bind) {
+                bail!("the {other} and {name} listeners are both configured to bind {bind}");
+            }
+        }
+        self.log.validate().context("invalid log config")?;
+        self.health.canary()?;
+        Ok(())
+    }
+}
+
+/// Check that `path`, if it exists, is a directory. Directories that don't
+/// exist yet are made when the server starts.
+fn check_directory(what: &str, path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => bail!("{what} {} is not a directory", path.display()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error).with_context(|| format!("cannot read {what} {}", path.display())),
+    }
+}
+
+/// Check that `path` is a file that can be read.
+fn check_readable(what: &str, path: &Path) -> Result<()> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("cannot read {what} {}", path.display()))?;
+    if !file.metadata()?.is_file() {
+        bail!("{what} {} is not a file", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::LogRotation;
+    use crate::rate_limit::RateLimit;
+
+    #[test]
+    fn test_parse_config() {
+        let config = ServerConfig::parse(
+            r#"
+            bind = "127.0.0.1:8080"
+            data_dir = "/var/lib/forjj"
+            swagger_ui = true
+
+            [storage]
+            soft_delete = true
+            redirect_grace_days = 30
+
+            [auth]
+            anonymous_read = true
+
+            [tcp]
+            bind = "0.0.0.0:4000"
+            noise = true
+
+            [rate_limits.expensive]
+            per_minute = 10
+            burst = 2
+
+            [limits]
+            max_blame_lines = 500
+
+            [log]
+            format = "json"
+            filter = "forjj=info"
+            file = "/var/log/forjj/forjj.log"
+            rotation = "hourly"
+
+            [shutdown]
+            drain_timeout_secs = 120
+
+            [health]
+            canary_repo = "alice/canary"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.repos_root(), PathBuf::from("/var/lib/forjj/repos"));
+        assert_eq!(config.tokens_file(), PathBuf::from("/var/lib/forjj/tokens"));
+        assert!(config.storage.soft_delete);
+        assert_eq!(
+            config.storage.redirect_grace_period(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert!(config.auth.anonymous_read);
+        assert!(config.swagger_ui);
+        assert_eq!(
+            config.tcp,
+            Some(TcpOptions {
+                bind: "0.0.0.0:4000".parse().unwrap(),
+                noise: true,
+            })
+        );
+        assert_eq!(
+            config.rate_limits,
+            RateLimits {
+                expensive: RateLimit {
+                    per_minute: 10,
+                    burst: 2,
+                },
+                ..RateLimits::default()
+            }
+        );
+        assert_eq!(config.limits.max_blame_lines, 500);
+        assert_eq!(config.limits.max_patch_bytes, DEFAULT_MAX_PATCH_BYTES);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.rotation, LogRotation::Hourly);
+        assert_eq!(config.shutdown.drain_timeout_secs, 120);
+        assert_eq!(
+            config.health.canary().unwrap(),
+            Some(Canary {
+                owner: "alice".to_string(),
+                name: "canary".to_string(),
+                budget: DEFAULT_CANARY_BUDGET,
+            })
+        );
+        assert_eq!(config.ssh, SshOptions::default());
+    }
+
+    #[test]
+    fn test_empty_config_is_default() {
+        assert_eq!(ServerConfig::parse("").unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn test_unknown_settings_rejected() {
+        let error = ServerConfig::parse("[auth]\nanonymous_reads = true\n").unwrap_err();
+        assert!(
+            format!("{error:#}").contains("anonymous_reads"),
+            "{error:#}"
+        );
+        assert!(ServerConfig::parse("[log]\nformat = \"yaml\"\n").is_err());
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "bind = \"127.0.0.1:8080\"\ndata_dir = {:?}\n[log]\nformat = \"compact\"\n",
+                dir.path()
+            ),
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "forjj",
+            "serve",
+            "--config",
+            path.to_str().unwrap(),
+            "--bind",
+            "127.0.0.1:9090",
+        ])
+        .unwrap();
+        let Command::Serve(args) = cli.command else {
+            panic!("not serve: {:?}", cli.command);
+        };
+        let config = args.load().unwrap();
+        // The flag wins over the file, which wins over the defaults.
+        assert_eq!(config.bind, "127.0.0.1:9090".parse().unwrap());
+        assert_eq!(config.log.format, LogFormat::Compact);
+        assert_eq!(config.repos_root(), dir.path().join("repos"));
+
+        let cli = Cli::try_parse_from([
+            "forjj",
+            "config",
+            "check",
+            "-c",
+            path.to_str().unwrap(),
+            "--repos-root",
+            "/srv/repos",
+            "--log-format",
+            "json",
+        ])
+        .unwrap();
+        let Command::Config(ConfigCommand::Check(args)) = cli.command else {
+            panic!("not config check: {:?}", cli.command);
+        };
+        let config = args.load().unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.repos_root(), PathBuf::from("/srv/repos"));
+        assert_eq!(config.log.format, LogFormat::Json);
+
+        assert!(Cli::try_parse_from(["forjj", "serve", "--log-format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig {
+            data_dir: dir.path().to_path_buf(),
+            ..ServerConfig::default()
+        };
+        config.validate().unwrap();
+
+        let missing = dir.path().join("missing.pem");
+        config.tls = Some(TlsOptions {
+            cert: missing.clone(),
+            key: missing.clone(),
+        });
+        let error = format!("{:#}", config.validate().unwrap_err());
+        assert!(error.contains("TLS certificate"), "{error}");
+        assert!(error.contains(&missing.display().to_string()), "{error}");
+        config.tls = None;
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        config.storage.repos_root = Some(file);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("not a directory"), "{error}");
+        config.storage.repos_root = None;
+
+        config.metrics.bind = Some(config.bind);
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("API and metrics"), "{error}");
+        config.metrics.bind = None;
+
+        // A disabled listener binds nothing.
+        config.ssh.bind = config.bind;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("API and SSH"), "{error}");
+        config.ssh.enabled = false;
+        config.validate().unwrap();
+
+        config.health.canary_repo = Some("canary".to_string());
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("not owner/name"), "{error}");
+
+        let error = ServerConfig::load(&missing).unwrap_err().to_string();
+        assert!(error.contains("cannot read config file"), "{error}");
+    }
+}
@@ -0,0 +1,184 @@
+//! Read-only git smart HTTP, for repositories with the git backend.
+//!
+//! Plenty of tools, such as CI runners and IDEs, only speak git. For them,
+//! git-backed repositories can be cloned and fetched at
+//! `/<owner>/<name>.git`, served by `git upload-pack` run on the git
+//! repository the jj store keeps its commits in. jj's own refs, under
+//! `refs/jj/`, are hidden from clients. Pushing over git is refused:
+//! changes go through forjj-sync, which keeps the operation log.
+//!
+//! The request body, which only says what the client wants and has, is
+//! read whole; the pack sent back is streamed from `git` as it is made.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{Context, Result, bail};
+use axum::body::Body;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+/// The service clients fetch with.
+pub const UPLOAD_PACK: &str = "git-upload-pack";
+
+/// The service clients push with, which isn't offered.
+pub const RECEIVE_PACK: &str = "git-receive-pack";
+
+/// Most bytes of a request body, once decompressed. Clients negotiating a
+/// fetch list the commits they have, which stays well under this.
+pub const MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+
+/// Content type of a ref advertisement for `service`.
+pub fn advertisement_type(service: &str) -> String {
+    format!("application/x-{service}-advertisement")
+}
+
+/// Content type of the result of `service`.
+pub fn result_type(service: &str) -> String {
+    format!("application/x-{service}-result")
+}
+
+/// Whether the `Git-Protocol` header asks for protocol version 2.
+fn is_v2(protocol: Option<&str>) -> bool {
+    protocol.is_some_and(|protocol| protocol.split(':').any(|param| param == "version=2"))
+}
+
+/// `data` as a pkt-line.
+fn pkt_line(data: &str) -> Vec<u8> {
+    let mut line = format!("{:04x}", data.len() + 4).into_bytes();
+    line.extend_from_slice(data.as_bytes());
+    line
+}
+
+/// The request body, decompressed if `encoding` says it is gzipped, as
+/// git does for large negotiations.
+pub fn decode_request(body: Bytes, encoding: Option<&str>) -> Result<Bytes> {
+    let body = match encoding {
+        None | Some("identity") => body,
+        Some("gzip" | "x-gzip") => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(MAX_REQUEST_BYTES as u64 + 1)
+                .read_to_end(&mut decoded)
+                .context("invalid gzip request body")?;
+            Bytes::from(decoded)
+        }
+        Some(other) => bail!("unsupported content encoding {other:?}"),
+    };
+    if body.len() > MAX_REQUEST_BYTES {
+        bail!("request body is over {MAX_REQUEST_BYTES} bytes");
+    }
+    Ok(body)
+}
+
+/// Run `git upload-pack` on `git_dir`, returning what it writes as a body.
+///
+/// With `advertise`, this is the answer to `info/refs`: the refs the
+/// repository has, after the service line that version 0 and 1 clients
+/// expect. Otherwise `input` is the client's request. `protocol` is the
+/// client's `Git-Protocol` header, passed on so version 2 clients are
+/// answered in kind.
+pub async fn upload_pack(
+    git_dir: &Path,
+    advertise: bool,
+    protocol: Option<&str>,
+    input: Bytes,
+) -> Result<Body> {
+    let mut command = Command::new("git");
+    command
+        .args(["-c", "uploadpack.hideRefs=refs/jj/", "upload-pack"])
+        .arg("--stateless-rpc");
+    if advertise {
+        command.arg("--advertise-refs");
+    }
+    command
+        .arg(git_dir)
+        .stdin(if advertise {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(protocol) = protocol {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn().context("failed to run git upload-pack")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // Written from a task of its own, so git can answer while reading.
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    let stdout = child
+        .stdout
+        .take()
+        .context("git upload-pack has no stdout")?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("git upload-pack has no stderr")?;
+    let git_dir = git_dir.to_path_buf();
+    tokio::spawn(async move {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors).await;
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!(
+                git_dir = %git_dir.display(),
+                "git upload-pack failed ({status}): {}",
+                errors.trim()
+            ),
+            Err(error) => warn!("failed to wait for git upload-pack: {error}"),
+        }
+    });
+
+    // Version 2 clients expect the capabilities straight away.
+    let mut prefix = Vec::new();
+    if advertise && !is_v2(protocol) {
+        prefix = pkt_line(&format!("# service={UPLOAD_PACK}\n"));
+        prefix.extend_from_slice(b"0000");
+    }
+    let output = AsyncReadExt::chain(std::io::Cursor::new(prefix), stdout);
+    Ok(Body::from_stream(ReaderStream::new(output)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_pkt_line() {
+        assert_eq!(
+            pkt_line("# service=git-upload-pack\n"),
+            b"001e# service=git-upload-pack\n"
+        );
+        assert!(is_v2(Some("version=2")));
+        assert!(is_v2(Some("object-format=sha1:version=2")));
+        assert!(!is_v2(Some("version=1")));
+        assert!(!is_v2(None));
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let body = Bytes::from_static(b"0032want 0000000000000000000000000000000000000000\n");
+        let decoded = decode_request(body.clone(), None).unwrap();
+        assert_eq!(decoded, body);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&body).unwrap();
+        let gzipped = Bytes::from(encoder.finish().unwrap());
+        assert_eq!(decode_request(gzipped, Some("gzip")).unwrap(), body);
+
+        assert!(decode_request(body.clone(), Some("br")).is_err());
+        assert!(decode_request(body, Some("gzip")).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Whether the server can serve, for load balancers and orchestrators.
+//!
+//! Liveness only says the process answers. Readiness checks the storage
+//! the server needs: that the repository root can be written to, that the
+//! state directory's files can be read, and, if one is configured, that a
+//! canary repository opens quickly enough. Each check touches the disk,
+//! so the result is kept for [`CACHE_TTL`], and probes arriving while a
+//! check runs wait for its result rather than starting their own.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use forjj_storage::RepositoryManager;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::state::StateStore;
+
+/// How long a readiness result is answered before the checks run again.
+pub const CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// How long the canary repository may take to open, unless configured.
+pub const DEFAULT_CANARY_BUDGET: Duration = Duration::from_millis(500);
+
+/// A repository opened on each check, to see that storage answers in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canary {
+    pub owner: String,
+    pub name: String,
+    /// Longest opening it may take before the server isn't ready
+    pub budget: Duration,
+}
+
+/// The outcome of one check.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CheckResult {
+    /// Which check, such as `repos_root`
+    pub name: &'static str,
+    pub ok: bool,
+    /// Why the check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How long the check took
+    pub duration_ms: u64,
+}
+
+/// Whether the server is ready, and the checks that say so.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReadinessReport {
+    /// `ready`, or `not ready` if any check failed
+    pub status: &'static str,
+    pub checks: Vec<CheckResult>,
+}
+
+impl ReadinessReport {
+    /// Whether every check passed.
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|check| check.ok)
+    }
+}
+
+/// Runs the readiness checks, keeping their result for a while.
+pub struct Readiness {
+    repos: Arc<RepositoryManager>,
+    repos_root: PathBuf,
+    state: Arc<StateStore>,
+    canary: Option<Canary>,
+    /// The last report and when it was made
+    cached: Mutex<Option<(Instant, Arc<ReadinessReport>)>>,
+}
+
+impl Readiness {
+    /// Checks of `repos`, kept in `repos_root`, and of `state`.
+    pub fn new(
+        repos: Arc<RepositoryManager>,
+        repos_root: PathBuf,
+        state: Arc<StateStore>,
+        canary: Option<Canary>,
+    ) -> Self {
+        Self {
+            repos,
+            repos_root,
+            state,
+            canary,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The result of the checks, run again if the last is older than
+    /// [`CACHE_TTL`].
+    pub async fn report(self: &Arc<Self>) -> Arc<ReadinessReport> {
+        let mut cached = self.cached.lock().await;
+        if let Some((made, report)) = &*cached
+            && made.elapsed() < CACHE_TTL
+        {
+            return report.clone();
+        }
+        let readiness = self.clone();
+        let report = match tokio::task::spawn_blocking(move || readiness.check()).await {
+            Ok(report) => report,
+            Err(error) => ReadinessReport {
+                status: "not ready",
+                checks: vec![CheckResult {
+                    name: "checks",
+                    ok: false,
+                    error: Some(format!("checks failed to run: {error}")),
+                    duration_ms: 0,
+                }],
+            },
+        };
+        let report = Arc::new(report);
+        *cached = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    /// Run every check.
+    fn check(&self) -> ReadinessReport {
+        let mut checks = vec![
+            run("repos_root", || self.check_repos_root()),
+            run("state", || self.state.check()),
+        ];
+        if let Some(canary) = &self.canary {
+            checks.push(run("canary", || self.check_canary(canary)));
+        }
+        let status = if checks.iter().all(|check| check.ok) {
+            "ready"
+        } else {
+            "not ready"
+        };
+        ReadinessReport { status, checks }
+    }
+
+    /// Make and remove a file in the repository root. Its name starts
+    /// with `.`, so no owner can have it.
+    fn check_repos_root(&self) -> Result<()> {
+        let probe = self
+            .repos_root
+            .join(format!(".ready-probe-{}", std::process::id()));
+        std::fs::write(&probe, b"")
+            .with_context(|| format!("cannot write to {}", self.repos_root.display()))?;
+        std::fs::remove_file(&probe)
+            .with_context(|| format!("cannot remove {}", probe.display()))?;
+        Ok(())
+    }
+
+    fn check_canary(&self, canary: &Canary) -> Result<()> {
+        let started = Instant::now();
+        self.repos
+            .open_repo(&canary.owner, &canary.name)
+            .with_context(|| format!("cannot open {}/{}", canary.owner, canary.name))?;
+        let elapsed = started.elapsed();
+        if elapsed > canary.budget {
+            bail!(
+                "opening {}/{} took {}ms, over the budget of {}ms",
+                canary.owner,
+                canary.name,
+                elapsed.as_millis(),
+                canary.budget.as_millis()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Run the check `name`, timing it.
+fn run(name: &'static str, check: impl FnOnce() -> Result<()>) -> CheckResult {
+    let started = Instant::now();
+    let outcome = check();
+    CheckResult {
+        name,
+        ok: outcome.is_ok(),
+        error: outcome.err().map(|error| format!("{error:#}")),
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forjj_storage::StorageConfig;
+    use tempfile::TempDir;
+
+    fn checks(dir: &TempDir, canary: Option<Canary>) -> Arc<Readiness> {
+        let repos_root = dir.path().join("repos");
+        let repos = Arc::new(
+            RepositoryManager::new(StorageConfig {
+                repos_root: repos_root.clone(),
+            })
+            .unwrap(),
+        );
+        repos.create_repo("alice", "canary").unwrap();
+        let state = Arc::new(StateStore::open(&dir.path().join("state")).unwrap());
+        Arc::new(Readiness::new(repos, repos_root, state, canary))
+    }
+
+    fn failed(report: &ReadinessReport) -> Vec<&'static str> {
+        report
+            .checks
+            .iter()
+            .filter(|check| !check.ok)
+            .map(|check| check.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let dir = TempDir::new().unwrap();
+        let canary = Canary {
+            owner: "alice".to_string(),
+            name: "canary".to_string(),
+            budget: Duration::from_secs(60),
+        };
+        let report = checks(&dir, Some(canary)).report().await;
+        assert!(report.is_ready(), "{report:?}");
+        assert_eq!(report.status, "ready");
+        let names: Vec<_> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["repos_root", "state", "canary"]);
+        // The probe file is gone.
+        let probes = std::fs::read_dir(dir.path().join("repos"))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with(".ready-probe")
+            })
+            .count();
+        assert_eq!(probes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_repos_root() {
+        let dir = TempDir::new().unwrap();
+        let readiness = checks(&dir, None);
+        // Permissions don't stop root, so take the probe's name instead.
+        let probe = dir
+            .path()
+            .join(format!("repos/.ready-probe-{}", std::process::id()));
+        std::fs::create_dir(&probe).unwrap();
+
+        let report = readiness.report().await;
+        assert_eq!(report.status, "not ready");
+        assert_eq!(failed(&report), ["repos_root"]);
+        let error = report.checks[0].error.as_deref().unwrap();
+        assert!(error.contains("cannot write to"), "{error}");
+
+        // The failure is answered until the cache expires.
+        std::fs::remove_dir(&probe).unwrap();
+        assert!(!readiness.report().await.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_missing_state_store() {
+        let dir = TempDir::new().unwrap();
+        let readiness = checks(&dir, None);
+        std::fs::remove_dir_all(dir.path().join("state")).unwrap();
+        let report = readiness.report().await;
+        assert_eq!(failed(&report), ["state"]);
+
+        // A state file that no longer parses fails the check too.
+        let dir = TempDir::new().unwrap();
+        let readiness = checks(&dir, None);
+        std::fs::write(dir.path().join("state/users.json"), "{").unwrap();
+        let report = readiness.report().await;
+        assert_eq!(failed(&report), ["state"]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_canary() {
+        let dir = TempDir::new().unwrap();
+        let canary = Canary {
+            owner: "alice".to_string(),
+            name: "missing".to_string(),
+            budget: DEFAULT_CANARY_BUDGET,
+        };
+        let report = checks(&dir, Some(canary)).report().await;
+        assert_eq!(failed(&report), ["canary"]);
+    }
+}
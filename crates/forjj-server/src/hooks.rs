@@ -0,0 +1,709 @@
+//! Webhooks: requests the server makes to other services when bookmarks
+//! move, so that CI and the like hear of pushes.
+//!
+//! Each repository lists its hooks in a sidecar file. When a push or an API
+//! request moves bookmarks, [`HookDispatcher::bookmarks_moved`] builds one
+//! JSON payload and posts it to each hook that wants the event, in the
+//! background. The payload is signed with the hook's secret: the
+//! [`SIGNATURE_HEADER`] is `sha256=` followed by the hex HMAC-SHA256 of the
+//! body. A delivery the hook doesn't answer with a success is tried again,
+//! waiting twice as long each time, up to [`RetryPolicy::attempts`] in all.
+//! The last attempts for each hook are kept in memory for the API to show.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use forjj_protocol::BookmarksMoved;
+use forjj_storage::{
+    BookmarkTarget, CommitId, CommitSummary, OperationId, RepoInfo, Repository, RepositoryManager,
+};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, HOST, USER_AGENT};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::metadata::unix_now;
+
+/// Sidecar file a repository's hooks are kept in.
+pub const HOOKS_FILE: &str = "hooks.json";
+
+/// Header naming the event a delivery is for.
+pub const EVENT_HEADER: &str = "x-forjj-event";
+
+/// Header with the ID of a delivery, the same for each attempt at it.
+pub const DELIVERY_HEADER: &str = "x-forjj-delivery";
+
+/// Header with the signature of a delivery's body.
+pub const SIGNATURE_HEADER: &str = "x-forjj-signature-256";
+
+/// How many attempts are kept for each hook.
+const DELIVERIES_KEPT: usize = 20;
+
+/// Most commits a payload lists.
+const MAX_PAYLOAD_COMMITS: usize = 20;
+
+/// How long a hook has to answer one attempt.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What moved bookmarks, for hooks to choose which they hear of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    /// A forjj-sync push
+    Push,
+    /// A request to the API to move or delete a bookmark
+    Bookmark,
+}
+
+impl HookEvent {
+    /// Every event, which hooks hear of unless they choose.
+    pub const ALL: [HookEvent; 2] = [HookEvent::Push, HookEvent::Bookmark];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::Push => "push",
+            HookEvent::Bookmark => "bookmark",
+        }
+    }
+}
+
+/// A hook of a repository.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    pub id: String,
+    /// Where payloads are posted
+    pub url: String,
+    /// Key the payloads are signed with
+    pub secret: String,
+    /// Which events are posted
+    pub events: Vec<HookEvent>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+}
+
+impl Hook {
+    /// A hook made now.
+    pub fn new(url: String, secret: String, events: Vec<HookEvent>) -> Self {
+        Self {
+            id: format!("{:016x}", rand::random::<u64>()),
+            url,
+            secret,
+            events,
+            created_at: unix_now(),
+        }
+    }
+
+    /// The hook as the API shows it, without its secret.
+    pub fn info(&self) -> HookInfo {
+        HookInfo {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            events: self.events.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// A hook as the API shows it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct HookInfo {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<HookEvent>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+}
+
+/// Check that `url` is one payloads can be posted to, returning why not if
+/// it isn't.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|error| format!("invalid URL: {error}"))?;
+    if uri.scheme_str() != Some("http") {
+        return Err("must be an http:// URL".to_string());
+    }
+    match uri.authority() {
+        Some(authority) if !authority.host().is_empty() => Ok(()),
+        _ => Err("must name a host".to_string()),
+    }
+}
+
+/// The hooks of a listed repository, read without opening it.
+pub fn load_hooks(info: &RepoInfo) -> Result<Vec<Hook>> {
+    let Some(data) = info.read_sidecar(HOOKS_FILE)? else {
+        return Ok(Vec::new());
+    };
+    serde_json::from_slice(&data)
+        .with_context(|| format!("invalid {HOOKS_FILE} in {}", info.path.display()))
+}
+
+/// Add `hook` to the hooks of `repo`.
+pub fn add_hook(repo: &Repository, hook: Hook) -> Result<()> {
+    repo.update_sidecar(HOOKS_FILE, |data| {
+        let mut hooks: Vec<Hook> = match data {
+            Some(data) => {
+                serde_json::from_slice(&data).with_context(|| format!("invalid {HOOKS_FILE}"))?
+            }
+            None => Vec::new(),
+        };
+        hooks.push(hook);
+        Ok(serde_json::to_vec(&hooks)?)
+    })
+}
+
+/// Remove the hook `id` from `repo`, returning `false` if it has no such
+/// hook.
+pub fn remove_hook(repo: &Repository, id: &str) -> Result<bool> {
+    let mut removed = false;
+    repo.update_sidecar(HOOKS_FILE, |data| {
+        let mut hooks: Vec<Hook> = match data {
+            Some(data) => {
+                serde_json::from_slice(&data).with_context(|| format!("invalid {HOOKS_FILE}"))?
+            }
+            None => Vec::new(),
+        };
+        let before = hooks.len();
+        hooks.retain(|hook| hook.id != id);
+        removed = hooks.len() < before;
+        Ok(serde_json::to_vec(&hooks)?)
+    })?;
+    Ok(removed)
+}
+
+/// The signature of `body` with `secret`, as sent in the
+/// [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// What is posted to hooks.
+#[derive(Debug, Serialize)]
+pub struct Payload {
+    pub event: HookEvent,
+    /// `owner/name`
+    pub repository: String,
+    /// Who moved the bookmarks, if they authenticated
+    pub sender: Option<String>,
+    /// The operation that moved them
+    pub operation_id: OperationId,
+    pub bookmarks: Vec<PayloadBookmark>,
+    /// Commits the bookmarks moved onto, newest first; see [`commits_moved`]
+    pub commits: Vec<PayloadCommit>,
+}
+
+/// A bookmark in a [`Payload`].
+#[derive(Debug, Serialize)]
+pub struct PayloadBookmark {
+    pub name: String,
+    /// Where it was, or null if it didn't exist or was conflicted
+    pub old: Option<CommitId>,
+    /// Where it is, or null if it was deleted
+    pub new: Option<CommitId>,
+}
+
+/// A commit in a [`Payload`].
+#[derive(Debug, Serialize)]
+pub struct PayloadCommit {
+    pub id: CommitId,
+    /// In jj's reverse-hex form
+    pub change_id: String,
+    pub author_name: String,
+    pub author_email: String,
+    /// When the commit was authored, in seconds since the Unix epoch
+    pub timestamp: i64,
+    /// The full description
+    pub description: String,
+}
+
+impl From<CommitSummary> for PayloadCommit {
+    fn from(summary: CommitSummary) -> Self {
+        Self {
+            id: summary.id,
+            change_id: summary.change_id.to_reverse_hex(),
+            author_name: summary.author_name,
+            author_email: summary.author_email,
+            timestamp: summary.author_time.div_euclid(1000),
+            description: summary.description,
+        }
+    }
+}
+
+/// The commits `moved` put bookmarks on, up to [`MAX_PAYLOAD_COMMITS`].
+///
+/// A bookmark that moved brings the commits between where it was and where
+/// it is; one that was made, or was conflicted, just the commit it points
+/// at.
+pub fn commits_moved(repo: &Repository, moved: &BookmarksMoved) -> Result<Vec<PayloadCommit>> {
+    let mut seen = HashSet::new();
+    let mut commits = Vec::new();
+    for update in &moved.updates {
+        let Some(new) = &update.target else {
+            continue;
+        };
+        let ids = match &update.expected {
+            BookmarkTarget::Normal(old) => repo.commits_between(old, new)?,
+            _ => vec![*new],
+        };
+        for id in ids {
+            if commits.len() == MAX_PAYLOAD_COMMITS {
+                return Ok(commits);
+            }
+            if seen.insert(id) {
+                commits.push(repo.commit_summary(&id)?.into());
+            }
+        }
+    }
+    Ok(commits)
+}
+
+/// How deliveries that fail are tried again.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Most attempts at one delivery, including the first
+    pub attempts: u32,
+    /// How long to wait before the second attempt; each wait after is
+    /// twice the one before
+    pub first_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            first_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How an attempt at a delivery went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// The hook answered with a success
+    Delivered,
+    /// The hook answered with an error, or didn't answer
+    Failed,
+}
+
+/// An attempt at delivering a payload to a hook.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Delivery {
+    /// Sent in the [`DELIVERY_HEADER`]; the same for each attempt
+    pub id: String,
+    pub event: HookEvent,
+    /// Counting from 1
+    pub attempt: u32,
+    pub status: DeliveryStatus,
+    /// The status code the hook answered with, if it answered
+    pub response_code: Option<u16>,
+    /// Why the hook didn't answer, if it didn't
+    pub error: Option<String>,
+    /// Seconds since the Unix epoch
+    pub delivered_at: u64,
+}
+
+/// Posts payloads to hooks when bookmarks move.
+pub struct HookDispatcher {
+    repos: Arc<RepositoryManager>,
+    retry: RetryPolicy,
+    /// Deliveries are made on this, whichever thread moved the bookmarks
+    runtime: Handle,
+    /// The last attempts for each hook, newest first
+    deliveries: Mutex<HashMap<String, VecDeque<Delivery>>>,
+    /// Payloads being prepared and delivered
+    tasks: TaskTracker,
+}
+
+impl HookDispatcher {
+    /// A dispatcher for the hooks of `repos`, delivering on the current
+    /// Tokio runtime.
+    pub fn new(repos: Arc<RepositoryManager>, retry: RetryPolicy) -> Self {
+        Self {
+            repos,
+            retry,
+            runtime: Handle::current(),
+            deliveries: Mutex::new(HashMap::new()),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Post `moved` to the hooks of its repository that want `event`.
+    ///
+    /// Returns at once: the payload is built and delivered in the
+    /// background, and failures are logged rather than returned.
+    pub fn bookmarks_moved(self: &Arc<Self>, event: HookEvent, moved: BookmarksMoved) {
+        let dispatcher = self.clone();
+        self.tasks.spawn_on(
+            async move {
+                let repos = dispatcher.repos.clone();
+                let repo = moved.repo.clone();
+                let prepared =
+                    tokio::task::spawn_blocking(move || prepare(&repos, event, &moved)).await;
+                let (hooks, body) = match prepared {
+                    Ok(Ok(Some(prepared))) => prepared,
+                    Ok(Ok(None)) => return,
+                    Ok(Err(error)) => {
+                        warn!(%repo, "failed to prepare hook payload: {error:#}");
+                        return;
+                    }
+                    Err(error) => {
+                        warn!(%repo, "failed to prepare hook payload: {error}");
+                        return;
+                    }
+                };
+                for hook in hooks {
+                    let dispatcher = dispatcher.clone();
+                    let body = body.clone();
+                    dispatcher
+                        .tasks
+                        .spawn(async move { dispatcher.deliver(hook, event, body).await });
+                }
+            },
+            &self.runtime,
+        );
+    }
+
+    /// Wait up to `timeout` for the deliveries in progress, and their
+    /// retries, to finish, as when the server shuts down. Returns whether
+    /// they all did.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.tasks.close();
+        let flushed = tokio::time::timeout(timeout, self.tasks.wait()).await;
+        self.tasks.reopen();
+        flushed.is_ok()
+    }
+
+    /// The last attempts at delivering to the hook `id`, newest first.
+    pub fn deliveries(&self, id: &str) -> Vec<Delivery> {
+        let deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        deliveries
+            .get(id)
+            .map(|attempts| attempts.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the attempts at delivering to the hook `id`, once it is
+    /// removed.
+    pub fn forget(&self, id: &str) {
+        self.deliveries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(id);
+    }
+
+    fn record(&self, hook: &str, delivery: Delivery) {
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let attempts = deliveries.entry(hook.to_string()).or_default();
+        attempts.push_front(delivery);
+        attempts.truncate(DELIVERIES_KEPT);
+    }
+
+    /// Post `body` to `hook`, trying again as the retry policy allows.
+    async fn deliver(&self, hook: Hook, event: HookEvent, body: Bytes) {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let signature = sign(&hook.secret, &body);
+        let headers = [
+            (EVENT_HEADER, event.as_str().to_string()),
+            (DELIVERY_HEADER, id.clone()),
+            (SIGNATURE_HEADER, signature),
+        ];
+        let mut delay = self.retry.first_delay;
+        for attempt in 1..=self.retry.attempts {
+            let posted =
+                tokio::time::timeout(DELIVERY_TIMEOUT, post(&hook.url, &headers, body.clone()))
+                    .await;
+            let (response_code, error) = match posted {
+                Ok(Ok(status)) => (Some(status), None),
+                Ok(Err(error)) => (None, Some(format!("{error:#}"))),
+                Err(_) => (None, Some("timed out".to_string())),
+            };
+            let delivered = response_code.is_some_and(|status| status.is_success());
+            self.record(
+                &hook.id,
+                Delivery {
+                    id: id.clone(),
+                    event,
+                    attempt,
+                    status: if delivered {
+                        DeliveryStatus::Delivered
+                    } else {
+                        DeliveryStatus::Failed
+                    },
+                    response_code: response_code.map(|status| status.as_u16()),
+                    error,
+                    delivered_at: unix_now(),
+                },
+            );
+            if delivered {
+                info!(hook = %hook.id, delivery = %id, attempt, "delivered hook");
+                return;
+            }
+            if attempt < self.retry.attempts {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        warn!(
+            hook = %hook.id,
+            delivery = %id,
+            url = %hook.url,
+            "gave up delivering hook after {} attempts",
+            self.retry.attempts
+        );
+    }
+}
+
+/// The hooks of `moved`'s repository that want `event`, and the payload to
+/// post them, or `None` if none do.
+fn prepare(
+    repos: &RepositoryManager,
+    event: HookEvent,
+    moved: &BookmarksMoved,
+) -> Result<Option<(Vec<Hook>, Bytes)>> {
+    let info = repos.repo_info(&moved.repo.owner, &moved.repo.name)?;
+    let mut hooks = load_hooks(&info)?;
+    hooks.retain(|hook| hook.events.contains(&event));
+    if hooks.is_empty() {
+        return Ok(None);
+    }
+    let repo = repos.open_repo(&moved.repo.owner, &moved.repo.name)?;
+    let payload = Payload {
+        event,
+        repository: moved.repo.to_string(),
+        sender: moved.identity.clone(),
+        operation_id: moved.operation,
+        bookmarks: moved
+            .updates
+            .iter()
+            .map(|update| PayloadBookmark {
+                name: update.name.clone(),
+                old: match &update.expected {
+                    BookmarkTarget::Normal(old) => Some(*old),
+                    _ => None,
+                },
+                new: update.target,
+            })
+            .collect(),
+        commits: commits_moved(&repo, moved)?,
+    };
+    Ok(Some((hooks, Bytes::from(serde_json::to_vec(&payload)?))))
+}
+
+/// Post `body` to `url` with `headers`, returning the status answered.
+async fn post(url: &str, headers: &[(&str, String)], body: Bytes) -> Result<StatusCode> {
+    let uri: Uri = url.parse()?;
+    let Some(authority) = uri.authority() else {
+        bail!("{url} names no host");
+    };
+    let address = match authority.port_u16() {
+        Some(port) => format!("{}:{port}", authority.host()),
+        None => format!("{}:80", authority.host()),
+    };
+    let stream = TcpStream::connect(&address)
+        .await
+        .with_context(|| format!("failed to connect to {address}"))?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    let path = uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let mut request = Request::post(path)
+        .header(HOST, authority.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .header(USER_AGENT, concat!("forjj/", env!("CARGO_PKG_VERSION")));
+    for (name, value) in headers {
+        request = request.header(*name, value);
+    }
+    let response = sender.send_request(request.body(Full::new(body))?).await?;
+    Ok(response.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::http::HeaderMap;
+    use axum::routing::post as post_route;
+    use forjj_protocol::RepoRef;
+    use forjj_storage::{BookmarkUpdate, StorageConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    /// A request a [`catcher`] received.
+    struct Caught {
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    /// Serve on a local port, answering the first `failures` requests with
+    /// 500 and the rest with 200, and sending each request on the channel.
+    async fn catcher(failures: usize) -> (String, mpsc::UnboundedReceiver<Caught>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/hook",
+            post_route(move |headers: HeaderMap, body: Bytes| {
+                let sender = sender.clone();
+                let seen = seen.clone();
+                async move {
+                    sender.send(Caught { headers, body }).unwrap();
+                    if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::OK
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{address}/hook"), receiver)
+    }
+
+    async fn next(receiver: &mut mpsc::UnboundedReceiver<Caught>) -> Caught {
+        tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .expect("hook was not delivered")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://ci.example.com/hook").is_ok());
+        assert!(validate_url("http://127.0.0.1:8080").is_ok());
+        assert!(validate_url("https://ci.example.com/hook").is_err());
+        assert!(validate_url("ftp://ci.example.com").is_err());
+        assert!(validate_url("/hook").is_err());
+        assert!(validate_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_push() {
+        let dir = TempDir::new().unwrap();
+        let repos = Arc::new(
+            RepositoryManager::new(StorageConfig {
+                repos_root: dir.path().to_path_buf(),
+            })
+            .unwrap(),
+        );
+        let mut repo = repos.create_repo("alice", "project").unwrap();
+        let base = repo
+            .write_commit(&[], &[("file", b"base")], "base")
+            .await
+            .unwrap();
+        let tip = repo
+            .write_commit(&[base], &[("file", b"tip")], "tip\n\nin detail")
+            .await
+            .unwrap();
+        let operation = repo
+            .set_bookmarks(&[("main".to_string(), Some(tip))], "push")
+            .unwrap();
+
+        // The first attempt fails, and the second is delivered.
+        let (url, mut caught) = catcher(1).await;
+        let hook = Hook::new(url, "s3cret".to_string(), vec![HookEvent::Push]);
+        add_hook(&repo, hook.clone()).unwrap();
+        let ignored = Hook::new(
+            "http://127.0.0.1:1/".to_string(),
+            "other".to_string(),
+            vec![HookEvent::Bookmark],
+        );
+        add_hook(&repo, ignored.clone()).unwrap();
+        assert_eq!(load_hooks(repo.info()).unwrap().len(), 2);
+
+        let dispatcher = Arc::new(HookDispatcher::new(
+            repos.clone(),
+            RetryPolicy {
+                attempts: 3,
+                first_delay: Duration::from_millis(10),
+            },
+        ));
+        dispatcher.bookmarks_moved(
+            HookEvent::Push,
+            BookmarksMoved {
+                repo: RepoRef::new("alice", "project"),
+                identity: Some("alice".to_string()),
+                operation,
+                updates: vec![BookmarkUpdate {
+                    name: "main".to_string(),
+                    expected: BookmarkTarget::Normal(base),
+                    target: Some(tip),
+                }],
+            },
+        );
+
+        let first = next(&mut caught).await;
+        let second = next(&mut caught).await;
+        assert_eq!(first.body, second.body);
+        assert_eq!(
+            first.headers[DELIVERY_HEADER],
+            second.headers[DELIVERY_HEADER]
+        );
+        assert_eq!(second.headers[EVENT_HEADER], "push");
+        assert_eq!(
+            second.headers[SIGNATURE_HEADER],
+            sign("s3cret", &second.body).as_str()
+        );
+        let payload: serde_json::Value = serde_json::from_slice(&second.body).unwrap();
+        assert_eq!(payload["event"], "push");
+        assert_eq!(payload["repository"], "alice/project");
+        assert_eq!(payload["sender"], "alice");
+        assert_eq!(payload["operation_id"], operation.to_string());
+        assert_eq!(payload["bookmarks"][0]["name"], "main");
+        assert_eq!(payload["bookmarks"][0]["old"], base.to_string());
+        assert_eq!(payload["bookmarks"][0]["new"], tip.to_string());
+        // Only the commit the bookmark moved onto
+        let commits = payload["commits"].as_array().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0]["id"], tip.to_string());
+        assert_eq!(commits[0]["description"], "tip\n\nin detail");
+
+        // Recording the attempt may lag the answer a little.
+        assert!(dispatcher.flush(Duration::from_secs(10)).await);
+        let deliveries = dispatcher.deliveries(&hook.id);
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[0].attempt, 2);
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+        assert_eq!(deliveries[0].response_code, Some(200));
+        assert_eq!(deliveries[1].status, DeliveryStatus::Failed);
+        assert_eq!(deliveries[1].response_code, Some(500));
+        assert!(dispatcher.deliveries(&ignored.id).is_empty());
+
+        assert!(remove_hook(&repo, &hook.id).unwrap());
+        assert!(!remove_hook(&repo, &hook.id).unwrap());
+        assert_eq!(load_hooks(repo.info()).unwrap(), [ignored]);
+    }
+}
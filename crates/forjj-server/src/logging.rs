@@ -0,0 +1,275 @@
+//! How the server logs: in which format, at which levels, and where to.
+//!
+//! Logs are human-readable by default. The JSON format writes one object
+//! per line, for log aggregators: the fields of every span an event is in,
+//! such as a request's ID and repository, are top-level fields alongside
+//! the event's own, so a query needn't know how spans nest. Spans log their
+//! timing as they close, as `time.busy` and `time.idle`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::fmt::format::{FmtSpan, JsonFields, Writer};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormattedFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// What is logged if neither the config nor `RUST_LOG` says.
+pub const DEFAULT_FILTER: &str = "forjj=debug,tower_http=debug";
+
+/// How each event is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A line per event, with the fields of the spans it is in
+    #[default]
+    Pretty,
+    /// An object per line, with span fields at the top level
+    Json,
+    /// A shorter line per event, with only the span fields
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            "compact" => Ok(LogFormat::Compact),
+            _ => bail!("unknown log format {format:?}; expected pretty, json or compact"),
+        }
+    }
+}
+
+/// How often a log file is started afresh.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(rotation: &str) -> Result<Self> {
+        match rotation {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "never" => Ok(LogRotation::Never),
+            _ => bail!("unknown log rotation {rotation:?}; expected hourly, daily or never"),
+        }
+    }
+}
+
+/// Where logs go, in what format, and which are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Filter directives, such as `forjj=info`; `RUST_LOG` if not given,
+    /// else [`DEFAULT_FILTER`]
+    pub filter: Option<String>,
+    /// A file to log to instead of stdout. Rotated files have the date,
+    /// and hour if hourly, appended to the name.
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+}
+
+impl LogConfig {
+    /// Check the filter parses and the log file's directory exists, so
+    /// mistakes are found before the server starts.
+    pub fn validate(&self) -> Result<()> {
+        self.env_filter()?;
+        if let Some(path) = &self.file {
+            if path.file_name().is_none() {
+                bail!("log file {} has no file name", path.display());
+            }
+            let directory = log_directory(path);
+            if !directory.is_dir() {
+                bail!(
+                    "log file {} is in {}, which is not a directory",
+                    path.display(),
+                    directory.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn env_filter(&self) -> Result<EnvFilter> {
+        match &self.filter {
+            Some(directives) => EnvFilter::try_new(directives)
+                .with_context(|| format!("invalid log filter {directives:?}")),
+            None => Ok(EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))),
+        }
+    }
+}
+
+/// The directory a log file is in.
+fn log_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Start logging as `config` says.
+///
+/// Logging to a file is done on a thread of its own; the guard returned
+/// must be held until the server exits, or the last events may be lost.
+pub fn init(config: &LogConfig) -> Result<Option<WorkerGuard>> {
+    let filter = config.env_filter()?;
+    let (layer, guard) = match &config.file {
+        Some(path) => {
+            let directory = log_directory(path);
+            let Some(name) = path.file_name() else {
+                bail!("log file {} has no file name", path.display());
+            };
+            let rotation = match config.rotation {
+                LogRotation::Hourly => tracing_appender::rolling::Rotation::HOURLY,
+                LogRotation::Daily => tracing_appender::rolling::Rotation::DAILY,
+                LogRotation::Never => tracing_appender::rolling::Rotation::NEVER,
+            };
+            let appender =
+                tracing_appender::rolling::RollingFileAppender::new(rotation, directory, name);
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (fmt_layer(config.format, writer, false), Some(guard))
+        }
+        None => (fmt_layer(config.format, std::io::stdout, true), None),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()?;
+    Ok(guard)
+}
+
+/// A layer writing events to `writer` in `format`, coloured if `ansi` and
+/// the format is one for people.
+pub fn fmt_layer<S, W>(
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        // Closing spans logs their timing, so slow storage calls stand out.
+        .with_span_events(FmtSpan::CLOSE);
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer
+            .with_ansi(false)
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson)
+            .boxed(),
+    }
+}
+
+/// Writes each event as a JSON object holding the event's fields and those
+/// of the spans it is in. Inner spans' fields win over outer ones', and
+/// the event's over any span's.
+struct FlatJson;
+
+impl<S> FormatEvent<S, JsonFields> for FlatJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        context: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let mut fields = Map::new();
+        fields.insert("timestamp".to_string(), timestamp.into());
+        fields.insert("level".to_string(), metadata.level().as_str().into());
+        fields.insert("target".to_string(), metadata.target().into());
+        if let Some(scope) = context.event_scope() {
+            let mut names = Vec::new();
+            for span in scope.from_root() {
+                names.push(span.name());
+                let extensions = span.extensions();
+                // The JSON field formatter records spans' fields as objects.
+                let recorded = extensions
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|recorded| serde_json::from_str::<Value>(recorded).ok());
+                if let Some(Value::Object(span_fields)) = recorded {
+                    fields.extend(span_fields);
+                }
+            }
+            fields.insert("spans".to_string(), names.join(":").into());
+        }
+        event.record(&mut JsonVisitor(&mut fields));
+        writeln!(writer, "{}", Value::Object(fields))
+    }
+}
+
+/// Records an event's fields into a JSON object.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("compact".parse::<LogFormat>().unwrap(), LogFormat::Compact);
+        assert!("yaml".parse::<LogFormat>().is_err());
+        assert_eq!(
+            "hourly".parse::<LogRotation>().unwrap(),
+            LogRotation::Hourly
+        );
+        assert!("weekly".parse::<LogRotation>().is_err());
+    }
+}
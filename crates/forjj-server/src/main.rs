@@ -3,33 +3,280 @@
 //! A native jj forge server providing repository hosting, push/fetch over SSH,
 //! and a REST API for repository management.
 
-use anyhow::Result;
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use forjj_storage::{RepositoryManager, StorageConfig};
+use tracing::{info, warn};
+
+use crate::config::{Cli, Command, ConfigCommand, ServerConfig};
 
 mod api;
+mod auth;
+mod config;
+mod git_http;
+mod health;
+mod hooks;
+mod logging;
+mod metadata;
+mod metrics;
+mod patch;
+mod rate_limit;
+mod raw;
+mod redirects;
+mod request_id;
+mod session;
+mod shutdown;
+mod ssh;
+mod state;
+mod tcp;
+mod tls;
+mod users;
+
+/// How long queued webhook deliveries have to be made once the server
+/// has stopped serving.
+const HOOK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "forjj=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    match Cli::parse().command {
+        Command::Serve(args) => serve(args.load()?).await,
+        Command::Config(ConfigCommand::Check(args)) => {
+            args.load()?;
+            match &args.config {
+                Some(path) => println!("{}: config is valid", path.display()),
+                None => println!("the default config is valid"),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Run the server as `config` says, until it fails or is shut down.
+async fn serve(config: ServerConfig) -> Result<()> {
+    // Initialize logging, holding the guard so file logs are flushed
+    let _log_guard = logging::init(&config.log)?;
 
     info!("Forjj - A native jj forge");
     info!("Version: 0.1.0-dev");
 
+    let data_dir = &config.data_dir;
+    let repos_root = config.repos_root();
+    std::fs::create_dir_all(&repos_root)
+        .with_context(|| format!("failed to create directory: {}", repos_root.display()))?;
+    let repos = Arc::new(RepositoryManager::new(StorageConfig {
+        repos_root: repos_root.clone(),
+    })?);
+    let hooks = Arc::new(hooks::HookDispatcher::new(
+        repos.clone(),
+        hooks::RetryPolicy::default(),
+    ));
+    let metrics = Arc::new(metrics::Metrics::new()?);
+    let shutdown = shutdown::Shutdown::new(Duration::from_secs(config.shutdown.drain_timeout_secs));
+    let sync_options = session::sync_options(hooks.clone(), metrics.clone(), shutdown.sessions());
+
+    let server_state = Arc::new(state::StateStore::open(&data_dir.join("state"))?);
+    let readiness = Arc::new(health::Readiness::new(
+        repos.clone(),
+        repos_root,
+        server_state.clone(),
+        config.health.canary()?,
+    ));
+
+    // Users manage their SSH keys through the API even if SSH is disabled
+    let keys_path = config.authorized_keys_file();
+    let keys = if keys_path.exists() {
+        ssh::AuthorizedKeysFile::load(&keys_path)?
+    } else {
+        ssh::AuthorizedKeysFile::default()
+    };
+    let fixed_keys = keys.len();
+    let ssh_keys = Arc::new(ssh::ManagedKeys::open(keys, server_state.clone())?);
+    if config.ssh.enabled && fixed_keys + ssh_keys.managed_len() == 0 {
+        warn!("no authorized SSH keys; SSH clients cannot connect until users add some");
+    } else {
+        info!(
+            "Loaded {fixed_keys} authorized SSH keys and {} added by users",
+            ssh_keys.managed_len()
+        );
+    }
+
+    // Start the SSH listener, sharing repositories and keys with the API
+    let ssh = if config.ssh.enabled {
+        let host_key = ssh::load_or_generate_host_key(&config.ssh_host_key())?;
+        let listener = tokio::net::TcpListener::bind(config.ssh.bind).await?;
+        info!("Listening on ssh://{}", config.ssh.bind);
+        let server = ssh::SshServer::new(repos.clone(), ssh_keys.clone(), sync_options.clone());
+        Some((server, listener, host_key))
+    } else {
+        info!("SSH is disabled");
+        None
+    };
+
+    let tokens_path = config.tokens_file();
+    let tokens = if tokens_path.exists() {
+        auth::TokenFile::load(&tokens_path)?
+    } else {
+        auth::TokenFile::default()
+    };
+    info!("Loaded {} API tokens", tokens.len());
+    let tokens = auth::ManagedTokens::open(tokens, server_state.clone())?;
+    info!("Loaded {} API tokens made by users", tokens.managed_len());
+    let tokens: Arc<dyn auth::TokenStore> = Arc::new(tokens);
+    let users = users::UserStore::open(server_state.clone())?;
+    info!("Loaded {} user accounts", users.len());
+    let redirects =
+        redirects::RedirectStore::open(server_state, config.storage.redirect_grace_period())?;
+
+    // Start the TCP listener for mirroring, if enabled
+    let tcp = match &config.tcp {
+        Some(tcp_options) => {
+            let listener = tokio::net::TcpListener::bind(tcp_options.bind).await?;
+            info!("Listening on tcp://{}", tcp_options.bind);
+            let mut server = tcp::TcpServer::new(
+                repos.clone(),
+                tokens.clone(),
+                sync_options.clone(),
+                tcp::TcpLimits::default(),
+            );
+            // Encrypt mirroring sessions if asked to
+            if tcp_options.noise {
+                let keypair = tcp::load_or_generate_noise_key(&data_dir.join("tcp_noise_key"))?;
+                info!(
+                    "TCP sessions use Noise with public key {}",
+                    hex::encode(keypair.public_key())
+                );
+                server = server.with_noise(keypair);
+            }
+            Some((server, listener))
+        }
+        None => None,
+    };
+
+    // Limit how fast each client may make API requests; zero for no limit
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(config.rate_limits));
+    tokio::spawn(rate_limiter.clone().evict_every(rate_limit::EVICT_INTERVAL));
+
+    // Serve metrics on an admin port of their own, if one is given
+    let metrics_listener = match config.metrics.bind {
+        Some(metrics_addr) => {
+            let listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+            info!(
+                "Serving metrics on http://{metrics_addr}{}",
+                metrics::METRICS_PATH
+            );
+            Some(listener)
+        }
+        None => None,
+    };
+
+    if config.swagger_ui && !cfg!(feature = "swagger-ui") {
+        warn!("swagger_ui is set, but this build was made without the swagger-ui feature");
+    }
+
     // Start HTTP server
-    let app = api::create_router();
+    let app = api::create_router(api::AppState {
+        repos,
+        tokens,
+        sync_options: Arc::new(sync_options),
+        soft_delete: config.storage.soft_delete,
+        max_patch_bytes: config.limits.max_patch_bytes,
+        max_raw_bytes: config.limits.max_raw_bytes,
+        max_blame_lines: config.limits.max_blame_lines,
+        stats_max_age: config.limits.stats_max_age_secs,
+        stats_scans: Arc::default(),
+        swagger_ui: config.swagger_ui,
+        anonymous_read: config.auth.anonymous_read,
+        users: Arc::new(users),
+        redirects: Arc::new(redirects),
+        ssh_keys,
+        hooks: hooks.clone(),
+        rate_limiter,
+        metrics: metrics.clone(),
+        serve_metrics: metrics_listener.is_none(),
+        readiness,
+    });
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    info!("Listening on http://0.0.0.0:3000");
+    // Load the certificate first, so bad files stop the server starting.
+    let tls = match &config.tls {
+        Some(options) => {
+            let certificate = tls::TlsCertificate::load(options)?;
+            tokio::spawn(certificate.clone().reload_on_hangup());
+            Some(certificate.server_config()?)
+        }
+        None => None,
+    };
+    let listener = tokio::net::TcpListener::bind(config.bind).await?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Listening on {scheme}://{}", config.bind);
 
-    axum::serve(listener, app).await?;
+    tokio::spawn(shutdown.clone().begin_on_signal());
+    let served = async {
+        tokio::try_join!(
+            async {
+                // Anonymous clients are rate limited by address.
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                match tls {
+                    Some(tls) => {
+                        let listener = tls::TlsListener::new(listener, tls)?;
+                        axum::serve(listener, app)
+                            .with_graceful_shutdown(shutdown.stopped())
+                            .await?;
+                    }
+                    None => {
+                        axum::serve(listener, app)
+                            .with_graceful_shutdown(shutdown.stopped())
+                            .await?
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            },
+            async {
+                match ssh {
+                    Some((server, listener, host_key)) => {
+                        server
+                            .run_until(listener, host_key, shutdown.stopped())
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            },
+            async {
+                match metrics_listener {
+                    Some(listener) => {
+                        let app = metrics::metrics_router(metrics);
+                        Ok(axum::serve(listener, app)
+                            .with_graceful_shutdown(shutdown.stopped())
+                            .await?)
+                    }
+                    None => Ok(()),
+                }
+            },
+            async {
+                match tcp {
+                    Some((server, listener)) => {
+                        server.run_until(listener, shutdown.stopped()).await
+                    }
+                    None => Ok(()),
+                }
+            },
+        )
+    };
+    // Connections that outlive the drain timeout, such as a client holding
+    // an API connection open, don't keep the server running.
+    tokio::select! {
+        served = served => {
+            served?;
+        }
+        () = shutdown.deadline() => warn!("exiting with connections still open"),
+    }
 
+    if !hooks.flush(HOOK_FLUSH_TIMEOUT).await {
+        warn!("exiting with webhook deliveries still queued");
+    }
+    info!("Shut down");
     Ok(())
 }
@@ -0,0 +1,223 @@
+//! Repository metadata kept by the server.
+//!
+//! Storage knows nothing about descriptions or creation times; the server
+//! keeps them in a sidecar file of each repository. Repositories created
+//! before the file existed, or by a push, have none. Statistics that take
+//! a scan of the repository to find are cached in another sidecar.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use forjj_storage::{RepoInfo, Repository};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Sidecar file the metadata is kept in.
+pub const METADATA_FILE: &str = "metadata.json";
+
+/// Longest description accepted, in bytes.
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+
+/// What the server records about a repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoMetadata {
+    /// Free-form description given at creation
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    /// Bookmark clients should start from, if not `main`
+    #[serde(default)]
+    pub default_bookmark: Option<String>,
+    /// Whether the API refuses to delete the repository, or to delete or
+    /// rewind its default bookmark
+    #[serde(default)]
+    pub protected: bool,
+    /// Whether the API refuses to change the repository's bookmarks
+    #[serde(default)]
+    pub archived: bool,
+    /// Who may see the repository
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// The repository this was forked from, as `owner/name`
+    #[serde(default)]
+    pub forked_from: Option<String>,
+}
+
+/// Who may see a repository.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Anyone who may read repositories on the server
+    #[default]
+    Public,
+    /// Only its owner, and admins
+    Private,
+}
+
+impl Visibility {
+    /// The visibility of a repository whose metadata loaded as `metadata`.
+    ///
+    /// Metadata that can't be read might have made the repository private,
+    /// so it is taken to.
+    pub fn of(metadata: &Result<Option<RepoMetadata>>) -> Self {
+        match metadata {
+            Ok(metadata) => metadata
+                .as_ref()
+                .map_or(Visibility::Public, |metadata| metadata.visibility),
+            Err(_) => Visibility::Private,
+        }
+    }
+}
+
+impl RepoMetadata {
+    /// Metadata for a repository created now.
+    pub fn new(description: Option<String>) -> Self {
+        Self {
+            description,
+            created_at: unix_now(),
+            default_bookmark: None,
+            protected: false,
+            archived: false,
+            visibility: Visibility::Public,
+            forked_from: None,
+        }
+    }
+
+    /// The metadata of `repo`, or `None` if it has none.
+    pub fn load(repo: &Repository) -> Result<Option<Self>> {
+        Self::load_listed(repo.info())
+    }
+
+    /// The metadata of a listed repository, read without opening it.
+    pub fn load_listed(info: &RepoInfo) -> Result<Option<Self>> {
+        let Some(data) = info.read_sidecar(METADATA_FILE)? else {
+            return Ok(None);
+        };
+        let metadata = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid {METADATA_FILE} in {}", info.path.display()))?;
+        Ok(Some(metadata))
+    }
+
+    /// Write this as the metadata of `repo`.
+    pub fn store(&self, repo: &Repository) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        repo.update_sidecar(METADATA_FILE, |_| Ok(data))
+    }
+}
+
+/// Sidecar file the statistics from the last scan are kept in.
+pub const STATS_FILE: &str = "stats.json";
+
+/// Statistics of a repository that take a scan of its files to find, kept
+/// so that they aren't found again on every request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScannedStats {
+    /// When the scan was, in seconds since the Unix epoch
+    pub scanned_at: u64,
+    /// Bytes the repository takes on disk
+    pub disk_size: u64,
+    /// Largest files at the tip of the default bookmark, largest first
+    pub largest_files: Vec<LargeFile>,
+}
+
+/// A file listed in [`ScannedStats`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LargeFile {
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+impl ScannedStats {
+    /// The statistics kept for `repo`, or `None` if none are.
+    pub fn load(repo: &Repository) -> Result<Option<Self>> {
+        let Some(data) = repo.read_sidecar(STATS_FILE)? else {
+            return Ok(None);
+        };
+        let stats = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid {STATS_FILE} in {}", repo.info().path.display()))?;
+        Ok(Some(stats))
+    }
+
+    /// Keep these as the statistics of `repo`.
+    pub fn store(&self, repo: &Repository) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        repo.update_sidecar(STATS_FILE, |_| Ok(data))
+    }
+
+    /// Whether these are more than `max_age` seconds old at `now`.
+    pub fn is_stale(&self, now: u64, max_age: u64) -> bool {
+        now.saturating_sub(self.scanned_at) > max_age
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forjj_storage::{RepositoryManager, StorageConfig};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let repo = repos.create_repo("alice", "project").unwrap();
+        assert_eq!(RepoMetadata::load(&repo).unwrap(), None);
+
+        let metadata = RepoMetadata::new(Some("a project".to_string()));
+        metadata.store(&repo).unwrap();
+        assert_eq!(RepoMetadata::load(&repo).unwrap(), Some(metadata.clone()));
+        let listed = &repos.list_repos("alice").unwrap()[0];
+        assert_eq!(RepoMetadata::load_listed(listed).unwrap(), Some(metadata));
+
+        // Metadata from before visibility was recorded is public.
+        let old = br#"{"description":null,"created_at":1}"#.to_vec();
+        repo.update_sidecar(METADATA_FILE, |_| Ok(old)).unwrap();
+        let loaded = RepoMetadata::load(&repo);
+        assert_eq!(Visibility::of(&loaded), Visibility::Public);
+        assert_eq!(Visibility::of(&Ok(None)), Visibility::Public);
+
+        repo.update_sidecar(METADATA_FILE, |_| Ok(b"{".to_vec()))
+            .unwrap();
+        let loaded = RepoMetadata::load(&repo);
+        assert!(loaded.is_err());
+        assert_eq!(Visibility::of(&loaded), Visibility::Private);
+    }
+
+    #[test]
+    fn test_scanned_stats() {
+        let dir = TempDir::new().unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let repo = repos.create_repo("alice", "project").unwrap();
+        assert_eq!(ScannedStats::load(&repo).unwrap(), None);
+
+        let stats = ScannedStats {
+            scanned_at: 1000,
+            disk_size: 4096,
+            largest_files: vec![LargeFile {
+                path: "README.md".to_string(),
+                size: 12,
+            }],
+        };
+        stats.store(&repo).unwrap();
+        assert_eq!(ScannedStats::load(&repo).unwrap(), Some(stats.clone()));
+        assert!(!stats.is_stale(1060, 60));
+        assert!(stats.is_stale(1061, 60));
+        // A clock that went back doesn't make them stale.
+        assert!(!stats.is_stale(900, 60));
+    }
+}
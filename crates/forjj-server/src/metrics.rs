@@ -0,0 +1,263 @@
+//! Metrics for operators, served in the Prometheus text format.
+//!
+//! The HTTP layer is measured by [`track_requests`], and forjj-sync
+//! sessions on every transport by the session monitor; see
+//! [`sync_options`](crate::session::sync_options). Labels only take values
+//! from small fixed sets, such as route templates rather than paths, so
+//! the number of series stays bounded however the server is used.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::Router;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use forjj_protocol::{RepoRef, RequestServed, SessionAbort};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Where metrics are served.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// The server's metrics, and the registry they are gathered from.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    http_in_flight: IntGauge,
+    sync_sessions: IntGauge,
+    sync_aborts: IntCounter,
+    sync_requests: IntCounterVec,
+    sync_bytes_received: IntCounterVec,
+    sync_bytes_sent: IntCounterVec,
+    stats_cache: IntCounterVec,
+}
+
+impl Metrics {
+    /// Metrics that all start at zero.
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("forjj".to_string()), None)?;
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "API requests answered"),
+            &["method", "route", "status"],
+        )?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to answer API requests, to the start of the body",
+            ),
+            &["method", "route"],
+        )?;
+        let http_in_flight =
+            IntGauge::new("http_requests_in_flight", "API requests being answered")?;
+        let sync_sessions =
+            IntGauge::new("sync_sessions_active", "forjj-sync sessions being served")?;
+        let sync_aborts = IntCounter::new(
+            "sync_sessions_aborted_total",
+            "forjj-sync sessions whose client went away",
+        )?;
+        let sync_requests = IntCounterVec::new(
+            Opts::new("sync_requests_total", "forjj-sync requests served"),
+            &["request"],
+        )?;
+        let sync_bytes_received = IntCounterVec::new(
+            Opts::new(
+                "sync_bytes_received_total",
+                "Bytes read from forjj-sync clients while serving requests, packs included",
+            ),
+            &["request"],
+        )?;
+        let sync_bytes_sent = IntCounterVec::new(
+            Opts::new(
+                "sync_bytes_sent_total",
+                "Bytes written to forjj-sync clients while serving requests, packs included",
+            ),
+            &["request"],
+        )?;
+        let stats_cache = IntCounterVec::new(
+            Opts::new(
+                "stats_cache_requests_total",
+                "Requests for repository statistics, by whether the cached scan was used",
+            ),
+            &["result"],
+        )?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        registry.register(Box::new(http_in_flight.clone()))?;
+        registry.register(Box::new(sync_sessions.clone()))?;
+        registry.register(Box::new(sync_aborts.clone()))?;
+        registry.register(Box::new(sync_requests.clone()))?;
+        registry.register(Box::new(sync_bytes_received.clone()))?;
+        registry.register(Box::new(sync_bytes_sent.clone()))?;
+        registry.register(Box::new(stats_cache.clone()))?;
+        Ok(Self {
+            registry,
+            http_requests,
+            http_duration,
+            http_in_flight,
+            sync_sessions,
+            sync_aborts,
+            sync_requests,
+            sync_bytes_received,
+            sync_bytes_sent,
+            stats_cache,
+        })
+    }
+
+    /// Every metric, in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        let mut text = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut text)?;
+        Ok(String::from_utf8(text)?)
+    }
+
+    /// Count a request for repository statistics, by whether the cached
+    /// scan answered it.
+    pub fn stats_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.stats_cache.with_label_values(&[result]).inc();
+    }
+
+    /// Count a forjj-sync session as begun.
+    pub fn session_started(&self, _repo: &RepoRef) {
+        self.sync_sessions.inc();
+    }
+
+    /// Count a forjj-sync session as over, however it ended.
+    pub fn session_ended(&self, _repo: &RepoRef) {
+        self.sync_sessions.dec();
+    }
+
+    /// Count a forjj-sync session whose client went away.
+    pub fn session_aborted(&self, _abort: &SessionAbort) {
+        self.sync_aborts.inc();
+    }
+
+    /// Count a forjj-sync request, and the bytes it took.
+    pub fn request_served(&self, served: &RequestServed) {
+        let labels = [served.request];
+        self.sync_requests.with_label_values(&labels).inc();
+        self.sync_bytes_received
+            .with_label_values(&labels)
+            .inc_by(served.bytes_received);
+        self.sync_bytes_sent
+            .with_label_values(&labels)
+            .inc_by(served.bytes_sent);
+    }
+}
+
+/// The label for `method`: methods the API doesn't route are all `other`,
+/// as clients may send any.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+/// Middleware that counts and times requests by method, route template and
+/// status. Requests no route matched share the route `unmatched`.
+pub async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = method_label(request.method());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    metrics.http_in_flight.inc();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics.http_in_flight.dec();
+    metrics
+        .http_duration
+        .with_label_values(&[method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Serve the metrics.
+pub async fn serve_metrics(State(metrics): State<Arc<Metrics>>) -> Response {
+    match metrics.render() {
+        Ok(text) => (
+            [("content-type", TextEncoder::new().format_type().to_string())],
+            text,
+        )
+            .into_response(),
+        Err(error) => {
+            tracing::warn!("failed to render metrics: {error:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// A router serving only the metrics, for an admin port of their own.
+pub fn metrics_router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route(METRICS_PATH, get(serve_metrics))
+        .with_state(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_metrics() {
+        let metrics = Metrics::new().unwrap();
+        let repo = RepoRef::new("alice", "project");
+        metrics.session_started(&repo);
+        metrics.session_started(&repo);
+        metrics.session_ended(&repo);
+        metrics.request_served(&RequestServed {
+            repo: repo.clone(),
+            request: "Push",
+            bytes_received: 300,
+            bytes_sent: 20,
+        });
+        metrics.request_served(&RequestServed {
+            repo,
+            request: "Push",
+            bytes_received: 100,
+            bytes_sent: 20,
+        });
+        let text = metrics.render().unwrap();
+        for series in [
+            "forjj_sync_sessions_active 1",
+            "forjj_sync_requests_total{request=\"Push\"} 2",
+            "forjj_sync_bytes_received_total{request=\"Push\"} 400",
+            "forjj_sync_bytes_sent_total{request=\"Push\"} 40",
+            "forjj_sync_sessions_aborted_total 0",
+        ] {
+            assert!(
+                text.lines().any(|line| line == series),
+                "{series} in {text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::from_bytes(b"BREW").unwrap()), "other");
+    }
+}
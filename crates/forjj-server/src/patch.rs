@@ -0,0 +1,112 @@
+//! Unified diffs of file contents, for showing commits.
+
+use imara_diff::intern::InternedInput;
+use imara_diff::{Algorithm, UnifiedDiffBuilder, diff};
+
+/// Most bytes of patches answered for one commit, unless configured.
+pub const DEFAULT_MAX_PATCH_BYTES: usize = 1024 * 1024;
+
+/// The hunks of a unified diff from `old` to `new`, or `None` if either
+/// isn't text.
+pub fn unified_diff(old: &[u8], new: &[u8]) -> Option<String> {
+    let input = InternedInput::new(as_text(old)?, as_text(new)?);
+    Some(diff(
+        Algorithm::Histogram,
+        &input,
+        UnifiedDiffBuilder::new(&input),
+    ))
+}
+
+/// One side of a changed file, for [`git_file_patch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchSide<'a> {
+    /// Nothing is at the path on this side
+    Missing,
+    /// A file with these contents
+    File(&'a [u8]),
+    /// Something that isn't a file, such as a symlink or a conflict
+    Other,
+}
+
+/// A file's part of a patch, in the form `git diff` writes and `patch`
+/// applies. Files that either side has as something other than text are
+/// only said to differ.
+pub fn git_file_patch(path: &str, old: PatchSide<'_>, new: PatchSide<'_>) -> String {
+    let name = |side: PatchSide<'_>, prefix: &str| match side {
+        PatchSide::Missing => "/dev/null".to_string(),
+        _ => format!("{prefix}/{path}"),
+    };
+    let (old_name, new_name) = (name(old, "a"), name(new, "b"));
+    let contents = |side: PatchSide<'_>| match side {
+        PatchSide::Missing => Some(&[][..]),
+        PatchSide::File(data) => Some(data),
+        PatchSide::Other => None,
+    };
+    let hunks = match (contents(old), contents(new)) {
+        (Some(old), Some(new)) => unified_diff(old, new),
+        _ => None,
+    };
+    let mut patch = format!("diff --git a/{path} b/{path}\n");
+    match hunks {
+        Some(hunks) if hunks.is_empty() => {}
+        Some(hunks) => {
+            patch.push_str(&format!("--- {old_name}\n+++ {new_name}\n"));
+            patch.push_str(&hunks);
+        }
+        None => patch.push_str(&format!("Binary files {old_name} and {new_name} differ\n")),
+    }
+    patch
+}
+
+/// `data` as text, if it is UTF-8 without NUL bytes.
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let patch = unified_diff(b"one\ntwo\n", b"one\nthree\n").unwrap();
+        assert!(patch.starts_with("@@"), "{patch}");
+        assert!(patch.contains("-two\n"), "{patch}");
+        assert!(patch.contains("+three\n"), "{patch}");
+        assert_eq!(unified_diff(b"same\n", b"same\n").unwrap(), "");
+
+        assert_eq!(unified_diff(b"text", b"\0binary"), None);
+        assert_eq!(unified_diff(b"\xff", b"text"), None);
+    }
+
+    #[test]
+    fn test_git_file_patch() {
+        let patch = git_file_patch(
+            "src/lib.rs",
+            PatchSide::File(b"one\n"),
+            PatchSide::File(b"two\n"),
+        );
+        assert!(
+            patch.starts_with(
+                "diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n@@"
+            ),
+            "{patch}"
+        );
+        assert!(patch.ends_with("-one\n+two\n"), "{patch}");
+
+        let patch = git_file_patch("new.txt", PatchSide::Missing, PatchSide::File(b"new\n"));
+        assert!(patch.contains("--- /dev/null\n+++ b/new.txt\n"), "{patch}");
+        assert!(patch.ends_with("+new\n"), "{patch}");
+
+        let patch = git_file_patch("logo.png", PatchSide::File(b"\0"), PatchSide::Missing);
+        assert!(
+            patch.ends_with("Binary files a/logo.png and /dev/null differ\n"),
+            "{patch}"
+        );
+        let patch = git_file_patch("link", PatchSide::Other, PatchSide::File(b"text\n"));
+        assert!(patch.contains("Binary files"), "{patch}");
+    }
+}
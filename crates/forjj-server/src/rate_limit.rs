@@ -0,0 +1,311 @@
+//! Limiting how fast each client may make API requests.
+//!
+//! Each client has a token bucket per class of route: a request takes a
+//! token, and tokens come back at the configured rate, up to the burst.
+//! Clients are told apart by user, or by address if they give no token.
+//! Expensive routes, such as archives, have their own stricter buckets, so
+//! that a client making many of them can't take the server down. Buckets
+//! that have filled back up are dropped by [`RateLimiter::evict`], so only
+//! clients that made requests lately take memory.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::api::ApiError;
+use crate::auth::AuthenticatedUser;
+
+/// Requests a client may make a minute, unless configured.
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 600;
+
+/// Requests a client may make at once, unless configured.
+pub const DEFAULT_BURST: u32 = 100;
+
+/// Requests to expensive routes a client may make a minute, unless
+/// configured.
+pub const DEFAULT_EXPENSIVE_REQUESTS_PER_MINUTE: u32 = 30;
+
+/// Requests to expensive routes a client may make at once, unless
+/// configured.
+pub const DEFAULT_EXPENSIVE_BURST: u32 = 5;
+
+/// How often buckets that have filled back up are dropped.
+pub const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Routes that take enough work to answer to be limited as
+/// [`RouteClass::Expensive`].
+const EXPENSIVE_ROUTES: &[&str] = &[
+    "/api/v1/repos/{owner}/{name}/archive/{file}",
+    "/api/v1/repos/{owner}/{name}/compare/{*range}",
+    "/api/v1/repos/{owner}/{name}/blame/{rev}/{*path}",
+    "/api/v1/repos/{owner}/{name}/history/{rev}/{*path}",
+];
+
+/// How fast a client may make requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests a minute, on average; zero for no limit
+    pub per_minute: u32,
+    /// Requests that may be made at once after a quiet spell; zero is
+    /// taken as one
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// No limit at all.
+    pub const UNLIMITED: RateLimit = RateLimit {
+        per_minute: 0,
+        burst: 0,
+    };
+}
+
+/// The limits for each [`RouteClass`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub default: RateLimit,
+    pub expensive: RateLimit,
+}
+
+impl RateLimits {
+    /// No limits on any route.
+    pub const UNLIMITED: RateLimits = RateLimits {
+        default: RateLimit::UNLIMITED,
+        expensive: RateLimit::UNLIMITED,
+    };
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            default: RateLimit {
+                per_minute: DEFAULT_REQUESTS_PER_MINUTE,
+                burst: DEFAULT_BURST,
+            },
+            expensive: RateLimit {
+                per_minute: DEFAULT_EXPENSIVE_REQUESTS_PER_MINUTE,
+                burst: DEFAULT_EXPENSIVE_BURST,
+            },
+        }
+    }
+}
+
+/// Which limit a route is held to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Default,
+    /// Routes that take much more work than most, listed in
+    /// [`EXPENSIVE_ROUTES`]
+    Expensive,
+}
+
+impl RouteClass {
+    /// The class of the route matched as `route`.
+    pub fn of(route: &str) -> Self {
+        if EXPENSIVE_ROUTES.contains(&route) {
+            RouteClass::Expensive
+        } else {
+            RouteClass::Default
+        }
+    }
+}
+
+/// The tokens a client has left for a class of route.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last brought up to date
+    updated: Instant,
+}
+
+/// What a [`RateLimit`] comes to as a bucket: its size, and the tokens it
+/// gets back a second. `None` if there is no limit.
+fn bucket_shape(limit: RateLimit) -> Option<(f64, f64)> {
+    if limit.per_minute == 0 {
+        return None;
+    }
+    Some((
+        f64::from(limit.burst.max(1)),
+        f64::from(limit.per_minute) / 60.0,
+    ))
+}
+
+impl Bucket {
+    /// The tokens in the bucket at `now`, of one `size` refilled at `rate`.
+    fn tokens_at(&self, now: Instant, size: f64, rate: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(size)
+    }
+}
+
+/// Token buckets for each client and class of route.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(RouteClass, String), Bucket>>,
+}
+
+impl RateLimiter {
+    /// A limiter holding clients to `limits`.
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit(&self, class: RouteClass) -> RateLimit {
+        match class {
+            RouteClass::Default => self.limits.default,
+            RouteClass::Expensive => self.limits.expensive,
+        }
+    }
+
+    /// Take a token from `client`'s bucket for `class`, or say how long
+    /// until there will be one.
+    pub fn check(&self, class: RouteClass, client: &str) -> Result<(), Duration> {
+        let Some((size, rate)) = bucket_shape(self.limit(class)) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let bucket = buckets
+            .entry((class, client.to_string()))
+            .or_insert(Bucket {
+                tokens: size,
+                updated: now,
+            });
+        bucket.tokens = bucket.tokens_at(now, size, rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    /// Drop the buckets that have filled back up. A client's next request
+    /// finds a full bucket whether or not its bucket is kept.
+    pub fn evict(&self) {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        buckets.retain(
+            |(class, _), bucket| match bucket_shape(self.limit(*class)) {
+                Some((size, rate)) => bucket.tokens_at(now, size, rate) < size,
+                None => false,
+            },
+        );
+    }
+
+    /// Call [`evict`](Self::evict) every `period`, for as long as the
+    /// server runs.
+    pub async fn evict_every(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.evict();
+        }
+    }
+}
+
+/// Who a request counts against: its user, else the address it came from.
+fn client_key(request: &Request) -> String {
+    if let Some(user) = request.extensions().get::<AuthenticatedUser>() {
+        return format!("user:{}", user.username);
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(address)) => format!("ip:{}", address.ip()),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// Middleware that answers requests past their client's limit with 429,
+/// saying when to try again.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let class = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(RouteClass::Default, |route| RouteClass::of(route.as_str()));
+    if let Err(wait) = limiter.check(class, &client_key(&request)) {
+        return Err(ApiError::rate_limited(wait));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimits {
+            default: RateLimit {
+                per_minute: 60,
+                burst: 3,
+            },
+            expensive: RateLimit {
+                per_minute: 6,
+                burst: 1,
+            },
+        });
+        for _ in 0..3 {
+            limiter.check(RouteClass::Default, "alice").unwrap();
+        }
+        let wait = limiter.check(RouteClass::Default, "alice").unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        // Others, and other classes, have buckets of their own.
+        limiter.check(RouteClass::Default, "bob").unwrap();
+        limiter.check(RouteClass::Expensive, "alice").unwrap();
+        let wait = limiter.check(RouteClass::Expensive, "alice").unwrap_err();
+        assert_eq!(wait, Duration::from_secs(10));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.check(RouteClass::Default, "alice").unwrap();
+        assert!(limiter.check(RouteClass::Default, "alice").is_err());
+
+        // Only buckets that are still filling are kept.
+        tokio::time::advance(Duration::from_secs(2)).await;
+        limiter.evict();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        limiter.evict();
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(RateLimits::UNLIMITED);
+        for _ in 0..1000 {
+            limiter.check(RouteClass::Expensive, "alice").unwrap();
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_route_class() {
+        assert_eq!(
+            RouteClass::of("/api/v1/repos/{owner}/{name}/archive/{file}"),
+            RouteClass::Expensive
+        );
+        assert_eq!(RouteClass::of("/api/v1/repos"), RouteClass::Default);
+    }
+}
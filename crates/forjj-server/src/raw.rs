@@ -0,0 +1,213 @@
+//! Serving file contents as they are, for the raw endpoint.
+
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Most bytes of a file answered without a range, unless configured.
+pub const DEFAULT_MAX_RAW_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How many bytes at the start of a file are looked at to tell whether it
+/// is text.
+const SNIFF_LEN: usize = 8192;
+
+/// Read `reader` to the end, returning its length and the bytes it starts
+/// with, without holding more than those in memory.
+pub async fn measure(mut reader: impl AsyncRead + Unpin) -> std::io::Result<(u64, Vec<u8>)> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    (&mut reader)
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+    let rest = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    Ok((head.len() as u64 + rest, head))
+}
+
+/// Whether a file starting with `head` is binary: it has a NUL byte or isn't
+/// UTF-8. A character cut off at the end of `head` doesn't count.
+pub fn looks_binary(head: &[u8]) -> bool {
+    if head.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => false,
+        Err(error) => error.error_len().is_some(),
+    }
+}
+
+/// The media type to answer a file with.
+///
+/// Files that browsers would run, such as HTML, SVG and JavaScript, are
+/// served as plain text so that the raw endpoint can't host pages.
+pub fn content_type(path: &str, binary: bool) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    let known = match extension.as_deref() {
+        Some("png") => Some("image/png"),
+        Some("jpg" | "jpeg") => Some("image/jpeg"),
+        Some("gif") => Some("image/gif"),
+        Some("webp") => Some("image/webp"),
+        Some("ico") => Some("image/x-icon"),
+        Some("pdf") => Some("application/pdf"),
+        Some("zip") => Some("application/zip"),
+        Some("gz" | "tgz") => Some("application/gzip"),
+        Some("tar") => Some("application/x-tar"),
+        Some("wasm") => Some("application/wasm"),
+        Some("json") => Some("application/json"),
+        _ => None,
+    };
+    match known {
+        Some(media_type) => media_type,
+        None if binary => "application/octet-stream",
+        None => "text/plain; charset=utf-8",
+    }
+}
+
+/// Which part of a file a request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// All of it
+    Whole,
+    /// `len` bytes from `start`
+    Partial { start: u64, len: u64 },
+    /// A range that doesn't overlap the file
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a file of `size` bytes.
+///
+/// Only a single byte range is supported; a header asking for several, or
+/// that can't be parsed, is ignored as HTTP allows.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Whole;
+    };
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return RangeRequest::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // A suffix: the last `last` bytes.
+        return match last.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if size == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => {
+                let len = suffix.min(size);
+                RangeRequest::Partial {
+                    start: size - len,
+                    len,
+                }
+            }
+            Err(_) => RangeRequest::Whole,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return RangeRequest::Whole;
+    };
+    let end = match last {
+        "" => None,
+        last => match last.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return RangeRequest::Whole,
+        },
+    };
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    let end = end.map_or(size - 1, |end| end.min(size - 1));
+    RangeRequest::Partial {
+        start,
+        len: end - start + 1,
+    }
+}
+
+/// Whether an `If-None-Match` header matches `etag`, so the client's copy is
+/// current.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_measure() {
+        let data = vec![b'x'; SNIFF_LEN * 3 + 5];
+        let (size, head) = measure(&data[..]).await.unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(head.len(), SNIFF_LEN);
+
+        let (size, head) = measure(&b"short"[..]).await.unwrap();
+        assert_eq!((size, &head[..]), (5, &b"short"[..]));
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(b"plain text\n"));
+        assert!(!looks_binary("caf\u{e9}".as_bytes()));
+        // "é" cut in half by the end of the sniffed bytes
+        assert!(!looks_binary(b"caf\xc3"));
+        assert!(looks_binary(b"\x89PNG\r\n\x1a\n\0"));
+        assert!(looks_binary(b"\xff\xfe text"));
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("logo.PNG", true), "image/png");
+        assert_eq!(
+            content_type("src/main.rs", false),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            content_type("index.html", false),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(content_type("data.bin", true), "application/octet-stream");
+        assert_eq!(content_type("Makefile", false), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        use RangeRequest::*;
+        assert_eq!(parse_range(None, 10), Whole);
+        assert_eq!(
+            parse_range(Some("bytes=2-4"), 10),
+            Partial { start: 2, len: 3 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=5-"), 10),
+            Partial { start: 5, len: 5 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=5-99"), 10),
+            Partial { start: 5, len: 5 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-3"), 10),
+            Partial { start: 7, len: 3 }
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-30"), 10),
+            Partial { start: 0, len: 10 }
+        );
+        assert_eq!(parse_range(Some("bytes=10-"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-"), 0), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), Whole);
+        assert_eq!(parse_range(Some("bytes=4-2"), 10), Whole);
+        assert_eq!(parse_range(Some("lines=1-2"), 10), Whole);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abd\"", "\"abc\""));
+    }
+}
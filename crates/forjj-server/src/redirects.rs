@@ -0,0 +1,150 @@
+//! Where renamed and transferred repositories went.
+//!
+//! When a repository moves, its old name is recorded with its new one, so
+//! that clients still using the old name can be pointed at the new one
+//! for a grace period. A repository created at the old name takes it
+//! back: redirects are only followed for names no repository has.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use forjj_protocol::RepoRef;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::unix_now;
+use crate::state::StateStore;
+
+/// State file the redirects are kept in.
+pub const REDIRECTS_FILE: &str = "redirects.json";
+
+/// How long a redirect is followed, unless configured.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// An old name of a repository, and where it went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redirect {
+    pub from: RepoRef,
+    pub to: RepoRef,
+    /// When it moved, in seconds since the Unix epoch
+    pub moved_at: u64,
+}
+
+/// The redirects, kept in a [`StateStore`].
+#[derive(Debug)]
+pub struct RedirectStore {
+    state: Arc<StateStore>,
+    grace_period: Duration,
+    redirects: RwLock<Vec<Redirect>>,
+}
+
+impl RedirectStore {
+    /// Open the redirects kept in `state`, following each for
+    /// `grace_period` after its repository moved.
+    pub fn open(state: Arc<StateStore>, grace_period: Duration) -> Result<Self> {
+        let redirects = state.read(REDIRECTS_FILE)?;
+        Ok(Self {
+            state,
+            grace_period,
+            redirects: RwLock::new(redirects),
+        })
+    }
+
+    /// Where the repository once called `repo` is now, if it moved within
+    /// the grace period.
+    pub fn lookup(&self, repo: &RepoRef) -> Option<RepoRef> {
+        let now = unix_now();
+        let redirects = self
+            .redirects
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        redirects
+            .iter()
+            .find(|redirect| redirect.from == *repo && !self.expired(redirect, now))
+            .map(|redirect| redirect.to.clone())
+    }
+
+    /// Record that the repository `from` moved to `to`.
+    ///
+    /// Redirects to `from` are pointed at `to`, so a repository that moves
+    /// twice is found from either of its old names, and any redirect from
+    /// `to` is dropped: a repository has that name now. Expired redirects
+    /// are dropped too.
+    pub fn add(&self, from: RepoRef, to: RepoRef) -> Result<()> {
+        let now = unix_now();
+        let mut redirects = self
+            .redirects
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut updated: Vec<Redirect> = redirects
+            .iter()
+            .filter(|redirect| {
+                redirect.from != from && redirect.from != to && !self.expired(redirect, now)
+            })
+            .cloned()
+            .map(|mut redirect| {
+                if redirect.to == from {
+                    redirect.to = to.clone();
+                }
+                redirect
+            })
+            .collect();
+        updated.push(Redirect {
+            from,
+            to,
+            moved_at: now,
+        });
+        self.state.write(REDIRECTS_FILE, &updated)?;
+        *redirects = updated;
+        Ok(())
+    }
+
+    fn expired(&self, redirect: &Redirect, now: u64) -> bool {
+        now.saturating_sub(redirect.moved_at) > self.grace_period.as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_redirect_store() {
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(StateStore::open(dir.path()).unwrap());
+        let redirects = RedirectStore::open(state.clone(), DEFAULT_GRACE_PERIOD).unwrap();
+        let (old, new, newer) = (
+            RepoRef::new("alice", "old"),
+            RepoRef::new("alice", "new"),
+            RepoRef::new("bob", "new"),
+        );
+        assert_eq!(redirects.lookup(&old), None);
+
+        redirects.add(old.clone(), new.clone()).unwrap();
+        assert_eq!(redirects.lookup(&old), Some(new.clone()));
+
+        // Moving again redirects both old names.
+        redirects.add(new.clone(), newer.clone()).unwrap();
+        assert_eq!(redirects.lookup(&old), Some(newer.clone()));
+        assert_eq!(redirects.lookup(&new), Some(newer.clone()));
+
+        // Moving back to an old name takes it back.
+        redirects.add(newer.clone(), old.clone()).unwrap();
+        assert_eq!(redirects.lookup(&old), None);
+        assert_eq!(redirects.lookup(&newer), Some(old.clone()));
+
+        let reopened = RedirectStore::open(state.clone(), DEFAULT_GRACE_PERIOD).unwrap();
+        assert_eq!(reopened.lookup(&new), Some(old.clone()));
+
+        // Past the grace period, the old names are forgotten.
+        let expired = Redirect {
+            from: RepoRef::new("alice", "ancient"),
+            to: old.clone(),
+            moved_at: unix_now() - 100,
+        };
+        state.write(REDIRECTS_FILE, &vec![expired]).unwrap();
+        let short = RedirectStore::open(state, Duration::from_secs(10)).unwrap();
+        assert_eq!(short.lookup(&RepoRef::new("alice", "ancient")), None);
+    }
+}
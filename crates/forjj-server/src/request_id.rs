@@ -0,0 +1,96 @@
+//! IDs that tie an API request to what the server logged while answering
+//! it.
+//!
+//! A request may bring its own ID in the [`REQUEST_ID_HEADER`], as a proxy
+//! in front of the server would; otherwise, or if the one it brings isn't
+//! [valid](is_valid), a UUID is made for it. The ID is kept as a
+//! [`RequestId`] extension, recorded in the request's span so every event
+//! logged while answering names it, echoed in the response, and given in
+//! the body of any error.
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Header a request's ID comes in and goes back out in.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID taken from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The ID of the request being answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    /// The ID of the request the current task is answering, so errors can
+    /// give it without being handed it.
+    static CURRENT: RequestId;
+}
+
+/// The ID of the request the current task is answering, if any.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// Whether `id` is fit to be logged and echoed: 1 to
+/// [`MAX_REQUEST_ID_LEN`] ASCII letters, digits, `-`, `_` or `.`.
+pub fn is_valid(id: &str) -> bool {
+    (1..=MAX_REQUEST_ID_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+/// Middleware that gives each request a [`RequestId`] and echoes it in the
+/// response.
+///
+/// It must be outside the layer that makes the request's span, which reads
+/// the ID from the request's extensions.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id));
+    let id = match given {
+        Some(id) => id.to_string(),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = CURRENT
+        .scope(RequestId(id.clone()), next.run(request))
+        .await;
+    // Valid IDs are all valid header values.
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("3f2b1c9e-5d6a-4e8b-9c0d-1a2b3c4d5e6f"));
+        assert!(is_valid("req_42.retry"));
+        assert!(!is_valid(""));
+        assert!(!is_valid(&"x".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert!(!is_valid("has space"));
+        assert!(!is_valid("line\nbreak"));
+        assert!(!is_valid("caf\u{e9}"));
+    }
+
+    #[tokio::test]
+    async fn test_current() {
+        assert_eq!(current(), None);
+        let id = RequestId("abc".to_string());
+        assert_eq!(
+            CURRENT.scope(id, async { current() }).await.as_deref(),
+            Some("abc")
+        );
+    }
+}
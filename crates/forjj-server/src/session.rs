@@ -0,0 +1,134 @@
+//! forjj-sync sessions over transports that authenticate the client
+//! themselves.
+//!
+//! The SSH and HTTP transports both know, before the protocol starts, which
+//! repository the client wants and what its credentials grant. The session
+//! is held to that repository, and the grant replaces the credentials in
+//! the Hello.
+
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use forjj_protocol::{
+    AuthGrant, BookmarksMoved, ProtocolError, RepoProvider, RepoRef, RequestServed, ServerOptions,
+    SessionAbort, SessionMonitor, SessionShutdown, TransportAuth, serve_session,
+};
+use forjj_storage::Repository;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::warn;
+
+use crate::hooks::{HookDispatcher, HookEvent};
+use crate::metrics::Metrics;
+
+/// Repositories the server can serve.
+pub type Repos = Arc<dyn RepoProvider + Send + Sync>;
+
+/// Logs each aborted session as a structured event, posts the bookmarks
+/// pushes move to the repository's hooks, and counts sessions and requests
+/// in the metrics.
+struct Monitor {
+    hooks: Arc<HookDispatcher>,
+    metrics: Arc<Metrics>,
+}
+
+impl SessionMonitor for Monitor {
+    fn session_started(&self, repo: &RepoRef) {
+        self.metrics.session_started(repo);
+    }
+
+    fn session_ended(&self, repo: &RepoRef) {
+        self.metrics.session_ended(repo);
+    }
+
+    fn request_served(&self, served: &RequestServed) {
+        self.metrics.request_served(served);
+    }
+
+    fn aborted(&self, abort: &SessionAbort) {
+        self.metrics.session_aborted(abort);
+        warn!(
+            repo = %abort.repo,
+            identity = abort.identity.as_deref(),
+            request = abort.request,
+            bytes_received = abort.bytes_received,
+            bytes_sent = abort.bytes_sent,
+            "forjj-sync session aborted: {}",
+            abort.reason
+        );
+    }
+
+    fn bookmarks_moved(&self, moved: &BookmarksMoved) {
+        self.hooks.bookmarks_moved(HookEvent::Push, moved.clone());
+    }
+}
+
+/// Session options for every transport, logging aborted sessions, firing
+/// `hooks` on pushes, counting sessions in `metrics` and ending sessions
+/// on `shutdown`.
+pub fn sync_options(
+    hooks: Arc<HookDispatcher>,
+    metrics: Arc<Metrics>,
+    shutdown: SessionShutdown,
+) -> ServerOptions {
+    ServerOptions {
+        monitor: Some(Arc::new(Monitor { hooks, metrics })),
+        shutdown,
+        ..ServerOptions::default()
+    }
+}
+
+/// Only the repository the transport selected, whatever the Hello asks for.
+struct OnlyRepo {
+    inner: Repos,
+    repo: RepoRef,
+}
+
+impl RepoProvider for OnlyRepo {
+    fn exists(&self, repo: &RepoRef) -> bool {
+        *repo == self.repo && self.inner.exists(repo)
+    }
+
+    fn open(&self, repo: &RepoRef) -> Result<Repository> {
+        if *repo != self.repo {
+            bail!("session is for {}, not {repo}", self.repo);
+        }
+        self.inner.open(repo)
+    }
+
+    fn create(&self, repo: &RepoRef) -> Result<Repository> {
+        if *repo != self.repo {
+            bail!("session is for {}, not {repo}", self.repo);
+        }
+        self.inner.create(repo)
+    }
+
+    fn delete(&self, repo: &RepoRef) -> Result<()> {
+        if *repo != self.repo {
+            bail!("session is for {}, not {repo}", self.repo);
+        }
+        self.inner.delete(repo)
+    }
+
+    fn may_see(&self, repo: &RepoRef, grant: &AuthGrant) -> bool {
+        self.inner.may_see(repo, grant)
+    }
+}
+
+/// Serve one session on `repo` over `stream`, with the access in `grant`.
+pub async fn serve_transport<S>(
+    stream: S,
+    repos: Repos,
+    repo: RepoRef,
+    grant: AuthGrant,
+    options: &ServerOptions,
+) -> Result<(), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let provider = OnlyRepo { inner: repos, repo };
+    let options = ServerOptions {
+        auth: Arc::new(TransportAuth(grant)),
+        ..options.clone()
+    };
+    serve_session(stream, &provider, &options).await
+}
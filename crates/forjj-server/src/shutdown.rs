@@ -0,0 +1,119 @@
+//! Shutting the server down without cutting off the clients it is serving.
+//!
+//! On SIGTERM or SIGINT the listeners stop taking connections, and
+//! forjj-sync sessions on every transport finish the request they are
+//! serving, then end with a retryable error. API requests in progress are
+//! answered. Sessions still mid-request when the drain timeout passes are
+//! aborted, telling their clients the server is going away, and once
+//! they've had a moment to hear it the server exits whatever is left.
+
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use forjj_protocol::SessionShutdown;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use tracing::{info, warn};
+
+/// How long sessions may take to finish, unless configured.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long after the drain timeout aborted sessions have to tell their
+/// clients so, before the server exits regardless.
+pub const ABORT_GRACE: Duration = Duration::from_secs(5);
+
+/// Tells the listeners and sessions of the server when it is shutting
+/// down. Clones share their state.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    stop: CancellationToken,
+    sessions: SessionShutdown,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    /// A shutdown not yet begun, giving sessions `drain_timeout` to finish.
+    pub fn new(drain_timeout: Duration) -> Self {
+        Self {
+            stop: CancellationToken::new(),
+            sessions: SessionShutdown::new(),
+            drain_timeout,
+        }
+    }
+
+    /// What tells forjj-sync sessions, for their
+    /// [`ServerOptions`](forjj_protocol::ServerOptions).
+    pub fn sessions(&self) -> SessionShutdown {
+        self.sessions.clone()
+    }
+
+    /// Resolves once listeners should stop taking connections.
+    pub fn stopped(&self) -> WaitForCancellationFutureOwned {
+        self.stop.clone().cancelled_owned()
+    }
+
+    /// Resolves once the server should exit, whatever is still running.
+    pub fn deadline(&self) -> impl Future<Output = ()> + Send + 'static {
+        let stopped = self.stopped();
+        let wait = self.drain_timeout + ABORT_GRACE;
+        async move {
+            stopped.await;
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Stop the listeners and drain sessions, aborting those still running
+    /// after the drain timeout. Only the first call does anything.
+    pub fn begin(&self) {
+        if self.stop.is_cancelled() {
+            return;
+        }
+        self.stop.cancel();
+        self.sessions.drain();
+        let sessions = self.sessions.clone();
+        let drain_timeout = self.drain_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(drain_timeout).await;
+            warn!("drain timeout passed; aborting forjj-sync sessions still running");
+            sessions.abort();
+        });
+    }
+
+    /// [`begin`](Self::begin) on the first SIGTERM or SIGINT.
+    pub async fn begin_on_signal(self) -> Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            interrupted = tokio::signal::ctrl_c() => interrupted?,
+        }
+        info!(
+            "Shutting down; waiting up to {:?} for sessions to finish",
+            self.drain_timeout
+        );
+        self.begin();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_begin() {
+        let shutdown = Shutdown::new(Duration::from_secs(10));
+        let sessions = shutdown.sessions();
+        let stopped = shutdown.stopped();
+        let deadline = tokio::spawn(shutdown.deadline());
+        assert!(!sessions.is_draining());
+
+        shutdown.begin();
+        stopped.await;
+        assert!(sessions.is_draining());
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        assert!(!deadline.is_finished());
+        tokio::time::sleep(ABORT_GRACE + Duration::from_secs(1)).await;
+        deadline.await.unwrap();
+    }
+}
@@ -0,0 +1,855 @@
+//! SSH transport for forjj-sync.
+//!
+//! Clients authenticate with a public key listed in an authorized-keys
+//! source, which also decides the key's access. A session starts with the
+//! exec request `forjj-sync '<owner>/<name>'`; the channel's data is then
+//! handed to [`serve_transport`], restricted to that repository and with
+//! the key's grant in place of the credentials in the Hello. The SSH user name
+//! is ignored, as with `git@` remotes.
+//!
+//! Keys come from a file the operator writes, and from users, who add their
+//! own through the API; [`ManagedKeys`] keeps both. Keys that only read a
+//! repository can't start sessions on it if it is private.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use forjj_protocol::{AccessLevel, AuthGrant, RepoRef, SYNC_COMMAND, ServerOptions};
+use forjj_storage::validate_name;
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::keys::ssh_key::{HashAlg, LineEnding};
+use russh::keys::{Algorithm, PrivateKey, PublicKey};
+use russh::server::{Auth, Msg, Server, Session};
+use russh::{Channel, ChannelId};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, debug, info, warn};
+use utoipa::ToSchema;
+
+use crate::metadata::{RepoMetadata, Visibility, unix_now};
+use crate::session::{Repos, serve_transport};
+use crate::state::StateStore;
+
+/// Decides which public keys may connect, and with what access.
+pub trait AuthorizedKeys: Send + Sync {
+    /// The grant for `key`, or `None` to reject it.
+    fn grant(&self, key: &PublicKey) -> Option<AuthGrant>;
+
+    /// The grant for `key` in a session on `repo`, or `None` to reject it.
+    ///
+    /// Asked again when the session starts, so keys removed since the
+    /// client authenticated are refused.
+    fn grant_on(&self, key: &PublicKey, _repo: &RepoRef) -> Option<AuthGrant> {
+        self.grant(key)
+    }
+}
+
+/// Keys listed in a file, one per line: an access level, then the key in
+/// OpenSSH format.
+///
+/// ```text
+/// write ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI... alice@laptop
+/// read ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI... ci
+/// ```
+///
+/// The key's comment is its identity; keys without one are identified by
+/// their fingerprint. Blank lines and lines starting with `#` are skipped.
+#[derive(Debug, Default)]
+pub struct AuthorizedKeysFile {
+    entries: Vec<(PublicKey, AuthGrant)>,
+}
+
+impl AuthorizedKeysFile {
+    /// Parse the contents of an authorized-keys file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_key_line(line).with_context(|| format!("line {}", number + 1))?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Read and parse the file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid authorized keys in {}", path.display()))
+    }
+
+    /// Number of keys listed.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no keys are listed.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl AuthorizedKeys for AuthorizedKeysFile {
+    fn grant(&self, key: &PublicKey) -> Option<AuthGrant> {
+        self.entries
+            .iter()
+            .find(|(listed, _)| listed.key_data() == key.key_data())
+            .map(|(_, grant)| grant.clone())
+    }
+}
+
+fn parse_key_line(line: &str) -> Result<(PublicKey, AuthGrant)> {
+    let (access, key) = line
+        .split_once(char::is_whitespace)
+        .context("expected an access level and a key")?;
+    let access = match access {
+        "read" => AccessLevel::Read,
+        "write" => AccessLevel::Write,
+        other => bail!("unknown access level {other:?}"),
+    };
+    let key = PublicKey::from_openssh(key.trim_start()).context("invalid public key")?;
+    let identity = if key.comment().is_empty() {
+        fingerprint(&key)
+    } else {
+        key.comment().to_string()
+    };
+    let grant = AuthGrant {
+        identity: Some(identity),
+        access,
+    };
+    Ok((key, grant))
+}
+
+/// The SHA-256 fingerprint of `key`, as `ssh-keygen -l` shows it.
+pub fn fingerprint(key: &PublicKey) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
+
+/// State file the SSH keys users add are kept in.
+pub const KEYS_FILE: &str = "ssh_keys.json";
+
+/// A key a user added, as kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyRecord {
+    id: String,
+    username: String,
+    title: String,
+    /// The key in OpenSSH format
+    key: String,
+    /// The [`fingerprint`] of the key
+    fingerprint: String,
+    created_at: u64,
+}
+
+impl KeyRecord {
+    fn info(&self) -> KeyInfo {
+        KeyInfo {
+            id: self.id.clone(),
+            title: self.title.clone(),
+            fingerprint: self.fingerprint.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// An SSH key a user added.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct KeyInfo {
+    /// Names the key to remove it
+    pub id: String,
+    /// What the user called the key
+    pub title: String,
+    /// SHA-256 fingerprint, as `ssh-keygen -l` shows it
+    pub fingerprint: String,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+}
+
+/// The keys in an [`AuthorizedKeysFile`], and those users add through the
+/// API, which are kept in a [`StateStore`].
+///
+/// A user's key writes to the user's own repositories and reads others'.
+/// Keys are looked up on every use, so a removed key stops working at once.
+pub struct ManagedKeys {
+    fixed: AuthorizedKeysFile,
+    state: Arc<StateStore>,
+    records: RwLock<Vec<KeyRecord>>,
+}
+
+impl ManagedKeys {
+    /// Check the keys in `fixed`, and those kept in `state`.
+    pub fn open(fixed: AuthorizedKeysFile, state: Arc<StateStore>) -> Result<Self> {
+        let records = state.read(KEYS_FILE)?;
+        Ok(Self {
+            fixed,
+            state,
+            records: RwLock::new(records),
+        })
+    }
+
+    /// Number of keys users have added.
+    pub fn managed_len(&self) -> usize {
+        self.records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    /// The user who added the key with `fingerprint`, if any did.
+    pub fn lookup(&self, fingerprint: &str) -> Option<String> {
+        let records = self
+            .records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        records
+            .iter()
+            .find(|record| record.fingerprint == fingerprint)
+            .map(|record| record.username.clone())
+    }
+
+    /// The keys `username` added, oldest first.
+    pub fn list_keys(&self, username: &str) -> Vec<KeyInfo> {
+        let records = self
+            .records
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        records
+            .iter()
+            .filter(|record| record.username == username)
+            .map(KeyRecord::info)
+            .collect()
+    }
+
+    /// Add `key` for `username`, returning `None` if it is already added,
+    /// by them or anyone else: a key identifies one user.
+    pub fn add_key(&self, username: &str, title: &str, key: &PublicKey) -> Result<Option<KeyInfo>> {
+        let record = KeyRecord {
+            id: format!("{:016x}", rand::random::<u64>()),
+            username: username.to_string(),
+            title: title.to_string(),
+            key: key.to_openssh().context("failed to encode key")?,
+            fingerprint: fingerprint(key),
+            created_at: unix_now(),
+        };
+        let mut records = self
+            .records
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let listed = self.fixed.grant(key).is_some();
+        if listed
+            || records
+                .iter()
+                .any(|existing| existing.fingerprint == record.fingerprint)
+        {
+            return Ok(None);
+        }
+        let mut updated = records.clone();
+        updated.push(record.clone());
+        self.state.write(KEYS_FILE, &updated)?;
+        *records = updated;
+        Ok(Some(record.info()))
+    }
+
+    /// Remove `username`'s key `id`, returning `false` if they have no
+    /// such key.
+    pub fn remove_key(&self, username: &str, id: &str) -> Result<bool> {
+        let mut records = self
+            .records
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut updated = records.clone();
+        updated.retain(|record| !(record.username == username && record.id == id));
+        if updated.len() == records.len() {
+            return Ok(false);
+        }
+        self.state.write(KEYS_FILE, &updated)?;
+        *records = updated;
+        Ok(true)
+    }
+}
+
+impl std::fmt::Debug for ManagedKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedKeys")
+            .field("fixed", &self.fixed)
+            .field("managed", &self.managed_len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuthorizedKeys for ManagedKeys {
+    fn grant(&self, key: &PublicKey) -> Option<AuthGrant> {
+        if let Some(grant) = self.fixed.grant(key) {
+            return Some(grant);
+        }
+        let username = self.lookup(&fingerprint(key))?;
+        Some(AuthGrant {
+            identity: Some(username),
+            access: AccessLevel::Write,
+        })
+    }
+
+    fn grant_on(&self, key: &PublicKey, repo: &RepoRef) -> Option<AuthGrant> {
+        if let Some(grant) = self.fixed.grant(key) {
+            return Some(grant);
+        }
+        let username = self.lookup(&fingerprint(key))?;
+        let access = if username == repo.owner {
+            AccessLevel::Write
+        } else {
+            AccessLevel::Read
+        };
+        Some(AuthGrant {
+            identity: Some(username),
+            access,
+        })
+    }
+}
+
+/// Load the host key at `path`, generating and saving an Ed25519 key there
+/// if there is none yet, so clients see the same key across restarts.
+pub fn load_or_generate_host_key(path: &Path) -> Result<PrivateKey> {
+    if path.exists() {
+        return russh::keys::load_secret_key(path, None)
+            .with_context(|| format!("failed to load host key from {}", path.display()));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+        .context("failed to generate host key")?;
+    key.write_openssh_file(path, LineEnding::LF)
+        .with_context(|| format!("failed to save host key to {}", path.display()))?;
+    info!("generated SSH host key at {}", path.display());
+    Ok(key)
+}
+
+/// Parse an exec command of the form `forjj-sync '<owner>/<name>'`.
+pub fn parse_exec(command: &[u8]) -> Result<RepoRef> {
+    let command = std::str::from_utf8(command).context("command is not UTF-8")?;
+    let Some(argument) = command
+        .strip_prefix(SYNC_COMMAND)
+        .and_then(|rest| rest.strip_prefix(' '))
+    else {
+        bail!("unsupported command {command:?}");
+    };
+    let path = argument
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+        .unwrap_or(argument);
+    let Some((owner, name)) = path.split_once('/') else {
+        bail!("expected '<owner>/<name>', got {path:?}");
+    };
+    validate_name(owner).with_context(|| format!("invalid owner name: {owner:?}"))?;
+    validate_name(name).with_context(|| format!("invalid repository name: {name:?}"))?;
+    Ok(RepoRef::new(owner, name))
+}
+
+/// Whether `repo` is private. Repositories that don't exist aren't: the
+/// session answers for those.
+async fn is_private(provider: &Repos, repo: &RepoRef) -> bool {
+    let provider = provider.clone();
+    let repo = repo.clone();
+    tokio::task::spawn_blocking(move || {
+        if !provider.exists(&repo) {
+            return false;
+        }
+        let metadata = provider
+            .open(&repo)
+            .and_then(|opened| RepoMetadata::load(&opened));
+        Visibility::of(&metadata) == Visibility::Private
+    })
+    .await
+    .unwrap_or(true)
+}
+
+/// Accepts SSH connections and serves forjj-sync sessions over them.
+pub struct SshServer {
+    provider: Repos,
+    keys: Arc<dyn AuthorizedKeys>,
+    options: Arc<ServerOptions>,
+    /// The forjj-sync sessions being served
+    sessions: TaskTracker,
+}
+
+impl SshServer {
+    /// Serve repositories from `provider` to clients with keys in `keys`.
+    ///
+    /// `options.auth` is replaced by each connection's key grant.
+    pub fn new(provider: Repos, keys: Arc<dyn AuthorizedKeys>, options: ServerOptions) -> Self {
+        Self {
+            provider,
+            keys,
+            options: Arc::new(options),
+            sessions: TaskTracker::new(),
+        }
+    }
+
+    /// Accept connections on `listener` until it fails.
+    pub async fn run(self, listener: TcpListener, host_key: PrivateKey) -> Result<()> {
+        self.run_until(listener, host_key, std::future::pending())
+            .await
+    }
+
+    /// Accept connections on `listener` until it fails or `stopped`
+    /// resolves, then wait for the forjj-sync sessions in progress to end.
+    pub async fn run_until(
+        mut self,
+        listener: TcpListener,
+        host_key: PrivateKey,
+        stopped: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let config = russh::server::Config {
+            keys: vec![host_key],
+            auth_rejection_time: Duration::from_secs(1),
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            inactivity_timeout: self.options.idle_timeout,
+            ..Default::default()
+        };
+        let sessions = self.sessions.clone();
+        tokio::select! {
+            ran = self.run_on_socket(Arc::new(config), &listener) => ran?,
+            () = stopped => {}
+        }
+        sessions.close();
+        sessions.wait().await;
+        Ok(())
+    }
+}
+
+impl Server for SshServer {
+    type Handler = Connection;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> Connection {
+        Connection {
+            peer,
+            provider: self.provider.clone(),
+            keys: self.keys.clone(),
+            options: self.options.clone(),
+            sessions: self.sessions.clone(),
+            key: None,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+/// One client's SSH connection.
+pub struct Connection {
+    peer: Option<SocketAddr>,
+    provider: Repos,
+    keys: Arc<dyn AuthorizedKeys>,
+    options: Arc<ServerOptions>,
+    sessions: TaskTracker,
+    /// The client's key, once it has authenticated with it
+    key: Option<PublicKey>,
+    /// Session channels waiting for their exec request
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl russh::server::Handler for Connection {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(&mut self, _user: &str, key: &PublicKey) -> Result<Auth> {
+        match self.keys.grant(key) {
+            Some(grant) => {
+                debug!(peer = ?self.peer, identity = ?grant.identity, "SSH key accepted");
+                self.key = Some(key.clone());
+                Ok(Auth::Accept)
+            }
+            None => {
+                debug!(peer = ?self.peer, "SSH key rejected");
+                Ok(Auth::reject())
+            }
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<()> {
+        let (Some(key), Some(open)) = (self.key.clone(), self.channels.remove(&channel)) else {
+            session.channel_failure(channel)?;
+            return Ok(());
+        };
+        let repo = match parse_exec(data) {
+            Ok(repo) => repo,
+            Err(error) => {
+                warn!(peer = ?self.peer, "rejected SSH exec request: {error:#}");
+                session.channel_failure(channel)?;
+                session.close(channel)?;
+                return Ok(());
+            }
+        };
+        let Some(grant) = self.keys.grant_on(&key, &repo) else {
+            warn!(peer = ?self.peer, "SSH key removed since it authenticated");
+            session.channel_failure(channel)?;
+            session.close(channel)?;
+            return Ok(());
+        };
+        if grant.access == AccessLevel::Read && is_private(&self.provider, &repo).await {
+            debug!(peer = ?self.peer, identity = ?grant.identity, %repo, "SSH key may not see private repository");
+            session.channel_failure(channel)?;
+            session.close(channel)?;
+            return Ok(());
+        }
+        session.channel_success(channel)?;
+
+        // Everything the session logs names who it is for and on what.
+        let span = tracing::info_span!(
+            "ssh_session",
+            peer = ?self.peer,
+            identity = grant.identity.as_deref(),
+            %repo,
+        );
+        span.in_scope(|| info!("forjj-sync session started"));
+        let repos = self.provider.clone();
+        let options = self.options.clone();
+        let handle = session.handle();
+        let served = async move {
+            let stream = open.into_stream();
+            let status = match serve_transport(stream, repos, repo, grant, &options).await {
+                Ok(()) => {
+                    info!("forjj-sync session ended");
+                    0
+                }
+                Err(error) => {
+                    warn!("forjj-sync session failed: {error}");
+                    1
+                }
+            };
+            // The client may already have hung up.
+            let _ = handle.exit_status_request(channel, status).await;
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        };
+        self.sessions.spawn(served.instrument(span));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use forjj_protocol::{
+        Auth as HelloAuth, ClientOptions, ErrorCode, ForjjClient, ProtocolError, PushRequest,
+        PushStatus, RefTargetWire, RefUpdate, SshError, SshTarget, connect_ssh,
+    };
+    use forjj_storage::{CommitId, RepositoryManager, StorageConfig};
+    use tempfile::TempDir;
+
+    fn client_key(comment: &str) -> PrivateKey {
+        let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        key.set_comment(comment);
+        key
+    }
+
+    fn key_line(access: &str, key: &PrivateKey) -> String {
+        format!("{access} {}", key.public_key().to_openssh().unwrap())
+    }
+
+    #[test]
+    fn test_parse_exec() {
+        let repo = parse_exec(b"forjj-sync 'alice/project'").unwrap();
+        assert_eq!(repo, RepoRef::new("alice", "project"));
+        // Unquoted paths are fine too.
+        let repo = parse_exec(b"forjj-sync alice/project").unwrap();
+        assert_eq!(repo, RepoRef::new("alice", "project"));
+
+        for command in [
+            &b"git-upload-pack 'alice/project'"[..],
+            b"forjj-sync",
+            b"forjj-syncer 'alice/project'",
+            b"forjj-sync 'alice'",
+            b"forjj-sync '../etc/passwd'",
+            b"forjj-sync 'alice/a b'",
+            b"forjj-sync '\xff/project'",
+        ] {
+            assert!(parse_exec(command).is_err(), "{command:?}");
+        }
+    }
+
+    #[test]
+    fn test_authorized_keys_file() {
+        let alice = client_key("alice@laptop");
+        let ci = client_key("");
+        let text = format!(
+            "# keys\n\n{}\n{}\n",
+            key_line("write", &alice),
+            key_line("read", &ci)
+        );
+        let keys = AuthorizedKeysFile::parse(&text).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let grant = keys.grant(alice.public_key()).unwrap();
+        assert_eq!(grant.identity.as_deref(), Some("alice@laptop"));
+        assert_eq!(grant.access, AccessLevel::Write);
+        let grant = keys.grant(ci.public_key()).unwrap();
+        assert!(grant.identity.unwrap().starts_with("SHA256:"));
+        assert_eq!(grant.access, AccessLevel::Read);
+        assert!(keys.grant(client_key("mallory").public_key()).is_none());
+
+        let error = AuthorizedKeysFile::parse(&key_line("admin", &alice)).unwrap_err();
+        assert!(format!("{error:#}").contains("line 1"), "{error:#}");
+        assert!(AuthorizedKeysFile::parse("write ssh-ed25519 garbage").is_err());
+        assert!(AuthorizedKeysFile::parse("write").is_err());
+    }
+
+    #[test]
+    fn test_managed_keys() {
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(StateStore::open(dir.path()).unwrap());
+        let operator = client_key("operator");
+        let fixed = AuthorizedKeysFile::parse(&key_line("read", &operator)).unwrap();
+        let keys = ManagedKeys::open(fixed, state.clone()).unwrap();
+
+        let alice = client_key("alice@laptop");
+        let added = keys.add_key("alice", "laptop", alice.public_key()).unwrap();
+        let added = added.unwrap();
+        assert_eq!(added.fingerprint, fingerprint(alice.public_key()));
+        assert_eq!(keys.list_keys("alice"), [added.clone()]);
+        assert!(keys.list_keys("bob").is_empty());
+        // Keys already listed, by anyone, are refused.
+        assert_eq!(
+            keys.add_key("bob", "mine", alice.public_key()).unwrap(),
+            None
+        );
+        assert_eq!(
+            keys.add_key("bob", "mine", operator.public_key()).unwrap(),
+            None
+        );
+
+        // The lookup an SSH login goes through.
+        assert_eq!(keys.lookup(&added.fingerprint).as_deref(), Some("alice"));
+        let grant = keys.grant(alice.public_key()).unwrap();
+        assert_eq!(grant.identity.as_deref(), Some("alice"));
+        let own = RepoRef::new("alice", "project");
+        let others = RepoRef::new("bob", "project");
+        let grant = keys.grant_on(alice.public_key(), &own).unwrap();
+        assert_eq!(grant.access, AccessLevel::Write);
+        let grant = keys.grant_on(alice.public_key(), &others).unwrap();
+        assert_eq!(grant.access, AccessLevel::Read);
+        let grant = keys.grant_on(operator.public_key(), &own).unwrap();
+        assert_eq!(grant.identity.as_deref(), Some("operator"));
+        assert!(keys.grant(client_key("mallory").public_key()).is_none());
+
+        // Kept across restarts, until removed.
+        let keys = ManagedKeys::open(AuthorizedKeysFile::default(), state).unwrap();
+        assert_eq!(keys.managed_len(), 1);
+        assert!(!keys.remove_key("bob", &added.id).unwrap());
+        assert!(keys.remove_key("alice", &added.id).unwrap());
+        assert!(!keys.remove_key("alice", &added.id).unwrap());
+        assert_eq!(keys.lookup(&added.fingerprint), None);
+        assert!(keys.grant(alice.public_key()).is_none());
+    }
+
+    #[test]
+    fn test_host_key_persists() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keys").join("ssh_host_ed25519_key");
+        let generated = load_or_generate_host_key(&path).unwrap();
+        assert!(path.exists());
+        let loaded = load_or_generate_host_key(&path).unwrap();
+        assert_eq!(
+            loaded.public_key().key_data(),
+            generated.public_key().key_data()
+        );
+    }
+
+    /// A server on a localhost port with `alice/project` and the given
+    /// authorized keys.
+    struct TestServer {
+        _dir: TempDir,
+        repos: Arc<RepositoryManager>,
+        port: u16,
+        host_key: PublicKey,
+        head: CommitId,
+    }
+
+    impl TestServer {
+        async fn start(keys: Arc<dyn AuthorizedKeys>) -> Self {
+            let dir = TempDir::new().unwrap();
+            let repos = Arc::new(
+                RepositoryManager::new(StorageConfig {
+                    repos_root: dir.path().join("repos"),
+                })
+                .unwrap(),
+            );
+            let repo = repos.create_repo("alice", "project").unwrap();
+            let head = repo.head_ids().unwrap()[0];
+            let host_key = load_or_generate_host_key(&dir.path().join("host_key")).unwrap();
+            let public = host_key.public_key().clone();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = SshServer::new(repos.clone(), keys, ServerOptions::default());
+            tokio::spawn(server.run(listener, host_key));
+            Self {
+                _dir: dir,
+                repos,
+                port,
+                host_key: public,
+                head,
+            }
+        }
+
+        fn target(&self, key: PrivateKey) -> SshTarget {
+            SshTarget {
+                host: "127.0.0.1".to_string(),
+                port: self.port,
+                user: "forjj".to_string(),
+                key: Arc::new(key),
+                host_key: self.host_key.clone(),
+            }
+        }
+    }
+
+    fn create_main(head: &CommitId) -> PushRequest {
+        PushRequest {
+            have_ops: vec![],
+            updates: vec![RefUpdate {
+                ref_name: "main".to_string(),
+                old_id: RefTargetWire::Absent,
+                new_id: Some(head.to_hex()),
+                force: false,
+            }],
+            atomic: false,
+            operation_count: 0,
+            request_id: None,
+            push_id: None,
+        }
+    }
+
+    async fn connect(
+        target: &SshTarget,
+        access: AccessLevel,
+    ) -> Result<ForjjClient<forjj_protocol::SshStream>, ProtocolError> {
+        let repo = RepoRef::new("alice", "project");
+        let stream = connect_ssh(target, &repo).await.unwrap();
+        let options = ClientOptions::new(repo).with_auth(HelloAuth::None, access);
+        ForjjClient::connect(stream, options).await
+    }
+
+    #[tokio::test]
+    async fn test_push_over_ssh() {
+        let alice = client_key("alice@laptop");
+        let keys = AuthorizedKeysFile::parse(&key_line("write", &alice)).unwrap();
+        let server = TestServer::start(Arc::new(keys)).await;
+
+        let mut client = connect(&server.target(alice), AccessLevel::Write)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("alice@laptop"));
+        let result = client
+            .push(create_main(&server.head), &mut tokio::io::empty())
+            .await
+            .unwrap();
+        assert_eq!(result.status, PushStatus::Ok);
+        client.shutdown().await.unwrap();
+
+        let repo = server.repos.open_repo("alice", "project").unwrap();
+        let bookmarks = repo.bookmark_ids().unwrap();
+        assert_eq!(bookmarks, [("main".to_string(), server.head)]);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_key_cannot_push() {
+        let ci = client_key("ci");
+        let keys = AuthorizedKeysFile::parse(&key_line("read", &ci)).unwrap();
+        let server = TestServer::start(Arc::new(keys)).await;
+
+        let error = connect(&server.target(ci), AccessLevel::Write)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_is_rejected() {
+        let alice = client_key("alice@laptop");
+        let keys = AuthorizedKeysFile::parse(&key_line("write", &alice)).unwrap();
+        let server = TestServer::start(Arc::new(keys)).await;
+
+        let repo = RepoRef::new("alice", "project");
+        let error = connect_ssh(&server.target(client_key("mallory")), &repo)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, SshError::AuthRejected(_)), "{error}");
+
+        // A client expecting another host key refuses the server.
+        let mut target = server.target(alice);
+        target.host_key = client_key("impostor").public_key().clone();
+        let error = connect_ssh(&target, &repo).await.unwrap_err();
+        assert!(matches!(error, SshError::HostKeyMismatch), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_user_key_over_ssh() {
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(StateStore::open(dir.path()).unwrap());
+        let keys = Arc::new(ManagedKeys::open(AuthorizedKeysFile::default(), state).unwrap());
+        let alice = client_key("alice@laptop");
+        let bob = client_key("bob@laptop");
+        let added = keys
+            .add_key("alice", "laptop", alice.public_key())
+            .unwrap()
+            .unwrap();
+        keys.add_key("bob", "laptop", bob.public_key()).unwrap();
+        let server = TestServer::start(keys.clone()).await;
+
+        let mut client = connect(&server.target(alice.clone()), AccessLevel::Write)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("alice"));
+        client.shutdown().await.unwrap();
+
+        // Others' keys only read, and only public repositories.
+        let error = connect(&server.target(bob.clone()), AccessLevel::Write)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+        let opened = server.repos.open_repo("alice", "project").unwrap();
+        let mut metadata = RepoMetadata::new(None);
+        metadata.visibility = Visibility::Private;
+        metadata.store(&opened).unwrap();
+        let repo = RepoRef::new("alice", "project");
+        let refused = match connect_ssh(&server.target(bob), &repo).await {
+            Err(_) => true,
+            Ok(stream) => {
+                let options =
+                    ClientOptions::new(repo.clone()).with_auth(HelloAuth::None, AccessLevel::Read);
+                ForjjClient::connect(stream, options).await.is_err()
+            }
+        };
+        assert!(refused);
+        let mut client = connect(&server.target(alice.clone()), AccessLevel::Write)
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        // A removed key stops working without a restart.
+        keys.remove_key("alice", &added.id).unwrap();
+        let error = connect_ssh(&server.target(alice), &repo).await.unwrap_err();
+        assert!(matches!(error, SshError::AuthRejected(_)), "{error}");
+    }
+}
@@ -0,0 +1,109 @@
+//! State the server keeps outside repositories, such as the API tokens
+//! users make.
+//!
+//! Each kind of record is kept as one JSON file in the state directory,
+//! replaced whole when it changes, so readers never see half a write.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// A directory of JSON files the server keeps its own state in.
+#[derive(Debug)]
+pub struct StateStore {
+    dir: PathBuf,
+    /// Held while a file is replaced, so writes don't interleave
+    writes: Mutex<()>,
+}
+
+impl StateStore {
+    /// Open the state kept in `dir`, creating it if need be.
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            writes: Mutex::new(()),
+        })
+    }
+
+    /// The records kept in the file `name`, or the default if there is
+    /// no such file yet.
+    pub fn read<T: DeserializeOwned + Default>(&self, name: &str) -> Result<T> {
+        let path = self.dir.join(name);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read: {}", path.display()));
+            }
+        };
+        serde_json::from_slice(&data).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    /// Replace the file `name` with `records`.
+    pub fn write<T: Serialize>(&self, name: &str, records: &T) -> Result<()> {
+        let data = serde_json::to_vec_pretty(records)?;
+        let _guard = self
+            .writes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path = self.dir.join(name);
+        let temp = self.dir.join(format!(".{name}.tmp"));
+        std::fs::write(&temp, data)
+            .with_context(|| format!("failed to write: {}", temp.display()))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Check the directory is still there and every file in it can be
+    /// read, as opening the server's stores would.
+    pub fn check(&self) -> Result<()> {
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to read directory: {}", self.dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if hidden || path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let data = std::fs::read(&path)
+                .with_context(|| format!("failed to read: {}", path.display()))?;
+            serde_json::from_slice::<serde_json::Value>(&data)
+                .with_context(|| format!("invalid {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let store = StateStore::open(&dir.path().join("state")).unwrap();
+        let empty: Vec<String> = store.read("names.json").unwrap();
+        assert!(empty.is_empty());
+
+        store.write("names.json", &vec!["alice", "bob"]).unwrap();
+        let names: Vec<String> = store.read("names.json").unwrap();
+        assert_eq!(names, ["alice", "bob"]);
+
+        // Reopening finds what was written.
+        let store = StateStore::open(&dir.path().join("state")).unwrap();
+        let names: Vec<String> = store.read("names.json").unwrap();
+        assert_eq!(names.len(), 2);
+
+        std::fs::write(dir.path().join("state/names.json"), "not json").unwrap();
+        assert!(store.read::<Vec<String>>("names.json").is_err());
+    }
+}
@@ -0,0 +1,605 @@
+//! Raw TCP transport for forjj-sync, for mirroring between servers.
+//!
+//! The protocol runs directly on each connection. With no transport
+//! authentication, every session must present a bearer token in its Hello,
+//! checked by [`TokenAuth`]. Private repositories are hidden from tokens
+//! whose users may not see them. Connections past the cap are turned away
+//! with a retryable error, and reads or writes that stall past their
+//! deadline fail the session.
+//!
+//! With a Noise key configured, every connection starts with a Noise
+//! handshake and the session runs encrypted; clients pin the key's public
+//! half.
+
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::{Pin, pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use forjj_protocol::{
+    AccessLevel, AuthGrant, ErrorCode, ErrorMessage, NoiseKeypair, RepoProvider, RepoRef,
+    ServerOptions, WireFormat, close_with_error, noise_accept, serve_session,
+};
+use forjj_storage::Repository;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, Sleep};
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+use crate::auth::{TokenAuth, TokenStore};
+use crate::metadata::{RepoMetadata, Visibility};
+use crate::session::Repos;
+
+/// Default cap on concurrent connections.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Default time a read or write may stall.
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on a [`TcpServer`]'s connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpLimits {
+    /// Connections served at once
+    pub max_connections: usize,
+    /// Time a read may wait for data
+    pub read_timeout: Duration,
+    /// Time a write may wait for the peer to take data
+    pub write_timeout: Duration,
+}
+
+impl Default for TcpLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            read_timeout: DEFAULT_IO_TIMEOUT,
+            write_timeout: DEFAULT_IO_TIMEOUT,
+        }
+    }
+}
+
+/// Accepts TCP connections and serves forjj-sync sessions on them.
+pub struct TcpServer {
+    repos: Repos,
+    options: Arc<ServerOptions>,
+    limits: TcpLimits,
+    noise: Option<Arc<NoiseKeypair>>,
+}
+
+impl TcpServer {
+    /// Serve repositories from `repos` to clients with tokens in `tokens`.
+    ///
+    /// `options.auth` is replaced by a check against `tokens`.
+    pub fn new(
+        repos: Repos,
+        tokens: Arc<dyn TokenStore>,
+        options: ServerOptions,
+        limits: TcpLimits,
+    ) -> Self {
+        let options = ServerOptions {
+            auth: Arc::new(TokenAuth(tokens)),
+            ..options
+        };
+        Self {
+            repos: Arc::new(VisibleRepos(repos)),
+            options: Arc::new(options),
+            limits,
+            noise: None,
+        }
+    }
+
+    /// Encrypt every connection with Noise, as the server with `keypair`.
+    pub fn with_noise(mut self, keypair: NoiseKeypair) -> Self {
+        self.noise = Some(Arc::new(keypair));
+        self
+    }
+
+    /// Accept connections on `listener` until it fails.
+    pub async fn run(self, listener: TcpListener) -> Result<()> {
+        self.run_until(listener, std::future::pending()).await
+    }
+
+    /// Accept connections on `listener` until it fails or `stopped`
+    /// resolves, then wait for the sessions in progress to end.
+    pub async fn run_until(
+        self,
+        listener: TcpListener,
+        stopped: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let slots = Arc::new(Semaphore::new(self.limits.max_connections));
+        let sessions = TaskTracker::new();
+        let mut stopped = pin!(stopped);
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = &mut stopped => break,
+            };
+            let Ok(permit) = slots.clone().try_acquire_owned() else {
+                warn!(%peer, "refusing TCP connection: too many connections");
+                let mut stream = Deadlines::new(stream, self.limits);
+                let noise = self.noise.clone();
+                tokio::spawn(async move {
+                    let error = ErrorMessage::retryable(
+                        ErrorCode::QuotaExceeded,
+                        "too many connections; try again later",
+                    );
+                    match noise {
+                        Some(keypair) => {
+                            if let Ok(mut stream) = noise_accept(stream, &keypair).await {
+                                close_with_error(&mut stream, error, WireFormat::Json).await;
+                            }
+                        }
+                        None => close_with_error(&mut stream, error, WireFormat::Json).await,
+                    }
+                });
+                continue;
+            };
+            if let Err(error) = stream.set_nodelay(true) {
+                warn!(%peer, "failed to disable Nagle's algorithm: {error}");
+            }
+
+            info!(%peer, "forjj-sync session over TCP");
+            let repos = self.repos.clone();
+            let options = self.options.clone();
+            let stream = Deadlines::new(stream, self.limits);
+            let noise = self.noise.clone();
+            sessions.spawn(async move {
+                let served = match noise {
+                    Some(keypair) => match noise_accept(stream, &keypair).await {
+                        Ok(stream) => serve_session(stream, repos.as_ref(), &options).await,
+                        Err(error) => {
+                            warn!(%peer, "Noise handshake failed: {error}");
+                            Ok(())
+                        }
+                    },
+                    None => serve_session(stream, repos.as_ref(), &options).await,
+                };
+                if let Err(error) = served {
+                    warn!(%peer, "forjj-sync session failed: {error}");
+                }
+                drop(permit);
+            });
+        }
+        sessions.close();
+        sessions.wait().await;
+        Ok(())
+    }
+}
+
+/// The server's repositories, with private ones hidden from sessions whose
+/// users may not see them.
+struct VisibleRepos(Repos);
+
+impl RepoProvider for VisibleRepos {
+    fn exists(&self, repo: &RepoRef) -> bool {
+        self.0.exists(repo)
+    }
+
+    fn open(&self, repo: &RepoRef) -> Result<Repository> {
+        self.0.open(repo)
+    }
+
+    fn create(&self, repo: &RepoRef) -> Result<Repository> {
+        self.0.create(repo)
+    }
+
+    fn delete(&self, repo: &RepoRef) -> Result<()> {
+        self.0.delete(repo)
+    }
+
+    fn may_see(&self, repo: &RepoRef, grant: &AuthGrant) -> bool {
+        // Users who may change a repository see it: its owner, and admins,
+        // who are granted write access to every repository.
+        if grant.access == AccessLevel::Write
+            || grant.identity.as_deref() == Some(repo.owner.as_str())
+        {
+            return true;
+        }
+        let metadata = self
+            .0
+            .open(repo)
+            .and_then(|opened| RepoMetadata::load(&opened));
+        Visibility::of(&metadata) == Visibility::Public
+    }
+}
+
+/// Load the Noise key at `path`, or generate and save one if it doesn't
+/// exist.
+pub fn load_or_generate_noise_key(path: &Path) -> Result<NoiseKeypair> {
+    if path.exists() {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read Noise key from {}", path.display()))?;
+        return NoiseKeypair::from_hex(&text)
+            .with_context(|| format!("failed to load Noise key from {}", path.display()));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+    let keypair = NoiseKeypair::generate();
+    write_private(path, &format!("{}\n", keypair.to_hex()))
+        .with_context(|| format!("failed to save Noise key to {}", path.display()))?;
+    info!("generated Noise key at {}", path.display());
+    Ok(keypair)
+}
+
+/// Write `contents` to a new file only its owner can read.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// A stream whose reads and writes fail with [`io::ErrorKind::TimedOut`]
+/// once they have waited longer than their deadline.
+struct Deadlines<S> {
+    inner: S,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Deadlines<S> {
+    fn new(inner: S, limits: TcpLimits) -> Self {
+        Self {
+            inner,
+            read_timeout: limits.read_timeout,
+            write_timeout: limits.write_timeout,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+}
+
+/// Settle a pending operation against its deadline: clear the deadline once
+/// the operation finishes, start it when the operation first waits, and
+/// fail the operation when it passes.
+fn check_deadline<T>(
+    poll: Poll<io::Result<T>>,
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    if poll.is_ready() {
+        *sleep = None;
+        return poll;
+    }
+    let sleep = sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            sleep.as_mut().reset(Instant::now() + timeout);
+            Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connection stalled for {timeout:?}"),
+            )))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deadlines<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        check_deadline(poll, &mut this.read_sleep, this.read_timeout, cx)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deadlines<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        check_deadline(poll, &mut this.write_sleep, this.write_timeout, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        check_deadline(poll, &mut this.write_sleep, this.write_timeout, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenFile;
+    use crate::shutdown::Shutdown;
+    use forjj_protocol::{
+        Auth, ClientOptions, ForjjClient, NoiseStream, ProtocolError, connect_tcp, noise_connect,
+    };
+    use forjj_storage::{RepositoryManager, StorageConfig};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// A server for `alice/project`, with `options`.
+    fn server(dir: &TempDir, options: ServerOptions, limits: TcpLimits) -> TcpServer {
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        repos.create_repo("alice", "project").unwrap();
+        let tokens =
+            TokenFile::parse("read mirror fj_mirror\nwrite alice fj_alice\nwrite bob fj_bob")
+                .unwrap();
+        TcpServer::new(Arc::new(repos), Arc::new(tokens), options, limits)
+    }
+
+    /// A listener on a localhost port with `alice/project`, encrypted with
+    /// `noise` if given, returning its address.
+    async fn start(
+        dir: &TempDir,
+        limits: TcpLimits,
+        noise: Option<NoiseKeypair>,
+    ) -> std::net::SocketAddr {
+        let mut server = server(dir, ServerOptions::default(), limits);
+        if let Some(keypair) = noise {
+            server = server.with_noise(keypair);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.run(listener));
+        addr
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        auth: Auth,
+    ) -> Result<ForjjClient<TcpStream>, ProtocolError> {
+        let repo = RepoRef::new("alice", "project");
+        connect_to(addr, repo, auth, AccessLevel::Read).await
+    }
+
+    /// Start a session on `repo`, asking for `access`.
+    async fn connect_to(
+        addr: std::net::SocketAddr,
+        repo: RepoRef,
+        auth: Auth,
+        access: AccessLevel,
+    ) -> Result<ForjjClient<TcpStream>, ProtocolError> {
+        let stream = connect_tcp(addr).await.unwrap();
+        let options = ClientOptions::new(repo).with_auth(auth, access);
+        ForjjClient::connect(stream, options).await
+    }
+
+    #[tokio::test]
+    async fn test_hello_over_tcp() {
+        let dir = TempDir::new().unwrap();
+        let addr = start(&dir, TcpLimits::default(), None).await;
+
+        let token = Auth::BearerToken("fj_mirror".to_string());
+        let client = connect(addr, token).await.unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("mirror"));
+        assert_eq!(client.hello().access, Some(AccessLevel::Read));
+        client.shutdown().await.unwrap();
+
+        // There is no anonymous access.
+        let error = connect(addr, Auth::None).await.unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn test_writes_only_to_own_repositories() {
+        let dir = TempDir::new().unwrap();
+        let addr = start(&dir, TcpLimits::default(), None).await;
+        let repo = RepoRef::new("alice", "project");
+
+        let alice = Auth::BearerToken("fj_alice".to_string());
+        let client = connect_to(addr, repo.clone(), alice, AccessLevel::Write)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().access, Some(AccessLevel::Write));
+        client.shutdown().await.unwrap();
+
+        // Bob's write token only reads Alice's repository, so can't push.
+        let bob = Auth::BearerToken("fj_bob".to_string());
+        let error = connect_to(addr, repo.clone(), bob.clone(), AccessLevel::Write)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+        let client = connect_to(addr, repo, bob, AccessLevel::Read)
+            .await
+            .unwrap();
+        assert_eq!(client.hello().access, Some(AccessLevel::Read));
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_private_repositories_hidden() {
+        let dir = TempDir::new().unwrap();
+        let repos = RepositoryManager::new(StorageConfig {
+            repos_root: dir.path().to_path_buf(),
+        })
+        .unwrap();
+        let opened = repos.create_repo("alice", "secret").unwrap();
+        let mut metadata = RepoMetadata::new(None);
+        metadata.visibility = Visibility::Private;
+        metadata.store(&opened).unwrap();
+        let addr = start(&dir, TcpLimits::default(), None).await;
+        let secret = RepoRef::new("alice", "secret");
+
+        // Others are told it doesn't exist, as for a repository that doesn't.
+        for token in ["fj_mirror", "fj_bob"] {
+            let auth = Auth::BearerToken(token.to_string());
+            let error = connect_to(addr, secret.clone(), auth, AccessLevel::Read)
+                .await
+                .unwrap_err();
+            assert_eq!(error.remote_code(), Some(ErrorCode::NotFound));
+        }
+        let bob = Auth::BearerToken("fj_bob".to_string());
+        let missing = RepoRef::new("alice", "missing");
+        let error = connect_to(addr, missing, bob, AccessLevel::Read)
+            .await
+            .unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::NotFound));
+
+        // Asking to create it is refused just as for a missing repository.
+        for name in ["secret", "missing"] {
+            let stream = connect_tcp(addr).await.unwrap();
+            let options = ClientOptions::new(RepoRef::new("alice", name))
+                .with_auth(Auth::BearerToken("fj_bob".to_string()), AccessLevel::Read)
+                .with_create_if_missing();
+            let error = ForjjClient::connect(stream, options).await.unwrap_err();
+            assert_eq!(error.remote_code(), Some(ErrorCode::PermissionDenied));
+        }
+
+        let alice = Auth::BearerToken("fj_alice".to_string());
+        let client = connect_to(addr, secret, alice, AccessLevel::Read)
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hello_over_noise() {
+        let dir = TempDir::new().unwrap();
+        let keypair = NoiseKeypair::generate();
+        let server_key = *keypair.public_key();
+        let addr = start(&dir, TcpLimits::default(), Some(keypair)).await;
+
+        let connect_noise = |pinned: [u8; 32]| async move {
+            let stream = connect_tcp(addr).await.unwrap();
+            let stream = noise_connect(stream, &NoiseKeypair::generate(), &pinned).await?;
+            let options = ClientOptions::new(RepoRef::new("alice", "project")).with_auth(
+                Auth::BearerToken("fj_mirror".to_string()),
+                AccessLevel::Read,
+            );
+            let client: ForjjClient<NoiseStream<TcpStream>> =
+                ForjjClient::connect(stream, options).await.unwrap();
+            Ok::<_, forjj_protocol::NoiseError>(client)
+        };
+        let client = connect_noise(server_key).await.unwrap();
+        assert_eq!(client.hello().identity.as_deref(), Some("mirror"));
+        client.shutdown().await.unwrap();
+
+        // A server presenting another key is refused.
+        let other = *NoiseKeypair::generate().public_key();
+        assert!(connect_noise(other).await.is_err());
+
+        // Cleartext clients don't get a session.
+        let token = Auth::BearerToken("fj_mirror".to_string());
+        connect(addr, token).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_load_or_generate_noise_key() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keys/tcp_noise_key");
+        let generated = load_or_generate_noise_key(&path).unwrap();
+        let loaded = load_or_generate_noise_key(&path).unwrap();
+        assert_eq!(loaded, generated);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_cap() {
+        let dir = TempDir::new().unwrap();
+        let limits = TcpLimits {
+            max_connections: 1,
+            ..TcpLimits::default()
+        };
+        let addr = start(&dir, limits, None).await;
+
+        let token = Auth::BearerToken("fj_mirror".to_string());
+        let first = connect(addr, token.clone()).await.unwrap();
+        let error = connect(addr, token.clone()).await.unwrap_err();
+        assert_eq!(error.remote_code(), Some(ErrorCode::QuotaExceeded));
+
+        // The slot frees up once the first session ends.
+        first.shutdown().await.unwrap();
+        let mut retries = 0;
+        let second = loop {
+            match connect(addr, token.clone()).await {
+                Ok(client) => break client,
+                Err(error) if retries < 50 => {
+                    assert_eq!(error.remote_code(), Some(ErrorCode::QuotaExceeded));
+                    retries += 1;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                Err(error) => panic!("slot was not freed: {error}"),
+            }
+        };
+        second.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadlines() {
+        let limits = TcpLimits {
+            max_connections: 1,
+            read_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(5),
+        };
+        let (near, mut far) = tokio::io::duplex(4);
+        let mut stream = Deadlines::new(near, limits);
+
+        // Data that arrives in time is read as usual.
+        far.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        let start = Instant::now();
+        let error = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // The pipe holds four bytes; nobody reads the rest.
+        let error = stream.write_all(&[0; 8]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let shutdown = Shutdown::new(Duration::from_secs(30));
+        let options = ServerOptions {
+            shutdown: shutdown.sessions(),
+            ..ServerOptions::default()
+        };
+        let server = server(&dir, options, TcpLimits::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let running = tokio::spawn(server.run_until(listener, shutdown.stopped()));
+
+        // A session between requests is ended, rather than waited on for as
+        // long as the client keeps it open.
+        let token = Auth::BearerToken("fj_mirror".to_string());
+        let client = connect(addr, token).await.unwrap();
+        shutdown.begin();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        drop(client);
+
+        // No one is listening any more.
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Serving the API over TLS, for deployments without a proxy in front.
+//!
+//! The certificate is loaded with rustls from the PEM files named in the
+//! config, and can be replaced without a restart: on SIGHUP the files are
+//! read again, as after a renewal, and new connections get the new
+//! certificate. If the new files are bad, the old certificate stays in
+//! use. Handshakes happen off the accept loop, so a client that stalls in
+//! one doesn't hold up others.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+
+use crate::config::TlsOptions;
+
+/// How long a client has to finish its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections that have finished their handshake but not yet been taken
+/// by the server.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// The certificate the server presents, which can be reloaded from its
+/// files while the server runs.
+#[derive(Debug)]
+pub struct TlsCertificate {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCertificate {
+    /// Load the certificate and key named in `options`, failing if either
+    /// can't be read or they don't match.
+    pub fn load(options: &TlsOptions) -> Result<Arc<Self>> {
+        let current = load_certified_key(&options.cert, &options.key)?;
+        Ok(Arc::new(Self {
+            cert: options.cert.clone(),
+            key: options.key.clone(),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Read the files again, keeping the certificate in use if they are bad.
+    pub fn reload(&self) -> Result<()> {
+        let reloaded = load_certified_key(&self.cert, &self.key)?;
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(reloaded);
+        Ok(())
+    }
+
+    /// Call [`reload`](Self::reload) on each SIGHUP, for as long as the
+    /// server runs.
+    pub async fn reload_on_hangup(self: Arc<Self>) -> Result<()> {
+        let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        while hangups.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("Reloaded TLS certificate {}", self.cert.display()),
+                Err(error) => warn!("keeping the TLS certificate in use: {error:#}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// The rustls config for the API, presenting this certificate.
+    pub fn server_config(self: &Arc<Self>) -> Result<Arc<rustls::ServerConfig>> {
+        let mut config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for TlsCertificate {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let current = self
+            .current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Some(current.clone())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// The certificate chain in the PEM file `cert`, with the private key in
+/// the PEM file `key`.
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("cannot read TLS certificate {}", cert.display()))?;
+    if chain.is_empty() {
+        bail!("TLS certificate {} has no certificates", cert.display());
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("cannot read TLS private key {}", key.display()))?;
+    let signing_key = provider()
+        .key_provider
+        .load_private_key(private_key)
+        .with_context(|| format!("unsupported TLS private key {}", key.display()))?;
+    let certified = CertifiedKey::new(chain, signing_key);
+    certified.keys_match().with_context(|| {
+        format!(
+            "TLS certificate {} does not match private key {}",
+            cert.display(),
+            key.display()
+        )
+    })?;
+    Ok(certified)
+}
+
+/// A listener for [`axum::serve`] yielding connections that have finished
+/// their TLS handshake.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    /// Accept connections on `listener`, handshaking with `config`.
+    pub fn new(listener: TcpListener, config: Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_loop(listener, TlsAcceptor::from(config), sender));
+        Ok(Self {
+            local_addr,
+            accepted,
+        })
+    }
+}
+
+/// Accept TCP connections until the listener is dropped, handshaking with
+/// each on a task of its own.
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    while !sender.is_closed() {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                // Such as running out of file descriptors; wait for some to
+                // be closed.
+                warn!("failed to accept connection: {error}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender.send((stream, address)).await;
+                }
+                Ok(Err(error)) => debug!(%address, "TLS handshake failed: {error}"),
+                Err(_) => debug!(%address, "TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only ends once this is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::get;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty};
+    use hyper_util::rt::TokioIo;
+    use rustls::pki_types::ServerName;
+    use tempfile::TempDir;
+    use tokio_rustls::TlsConnector;
+
+    /// A new self-signed certificate for `localhost`, written to `dir`.
+    fn write_certificate(dir: &TempDir) -> (TlsOptions, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let options = TlsOptions {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        std::fs::write(&options.cert, generated.cert.pem()).unwrap();
+        std::fs::write(&options.key, generated.key_pair.serialize_pem()).unwrap();
+        (options, generated.cert.der().clone())
+    }
+
+    /// GET `/` over TLS from `address`, trusting only `trusted`, and return
+    /// the body and the certificate the server presented.
+    async fn get(
+        address: SocketAddr,
+        trusted: &CertificateDer<'static>,
+    ) -> (String, CertificateDer<'static>) {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let presented = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = axum::http::Request::get("/")
+            .header("host", "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert!(response.status().is_success());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (String::from_utf8(body.to_vec()).unwrap(), presented)
+    }
+
+    #[tokio::test]
+    async fn test_serve_over_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let (options, first) = write_certificate(&dir);
+        let certificate = TlsCertificate::load(&options).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, certificate.server_config().unwrap()).unwrap();
+        let address = axum::serve::Listener::local_addr(&listener).unwrap();
+        let app = Router::new().route("/", get(|| async { "forjj" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (body, presented) = get(address, &first).await;
+        assert_eq!(body, "forjj");
+        assert_eq!(presented, first);
+
+        // New connections get a reloaded certificate.
+        let (_, second) = write_certificate(&dir);
+        certificate.reload().unwrap();
+        let (_, presented) = get(address, &second).await;
+        assert_eq!(presented, second);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (options, _) = write_certificate(&dir);
+
+        let missing = TlsOptions {
+            cert: dir.path().join("missing.pem"),
+            ..options.clone()
+        };
+        let error = format!("{:#}", TlsCertificate::load(&missing).unwrap_err());
+        assert!(error.contains("cannot read TLS certificate"), "{error}");
+
+        // The key of another certificate.
+        let other = tempfile::tempdir().unwrap();
+        let (other, _) = write_certificate(&other);
+        let mismatched = TlsOptions {
+            key: other.key,
+            ..options.clone()
+        };
+        let error = format!("{:#}", TlsCertificate::load(&mismatched).unwrap_err());
+        assert!(error.contains("does not match"), "{error}");
+
+        // A bad reload keeps the certificate in use.
+        let certificate = TlsCertificate::load(&options).unwrap();
+        let before = certificate.current.read().unwrap().clone();
+        std::fs::write(&options.key, "not a key").unwrap();
+        assert!(certificate.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &certificate.current.read().unwrap()));
+    }
+}
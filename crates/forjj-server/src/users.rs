@@ -0,0 +1,105 @@
+//! Accounts of the people who use the server.
+//!
+//! Storage knows nothing of users. Who may change a repository is the
+//! server's policy: its owner, or an admin; see
+//! [`AuthenticatedUser::may_change`](crate::auth::AuthenticatedUser::may_change).
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::state::StateStore;
+
+/// State file the accounts are kept in.
+pub const USERS_FILE: &str = "users.json";
+
+/// An account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct User {
+    /// Also the owner name of the user's repositories
+    pub username: String,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+}
+
+/// The accounts, kept in a [`StateStore`].
+#[derive(Debug)]
+pub struct UserStore {
+    state: Arc<StateStore>,
+    users: RwLock<Vec<User>>,
+}
+
+impl UserStore {
+    /// Open the accounts kept in `state`.
+    pub fn open(state: Arc<StateStore>) -> Result<Self> {
+        let users = state.read(USERS_FILE)?;
+        Ok(Self {
+            state,
+            users: RwLock::new(users),
+        })
+    }
+
+    /// The account named `username`, if there is one.
+    pub fn get(&self, username: &str) -> Option<User> {
+        let users = self
+            .users
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        users.iter().find(|user| user.username == username).cloned()
+    }
+
+    /// Number of accounts.
+    pub fn len(&self) -> usize {
+        self.users
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    /// Add `user`, returning `false` if there already is an account by
+    /// that name.
+    pub fn create(&self, user: User) -> Result<bool> {
+        let mut users = self
+            .users
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if users
+            .iter()
+            .any(|existing| existing.username == user.username)
+        {
+            return Ok(false);
+        }
+        let mut updated = users.clone();
+        updated.push(user);
+        self.state.write(USERS_FILE, &updated)?;
+        *users = updated;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_user_store() {
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(StateStore::open(dir.path()).unwrap());
+        let users = UserStore::open(state.clone()).unwrap();
+        let bob = User {
+            username: "bob".to_string(),
+            created_at: 1_700_000_000,
+        };
+        assert!(users.create(bob.clone()).unwrap());
+        assert!(!users.create(bob.clone()).unwrap());
+        assert_eq!(users.get("bob"), Some(bob.clone()));
+        assert_eq!(users.get("alice"), None);
+
+        let users = UserStore::open(state).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users.get("bob"), Some(bob));
+    }
+}
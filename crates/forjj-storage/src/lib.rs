@@ -6,9 +6,17 @@
 pub mod object_id;
 pub mod repository;
 
-pub use object_id::{ChangeId, CommitId, FileId, ObjectId, OperationId, TreeId, ViewId};
+pub use object_id::{
+    ChangeId, CommitId, DisplayShort, FileId, ObjectHasher, ObjectId, OperationId, TreeId, ViewId,
+    change_id_prefix_to_hex,
+};
 pub use repository::{
-    BackendType, RepoInfo, Repository, RepositoryManager, StorageConfig, TreeEntry, TreeEntryKind,
+    BackendType, BlameRange, BookmarkNameError, BookmarkTarget, BookmarkUpdate, CommitLog,
+    CommitObjects, CommitStats, CommitSummary, FileChange, FileChangeKind, FileHistory,
+    FileHistoryEntry, FileSize, InvalidBookmarkName, LocalRef, MAX_BOOKMARK_NAME_LEN, MAX_NAME_LEN,
+    NameError, OperationEntry, PathValue, PrefixResolution, RawObjectKind, RepoInfo, Repository,
+    RepositoryManager, StaleBookmark, StorageConfig, StorageError, TreeEntry, TreeEntryKind,
+    validate_bookmark_name, validate_name,
 };
 
 /// Re-export jj-lib for direct access when needed
@@ -1,72 +1,242 @@
 //! Object ID types for content-addressed storage.
 //!
-//! jj uses BLAKE2b-256 for content addressing. These types wrap the raw bytes
-//! and provide convenience methods for hex encoding/decoding.
+//! Forjj uses BLAKE2b-256 for content addressing. jj-lib ids are
+//! backend-dependent (64-byte BLAKE2b-512 for the native backend, 20-byte SHA-1
+//! for git, 16 bytes for change IDs), so these types wrap a variable-length
+//! byte string and provide convenience methods for hex encoding/decoding.
 
 use blake2::digest::consts::U32;
 use blake2::{Blake2b, Digest};
 
 type Blake2b256 = Blake2b<U32>;
-use serde::{Deserialize, Serialize};
+use jj_lib::object_id::ObjectId as _;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt};
+#[cfg(feature = "zeroize")]
+use zeroize::Zeroize;
 
-/// Length of object IDs in bytes (BLAKE2b-256 = 32 bytes)
+/// Length of forjj-computed object IDs in bytes (BLAKE2b-256 = 32 bytes)
 pub const HASH_LEN: usize = 32;
 
+/// Length of jj change IDs in bytes.
+pub const CHANGE_ID_LEN: usize = 16;
+
+/// Length of git-backend object IDs in bytes (SHA-1 = 20 bytes)
+pub const GIT_HASH_LEN: usize = 20;
+
+/// Length of native jj backend object IDs in bytes (BLAKE2b-512 = 64 bytes)
+pub const NATIVE_HASH_LEN: usize = 64;
+
+/// Maximum object ID length in bytes.
+pub const MAX_ID_LEN: usize = NATIVE_HASH_LEN;
+
+/// ID lengths in bytes accepted by [`ObjectId`].
+pub const SUPPORTED_ID_LENS: [usize; 4] = [CHANGE_ID_LEN, GIT_HASH_LEN, HASH_LEN, NATIVE_HASH_LEN];
+
 /// Generic content-addressed object identifier.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct ObjectId([u8; HASH_LEN]);
+///
+/// Holds any of the [`SUPPORTED_ID_LENS`] inline, so ids from both the native
+/// and git backends can be carried without allocating.
+///
+/// Serializes as a hex string in human-readable formats (e.g. JSON) and as raw
+/// bytes in binary formats. Ordering is lexicographic over the bytes, and
+/// hashing matches the underlying byte slice so maps keyed by `ObjectId` can be
+/// queried with `&[u8]`.
+#[derive(Clone, Copy)]
+pub struct ObjectId {
+    len: u8,
+    bytes: [u8; MAX_ID_LEN],
+}
 
 impl ObjectId {
-    /// Create an ObjectId from raw bytes.
+    /// The all-zero 32-byte ID, for use as a sentinel.
+    pub const ZERO: Self = Self {
+        len: HASH_LEN as u8,
+        bytes: [0u8; MAX_ID_LEN],
+    };
+
+    /// Create an ObjectId from raw BLAKE2b-256 bytes.
     pub fn from_bytes(bytes: [u8; HASH_LEN]) -> Self {
-        Self(bytes)
+        let mut id = Self::ZERO;
+        id.bytes[..HASH_LEN].copy_from_slice(&bytes);
+        id
     }
 
-    /// Create an ObjectId from a byte slice.
+    /// Create an ObjectId from a byte slice of a supported length.
     pub fn from_slice(slice: &[u8]) -> Result<Self, ObjectIdError> {
-        if slice.len() != HASH_LEN {
+        if !SUPPORTED_ID_LENS.contains(&slice.len()) {
             return Err(ObjectIdError::InvalidLength {
-                expected: HASH_LEN,
                 actual: slice.len(),
             });
         }
-        let mut bytes = [0u8; HASH_LEN];
-        bytes.copy_from_slice(slice);
-        Ok(Self(bytes))
+        let mut bytes = [0u8; MAX_ID_LEN];
+        bytes[..slice.len()].copy_from_slice(slice);
+        Ok(Self {
+            len: slice.len() as u8,
+            bytes,
+        })
     }
 
-    /// Create an ObjectId from a hex string.
+    /// Create an ObjectId from a hex string of a supported length.
     pub fn from_hex(hex: &str) -> Result<Self, ObjectIdError> {
-        if hex.len() != HASH_LEN * 2 {
-            return Err(ObjectIdError::InvalidHexLength {
-                expected: HASH_LEN * 2,
-                actual: hex.len(),
-            });
+        if hex.len() % 2 != 0 || !SUPPORTED_ID_LENS.contains(&(hex.len() / 2)) {
+            return Err(ObjectIdError::InvalidHexLength { actual: hex.len() });
         }
-        let mut bytes = [0u8; HASH_LEN];
-        hex::decode_to_slice(hex, &mut bytes).map_err(|_| ObjectIdError::InvalidHexCharacter)?;
-        Ok(Self(bytes))
+        let len = hex.len() / 2;
+        let mut bytes = [0u8; MAX_ID_LEN];
+        hex::decode_to_slice(hex, &mut bytes[..len])
+            .map_err(|_| ObjectIdError::InvalidHexCharacter)?;
+        Ok(Self {
+            len: len as u8,
+            bytes,
+        })
+    }
+
+    /// Create an ObjectId from jj's reverse-hex encoding.
+    ///
+    /// jj displays change IDs using the alphabet `z-k` in place of `0-9a-f`.
+    pub fn from_reverse_hex(reverse_hex: &str) -> Result<Self, ObjectIdError> {
+        let hex = reverse_hex
+            .chars()
+            .map(reverse_hex_to_hex)
+            .collect::<Option<String>>()
+            .ok_or(ObjectIdError::InvalidReverseHexCharacter)?;
+        Self::from_hex(&hex)
+    }
+
+    /// Convert to jj's reverse-hex encoding, as used for change IDs.
+    pub fn to_reverse_hex(&self) -> String {
+        self.to_hex()
+            .chars()
+            .filter_map(hex_to_reverse_hex)
+            .collect()
     }
 
     /// Get the raw bytes.
-    pub fn as_bytes(&self) -> &[u8; HASH_LEN] {
-        &self.0
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.byte_len()]
+    }
+
+    /// Compare two IDs in constant time.
+    ///
+    /// The derived `==` may short-circuit on the first differing byte. Any
+    /// comparison where either side is derived from a secret (session tokens
+    /// for resumable transfers, API token hashes) must use this instead. The
+    /// ID length is not treated as secret.
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+
+    /// Get the length of the ID in bytes.
+    pub fn byte_len(&self) -> usize {
+        usize::from(self.len)
     }
 
     /// Convert to hex string.
     pub fn to_hex(&self) -> String {
-        hex::encode(self.0)
+        hex::encode(self.as_bytes())
+    }
+
+    /// Convert to an abbreviated hex string of `len` characters.
+    ///
+    /// `len` is clamped to the range `[1, 2 * byte_len()]`.
+    pub fn short(&self, len: usize) -> String {
+        self.display_short(len).to_string()
+    }
+
+    /// Get an adapter that displays the first `len` hex characters.
+    ///
+    /// `len` is clamped to the range `[1, 2 * byte_len()]`.
+    pub fn display_short(&self, len: usize) -> DisplayShort<'_> {
+        DisplayShort {
+            id: self,
+            len: len.clamp(1, self.byte_len() * 2),
+        }
     }
 
     /// Hash data to produce an ObjectId.
     pub fn hash(data: &[u8]) -> Self {
-        let mut hasher = Blake2b256::new();
+        let mut hasher = ObjectHasher::new();
         hasher.update(data);
-        let result = hasher.finalize();
+        hasher.finalize()
+    }
+
+    /// Hash everything readable from `reader` to produce an ObjectId.
+    pub fn hash_reader(mut reader: impl Read) -> std::io::Result<Self> {
+        let mut hasher = ObjectHasher::new();
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        #[cfg(feature = "zeroize")]
+        buffer.zeroize();
+        Ok(hasher.finalize())
+    }
+
+    /// Hash everything readable from an async `reader` to produce an ObjectId.
+    pub async fn hash_async_reader(mut reader: impl AsyncRead + Unpin) -> std::io::Result<Self> {
+        let mut hasher = ObjectHasher::new();
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        #[cfg(feature = "zeroize")]
+        buffer.zeroize();
+        Ok(hasher.finalize())
+    }
+}
+
+/// Buffer size used when hashing from a reader.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Incremental hasher producing an [`ObjectId`].
+///
+/// Feeding data in chunks via [`update`](Self::update) produces the same ID as
+/// [`ObjectId::hash`] over the concatenated chunks.
+#[derive(Clone, Default)]
+pub struct ObjectHasher {
+    inner: Blake2b256,
+}
+
+impl ObjectHasher {
+    /// Create a new hasher with no data.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed more data into the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Consume the hasher and produce the final ObjectId.
+    pub fn finalize(self) -> ObjectId {
+        let result = self.inner.finalize();
         let mut bytes = [0u8; HASH_LEN];
         bytes.copy_from_slice(&result);
-        Self(bytes)
+        ObjectId::from_bytes(bytes)
+    }
+}
+
+impl fmt::Debug for ObjectHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjectHasher").finish_non_exhaustive()
     }
 }
 
@@ -76,23 +246,185 @@ impl fmt::Display for ObjectId {
     }
 }
 
+impl PartialEq for ObjectId {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for ObjectId {}
+
+impl PartialOrd for ObjectId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ObjectId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for ObjectId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state);
+    }
+}
+
+impl AsRef<[u8]> for ObjectId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Borrow<[u8]> for ObjectId {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = ObjectIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+impl Serialize for ObjectId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_hex())
+        } else {
+            serializer.serialize_bytes(self.as_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ObjectIdVisitor)
+        } else {
+            deserializer.deserialize_bytes(ObjectIdVisitor)
+        }
+    }
+}
+
+/// Serde visitor accepting hex strings, raw bytes, and the legacy integer
+/// array representation.
+struct ObjectIdVisitor;
+
+impl<'de> Visitor<'de> for ObjectIdVisitor {
+    type Value = ObjectId;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a hex string or byte string of length {SUPPORTED_ID_LENS:?} bytes"
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        ObjectId::from_hex(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        ObjectId::from_slice(v).map_err(E::custom)
+    }
+
+    // TODO: Drop the legacy array form once stored data has been rewritten.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(MAX_ID_LEN);
+        while let Some(byte) = seq.next_element::<u8>()? {
+            if bytes.len() == MAX_ID_LEN {
+                return Err(de::Error::invalid_length(MAX_ID_LEN + 1, &self));
+            }
+            bytes.push(byte);
+        }
+        ObjectId::from_slice(&bytes).map_err(de::Error::custom)
+    }
+}
+
 impl fmt::Debug for ObjectId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ObjectId({})", &self.to_hex()[..12])
+        write!(f, "ObjectId({})", self.display_short(DEBUG_SHORT_LEN))
+    }
+}
+
+/// Number of hex characters shown by the `Debug` impl.
+const DEBUG_SHORT_LEN: usize = 12;
+
+/// Displays an abbreviated hex form of an [`ObjectId`].
+///
+/// Created by [`ObjectId::display_short`].
+#[derive(Clone, Copy)]
+pub struct DisplayShort<'a> {
+    id: &'a ObjectId,
+    len: usize,
+}
+
+impl fmt::Display for DisplayShort<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id.to_hex()[..self.len])
+    }
+}
+
+/// Map a forward hex digit (`0-9a-f`) to jj's reverse alphabet (`z-k`).
+fn hex_to_reverse_hex(c: char) -> Option<char> {
+    let value = c.to_digit(16)? as u8;
+    Some(char::from(b'z' - value))
+}
+
+/// Map a reverse-hex digit (`z-k`) to its forward hex digit (`0-9a-f`).
+fn reverse_hex_to_hex(c: char) -> Option<char> {
+    if !('k'..='z').contains(&c) {
+        return None;
+    }
+    let value = b'z' - c as u8;
+    char::from_digit(u32::from(value), 16)
+}
+
+/// Normalize a change ID prefix to forward hex.
+///
+/// Accepts either the forward (`0-9a-f`) or jj's reverse (`z-k`) alphabet,
+/// detected from the characters present. Input mixing both alphabets is
+/// rejected since it cannot be interpreted unambiguously.
+pub fn change_id_prefix_to_hex(prefix: &str) -> Result<String, ObjectIdError> {
+    let is_forward = |c: char| c.is_ascii_digit() || ('a'..='f').contains(&c);
+    let is_reverse = |c: char| ('k'..='z').contains(&c);
+
+    let has_forward = prefix.chars().any(is_forward);
+    let has_reverse = prefix.chars().any(is_reverse);
+    if prefix.chars().any(|c| !is_forward(c) && !is_reverse(c)) {
+        return Err(ObjectIdError::InvalidHexCharacter);
+    }
+
+    match (has_forward, has_reverse) {
+        (true, true) => Err(ObjectIdError::MixedHexAlphabet),
+        (_, true) => Ok(prefix.chars().filter_map(reverse_hex_to_hex).collect()),
+        _ => Ok(prefix.to_string()),
     }
 }
 
 /// Error type for ObjectId parsing.
 #[derive(Debug, thiserror::Error)]
 pub enum ObjectIdError {
-    #[error("invalid length: expected {expected}, got {actual}")]
-    InvalidLength { expected: usize, actual: usize },
+    #[error("invalid length: expected one of {SUPPORTED_ID_LENS:?} bytes, got {actual}")]
+    InvalidLength { actual: usize },
 
-    #[error("invalid hex length: expected {expected}, got {actual}")]
-    InvalidHexLength { expected: usize, actual: usize },
+    #[error("invalid hex length: {actual} characters is not a supported id length")]
+    InvalidHexLength { actual: usize },
 
     #[error("invalid hex character")]
     InvalidHexCharacter,
+
+    #[error("invalid reverse-hex character (expected z-k)")]
+    InvalidReverseHexCharacter,
+
+    #[error("id mixes hex (0-9a-f) and reverse-hex (z-k) characters")]
+    MixedHexAlphabet,
 }
 
 // Type aliases for semantic clarity
@@ -105,9 +437,60 @@ pub type ConflictId = ObjectId;
 pub type OperationId = ObjectId;
 pub type ViewId = ObjectId;
 
+/// Implements conversions between [`ObjectId`] and jj-lib id types.
+///
+/// jj-lib ids are backend-dependent byte strings (e.g. 20 bytes for git
+/// commits), so converting into an [`ObjectId`] validates the length and
+/// fails with [`ObjectIdError::InvalidLength`] if it is not one of
+/// [`SUPPORTED_ID_LENS`]. Converting the other way is infallible.
+macro_rules! impl_jj_id_conversions {
+    ($($jj_id:ty),* $(,)?) => {
+        $(
+            impl TryFrom<&$jj_id> for ObjectId {
+                type Error = ObjectIdError;
+
+                fn try_from(id: &$jj_id) -> Result<Self, Self::Error> {
+                    Self::from_slice(id.as_bytes())
+                }
+            }
+
+            impl TryFrom<$jj_id> for ObjectId {
+                type Error = ObjectIdError;
+
+                fn try_from(id: $jj_id) -> Result<Self, Self::Error> {
+                    Self::try_from(&id)
+                }
+            }
+
+            impl From<&ObjectId> for $jj_id {
+                fn from(id: &ObjectId) -> Self {
+                    <$jj_id>::from_bytes(id.as_bytes())
+                }
+            }
+
+            impl From<ObjectId> for $jj_id {
+                fn from(id: ObjectId) -> Self {
+                    Self::from(&id)
+                }
+            }
+        )*
+    };
+}
+
+impl_jj_id_conversions!(
+    jj_lib::backend::CommitId,
+    jj_lib::backend::ChangeId,
+    jj_lib::backend::TreeId,
+    jj_lib::backend::FileId,
+    jj_lib::backend::SymlinkId,
+    jj_lib::op_store::OperationId,
+    jj_lib::op_store::ViewId,
+);
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::{BTreeSet, HashMap};
 
     #[test]
     fn test_hash_produces_consistent_results() {
@@ -146,4 +529,294 @@ mod tests {
         let result = ObjectId::from_hex(&invalid);
         assert!(matches!(result, Err(ObjectIdError::InvalidHexCharacter)));
     }
+
+    #[test]
+    fn test_ordering_is_lexicographic() {
+        let mut low = [0u8; HASH_LEN];
+        low[HASH_LEN - 1] = 0xff;
+        let mut high = [0u8; HASH_LEN];
+        high[0] = 0x01;
+        assert!(ObjectId::from_bytes(low) < ObjectId::from_bytes(high));
+        assert!(ObjectId::ZERO < ObjectId::from_bytes(low));
+    }
+
+    #[test]
+    fn test_btree_set_iterates_in_byte_order() {
+        let ids: BTreeSet<ObjectId> = [b"c", b"a", b"b"]
+            .iter()
+            .map(|data| ObjectId::hash(*data))
+            .collect();
+        let mut expected: Vec<Vec<u8>> = ids.iter().map(|id| id.as_bytes().to_vec()).collect();
+        expected.sort();
+        let actual: Vec<Vec<u8>> = ids.iter().map(|id| id.as_bytes().to_vec()).collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_lookup_by_byte_slice() {
+        let id = ObjectId::hash(b"lookup");
+        let mut map = HashMap::new();
+        map.insert(id, "value");
+        assert_eq!(map.get(id.as_bytes()), Some(&"value"));
+        assert_eq!(map.get(&[0u8; HASH_LEN][..]), None);
+
+        let set: BTreeSet<ObjectId> = [id].into_iter().collect();
+        assert!(set.contains(id.as_bytes()));
+        assert_eq!(AsRef::<[u8]>::as_ref(&id), id.as_bytes());
+    }
+
+    #[test]
+    fn test_zero_is_all_zero_bytes() {
+        assert_eq!(ObjectId::ZERO.as_bytes(), &[0u8; HASH_LEN]);
+        assert_eq!(ObjectId::ZERO.to_hex(), "0".repeat(64));
+    }
+
+    #[test]
+    fn test_short_is_hex_prefix() {
+        let id = ObjectId::hash(b"short");
+        let hex = id.to_hex();
+        for len in [1, 7, 12, 33, 64] {
+            assert_eq!(id.short(len), hex[..len]);
+        }
+        assert_eq!(format!("{}", id.display_short(8)), hex[..8]);
+    }
+
+    #[test]
+    fn test_short_clamps_length() {
+        let id = ObjectId::hash(b"clamp");
+        assert_eq!(id.short(0).len(), 1);
+        assert_eq!(id.short(1000), id.to_hex());
+    }
+
+    #[test]
+    fn test_debug_uses_short_form() {
+        let id = ObjectId::hash(b"debug");
+        assert_eq!(format!("{id:?}"), format!("ObjectId({})", id.short(12)));
+    }
+
+    #[test]
+    fn test_reverse_hex_known_pairs() {
+        // The root change ID, which `jj log -r 'root()' -T change_id`
+        // prints as 32 `z`s.
+        let root = ChangeId::from_slice(&[0; CHANGE_ID_LEN]).unwrap();
+        assert_eq!(root.to_reverse_hex(), "z".repeat(32));
+        assert_eq!(ChangeId::from_reverse_hex(&"z".repeat(32)).unwrap(), root);
+
+        // Every digit, as in jj-lib's own test of the encoding.
+        let id = ChangeId::from_hex("0123456789abcdef0123456789abcdef").unwrap();
+        let reverse = "zyxwvutsrqponmlkzyxwvutsrqponmlk";
+        assert_eq!(id.to_reverse_hex(), reverse);
+        assert_eq!(ChangeId::from_reverse_hex(reverse).unwrap(), id);
+
+        // jj renders change IDs with jj-lib's encoder, so agree with it.
+        for seed in [&b"first"[..], b"second", b"third"] {
+            let bytes = &ChangeId::hash(seed).as_bytes()[..CHANGE_ID_LEN];
+            let id = ChangeId::from_slice(bytes).unwrap();
+            let rendered = jj_lib::hex_util::encode_reverse_hex(bytes);
+            assert_eq!(id.to_reverse_hex(), rendered);
+            assert_eq!(ChangeId::from_reverse_hex(&rendered).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_reverse_hex_roundtrip() {
+        let id = ChangeId::hash(b"change");
+        assert_eq!(
+            ChangeId::from_reverse_hex(&id.to_reverse_hex()).unwrap(),
+            id
+        );
+    }
+
+    #[test]
+    fn test_from_reverse_hex_rejects_forward_alphabet() {
+        let result = ChangeId::from_reverse_hex(&"a".repeat(64));
+        assert!(matches!(
+            result,
+            Err(ObjectIdError::InvalidReverseHexCharacter)
+        ));
+    }
+
+    #[test]
+    fn test_change_id_prefix_accepts_either_alphabet() {
+        assert_eq!(change_id_prefix_to_hex("0a1f").unwrap(), "0a1f");
+        assert_eq!(change_id_prefix_to_hex("zpyk").unwrap(), "0a1f");
+    }
+
+    #[test]
+    fn test_change_id_prefix_rejects_mixed_alphabet() {
+        assert!(matches!(
+            change_id_prefix_to_hex("0azk"),
+            Err(ObjectIdError::MixedHexAlphabet)
+        ));
+        assert!(matches!(
+            change_id_prefix_to_hex("xyz!"),
+            Err(ObjectIdError::InvalidHexCharacter)
+        ));
+    }
+
+    #[test]
+    fn test_ct_eq() {
+        let id = ObjectId::hash(b"token");
+        let copy = ObjectId::from_hex(&id.to_hex()).unwrap();
+        assert!(id.ct_eq(&copy));
+        assert!(!id.ct_eq(&ObjectId::hash(b"other token")));
+    }
+
+    #[test]
+    fn test_ct_eq_different_lengths() {
+        let short = ObjectId::from_slice(&[0u8; GIT_HASH_LEN]).unwrap();
+        assert!(!short.ct_eq(&ObjectId::ZERO));
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn test_hash_reader_with_zeroize() {
+        let data = vec![0x11; READ_CHUNK_SIZE + 1];
+        let id = ObjectId::hash_reader(data.as_slice()).unwrap();
+        assert!(id.ct_eq(&ObjectId::hash(&data)));
+    }
+
+    #[test]
+    fn test_from_str() {
+        let original = ObjectId::hash(b"from str");
+        let parsed: ObjectId = original.to_hex().parse().unwrap();
+        assert_eq!(parsed, original);
+    }
+
+    #[test]
+    fn test_json_is_hex_string() {
+        let id = ObjectId::hash(b"json");
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.to_hex()));
+        let parsed: ObjectId = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, id);
+    }
+
+    #[test]
+    fn test_json_legacy_array_still_deserializes() {
+        let id = ObjectId::hash(b"legacy");
+        let legacy = serde_json::to_string(id.as_bytes()).unwrap();
+        let parsed: ObjectId = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(parsed, id);
+    }
+
+    #[test]
+    fn test_json_rejects_short_array() {
+        let result: Result<ObjectId, _> = serde_json::from_str("[1, 2, 3]");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_incremental_hash_matches_one_shot() {
+        let data = b"hello, incremental forjj hashing!";
+        let mut hasher = ObjectHasher::new();
+        for chunk in data.chunks(5) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), ObjectId::hash(data));
+    }
+
+    #[test]
+    fn test_empty_hasher_matches_empty_hash() {
+        assert_eq!(ObjectHasher::new().finalize(), ObjectId::hash(b""));
+    }
+
+    #[test]
+    fn test_hash_reader_matches_one_shot() {
+        let data = vec![0x5a; READ_CHUNK_SIZE * 3 + 17];
+        let id = ObjectId::hash_reader(data.as_slice()).unwrap();
+        assert_eq!(id, ObjectId::hash(&data));
+    }
+
+    #[tokio::test]
+    async fn test_hash_async_reader_matches_one_shot() {
+        let data = vec![0xa5; READ_CHUNK_SIZE * 2 + 3];
+        let id = ObjectId::hash_async_reader(data.as_slice()).await.unwrap();
+        assert_eq!(id, ObjectId::hash(&data));
+    }
+
+    #[test]
+    fn test_jj_commit_id_roundtrip() {
+        let original = ObjectId::hash(b"commit");
+        let jj_id = jj_lib::backend::CommitId::from(original);
+        assert_eq!(jj_id.as_bytes(), original.as_bytes());
+
+        let converted = ObjectId::try_from(&jj_id).unwrap();
+        assert_eq!(converted, original);
+    }
+
+    #[test]
+    fn test_jj_operation_id_roundtrip() {
+        let original = ObjectId::hash(b"operation");
+        let jj_id = jj_lib::op_store::OperationId::from(&original);
+        assert_eq!(ObjectId::try_from(jj_id).unwrap(), original);
+    }
+
+    #[test]
+    fn test_git_backend_commit_id_conversion() {
+        // Git-backend commit ids are 20-byte SHA-1 hashes.
+        let jj_id = jj_lib::backend::CommitId::new(vec![0xab; GIT_HASH_LEN]);
+        let id = ObjectId::try_from(&jj_id).unwrap();
+        assert_eq!(id.byte_len(), GIT_HASH_LEN);
+        assert_eq!(jj_lib::backend::CommitId::from(id), jj_id);
+    }
+
+    #[test]
+    fn test_native_backend_commit_id_conversion() {
+        // The native (simple) backend uses 64-byte BLAKE2b-512 commit ids.
+        let jj_id = jj_lib::backend::CommitId::new(vec![0xcd; NATIVE_HASH_LEN]);
+        let id = ObjectId::try_from(&jj_id).unwrap();
+        assert_eq!(id.byte_len(), NATIVE_HASH_LEN);
+        assert_eq!(jj_lib::backend::CommitId::from(id), jj_id);
+    }
+
+    #[test]
+    fn test_jj_change_id_conversion() {
+        let jj_id = jj_lib::backend::ChangeId::new(vec![0x11; CHANGE_ID_LEN]);
+        let id = ObjectId::try_from(&jj_id).unwrap();
+        assert_eq!(id.byte_len(), CHANGE_ID_LEN);
+    }
+
+    #[test]
+    fn test_unsupported_jj_id_length() {
+        let jj_id = jj_lib::backend::CommitId::new(vec![0xab; 7]);
+        let result = ObjectId::try_from(jj_id);
+        assert!(matches!(
+            result,
+            Err(ObjectIdError::InvalidLength { actual: 7 })
+        ));
+    }
+
+    #[test]
+    fn test_git_length_hex_roundtrip() {
+        let hex = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(hex.len(), 40);
+        let id = ObjectId::from_hex(hex).unwrap();
+        assert_eq!(id.byte_len(), GIT_HASH_LEN);
+        assert_eq!(id.to_hex(), hex);
+    }
+
+    #[test]
+    fn test_hash_length_hex_roundtrip() {
+        let hex = "fedcba9876543210".repeat(4);
+        let id = ObjectId::from_hex(&hex).unwrap();
+        assert_eq!(id.byte_len(), HASH_LEN);
+        assert_eq!(id.to_hex(), hex);
+    }
+
+    #[test]
+    fn test_ids_of_different_lengths_are_distinct() {
+        let git = ObjectId::from_slice(&[0u8; GIT_HASH_LEN]).unwrap();
+        assert_ne!(git, ObjectId::ZERO);
+        assert!(git < ObjectId::ZERO);
+    }
+
+    #[test]
+    fn test_variable_length_json_roundtrip() {
+        let id = ObjectId::from_slice(&[0x42; GIT_HASH_LEN]).unwrap();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json.len(), GIT_HASH_LEN * 2 + 2);
+        let parsed: ObjectId = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, id);
+    }
 }
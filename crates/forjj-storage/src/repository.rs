@@ -3,21 +3,41 @@
 //! This module provides high-level repository operations, wrapping jj-lib's
 //! storage backend to provide a clean API for the rest of Forjj.
 
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet};
+use std::io::Write;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{Context, Result, bail};
-use jj_lib::backend::CommitId;
+use blake2::{Blake2b512, Digest};
+use imara_diff::intern::InternedInput;
+use imara_diff::{Algorithm, diff};
+use jj_lib::backend::{
+    Backend, ChangeId as JjChangeId, CommitId, CopyId, TreeId as JjTreeId, TreeValue,
+};
 use jj_lib::commit::Commit;
 use jj_lib::config::StackedConfig;
-use jj_lib::merged_tree::MergedTree;
-use jj_lib::op_store::OperationId;
+use jj_lib::content_hash::blake2b_hash;
+use jj_lib::dag_walk;
+use jj_lib::merge::Merge;
+use jj_lib::merged_tree::{MergedTree, MergedTreeBuilder, MergedTreeValue};
+use jj_lib::op_store::{OpStore, OperationId, RefTarget, RootOperationData, ViewId as JjViewId};
 use jj_lib::operation::Operation;
+use jj_lib::ref_name::RefName;
 use jj_lib::repo::{ReadonlyRepo, Repo, StoreFactories};
-use jj_lib::repo_path::RepoPath;
+use jj_lib::repo_path::{RepoPath, RepoPathBuf};
+use jj_lib::rewrite::merge_commit_trees;
 use jj_lib::settings::UserSettings;
+use jj_lib::simple_backend::SimpleBackend;
+use jj_lib::simple_op_store::SimpleOpStore;
 use jj_lib::workspace::{Workspace, default_working_copy_factories};
-use tracing::{debug, info};
+use pollster::FutureExt as _;
+use tokio::io::AsyncRead;
+use tracing::{debug, info, warn};
+
+use crate::object_id::{self, ObjectId};
 
 /// Repository information.
 #[derive(Debug, Clone)]
@@ -28,6 +48,47 @@ pub struct RepoInfo {
     pub backend_type: BackendType,
 }
 
+impl RepoInfo {
+    /// Read a sidecar file of the repository, as
+    /// [`Repository::read_sidecar`] does, without opening it.
+    pub fn read_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.sidecar_path(name)?;
+        match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error).with_context(|| format!("failed to read: {}", path.display())),
+        }
+    }
+
+    /// The git repository a git-backed repository keeps its commits in,
+    /// as named by the store's `git_target`: inside the store, or beside
+    /// the workspace if it is colocated.
+    pub fn git_dir(&self) -> Result<PathBuf> {
+        if self.backend_type != BackendType::Git {
+            bail!(
+                "{}/{} uses the {} backend, not git",
+                self.owner,
+                self.name,
+                self.backend_type.as_str()
+            );
+        }
+        let store = self.path.join(".jj/repo/store");
+        let target_file = store.join("git_target");
+        let target = std::fs::read_to_string(&target_file)
+            .with_context(|| format!("failed to read: {}", target_file.display()))?;
+        Ok(store.join(target.trim()))
+    }
+
+    fn sidecar_dir(&self) -> PathBuf {
+        self.path.join(".jj/repo/forjj")
+    }
+
+    fn sidecar_path(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name).with_context(|| format!("invalid sidecar name {name:?}"))?;
+        Ok(self.sidecar_dir().join(name))
+    }
+}
+
 /// Supported backend types.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BackendType {
@@ -46,6 +107,98 @@ impl BackendType {
     }
 }
 
+/// Maximum length of an owner or repository name.
+pub const MAX_NAME_LEN: usize = 100;
+
+/// Reasons an owner or repository name is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NameError {
+    #[error("name is empty")]
+    Empty,
+
+    #[error("name is longer than {MAX_NAME_LEN} characters")]
+    TooLong,
+
+    #[error("name must not start with '{0}'")]
+    BadStart(char),
+
+    #[error("name contains invalid character {0:?}")]
+    InvalidChar(char),
+}
+
+/// Check that `name` is a valid owner or repository name.
+///
+/// Names are used as path components, so they are limited to ASCII letters,
+/// digits, `-`, `_`, and `.`, and may not start with `.` or `-`.
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+    let first = name.chars().next().ok_or(NameError::Empty)?;
+    if name.len() > MAX_NAME_LEN {
+        return Err(NameError::TooLong);
+    }
+    if first == '.' || first == '-' {
+        return Err(NameError::BadStart(first));
+    }
+    match name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        Some(c) => Err(NameError::InvalidChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// Check both names of a repository, refusing with
+/// [`StorageError::InvalidName`].
+fn validate_repo_names(owner: &str, name: &str) -> Result<(), StorageError> {
+    for name in [owner, name] {
+        validate_name(name).map_err(|error| StorageError::InvalidName {
+            name: name.to_string(),
+            error,
+        })?;
+    }
+    Ok(())
+}
+
+/// Maximum length of a bookmark name, in bytes.
+pub const MAX_BOOKMARK_NAME_LEN: usize = 255;
+
+/// Reasons a bookmark name is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BookmarkNameError {
+    #[error("bookmark name is empty")]
+    Empty,
+
+    #[error("bookmark name is longer than {MAX_BOOKMARK_NAME_LEN} bytes")]
+    TooLong,
+
+    #[error("bookmark name contains invalid character {0:?}")]
+    InvalidChar(char),
+}
+
+/// Check that `name` can be used as a bookmark name.
+///
+/// Bookmarks are shown and typed on command lines, so whitespace and control
+/// characters are not allowed.
+pub fn validate_bookmark_name(name: &str) -> Result<(), BookmarkNameError> {
+    if name.is_empty() {
+        return Err(BookmarkNameError::Empty);
+    }
+    if name.len() > MAX_BOOKMARK_NAME_LEN {
+        return Err(BookmarkNameError::TooLong);
+    }
+    match name.chars().find(|c| c.is_whitespace() || c.is_control()) {
+        Some(c) => Err(BookmarkNameError::InvalidChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// Directory in the repositories root that trashed repositories are moved to.
+const TRASH_DIR: &str = ".trash";
+
+/// Directory in the repositories root forks are copied into before they
+/// are moved into place. As with the trash, no owner can have its name.
+const FORKS_DIR: &str = ".forks";
+
 /// Repository storage configuration.
 #[derive(Debug, Clone)]
 pub struct StorageConfig {
@@ -63,7 +216,6 @@ impl Default for StorageConfig {
 
 /// A handle to an opened jj repository.
 pub struct Repository {
-    #[allow(dead_code)]
     workspace: Workspace,
     repo: Arc<ReadonlyRepo>,
     info: RepoInfo,
@@ -93,6 +245,18 @@ impl Repository {
         self.repo.store().root_commit()
     }
 
+    /// Get the root commit's ID as a forjj ID.
+    pub fn root_commit_id(&self) -> Result<object_id::CommitId> {
+        ObjectId::try_from(self.repo.store().root_commit_id())
+            .context("failed to convert root commit id")
+    }
+
+    /// Get the root operation's ID as a forjj ID.
+    pub fn root_operation_id(&self) -> Result<object_id::OperationId> {
+        ObjectId::try_from(self.repo.op_store().root_operation_id())
+            .context("failed to convert root operation id")
+    }
+
     /// Get all visible heads (commits with no children in the view).
     pub fn heads(&self) -> Vec<CommitId> {
         self.repo.view().heads().iter().cloned().collect()
@@ -123,6 +287,12 @@ impl Repository {
         self.repo.operation()
     }
 
+    /// When the current operation finished, in seconds since the Unix epoch.
+    pub fn operation_time(&self) -> u64 {
+        let millis = self.repo.operation().metadata().time.end.timestamp.0;
+        u64::try_from(millis / 1000).unwrap_or(0)
+    }
+
     /// Get the tree for a commit.
     pub fn get_tree(&self, commit: &Commit) -> MergedTree {
         commit.tree()
@@ -183,377 +353,3764 @@ impl Repository {
         Ok(op_heads)
     }
 
-    /// Check if this is a fresh repository with no user commits.
-    ///
-    /// A fresh jj repository has:
-    /// - A root commit (empty, parent of all commits)
-    /// - A working-copy commit (child of root, may be empty)
+    /// Get a commit by its forjj ID.
+    pub fn commit_by_id(&self, id: &object_id::CommitId) -> Result<Commit> {
+        self.get_commit(&CommitId::from(id))
+    }
+
+    /// Get all visible heads as forjj IDs.
     ///
-    /// This returns true if all heads are either the root or empty commits
-    /// with only the root as parent.
-    pub fn is_fresh(&self) -> bool {
-        let heads = self.heads();
-        let root_id = self.root_commit().id().clone();
+    /// Fails if the backend's commit IDs are not one of
+    /// [`object_id::SUPPORTED_ID_LENS`].
+    pub fn head_ids(&self) -> Result<Vec<object_id::CommitId>> {
+        self.heads()
+            .iter()
+            .map(|id| ObjectId::try_from(id).context("failed to convert head commit id"))
+            .collect()
+    }
 
-        heads.iter().all(|head_id| {
-            if *head_id == root_id {
-                return true;
-            }
-            // Check if this is an empty working-copy commit (child of root only)
-            if let Ok(commit) = self.get_commit(head_id) {
-                let parents = commit.parent_ids();
-                // Fresh working-copy commit has only root as parent
-                parents.len() == 1 && parents[0] == root_id
-            } else {
-                false
-            }
-        })
+    /// Get all bookmarks with forjj commit IDs.
+    pub fn bookmark_ids(&self) -> Result<Vec<(String, object_id::CommitId)>> {
+        self.bookmarks()
+            .into_iter()
+            .map(|(name, id)| {
+                let id = ObjectId::try_from(&id)
+                    .with_context(|| format!("failed to convert target of bookmark {name}"))?;
+                Ok((name, id))
+            })
+            .collect()
     }
-}
 
-/// Entry in a tree.
-#[derive(Debug, Clone)]
-pub struct TreeEntry {
-    /// The path of the entry relative to the tree root.
-    pub path: String,
-    /// The kind of entry.
-    pub kind: TreeEntryKind,
-}
+    /// Get the current operation ID as a forjj ID.
+    pub fn current_op_id(&self) -> Result<object_id::OperationId> {
+        ObjectId::try_from(self.operation_id()).context("failed to convert operation id")
+    }
 
-/// Kind of tree entry.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TreeEntryKind {
-    /// Regular file
-    File,
-    /// Subdirectory
-    Tree,
-    /// Conflicted entry
-    Conflict,
-}
+    /// Get all operation heads as forjj IDs.
+    pub async fn op_head_ids(&self) -> Result<Vec<object_id::OperationId>> {
+        self.operation_heads()
+            .await?
+            .iter()
+            .map(|id| ObjectId::try_from(id).context("failed to convert operation head id"))
+            .collect()
+    }
 
-/// Repository manager for creating and accessing repositories.
-pub struct RepositoryManager {
-    config: StorageConfig,
-    user_settings: UserSettings,
-    store_factories: StoreFactories,
-}
+    /// Walk the operation log back from the current operation heads.
+    ///
+    /// Returns every operation reachable from the heads, down to the root
+    /// operation, in no particular order.
+    pub async fn operation_log(&self) -> Result<Vec<OperationEntry>> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = self.op_head_ids().await?;
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let entry = self.operation_entry(&id).await?;
+            pending.extend(&entry.parents);
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
 
-impl RepositoryManager {
-    /// Create a new repository manager with the given configuration.
-    pub fn new(config: StorageConfig) -> Result<Self> {
-        let jj_config = StackedConfig::with_defaults();
-        let user_settings =
-            UserSettings::from_config(jj_config).context("failed to create user settings")?;
-        let store_factories = StoreFactories::default();
+    /// Read one operation from the store.
+    pub async fn operation_entry(&self, id: &object_id::OperationId) -> Result<OperationEntry> {
+        let op_store = self.repo.op_store();
+        let op = op_store
+            .read_operation(&OperationId::from(id))
+            .await
+            .with_context(|| format!("failed to read operation {id}"))?;
+        let view = op_store
+            .read_view(&op.view_id)
+            .await
+            .with_context(|| format!("failed to read view of operation {id}"))?;
 
-        Ok(Self {
-            config,
-            user_settings,
-            store_factories,
+        let parents = op
+            .parents
+            .iter()
+            .map(ObjectId::try_from)
+            .collect::<Result<_, _>>()
+            .context("failed to convert operation parent id")?;
+        let view_heads = view
+            .head_ids
+            .iter()
+            .map(ObjectId::try_from)
+            .collect::<Result<_, _>>()
+            .context("failed to convert view head id")?;
+        Ok(OperationEntry {
+            id: *id,
+            parents,
+            view_id: ObjectId::try_from(&op.view_id).context("failed to convert view id")?,
+            view_heads,
+            description: op.metadata.description.clone(),
         })
     }
 
-    /// Get the path to a repository.
-    pub fn repo_path(&self, owner: &str, name: &str) -> PathBuf {
-        self.config.repos_root.join(owner).join(name)
+    /// Get the parents of a commit as forjj IDs.
+    pub fn commit_parent_ids(&self, id: &object_id::CommitId) -> Result<Vec<object_id::CommitId>> {
+        self.commit_by_id(id)?
+            .parent_ids()
+            .iter()
+            .map(|id| ObjectId::try_from(id).context("failed to convert parent commit id"))
+            .collect()
     }
 
-    /// Check if a repository exists.
-    pub fn repo_exists(&self, owner: &str, name: &str) -> bool {
-        let path = self.repo_path(owner, name);
-        path.join(".jj").exists()
+    /// Count the commits reachable from `a` but not `b` (ahead), and from `b`
+    /// but not `a` (behind).
+    ///
+    /// `a` descends from `b`, so moving a bookmark from `b` to `a` is a
+    /// fast-forward, exactly when nothing is behind.
+    pub fn ahead_behind(
+        &self,
+        a: &object_id::CommitId,
+        b: &object_id::CommitId,
+    ) -> Result<(usize, usize)> {
+        let from_a = self.ancestors(a)?;
+        let from_b = self.ancestors(b)?;
+        Ok((
+            from_a.difference(&from_b).count(),
+            from_b.difference(&from_a).count(),
+        ))
     }
 
-    /// Create a new repository with the native jj backend.
-    pub fn create_repo(&self, owner: &str, name: &str) -> Result<Repository> {
-        let repo_path = self.repo_path(owner, name);
+    /// Collect a commit and all of its ancestors.
+    fn ancestors(&self, id: &object_id::CommitId) -> Result<HashSet<object_id::CommitId>> {
+        let mut seen = HashSet::from([*id]);
+        let mut pending = vec![*id];
+        while let Some(id) = pending.pop() {
+            for parent in self.commit_parent_ids(&id)? {
+                if seen.insert(parent) {
+                    pending.push(parent);
+                }
+            }
+        }
+        Ok(seen)
+    }
 
-        if repo_path.exists() {
-            bail!("repository already exists: {}/{}", owner, name);
+    /// The newest commit that both `a` and `b` descend from, or `None` if
+    /// they only share the root commit.
+    ///
+    /// Of several such commits, as criss-cross merges give, the one
+    /// committed last is chosen, and of those committed at once, the one
+    /// with the greatest ID.
+    pub fn merge_base(
+        &self,
+        a: &object_id::CommitId,
+        b: &object_id::CommitId,
+    ) -> Result<Option<object_id::CommitId>> {
+        let from_a = self.ancestors(a)?;
+        let mut common: HashSet<_> = self.ancestors(b)?.intersection(&from_a).copied().collect();
+        common.remove(&self.root_commit_id()?);
+        // Every ancestor of a common ancestor is one, so the best ones are
+        // those no other is descended from.
+        let mut bases = common.clone();
+        for id in &common {
+            for parent in self.commit_parent_ids(id)? {
+                bases.remove(&parent);
+            }
         }
+        let keys = bases
+            .iter()
+            .map(|id| self.log_key(id))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(keys.into_iter().max().map(|(_, id)| id))
+    }
 
-        // Create parent directories
-        std::fs::create_dir_all(&repo_path)
-            .with_context(|| format!("failed to create directory: {}", repo_path.display()))?;
+    /// The commits reachable from `head` but not from `base`, newest first
+    /// as [`log`](Self::log) orders them.
+    pub fn commits_between(
+        &self,
+        base: &object_id::CommitId,
+        head: &object_id::CommitId,
+    ) -> Result<Vec<object_id::CommitId>> {
+        let from_base = self.ancestors(base)?;
+        let mut seen = HashSet::from([*head]);
+        let mut pending = BinaryHeap::from([self.log_key(head)?]);
+        let mut commits = Vec::new();
+        while let Some((_, id)) = pending.pop() {
+            // The ancestors of `base`'s ancestors are all among them too.
+            if from_base.contains(&id) {
+                continue;
+            }
+            for parent in self.commit_parent_ids(&id)? {
+                if seen.insert(parent) {
+                    pending.push(self.log_key(&parent)?);
+                }
+            }
+            commits.push(id);
+        }
+        Ok(commits)
+    }
 
-        info!("creating repository at {}", repo_path.display());
+    /// Whether `ancestor` is `descendant` or one of its ancestors.
+    pub fn is_ancestor(
+        &self,
+        ancestor: &object_id::CommitId,
+        descendant: &object_id::CommitId,
+    ) -> Result<bool> {
+        Ok(self.ancestors(descendant)?.contains(ancestor))
+    }
 
-        // Initialize with native (simple) backend
-        let (workspace, repo) = Workspace::init_simple(&self.user_settings, &repo_path)
-            .with_context(|| format!("failed to init repository at {}", repo_path.display()))?;
+    /// Summarize a commit, as listed by [`log`](Self::log).
+    pub fn commit_summary(&self, id: &object_id::CommitId) -> Result<CommitSummary> {
+        let commit = self.commit_by_id(id)?;
+        let mut parents = Vec::new();
+        let mut empty = !commit.parent_ids().is_empty();
+        for parent in commit.parent_ids() {
+            // A merge is only empty if it leaves every parent's tree as is.
+            empty &= self.get_commit(parent)?.tree_ids() == commit.tree_ids();
+            parents.push(ObjectId::try_from(parent).context("failed to convert parent commit id")?);
+        }
+        let author = commit.author();
+        let change_id =
+            ObjectId::try_from(commit.change_id()).context("failed to convert change id")?;
+        Ok(CommitSummary {
+            id: *id,
+            short_id: self.short_commit_id(id)?,
+            short_change_id: self.short_change_id(&change_id)?,
+            change_id,
+            author_name: author.name.clone(),
+            author_email: author.email.clone(),
+            author_time: author.timestamp.timestamp.0,
+            description: commit.description().to_string(),
+            parents,
+            empty,
+            conflict: !commit.tree_ids().is_resolved(),
+        })
+    }
 
-        debug!("repository created with backend: simple");
+    /// The shortest prefix of `id`'s hex form that no other commit in the
+    /// index starts with, as jj highlights in `jj log`.
+    pub fn short_commit_id(&self, id: &object_id::CommitId) -> Result<String> {
+        let len = self
+            .repo
+            .index()
+            .shortest_unique_commit_id_prefix_len(&CommitId::from(id))
+            .context("failed to find the shortest unique commit ID prefix")?;
+        Ok(id.short(len))
+    }
 
-        let info = RepoInfo {
-            name: name.to_string(),
-            owner: owner.to_string(),
-            path: repo_path,
-            backend_type: BackendType::Native,
-        };
+    /// The shortest prefix of `change_id`'s reverse-hex form that no other
+    /// visible change starts with.
+    pub fn short_change_id(&self, change_id: &object_id::ChangeId) -> Result<String> {
+        let len = self
+            .repo
+            .shortest_unique_change_id_prefix_len(&JjChangeId::new(change_id.as_bytes().to_vec()))
+            .context("failed to find the shortest unique change ID prefix")?;
+        let reverse_hex = change_id.to_reverse_hex();
+        Ok(reverse_hex[..len.clamp(1, reverse_hex.len())].to_string())
+    }
 
-        Ok(Repository {
-            workspace,
-            repo,
-            info,
+    /// List the history of `start`, newest first by committer time.
+    ///
+    /// Lists up to `limit` commits, starting after `after` if given, which
+    /// should be one of `start`'s ancestors. With a `path`, only commits
+    /// that change what is at it from their first parent are listed. The
+    /// root commit never is.
+    pub async fn log(
+        &self,
+        start: &object_id::CommitId,
+        after: Option<&object_id::CommitId>,
+        path: Option<&str>,
+        limit: usize,
+    ) -> Result<CommitLog> {
+        let path = path
+            .map(|path| {
+                RepoPathBuf::from_internal_string(path)
+                    .with_context(|| format!("invalid path: {path}"))
+            })
+            .transpose()?;
+        let root = self.root_commit_id()?;
+        let mut skipping = after.is_some();
+        let mut seen = HashSet::from([*start]);
+        let mut pending = BinaryHeap::from([self.log_key(start)?]);
+        let mut commits = Vec::new();
+        while let Some(id) = self.log_next(&mut pending, &mut seen)? {
+            if skipping {
+                skipping = after != Some(&id);
+                continue;
+            }
+            if id == root {
+                continue;
+            }
+            if let Some(path) = &path {
+                if !self.changes_path(&id, path).await? {
+                    continue;
+                }
+            }
+            if commits.len() == limit {
+                return Ok(CommitLog {
+                    commits,
+                    more: true,
+                });
+            }
+            commits.push(self.commit_summary(&id)?);
+        }
+        Ok(CommitLog {
+            commits,
+            more: false,
         })
     }
 
-    /// Open an existing repository.
-    pub fn open_repo(&self, owner: &str, name: &str) -> Result<Repository> {
-        let repo_path = self.repo_path(owner, name);
+    /// List the commits in the history of `start` that change what is at
+    /// `path` from their first parent, in [`log`](Self::log)'s order.
+    ///
+    /// Lists up to `limit` changes, starting after the commit `after` if
+    /// given. A path that never existed has no history; one that was
+    /// removed keeps the history from before.
+    pub async fn file_history(
+        &self,
+        start: &object_id::CommitId,
+        path: &str,
+        after: Option<&object_id::CommitId>,
+        limit: usize,
+    ) -> Result<FileHistory> {
+        let root = self.root_commit_id()?;
+        let mut skipping = after.is_some();
+        let mut seen = HashSet::from([*start]);
+        let mut pending = BinaryHeap::from([self.log_key(start)?]);
+        let mut entries = Vec::new();
+        while let Some(id) = self.log_next(&mut pending, &mut seen)? {
+            if skipping {
+                skipping = after != Some(&id);
+                continue;
+            }
+            if id == root {
+                continue;
+            }
+            let value = self.path_value(&id, path).await?;
+            let before = match self.commit_parent_ids(&id)?.first() {
+                Some(parent) => self.path_value(parent, path).await?,
+                None => PathValue::Absent,
+            };
+            if value == before {
+                continue;
+            }
+            if entries.len() == limit {
+                return Ok(FileHistory {
+                    entries,
+                    more: true,
+                });
+            }
+            let kind = match (&before, &value) {
+                (PathValue::Absent, _) => FileChangeKind::Added,
+                (_, PathValue::Absent) => FileChangeKind::Deleted,
+                _ => FileChangeKind::Modified,
+            };
+            let file = match value {
+                PathValue::File { id, .. } => Some(id),
+                _ => None,
+            };
+            entries.push(FileHistoryEntry {
+                commit: self.commit_summary(&id)?,
+                kind,
+                file,
+            });
+        }
+        Ok(FileHistory {
+            entries,
+            more: false,
+        })
+    }
 
-        if !repo_path.join(".jj").exists() {
-            bail!("repository does not exist: {}/{}", owner, name);
+    /// Take the newest commit queued by a walk in [`log`](Self::log)'s
+    /// order, queueing its parents that haven't been `seen`.
+    fn log_next(
+        &self,
+        pending: &mut BinaryHeap<(i64, object_id::CommitId)>,
+        seen: &mut HashSet<object_id::CommitId>,
+    ) -> Result<Option<object_id::CommitId>> {
+        let Some((_, id)) = pending.pop() else {
+            return Ok(None);
+        };
+        for parent in self.commit_parent_ids(&id)? {
+            if seen.insert(parent) {
+                pending.push(self.log_key(&parent)?);
+            }
         }
+        Ok(Some(id))
+    }
 
-        debug!("opening repository at {}", repo_path.display());
+    /// Where a commit goes in [`log`](Self::log)'s order.
+    fn log_key(&self, id: &object_id::CommitId) -> Result<(i64, object_id::CommitId)> {
+        let commit = self.commit_by_id(id)?;
+        Ok((commit.committer().timestamp.timestamp.0, *id))
+    }
 
-        let workspace = Workspace::load(
-            &self.user_settings,
-            &repo_path,
-            &self.store_factories,
-            &default_working_copy_factories(),
-        )
-        .with_context(|| format!("failed to load workspace at {}", repo_path.display()))?;
+    /// List the files a commit changes, sorted by path.
+    ///
+    /// A commit is compared with its parent, or for a merge, with what
+    /// merging its parents gives.
+    pub async fn commit_changes(&self, id: &object_id::CommitId) -> Result<Vec<FileChange>> {
+        let commit = self.commit_by_id(id)?;
+        let parents = commit
+            .parent_ids()
+            .iter()
+            .map(|parent| self.get_commit(parent))
+            .collect::<Result<Vec<_>>>()?;
+        let before = if parents.is_empty() {
+            BTreeMap::new()
+        } else {
+            let merged = merge_commit_trees(self.repo.as_ref(), &parents)
+                .await
+                .context("failed to merge parent trees")?;
+            file_values(&merged)?
+        };
+        let after = file_values(&commit.tree())?;
+        self.tree_changes(&before, &after).await
+    }
 
-        let repo = workspace
-            .repo_loader()
-            .load_at_head()
-            .context("failed to load repository at head")?;
+    /// List the files that differ between two commits' trees, sorted by
+    /// path, as changes from `from` to `to`.
+    pub async fn diff_commits(
+        &self,
+        from: &object_id::CommitId,
+        to: &object_id::CommitId,
+    ) -> Result<Vec<FileChange>> {
+        let before = file_values(&self.commit_by_id(from)?.tree())?;
+        let after = file_values(&self.commit_by_id(to)?.tree())?;
+        self.tree_changes(&before, &after).await
+    }
 
-        let backend_type = self.detect_backend_type(&repo_path)?;
+    /// The changes that take the files of `before` to those of `after`.
+    async fn tree_changes(
+        &self,
+        before: &BTreeMap<RepoPathBuf, MergedTreeValue>,
+        after: &BTreeMap<RepoPathBuf, MergedTreeValue>,
+    ) -> Result<Vec<FileChange>> {
+        let paths: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+        let mut changes = Vec::new();
+        for path in paths {
+            let (old, new) = (before.get(path), after.get(path));
+            if old == new {
+                continue;
+            }
+            let kind = match (old, new) {
+                (None, _) => FileChangeKind::Added,
+                (_, None) => FileChangeKind::Deleted,
+                _ => FileChangeKind::Modified,
+            };
+            let (old_file, old_size) = self.file_version(path, old).await?;
+            let (new_file, new_size) = self.file_version(path, new).await?;
+            changes.push(FileChange {
+                path: path.as_internal_file_string().to_string(),
+                kind,
+                old_file,
+                old_size,
+                new_file,
+                new_size,
+            });
+        }
+        Ok(changes)
+    }
 
-        let info = RepoInfo {
-            name: name.to_string(),
-            owner: owner.to_string(),
-            path: repo_path,
-            backend_type,
+    /// The ID and size of the file `value` holds, if it holds a file rather
+    /// than a symlink or a conflict.
+    async fn file_version(
+        &self,
+        path: &RepoPath,
+        value: Option<&MergedTreeValue>,
+    ) -> Result<(Option<object_id::FileId>, Option<u64>)> {
+        let Some(Some(TreeValue::File { id, .. })) = value.and_then(|value| value.as_resolved())
+        else {
+            return Ok((None, None));
         };
+        let size = self.read_file(path, id).await?.len() as u64;
+        let id = ObjectId::try_from(id).context("failed to convert file id")?;
+        Ok((Some(id), Some(size)))
+    }
 
-        Ok(Repository {
-            workspace,
-            repo,
-            info,
+    /// Find what is at `path` in a commit's tree.
+    ///
+    /// A path that isn't valid has nothing at it.
+    pub async fn path_value(&self, id: &object_id::CommitId, path: &str) -> Result<PathValue> {
+        let commit = self.commit_by_id(id)?;
+        let Ok(path) = RepoPathBuf::from_internal_string(path) else {
+            return Ok(PathValue::Absent);
+        };
+        let values = self.path_values(&commit, &path).await?;
+        let Some(value) = trivial_merge(&values) else {
+            return Ok(PathValue::Conflict {
+                sides: values.len() / 2 + 1,
+            });
+        };
+        Ok(match value {
+            None => PathValue::Absent,
+            Some(TreeValue::File { id, executable, .. }) => PathValue::File {
+                id: ObjectId::try_from(id).context("failed to convert file id")?,
+                executable: *executable,
+            },
+            Some(TreeValue::Symlink(id)) => {
+                PathValue::Symlink(ObjectId::try_from(id).context("failed to convert symlink id")?)
+            }
+            Some(TreeValue::Tree(_)) => PathValue::Tree,
+            Some(_) => PathValue::Other,
         })
     }
 
-    /// Delete a repository.
-    pub fn delete_repo(&self, owner: &str, name: &str) -> Result<()> {
-        let repo_path = self.repo_path(owner, name);
-
-        if !repo_path.exists() {
-            bail!("repository does not exist: {}/{}", owner, name);
+    /// List the paths with conflicts in a commit's tree, sorted.
+    ///
+    /// A commit without conflicts is answered without walking its tree.
+    pub fn conflicted_paths(&self, id: &object_id::CommitId) -> Result<Vec<String>> {
+        let commit = self.commit_by_id(id)?;
+        if commit.tree_ids().is_resolved() {
+            return Ok(Vec::new());
         }
-
-        info!("deleting repository at {}", repo_path.display());
-
-        std::fs::remove_dir_all(&repo_path)
-            .with_context(|| format!("failed to delete: {}", repo_path.display()))?;
-
-        Ok(())
+        Ok(file_values(&commit.tree())?
+            .into_iter()
+            .filter(|(_, value)| !value.is_resolved())
+            .map(|(path, _)| path.as_internal_file_string().to_string())
+            .collect())
     }
 
-    /// List all repositories for an owner.
-    pub fn list_repos(&self, owner: &str) -> Result<Vec<RepoInfo>> {
-        let owner_path = self.config.repos_root.join(owner);
-        if !owner_path.exists() {
-            return Ok(Vec::new());
+    /// Open a file for reading, without reading it into memory.
+    pub async fn open_file(
+        &self,
+        path: &str,
+        id: &object_id::FileId,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let path = RepoPathBuf::from_internal_string(path)
+            .with_context(|| format!("invalid path: {path}"))?;
+        self.repo
+            .store()
+            .read_file(&path, &id.into())
+            .await
+            .context("failed to read file")
+    }
+
+    /// Read the target of a symlink.
+    pub async fn symlink_target(&self, path: &str, id: &object_id::SymlinkId) -> Result<String> {
+        let path = RepoPathBuf::from_internal_string(path)
+            .with_context(|| format!("invalid path: {path}"))?;
+        self.repo
+            .store()
+            .read_symlink(&path, &id.into())
+            .await
+            .context("failed to read symlink")
+    }
+
+    /// Write a commit's tree to `out` as a tar archive, with every path
+    /// under `prefix`, and return `out`.
+    ///
+    /// The archive only depends on the commit: entries are sorted by path
+    /// and carry the committer time and no owner, so a commit always gives
+    /// the same bytes. Files are read and written one at a time, so `out`
+    /// can stream the archive. Fails on a tree with conflicts; entries other
+    /// than files and symlinks, such as git submodules, are left out.
+    pub fn export_tar<W: Write>(
+        &self,
+        id: &object_id::CommitId,
+        prefix: &str,
+        out: W,
+    ) -> Result<W> {
+        let commit = self.commit_by_id(id)?;
+        let mtime = commit
+            .committer()
+            .timestamp
+            .timestamp
+            .0
+            .div_euclid(1000)
+            .max(0) as u64;
+        let mut builder = tar::Builder::new(out);
+        for (path, value) in file_values(&commit.tree())? {
+            let name = format!("{prefix}{}", path.as_internal_file_string());
+            let Some(value) = value.as_resolved() else {
+                bail!("{} has conflicts", path.as_internal_file_string());
+            };
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(mtime);
+            match value {
+                Some(TreeValue::File { id, executable, .. }) => {
+                    let content = self.read_file(&path, id).block_on()?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(if *executable { 0o755 } else { 0o644 });
+                    header.set_size(content.len() as u64);
+                    builder
+                        .append_data(&mut header, &name, &content[..])
+                        .with_context(|| format!("failed to archive {name}"))?;
+                }
+                Some(TreeValue::Symlink(id)) => {
+                    let target = self
+                        .repo
+                        .store()
+                        .read_symlink(&path, id)
+                        .block_on()
+                        .context("failed to read symlink")?;
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder
+                        .append_link(&mut header, &name, &target)
+                        .with_context(|| format!("failed to archive {name}"))?;
+                }
+                _ => {}
+            }
         }
+        builder.into_inner().context("failed to finish archive")
+    }
 
-        let mut repos = Vec::new();
-        for entry in std::fs::read_dir(&owner_path)
-            .with_context(|| format!("failed to read directory: {}", owner_path.display()))?
+    /// Read the contents of a file listed by
+    /// [`commit_changes`](Self::commit_changes).
+    pub async fn file_content(&self, path: &str, id: &object_id::FileId) -> Result<Vec<u8>> {
+        let path = RepoPathBuf::from_internal_string(path)
+            .with_context(|| format!("invalid path: {path}"))?;
+        self.read_file(&path, &id.into()).await
+    }
+
+    /// Find the commit each line of a file last changed in.
+    ///
+    /// History is followed through first parents, back to where the file
+    /// was added, as a file rather than a symlink or conflict. Returns the
+    /// file's lines as ranges of consecutive lines from the same commit, in
+    /// order. Fails if `path` isn't a file at `id`.
+    pub async fn blame(&self, id: &object_id::CommitId, path: &str) -> Result<Vec<BlameRange>> {
+        let PathValue::File { id: mut file, .. } = self.path_value(id, path).await? else {
+            bail!("{path} is not a file");
+        };
+        let mut commit = *id;
+        let mut content = self.file_content(path, &file).await?;
+        let line_count = content.split_inclusive(|&b| b == b'\n').count();
+        let mut origins = vec![None; line_count];
+        // Lines not yet attributed, as (line in `content`, line of the file).
+        let mut pending: Vec<(usize, usize)> = (0..line_count).map(|line| (line, line)).collect();
+        let root = self.root_commit_id()?;
+        while !pending.is_empty() {
+            let parent = self
+                .commit_parent_ids(&commit)?
+                .first()
+                .copied()
+                .filter(|parent| *parent != root);
+            let parent_file = match parent {
+                Some(parent) => match self.path_value(&parent, path).await? {
+                    PathValue::File { id, .. } => Some((parent, id)),
+                    _ => None,
+                },
+                None => None,
+            };
+            let Some((parent, parent_file)) = parent_file else {
+                for (_, line) in pending.drain(..) {
+                    origins[line] = Some(commit);
+                }
+                break;
+            };
+            if parent_file != file {
+                let parent_content = self.file_content(path, &parent_file).await?;
+                let from_parent = unchanged_lines(&parent_content, &content);
+                pending.retain_mut(|(current, line)| match from_parent[*current] {
+                    Some(before) => {
+                        *current = before;
+                        true
+                    }
+                    None => {
+                        origins[*line] = Some(commit);
+                        false
+                    }
+                });
+                content = parent_content;
+                file = parent_file;
+            }
+            commit = parent;
+        }
+
+        let mut ranges: Vec<BlameRange> = Vec::new();
+        for (line, origin) in origins.into_iter().enumerate() {
+            let origin = origin.context("line left unattributed")?;
+            match ranges.last_mut() {
+                Some(range) if range.commit == origin => range.line_count += 1,
+                _ => ranges.push(BlameRange {
+                    commit: origin,
+                    start_line: line + 1,
+                    line_count: 1,
+                }),
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Whether a commit changes what is at `path` from its first parent.
+    async fn changes_path(&self, id: &object_id::CommitId, path: &RepoPath) -> Result<bool> {
+        let commit = self.commit_by_id(id)?;
+        let before = match commit.parent_ids().first() {
+            Some(parent) => self.path_values(&self.get_commit(parent)?, path).await?,
+            None => Vec::new(),
+        };
+        Ok(self.path_values(&commit, path).await? != before)
+    }
+
+    /// What is at `path` in each of a commit's trees, `None` where nothing
+    /// is.
+    async fn path_values(
+        &self,
+        commit: &Commit,
+        path: &RepoPath,
+    ) -> Result<Vec<Option<TreeValue>>> {
+        let store = self.repo.store();
+        let mut values = Vec::new();
+        for tree_id in commit.tree_ids().iter() {
+            let mut dir = RepoPathBuf::root();
+            let mut value = Some(TreeValue::Tree(tree_id.clone()));
+            for component in path.components() {
+                let Some(TreeValue::Tree(id)) = value.take() else {
+                    break;
+                };
+                let tree = store
+                    .get_tree(dir.clone(), &id)
+                    .await
+                    .with_context(|| format!("failed to read tree {}", id.hex()))?;
+                value = tree.value(component).cloned();
+                dir = dir.join(component);
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Check if this is a fresh repository with no user commits.
+    ///
+    /// A fresh jj repository has:
+    /// - A root commit (empty, parent of all commits)
+    /// - A working-copy commit (child of root, may be empty)
+    ///
+    /// This returns true if all heads are either the root or empty commits
+    /// with only the root as parent.
+    pub fn is_fresh(&self) -> bool {
+        let heads = self.heads();
+        let root_id = self.root_commit().id().clone();
+
+        heads.iter().all(|head_id| {
+            if *head_id == root_id {
+                return true;
+            }
+            // Check if this is an empty working-copy commit (child of root only)
+            if let Ok(commit) = self.get_commit(head_id) {
+                let parents = commit.parent_ids();
+                // Fresh working-copy commit has only root as parent
+                parents.len() == 1 && parents[0] == root_id
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Get the local target of a bookmark.
+    pub fn bookmark_target(&self, name: &str) -> Result<BookmarkTarget> {
+        let target = self.repo.view().get_local_bookmark(RefName::new(name));
+        to_bookmark_target(target)
+            .with_context(|| format!("failed to convert target of bookmark {name}"))
+    }
+
+    /// Get the target of a tag, in the same form as a bookmark's.
+    pub fn tag_target(&self, name: &str) -> Result<BookmarkTarget> {
+        let target = self.repo.view().get_local_tag(RefName::new(name));
+        to_bookmark_target(target)
+            .with_context(|| format!("failed to convert target of tag {name}"))
+    }
+
+    /// List the trees, files, and symlinks that make up a commit's tree.
+    pub async fn commit_objects(&self, id: &object_id::CommitId) -> Result<CommitObjects> {
+        let commit = self.commit_by_id(id)?;
+        let store = self.repo.store();
+        let mut objects = CommitObjects::default();
+        let mut seen = HashSet::new();
+        let mut pending: Vec<_> = commit
+            .tree_ids()
+            .iter()
+            .map(|tree_id| (RepoPathBuf::root(), tree_id.clone()))
+            .collect();
+        while let Some((dir, tree_id)) = pending.pop() {
+            // The same tree can appear under several paths; files are
+            // listed under each of them.
+            if !seen.insert((dir.clone(), tree_id.clone())) {
+                continue;
+            }
+            let tree = store
+                .get_tree(dir.clone(), &tree_id)
+                .await
+                .with_context(|| format!("failed to read tree {}", tree_id.hex()))?;
+            let tree_id = ObjectId::try_from(&tree_id).context("failed to convert tree id")?;
+            if !objects.trees.contains(&tree_id) {
+                objects.trees.push(tree_id);
+            }
+
+            for entry in tree.entries() {
+                let path = dir.join(entry.name());
+                match entry.value() {
+                    TreeValue::File { id, .. } => {
+                        let id = ObjectId::try_from(id).context("failed to convert file id")?;
+                        objects
+                            .files
+                            .push((path.as_internal_file_string().to_string(), id));
+                    }
+                    TreeValue::Symlink(id) => {
+                        let id = ObjectId::try_from(id).context("failed to convert symlink id")?;
+                        objects
+                            .symlinks
+                            .push((path.as_internal_file_string().to_string(), id));
+                    }
+                    TreeValue::Tree(id) => pending.push((path, id.clone())),
+                    _ => {}
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Write a commit on top of `parents` that sets `files` in their merged
+    /// tree, and make it visible.
+    ///
+    /// The parents' trees are merged as `jj new` merges them, so parents
+    /// that conflict leave conflicts in paths `files` doesn't set. With no
+    /// parents, the commit goes on the root commit.
+    pub async fn write_commit(
+        &mut self,
+        parents: &[object_id::CommitId],
+        files: &[(&str, &[u8])],
+        description: &str,
+    ) -> Result<object_id::CommitId> {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(path, content)| (*path, Some(*content)))
+            .collect();
+        self.write_tree_changes(parents, &files, description).await
+    }
+
+    /// Write a commit on top of `parents` that removes `paths` from their
+    /// merged tree, as [`write_commit`](Self::write_commit) sets files.
+    pub async fn remove_files(
+        &mut self,
+        parents: &[object_id::CommitId],
+        paths: &[&str],
+        description: &str,
+    ) -> Result<object_id::CommitId> {
+        let files: Vec<_> = paths.iter().map(|path| (*path, None)).collect();
+        self.write_tree_changes(parents, &files, description).await
+    }
+
+    /// Write a commit on top of `parents` that sets the files given content
+    /// and removes those given `None`.
+    async fn write_tree_changes(
+        &mut self,
+        parents: &[object_id::CommitId],
+        files: &[(&str, Option<&[u8]>)],
+        description: &str,
+    ) -> Result<object_id::CommitId> {
+        let store = self.repo.store().clone();
+        let parent_ids: Vec<CommitId> = if parents.is_empty() {
+            vec![store.root_commit_id().clone()]
+        } else {
+            parents.iter().map(CommitId::from).collect()
+        };
+
+        let parent_commits = parent_ids
+            .iter()
+            .map(|id| self.get_commit(id))
+            .collect::<Result<Vec<_>>>()?;
+        let base = merge_commit_trees(self.repo.as_ref(), &parent_commits)
+            .await
+            .context("failed to merge parent trees")?;
+        let mut builder = MergedTreeBuilder::new(base);
+        for (path, content) in files {
+            let path = RepoPathBuf::from_internal_string(*path)
+                .with_context(|| format!("invalid path: {path}"))?;
+            let Some(content) = content else {
+                builder.set_or_remove(path, Merge::absent());
+                continue;
+            };
+            let id = store
+                .write_file(&path, &mut &content[..])
+                .await
+                .with_context(|| format!("failed to write file {path:?}"))?;
+            builder.set_or_remove(
+                path,
+                Merge::normal(TreeValue::File {
+                    id,
+                    executable: false,
+                    copy_id: CopyId::placeholder(),
+                }),
+            );
+        }
+        let tree = builder.write_tree().context("failed to write tree")?;
+
+        let mut tx = self.repo.start_transaction();
+        let commit = tx
+            .repo_mut()
+            .new_commit(parent_ids, tree)
+            .set_description(description)
+            .write()
+            .context("failed to write commit")?;
+        self.repo = tx.commit(description).context("failed to commit")?;
+        ObjectId::try_from(commit.id()).context("failed to convert commit id")
+    }
+
+    /// Names of all local bookmarks, conflicted ones included.
+    pub fn bookmark_names(&self) -> Vec<String> {
+        self.repo
+            .view()
+            .local_bookmarks()
+            .map(|(name, _)| name.as_str().to_string())
+            .collect()
+    }
+
+    /// Names of all tags.
+    pub fn tag_names(&self) -> Vec<String> {
+        self.repo
+            .view()
+            .local_tags()
+            .map(|(name, _)| name.as_str().to_string())
+            .collect()
+    }
+
+    /// Local bookmarks and the commits they point at, sorted by name.
+    ///
+    /// Unlike [`bookmarks`](Self::bookmarks), a conflicted bookmark lists
+    /// every commit it could point at.
+    pub fn local_bookmarks(&self) -> Result<Vec<LocalRef>> {
+        ref_targets("bookmark", self.repo.view().local_bookmarks())
+    }
+
+    /// Tags and the commits they point at, like
+    /// [`local_bookmarks`](Self::local_bookmarks).
+    pub fn local_tags(&self) -> Result<Vec<LocalRef>> {
+        ref_targets("tag", self.repo.view().local_tags())
+    }
+
+    /// Check if a commit exists in the store.
+    pub fn has_commit(&self, id: &object_id::CommitId) -> bool {
+        self.commit_by_id(id).is_ok()
+    }
+
+    /// Look up a commit by its hex ID or a prefix of it.
+    ///
+    /// Every commit in the store counts, whether or not it is visible.
+    pub fn resolve_commit_prefix(&self, prefix: &str) -> Result<PrefixResolution> {
+        if prefix.is_empty()
+            || prefix.len() > 2 * object_id::MAX_ID_LEN
+            || !prefix.bytes().all(|b| b.is_ascii_hexdigit())
         {
-            let entry = entry?;
-            let path = entry.path();
-            if path.join(".jj").exists() {
-                let name = entry.file_name().to_string_lossy().to_string();
-                let backend_type = self.detect_backend_type(&path)?;
-                repos.push(RepoInfo {
-                    name,
-                    owner: owner.to_string(),
-                    path,
-                    backend_type,
+            bail!("invalid commit ID prefix {prefix:?}");
+        }
+        let prefix = prefix.to_ascii_lowercase();
+        let commits = self.list_raw_objects(RawObjectKind::Commit)?;
+        let mut found: Vec<_> = std::iter::once(self.root_commit_id()?)
+            .chain(commits)
+            .filter(|id| id.to_hex().starts_with(&prefix))
+            .collect();
+        found.sort();
+        found.dedup();
+        Ok(PrefixResolution::of(found))
+    }
+
+    /// Look up a visible commit by its change ID or a prefix of it, in
+    /// either hex alphabet.
+    ///
+    /// A divergent change, with several visible commits, is ambiguous.
+    pub fn resolve_change_prefix(&self, prefix: &str) -> Result<PrefixResolution> {
+        let hex = match object_id::change_id_prefix_to_hex(prefix) {
+            Ok(hex) if !hex.is_empty() && hex.len() <= 2 * object_id::CHANGE_ID_LEN => hex,
+            _ => bail!("invalid change ID prefix {prefix:?}"),
+        };
+        let mut found = Vec::new();
+        for id in self.ancestors_of_heads()? {
+            let change_id = ObjectId::try_from(self.commit_by_id(&id)?.change_id())
+                .context("failed to convert change id")?;
+            if change_id.to_hex().starts_with(&hex) {
+                found.push(id);
+            }
+        }
+        found.sort();
+        Ok(PrefixResolution::of(found))
+    }
+
+    /// Collect every visible commit.
+    fn ancestors_of_heads(&self) -> Result<HashSet<object_id::CommitId>> {
+        let mut seen = HashSet::new();
+        for head in self.head_ids()? {
+            if !seen.contains(&head) {
+                seen.extend(self.ancestors(&head)?);
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Count the commits reachable from the visible heads, and find when
+    /// the oldest and newest of them were committed. The root commit
+    /// doesn't count.
+    pub fn commit_stats(&self) -> Result<CommitStats> {
+        let root = self.root_commit_id()?;
+        let mut stats = CommitStats {
+            count: 0,
+            first_time: None,
+            latest_time: None,
+        };
+        for id in self.ancestors_of_heads()? {
+            if id == root {
+                continue;
+            }
+            let (time, _) = self.log_key(&id)?;
+            stats.count += 1;
+            stats.first_time = Some(stats.first_time.map_or(time, |first| first.min(time)));
+            stats.latest_time = Some(stats.latest_time.map_or(time, |latest| latest.max(time)));
+        }
+        Ok(stats)
+    }
+
+    /// Find the `count` largest files in a commit's tree, largest first.
+    ///
+    /// Every file is read to measure it. Paths with conflicts are left out.
+    pub async fn largest_files(
+        &self,
+        id: &object_id::CommitId,
+        count: usize,
+    ) -> Result<Vec<FileSize>> {
+        let commit = self.commit_by_id(id)?;
+        let mut files = Vec::new();
+        for (path, value) in file_values(&commit.tree())? {
+            if let (Some(_), Some(size)) = self.file_version(&path, Some(&value)).await? {
+                files.push(FileSize {
+                    path: path.as_internal_file_string().to_string(),
+                    size,
                 });
             }
         }
+        files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+        files.truncate(count);
+        Ok(files)
+    }
+
+    /// Bytes the repository's files take on disk, sidecars included.
+    pub fn disk_size(&self) -> Result<u64> {
+        let mut size = 0;
+        let mut pending = vec![self.info.path.clone()];
+        while let Some(dir) = pending.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("failed to read {}", dir.display()))?;
+            for entry in entries {
+                let entry = entry.with_context(|| format!("failed to read {}", dir.display()))?;
+                let metadata = entry
+                    .metadata()
+                    .with_context(|| format!("failed to stat {}", entry.path().display()))?;
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    size += metadata.len();
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// Whether a commit is reachable from a visible head, rather than only
+    /// kept in the store, e.g. after being abandoned or rewritten.
+    pub fn is_visible(&self, id: &object_id::CommitId) -> Result<bool> {
+        let mut seen = HashSet::new();
+        let mut pending = self.head_ids()?;
+        while let Some(commit) = pending.pop() {
+            if commit == *id {
+                return Ok(true);
+            }
+            if seen.insert(commit) {
+                pending.extend(self.commit_parent_ids(&commit)?);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Point bookmarks at new commits, or delete them, in one operation.
+    ///
+    /// Targets must already be in the store. Returns the ID of the new
+    /// operation.
+    pub fn set_bookmarks(
+        &mut self,
+        targets: &[(String, Option<object_id::CommitId>)],
+        description: &str,
+    ) -> Result<object_id::OperationId> {
+        let commits = targets
+            .iter()
+            .filter_map(|(_, target)| target.as_ref())
+            .map(|id| self.commit_by_id(id))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tx = self.repo.start_transaction();
+        // Index commits that arrived in a pack before anything refers to them.
+        tx.repo_mut()
+            .add_heads(&commits)
+            .context("failed to add bookmark targets to the index")?;
+        for (name, target) in targets {
+            tx.repo_mut()
+                .set_local_bookmark_target(RefName::new(name), to_ref_target(target));
+        }
+        self.repo = tx
+            .commit(description)
+            .context("failed to commit bookmark updates")?;
+        self.current_op_id()
+    }
+
+    /// Point tags at commits, or delete them, in one operation.
+    ///
+    /// Targets must already be in the store. Returns the ID of the new
+    /// operation.
+    pub fn set_tags(
+        &mut self,
+        targets: &[(String, Option<object_id::CommitId>)],
+        description: &str,
+    ) -> Result<object_id::OperationId> {
+        let commits = targets
+            .iter()
+            .filter_map(|(_, target)| target.as_ref())
+            .map(|id| self.commit_by_id(id))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tx = self.repo.start_transaction();
+        tx.repo_mut()
+            .add_heads(&commits)
+            .context("failed to add tag targets to the index")?;
+        for (name, target) in targets {
+            tx.repo_mut()
+                .set_local_tag_target(RefName::new(name), to_ref_target(target));
+        }
+        self.repo = tx
+            .commit(description)
+            .context("failed to commit tag updates")?;
+        self.current_op_id()
+    }
+
+    /// Move bookmarks like [`set_bookmarks`](Self::set_bookmarks), but only
+    /// if each one is still at its expected target.
+    ///
+    /// Every expectation is checked against the repository as loaded, which
+    /// is also what the single operation moving the bookmarks builds on, so
+    /// either all of them move or none do. If one has moved, the error
+    /// downcasts to [`StaleBookmark`] naming the first such bookmark; if a
+    /// name is invalid, to [`InvalidBookmarkName`]. [`StorageError::of`]
+    /// finds either.
+    pub fn update_bookmarks(
+        &mut self,
+        updates: &[BookmarkUpdate],
+        description: &str,
+    ) -> Result<object_id::OperationId> {
+        for update in updates {
+            if let Err(error) = validate_bookmark_name(&update.name) {
+                return Err(InvalidBookmarkName {
+                    name: update.name.clone(),
+                    error,
+                }
+                .into());
+            }
+        }
+        for update in updates {
+            let actual = self.bookmark_target(&update.name)?;
+            if actual != update.expected {
+                return Err(StaleBookmark {
+                    name: update.name.clone(),
+                    actual,
+                }
+                .into());
+            }
+        }
+        let targets: Vec<_> = updates
+            .iter()
+            .map(|update| (update.name.clone(), update.target))
+            .collect();
+        self.set_bookmarks(&targets, description)
+    }
+
+    /// List the IDs of every object of one kind in the store.
+    ///
+    /// Raw objects are only available for the native backend.
+    pub fn list_raw_objects(&self, kind: RawObjectKind) -> Result<Vec<ObjectId>> {
+        let dir = self.raw_object_dir(kind)?;
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("failed to read directory: {}", dir.display()))?
+        {
+            let name = entry?.file_name();
+            // Skip temporary files left behind by interrupted writes.
+            if let Some(id) = name.to_str().and_then(|name| ObjectId::from_hex(name).ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Check if an object is in the store.
+    pub fn has_raw_object(&self, kind: RawObjectKind, id: &ObjectId) -> Result<bool> {
+        Ok(self.raw_object_dir(kind)?.join(id.to_hex()).exists())
+    }
+
+    /// Read an object's stored bytes.
+    pub fn read_raw_object(&self, kind: RawObjectKind, id: &ObjectId) -> Result<Vec<u8>> {
+        let path = self.raw_object_dir(kind)?.join(id.to_hex());
+        std::fs::read(&path).with_context(|| format!("failed to read: {}", path.display()))
+    }
+
+    /// Store an object's bytes as read from another repository.
+    ///
+    /// The caller checks that `id` names `data`, e.g. with
+    /// [`RawObjectKind::native_id`]. Objects are content-addressed, so an
+    /// object that is already present is left alone, but only if its stored
+    /// bytes are the same. Returns whether the object was written.
+    pub fn write_raw_object(
+        &self,
+        kind: RawObjectKind,
+        id: &ObjectId,
+        data: &[u8],
+    ) -> Result<bool> {
+        let dir = self.raw_object_dir(kind)?;
+        let path = dir.join(id.to_hex());
+        if path.exists() {
+            if self.read_raw_object(kind, id)? != data {
+                bail!("{kind:?} object {id} is already stored with different contents");
+            }
+            return Ok(false);
+        }
+
+        // Write then rename, so readers never see a partial object.
+        let temp = dir.join(format!(".{}.tmp", id.to_hex()));
+        std::fs::write(&temp, data)
+            .with_context(|| format!("failed to write: {}", temp.display()))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Read a file forjj keeps alongside the repository's data, if there is
+    /// one by that name.
+    ///
+    /// `name` must be valid as a repository name.
+    pub fn read_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.info.read_sidecar(name)
+    }
+
+    /// Replace the sidecar file `name` with what `update` makes of its
+    /// current contents, if any.
+    ///
+    /// Updates from this process don't interleave, and readers see the old
+    /// contents or the new ones, never a mix.
+    pub fn update_sidecar<F>(&self, name: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>>,
+    {
+        static UPDATES: Mutex<()> = Mutex::new(());
+        let _guard = UPDATES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let data = update(self.read_sidecar(name)?)?;
+        let path = self.info.sidecar_path(name)?;
+        let dir = self.info.sidecar_dir();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+        let temp = dir.join(format!(".{name}.tmp"));
+        std::fs::write(&temp, data)
+            .with_context(|| format!("failed to write: {}", temp.display()))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Add an operation head, e.g. one fetched from another repository, and
+    /// reload the repository.
+    ///
+    /// The operation and everything it refers to must already be in the
+    /// store. Divergent heads are merged when the repository is reloaded.
+    pub fn add_op_head(&mut self, id: &object_id::OperationId) -> Result<()> {
+        if !self.has_raw_object(RawObjectKind::Operation, id)? {
+            bail!("operation {id} is not in the store");
+        }
+        let path = self
+            .info
+            .path
+            .join(".jj/repo/op_heads/heads")
+            .join(id.to_hex());
+        std::fs::write(&path, b"")
+            .with_context(|| format!("failed to write: {}", path.display()))?;
+        self.reload()
+    }
+
+    /// Load the repository as it would be with `heads` adopted alongside
+    /// its current operation, without publishing anything.
+    ///
+    /// The operations must already be in the store. Divergent heads are
+    /// merged into a new operation. The staged operation becomes a head only
+    /// through [`publish_staged_op`](Self::publish_staged_op) or an operation
+    /// made on top of it, such as [`set_bookmarks`](Self::set_bookmarks);
+    /// [`reload`](Self::reload) discards it.
+    pub fn stage_op_heads(&mut self, heads: &[object_id::OperationId]) -> Result<()> {
+        let loader = self.repo.loader().clone();
+        let mut ops = vec![self.repo.operation().clone()];
+        for id in heads {
+            let op = loader
+                .load_operation(&OperationId::from(id))
+                .with_context(|| format!("failed to load operation {id}"))?;
+            ops.push(op);
+        }
+        // Leave out operations the others build on, keeping the current one
+        // first so that the merge starts from it.
+        let mut kept = dag_walk::heads_ok(
+            ops.iter().cloned().map(Ok),
+            |op: &Operation| op.id().clone(),
+            |op: &Operation| op.parents().collect::<Vec<_>>(),
+        )
+        .context("failed to walk the operation log")?;
+        ops.retain(|op| kept.remove(op));
+        let op = loader
+            .merge_operations(ops, Some("merge pushed operations"))
+            .context("failed to merge operations")?;
+        self.repo = loader
+            .load_at(&op)
+            .context("failed to load staged operation")?;
+        Ok(())
+    }
+
+    /// Publish the operation staged by
+    /// [`stage_op_heads`](Self::stage_op_heads) as an operation head, and
+    /// reload the repository. Returns its ID.
+    pub fn publish_staged_op(&mut self) -> Result<object_id::OperationId> {
+        let id = self.current_op_id()?;
+        self.add_op_head(&id)?;
+        Ok(id)
+    }
+
+    /// Reload the repository at its current operation heads.
+    pub fn reload(&mut self) -> Result<()> {
+        self.repo = self
+            .workspace
+            .repo_loader()
+            .load_at_head()
+            .context("failed to load repository at head")?;
+        Ok(())
+    }
+
+    fn raw_object_dir(&self, kind: RawObjectKind) -> Result<PathBuf> {
+        if self.info.backend_type != BackendType::Native {
+            bail!(
+                "raw objects are not available for the {} backend",
+                self.info.backend_type.as_str()
+            );
+        }
+        Ok(self.info.path.join(".jj/repo").join(kind.dir()))
+    }
+}
+
+/// For each line of `after`, the line of `before` it is unchanged from, if
+/// any.
+fn unchanged_lines(before: &[u8], after: &[u8]) -> Vec<Option<usize>> {
+    let input = InternedInput::new(before, after);
+    let mut mapping = Vec::with_capacity(input.after.len());
+    let mut before_line = 0;
+    diff(
+        Algorithm::Histogram,
+        &input,
+        |removed: Range<u32>, added: Range<u32>| {
+            // Lines between hunks are unchanged.
+            while mapping.len() < added.start as usize {
+                mapping.push(Some(before_line));
+                before_line += 1;
+            }
+            mapping.extend(added.map(|_| None));
+            before_line = removed.end as usize;
+        },
+    );
+    while mapping.len() < input.after.len() {
+        mapping.push(Some(before_line));
+        before_line += 1;
+    }
+    mapping
+}
+
+/// The value the terms of a merge, adds and removes alternating, resolve to
+/// without looking inside them, or `None` if they conflict.
+fn trivial_merge<T: PartialEq>(terms: &[T]) -> Option<&T> {
+    let mut adds: Vec<&T> = terms.iter().step_by(2).collect();
+    let mut removes: Vec<&T> = terms.iter().skip(1).step_by(2).collect();
+    // A remove cancels out an add of the same value.
+    removes.retain(|remove| match adds.iter().position(|add| add == remove) {
+        Some(index) => {
+            adds.remove(index);
+            false
+        }
+        None => true,
+    });
+    match adds[..] {
+        [add] => Some(add),
+        // Every side made the same change.
+        [first, ..]
+            if adds.iter().all(|add| *add == first)
+                && removes.iter().all(|remove| *remove == removes[0]) =>
+        {
+            Some(first)
+        }
+        _ => None,
+    }
+}
+
+/// What is at each path of `tree` that holds something other than a tree.
+fn file_values(tree: &MergedTree) -> Result<BTreeMap<RepoPathBuf, MergedTreeValue>> {
+    tree.entries()
+        .map(|(path, value)| {
+            let value = value.with_context(|| {
+                format!(
+                    "failed to read tree entry {}",
+                    path.as_internal_file_string()
+                )
+            })?;
+            Ok((path, value))
+        })
+        .collect()
+}
+
+fn to_ref_target(target: &Option<object_id::CommitId>) -> RefTarget {
+    match target {
+        Some(id) => RefTarget::normal(CommitId::from(id)),
+        None => RefTarget::absent(),
+    }
+}
+
+fn ref_targets<'a>(
+    kind: &str,
+    refs: impl Iterator<Item = (&'a RefName, &'a RefTarget)>,
+) -> Result<Vec<LocalRef>> {
+    refs.map(|(name, target)| {
+        let targets = target
+            .added_ids()
+            .map(ObjectId::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("failed to convert target of {kind} {}", name.as_str()))?;
+        Ok(LocalRef {
+            name: name.as_str().to_string(),
+            targets,
+            conflicted: target.has_conflict(),
+        })
+    })
+    .collect()
+}
+
+fn to_bookmark_target(target: &RefTarget) -> Result<BookmarkTarget, object_id::ObjectIdError> {
+    if target.is_absent() {
+        return Ok(BookmarkTarget::Absent);
+    }
+    if let Some(id) = target.as_normal() {
+        return Ok(BookmarkTarget::Normal(ObjectId::try_from(id)?));
+    }
+    let adds = target
+        .added_ids()
+        .map(ObjectId::try_from)
+        .collect::<Result<_, _>>()?;
+    let removes = target
+        .removed_ids()
+        .map(ObjectId::try_from)
+        .collect::<Result<_, _>>()?;
+    Ok(BookmarkTarget::conflicted(adds, removes))
+}
+
+/// Local target of a bookmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BookmarkTarget {
+    /// The bookmark doesn't exist
+    Absent,
+    /// The bookmark points at a single commit
+    Normal(object_id::CommitId),
+    /// The bookmark has conflicting targets: concurrent operations moved it
+    /// to each of `adds`, from `removes`. Both are sorted, so equal
+    /// conflicts compare equal.
+    Conflicted {
+        adds: Vec<object_id::CommitId>,
+        removes: Vec<object_id::CommitId>,
+    },
+}
+
+impl BookmarkTarget {
+    /// A conflicted target, with `adds` and `removes` in any order.
+    pub fn conflicted(
+        mut adds: Vec<object_id::CommitId>,
+        mut removes: Vec<object_id::CommitId>,
+    ) -> Self {
+        adds.sort();
+        removes.sort();
+        BookmarkTarget::Conflicted { adds, removes }
+    }
+}
+
+/// Outcome of [`Repository::resolve_commit_prefix`] and
+/// [`Repository::resolve_change_prefix`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrefixResolution {
+    /// Exactly one commit matches
+    Single(object_id::CommitId),
+    /// Several commits match; these, sorted
+    Ambiguous(Vec<object_id::CommitId>),
+    /// No commit matches
+    NoMatch,
+}
+
+impl PrefixResolution {
+    /// The resolution of a prefix that `found`, sorted and without
+    /// duplicates, match.
+    fn of(mut found: Vec<object_id::CommitId>) -> Self {
+        match found.len() {
+            0 => PrefixResolution::NoMatch,
+            1 => PrefixResolution::Single(found.remove(0)),
+            _ => PrefixResolution::Ambiguous(found),
+        }
+    }
+}
+
+/// A local bookmark or tag and where it points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalRef {
+    /// Name of the bookmark or tag
+    pub name: String,
+    /// The commit it points at, or each commit it could point at if it is
+    /// conflicted
+    pub targets: Vec<object_id::CommitId>,
+    /// Whether it has conflicting targets
+    pub conflicted: bool,
+}
+
+/// A compare-and-set bookmark move, for [`Repository::update_bookmarks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookmarkUpdate {
+    /// Name of the bookmark
+    pub name: String,
+    /// Where the bookmark must currently point: absent if it must not
+    /// exist, or exactly the conflict the update resolves
+    pub expected: BookmarkTarget,
+    /// Where to point it, `None` to delete it
+    pub target: Option<object_id::CommitId>,
+}
+
+/// A bookmark that wasn't at the target an update expected.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("bookmark {name} has moved")]
+pub struct StaleBookmark {
+    /// Name of the bookmark
+    pub name: String,
+    /// Where it actually points
+    pub actual: BookmarkTarget,
+}
+
+/// A bookmark update with a name [`validate_bookmark_name`] rejects.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid bookmark name {name:?}: {error}")]
+pub struct InvalidBookmarkName {
+    /// The rejected name
+    pub name: String,
+    /// Why it was rejected
+    pub error: BookmarkNameError,
+}
+
+/// A call storage refused, rather than failed to make.
+///
+/// Calls return `anyhow::Error`s; a refusal downcasts to this, or to the
+/// [`StaleBookmark`] or [`InvalidBookmarkName`] it wraps, and
+/// [`StorageError::of`] finds it either way.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StorageError {
+    /// There is no such repository
+    #[error("repository {owner}/{name} does not exist")]
+    NotFound { owner: String, name: String },
+
+    /// The repository to create is already there
+    #[error("repository {owner}/{name} already exists")]
+    AlreadyExists { owner: String, name: String },
+
+    /// An owner or repository name storage doesn't accept
+    #[error("invalid name {name:?}: {error}")]
+    InvalidName { name: String, error: NameError },
+
+    /// The write would take the repository over a limit
+    #[error("repository limit of {limit} exceeded: {attempted} attempted")]
+    QuotaExceeded { limit: u64, attempted: u64 },
+
+    /// The repository is archived and takes no writes
+    #[error("repository is archived")]
+    Archived,
+
+    /// The bookmark may not be changed
+    #[error("bookmark {name} is protected")]
+    Protected { name: String },
+
+    #[error(transparent)]
+    Stale(#[from] StaleBookmark),
+
+    #[error(transparent)]
+    InvalidBookmarkName(#[from] InvalidBookmarkName),
+}
+
+impl StorageError {
+    /// The refusal `error` carries, or `None` if the write failed for
+    /// another reason.
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        if let Some(refused) = error.downcast_ref::<StorageError>() {
+            return Some(refused.clone());
+        }
+        if let Some(stale) = error.downcast_ref::<StaleBookmark>() {
+            return Some(stale.clone().into());
+        }
+        error
+            .downcast_ref::<InvalidBookmarkName>()
+            .map(|invalid| invalid.clone().into())
+    }
+}
+
+/// An operation in the operation log, as returned by
+/// [`Repository::operation_log`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationEntry {
+    /// The operation's ID
+    pub id: object_id::OperationId,
+    /// The operations it was based on; empty for the root operation
+    pub parents: Vec<object_id::OperationId>,
+    /// The operation's view
+    pub view_id: object_id::ViewId,
+    /// Visible head commits in the operation's view
+    pub view_heads: Vec<object_id::CommitId>,
+    /// What the operation did, as shown by `jj op log`
+    pub description: String,
+}
+
+/// The objects making up a commit's tree, as returned by
+/// [`Repository::commit_objects`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitObjects {
+    /// Every tree, the root tree(s) included
+    pub trees: Vec<object_id::TreeId>,
+    /// Files, with their paths
+    pub files: Vec<(String, object_id::FileId)>,
+    /// Symlinks, with their paths
+    pub symlinks: Vec<(String, object_id::SymlinkId)>,
+}
+
+/// What is at a path of a commit's tree, as found by
+/// [`Repository::path_value`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathValue {
+    /// Nothing is
+    Absent,
+    File {
+        id: object_id::FileId,
+        executable: bool,
+    },
+    Symlink(object_id::SymlinkId),
+    /// A directory
+    Tree,
+    /// Values that conflict, from this many sides
+    Conflict {
+        sides: usize,
+    },
+    /// Something else, such as a git submodule
+    Other,
+}
+
+/// How a commit changes a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl FileChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileChangeKind::Added => "added",
+            FileChangeKind::Modified => "modified",
+            FileChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A file a commit changes, as listed by [`Repository::commit_changes`].
+///
+/// The IDs and sizes are only known for sides that hold a file, rather than
+/// a symlink or a conflict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: FileChangeKind,
+    pub old_file: Option<object_id::FileId>,
+    /// Size in bytes
+    pub old_size: Option<u64>,
+    pub new_file: Option<object_id::FileId>,
+    /// Size in bytes
+    pub new_size: Option<u64>,
+}
+
+/// A commit as listed by [`Repository::log`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitSummary {
+    /// The commit's ID
+    pub id: object_id::CommitId,
+    /// The shortest prefix of the commit's ID that is unique in the
+    /// repository
+    pub short_id: String,
+    /// The ID of the change it is a version of
+    pub change_id: object_id::ChangeId,
+    /// The shortest prefix of the change ID, in jj's reverse-hex form,
+    /// that is unique among visible changes
+    pub short_change_id: String,
+    pub author_name: String,
+    pub author_email: String,
+    /// When the commit was authored, in milliseconds since the Unix epoch
+    pub author_time: i64,
+    /// The full description
+    pub description: String,
+    /// The commit's parents, in order
+    pub parents: Vec<object_id::CommitId>,
+    /// Whether the commit leaves its parents' tree as is
+    pub empty: bool,
+    /// Whether the commit's tree has conflicts
+    pub conflict: bool,
+}
+
+/// Consecutive lines of a file that [`Repository::blame`] finds last changed
+/// in the same commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameRange {
+    pub commit: object_id::CommitId,
+    /// Number of the first line, counting from 1
+    pub start_line: usize,
+    pub line_count: usize,
+}
+
+/// A page of [`Repository::log`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitLog {
+    /// The commits on the page, newest first
+    pub commits: Vec<CommitSummary>,
+    /// Whether more commits follow the last one
+    pub more: bool,
+}
+
+/// What [`Repository::commit_stats`] finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitStats {
+    pub count: usize,
+    /// Committer time of the oldest commit, in milliseconds since the Unix
+    /// epoch
+    pub first_time: Option<i64>,
+    /// Committer time of the newest commit, in milliseconds since the Unix
+    /// epoch
+    pub latest_time: Option<i64>,
+}
+
+/// A file listed by [`Repository::largest_files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSize {
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// A page of [`Repository::file_history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHistory {
+    /// The changes on the page, newest first
+    pub entries: Vec<FileHistoryEntry>,
+    /// Whether more changes follow the last one
+    pub more: bool,
+}
+
+/// A commit that changes what is at a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHistoryEntry {
+    pub commit: CommitSummary,
+    pub kind: FileChangeKind,
+    /// The file at the path after the commit, if there is one
+    pub file: Option<object_id::FileId>,
+}
+
+/// Kind of object stored by the native backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RawObjectKind {
+    Commit,
+    Tree,
+    File,
+    Symlink,
+    Conflict,
+    Operation,
+    View,
+}
+
+impl RawObjectKind {
+    /// Every kind, in the order a receiver should store them: objects before
+    /// the views that refer to them, and views before their operations.
+    pub const ALL: [RawObjectKind; 7] = [
+        RawObjectKind::File,
+        RawObjectKind::Symlink,
+        RawObjectKind::Conflict,
+        RawObjectKind::Tree,
+        RawObjectKind::Commit,
+        RawObjectKind::View,
+        RawObjectKind::Operation,
+    ];
+
+    /// Directory holding objects of this kind, relative to `.jj/repo`.
+    fn dir(self) -> &'static str {
+        match self {
+            RawObjectKind::Commit => "store/commits",
+            RawObjectKind::Tree => "store/trees",
+            RawObjectKind::File => "store/files",
+            RawObjectKind::Symlink => "store/symlinks",
+            RawObjectKind::Conflict => "store/conflicts",
+            RawObjectKind::Operation => "op_store/operations",
+            RawObjectKind::View => "op_store/views",
+        }
+    }
+
+    /// Compute the id the native backend gives an object of this kind whose
+    /// stored bytes are `data`.
+    ///
+    /// Files and symlinks are named by the BLAKE2b-512 hash of their
+    /// contents. Trees, commits, views, and operations are named by the hash
+    /// of the decoded object, so jj decodes them first. Fails if `data`
+    /// doesn't decode, and for conflicts, which jj no longer reads.
+    pub fn native_id(self, data: &[u8]) -> Result<ObjectId> {
+        let hash = match self {
+            RawObjectKind::File | RawObjectKind::Symlink => Blake2b512::digest(data).to_vec(),
+            RawObjectKind::Conflict => bail!("conflict objects can't be decoded"),
+            RawObjectKind::Tree
+            | RawObjectKind::Commit
+            | RawObjectKind::View
+            | RawObjectKind::Operation => decoded_hash(self, data)?,
+        };
+        Ok(ObjectId::from_slice(&hash)?)
+    }
+}
+
+/// Hash an object the way the native backend does, by storing `data` in a
+/// scratch store and having jj read it back.
+fn decoded_hash(kind: RawObjectKind, data: &[u8]) -> Result<Vec<u8>> {
+    // Anything but all zeros, which names the root commit, operation, and
+    // view without reading them.
+    let scratch_id = [0xff; object_id::NATIVE_HASH_LEN];
+    let scratch = tempfile::tempdir().context("failed to create scratch store")?;
+    let dir = scratch.path().join(kind.dir());
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+    let path = dir.join(hex::encode(scratch_id));
+    std::fs::write(&path, data).with_context(|| format!("failed to write: {}", path.display()))?;
+
+    let store = SimpleBackend::load(&scratch.path().join("store"));
+    let op_store = SimpleOpStore::load(
+        &scratch.path().join("op_store"),
+        RootOperationData {
+            root_commit_id: CommitId::from_bytes(&[0; object_id::NATIVE_HASH_LEN]),
+        },
+    );
+    let hash = match kind {
+        RawObjectKind::Tree => {
+            let id = JjTreeId::from_bytes(&scratch_id);
+            blake2b_hash(&store.read_tree(RepoPath::root(), &id).block_on()?)
+        }
+        RawObjectKind::Commit => {
+            let id = CommitId::from_bytes(&scratch_id);
+            blake2b_hash(&store.read_commit(&id).block_on()?)
+        }
+        RawObjectKind::View => {
+            let id = JjViewId::from_bytes(&scratch_id);
+            blake2b_hash(&op_store.read_view(&id).block_on()?)
+        }
+        RawObjectKind::Operation => {
+            let id = OperationId::from_bytes(&scratch_id);
+            blake2b_hash(&op_store.read_operation(&id).block_on()?)
+        }
+        RawObjectKind::File | RawObjectKind::Symlink | RawObjectKind::Conflict => {
+            unreachable!("{kind:?} objects are hashed as stored")
+        }
+    };
+    Ok(hash.to_vec())
+}
+
+/// Entry in a tree.
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    /// The path of the entry relative to the tree root.
+    pub path: String,
+    /// The kind of entry.
+    pub kind: TreeEntryKind,
+}
+
+/// Kind of tree entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeEntryKind {
+    /// Regular file
+    File,
+    /// Subdirectory
+    Tree,
+    /// Conflicted entry
+    Conflict,
+}
+
+/// Repository manager for creating and accessing repositories.
+pub struct RepositoryManager {
+    config: StorageConfig,
+    user_settings: UserSettings,
+    store_factories: StoreFactories,
+}
+
+impl RepositoryManager {
+    /// Create a new repository manager with the given configuration.
+    pub fn new(config: StorageConfig) -> Result<Self> {
+        let jj_config = StackedConfig::with_defaults();
+        let user_settings =
+            UserSettings::from_config(jj_config).context("failed to create user settings")?;
+        let store_factories = StoreFactories::default();
+
+        Ok(Self {
+            config,
+            user_settings,
+            store_factories,
+        })
+    }
+
+    /// Get the path to a repository.
+    pub fn repo_path(&self, owner: &str, name: &str) -> PathBuf {
+        self.config.repos_root.join(owner).join(name)
+    }
+
+    /// Check if a repository exists.
+    pub fn repo_exists(&self, owner: &str, name: &str) -> bool {
+        if validate_name(owner).is_err() || validate_name(name).is_err() {
+            return false;
+        }
+        let path = self.repo_path(owner, name);
+        path.join(".jj").exists()
+    }
+
+    /// Create a new repository with the native jj backend.
+    pub fn create_repo(&self, owner: &str, name: &str) -> Result<Repository> {
+        validate_repo_names(owner, name)?;
+        let repo_path = self.repo_path(owner, name);
+
+        if repo_path.exists() {
+            bail!(StorageError::AlreadyExists {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        // Create parent directories
+        std::fs::create_dir_all(&repo_path)
+            .with_context(|| format!("failed to create directory: {}", repo_path.display()))?;
+
+        info!("creating repository at {}", repo_path.display());
+
+        // Initialize with native (simple) backend
+        let (workspace, repo) = Workspace::init_simple(&self.user_settings, &repo_path)
+            .with_context(|| format!("failed to init repository at {}", repo_path.display()))?;
+
+        debug!("repository created with backend: simple");
+
+        let info = RepoInfo {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            path: repo_path,
+            backend_type: BackendType::Native,
+        };
+
+        Ok(Repository {
+            workspace,
+            repo,
+            info,
+        })
+    }
+
+    /// Information about an existing repository, found without opening it.
+    pub fn repo_info(&self, owner: &str, name: &str) -> Result<RepoInfo> {
+        validate_repo_names(owner, name)?;
+        let repo_path = self.repo_path(owner, name);
+
+        if !repo_path.join(".jj").exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        let backend_type = self.detect_backend_type(&repo_path)?;
+        Ok(RepoInfo {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            path: repo_path,
+            backend_type,
+        })
+    }
+
+    /// Open an existing repository.
+    pub fn open_repo(&self, owner: &str, name: &str) -> Result<Repository> {
+        validate_repo_names(owner, name)?;
+        let repo_path = self.repo_path(owner, name);
+
+        if !repo_path.join(".jj").exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        debug!("opening repository at {}", repo_path.display());
+
+        let workspace = Workspace::load(
+            &self.user_settings,
+            &repo_path,
+            &self.store_factories,
+            &default_working_copy_factories(),
+        )
+        .with_context(|| format!("failed to load workspace at {}", repo_path.display()))?;
+
+        let repo = workspace
+            .repo_loader()
+            .load_at_head()
+            .context("failed to load repository at head")?;
+
+        let backend_type = self.detect_backend_type(&repo_path)?;
+
+        let info = RepoInfo {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            path: repo_path,
+            backend_type,
+        };
+
+        Ok(Repository {
+            workspace,
+            repo,
+            info,
+        })
+    }
+
+    /// Delete a repository.
+    pub fn delete_repo(&self, owner: &str, name: &str) -> Result<()> {
+        validate_repo_names(owner, name)?;
+        let repo_path = self.repo_path(owner, name);
+
+        if !repo_path.exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        info!("deleting repository at {}", repo_path.display());
+
+        std::fs::remove_dir_all(&repo_path)
+            .with_context(|| format!("failed to delete: {}", repo_path.display()))?;
+
+        Ok(())
+    }
+
+    /// Move a repository out of the way instead of deleting it, returning
+    /// where it was moved to.
+    ///
+    /// Trashed repositories are kept under `.trash` in the repositories root,
+    /// which no owner name can refer to, with the time they were trashed in
+    /// their name so the same repository can be trashed more than once.
+    pub fn trash_repo(&self, owner: &str, name: &str) -> Result<PathBuf> {
+        validate_repo_names(owner, name)?;
+        let repo_path = self.repo_path(owner, name);
+
+        if !repo_path.exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        let trash_dir = self.config.repos_root.join(TRASH_DIR).join(owner);
+        std::fs::create_dir_all(&trash_dir)
+            .with_context(|| format!("failed to create directory: {}", trash_dir.display()))?;
+        let trashed_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let trash_path = trash_dir.join(format!("{name}.{trashed_at}"));
+
+        info!(
+            "moving repository at {} to {}",
+            repo_path.display(),
+            trash_path.display()
+        );
+
+        std::fs::rename(&repo_path, &trash_path)
+            .with_context(|| format!("failed to move to trash: {}", repo_path.display()))?;
+
+        Ok(trash_path)
+    }
+
+    /// Move a repository to `new_owner/new_name`, which renames it, gives
+    /// it to another owner, or both, returning it at its new name.
+    pub fn rename_repo(
+        &self,
+        owner: &str,
+        name: &str,
+        new_owner: &str,
+        new_name: &str,
+    ) -> Result<Repository> {
+        validate_repo_names(owner, name)?;
+        validate_repo_names(new_owner, new_name)?;
+        let repo_path = self.repo_path(owner, name);
+        let new_path = self.repo_path(new_owner, new_name);
+
+        if !repo_path.join(".jj").exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+        if new_path.exists() {
+            bail!(StorageError::AlreadyExists {
+                owner: new_owner.to_string(),
+                name: new_name.to_string(),
+            });
+        }
+
+        let owner_path = self.config.repos_root.join(new_owner);
+        std::fs::create_dir_all(&owner_path)
+            .with_context(|| format!("failed to create directory: {}", owner_path.display()))?;
+
+        info!(
+            "moving repository at {} to {}",
+            repo_path.display(),
+            new_path.display()
+        );
+
+        std::fs::rename(&repo_path, &new_path)
+            .with_context(|| format!("failed to move: {}", repo_path.display()))?;
+
+        self.open_repo(new_owner, new_name)
+    }
+
+    /// Copy a repository to `fork_owner/fork_name`, returning the copy.
+    ///
+    /// The copy is made under `.forks` in the repositories root and moved
+    /// into place once whole, so a failed fork leaves nothing behind under
+    /// the new name. The operation heads are copied before the stores they
+    /// point into, which only ever grow, so a write to the repository while
+    /// it is copied can't leave the fork pointing at what it doesn't have.
+    /// The sidecar files forjj keeps about the repository aren't copied.
+    pub fn fork_repo(
+        &self,
+        owner: &str,
+        name: &str,
+        fork_owner: &str,
+        fork_name: &str,
+    ) -> Result<Repository> {
+        validate_repo_names(owner, name)?;
+        validate_repo_names(fork_owner, fork_name)?;
+        let repo_path = self.repo_path(owner, name);
+        let fork_path = self.repo_path(fork_owner, fork_name);
+
+        if !repo_path.join(".jj").exists() {
+            bail!(StorageError::NotFound {
+                owner: owner.to_string(),
+                name: name.to_string(),
+            });
+        }
+        if fork_path.exists() {
+            bail!(StorageError::AlreadyExists {
+                owner: fork_owner.to_string(),
+                name: fork_name.to_string(),
+            });
+        }
+
+        let forks_dir = self.config.repos_root.join(FORKS_DIR);
+        std::fs::create_dir_all(&forks_dir)
+            .with_context(|| format!("failed to create directory: {}", forks_dir.display()))?;
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let staging = forks_dir.join(format!("{fork_owner}.{fork_name}.{started_at}"));
+
+        info!(
+            "forking repository at {} to {}",
+            repo_path.display(),
+            fork_path.display()
+        );
+
+        let op_heads = Path::new(".jj/repo/op_heads");
+        let sidecars = Path::new(".jj/repo/forjj");
+        let copied = copy_dir(&repo_path.join(op_heads), &staging.join(op_heads), &|_| {
+            false
+        })
+        .and_then(|()| {
+            copy_dir(&repo_path, &staging, &|relative| {
+                relative == op_heads || relative == sidecars
+            })
+        })
+        .and_then(|()| {
+            let owner_path = self.config.repos_root.join(fork_owner);
+            std::fs::create_dir_all(&owner_path)
+                .with_context(|| format!("failed to create directory: {}", owner_path.display()))?;
+            std::fs::rename(&staging, &fork_path)
+                .with_context(|| format!("failed to move into place: {}", fork_path.display()))
+        });
+        if let Err(error) = copied {
+            if let Err(cleanup) = std::fs::remove_dir_all(&staging) {
+                warn!("failed to remove {}: {cleanup}", staging.display());
+            }
+            return Err(error);
+        }
+
+        self.open_repo(fork_owner, fork_name)
+    }
+
+    /// List all repositories for an owner.
+    pub fn list_repos(&self, owner: &str) -> Result<Vec<RepoInfo>> {
+        let owner_path = self.config.repos_root.join(owner);
+        if !owner_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut repos = Vec::new();
+        for entry in std::fs::read_dir(&owner_path)
+            .with_context(|| format!("failed to read directory: {}", owner_path.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            if path.join(".jj").exists() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let backend_type = self.detect_backend_type(&path)?;
+                repos.push(RepoInfo {
+                    name,
+                    owner: owner.to_string(),
+                    path,
+                    backend_type,
+                });
+            }
+        }
+
+        Ok(repos)
+    }
+
+    /// List all owners.
+    pub fn list_owners(&self) -> Result<Vec<String>> {
+        if !self.config.repos_root.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut owners = Vec::new();
+        for entry in std::fs::read_dir(&self.config.repos_root)
+            .with_context(|| format!("failed to read: {}", self.config.repos_root.display()))?
+        {
+            let entry = entry?;
+            let owner = entry.file_name().to_string_lossy().to_string();
+            // Skips the trash, among other things no owner could be called
+            if entry.path().is_dir() && validate_name(&owner).is_ok() {
+                owners.push(owner);
+            }
+        }
+
+        Ok(owners)
+    }
+
+    /// Detect the backend type of a repository.
+    fn detect_backend_type(&self, repo_path: &Path) -> Result<BackendType> {
+        let type_file = repo_path.join(".jj/repo/store/type");
+        if type_file.exists() {
+            let content = std::fs::read_to_string(&type_file)
+                .with_context(|| format!("failed to read: {}", type_file.display()))?;
+            let backend = content.trim();
+            match backend.to_lowercase().as_str() {
+                "simple" => Ok(BackendType::Native),
+                "git" => Ok(BackendType::Git),
+                _ => {
+                    info!("unknown backend type: {}, assuming git", backend);
+                    Ok(BackendType::Git)
+                }
+            }
+        } else {
+            // Default to git if type file doesn't exist
+            Ok(BackendType::Git)
+        }
+    }
+}
+
+/// Copy what is in the directory `from` into `to`, leaving out lock files
+/// and whatever `skip` says to, given its path within `from`.
+fn copy_dir(from: &Path, to: &Path, skip: &dyn Fn(&Path) -> bool) -> Result<()> {
+    fn copy(from: &Path, to: &Path, relative: &Path, skip: &dyn Fn(&Path) -> bool) -> Result<()> {
+        std::fs::create_dir_all(to)
+            .with_context(|| format!("failed to create directory: {}", to.display()))?;
+        for entry in std::fs::read_dir(from)
+            .with_context(|| format!("failed to read directory: {}", from.display()))?
+        {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let relative = relative.join(&file_name);
+            let is_lock = file_name == "lock" || file_name.to_string_lossy().ends_with(".lock");
+            if is_lock || skip(&relative) {
+                continue;
+            }
+            let (source, target) = (entry.path(), to.join(&file_name));
+            if entry.file_type()?.is_dir() {
+                copy(&source, &target, &relative, skip)?;
+            } else {
+                std::fs::copy(&source, &target)
+                    .with_context(|| format!("failed to copy: {}", source.display()))?;
+            }
+        }
+        Ok(())
+    }
+    copy(from, to, Path::new(""), skip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jj_lib::object_id::ObjectId as _;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_repo_path() {
+        let config = StorageConfig {
+            repos_root: PathBuf::from("/data/repos"),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        assert_eq!(
+            manager.repo_path("alice", "my-project"),
+            PathBuf::from("/data/repos/alice/my-project")
+        );
+    }
+
+    #[test]
+    fn test_validate_name() {
+        for name in ["alice", "my-project", "jj_lib", "v1.2", "A9"] {
+            assert_eq!(validate_name(name), Ok(()), "{name}");
+        }
+        assert_eq!(validate_name(""), Err(NameError::Empty));
+        assert_eq!(validate_name(".."), Err(NameError::BadStart('.')));
+        assert_eq!(validate_name(".jj"), Err(NameError::BadStart('.')));
+        assert_eq!(validate_name("-rf"), Err(NameError::BadStart('-')));
+        assert_eq!(validate_name("a/b"), Err(NameError::InvalidChar('/')));
+        assert_eq!(validate_name("a b"), Err(NameError::InvalidChar(' ')));
+        assert_eq!(validate_name("café"), Err(NameError::InvalidChar('é')));
+        assert_eq!(
+            validate_name(&"x".repeat(MAX_NAME_LEN + 1)),
+            Err(NameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_validate_bookmark_name() {
+        for name in ["main", "feature/login", "v1.2", "-x", "ünïcode"] {
+            assert_eq!(validate_bookmark_name(name), Ok(()), "{name}");
+        }
+        assert_eq!(validate_bookmark_name(""), Err(BookmarkNameError::Empty));
+        assert_eq!(
+            validate_bookmark_name("a b"),
+            Err(BookmarkNameError::InvalidChar(' '))
+        );
+        assert_eq!(
+            validate_bookmark_name("a\0b"),
+            Err(BookmarkNameError::InvalidChar('\0'))
+        );
+        assert_eq!(
+            validate_bookmark_name(&"x".repeat(MAX_BOOKMARK_NAME_LEN + 1)),
+            Err(BookmarkNameError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_invalid_names_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().join("repos"),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        assert!(manager.create_repo("alice", "../escape").is_err());
+        assert!(manager.create_repo("..", "escape").is_err());
+        assert!(!temp_dir.path().join("escape").exists());
+        assert!(!manager.repo_exists("alice", "../../etc"));
+    }
+
+    #[test]
+    fn test_backend_type_as_str() {
+        assert_eq!(BackendType::Native.as_str(), "simple");
+        assert_eq!(BackendType::Git.as_str(), "git");
+    }
+
+    #[test]
+    fn test_git_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = RepositoryManager::new(StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        })
+        .unwrap();
+        manager.create_repo("alice", "native").unwrap();
+        let info = manager.repo_info("alice", "native").unwrap();
+        assert!(info.git_dir().is_err());
+
+        // As jj lays out a repository with an internal git store.
+        let store = temp_dir.path().join("alice/git/.jj/repo/store");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join("type"), "git").unwrap();
+        std::fs::write(store.join("git_target"), "git").unwrap();
+        let info = manager.repo_info("alice", "git").unwrap();
+        assert_eq!(info.backend_type, BackendType::Git);
+        assert_eq!(info.git_dir().unwrap(), store.join("git"));
+    }
+
+    #[test]
+    fn test_create_and_open_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        // Create a new repository
+        let repo = manager.create_repo("alice", "test-repo").unwrap();
+        assert_eq!(repo.info().name, "test-repo");
+        assert_eq!(repo.info().owner, "alice");
+        assert_eq!(repo.info().backend_type, BackendType::Native);
+
+        // Verify it exists
+        assert!(manager.repo_exists("alice", "test-repo"));
+
+        // Open the repository
+        let repo2 = manager.open_repo("alice", "test-repo").unwrap();
+        assert_eq!(repo2.info().name, "test-repo");
+        assert_eq!(repo2.info().backend_type, BackendType::Native);
+        let info = manager.repo_info("alice", "test-repo").unwrap();
+        assert_eq!(info.path, repo2.info().path);
+        assert!(manager.repo_info("alice", "missing").is_err());
+
+        // Refusals are typed
+        let refusal = |result: Result<Repository>| StorageError::of(&result.err().unwrap());
+        assert_eq!(
+            refusal(manager.create_repo("alice", "test-repo")),
+            Some(StorageError::AlreadyExists {
+                owner: "alice".to_string(),
+                name: "test-repo".to_string(),
+            })
+        );
+        assert_eq!(
+            refusal(manager.open_repo("alice", "missing")),
+            Some(StorageError::NotFound {
+                owner: "alice".to_string(),
+                name: "missing".to_string(),
+            })
+        );
+        assert_eq!(
+            refusal(manager.open_repo("alice", ".hidden")),
+            Some(StorageError::InvalidName {
+                name: ".hidden".to_string(),
+                error: NameError::BadStart('.'),
+            })
+        );
+
+        // List repositories
+        let repos = manager.list_repos("alice").unwrap();
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].name, "test-repo");
+    }
+
+    #[test]
+    fn test_delete_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        // Create and then delete
+        manager.create_repo("bob", "to-delete").unwrap();
+        assert!(manager.repo_exists("bob", "to-delete"));
+
+        manager.delete_repo("bob", "to-delete").unwrap();
+        assert!(!manager.repo_exists("bob", "to-delete"));
+    }
+
+    #[test]
+    fn test_trash_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        manager.create_repo("bob", "to-trash").unwrap();
+        let first = manager.trash_repo("bob", "to-trash").unwrap();
+        assert!(!manager.repo_exists("bob", "to-trash"));
+        assert!(first.join(".jj").exists());
+
+        // The name is free again, and can be trashed again.
+        manager.create_repo("bob", "to-trash").unwrap();
+        let second = manager.trash_repo("bob", "to-trash").unwrap();
+        assert_ne!(first, second);
+        assert!(second.join(".jj").exists());
+
+        // The trash isn't mistaken for an owner.
+        assert_eq!(manager.list_owners().unwrap(), ["bob"]);
+        assert!(manager.list_repos("bob").unwrap().is_empty());
+        assert!(manager.trash_repo("bob", "to-trash").is_err());
+    }
+
+    #[test]
+    fn test_rename_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        let repo = manager.create_repo("alice", "old").unwrap();
+        let op_id = repo.current_op_id().unwrap();
+        let renamed = manager.rename_repo("alice", "old", "alice", "new").unwrap();
+        assert_eq!(renamed.info().name, "new");
+        assert_eq!(renamed.current_op_id().unwrap(), op_id);
+        assert!(!manager.repo_exists("alice", "old"));
+
+        // To another owner, who needn't have repositories yet.
+        let moved = manager.rename_repo("alice", "new", "bob", "new").unwrap();
+        assert_eq!(moved.info().owner, "bob");
+        assert!(manager.list_repos("alice").unwrap().is_empty());
+
+        manager.create_repo("alice", "taken").unwrap();
+        let refusal = |result: Result<Repository>| StorageError::of(&result.err().unwrap());
+        assert!(matches!(
+            refusal(manager.rename_repo("bob", "new", "alice", "taken")),
+            Some(StorageError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            refusal(manager.rename_repo("alice", "old", "alice", "other")),
+            Some(StorageError::NotFound { .. })
+        ));
+        assert!(manager.repo_exists("bob", "new"));
+    }
+
+    #[test]
+    fn test_fork_repo() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        let mut repo = manager.create_repo("alice", "project").unwrap();
+        let root = repo.root_commit_id().unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(root))], "set main")
+            .unwrap();
+        repo.update_sidecar("metadata.json", |_| Ok(b"{}".to_vec()))
+            .unwrap();
+
+        let fork = manager
+            .fork_repo("alice", "project", "bob", "project")
+            .unwrap();
+        assert_eq!(fork.info().owner, "bob");
+        assert_eq!(fork.current_op_id().unwrap(), repo.current_op_id().unwrap());
+        assert_eq!(fork.bookmark_ids().unwrap(), repo.bookmark_ids().unwrap());
+        assert_eq!(fork.read_sidecar("metadata.json").unwrap(), None);
+
+        // The fork is a repository of its own.
+        let mut fork = fork;
+        fork.set_bookmarks(&[("main".to_string(), None)], "delete main")
+            .unwrap();
+        let reopened = manager.open_repo("alice", "project").unwrap();
+        assert_eq!(reopened.bookmark_names(), ["main"]);
+
+        let refusal = |result: Result<Repository>| StorageError::of(&result.err().unwrap());
+        assert!(matches!(
+            refusal(manager.fork_repo("alice", "project", "bob", "project")),
+            Some(StorageError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            refusal(manager.fork_repo("alice", "missing", "bob", "missing")),
+            Some(StorageError::NotFound { .. })
+        ));
+
+        // Nothing is left in the staging directory, which isn't an owner.
+        let staged = std::fs::read_dir(temp_dir.path().join(FORKS_DIR)).unwrap();
+        assert_eq!(staged.count(), 0);
+        let mut owners = manager.list_owners().unwrap();
+        owners.sort();
+        assert_eq!(owners, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn test_read_commits() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        // Create a new repository
+        let repo = manager.create_repo("alice", "commits-test").unwrap();
+
+        // Every jj repo has a root commit
+        let root = repo.root_commit();
+        assert!(root.parent_ids().is_empty());
+
+        // Get the root commit by ID
+        let root_id = root.id().clone();
+        let fetched = repo.get_commit(&root_id).unwrap();
+        assert_eq!(fetched.id(), root.id());
+
+        // New repo should have heads
+        let heads = repo.heads();
+        assert!(!heads.is_empty());
+
+        // Get operation ID
+        let op_id = repo.operation_id();
+        assert!(!op_id.hex().is_empty());
+    }
+
+    #[test]
+    fn test_tree_operations() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        let repo = manager.create_repo("alice", "tree-test").unwrap();
+
+        // Get the root commit and its tree
+        let root = repo.root_commit();
+        let tree = repo.get_tree(&root);
+
+        // Root commit has an empty tree
+        let entries = repo.list_tree_entries(&tree);
+        assert!(entries.is_empty(), "root commit should have empty tree");
+    }
+
+    #[test]
+    fn test_operation_info() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+
+        let repo = manager.create_repo("alice", "op-test").unwrap();
+
+        // Get current operation
+        let op = repo.operation();
+        assert!(!op.id().hex().is_empty());
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now.abs_diff(repo.operation_time()) < 60);
+
+        // New repo should be fresh (no user commits)
+        assert!(repo.is_fresh());
+    }
+
+    #[test]
+    fn test_set_bookmarks() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "bookmarks-test").unwrap();
+
+        assert_eq!(
+            repo.bookmark_target("main").unwrap(),
+            BookmarkTarget::Absent
+        );
+
+        let head = repo.head_ids().unwrap()[0];
+        let before = repo.current_op_id().unwrap();
+        let op = repo
+            .set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+            .unwrap();
+        assert_ne!(op, before);
+        assert_eq!(repo.current_op_id().unwrap(), op);
+        assert_eq!(
+            repo.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+        assert_eq!(repo.bookmark_names(), vec!["main".to_string()]);
+        assert!(repo.tag_names().is_empty());
+
+        // The update is visible after reopening.
+        let reopened = manager.open_repo("alice", "bookmarks-test").unwrap();
+        assert_eq!(
+            reopened.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+
+        repo.set_bookmarks(&[("main".to_string(), None)], "delete main")
+            .unwrap();
+        assert_eq!(
+            repo.bookmark_target("main").unwrap(),
+            BookmarkTarget::Absent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ahead_behind() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "ahead-behind").unwrap();
+
+        let base = repo
+            .write_commit(&[], &[("a", b"1")], "base")
+            .await
+            .unwrap();
+        let left = repo
+            .write_commit(&[base], &[("a", b"2")], "left")
+            .await
+            .unwrap();
+        let left2 = repo
+            .write_commit(&[left], &[("a", b"3")], "left 2")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("b", b"1")], "right")
+            .await
+            .unwrap();
+
+        assert_eq!(repo.ahead_behind(&left2, &base).unwrap(), (2, 0));
+        assert_eq!(repo.ahead_behind(&base, &left2).unwrap(), (0, 2));
+        assert_eq!(repo.ahead_behind(&left2, &right).unwrap(), (2, 1));
+        assert_eq!(repo.ahead_behind(&base, &base).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_update_bookmarks_all_or_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "cas-test").unwrap();
+        let head = repo.head_ids().unwrap()[0];
+        let root = repo.root_commit_id().unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+            .unwrap();
+
+        let update = |name: &str, expected, target| BookmarkUpdate {
+            name: name.to_string(),
+            expected,
+            target,
+        };
+        let before = repo.current_op_id().unwrap();
+        let error = repo
+            .update_bookmarks(
+                &[
+                    update("feature", BookmarkTarget::Absent, Some(head)),
+                    update("main", BookmarkTarget::Normal(root), None),
+                ],
+                "stale",
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<StaleBookmark>(),
+            Some(&StaleBookmark {
+                name: "main".to_string(),
+                actual: BookmarkTarget::Normal(head),
+            })
+        );
+        // Nothing moved, and no operation was recorded.
+        assert_eq!(repo.current_op_id().unwrap(), before);
+        assert_eq!(
+            repo.bookmark_target("feature").unwrap(),
+            BookmarkTarget::Absent
+        );
+
+        let error = repo
+            .update_bookmarks(
+                &[
+                    update("feature", BookmarkTarget::Absent, Some(head)),
+                    update("bad name", BookmarkTarget::Absent, Some(head)),
+                ],
+                "invalid",
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<InvalidBookmarkName>(),
+            Some(&InvalidBookmarkName {
+                name: "bad name".to_string(),
+                error: BookmarkNameError::InvalidChar(' '),
+            })
+        );
+        assert_eq!(repo.current_op_id().unwrap(), before);
+
+        repo.update_bookmarks(
+            &[
+                update("feature", BookmarkTarget::Absent, Some(head)),
+                update("main", BookmarkTarget::Normal(head), None),
+            ],
+            "move",
+        )
+        .unwrap();
+        assert_eq!(
+            repo.bookmark_target("feature").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+        assert_eq!(
+            repo.bookmark_target("main").unwrap(),
+            BookmarkTarget::Absent
+        );
+    }
+
+    #[test]
+    fn test_storage_error_of() {
+        let stale = StaleBookmark {
+            name: "main".to_string(),
+            actual: BookmarkTarget::Absent,
+        };
+        let error = anyhow::Error::from(stale.clone()).context("failed to push");
+        assert_eq!(StorageError::of(&error), Some(StorageError::Stale(stale)));
+
+        let error = anyhow::Error::from(StorageError::Archived);
+        assert_eq!(StorageError::of(&error), Some(StorageError::Archived));
+
+        let invalid = InvalidBookmarkName {
+            name: String::new(),
+            error: BookmarkNameError::Empty,
+        };
+        let error = anyhow::Error::from(invalid.clone());
+        assert_eq!(
+            StorageError::of(&error),
+            Some(StorageError::InvalidBookmarkName(invalid))
+        );
+
+        let error = anyhow::anyhow!("failed to write /srv/repos/alice/project");
+        assert_eq!(StorageError::of(&error), None);
+    }
+
+    #[test]
+    fn test_raw_objects_copy_between_repos() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut source = manager.create_repo("alice", "source").unwrap();
+        let mut target = manager.create_repo("alice", "target").unwrap();
+
+        let head = source.head_ids().unwrap()[0];
+        source
+            .set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+            .unwrap();
+        assert!(!target.has_commit(&head));
+
+        for kind in RawObjectKind::ALL {
+            for id in source.list_raw_objects(kind).unwrap() {
+                let data = source.read_raw_object(kind, &id).unwrap();
+                target.write_raw_object(kind, &id, &data).unwrap();
+                assert!(!target.write_raw_object(kind, &id, &data).unwrap());
+            }
+        }
+        target
+            .add_op_head(&source.current_op_id().unwrap())
+            .unwrap();
+
+        assert!(target.has_commit(&head));
+        assert_eq!(
+            target.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stage_op_heads() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut source = manager.create_repo("alice", "source").unwrap();
+        let mut target = manager.create_repo("alice", "target").unwrap();
+
+        let head = source.head_ids().unwrap()[0];
+        let pushed = source
+            .set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+            .unwrap();
+        let other = target.head_ids().unwrap()[0];
+        let before = target
+            .set_bookmarks(&[("other".to_string(), Some(other))], "set other")
+            .unwrap();
+        for kind in RawObjectKind::ALL {
+            for id in source.list_raw_objects(kind).unwrap() {
+                let data = source.read_raw_object(kind, &id).unwrap();
+                target.write_raw_object(kind, &id, &data).unwrap();
+            }
+        }
+
+        // Staging shows both sides without changing the heads.
+        target.stage_op_heads(&[pushed]).unwrap();
+        assert_eq!(
+            target.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+        assert_eq!(
+            target.bookmark_target("other").unwrap(),
+            BookmarkTarget::Normal(other)
+        );
+        assert_eq!(target.op_head_ids().await.unwrap(), vec![before]);
+
+        target.reload().unwrap();
+        assert_eq!(
+            target.bookmark_target("main").unwrap(),
+            BookmarkTarget::Absent
+        );
+
+        target.stage_op_heads(&[pushed]).unwrap();
+        let merged = target.publish_staged_op().unwrap();
+        assert_eq!(target.op_head_ids().await.unwrap(), vec![merged]);
+        assert_eq!(
+            target.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(head)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_native_ids() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "project").unwrap();
+        let commit = repo
+            .write_commit(&[], &[("file", b"contents")], "add file")
+            .await
+            .unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(commit))], "set main")
+            .unwrap();
+
+        for kind in RawObjectKind::ALL {
+            for id in repo.list_raw_objects(kind).unwrap() {
+                let data = repo.read_raw_object(kind, &id).unwrap();
+                assert_eq!(kind.native_id(&data).unwrap(), id, "{kind:?}");
+            }
+        }
+
+        // Changed bytes give another id, and aren't stored under the old one.
+        let mut data = repo
+            .read_raw_object(RawObjectKind::Commit, &commit)
+            .unwrap();
+        let offset = data
+            .windows(b"add file".len())
+            .position(|window| window == b"add file")
+            .unwrap();
+        data[offset] = b'A';
+        assert_ne!(RawObjectKind::Commit.native_id(&data).unwrap(), commit);
+        assert!(RawObjectKind::Commit.native_id(b"\xff").is_err());
+        assert!(
+            repo.write_raw_object(RawObjectKind::Commit, &commit, &data)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_sidecar_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let repo = manager.create_repo("alice", "project").unwrap();
+        assert_eq!(repo.read_sidecar("state.json").unwrap(), None);
+
+        repo.update_sidecar("state.json", |old| {
+            assert_eq!(old, None);
+            Ok(b"one".to_vec())
+        })
+        .unwrap();
+        repo.update_sidecar("state.json", |old| {
+            let mut data = old.unwrap();
+            data.extend_from_slice(b" two");
+            Ok(data)
+        })
+        .unwrap();
+        // So does a listing, without opening the repository.
+        let listed = &manager.list_repos("alice").unwrap()[0];
+        assert_eq!(
+            listed.read_sidecar("state.json").unwrap().as_deref(),
+            Some(&b"one two"[..])
+        );
+        // Another handle sees the update, and a failed update changes nothing.
+        let reopened = manager.open_repo("alice", "project").unwrap();
+        assert_eq!(
+            reopened.read_sidecar("state.json").unwrap().as_deref(),
+            Some(&b"one two"[..])
+        );
+        assert!(
+            reopened
+                .update_sidecar("state.json", |_| bail!("no"))
+                .is_err()
+        );
+        assert_eq!(
+            repo.read_sidecar("state.json").unwrap().as_deref(),
+            Some(&b"one two"[..])
+        );
+
+        assert!(repo.read_sidecar("../escape").is_err());
+        assert!(repo.update_sidecar("", |_| Ok(vec![])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_operation_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "oplog-test").unwrap();
+
+        let head = repo.head_ids().unwrap()[0];
+        let before = repo.current_op_id().unwrap();
+        let op = repo
+            .set_bookmarks(&[("main".to_string(), Some(head))], "set main")
+            .unwrap();
+
+        let log = repo.operation_log().await.unwrap();
+        let entry = log.iter().find(|entry| entry.id == op).unwrap();
+        assert_eq!(entry.parents, vec![before]);
+        assert!(entry.view_heads.contains(&head));
+        assert_eq!(entry.description, "set main");
+        assert_eq!(repo.operation_entry(&op).await.unwrap(), *entry);
+
+        // Every parent is in the log, and it ends at a single root.
+        for entry in &log {
+            for parent in &entry.parents {
+                assert!(log.iter().any(|other| other.id == *parent));
+            }
+        }
+        let roots: Vec<_> = log
+            .iter()
+            .filter(|entry| entry.parents.is_empty())
+            .collect();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].id, repo.root_operation_id().unwrap());
+
+        let parents = repo.commit_parent_ids(&head).unwrap();
+        let root = ObjectId::try_from(repo.root_commit().id()).unwrap();
+        assert_eq!(parents, vec![root]);
+    }
+
+    #[tokio::test]
+    async fn test_write_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "commit-test").unwrap();
+
+        let first = repo
+            .write_commit(&[], &[("README.md", b"hello")], "first")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(&[first], &[("src/lib.rs", b"// lib")], "second")
+            .await
+            .unwrap();
+        assert!(repo.has_commit(&second));
+        assert_eq!(repo.commit_parent_ids(&second).unwrap(), vec![first]);
+
+        let objects = repo.commit_objects(&second).await.unwrap();
+        let paths: Vec<_> = objects
+            .files
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(&"README.md"));
+        assert!(paths.contains(&"src/lib.rs"));
+        // The root tree and src/.
+        assert_eq!(objects.trees.len(), 2);
+        for (_, id) in &objects.files {
+            assert!(repo.has_raw_object(RawObjectKind::File, id).unwrap());
+        }
+        for id in &objects.trees {
+            assert!(repo.has_raw_object(RawObjectKind::Tree, id).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "log-test").unwrap();
+
+        let first = repo
+            .write_commit(&[], &[("README.md", b"hello")], "first\n\nbody")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(&[first], &[("src/lib.rs", b"// lib")], "second")
+            .await
+            .unwrap();
+        let third = repo
+            .write_commit(&[second], &[("README.md", b"hello again")], "third")
+            .await
+            .unwrap();
+        let fourth = repo.write_commit(&[third], &[], "fourth").await.unwrap();
+
+        let log = repo.log(&fourth, None, None, 10).await.unwrap();
+        let ids: Vec<_> = log.commits.iter().map(|commit| commit.id).collect();
+        assert_eq!(ids, [fourth, third, second, first]);
+        assert!(!log.more);
+        let summary = &log.commits[3];
+        assert_eq!(summary.description, "first\n\nbody");
+        assert_eq!(summary.parents, [repo.root_commit_id().unwrap()]);
+        assert!(!summary.empty);
+        assert!(!summary.conflict);
+        assert!(log.commits[0].empty);
+
+        // Pages pick up after the last commit of the one before.
+        let page = repo.log(&fourth, None, None, 2).await.unwrap();
+        assert!(page.more);
+        assert_eq!(page.commits[1].id, third);
+        let page = repo.log(&fourth, Some(&third), None, 2).await.unwrap();
+        let ids: Vec<_> = page.commits.iter().map(|commit| commit.id).collect();
+        assert_eq!(ids, [second, first]);
+        assert!(!page.more);
+
+        let log = repo
+            .log(&fourth, None, Some("README.md"), 10)
+            .await
+            .unwrap();
+        let ids: Vec<_> = log.commits.iter().map(|commit| commit.id).collect();
+        assert_eq!(ids, [third, first]);
+        let log = repo.log(&fourth, None, Some("src"), 10).await.unwrap();
+        assert_eq!(log.commits.len(), 1);
+        assert_eq!(log.commits[0].id, second);
 
-        Ok(repos)
+        assert!(repo.is_ancestor(&first, &third).unwrap());
+        assert!(!repo.is_ancestor(&third, &first).unwrap());
     }
 
-    /// List all owners.
-    pub fn list_owners(&self) -> Result<Vec<String>> {
-        if !self.config.repos_root.exists() {
-            return Ok(Vec::new());
-        }
+    #[tokio::test]
+    async fn test_short_ids() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "short-test").unwrap();
 
-        let mut owners = Vec::new();
-        for entry in std::fs::read_dir(&self.config.repos_root)
-            .with_context(|| format!("failed to read: {}", self.config.repos_root.display()))?
-        {
-            let entry = entry?;
-            if entry.path().is_dir() {
-                owners.push(entry.file_name().to_string_lossy().to_string());
-            }
+        let mut tip = repo.write_commit(&[], &[], "0").await.unwrap();
+        for n in 1..40 {
+            let description = n.to_string();
+            tip = repo.write_commit(&[tip], &[], &description).await.unwrap();
         }
+        let commits = repo.log(&tip, None, None, 100).await.unwrap().commits;
+        // Every commit in the store, the working-copy commit made with the
+        // repository among them, and every visible change.
+        let hexes: BTreeSet<_> = repo
+            .list_raw_objects(RawObjectKind::Commit)
+            .unwrap()
+            .into_iter()
+            .chain([repo.root_commit_id().unwrap()])
+            .map(|id| id.to_hex())
+            .collect();
+        let change_ids: BTreeSet<_> = repo
+            .ancestors_of_heads()
+            .unwrap()
+            .iter()
+            .map(|id| repo.commit_summary(id).unwrap().change_id.to_reverse_hex())
+            .collect();
 
-        Ok(owners)
-    }
+        // Each is a prefix no other ID shares, one shorter than is shared.
+        let unique = |prefix: &str, all: &BTreeSet<String>| {
+            all.iter().filter(|id| id.starts_with(prefix)).count() == 1
+        };
+        for commit in &commits {
+            let short = &commit.short_id;
+            assert_eq!(*short, commit.id.short(short.len()));
+            assert!(unique(short, &hexes), "{short}");
+            assert!(short.len() == 1 || !unique(&short[..short.len() - 1], &hexes));
 
-    /// Detect the backend type of a repository.
-    fn detect_backend_type(&self, repo_path: &Path) -> Result<BackendType> {
-        let type_file = repo_path.join(".jj/repo/store/type");
-        if type_file.exists() {
-            let content = std::fs::read_to_string(&type_file)
-                .with_context(|| format!("failed to read: {}", type_file.display()))?;
-            let backend = content.trim();
-            match backend.to_lowercase().as_str() {
-                "simple" => Ok(BackendType::Native),
-                "git" => Ok(BackendType::Git),
-                _ => {
-                    info!("unknown backend type: {}, assuming git", backend);
-                    Ok(BackendType::Git)
-                }
-            }
-        } else {
-            // Default to git if type file doesn't exist
-            Ok(BackendType::Git)
+            let short = &commit.short_change_id;
+            assert!(
+                commit
+                    .change_id
+                    .to_reverse_hex()
+                    .starts_with(short.as_str())
+            );
+            assert!(unique(short, &change_ids), "{short}");
+            assert!(short.len() == 1 || !unique(&short[..short.len() - 1], &change_ids));
         }
+        assert_eq!(
+            repo.commit_summary(&tip).unwrap().short_id,
+            commits[0].short_id
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use jj_lib::object_id::ObjectId as _;
-    use tempfile::TempDir;
 
-    #[test]
-    fn test_repo_path() {
+    #[tokio::test]
+    async fn test_file_history() {
+        let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
-            repos_root: PathBuf::from("/data/repos"),
+            repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "history-test").unwrap();
+
+        let added = repo
+            .write_commit(&[], &[("a.txt", b"one")], "add")
+            .await
+            .unwrap();
+        let other = repo
+            .write_commit(&[added], &[("b.txt", b"other")], "other")
+            .await
+            .unwrap();
+        let modified = repo
+            .write_commit(&[other], &[("a.txt", b"two")], "modify")
+            .await
+            .unwrap();
+        let removed = repo
+            .remove_files(&[modified], &["a.txt"], "remove")
+            .await
+            .unwrap();
+        let readded = repo
+            .write_commit(&[removed], &[("a.txt", b"three")], "re-add")
+            .await
+            .unwrap();
+
+        let history = repo
+            .file_history(&readded, "a.txt", None, 10)
+            .await
+            .unwrap();
+        let steps: Vec<_> = history
+            .entries
+            .iter()
+            .map(|entry| (entry.commit.id, entry.kind, entry.file.is_some()))
+            .collect();
         assert_eq!(
-            manager.repo_path("alice", "my-project"),
-            PathBuf::from("/data/repos/alice/my-project")
+            steps,
+            [
+                (readded, FileChangeKind::Added, true),
+                (removed, FileChangeKind::Deleted, false),
+                (modified, FileChangeKind::Modified, true),
+                (added, FileChangeKind::Added, true),
+            ]
         );
+        assert!(!history.more);
+        assert_ne!(history.entries[0].file, history.entries[2].file);
+
+        // History from before the removal still lists the earlier changes.
+        let history = repo.file_history(&removed, "a.txt", None, 1).await.unwrap();
+        assert_eq!(history.entries[0].commit.id, removed);
+        assert!(history.more);
+        let history = repo
+            .file_history(&removed, "a.txt", Some(&removed), 10)
+            .await
+            .unwrap();
+        let ids: Vec<_> = history
+            .entries
+            .iter()
+            .map(|entry| entry.commit.id)
+            .collect();
+        assert_eq!(ids, [modified, added]);
+
+        let history = repo
+            .file_history(&readded, "missing", None, 10)
+            .await
+            .unwrap();
+        assert!(history.entries.is_empty());
+        assert!(!history.more);
     }
 
-    #[test]
-    fn test_backend_type_as_str() {
-        assert_eq!(BackendType::Native.as_str(), "simple");
-        assert_eq!(BackendType::Git.as_str(), "git");
+    #[tokio::test]
+    async fn test_repo_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "stats-test").unwrap();
+        let stats = repo.commit_stats().unwrap();
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.first_time, None);
+
+        let first = repo
+            .write_commit(&[], &[("a.txt", b"a"), ("big.bin", &[0; 100])], "first")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(
+                &[first],
+                &[("src/b.txt", b"bb"), ("c.txt", b"cc")],
+                "second",
+            )
+            .await
+            .unwrap();
+        let stats = repo.commit_stats().unwrap();
+        assert_eq!(stats.count, 2);
+        let (first_time, _) = repo.log_key(&first).unwrap();
+        let (second_time, _) = repo.log_key(&second).unwrap();
+        assert_eq!(stats.first_time, Some(first_time.min(second_time)));
+        assert_eq!(stats.latest_time, Some(first_time.max(second_time)));
+
+        let largest = repo.largest_files(&second, 3).await.unwrap();
+        let sizes: Vec<_> = largest
+            .iter()
+            .map(|file| (file.path.as_str(), file.size))
+            .collect();
+        assert_eq!(sizes, [("big.bin", 100), ("c.txt", 2), ("src/b.txt", 2)]);
+
+        assert!(repo.disk_size().unwrap() > 100);
     }
 
-    #[test]
-    fn test_create_and_open_repo() {
+    #[tokio::test]
+    async fn test_commit_changes() {
         let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
             repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "changes-test").unwrap();
 
-        // Create a new repository
-        let repo = manager.create_repo("alice", "test-repo").unwrap();
-        assert_eq!(repo.info().name, "test-repo");
-        assert_eq!(repo.info().owner, "alice");
-        assert_eq!(repo.info().backend_type, BackendType::Native);
+        let base = repo
+            .write_commit(&[], &[("a.txt", b"a"), ("b.txt", b"b")], "base")
+            .await
+            .unwrap();
+        let left = repo
+            .write_commit(&[base], &[("a.txt", b"left")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("c.txt", b"new file")], "right")
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(
+                &[left, right],
+                &[("b.txt", b"merged"), ("c.txt", b"new file")],
+                "merge",
+            )
+            .await
+            .unwrap();
 
-        // Verify it exists
-        assert!(manager.repo_exists("alice", "test-repo"));
+        let changes = repo.commit_changes(&left).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "a.txt");
+        assert_eq!(changes[0].kind, FileChangeKind::Modified);
+        assert_eq!(
+            (changes[0].old_size, changes[0].new_size),
+            (Some(1), Some(4))
+        );
+        let content = repo
+            .file_content("a.txt", &changes[0].new_file.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(content, b"left");
 
-        // Open the repository
-        let repo2 = manager.open_repo("alice", "test-repo").unwrap();
-        assert_eq!(repo2.info().name, "test-repo");
-        assert_eq!(repo2.info().backend_type, BackendType::Native);
+        let changes = repo.commit_changes(&base).await.unwrap();
+        let paths: Vec<_> = changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt"]);
+        assert!(
+            changes
+                .iter()
+                .all(|change| change.kind == FileChangeKind::Added)
+        );
+        assert_eq!(changes[0].old_size, None);
 
-        // List repositories
-        let repos = manager.list_repos("alice").unwrap();
-        assert_eq!(repos.len(), 1);
-        assert_eq!(repos[0].name, "test-repo");
+        // The merge is compared with its merged parents, so what it brings
+        // in from the right doesn't show.
+        let changes = repo.commit_changes(&merge).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "b.txt");
     }
 
-    #[test]
-    fn test_delete_repo() {
+    #[tokio::test]
+    async fn test_path_value() {
         let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
             repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "path-test").unwrap();
 
-        // Create and then delete
-        manager.create_repo("bob", "to-delete").unwrap();
-        assert!(manager.repo_exists("bob", "to-delete"));
+        let base = repo
+            .write_commit(
+                &[],
+                &[("src/lib.rs", b"base"), ("README.md", b"readme")],
+                "base",
+            )
+            .await
+            .unwrap();
+        let left = repo
+            .write_commit(&[base], &[("src/lib.rs", b"left")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(&[base], &[("src/lib.rs", b"right")], "right")
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(&[left, right], &[], "merge")
+            .await
+            .unwrap();
 
-        manager.delete_repo("bob", "to-delete").unwrap();
-        assert!(!manager.repo_exists("bob", "to-delete"));
+        let PathValue::File { id, executable } =
+            repo.path_value(&left, "src/lib.rs").await.unwrap()
+        else {
+            panic!("src/lib.rs is not a file");
+        };
+        assert!(!executable);
+        let mut content = Vec::new();
+        let mut reader = repo.open_file("src/lib.rs", &id).await.unwrap();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"left");
+
+        assert_eq!(
+            repo.path_value(&left, "src").await.unwrap(),
+            PathValue::Tree
+        );
+        assert_eq!(
+            repo.path_value(&left, "missing").await.unwrap(),
+            PathValue::Absent
+        );
+        assert_eq!(
+            repo.path_value(&left, "src/lib.rs/x").await.unwrap(),
+            PathValue::Absent
+        );
+        assert_eq!(
+            repo.path_value(&left, "a//b").await.unwrap(),
+            PathValue::Absent
+        );
+
+        // Both sides changed the file; only the README merges cleanly.
+        assert_eq!(
+            repo.path_value(&merge, "src/lib.rs").await.unwrap(),
+            PathValue::Conflict { sides: 2 }
+        );
+        assert!(matches!(
+            repo.path_value(&merge, "README.md").await.unwrap(),
+            PathValue::File { .. }
+        ));
     }
 
-    #[test]
-    fn test_read_commits() {
+    #[tokio::test]
+    async fn test_merge_base() {
         let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
             repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "base-test").unwrap();
 
-        // Create a new repository
-        let repo = manager.create_repo("alice", "commits-test").unwrap();
+        let base = repo
+            .write_commit(&[], &[("a.txt", b"a")], "base")
+            .await
+            .unwrap();
+        let left = repo.write_commit(&[base], &[], "left").await.unwrap();
+        let left2 = repo.write_commit(&[left], &[], "left 2").await.unwrap();
+        let right = repo
+            .write_commit(&[base], &[("b.txt", b"b")], "right")
+            .await
+            .unwrap();
+        let other = repo.write_commit(&[], &[], "other").await.unwrap();
 
-        // Every jj repo has a root commit
-        let root = repo.root_commit();
-        assert!(root.parent_ids().is_empty());
+        assert_eq!(repo.merge_base(&left2, &right).unwrap(), Some(base));
+        assert_eq!(repo.merge_base(&left2, &left).unwrap(), Some(left));
+        assert_eq!(repo.merge_base(&left, &left).unwrap(), Some(left));
+        assert_eq!(repo.merge_base(&left, &other).unwrap(), None);
 
-        // Get the root commit by ID
-        let root_id = root.id().clone();
-        let fetched = repo.get_commit(&root_id).unwrap();
-        assert_eq!(fetched.id(), root.id());
+        assert_eq!(repo.commits_between(&right, &left2).unwrap(), [left2, left]);
+        assert!(repo.commits_between(&left2, &left).unwrap().is_empty());
 
-        // New repo should have heads
-        let heads = repo.heads();
-        assert!(!heads.is_empty());
+        let changes = repo.diff_commits(&left2, &right).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "b.txt");
+        assert_eq!(changes[0].kind, FileChangeKind::Added);
+        assert!(repo.diff_commits(&left, &left2).await.unwrap().is_empty());
+    }
 
-        // Get operation ID
-        let op_id = repo.operation_id();
-        assert!(!op_id.hex().is_empty());
+    #[tokio::test]
+    async fn test_export_tar() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "tar-test").unwrap();
+        let id = repo
+            .write_commit(
+                &[],
+                &[("src/lib.rs", b"// lib\n"), ("README.md", b"hello\n")],
+                "files",
+            )
+            .await
+            .unwrap();
+
+        let archive = repo.export_tar(&id, "tar-test/", Vec::new()).unwrap();
+        assert_eq!(
+            repo.export_tar(&id, "tar-test/", Vec::new()).unwrap(),
+            archive
+        );
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(&archive[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            assert_eq!(entry.header().mode().unwrap(), 0o644);
+            entries.push((path, content));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("tar-test/README.md".to_string(), "hello\n".to_string()),
+                ("tar-test/src/lib.rs".to_string(), "// lib\n".to_string()),
+            ]
+        );
     }
 
-    #[test]
-    fn test_tree_operations() {
+    #[tokio::test]
+    async fn test_blame() {
         let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
             repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "blame-test").unwrap();
 
-        let repo = manager.create_repo("alice", "tree-test").unwrap();
+        let first = repo
+            .write_commit(&[], &[("a.txt", b"one\ntwo\nthree\n")], "first")
+            .await
+            .unwrap();
+        let second = repo
+            .write_commit(&[first], &[("b.txt", b"other")], "second")
+            .await
+            .unwrap();
+        let third = repo
+            .write_commit(&[second], &[("a.txt", b"one\n2\nthree\nfour")], "third")
+            .await
+            .unwrap();
 
-        // Get the root commit and its tree
-        let root = repo.root_commit();
-        let tree = repo.get_tree(&root);
+        let range = |commit, start_line, line_count| BlameRange {
+            commit,
+            start_line,
+            line_count,
+        };
+        assert_eq!(
+            repo.blame(&third, "a.txt").await.unwrap(),
+            [
+                range(first, 1, 1),
+                range(third, 2, 1),
+                range(first, 3, 1),
+                range(third, 4, 1)
+            ]
+        );
+        assert_eq!(
+            repo.blame(&second, "a.txt").await.unwrap(),
+            [range(first, 1, 3)]
+        );
+        assert!(repo.blame(&third, "missing").await.is_err());
+    }
 
-        // Root commit has an empty tree
-        let entries = repo.list_tree_entries(&tree);
-        assert!(entries.is_empty(), "root commit should have empty tree");
+    #[test]
+    fn test_unchanged_lines() {
+        assert_eq!(
+            unchanged_lines(b"a\nb\nc\n", b"a\nx\nc\nd\n"),
+            [Some(0), None, Some(2), None]
+        );
+        assert_eq!(unchanged_lines(b"a\nb\n", b"b\n"), [Some(1)]);
+        assert_eq!(unchanged_lines(b"", b"a\n"), [None]);
     }
 
     #[test]
-    fn test_operation_info() {
+    fn test_trivial_merge() {
+        assert_eq!(trivial_merge(&[1]), Some(&1));
+        assert_eq!(trivial_merge(&[2, 1, 1]), Some(&2));
+        assert_eq!(trivial_merge(&[1, 1, 3]), Some(&3));
+        assert_eq!(trivial_merge(&[2, 1, 2]), Some(&2));
+        assert_eq!(trivial_merge(&[2, 1, 3]), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_change_prefix() {
         let temp_dir = TempDir::new().unwrap();
         let config = StorageConfig {
             repos_root: temp_dir.path().to_path_buf(),
         };
         let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "change-test").unwrap();
+        let commit = repo
+            .write_commit(&[], &[("file", b"content")], "commit")
+            .await
+            .unwrap();
+        let change_id = repo.commit_summary(&commit).unwrap().change_id;
 
-        let repo = manager.create_repo("alice", "op-test").unwrap();
+        for prefix in [
+            change_id.to_hex(),
+            change_id.to_reverse_hex()[..12].to_string(),
+        ] {
+            assert_eq!(
+                repo.resolve_change_prefix(&prefix).unwrap(),
+                PrefixResolution::Single(commit)
+            );
+        }
+        assert!(repo.resolve_change_prefix("").is_err());
+        assert!(repo.resolve_change_prefix("0azk").is_err());
+    }
 
-        // Get current operation
-        let op = repo.operation();
-        assert!(!op.id().hex().is_empty());
+    #[tokio::test]
+    async fn test_conflicted_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "conflicts-test").unwrap();
+        let base = repo
+            .write_commit(
+                &[],
+                &[("a.txt", b"a"), ("b/c.txt", b"c"), ("d.txt", b"d")],
+                "base",
+            )
+            .await
+            .unwrap();
+        let left = repo
+            .write_commit(&[base], &[("a.txt", b"left"), ("b/c.txt", b"left")], "left")
+            .await
+            .unwrap();
+        let right = repo
+            .write_commit(
+                &[base],
+                &[("a.txt", b"right"), ("b/c.txt", b"right")],
+                "right",
+            )
+            .await
+            .unwrap();
+        let merge = repo
+            .write_commit(&[left, right], &[], "merge")
+            .await
+            .unwrap();
 
-        // New repo should be fresh (no user commits)
-        assert!(repo.is_fresh());
+        assert!(repo.conflicted_paths(&base).unwrap().is_empty());
+        assert_eq!(repo.conflicted_paths(&merge).unwrap(), ["a.txt", "b/c.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_local_refs() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut repo = manager.create_repo("alice", "refs-test").unwrap();
+        let left = repo.write_commit(&[], &[], "left").await.unwrap();
+        let right = repo.write_commit(&[], &[], "right").await.unwrap();
+
+        repo.set_tags(&[("v1.0".to_string(), Some(left))], "tag v1.0")
+            .unwrap();
+        assert_eq!(repo.tag_names(), vec!["v1.0".to_string()]);
+        assert_eq!(
+            repo.tag_target("v1.0").unwrap(),
+            BookmarkTarget::Normal(left)
+        );
+        assert_eq!(
+            repo.local_tags().unwrap(),
+            vec![LocalRef {
+                name: "v1.0".to_string(),
+                targets: vec![left],
+                conflicted: false,
+            }]
+        );
+
+        // Two concurrent operations move main apart; merging them leaves it
+        // conflicted.
+        let mut other = manager.open_repo("alice", "refs-test").unwrap();
+        repo.set_bookmarks(&[("main".to_string(), Some(left))], "main to left")
+            .unwrap();
+        other
+            .set_bookmarks(&[("main".to_string(), Some(right))], "main to right")
+            .unwrap();
+        repo.reload().unwrap();
+        let conflict = BookmarkTarget::conflicted(vec![right, left], vec![]);
+        assert_eq!(repo.bookmark_target("main").unwrap(), conflict);
+        let bookmarks = repo.local_bookmarks().unwrap();
+        assert_eq!(bookmarks.len(), 1);
+        let mut main = bookmarks.into_iter().next().unwrap();
+        assert_eq!(main.name, "main");
+        assert!(main.conflicted);
+        main.targets.sort();
+        let mut expected = vec![left, right];
+        expected.sort();
+        assert_eq!(main.targets, expected);
+
+        // Presenting the conflict exactly resolves it.
+        let resolve = |expected| BookmarkUpdate {
+            name: "main".to_string(),
+            expected,
+            target: Some(left),
+        };
+        let stale = repo
+            .update_bookmarks(&[resolve(BookmarkTarget::Normal(right))], "resolve")
+            .unwrap_err();
+        assert_eq!(
+            stale
+                .downcast_ref::<StaleBookmark>()
+                .map(|stale| &stale.actual),
+            Some(&conflict)
+        );
+        repo.update_bookmarks(&[resolve(conflict)], "resolve")
+            .unwrap();
+        assert_eq!(
+            repo.bookmark_target("main").unwrap(),
+            BookmarkTarget::Normal(left)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_commit_prefix() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            repos_root: temp_dir.path().to_path_buf(),
+        };
+        let manager = RepositoryManager::new(config).unwrap();
+        let mut source = manager.create_repo("alice", "source").unwrap();
+        let target = manager.create_repo("alice", "target").unwrap();
+        let commit = source
+            .write_commit(&[], &[("file", b"hidden")], "hidden")
+            .await
+            .unwrap();
+
+        let hex = commit.to_hex();
+        assert_eq!(
+            source.resolve_commit_prefix(&hex).unwrap(),
+            PrefixResolution::Single(commit)
+        );
+        assert_eq!(
+            source
+                .resolve_commit_prefix(&hex[..12].to_uppercase())
+                .unwrap(),
+            PrefixResolution::Single(commit)
+        );
+        assert_eq!(
+            target.resolve_commit_prefix(&hex).unwrap(),
+            PrefixResolution::NoMatch
+        );
+        assert!(source.resolve_commit_prefix("").is_err());
+        assert!(source.resolve_commit_prefix("xyz").is_err());
+        assert!(source.is_visible(&commit).unwrap());
+
+        // Copied into the store without an operation, the commit is there
+        // but hidden.
+        for kind in RawObjectKind::ALL {
+            for id in source.list_raw_objects(kind).unwrap() {
+                let data = source.read_raw_object(kind, &id).unwrap();
+                target.write_raw_object(kind, &id, &data).unwrap();
+            }
+        }
+        assert_eq!(
+            target.resolve_commit_prefix(&hex[..12]).unwrap(),
+            PrefixResolution::Single(commit)
+        );
+        assert!(!target.is_visible(&commit).unwrap());
     }
 
     #[tokio::test]